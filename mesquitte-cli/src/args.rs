@@ -0,0 +1,494 @@
+use std::{fmt, str::FromStr, time::Duration};
+
+use mqtt_codec_kit::common::QualityOfService;
+
+use crate::client::{ConnectOptions, MessageProperties, Version};
+
+pub const USAGE: &str = "\
+Usage: mesquitte-cli <pub|sub|bench> [options]
+       mesquitte-cli session <export|import> --id <ID> [options]
+
+Connection options:
+  -h, --host <HOST>              broker host [default: localhost]
+  -p, --port <PORT>              broker port [default: 1883]
+  -i, --id <ID>                  client identifier [default: mesquitte-cli-<random>]
+  -u, --username <USERNAME>
+  -P, --password <PASSWORD>
+  -k, --keep-alive <SECONDS>     [default: 60]
+  -V, --protocol <VERSION>       4 (MQTT 3.1.1) or 5 (MQTT 5.0) [default: 4]
+  -c, --no-clean                 keep the session of the client
+  -x, --session-expiry <SECONDS> session expiry interval (v5)
+      --connect-property <KEY=VALUE>
+                                 user property of CONNECT (v5), repeatable
+
+pub options:
+  -t, --topic <TOPIC>
+  -m, --message <MESSAGE>
+  -f, --file <PATH>              publish the content of a file
+  -n, --null-message             publish an empty message
+  -q, --qos <QOS>                [default: 0]
+  -r, --retain
+  -C, --count <COUNT>            number of messages to publish [default: 1]
+      --interval <MILLIS>        delay between two messages [default: 0]
+      --payload-format <0|1>     payload format indicator (v5)
+      --message-expiry <SECONDS> message expiry interval (v5)
+      --content-type <TYPE>      content type (v5)
+      --response-topic <TOPIC>   response topic (v5)
+      --correlation-data <DATA>  correlation data (v5)
+      --user-property <KEY=VALUE>
+                                 user property (v5), repeatable
+
+sub options:
+  -t, --topic <FILTER>           repeatable
+  -q, --qos <QOS>                [default: 0]
+  -C, --count <COUNT>            exit after receiving this many messages
+  -v, --verbose                  print topics and properties of messages
+
+bench options:
+  -t, --topic <TOPIC>            [default: mesquitte/bench]
+  -q, --qos <QOS>                [default: 0]
+  -n, --clients <CLIENTS>        number of publishing clients [default: 10]
+  -C, --count <COUNT>            messages published by each client [default: 100]
+  -s, --size <BYTES>             payload size, at least 8 [default: 64]
+      --interval <MILLIS>        delay between two messages of a client [default: 0]
+
+session options, through the HTTP API of the broker:
+  -h, --host <HOST>              HTTP API host [default: localhost]
+  -p, --port <PORT>              HTTP API port [default: 8080]
+  -i, --id <ID>                  client identifier of the session
+      --token <TOKEN>            bearer token of the HTTP API
+  -f, --file <PATH>              write the export to or read the import from a file
+                                 instead of stdout or stdin
+";
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct ArgsError(String);
+
+impl fmt::Display for ArgsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for ArgsError {}
+
+#[derive(Debug)]
+pub enum Command {
+    Pub(PubArgs),
+    Sub(SubArgs),
+    Bench(BenchArgs),
+    Session(SessionArgs),
+    Help,
+}
+
+#[derive(Debug)]
+pub struct PubArgs {
+    pub connect: ConnectOptions,
+    pub topic: String,
+    pub payload: Vec<u8>,
+    pub qos: QualityOfService,
+    pub retain: bool,
+    pub count: usize,
+    pub interval: Duration,
+    pub properties: MessageProperties,
+}
+
+#[derive(Debug)]
+pub struct SubArgs {
+    pub connect: ConnectOptions,
+    pub filters: Vec<String>,
+    pub qos: QualityOfService,
+    pub count: Option<usize>,
+    pub verbose: bool,
+}
+
+#[derive(Debug)]
+pub struct BenchArgs {
+    pub connect: ConnectOptions,
+    pub topic: String,
+    pub qos: QualityOfService,
+    pub clients: usize,
+    pub count: usize,
+    pub size: usize,
+    pub interval: Duration,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionAction {
+    Export,
+    Import,
+}
+
+#[derive(Debug)]
+pub struct SessionArgs {
+    pub action: SessionAction,
+    pub host: String,
+    pub port: u16,
+    pub client_id: String,
+    pub token: Option<String>,
+    /// Standard output for an export, standard input for an import without one
+    pub file: Option<String>,
+}
+
+struct Parser<I> {
+    args: I,
+}
+
+impl<I: Iterator<Item = String>> Parser<I> {
+    fn value(&mut self, flag: &str) -> Result<String, ArgsError> {
+        self.args
+            .next()
+            .ok_or_else(|| ArgsError(format!("{flag} requires a value")))
+    }
+
+    fn parse<T: FromStr>(&mut self, flag: &str) -> Result<T, ArgsError> {
+        let value = self.value(flag)?;
+        value
+            .parse()
+            .map_err(|_| ArgsError(format!("invalid value for {flag}: {value}")))
+    }
+
+    fn qos(&mut self, flag: &str) -> Result<QualityOfService, ArgsError> {
+        match self.parse::<u8>(flag)? {
+            0 => Ok(QualityOfService::Level0),
+            1 => Ok(QualityOfService::Level1),
+            2 => Ok(QualityOfService::Level2),
+            qos => Err(ArgsError(format!("invalid QoS: {qos}"))),
+        }
+    }
+
+    fn property(&mut self, flag: &str) -> Result<(String, String), ArgsError> {
+        let value = self.value(flag)?;
+        match value.split_once('=') {
+            Some((key, value)) => Ok((key.to_owned(), value.to_owned())),
+            None => Err(ArgsError(format!("{flag} expects KEY=VALUE, got {value}"))),
+        }
+    }
+
+    /// Parses the connection option `flag`, returns false if `flag` is not one
+    fn connect_option(
+        &mut self,
+        flag: &str,
+        options: &mut ConnectOptions,
+    ) -> Result<bool, ArgsError> {
+        match flag {
+            "-h" | "--host" => options.host = self.value(flag)?,
+            "-p" | "--port" => options.port = self.parse(flag)?,
+            "-i" | "--id" => options.client_id = self.value(flag)?,
+            "-u" | "--username" => options.username = Some(self.value(flag)?),
+            "-P" | "--password" => options.password = Some(self.value(flag)?),
+            "-k" | "--keep-alive" => options.keep_alive = self.parse(flag)?,
+            "-V" | "--protocol" => {
+                options.version = match self.value(flag)?.as_str() {
+                    "4" | "311" | "mqttv311" => Version::V4,
+                    "5" | "mqttv5" => Version::V5,
+                    version => return Err(ArgsError(format!("unknown protocol: {version}"))),
+                }
+            }
+            "-c" | "--no-clean" => options.clean_start = false,
+            "-x" | "--session-expiry" => options.session_expiry_interval = Some(self.parse(flag)?),
+            "--connect-property" => options.user_properties.push(self.property(flag)?),
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+}
+
+fn unknown(flag: &str) -> ArgsError {
+    ArgsError(format!("unexpected argument: {flag}"))
+}
+
+fn check_version(
+    connect: &ConnectOptions,
+    properties: &MessageProperties,
+) -> Result<(), ArgsError> {
+    let has_v5_options = connect.session_expiry_interval.is_some()
+        || !connect.user_properties.is_empty()
+        || !properties.is_empty();
+    if connect.version == Version::V4 && has_v5_options {
+        return Err(ArgsError("MQTT 5 options require --protocol 5".to_owned()));
+    }
+    Ok(())
+}
+
+pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Command, ArgsError> {
+    let mut args = args.into_iter();
+    let command = match args.next() {
+        Some(command) => command,
+        None => return Ok(Command::Help),
+    };
+    let mut parser = Parser { args };
+    match command.as_str() {
+        "pub" => parse_pub(&mut parser).map(Command::Pub),
+        "sub" => parse_sub(&mut parser).map(Command::Sub),
+        "bench" => parse_bench(&mut parser).map(Command::Bench),
+        "session" => parse_session(&mut parser).map(Command::Session),
+        "help" | "--help" => Ok(Command::Help),
+        command => Err(ArgsError(format!("unknown command: {command}"))),
+    }
+}
+
+fn parse_pub<I: Iterator<Item = String>>(parser: &mut Parser<I>) -> Result<PubArgs, ArgsError> {
+    let mut connect = ConnectOptions::default();
+    let mut topic = None;
+    let mut payload = None;
+    let mut qos = QualityOfService::Level0;
+    let mut retain = false;
+    let mut count = 1;
+    let mut interval = 0;
+    let mut properties = MessageProperties::default();
+    while let Some(flag) = parser.args.next() {
+        if parser.connect_option(&flag, &mut connect)? {
+            continue;
+        }
+        match flag.as_str() {
+            "-t" | "--topic" => topic = Some(parser.value(&flag)?),
+            "-m" | "--message" => payload = Some(parser.value(&flag)?.into_bytes()),
+            "-f" | "--file" => {
+                let path = parser.value(&flag)?;
+                let content = std::fs::read(&path)
+                    .map_err(|err| ArgsError(format!("cannot read {path}: {err}")))?;
+                payload = Some(content);
+            }
+            "-n" | "--null-message" => payload = Some(Vec::new()),
+            "-q" | "--qos" => qos = parser.qos(&flag)?,
+            "-r" | "--retain" => retain = true,
+            "-C" | "--count" => count = parser.parse(&flag)?,
+            "--interval" => interval = parser.parse(&flag)?,
+            "--payload-format" => match parser.parse(&flag)? {
+                indicator @ (0 | 1) => properties.payload_format_indicator = Some(indicator),
+                indicator => {
+                    return Err(ArgsError(format!(
+                        "invalid payload format indicator: {indicator}"
+                    )))
+                }
+            },
+            "--message-expiry" => properties.message_expiry_interval = Some(parser.parse(&flag)?),
+            "--content-type" => properties.content_type = Some(parser.value(&flag)?),
+            "--response-topic" => properties.response_topic = Some(parser.value(&flag)?),
+            "--correlation-data" => {
+                properties.correlation_data = Some(parser.value(&flag)?.into_bytes())
+            }
+            "--user-property" => properties.user_properties.push(parser.property(&flag)?),
+            _ => return Err(unknown(&flag)),
+        }
+    }
+    check_version(&connect, &properties)?;
+
+    Ok(PubArgs {
+        connect,
+        topic: topic.ok_or_else(|| ArgsError("pub requires --topic".to_owned()))?,
+        payload: payload.ok_or_else(|| {
+            ArgsError("pub requires --message, --file or --null-message".to_owned())
+        })?,
+        qos,
+        retain,
+        count,
+        interval: Duration::from_millis(interval),
+        properties,
+    })
+}
+
+fn parse_sub<I: Iterator<Item = String>>(parser: &mut Parser<I>) -> Result<SubArgs, ArgsError> {
+    let mut connect = ConnectOptions::default();
+    let mut filters = Vec::new();
+    let mut qos = QualityOfService::Level0;
+    let mut count = None;
+    let mut verbose = false;
+    while let Some(flag) = parser.args.next() {
+        if parser.connect_option(&flag, &mut connect)? {
+            continue;
+        }
+        match flag.as_str() {
+            "-t" | "--topic" => filters.push(parser.value(&flag)?),
+            "-q" | "--qos" => qos = parser.qos(&flag)?,
+            "-C" | "--count" => count = Some(parser.parse(&flag)?),
+            "-v" | "--verbose" => verbose = true,
+            _ => return Err(unknown(&flag)),
+        }
+    }
+    check_version(&connect, &MessageProperties::default())?;
+    if filters.is_empty() {
+        return Err(ArgsError("sub requires at least one --topic".to_owned()));
+    }
+
+    Ok(SubArgs {
+        connect,
+        filters,
+        qos,
+        count,
+        verbose,
+    })
+}
+
+fn parse_bench<I: Iterator<Item = String>>(parser: &mut Parser<I>) -> Result<BenchArgs, ArgsError> {
+    let mut connect = ConnectOptions::default();
+    let mut topic = "mesquitte/bench".to_owned();
+    let mut qos = QualityOfService::Level0;
+    let mut clients = 10;
+    let mut count = 100;
+    let mut size = 64;
+    let mut interval = 0;
+    while let Some(flag) = parser.args.next() {
+        if parser.connect_option(&flag, &mut connect)? {
+            continue;
+        }
+        match flag.as_str() {
+            "-t" | "--topic" => topic = parser.value(&flag)?,
+            "-q" | "--qos" => qos = parser.qos(&flag)?,
+            "-n" | "--clients" => clients = parser.parse(&flag)?,
+            "-C" | "--count" => count = parser.parse(&flag)?,
+            "-s" | "--size" => size = parser.parse(&flag)?,
+            "--interval" => interval = parser.parse(&flag)?,
+            _ => return Err(unknown(&flag)),
+        }
+    }
+    check_version(&connect, &MessageProperties::default())?;
+    if size < 8 {
+        // the first 8 bytes carry the time the message was sent at
+        return Err(ArgsError("--size must be at least 8".to_owned()));
+    }
+
+    Ok(BenchArgs {
+        connect,
+        topic,
+        qos,
+        clients,
+        count,
+        size,
+        interval: Duration::from_millis(interval),
+    })
+}
+
+fn parse_session<I: Iterator<Item = String>>(
+    parser: &mut Parser<I>,
+) -> Result<SessionArgs, ArgsError> {
+    let action = match parser.args.next().as_deref() {
+        Some("export") => SessionAction::Export,
+        Some("import") => SessionAction::Import,
+        Some(action) => return Err(ArgsError(format!("unknown session action: {action}"))),
+        None => return Err(ArgsError("session requires export or import".to_owned())),
+    };
+    let mut host = "localhost".to_owned();
+    let mut port = 8080;
+    let mut client_id = None;
+    let mut token = None;
+    let mut file = None;
+    while let Some(flag) = parser.args.next() {
+        match flag.as_str() {
+            "-h" | "--host" => host = parser.value(&flag)?,
+            "-p" | "--port" => port = parser.parse(&flag)?,
+            "-i" | "--id" => client_id = Some(parser.value(&flag)?),
+            "--token" => token = Some(parser.value(&flag)?),
+            "-f" | "--file" => file = Some(parser.value(&flag)?),
+            _ => return Err(unknown(&flag)),
+        }
+    }
+
+    Ok(SessionArgs {
+        action,
+        host,
+        port,
+        client_id: client_id.ok_or_else(|| ArgsError("session requires --id".to_owned()))?,
+        token,
+        file,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_args(args: &[&str]) -> Result<Command, ArgsError> {
+        parse(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn test_parse_pub() {
+        let command = parse_args(&[
+            "pub",
+            "-h",
+            "broker",
+            "-p",
+            "1884",
+            "-V",
+            "5",
+            "-t",
+            "a/b",
+            "-m",
+            "hello",
+            "-q",
+            "2",
+            "--content-type",
+            "text/plain",
+            "--user-property",
+            "k=v",
+        ])
+        .unwrap();
+        let Command::Pub(args) = command else {
+            panic!("expected pub, got {command:?}");
+        };
+        assert_eq!(args.connect.host, "broker");
+        assert_eq!(args.connect.port, 1884);
+        assert_eq!(args.connect.version, Version::V5);
+        assert_eq!(args.topic, "a/b");
+        assert_eq!(args.payload, b"hello");
+        assert_eq!(args.qos, QualityOfService::Level2);
+        assert_eq!(args.properties.content_type.as_deref(), Some("text/plain"));
+        assert_eq!(
+            args.properties.user_properties,
+            vec![("k".to_owned(), "v".to_owned())]
+        );
+    }
+
+    #[test]
+    fn test_parse_sub() {
+        let command = parse_args(&["sub", "-t", "a/#", "-t", "b/+", "-C", "3", "-v"]).unwrap();
+        let Command::Sub(args) = command else {
+            panic!("expected sub, got {command:?}");
+        };
+        assert_eq!(args.filters, vec!["a/#", "b/+"]);
+        assert_eq!(args.count, Some(3));
+        assert!(args.verbose);
+        assert!(args.connect.clean_start);
+    }
+
+    #[test]
+    fn test_parse_session() {
+        let command = parse_args(&[
+            "session", "import", "-i", "c1", "-p", "8081", "--token", "secret", "-f", "c1.json",
+        ])
+        .unwrap();
+        let Command::Session(args) = command else {
+            panic!("expected session, got {command:?}");
+        };
+        assert_eq!(args.action, SessionAction::Import);
+        assert_eq!(args.host, "localhost");
+        assert_eq!(args.port, 8081);
+        assert_eq!(args.client_id, "c1");
+        assert_eq!(args.token.as_deref(), Some("secret"));
+        assert_eq!(args.file.as_deref(), Some("c1.json"));
+
+        assert!(parse_args(&["session", "export"]).is_err());
+        assert!(parse_args(&["session", "dump", "-i", "c1"]).is_err());
+        assert!(parse_args(&["session"]).is_err());
+    }
+
+    #[test]
+    fn test_v5_options_require_v5() {
+        assert!(parse_args(&["pub", "-t", "a", "-n", "--message-expiry", "10"]).is_err());
+        assert!(parse_args(&["sub", "-t", "a", "-x", "10"]).is_err());
+        assert!(parse_args(&["sub", "-t", "a", "-V", "5", "-x", "10"]).is_ok());
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(parse_args(&["pub", "-m", "hello"]).is_err());
+        assert!(parse_args(&["pub", "-t", "a"]).is_err());
+        assert!(parse_args(&["sub"]).is_err());
+        assert!(parse_args(&["sub", "-t", "a", "-q", "3"]).is_err());
+        assert!(parse_args(&["bench", "-s", "4"]).is_err());
+        assert!(parse_args(&["bench", "--unknown"]).is_err());
+        assert!(parse_args(&["publish"]).is_err());
+    }
+}
@@ -0,0 +1,146 @@
+use std::time::Duration;
+
+use mqtt_codec_kit::common::QualityOfService;
+use tokio::{
+    task::JoinSet,
+    time::{self, Instant},
+};
+
+use crate::{
+    args::BenchArgs,
+    client::{Client, ConnectOptions, Error, Message, MessageProperties},
+};
+
+/// The subscriber gives up once no message arrived for this long
+const IDLE_TIMEOUT: Duration = Duration::from_secs(5);
+
+struct Report {
+    published: usize,
+    publish_elapsed: Duration,
+    received: usize,
+    latencies: Vec<Duration>,
+}
+
+impl Report {
+    fn print(mut self, expected: usize) {
+        let rate = self.published as f64 / self.publish_elapsed.as_secs_f64().max(f64::EPSILON);
+        println!(
+            "published: {} messages in {:.3}s ({rate:.0} msg/s)",
+            self.published,
+            self.publish_elapsed.as_secs_f64()
+        );
+        println!(
+            "received:  {} messages ({:.2}%)",
+            self.received,
+            self.received as f64 * 100.0 / expected.max(1) as f64
+        );
+        if self.latencies.is_empty() {
+            return;
+        }
+        self.latencies.sort_unstable();
+        let percentile = |p: usize| self.latencies[(self.latencies.len() - 1) * p / 100];
+        println!(
+            "latency:   min {:?}, p50 {:?}, p95 {:?}, p99 {:?}, max {:?}",
+            self.latencies[0],
+            percentile(50),
+            percentile(95),
+            percentile(99),
+            self.latencies[self.latencies.len() - 1]
+        );
+    }
+}
+
+async fn publisher(
+    connect: ConnectOptions,
+    message: Message,
+    count: usize,
+    interval: Duration,
+    start: Instant,
+) -> Result<usize, Error> {
+    let mut client = Client::connect(&connect).await?;
+    let mut message = message;
+    for i in 0..count {
+        if i > 0 && !interval.is_zero() {
+            time::sleep(interval).await;
+        }
+        let sent_at = start.elapsed().as_micros() as u64;
+        message.payload[..8].copy_from_slice(&sent_at.to_be_bytes());
+        client.publish(&message).await?;
+    }
+    client.disconnect().await?;
+    Ok(count)
+}
+
+/// Receives `expected` messages, returns the latency of every one of them
+async fn subscriber(
+    mut client: Client,
+    expected: usize,
+    start: Instant,
+) -> Result<Vec<Duration>, Error> {
+    let mut latencies = Vec::with_capacity(expected);
+    while latencies.len() < expected {
+        let message = match time::timeout(IDLE_TIMEOUT, client.recv()).await {
+            Ok(message) => message?,
+            Err(_) => break,
+        };
+        let Some(sent_at) = message.payload.first_chunk::<8>() else {
+            continue;
+        };
+        let sent_at = Duration::from_micros(u64::from_be_bytes(*sent_at));
+        latencies.push(start.elapsed().saturating_sub(sent_at));
+    }
+    client.disconnect().await?;
+    Ok(latencies)
+}
+
+pub async fn run(args: BenchArgs) -> Result<(), Error> {
+    let expected = args.clients * args.count;
+    let start = Instant::now();
+
+    let mut connect = args.connect.clone();
+    connect.client_id = format!("{}-sub", args.connect.client_id);
+    let mut client = Client::connect(&connect).await?;
+    client
+        .subscribe(&[(args.topic.clone(), QualityOfService::Level2)])
+        .await?;
+    let subscriber = tokio::spawn(subscriber(client, expected, start));
+
+    let message = Message {
+        topic: args.topic,
+        payload: vec![0; args.size],
+        qos: args.qos,
+        retain: false,
+        properties: MessageProperties::default(),
+    };
+    let publish_start = Instant::now();
+    let mut publishers = JoinSet::new();
+    for i in 0..args.clients {
+        let mut connect = args.connect.clone();
+        connect.client_id = format!("{}-pub-{i}", args.connect.client_id);
+        publishers.spawn(publisher(
+            connect,
+            message.clone(),
+            args.count,
+            args.interval,
+            start,
+        ));
+    }
+    let mut published = 0;
+    while let Some(result) = publishers.join_next().await {
+        match result.expect("publisher panicked") {
+            Ok(count) => published += count,
+            Err(err) => eprintln!("publisher failed: {err}"),
+        }
+    }
+    let publish_elapsed = publish_start.elapsed();
+
+    let latencies = subscriber.await.expect("subscriber panicked")?;
+    Report {
+        published,
+        publish_elapsed,
+        received: latencies.len(),
+        latencies,
+    }
+    .print(expected);
+    Ok(())
+}
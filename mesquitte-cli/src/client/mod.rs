@@ -0,0 +1,333 @@
+//! A small MQTT client over TCP, enough for smoke tests and load generation
+
+use std::{collections::VecDeque, io, time::Duration};
+
+use mqtt_codec_kit::common::{topic_filter::TopicFilterError, QualityOfService};
+use tokio::{
+    net::TcpStream,
+    time::{self, Instant},
+};
+
+mod v4;
+mod v5;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+    #[error(transparent)]
+    V4Packet(#[from] mqtt_codec_kit::v4::packet::VariablePacketError),
+    #[error(transparent)]
+    V5Packet(#[from] mqtt_codec_kit::v5::packet::VariablePacketError),
+    #[error(transparent)]
+    V4Builder(#[from] mqtt_codec_kit::v4::packet::PacketBuilderError),
+    #[error(transparent)]
+    V5Builder(#[from] mqtt_codec_kit::v5::packet::PacketBuilderError),
+    #[error(transparent)]
+    TopicFilter(#[from] TopicFilterError),
+    #[error("connection refused: {0}")]
+    Refused(String),
+    #[error("{0} rejected: {1}")]
+    Rejected(&'static str, String),
+    #[error("unexpected packet: {0}")]
+    Unexpected(String),
+    #[error("disconnected by the broker: {0}")]
+    Disconnected(String),
+    #[error("connection closed")]
+    Closed,
+    #[error("HTTP API answered {0}: {1}")]
+    Http(u16, String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Version {
+    V4,
+    V5,
+}
+
+#[derive(Debug, Clone)]
+pub struct ConnectOptions {
+    pub host: String,
+    pub port: u16,
+    pub client_id: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Seconds, 0 disables keep alive
+    pub keep_alive: u16,
+    pub clean_start: bool,
+    pub version: Version,
+    /// MQTT 5 only
+    pub session_expiry_interval: Option<u32>,
+    /// MQTT 5 only
+    pub user_properties: Vec<(String, String)>,
+}
+
+impl Default for ConnectOptions {
+    fn default() -> Self {
+        Self {
+            host: "localhost".to_owned(),
+            port: 1883,
+            client_id: format!("mesquitte-cli-{}", nanoid::nanoid!(8)),
+            username: None,
+            password: None,
+            keep_alive: 60,
+            clean_start: true,
+            version: Version::V4,
+            session_expiry_interval: None,
+            user_properties: Vec::new(),
+        }
+    }
+}
+
+/// MQTT 5 properties of a message, MQTT 3.1.1 messages never have any
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MessageProperties {
+    pub payload_format_indicator: Option<u8>,
+    pub message_expiry_interval: Option<u32>,
+    pub content_type: Option<String>,
+    pub response_topic: Option<String>,
+    pub correlation_data: Option<Vec<u8>>,
+    pub user_properties: Vec<(String, String)>,
+}
+
+impl MessageProperties {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Message {
+    pub topic: String,
+    pub payload: Vec<u8>,
+    pub qos: QualityOfService,
+    pub retain: bool,
+    pub properties: MessageProperties,
+}
+
+/// Packets sent by the client, independent of the protocol version
+#[derive(Debug)]
+enum Outgoing<'a> {
+    Publish {
+        message: &'a Message,
+        packet_id: Option<u16>,
+    },
+    Puback(u16),
+    Pubrec(u16),
+    Pubrel(u16),
+    Pubcomp(u16),
+    Subscribe {
+        packet_id: u16,
+        filters: &'a [(String, QualityOfService)],
+    },
+    Pingreq,
+    Disconnect,
+}
+
+/// Packets received by the client, independent of the protocol version
+#[derive(Debug)]
+enum Incoming {
+    Publish {
+        message: Message,
+        packet_id: Option<u16>,
+    },
+    Puback(u16, Result<(), String>),
+    Pubrec(u16, Result<(), String>),
+    Pubrel(u16),
+    Pubcomp(u16),
+    /// The granted QoS or the failure of every filter
+    Suback(u16, Vec<Result<QualityOfService, String>>),
+    Pingresp,
+    Disconnect(String),
+    Other(String),
+}
+
+enum Transport {
+    V4(v4::Transport),
+    V5(v5::Transport),
+}
+
+impl Transport {
+    async fn send(&mut self, packet: Outgoing<'_>) -> Result<(), Error> {
+        match self {
+            Transport::V4(transport) => transport.send(packet).await,
+            Transport::V5(transport) => transport.send(packet).await,
+        }
+    }
+
+    async fn next(&mut self) -> Result<Incoming, Error> {
+        match self {
+            Transport::V4(transport) => transport.next().await,
+            Transport::V5(transport) => transport.next().await,
+        }
+    }
+}
+
+pub struct Client {
+    transport: Transport,
+    keep_alive: Duration,
+    last_sent_at: Instant,
+    next_packet_id: u16,
+    session_present: bool,
+    // publishes received while waiting for an acknowledgement
+    received: VecDeque<Message>,
+}
+
+impl Client {
+    pub async fn connect(options: &ConnectOptions) -> Result<Self, Error> {
+        let stream = TcpStream::connect((options.host.as_str(), options.port)).await?;
+        stream.set_nodelay(true)?;
+        let (transport, session_present) = match options.version {
+            Version::V4 => {
+                let (transport, session_present) = v4::Transport::connect(stream, options).await?;
+                (Transport::V4(transport), session_present)
+            }
+            Version::V5 => {
+                let (transport, session_present) = v5::Transport::connect(stream, options).await?;
+                (Transport::V5(transport), session_present)
+            }
+        };
+        Ok(Self {
+            transport,
+            keep_alive: Duration::from_secs(options.keep_alive as u64),
+            last_sent_at: Instant::now(),
+            next_packet_id: 1,
+            session_present,
+            received: VecDeque::new(),
+        })
+    }
+
+    pub fn session_present(&self) -> bool {
+        self.session_present
+    }
+
+    /// Publishes a message and waits until the broker completed its QoS flow
+    pub async fn publish(&mut self, message: &Message) -> Result<(), Error> {
+        let packet_id = (message.qos != QualityOfService::Level0).then(|| self.next_packet_id());
+        self.send(Outgoing::Publish { message, packet_id }).await?;
+        let Some(packet_id) = packet_id else {
+            return Ok(());
+        };
+
+        let ack = self
+            .wait_for(|incoming| {
+                matches!(incoming, Incoming::Puback(id, _) | Incoming::Pubrec(id, _) if *id == packet_id)
+            })
+            .await?;
+        match ack {
+            Incoming::Puback(_, Err(reason)) | Incoming::Pubrec(_, Err(reason)) => {
+                Err(Error::Rejected("publish", reason))
+            }
+            Incoming::Pubrec(_, Ok(())) => {
+                self.send(Outgoing::Pubrel(packet_id)).await?;
+                self.wait_for(
+                    |incoming| matches!(incoming, Incoming::Pubcomp(id) if *id == packet_id),
+                )
+                .await?;
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Subscribes to the filters, returns the QoS granted to each of them
+    pub async fn subscribe(
+        &mut self,
+        filters: &[(String, QualityOfService)],
+    ) -> Result<Vec<QualityOfService>, Error> {
+        let packet_id = self.next_packet_id();
+        self.send(Outgoing::Subscribe { packet_id, filters })
+            .await?;
+        let ack = self
+            .wait_for(|incoming| matches!(incoming, Incoming::Suback(id, _) if *id == packet_id))
+            .await?;
+        let Incoming::Suback(_, granted) = ack else {
+            return Err(Error::Unexpected(format!("{ack:?}")));
+        };
+        filters
+            .iter()
+            .zip(granted)
+            .map(|((filter, _), granted)| {
+                granted
+                    .map_err(|reason| Error::Rejected("subscribe", format!("{filter}: {reason}")))
+            })
+            .collect()
+    }
+
+    /// Waits for the next message published to a subscription of the client
+    pub async fn recv(&mut self) -> Result<Message, Error> {
+        if let Some(message) = self.received.pop_front() {
+            return Ok(message);
+        }
+        loop {
+            if let Incoming::Publish { message, .. } = self.next().await? {
+                return Ok(message);
+            }
+        }
+    }
+
+    pub async fn disconnect(mut self) -> Result<(), Error> {
+        self.send(Outgoing::Disconnect).await
+    }
+
+    fn next_packet_id(&mut self) -> u16 {
+        let packet_id = self.next_packet_id;
+        self.next_packet_id = packet_id.checked_add(1).unwrap_or(1);
+        packet_id
+    }
+
+    async fn send(&mut self, packet: Outgoing<'_>) -> Result<(), Error> {
+        self.last_sent_at = Instant::now();
+        self.transport.send(packet).await
+    }
+
+    /// Next packet from the broker, pings and acknowledgements of received publishes are
+    /// handled here
+    async fn next(&mut self) -> Result<Incoming, Error> {
+        loop {
+            let incoming = if self.keep_alive.is_zero() {
+                self.transport.next().await?
+            } else {
+                let deadline = self.last_sent_at + self.keep_alive;
+                match time::timeout_at(deadline, self.transport.next()).await {
+                    Ok(incoming) => incoming?,
+                    Err(_) => {
+                        self.send(Outgoing::Pingreq).await?;
+                        continue;
+                    }
+                }
+            };
+            match incoming {
+                Incoming::Pingresp => continue,
+                Incoming::Pubrel(packet_id) => self.send(Outgoing::Pubcomp(packet_id)).await?,
+                Incoming::Disconnect(reason) => return Err(Error::Disconnected(reason)),
+                Incoming::Other(packet) => return Err(Error::Unexpected(packet)),
+                Incoming::Publish { message, packet_id } => {
+                    match (message.qos, packet_id) {
+                        (QualityOfService::Level1, Some(packet_id)) => {
+                            self.send(Outgoing::Puback(packet_id)).await?
+                        }
+                        (QualityOfService::Level2, Some(packet_id)) => {
+                            self.send(Outgoing::Pubrec(packet_id)).await?
+                        }
+                        _ => {}
+                    }
+                    return Ok(Incoming::Publish { message, packet_id });
+                }
+                incoming => return Ok(incoming),
+            }
+        }
+    }
+
+    /// Waits for the packet accepted by `is_ack`, messages received meanwhile are kept for
+    /// [`Client::recv`]
+    async fn wait_for(&mut self, is_ack: impl Fn(&Incoming) -> bool) -> Result<Incoming, Error> {
+        loop {
+            match self.next().await? {
+                Incoming::Publish { message, .. } => self.received.push_back(message),
+                incoming if is_ack(&incoming) => return Ok(incoming),
+                incoming => return Err(Error::Unexpected(format!("{incoming:?}"))),
+            }
+        }
+    }
+}
@@ -0,0 +1,126 @@
+use futures::{SinkExt as _, StreamExt as _};
+use mqtt_codec_kit::{
+    common::{QualityOfService, TopicFilter},
+    v4::{
+        control::ConnectReturnCode,
+        packet::{
+            suback::SubscribeReturnCode, ConnectPacket, DisconnectPacket, MqttCodec, PingreqPacket,
+            PubackPacket, PubcompPacket, PublishPacket, PubrecPacket, PubrelPacket,
+            SubscribePacket, VariablePacket,
+        },
+    },
+};
+use tokio::net::TcpStream;
+use tokio_util::codec::Framed;
+
+use super::{ConnectOptions, Error, Incoming, Message, MessageProperties, Outgoing};
+
+pub(super) struct Transport(Framed<TcpStream, MqttCodec>);
+
+impl Transport {
+    /// Sends CONNECT and waits for CONNACK, returns whether the broker had a session
+    pub async fn connect(
+        stream: TcpStream,
+        options: &ConnectOptions,
+    ) -> Result<(Self, bool), Error> {
+        let mut builder = ConnectPacket::builder(options.client_id.as_str())
+            .keep_alive(options.keep_alive)
+            .clean_session(options.clean_start);
+        if let Some(username) = &options.username {
+            builder = builder.username(username.as_str());
+        }
+        if let Some(password) = &options.password {
+            builder = builder.password(password.as_str());
+        }
+
+        let mut framed = Framed::new(stream, MqttCodec::new());
+        framed.send(builder.build()?).await?;
+        match framed.next().await {
+            Some(Ok(VariablePacket::ConnackPacket(packet))) => match packet.connect_return_code() {
+                ConnectReturnCode::ConnectionAccepted => {
+                    Ok((Self(framed), packet.connack_flags().session_present))
+                }
+                code => Err(Error::Refused(code.to_string())),
+            },
+            Some(Ok(packet)) => Err(Error::Unexpected(format!("{packet:?}"))),
+            Some(Err(err)) => Err(err.into()),
+            None => Err(Error::Closed),
+        }
+    }
+
+    pub async fn send(&mut self, packet: Outgoing<'_>) -> Result<(), Error> {
+        let packet: VariablePacket = match packet {
+            Outgoing::Publish { message, packet_id } => {
+                let mut builder = PublishPacket::builder()
+                    .topic_name(message.topic.as_str())
+                    .qos(message.qos)
+                    .retain(message.retain)
+                    .payload(message.payload.as_slice());
+                if let Some(packet_id) = packet_id {
+                    builder = builder.packet_identifier(packet_id);
+                }
+                builder.build()?.into()
+            }
+            Outgoing::Puback(packet_id) => PubackPacket::new(packet_id).into(),
+            Outgoing::Pubrec(packet_id) => PubrecPacket::new(packet_id).into(),
+            Outgoing::Pubrel(packet_id) => PubrelPacket::new(packet_id).into(),
+            Outgoing::Pubcomp(packet_id) => PubcompPacket::new(packet_id).into(),
+            Outgoing::Subscribe { packet_id, filters } => {
+                let filters = filters
+                    .iter()
+                    .map(|(filter, qos)| Ok((TopicFilter::new(filter.as_str())?, *qos)))
+                    .collect::<Result<_, Error>>()?;
+                SubscribePacket::new(packet_id, filters).into()
+            }
+            Outgoing::Pingreq => PingreqPacket::new().into(),
+            Outgoing::Disconnect => DisconnectPacket::new().into(),
+        };
+        Ok(self.0.send(packet).await?)
+    }
+
+    pub async fn next(&mut self) -> Result<Incoming, Error> {
+        let packet = match self.0.next().await {
+            Some(packet) => packet?,
+            None => return Err(Error::Closed),
+        };
+        let incoming = match packet {
+            VariablePacket::PublishPacket(packet) => {
+                let (qos, packet_id) = packet.qos().split();
+                Incoming::Publish {
+                    message: Message {
+                        topic: packet.topic_name().to_string(),
+                        payload: packet.payload().to_vec(),
+                        qos,
+                        retain: packet.retain(),
+                        properties: MessageProperties::default(),
+                    },
+                    packet_id,
+                }
+            }
+            VariablePacket::PubackPacket(packet) => {
+                Incoming::Puback(packet.packet_identifier(), Ok(()))
+            }
+            VariablePacket::PubrecPacket(packet) => {
+                Incoming::Pubrec(packet.packet_identifier(), Ok(()))
+            }
+            VariablePacket::PubrelPacket(packet) => Incoming::Pubrel(packet.packet_identifier()),
+            VariablePacket::PubcompPacket(packet) => Incoming::Pubcomp(packet.packet_identifier()),
+            VariablePacket::SubackPacket(packet) => {
+                let granted = packet
+                    .return_codes()
+                    .iter()
+                    .map(|code| match code {
+                        SubscribeReturnCode::MaximumQoSLevel0 => Ok(QualityOfService::Level0),
+                        SubscribeReturnCode::MaximumQoSLevel1 => Ok(QualityOfService::Level1),
+                        SubscribeReturnCode::MaximumQoSLevel2 => Ok(QualityOfService::Level2),
+                        SubscribeReturnCode::Failure => Err("failure".to_owned()),
+                    })
+                    .collect();
+                Incoming::Suback(packet.packet_identifier(), granted)
+            }
+            VariablePacket::PingrespPacket(_) => Incoming::Pingresp,
+            packet => Incoming::Other(format!("{packet:?}")),
+        };
+        Ok(incoming)
+    }
+}
@@ -0,0 +1,185 @@
+use futures::{SinkExt as _, StreamExt as _};
+use mqtt_codec_kit::{
+    common::{QualityOfService, TopicFilter},
+    v5::{
+        control::{ConnectReasonCode, DisconnectReasonCode},
+        packet::{
+            suback::SubscribeReasonCode, subscribe::SubscribeOptions, ConnectPacket,
+            DisconnectPacket, MqttCodec, PingreqPacket, PubackPacket, PubcompPacket, PublishPacket,
+            PubrecPacket, PubrelPacket, SubscribePacket, VariablePacket,
+        },
+    },
+};
+use tokio::net::TcpStream;
+use tokio_util::codec::Framed;
+
+use super::{ConnectOptions, Error, Incoming, Message, MessageProperties, Outgoing};
+
+pub(super) struct Transport(Framed<TcpStream, MqttCodec>);
+
+/// Reason codes below 0x80 report a success
+fn check_reason<C: Copy + ToString>(code: C) -> Result<(), String>
+where
+    u8: From<C>,
+{
+    if u8::from(code) < 0x80 {
+        Ok(())
+    } else {
+        Err(code.to_string())
+    }
+}
+
+impl Transport {
+    /// Sends CONNECT and waits for CONNACK, returns whether the broker had a session
+    pub async fn connect(
+        stream: TcpStream,
+        options: &ConnectOptions,
+    ) -> Result<(Self, bool), Error> {
+        let mut builder = ConnectPacket::builder(options.client_id.as_str())
+            .keep_alive(options.keep_alive)
+            .clean_start(options.clean_start);
+        if let Some(username) = &options.username {
+            builder = builder.username(username.as_str());
+        }
+        if let Some(password) = &options.password {
+            builder = builder.password(password.as_str());
+        }
+        if let Some(session_expiry_interval) = options.session_expiry_interval {
+            builder = builder.session_expiry_interval(session_expiry_interval);
+        }
+        for (key, value) in &options.user_properties {
+            builder = builder.user_property(key.as_str(), value.as_str());
+        }
+
+        let mut framed = Framed::new(stream, MqttCodec::new());
+        framed.send(builder.build()?).await?;
+        match framed.next().await {
+            Some(Ok(VariablePacket::ConnackPacket(packet))) => match packet.connect_reason_code() {
+                ConnectReasonCode::Success => {
+                    Ok((Self(framed), packet.connack_flags().session_present))
+                }
+                code => Err(Error::Refused(code.to_string())),
+            },
+            Some(Ok(packet)) => Err(Error::Unexpected(format!("{packet:?}"))),
+            Some(Err(err)) => Err(err.into()),
+            None => Err(Error::Closed),
+        }
+    }
+
+    pub async fn send(&mut self, packet: Outgoing<'_>) -> Result<(), Error> {
+        let packet: VariablePacket = match packet {
+            Outgoing::Publish { message, packet_id } => {
+                let properties = &message.properties;
+                let mut builder = PublishPacket::builder()
+                    .topic_name(message.topic.as_str())
+                    .qos(message.qos)
+                    .retain(message.retain)
+                    .payload(message.payload.as_slice());
+                if let Some(packet_id) = packet_id {
+                    builder = builder.packet_identifier(packet_id);
+                }
+                if let Some(indicator) = properties.payload_format_indicator {
+                    builder = builder.payload_format_indicator(indicator);
+                }
+                if let Some(interval) = properties.message_expiry_interval {
+                    builder = builder.message_expiry_interval(interval);
+                }
+                if let Some(content_type) = &properties.content_type {
+                    builder = builder.content_type(content_type.as_str());
+                }
+                if let Some(response_topic) = &properties.response_topic {
+                    builder = builder.response_topic(response_topic.as_str());
+                }
+                if let Some(correlation_data) = &properties.correlation_data {
+                    builder = builder.correlation_data(correlation_data.as_slice());
+                }
+                for (key, value) in &properties.user_properties {
+                    builder = builder.user_property(key.as_str(), value.as_str());
+                }
+                builder.build()?.into()
+            }
+            Outgoing::Puback(packet_id) => PubackPacket::new_success(packet_id).into(),
+            Outgoing::Pubrec(packet_id) => PubrecPacket::new_success(packet_id).into(),
+            Outgoing::Pubrel(packet_id) => PubrelPacket::new_success(packet_id).into(),
+            Outgoing::Pubcomp(packet_id) => PubcompPacket::new_success(packet_id).into(),
+            Outgoing::Subscribe { packet_id, filters } => {
+                let filters = filters
+                    .iter()
+                    .map(|(filter, qos)| {
+                        let mut options = SubscribeOptions::default();
+                        options.set_qos(*qos);
+                        Ok((TopicFilter::new(filter.as_str())?, options))
+                    })
+                    .collect::<Result<_, Error>>()?;
+                SubscribePacket::new(packet_id, filters).into()
+            }
+            Outgoing::Pingreq => PingreqPacket::new().into(),
+            Outgoing::Disconnect => {
+                DisconnectPacket::new(DisconnectReasonCode::NormalDisconnection).into()
+            }
+        };
+        Ok(self.0.send(packet).await?)
+    }
+
+    pub async fn next(&mut self) -> Result<Incoming, Error> {
+        let packet = match self.0.next().await {
+            Some(packet) => packet?,
+            None => return Err(Error::Closed),
+        };
+        let incoming = match packet {
+            VariablePacket::PublishPacket(packet) => {
+                let (qos, packet_id) = packet.qos().split();
+                let properties = packet.properties();
+                Incoming::Publish {
+                    message: Message {
+                        topic: packet.topic_name().to_string(),
+                        payload: packet.payload().to_vec(),
+                        qos,
+                        retain: packet.retain(),
+                        properties: MessageProperties {
+                            payload_format_indicator: properties.payload_format_indicator(),
+                            message_expiry_interval: properties.message_expiry_interval(),
+                            content_type: properties.content_type().clone(),
+                            response_topic: properties.response_topic().clone(),
+                            correlation_data: properties
+                                .correlation_data()
+                                .as_ref()
+                                .map(|data| data.0.clone()),
+                            user_properties: properties.user_properties().to_vec(),
+                        },
+                    },
+                    packet_id,
+                }
+            }
+            VariablePacket::PubackPacket(packet) => Incoming::Puback(
+                packet.packet_identifier(),
+                check_reason(packet.reason_code()),
+            ),
+            VariablePacket::PubrecPacket(packet) => Incoming::Pubrec(
+                packet.packet_identifier(),
+                check_reason(packet.reason_code()),
+            ),
+            VariablePacket::PubrelPacket(packet) => Incoming::Pubrel(packet.packet_identifier()),
+            VariablePacket::PubcompPacket(packet) => Incoming::Pubcomp(packet.packet_identifier()),
+            VariablePacket::SubackPacket(packet) => {
+                let granted = packet
+                    .reason_code()
+                    .iter()
+                    .map(|code| match code {
+                        SubscribeReasonCode::GrantedQos0 => Ok(QualityOfService::Level0),
+                        SubscribeReasonCode::GrantedQos1 => Ok(QualityOfService::Level1),
+                        SubscribeReasonCode::GrantedQos2 => Ok(QualityOfService::Level2),
+                        code => Err(format!("{code:?}")),
+                    })
+                    .collect();
+                Incoming::Suback(packet.packet_identifier(), granted)
+            }
+            VariablePacket::PingrespPacket(_) => Incoming::Pingresp,
+            VariablePacket::DisconnectPacket(packet) => {
+                Incoming::Disconnect(packet.reason_code().to_string())
+            }
+            packet => Incoming::Other(format!("{packet:?}")),
+        };
+        Ok(incoming)
+    }
+}
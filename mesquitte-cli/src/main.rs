@@ -0,0 +1,39 @@
+use std::{env, process::ExitCode};
+
+use args::Command;
+
+mod args;
+mod bench;
+mod client;
+mod publish;
+mod session;
+mod subscribe;
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let command = match args::parse(env::args().skip(1)) {
+        Ok(command) => command,
+        Err(err) => {
+            eprintln!("error: {err}\n\n{}", args::USAGE);
+            return ExitCode::from(2);
+        }
+    };
+
+    let result = match command {
+        Command::Pub(args) => publish::run(args).await,
+        Command::Sub(args) => subscribe::run(args).await,
+        Command::Bench(args) => bench::run(args).await,
+        Command::Session(args) => session::run(args).await,
+        Command::Help => {
+            print!("{}", args::USAGE);
+            Ok(())
+        }
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("error: {err}");
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,24 @@
+use tokio::time;
+
+use crate::{
+    args::PubArgs,
+    client::{Client, Error, Message},
+};
+
+pub async fn run(args: PubArgs) -> Result<(), Error> {
+    let mut client = Client::connect(&args.connect).await?;
+    let message = Message {
+        topic: args.topic,
+        payload: args.payload,
+        qos: args.qos,
+        retain: args.retain,
+        properties: args.properties,
+    };
+    for i in 0..args.count {
+        if i > 0 && !args.interval.is_zero() {
+            time::sleep(args.interval).await;
+        }
+        client.publish(&message).await?;
+    }
+    client.disconnect().await
+}
@@ -0,0 +1,92 @@
+//! Session export and import through `/api/v1/clients/{id}/session` of the HTTP API
+
+use std::{fmt::Write as _, fs, io};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+use crate::{
+    args::{SessionAction, SessionArgs},
+    client::Error,
+};
+
+/// The client identifier as a path segment, with every byte but the unreserved ones escaped
+fn path_segment(client_id: &str) -> String {
+    let mut segment = String::with_capacity(client_id.len());
+    for byte in client_id.bytes() {
+        if byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'.' | b'_' | b'~') {
+            segment.push(byte as char);
+        } else {
+            let _ = write!(segment, "%{byte:02X}");
+        }
+    }
+    segment
+}
+
+/// Sends one request over a connection of its own and answers the body of a 2xx response, the
+/// API answers with a content length so the body ends with the connection
+async fn request(args: &SessionArgs, method: &str, body: &str) -> Result<String, Error> {
+    let mut stream = TcpStream::connect((args.host.as_str(), args.port)).await?;
+    let mut request = format!(
+        "{method} /api/v1/clients/{}/session HTTP/1.1\r\nhost: {}:{}\r\n\
+         content-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n",
+        path_segment(&args.client_id),
+        args.host,
+        args.port,
+        body.len()
+    );
+    if let Some(token) = &args.token {
+        let _ = write!(request, "authorization: Bearer {token}\r\n");
+    }
+    request.push_str("\r\n");
+    request.push_str(body);
+    stream.write_all(request.as_bytes()).await?;
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await?;
+    let (head, body) = response.split_once("\r\n\r\n").ok_or(Error::Closed)?;
+    let status = head
+        .split(' ')
+        .nth(1)
+        .and_then(|status| status.parse().ok())
+        .ok_or_else(|| Error::Unexpected(head.lines().next().unwrap_or_default().to_owned()))?;
+    if !(200..300).contains(&status) {
+        return Err(Error::Http(status, body.trim().to_owned()));
+    }
+    Ok(body.to_owned())
+}
+
+pub async fn run(args: SessionArgs) -> Result<(), Error> {
+    match args.action {
+        SessionAction::Export => {
+            let export = request(&args, "GET", "").await?;
+            match &args.file {
+                Some(path) => fs::write(path, export)?,
+                None => println!("{export}"),
+            }
+        }
+        SessionAction::Import => {
+            let export = match &args.file {
+                Some(path) => fs::read_to_string(path)?,
+                None => io::read_to_string(io::stdin())?,
+            };
+            request(&args, "PUT", &export).await?;
+            eprintln!("imported the session of {}", args.client_id);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_path_segment() {
+        assert_eq!(path_segment("sensor-1_a.b~c"), "sensor-1_a.b~c");
+        assert_eq!(path_segment("a/b c"), "a%2Fb%20c");
+        assert_eq!(path_segment("é"), "%C3%A9");
+    }
+}
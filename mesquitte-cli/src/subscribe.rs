@@ -0,0 +1,40 @@
+use std::io;
+
+use crate::{
+    args::SubArgs,
+    client::{Client, Error, Message},
+};
+
+fn print_message(out: &mut impl io::Write, message: &Message, verbose: bool) -> io::Result<()> {
+    if verbose {
+        write!(out, "{} ", message.topic)?;
+    }
+    out.write_all(&message.payload)?;
+    writeln!(out)?;
+    if verbose && !message.properties.is_empty() {
+        writeln!(out, "  {:?}", message.properties)?;
+    }
+    out.flush()
+}
+
+pub async fn run(args: SubArgs) -> Result<(), Error> {
+    let mut client = Client::connect(&args.connect).await?;
+    let filters: Vec<_> = args
+        .filters
+        .into_iter()
+        .map(|filter| (filter, args.qos))
+        .collect();
+    client.subscribe(&filters).await?;
+    if args.verbose && client.session_present() {
+        eprintln!("resumed the existing session");
+    }
+
+    let mut stdout = io::stdout().lock();
+    let mut received = 0;
+    while args.count.is_none_or(|count| received < count) {
+        let message = client.recv().await?;
+        print_message(&mut stdout, &message, args.verbose)?;
+        received += 1;
+    }
+    client.disconnect().await
+}
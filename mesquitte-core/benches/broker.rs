@@ -0,0 +1,213 @@
+//! End to end throughput of a TCP broker with the memory storage
+//!
+//! Runs two scenarios against a broker on a local port, with MQTT 3.1.1 clients:
+//!
+//! - QoS 1 round trip: one message at a time from the publisher to a single subscriber, the
+//!   latency covers the publisher's PUBACK and the delivery to the subscriber
+//! - fan-out: every QoS 1 message is delivered to all subscribers
+//!
+//! ```text
+//! cargo bench -p mesquitte-core --bench broker -- [messages] [subscribers]
+//! ```
+
+use std::{
+    env,
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use futures::{SinkExt as _, StreamExt as _};
+use mesquitte_core::{
+    server::{config::ServerConfig, state::GlobalState, tcp::server::TcpServer},
+    store::{
+        memory::{
+            message::MessageMemoryStore, retain::RetainMessageMemoryStore, topic::TopicMemoryStore,
+            MemoryStore,
+        },
+        queue::QueueConfig,
+        Storage,
+    },
+};
+use mqtt_codec_kit::{
+    common::{qos::QoSWithPacketIdentifier, QualityOfService, TopicFilter, TopicName},
+    v4::{
+        control::ConnectReturnCode,
+        packet::{
+            ConnectPacket, MqttCodec, PubackPacket, PublishPacket, SubscribePacket, VariablePacket,
+        },
+    },
+};
+use tokio::net::TcpStream;
+use tokio_util::codec::Framed;
+
+type Client = Framed<TcpStream, MqttCodec>;
+
+const PAYLOAD_SIZE: usize = 256;
+
+fn main() {
+    let mut args = env::args().skip(1).filter(|arg| arg != "--bench");
+    let messages: usize = args.next().and_then(|n| n.parse().ok()).unwrap_or(10_000);
+    let subscribers: usize = args.next().and_then(|n| n.parse().ok()).unwrap_or(100);
+
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    runtime.block_on(async {
+        let addr = start_broker(messages).await;
+        round_trip(addr, messages).await;
+        fan_out(addr, messages, subscribers).await;
+    });
+}
+
+async fn start_broker(messages: usize) -> SocketAddr {
+    let storage = Storage::new(MemoryStore::new(
+        MessageMemoryStore::new(102400, 30, 3),
+        RetainMessageMemoryStore::default(),
+        TopicMemoryStore::default(),
+    ));
+    // the subscribers may fall behind the publisher, no message may be dropped for the counts
+    // to add up
+    let global = Arc::new(GlobalState::new(storage).with_queue_config(QueueConfig {
+        capacity: messages,
+        high_watermark: messages,
+        ..Default::default()
+    }));
+    let config = ServerConfig::new("127.0.0.1:0".parse().unwrap(), None, "4").unwrap();
+    let mut broker = TcpServer::new(config, global).await.unwrap();
+    let addr = broker.bind().unwrap()[0];
+    tokio::spawn(broker.serve());
+    addr
+}
+
+async fn connect(addr: SocketAddr, client_id: &str) -> Client {
+    let mut client = Framed::new(TcpStream::connect(addr).await.unwrap(), MqttCodec::new());
+    let mut packet = ConnectPacket::new(client_id);
+    packet.set_clean_session(true);
+    client.send(packet).await.unwrap();
+    match client.next().await {
+        Some(Ok(VariablePacket::ConnackPacket(ack)))
+            if ack.connect_return_code() == ConnectReturnCode::ConnectionAccepted => {}
+        other => panic!("{client_id} not connected: {other:?}"),
+    }
+    client
+}
+
+async fn subscribe(client: &mut Client, filter: &str) {
+    let filter = TopicFilter::new(filter).unwrap();
+    client
+        .send(SubscribePacket::new(
+            1,
+            vec![(filter, QualityOfService::Level1)],
+        ))
+        .await
+        .unwrap();
+    match client.next().await {
+        Some(Ok(VariablePacket::SubackPacket(_))) => {}
+        other => panic!("not subscribed: {other:?}"),
+    }
+}
+
+/// Waits for the next PUBLISH and acknowledges it
+async fn receive(client: &mut Client) {
+    loop {
+        match client.next().await {
+            Some(Ok(VariablePacket::PublishPacket(packet))) => {
+                if let (_, Some(packet_id)) = packet.qos().split() {
+                    client.send(PubackPacket::new(packet_id)).await.unwrap();
+                }
+                return;
+            }
+            Some(Ok(_)) => continue,
+            other => panic!("subscriber closed: {other:?}"),
+        }
+    }
+}
+
+/// Waits for the PUBACK of the last PUBLISH
+async fn acknowledged(client: &mut Client) {
+    loop {
+        match client.next().await {
+            Some(Ok(VariablePacket::PubackPacket(_))) => return,
+            Some(Ok(_)) => continue,
+            other => panic!("publisher closed: {other:?}"),
+        }
+    }
+}
+
+fn publish_packet(topic_name: &TopicName, seq: usize) -> PublishPacket {
+    // packet identifiers are 1..=65535
+    let packet_id = (seq % u16::MAX as usize) as u16 + 1;
+    PublishPacket::new(
+        topic_name.clone(),
+        QoSWithPacketIdentifier::Level1(packet_id),
+        vec![0u8; PAYLOAD_SIZE],
+    )
+}
+
+async fn round_trip(addr: SocketAddr, messages: usize) {
+    let mut subscriber = connect(addr, "rtt-sub").await;
+    subscribe(&mut subscriber, "bench/rtt").await;
+    let mut publisher = connect(addr, "rtt-pub").await;
+    let topic_name = TopicName::new("bench/rtt").unwrap();
+
+    let mut latencies = Vec::with_capacity(messages);
+    for seq in 0..messages {
+        let start = Instant::now();
+        publisher
+            .send(publish_packet(&topic_name, seq))
+            .await
+            .unwrap();
+        tokio::join!(acknowledged(&mut publisher), receive(&mut subscriber));
+        latencies.push(start.elapsed());
+    }
+
+    latencies.sort_unstable();
+    let total: Duration = latencies.iter().sum();
+    println!(
+        "qos1 round trip, {messages} messages: mean {:?}, p50 {:?}, p99 {:?}, max {:?}",
+        total / messages as u32,
+        percentile(&latencies, 50),
+        percentile(&latencies, 99),
+        latencies[latencies.len() - 1],
+    );
+}
+
+fn percentile(sorted: &[Duration], p: usize) -> Duration {
+    sorted[(sorted.len() * p / 100).min(sorted.len() - 1)]
+}
+
+async fn fan_out(addr: SocketAddr, messages: usize, subscribers: usize) {
+    let mut tasks = Vec::with_capacity(subscribers);
+    for i in 0..subscribers {
+        let mut subscriber = connect(addr, &format!("fan-out-sub-{i}")).await;
+        subscribe(&mut subscriber, "bench/fan-out/#").await;
+        tasks.push(tokio::spawn(async move {
+            for _ in 0..messages {
+                receive(&mut subscriber).await;
+            }
+        }));
+    }
+    let mut publisher = connect(addr, "fan-out-pub").await;
+    let topic_name = TopicName::new("bench/fan-out/data").unwrap();
+
+    let start = Instant::now();
+    for seq in 0..messages {
+        publisher
+            .send(publish_packet(&topic_name, seq))
+            .await
+            .unwrap();
+        acknowledged(&mut publisher).await;
+    }
+    for task in tasks {
+        task.await.unwrap();
+    }
+    let elapsed = start.elapsed();
+
+    let deliveries = messages as f64 * subscribers as f64;
+    println!(
+        "fan-out, {messages} messages x {subscribers} subscribers: {deliveries} deliveries in {elapsed:?}, {:.0} deliveries/s",
+        deliveries / elapsed.as_secs_f64()
+    );
+}
@@ -0,0 +1,77 @@
+//! Throughput of the memory message store with many sessions acknowledging messages at once
+//!
+//! Every session saves and acknowledges QoS 1 and QoS 2 messages on its own task, like the
+//! protocol loops of connected clients do:
+//!
+//! ```text
+//! cargo bench -p mesquitte-core --bench message_store -- [sessions] [rounds]
+//! ```
+
+use std::{env, sync::Arc, time::Instant};
+
+use mesquitte_core::store::{
+    memory::message::MessageMemoryStore,
+    message::{MessageStore, PendingPublishMessage, PublishMessage},
+};
+use mqtt_codec_kit::{
+    common::{qos::QoSWithPacketIdentifier, TopicName},
+    v4::packet::PublishPacket,
+};
+
+fn main() {
+    let mut args = env::args().skip(1).filter(|arg| arg != "--bench");
+    let sessions: usize = args.next().and_then(|n| n.parse().ok()).unwrap_or(100_000);
+    let rounds: u16 = args.next().and_then(|n| n.parse().ok()).unwrap_or(10);
+
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let store = Arc::new(MessageMemoryStore::new(1024, 30, 3));
+    let packet = PublishPacket::new(
+        TopicName::new("bench/topic").unwrap(),
+        QoSWithPacketIdentifier::Level1(1),
+        vec![0u8; 64],
+    );
+    let message: PublishMessage = (&packet).into();
+
+    let start = Instant::now();
+    runtime.block_on(async {
+        let tasks: Vec<_> = (0..sessions)
+            .map(|session| {
+                let store = store.clone();
+                let message = message.clone();
+                tokio::spawn(async move {
+                    let client_id = format!("client-{session}");
+                    for packet_id in 1..=rounds {
+                        let pending = PendingPublishMessage::new(
+                            QoSWithPacketIdentifier::Level1(packet_id),
+                            message.clone(),
+                        );
+                        store
+                            .save_pending_publish_message(&client_id, packet_id, pending)
+                            .await
+                            .unwrap();
+                        store
+                            .save_publish_message(&client_id, packet_id, message.clone())
+                            .await
+                            .unwrap();
+                        store.puback(&client_id, packet_id).await.unwrap();
+                        store.pubrel(&client_id, packet_id).await.unwrap();
+                    }
+                    store.clear_all(&client_id).await.unwrap();
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+    });
+    let elapsed = start.elapsed();
+
+    let ops = sessions as f64 * rounds as f64 * 4.0;
+    println!(
+        "{sessions} sessions x {rounds} rounds: {ops} store operations in {elapsed:?}, {:.0} ops/s",
+        ops / elapsed.as_secs_f64()
+    );
+}
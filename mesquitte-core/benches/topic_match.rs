@@ -0,0 +1,57 @@
+//! Topic match throughput of the memory topic store with a growing number of subscriptions
+//!
+//! ```text
+//! cargo bench -p mesquitte-core --bench topic_match
+//! ```
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use futures::executor::block_on;
+use mesquitte_core::store::{memory::topic::TopicMemoryStore, topic::TopicStore};
+use mqtt_codec_kit::common::{QualityOfService, TopicFilter, TopicName};
+
+const SUBSCRIPTIONS: [usize; 3] = [1_000, 10_000, 100_000];
+
+/// Subscribes one client per device, every tenth with a single level wildcard and every
+/// hundredth with a multi level wildcard
+fn store(subscriptions: usize) -> TopicMemoryStore {
+    let store = TopicMemoryStore::default();
+    for i in 0..subscriptions {
+        let filter = match i % 100 {
+            0 => format!("tenant-{}/#", i % 10),
+            n if n % 10 == 0 => format!("tenant-{}/+/device-{i}/state", i % 10),
+            _ => format!("tenant-{}/site-{}/device-{i}/state", i % 10, i % 7),
+        };
+        let filter = TopicFilter::new(filter).unwrap();
+        block_on(store.subscribe(
+            &format!("client-{i}"),
+            &filter,
+            QualityOfService::Level1.into(),
+        ))
+        .unwrap();
+    }
+    store
+}
+
+fn match_topic(c: &mut Criterion) {
+    let mut group = c.benchmark_group("match_topic");
+    group.throughput(Throughput::Elements(1));
+    for subscriptions in SUBSCRIPTIONS {
+        let store = store(subscriptions);
+        let exact = TopicName::new("tenant-1/site-4/device-11/state").unwrap();
+        let unmatched = TopicName::new("tenant-1/site-4/device-0/config").unwrap();
+        group.bench_with_input(
+            BenchmarkId::new("exact", subscriptions),
+            &exact,
+            |b, topic| b.iter(|| block_on(store.match_topic(topic)).unwrap()),
+        );
+        group.bench_with_input(
+            BenchmarkId::new("unmatched", subscriptions),
+            &unmatched,
+            |b, topic| b.iter(|| block_on(store.match_topic(topic)).unwrap()),
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, match_topic);
+criterion_main!(benches);
@@ -0,0 +1,4 @@
+fn main() {
+    #[cfg(feature = "grpc-api")]
+    tonic_build::compile_protos("proto/mesquitte.proto").expect("compile proto/mesquitte.proto");
+}
@@ -1,4 +1,4 @@
-use std::{env, sync::OnceLock};
+use std::{env, sync::Arc};
 
 use mesquitte_core::{
     broker::Broker,
@@ -30,15 +30,12 @@ async fn main() {
 
     let mem_store = MemoryStore::new(message_store, retain_message_store, topic_store);
     let storage = Storage::new(mem_store);
-    let global = GlobalState::new(storage);
-
-    static GLOBAL: OnceLock<GlobalState<MemoryStore>> = OnceLock::new();
-    let _ = GLOBAL.set(global);
+    let global = Arc::new(GlobalState::new(storage));
 
     let config = ServerConfig::new("0.0.0.0:1883".parse().unwrap(), None, "4").unwrap();
-    let mqtt = TcpServer::new(config, GLOBAL.get().unwrap()).await.unwrap();
+    let mqtt = TcpServer::new(config, global.clone()).await.unwrap();
     let config = ServerConfig::new("0.0.0.0:8883".parse().unwrap(), None, "4").unwrap();
-    let ws = WsServer::new(config, GLOBAL.get().unwrap()).await.unwrap();
+    let ws = WsServer::new(config, global.clone()).await.unwrap();
     let tls = TlsConfig::new(
         None,
         "mesquitte-core/examples/certs/cert.pem".parse().unwrap(),
@@ -46,11 +43,13 @@ async fn main() {
         false,
     );
     let config = ServerConfig::new("0.0.0.0:6883".parse().unwrap(), Some(tls), "4").unwrap();
-    let quic = QuicServer::new(config, GLOBAL.get().unwrap()).unwrap();
+    let quic = QuicServer::new(config, global).unwrap();
     let broker = Broker::<MemoryStore>::default()
         .with_mqtt(mqtt)
         .with_ws(ws)
         .with_quic(quic);
-    broker.serve().await.unwrap();
-    signal::ctrl_c().await.expect("failed to listen for event");
+    tokio::select! {
+        ret = broker.serve() => ret.unwrap(),
+        ret = signal::ctrl_c() => ret.expect("failed to listen for event"),
+    }
 }
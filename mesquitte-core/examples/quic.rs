@@ -1,4 +1,4 @@
-use std::{env, sync::OnceLock};
+use std::{env, sync::Arc};
 
 use mesquitte_core::{
     server::{
@@ -26,9 +26,7 @@ async fn main() {
 
     let mem_store = MemoryStore::new(message_store, retain_message_store, topic_store);
     let storage = Storage::new(mem_store);
-    let global = GlobalState::new(storage);
-
-    static GLOBAL: OnceLock<GlobalState<MemoryStore>> = OnceLock::new();
+    let global = Arc::new(GlobalState::new(storage));
 
     let tls = TlsConfig::new(
         None,
@@ -37,6 +35,6 @@ async fn main() {
         false,
     );
     let config = ServerConfig::new("0.0.0.0:1883".parse().unwrap(), Some(tls), "4").unwrap();
-    let broker = QuicServer::new(config, GLOBAL.get_or_init(|| global)).unwrap();
+    let broker = QuicServer::new(config, global).unwrap();
     broker.serve().await.unwrap();
 }
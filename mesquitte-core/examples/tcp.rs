@@ -1,4 +1,4 @@
-use std::{env, sync::OnceLock};
+use std::{env, sync::Arc};
 
 use log::info;
 use mesquitte_core::{
@@ -23,14 +23,12 @@ async fn main() {
 
     let mem_store = MemoryStore::new(message_store, retain_message_store, topic_store);
     let storage = Storage::new(mem_store);
-    let global = GlobalState::new(storage);
+    let global = Arc::new(GlobalState::new(storage));
 
-    static GLOBAL: OnceLock<GlobalState<MemoryStore>> = OnceLock::new();
-
-    let config = ServerConfig::new("0.0.0.0:1883".parse().unwrap(), None, "4").unwrap();
+    let config = ServerConfig::new("0.0.0.0:1883".parse().unwrap(), None, "4")
+        .unwrap()
+        .with_addr("[::]:1883".parse().unwrap());
     info!("server config: {:?}", config);
-    let broker = TcpServer::new(config, GLOBAL.get_or_init(|| global))
-        .await
-        .unwrap();
+    let broker = TcpServer::new(config, global).await.unwrap();
     broker.serve().await.unwrap();
 }
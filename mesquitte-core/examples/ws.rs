@@ -1,4 +1,4 @@
-use std::{env, io, sync::OnceLock};
+use std::{env, io, sync::Arc};
 
 use mesquitte_core::{
     server::{config::ServerConfig, state::GlobalState, ws::server::WsServer},
@@ -22,14 +22,10 @@ async fn main() -> io::Result<()> {
 
     let mem_store = MemoryStore::new(message_store, retain_message_store, topic_store);
     let storage = Storage::new(mem_store);
-    let global = GlobalState::new(storage);
-
-    static GLOBAL: OnceLock<GlobalState<MemoryStore>> = OnceLock::new();
+    let global = Arc::new(GlobalState::new(storage));
 
     let config = ServerConfig::new("0.0.0.0:8883".parse().unwrap(), None, "4").unwrap();
-    let broker = WsServer::new(config, GLOBAL.get_or_init(|| global))
-        .await
-        .unwrap();
+    let broker = WsServer::new(config, global).await.unwrap();
     broker.serve().await.unwrap();
     Ok(())
 }
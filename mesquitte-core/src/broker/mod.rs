@@ -1,5 +1,28 @@
+use std::{
+    future::Future,
+    net::SocketAddr,
+    panic,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use tokio::task::{self, JoinSet};
+
+#[cfg(any(feature = "mqtt", feature = "ws", feature = "wss", feature = "quic"))]
+use crate::server::config::ServerConfig;
+#[cfg(feature = "mqtt-sn")]
+use crate::server::mqtt_sn::server::MqttSnServer;
+#[cfg(feature = "quic")]
+use crate::server::quic::server::QuicServer;
+#[cfg(any(feature = "mqtt", feature = "mqtts"))]
+use crate::server::tcp::server::TcpServer;
+#[cfg(any(feature = "ws", feature = "wss"))]
+use crate::server::ws::server::WsServer;
 use crate::{
-    server::{quic::server::QuicServer, tcp::server::TcpServer, ws::server::WsServer, Error},
+    error, info,
+    server::Error,
     store::{message::MessageStore, retain::RetainMessageStore, topic::TopicStore},
 };
 
@@ -18,6 +41,23 @@ where
     wss: Option<WsServer<S>>,
     #[cfg(feature = "quic")]
     quic: Option<QuicServer<S>>,
+    #[cfg(feature = "mqtt-sn")]
+    mqtt_sn: Option<MqttSnServer<S>>,
+    bound: ListenersBound,
+}
+
+/// Whether the listeners of a [`Broker`] are bound and served, e.g. for a readiness probe
+#[derive(Clone, Debug, Default)]
+pub struct ListenersBound(Arc<AtomicBool>);
+
+impl ListenersBound {
+    pub fn get(&self) -> bool {
+        self.0.load(Ordering::Acquire)
+    }
+
+    pub(crate) fn set(&self, bound: bool) {
+        self.0.store(bound, Ordering::Release);
+    }
 }
 
 impl<S> Broker<S>
@@ -54,27 +94,417 @@ where
         self
     }
 
-    pub async fn serve(self) -> Result<(), Error> {
+    #[cfg(feature = "mqtt-sn")]
+    pub fn with_mqtt_sn(mut self, mqtt_sn: MqttSnServer<S>) -> Self {
+        self.mqtt_sn = Some(mqtt_sn);
+        self
+    }
+
+    /// Handle telling whether the listeners are bound, set once [`Broker::bind`] succeeded and
+    /// cleared when [`Broker::serve`] returns
+    pub fn listeners_bound(&self) -> ListenersBound {
+        self.bound.clone()
+    }
+
+    /// Checks the configuration of the broker, so a broker which could never serve fails here
+    /// with a [`Error::WrongConfig`] describing the problem
+    ///
+    /// At least one server must be set, mqtts, wss and quic servers need a TLS config (a ws
+    /// server too if it has TLS addresses) and no two listeners may use the same port of the
+    /// same address. [`Broker::serve`] runs the same checks.
+    #[allow(clippy::result_large_err)]
+    pub fn build(self) -> Result<Self, Error> {
+        self.validate()?;
+        Ok(self)
+    }
+
+    #[allow(clippy::result_large_err)]
+    fn validate(&self) -> Result<(), Error> {
+        // name, address and whether it is a UDP socket
+        #[allow(unused_mut)]
+        let mut listeners: Vec<(&'static str, SocketAddr, bool)> = Vec::new();
+        #[cfg(feature = "mqtt")]
+        if let Some(server) = &self.mqtt {
+            reject_cert_auth("mqtt", server.config())?;
+            listeners.extend(
+                server
+                    .config()
+                    .addrs
+                    .iter()
+                    .map(|addr| ("mqtt", *addr, false)),
+            );
+        }
+        #[cfg(feature = "mqtts")]
+        if let Some(server) = &self.mqtts {
+            require_tls("mqtts", server.has_tls())?;
+            let config = server.config();
+            if config.cert_auth
+                && config
+                    .tls
+                    .as_ref()
+                    .is_some_and(|tls| !tls.fail_if_no_peer_cert)
+            {
+                return Err(Error::WrongConfig(
+                    "mqtts certificate authentication requires client certificates".to_owned(),
+                ));
+            }
+            listeners.extend(
+                server
+                    .config()
+                    .addrs
+                    .iter()
+                    .map(|addr| ("mqtts", *addr, false)),
+            );
+        }
+        #[cfg(feature = "ws")]
+        if let Some(server) = &self.ws {
+            let config = server.config();
+            reject_cert_auth("ws", config)?;
+            if !config.tls_addrs.is_empty() {
+                require_tls("ws", server.has_tls())?;
+            }
+            listeners.extend(config.addrs.iter().map(|addr| ("ws", *addr, false)));
+            listeners.extend(config.tls_addrs.iter().map(|addr| ("ws", *addr, false)));
+        }
+        #[cfg(feature = "wss")]
+        if let Some(server) = &self.wss {
+            require_tls("wss", server.has_tls())?;
+            let config = server.config();
+            reject_cert_auth("wss", config)?;
+            listeners.extend(config.addrs.iter().map(|addr| ("wss", *addr, false)));
+            listeners.extend(config.tls_addrs.iter().map(|addr| ("wss", *addr, false)));
+        }
+        #[cfg(feature = "quic")]
+        if let Some(server) = &self.quic {
+            require_tls("quic", server.config().tls.is_some())?;
+            reject_cert_auth("quic", server.config())?;
+            listeners.extend(
+                server
+                    .config()
+                    .addrs
+                    .iter()
+                    .map(|addr| ("quic", *addr, true)),
+            );
+        }
+        #[cfg(feature = "mqtt-sn")]
+        if let Some(server) = &self.mqtt_sn {
+            listeners.extend(
+                server
+                    .config()
+                    .addrs
+                    .iter()
+                    .map(|addr| ("mqtt-sn", *addr, true)),
+            );
+        }
+
+        if listeners.is_empty() {
+            return Err(Error::WrongConfig("no server configured".to_owned()));
+        }
+        for (i, (name, addr, udp)) in listeners.iter().enumerate() {
+            let conflict = listeners[i + 1..]
+                .iter()
+                .find(|(_, other, other_udp)| udp == other_udp && conflicts(addr, other));
+            if let Some((other_name, other, _)) = conflict {
+                return Err(Error::WrongConfig(format!(
+                    "{name} address {addr} conflicts with {other_name} address {other}"
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Binds the listeners of every server, the ports assigned for port 0 are then known from
+    /// [`Broker::local_addrs`]. [`Broker::serve`] binds them itself if this was not called.
+    #[allow(clippy::result_large_err)]
+    pub fn bind(&mut self) -> Result<(), Error> {
+        #[cfg(feature = "mqtt")]
+        if let Some(server) = &mut self.mqtt {
+            server.bind()?;
+        }
+        #[cfg(feature = "mqtts")]
+        if let Some(server) = &mut self.mqtts {
+            server.bind()?;
+        }
+        #[cfg(feature = "ws")]
+        if let Some(server) = &mut self.ws {
+            server.bind()?;
+        }
+        #[cfg(feature = "wss")]
+        if let Some(server) = &mut self.wss {
+            server.bind()?;
+        }
+        #[cfg(feature = "quic")]
+        if let Some(server) = &mut self.quic {
+            server.bind()?;
+        }
+        #[cfg(feature = "mqtt-sn")]
+        if let Some(server) = &mut self.mqtt_sn {
+            server.bind()?;
+        }
+        self.bound.set(true);
+        Ok(())
+    }
+
+    /// The bound addresses with the name of their server, e.g. `("mqtt", 127.0.0.1:1883)`
+    pub fn local_addrs(&self) -> Vec<(&'static str, SocketAddr)> {
+        #[allow(unused_mut)]
+        let mut addrs = Vec::new();
+        #[cfg(feature = "mqtt")]
+        if let Some(server) = &self.mqtt {
+            addrs.extend(server.local_addrs().into_iter().map(|addr| ("mqtt", addr)));
+        }
+        #[cfg(feature = "mqtts")]
+        if let Some(server) = &self.mqtts {
+            addrs.extend(server.local_addrs().into_iter().map(|addr| ("mqtts", addr)));
+        }
+        #[cfg(feature = "ws")]
+        if let Some(server) = &self.ws {
+            addrs.extend(server.local_addrs().into_iter().map(|addr| ("ws", addr)));
+        }
+        #[cfg(feature = "wss")]
+        if let Some(server) = &self.wss {
+            addrs.extend(server.local_addrs().into_iter().map(|addr| ("wss", addr)));
+        }
+        #[cfg(feature = "quic")]
+        if let Some(server) = &self.quic {
+            addrs.extend(server.local_addrs().into_iter().map(|addr| ("quic", addr)));
+        }
+        #[cfg(feature = "mqtt-sn")]
+        if let Some(server) = &self.mqtt_sn {
+            addrs.extend(
+                server
+                    .local_addrs()
+                    .into_iter()
+                    .map(|addr| ("mqtt-sn", addr)),
+            );
+        }
+        addrs
+    }
+
+    /// Serves every configured server until one of them fails
+    ///
+    /// The listeners are bound first, a failed bind is returned right away. After that the
+    /// future only resolves when a server stops with an error, returned as [`Error::Listener`]
+    /// with the name of the server, or when every server stopped. The other servers are
+    /// stopped with it, as they are when the future is dropped. A panicking server is logged
+    /// and the panic is resumed in the caller.
+    pub async fn serve(mut self) -> Result<(), Error> {
+        self.validate()?;
+        self.bind()?;
+        for (name, addr) in self.local_addrs() {
+            info!("{name} listening on {addr}");
+        }
+        let mut servers = Servers::default();
         #[cfg(feature = "mqtt")]
-        tokio::spawn(async {
-            self.mqtt.unwrap().serve().await.unwrap();
-        });
+        if let Some(server) = self.mqtt.take() {
+            servers.spawn("mqtt", server.serve());
+        }
         #[cfg(feature = "mqtts")]
-        tokio::spawn(async {
-            self.mqtts.unwrap().serve().await.unwrap();
-        });
+        if let Some(server) = self.mqtts.take() {
+            servers.spawn("mqtts", server.serve_tls());
+        }
         #[cfg(feature = "ws")]
-        tokio::spawn(async {
-            self.ws.unwrap().serve().await.unwrap();
-        });
+        if let Some(server) = self.ws.take() {
+            servers.spawn("ws", server.serve());
+        }
         #[cfg(feature = "wss")]
-        tokio::spawn(async {
-            self.wss.unwrap().serve().await.unwrap();
-        });
+        if let Some(server) = self.wss.take() {
+            servers.spawn("wss", server.serve_tls());
+        }
         #[cfg(feature = "quic")]
-        tokio::spawn(async {
-            self.quic.unwrap().serve().await.unwrap();
-        });
+        if let Some(server) = self.quic.take() {
+            servers.spawn("quic", server.serve());
+        }
+        #[cfg(feature = "mqtt-sn")]
+        if let Some(server) = self.mqtt_sn.take() {
+            servers.spawn("mqtt-sn", server.serve());
+        }
+        let res = servers.join().await;
+        self.bound.set(false);
+        res
+    }
+}
+
+#[cfg(any(feature = "mqtts", feature = "ws", feature = "wss", feature = "quic"))]
+#[allow(clippy::result_large_err)]
+fn require_tls(name: &str, has_tls: bool) -> Result<(), Error> {
+    if has_tls {
+        Ok(())
+    } else {
+        Err(Error::WrongConfig(format!(
+            "{name} server without a TLS config"
+        )))
+    }
+}
+
+/// Only an mqtts listener authenticates clients by their certificate
+#[cfg(any(feature = "mqtt", feature = "ws", feature = "wss", feature = "quic"))]
+#[allow(clippy::result_large_err)]
+fn reject_cert_auth(name: &str, config: &ServerConfig) -> Result<(), Error> {
+    if config.cert_auth {
+        return Err(Error::WrongConfig(format!(
+            "{name} server can not authenticate by certificate"
+        )));
+    }
+    Ok(())
+}
+
+/// Whether two sockets can not be bound at the same time, the system assigns a free port for
+/// port 0 and an unspecified address includes every address of its port
+fn conflicts(a: &SocketAddr, b: &SocketAddr) -> bool {
+    a.port() != 0
+        && a.port() == b.port()
+        && (a.ip() == b.ip() || a.ip().is_unspecified() || b.ip().is_unspecified())
+}
+
+/// Serve tasks of the servers of a broker, with the name of each server
+#[derive(Default)]
+struct Servers {
+    tasks: JoinSet<Result<(), Error>>,
+    names: Vec<(task::Id, &'static str)>,
+}
+
+impl Servers {
+    fn spawn<F>(&mut self, name: &'static str, serve: F)
+    where
+        F: Future<Output = Result<(), Error>> + Send + 'static,
+    {
+        let id = self.tasks.spawn(serve).id();
+        self.names.push((id, name));
+    }
+
+    fn name(&self, id: task::Id) -> &'static str {
+        self.names
+            .iter()
+            .find_map(|(task_id, name)| (*task_id == id).then_some(*name))
+            .unwrap_or("unknown")
+    }
+
+    /// Waits for the first failing server, the remaining ones are aborted on return
+    async fn join(mut self) -> Result<(), Error> {
+        while let Some(joined) = self.tasks.join_next_with_id().await {
+            match joined {
+                Ok((id, Ok(()))) => info!("{} server stopped", self.name(id)),
+                Ok((id, Err(err))) => {
+                    let name = self.name(id);
+                    error!("{name} server failed: {err}");
+                    return Err(Error::Listener {
+                        name,
+                        source: Box::new(err),
+                    });
+                }
+                Err(err) => {
+                    let name = self.name(err.id());
+                    error!("{name} server task failed: {err}");
+                    if err.is_panic() {
+                        panic::resume_unwind(err.into_panic());
+                    }
+                }
+            }
+        }
         Ok(())
     }
 }
+
+#[cfg(all(test, feature = "mqtt", feature = "mqtts"))]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::{
+        server::{
+            config::{ServerConfig, TlsConfig},
+            state::GlobalState,
+        },
+        store::{memory::MemoryStore, memory_storage},
+    };
+
+    async fn tcp_server(addr: &str) -> TcpServer<MemoryStore> {
+        tcp_server_with(ServerConfig::new(addr.parse().unwrap(), None, "4").unwrap()).await
+    }
+
+    async fn tcp_server_with(config: ServerConfig) -> TcpServer<MemoryStore> {
+        let global = Arc::new(GlobalState::new(memory_storage()));
+        TcpServer::new(config, global).await.unwrap()
+    }
+
+    fn wrong_config(ret: Result<Broker<MemoryStore>, Error>) -> String {
+        match ret {
+            Err(Error::WrongConfig(reason)) => reason,
+            Err(err) => panic!("unexpected error {err}"),
+            Ok(_) => panic!("broker is accepted"),
+        }
+    }
+
+    #[tokio::test]
+    async fn misconfigured_broker_is_rejected() {
+        assert_eq!(
+            wrong_config(Broker::default().build()),
+            "no server configured"
+        );
+
+        let broker = Broker::default().with_mqtts(tcp_server("127.0.0.1:8883").await);
+        assert_eq!(
+            wrong_config(broker.build()),
+            "mqtts server without a TLS config"
+        );
+
+        let broker = Broker::default()
+            .with_mqtt(tcp_server("0.0.0.0:1883").await)
+            .with_mqtts(
+                tcp_server("127.0.0.1:1883")
+                    .await
+                    .with_rustls_config(Arc::new(
+                        tokio_rustls::rustls::ServerConfig::builder()
+                            .with_no_client_auth()
+                            .with_cert_resolver(Arc::new(NoCert)),
+                    )),
+            );
+        assert_eq!(
+            wrong_config(broker.build()),
+            "mqtt address 0.0.0.0:1883 conflicts with mqtts address 127.0.0.1:1883"
+        );
+    }
+
+    #[tokio::test]
+    async fn cert_auth_needs_client_certificates() {
+        let config = ServerConfig::new("127.0.0.1:1883".parse().unwrap(), None, "4")
+            .unwrap()
+            .with_cert_auth();
+        let broker = Broker::default().with_mqtt(tcp_server_with(config).await);
+        assert_eq!(
+            wrong_config(broker.build()),
+            "mqtt server can not authenticate by certificate"
+        );
+
+        let tls = TlsConfig::new(None, "cert.pem".into(), "key.pem".into(), false);
+        let config = ServerConfig::new("127.0.0.1:8883".parse().unwrap(), Some(tls), "4")
+            .unwrap()
+            .with_cert_auth();
+        let broker = Broker::default().with_mqtts(tcp_server_with(config).await);
+        assert_eq!(
+            wrong_config(broker.build()),
+            "mqtts certificate authentication requires client certificates"
+        );
+    }
+
+    #[tokio::test]
+    async fn port_zero_never_conflicts() {
+        let broker = Broker::default()
+            .with_mqtt(tcp_server("127.0.0.1:0").await)
+            .build();
+        assert!(broker.is_ok());
+    }
+
+    #[derive(Debug)]
+    struct NoCert;
+
+    impl tokio_rustls::rustls::server::ResolvesServerCert for NoCert {
+        fn resolve(
+            &self,
+            _: tokio_rustls::rustls::server::ClientHello<'_>,
+        ) -> Option<Arc<tokio_rustls::rustls::sign::CertifiedKey>> {
+            None
+        }
+    }
+}
@@ -3,7 +3,7 @@ use std::collections::{BTreeMap, BTreeSet};
 use axum::{extract::State, http::StatusCode, response::IntoResponse, Json};
 use log::info;
 
-use super::{app::App, store::Request, typ::RaftMetrics, Node, NodeId};
+use super::{app::App, store::Request, sys::ClusterStatus, typ::RaftMetrics, Node, NodeId};
 
 pub async fn write(State(app): State<App>, Json(req): Json<Request>) -> impl IntoResponse {
     let res = app.raft.client_write(req).await;
@@ -57,3 +57,8 @@ pub async fn metrics(State(app): State<App>) -> Result<Json<RaftMetrics>, (Statu
     let metrics = app.raft.metrics().borrow().clone();
     Ok(Json(metrics))
 }
+
+pub async fn status(State(app): State<App>) -> Json<ClusterStatus> {
+    let status = ClusterStatus::from(&*app.raft.metrics().borrow());
+    Json(status)
+}
@@ -4,25 +4,35 @@ use std::{
     sync::Arc,
 };
 
+#[cfg(not(feature = "cluster-sim"))]
 use axum::{
     routing::{get, post},
     Router,
 };
-use futures::{future, prelude::*};
-use log::info;
+use futures::{future, prelude::*, stream};
+use log::{info, warn};
 use openraft::raft::{
     AppendEntriesRequest, AppendEntriesResponse, SnapshotResponse, VoteRequest, VoteResponse,
 };
+use parking_lot::Mutex;
 use tarpc::{
     context::Context,
+    serde_transport::Transport,
     server::{incoming::Incoming as _, BaseChannel, Channel as _},
     tokio_serde::formats::Bincode,
 };
+use tokio_util::either::Either;
 
+#[cfg(not(feature = "cluster-sim"))]
+use super::tls::TlsListener;
+#[cfg(not(feature = "cluster-sim"))]
 use crate::cluster::api::*;
 
 use super::{
+    net,
+    snapshot::{self, ReceivingSnapshot},
     store::Request,
+    tls::{peer_ip, ClusterTls},
     typ::{
         ClientWriteError, ClientWriteResponse, InitializeError, Raft, RaftError, RaftMetrics,
         Snapshot, SnapshotData, SnapshotMeta, Vote,
@@ -43,11 +53,13 @@ pub trait RaftRPC {
     ) -> Result<ClientWriteResponse, RaftError<ClientWriteError>>;
     async fn metrics() -> RaftMetrics;
     async fn append(args: AppendEntriesRequest<TypeConfig>) -> AppendEntriesResponse<TypeConfig>;
+    /// Appends a chunk of a compressed snapshot, answers the bytes received so far
+    async fn snapshot_chunk(snapshot_id: String, offset: u64, chunk: Vec<u8>) -> u64;
+    /// Installs the snapshot whose chunks were all received
     async fn snapshot(
         vote: Vote,
         snapshot_meta: SnapshotMeta,
-        snapshot_data: SnapshotData,
-    ) -> SnapshotResponse<TypeConfig>;
+    ) -> Result<SnapshotResponse<TypeConfig>, String>;
     async fn vote(args: VoteRequest<TypeConfig>) -> VoteResponse<TypeConfig>;
 }
 
@@ -58,6 +70,8 @@ pub struct App {
     pub api_addr: SocketAddr,
     pub raft: Raft,
     pub state_machine_store: Arc<StateMachineStore>,
+    tls: Option<ClusterTls>,
+    receiving_snapshot: Arc<Mutex<ReceivingSnapshot>>,
 }
 
 impl App {
@@ -74,11 +88,61 @@ impl App {
             api_addr,
             raft,
             state_machine_store,
+            tls: None,
+            receiving_snapshot: Default::default(),
         }
     }
 
+    /// Serves the raft RPC and the HTTP API over mutual TLS
+    pub fn with_tls(mut self, tls: ClusterTls) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+
     pub async fn run(&self) {
+        // simulated nodes only have their raft RPC
+        #[cfg(not(feature = "cluster-sim"))]
+        self.spawn_api();
+
+        let listener = net::bind(self.rpc_addr).await.unwrap();
+        info!(
+            "Listening on port {}",
+            listener.local_addr().unwrap().port()
+        );
+        let tls = self.tls.clone();
+        stream::unfold(listener, |listener| async move {
+            let accepted = listener.accept().await;
+            Some((accepted, listener))
+        })
+        .filter_map(|r| future::ready(r.ok()))
+        .map(move |(stream, addr)| {
+            let tls = tls.clone();
+            async move {
+                match tls {
+                    Some(tls) => tls
+                        .accept(stream)
+                        .await
+                        .inspect_err(|e| warn!("TLS handshake with {addr} failed: {e}"))
+                        .ok()
+                        .map(Either::Right),
+                    None => Some(Either::Left(stream)),
+                }
+            }
+        })
+        .buffer_unordered(64)
+        .filter_map(future::ready)
+        .map(|stream| BaseChannel::with_defaults(Transport::from((stream, Bincode::default()))))
+        .max_channels_per_key(10, |t| peer_ip(t.transport().get_ref()))
+        .map(|channel| channel.execute(self.clone().serve()).for_each(Self::spawn))
+        .buffer_unordered(200)
+        .for_each(|_| async {})
+        .await;
+    }
+
+    #[cfg(not(feature = "cluster-sim"))]
+    fn spawn_api(&self) {
         let api_addr = self.api_addr;
+        let tls = self.tls.clone();
         let this = self.clone();
         tokio::spawn(async move {
             let app = Router::new()
@@ -88,24 +152,18 @@ impl App {
                 .route("/membership", post(change_membership))
                 .route("/init", post(init))
                 .route("/metrics", get(metrics))
+                .route("/status", get(status))
                 .with_state(this);
             let listener = tokio::net::TcpListener::bind(&api_addr).await.unwrap();
-            axum::serve(listener, app).await.unwrap();
+            // the API writes and changes the membership, only peers with a certificate of the
+            // cluster CA may call it when the raft RPC is secured
+            match tls {
+                Some(tls) => axum::serve(TlsListener::new(listener, tls), app)
+                    .await
+                    .unwrap(),
+                None => axum::serve(listener, app).await.unwrap(),
+            }
         });
-
-        let mut listener = tarpc::serde_transport::tcp::listen(&self.rpc_addr, Bincode::default)
-            .await
-            .unwrap();
-        info!("Listening on port {}", listener.local_addr().port());
-        listener.config_mut().max_frame_length(8 * 1024 * 1024);
-        listener
-            .filter_map(|r| future::ready(r.ok()))
-            .map(BaseChannel::with_defaults)
-            .max_channels_per_key(10, |t| t.transport().peer_addr().unwrap().ip())
-            .map(|channel| channel.execute(self.clone().serve()).for_each(Self::spawn))
-            .buffer_unordered(200)
-            .for_each(|_| async {})
-            .await;
     }
 
     async fn spawn(fut: impl Future<Output = ()> + Send + 'static) {
@@ -171,21 +229,39 @@ impl RaftRPC for App {
         self.raft.append_entries(args).await.unwrap()
     }
 
+    async fn snapshot_chunk(
+        self,
+        _: Context,
+        snapshot_id: String,
+        offset: u64,
+        chunk: Vec<u8>,
+    ) -> u64 {
+        self.receiving_snapshot
+            .lock()
+            .receive(snapshot_id, offset, &chunk)
+    }
+
     async fn snapshot(
         self,
         _: Context,
         vote: Vote,
         snapshot_meta: SnapshotMeta,
-        snapshot_data: SnapshotData,
-    ) -> SnapshotResponse<TypeConfig> {
+    ) -> Result<SnapshotResponse<TypeConfig>, String> {
+        let data = self
+            .receiving_snapshot
+            .lock()
+            .take(&snapshot_meta.snapshot_id)
+            .ok_or_else(|| format!("snapshot {} not received", snapshot_meta.snapshot_id))?;
+        let snapshot_data: SnapshotData = snapshot::decompress(&data).map_err(|e| e.to_string())?;
         let snapshot = Snapshot {
             meta: snapshot_meta,
             snapshot: Box::new(snapshot_data),
         };
-        self.raft
+        Ok(self
+            .raft
             .install_full_snapshot(vote, snapshot)
             .await
-            .unwrap()
+            .unwrap())
     }
 
     async fn vote(self, _: Context, args: VoteRequest<TypeConfig>) -> VoteResponse<TypeConfig> {
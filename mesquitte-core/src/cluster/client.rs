@@ -1,14 +1,19 @@
-use std::collections::BTreeSet;
+use std::{collections::BTreeSet, net::SocketAddr, sync::Arc};
 
-use log::info;
+use backon::{ExponentialBuilder, Retryable};
+use log::{info, warn};
 use openraft::error::NetworkError;
+use parking_lot::Mutex;
 use serde::{Deserialize, Serialize};
-use tarpc::{client::Config, context, serde_transport::Transport, tokio_serde::formats::Bincode};
-use tokio::net::TcpStream;
+use tarpc::{client::Config, serde_transport::Transport, tokio_serde::formats::Bincode};
 
 use super::{
     app::RaftRPCClient,
+    error::Error,
+    net::rpc_context,
+    pool::ClientPool,
     store::Request,
+    tls::{self, ClusterTls},
     typ::{ClientWriteResponse, ForwardToLeader, RPCError, RaftMetrics},
     NodeId,
 };
@@ -19,23 +24,43 @@ pub struct Empty {}
 pub struct ClusterClient {
     pub leader_id: NodeId,
     pub inner: RaftRPCClient,
+    tls: Option<ClusterTls>,
+}
+
+async fn rpc_client(addr: &str, tls: Option<&ClusterTls>) -> Result<RaftRPCClient, RPCError> {
+    let stream = tls::connect(addr, tls)
+        .await
+        .map_err(|e| RPCError::Network(NetworkError::new(&e)))?;
+    let transport = Transport::from((stream, Bincode::default()));
+    Ok(RaftRPCClient::new(Config::default(), transport).spawn())
 }
 
 impl ClusterClient {
-    pub async fn new(leader_id: NodeId, leader_addr: String) -> Self {
-        let stream = TcpStream::connect(leader_addr).await.unwrap();
-        let transport = Transport::from((stream, Bincode::default()));
-        let client_stub = RaftRPCClient::new(Config::default(), transport).spawn();
-        Self {
+    pub async fn new(leader_id: NodeId, leader_addr: String) -> Result<Self, RPCError> {
+        Ok(Self {
             leader_id,
-            inner: client_stub,
-        }
+            inner: rpc_client(&leader_addr, None).await?,
+            tls: None,
+        })
+    }
+
+    /// A client of a cluster whose nodes require mutual TLS
+    pub async fn with_tls(
+        leader_id: NodeId,
+        leader_addr: String,
+        tls: ClusterTls,
+    ) -> Result<Self, RPCError> {
+        Ok(Self {
+            leader_id,
+            inner: rpc_client(&leader_addr, Some(&tls)).await?,
+            tls: Some(tls),
+        })
     }
 
     pub async fn write(&mut self, req: &Request) -> Result<ClientWriteResponse, RPCError> {
         let mut n_retry = 3;
         loop {
-            match self.inner.write(context::current(), req.to_owned()).await {
+            match self.inner.write(rpc_context(), req.to_owned()).await {
                 Ok(r) => match r {
                     Ok(v) => return Ok(v),
                     Err(e) => {
@@ -46,11 +71,8 @@ impl ClusterClient {
                         }) = e.forward_to_leader()
                         {
                             info!("new leader {} : {}", leader_id, leader_node);
-                            let stream = TcpStream::connect(&leader_node.rpc_addr).await.unwrap();
-                            let transport = Transport::from((stream, Bincode::default()));
-                            let client_stub =
-                                RaftRPCClient::new(Config::default(), transport).spawn();
-                            self.inner = client_stub;
+                            self.inner =
+                                rpc_client(&leader_node.rpc_addr, self.tls.as_ref()).await?;
                             n_retry -= 1;
                             if n_retry > 0 {
                                 continue;
@@ -67,13 +89,13 @@ impl ClusterClient {
 
     pub async fn read(&self, req: &String) -> Result<Option<String>, RPCError> {
         self.inner
-            .read(context::current(), req.to_owned())
+            .read(rpc_context(), req.to_owned())
             .await
             .map_err(|e| RPCError::Network(NetworkError::new(&e)))
     }
 
     pub async fn init(&self) -> Result<(), RPCError> {
-        match self.inner.init(context::current()).await {
+        match self.inner.init(rpc_context()).await {
             Ok(r) => match r {
                 Ok(v) => Ok(v),
                 Err(e) => Err(RPCError::Network(NetworkError::new(&e))),
@@ -88,11 +110,7 @@ impl ClusterClient {
     ) -> Result<ClientWriteResponse, RPCError> {
         let mut n_retry = 3;
         loop {
-            match self
-                .inner
-                .add_learner(context::current(), req.to_owned())
-                .await
-            {
+            match self.inner.add_learner(rpc_context(), req.to_owned()).await {
                 Ok(r) => match r {
                     Ok(v) => return Ok(v),
                     Err(e) => {
@@ -103,11 +121,8 @@ impl ClusterClient {
                         }) = e.forward_to_leader()
                         {
                             info!("new leader {} : {}", leader_id, leader_node);
-                            let stream = TcpStream::connect(&leader_node.rpc_addr).await.unwrap();
-                            let transport = Transport::from((stream, Bincode::default()));
-                            let client_stub =
-                                RaftRPCClient::new(Config::default(), transport).spawn();
-                            self.inner = client_stub;
+                            self.inner =
+                                rpc_client(&leader_node.rpc_addr, self.tls.as_ref()).await?;
                             n_retry -= 1;
                             if n_retry > 0 {
                                 continue;
@@ -130,7 +145,7 @@ impl ClusterClient {
         loop {
             match self
                 .inner
-                .change_membership(context::current(), req.to_owned())
+                .change_membership(rpc_context(), req.to_owned())
                 .await
             {
                 Ok(r) => match r {
@@ -143,11 +158,8 @@ impl ClusterClient {
                         }) = e.forward_to_leader()
                         {
                             info!("new leader {} : {}", leader_id, leader_node);
-                            let stream = TcpStream::connect(&leader_node.rpc_addr).await.unwrap();
-                            let transport = Transport::from((stream, Bincode::default()));
-                            let client_stub =
-                                RaftRPCClient::new(Config::default(), transport).spawn();
-                            self.inner = client_stub;
+                            self.inner =
+                                rpc_client(&leader_node.rpc_addr, self.tls.as_ref()).await?;
                             n_retry -= 1;
                             if n_retry > 0 {
                                 continue;
@@ -164,8 +176,104 @@ impl ClusterClient {
 
     pub async fn metrics(&self) -> Result<RaftMetrics, RPCError> {
         self.inner
-            .metrics(context::current())
+            .metrics(rpc_context())
             .await
             .map_err(|e| RPCError::Network(NetworkError::new(&e)))
     }
 }
+
+/// Writes to a cluster through whichever node leads it
+///
+/// The leader is found from the metrics of the seed nodes and kept until it stops being the
+/// leader: a write answered with `ForwardToLeader` is retried on the node it names, a write
+/// which failed on the network is retried after finding the leader again, with exponential
+/// backoff in both cases.
+#[derive(Clone)]
+pub struct ClusterHandle {
+    seeds: Vec<SocketAddr>,
+    leader: Arc<Mutex<Option<SocketAddr>>>,
+    pool: ClientPool,
+    backoff: ExponentialBuilder,
+}
+
+impl ClusterHandle {
+    pub fn new(seeds: Vec<SocketAddr>) -> Self {
+        Self {
+            seeds,
+            leader: Default::default(),
+            pool: ClientPool::new(10),
+            backoff: ExponentialBuilder::default(),
+        }
+    }
+
+    /// Connects to the nodes over mutual TLS
+    pub fn with_tls(mut self, tls: ClusterTls) -> Self {
+        self.pool = self.pool.with_tls(tls);
+        self
+    }
+
+    pub fn with_backoff(mut self, backoff: ExponentialBuilder) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    pub async fn write(&self, req: Request) -> Result<ClientWriteResponse, Error> {
+        (|| self.try_write(&req))
+            .retry(self.backoff)
+            .sleep(tokio::time::sleep)
+            .when(|e| !matches!(e, Error::Write(_)))
+            .notify(|err, dur| {
+                warn!("retrying cluster write {:?} after {:?}", err, dur);
+            })
+            .await
+    }
+
+    async fn try_write(&self, req: &Request) -> Result<ClientWriteResponse, Error> {
+        let addr = self.leader().await?;
+        let client = self.pool.make_rpc_connection(addr).await.inspect_err(|_| {
+            self.leader.lock().take();
+        })?;
+        let err = match client.write(rpc_context(), req.to_owned()).await {
+            Ok(Ok(resp)) => return Ok(resp),
+            Ok(Err(err)) => err,
+            Err(err) => {
+                self.leader.lock().take();
+                return Err(Error::Rpc(err.to_string()));
+            }
+        };
+        let Some(forward) = err.forward_to_leader() else {
+            return Err(Error::Write(err));
+        };
+        let leader = forward
+            .leader_node
+            .as_ref()
+            .and_then(|node| node.rpc_addr.parse().ok());
+        info!("{} forwards cluster write to {:?}", addr, leader);
+        *self.leader.lock() = leader;
+        Err(Error::NoLeader)
+    }
+
+    /// The RPC address of the leader, asked to the seed nodes if it is not known
+    async fn leader(&self) -> Result<SocketAddr, Error> {
+        if let Some(leader) = *self.leader.lock() {
+            return Ok(leader);
+        }
+        for seed in &self.seeds {
+            let Ok(client) = self.pool.make_rpc_connection(*seed).await else {
+                continue;
+            };
+            let Ok(metrics) = client.metrics(rpc_context()).await else {
+                continue;
+            };
+            let leader = metrics
+                .current_leader
+                .and_then(|id| metrics.membership_config.membership().get_node(&id))
+                .and_then(|node| node.rpc_addr.parse().ok());
+            if let Some(leader) = leader {
+                *self.leader.lock() = Some(leader);
+                return Ok(leader);
+            }
+        }
+        Err(Error::NoLeader)
+    }
+}
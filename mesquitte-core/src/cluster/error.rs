@@ -1,7 +1,19 @@
+use super::typ::{ClientWriteError, RaftError};
+
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
     #[error("Io Error : {0}")]
     Io(#[from] std::io::Error),
     #[error("No RPC Client established to {0} cause {1}")]
     NoAvailableRaftRPCClient(String, String),
+    #[error("Rustls error {0}")]
+    Rustls(#[from] rustls::Error),
+    #[error("Invalid certificate {0}")]
+    InvalidCert(String),
+    #[error("No leader elected")]
+    NoLeader,
+    #[error("RPC error {0}")]
+    Rpc(String),
+    #[error("Write error {0}")]
+    Write(RaftError<ClientWriteError>),
 }
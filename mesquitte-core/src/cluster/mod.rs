@@ -2,9 +2,13 @@ mod api;
 mod app;
 pub mod client;
 pub mod error;
+mod net;
 mod network;
 mod pool;
+mod snapshot;
 pub mod store;
+pub mod sys;
+pub mod tls;
 
 use std::{fmt::Display, net::SocketAddr, path::Path, sync::Arc};
 
@@ -14,6 +18,7 @@ use openraft::{Config, Raft};
 use pool::ClientPool;
 use serde::{Deserialize, Serialize};
 use store::{log_store, Request, Response, StateMachineData};
+use tls::ClusterTls;
 
 pub type NodeId = u64;
 
@@ -107,6 +112,27 @@ pub async fn new_raft<P: AsRef<Path>>(
     rpc_addr: SocketAddr,
     api_addr: SocketAddr,
     dir: P,
+) -> (typ::Raft, App) {
+    build_raft(node_id, rpc_addr, api_addr, dir, None).await
+}
+
+/// A node whose raft RPC with the other nodes, and HTTP API, go over mutual TLS
+pub async fn new_raft_with_tls<P: AsRef<Path>>(
+    node_id: NodeId,
+    rpc_addr: SocketAddr,
+    api_addr: SocketAddr,
+    dir: P,
+    tls: ClusterTls,
+) -> (typ::Raft, App) {
+    build_raft(node_id, rpc_addr, api_addr, dir, Some(tls)).await
+}
+
+async fn build_raft<P: AsRef<Path>>(
+    node_id: NodeId,
+    rpc_addr: SocketAddr,
+    api_addr: SocketAddr,
+    dir: P,
+    tls: Option<ClusterTls>,
 ) -> (typ::Raft, App) {
     let config = Config {
         heartbeat_interval: 500,
@@ -119,7 +145,10 @@ pub async fn new_raft<P: AsRef<Path>>(
     let config = Arc::new(config.validate().unwrap());
 
     let (log_store, state_machine_store) = store::new(dir).await;
-    let client_poll = ClientPool::new(10);
+    let mut client_poll = ClientPool::new(10);
+    if let Some(tls) = &tls {
+        client_poll = client_poll.with_tls(tls.clone());
+    }
     let network = Network::new(node_id, client_poll);
     let raft = Raft::new(
         node_id,
@@ -131,13 +160,16 @@ pub async fn new_raft<P: AsRef<Path>>(
     .await
     .unwrap();
 
-    let app = App::new(
+    let mut app = App::new(
         node_id,
         rpc_addr,
         api_addr,
         raft.clone(),
         state_machine_store,
     );
+    if let Some(tls) = tls {
+        app = app.with_tls(tls);
+    }
 
     (raft, app)
 }
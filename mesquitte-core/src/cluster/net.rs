@@ -0,0 +1,43 @@
+//! Sockets of the raft RPC: the ones of tokio, or the simulated ones of turmoil with the
+//! `cluster-sim` feature, which runs nodes in a deterministic simulation
+
+use std::{io, net::SocketAddr};
+
+use tarpc::context::{self, Context};
+
+#[cfg(not(feature = "cluster-sim"))]
+pub(crate) use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
+#[cfg(feature = "cluster-sim")]
+pub(crate) use turmoil::{
+    net::{TcpListener, TcpStream},
+    ToSocketAddrs,
+};
+
+/// Listens for the raft RPC on `addr`
+#[cfg(not(feature = "cluster-sim"))]
+pub(crate) async fn bind(addr: SocketAddr) -> io::Result<TcpListener> {
+    TcpListener::bind(addr).await
+}
+
+/// Listens for the raft RPC on the port of `addr`, a simulated host only binds unspecified
+/// addresses
+#[cfg(feature = "cluster-sim")]
+pub(crate) async fn bind(addr: SocketAddr) -> io::Result<TcpListener> {
+    TcpListener::bind((std::net::Ipv4Addr::UNSPECIFIED, addr.port())).await
+}
+
+/// Context of an RPC
+#[cfg(not(feature = "cluster-sim"))]
+pub(crate) fn rpc_context() -> Context {
+    context::current()
+}
+
+/// Context of an RPC, with its deadline on the simulated clock: the one of tarpc is taken from
+/// the system clock, which the simulation runs ahead of
+#[cfg(feature = "cluster-sim")]
+pub(crate) fn rpc_context() -> Context {
+    let mut context = context::current();
+    let timeout = std::time::Duration::from_secs(10);
+    context.deadline = (tokio::time::Instant::now() + timeout).into_std();
+    context
+}
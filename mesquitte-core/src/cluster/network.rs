@@ -1,16 +1,17 @@
-use std::{future::Future, net::SocketAddr};
+use std::{future::Future, io, net::SocketAddr};
 
 use backon::{ExponentialBuilder, Retryable};
 use log::{info, warn};
 use openraft::{
-    error::{ReplicationClosed, Unreachable},
+    error::{NetworkError, ReplicationClosed, Unreachable},
     network::{v2::RaftNetworkV2, RPCOption},
     OptionalSend, RaftNetworkFactory,
 };
-use tarpc::context;
 
 use super::{
+    net::rpc_context,
     pool::{ClientPool, RPCClientManager},
+    snapshot::{self, CHUNK_SIZE},
     typ::*,
     Node, NodeId, TypeConfig,
 };
@@ -56,6 +57,10 @@ impl Connection {
         Ok(client_stub)
     }
 }
+fn network_err(e: &(impl std::error::Error + 'static)) -> StreamingError {
+    StreamingError::Network(NetworkError::new(e))
+}
+
 pub struct Network {
     pub id: NodeId,
     pub client_poll: ClientPool,
@@ -85,7 +90,7 @@ impl RaftNetworkV2<TypeConfig> for Connection {
     ) -> Result<AppendEntriesResponse, RPCError> {
         info!("id:{} append entries take client", self.node_id);
         let client = self.take_client().await?;
-        let resp = client.append(context::current(), req).await.unwrap();
+        let resp = client.append(rpc_context(), req).await.unwrap();
         Ok(resp)
     }
 
@@ -97,12 +102,42 @@ impl RaftNetworkV2<TypeConfig> for Connection {
         _option: RPCOption,
     ) -> Result<SnapshotResponse, StreamingError> {
         info!("id:{} full snapshot take client", self.node_id);
+        let data = snapshot::compress(&*snapshot.snapshot).map_err(|e| network_err(&e))?;
+        let snapshot_id = snapshot.meta.snapshot_id.clone();
+        info!(
+            "id:{} sending snapshot {} of {} compressed bytes",
+            self.node_id,
+            snapshot_id,
+            data.len()
+        );
+
+        // the target answers how much it has, a chunk lost with a connection is sent again
+        let mut offset = 0;
+        while offset < data.len() {
+            let end = data.len().min(offset + CHUNK_SIZE);
+            let client = self.take_client().await?;
+            let received = client
+                .snapshot_chunk(
+                    rpc_context(),
+                    snapshot_id.clone(),
+                    offset as u64,
+                    data[offset..end].to_vec(),
+                )
+                .await
+                .map_err(|e| network_err(&e))?;
+            offset = received as usize;
+            if offset > data.len() {
+                let e = io::Error::other(format!("snapshot {snapshot_id} overrun"));
+                return Err(network_err(&e));
+            }
+        }
+
         let client = self.take_client().await?;
-        let resp = client
-            .snapshot(context::current(), vote, snapshot.meta, *snapshot.snapshot)
+        client
+            .snapshot(rpc_context(), vote, snapshot.meta)
             .await
-            .unwrap();
-        Ok(resp)
+            .map_err(|e| network_err(&e))?
+            .map_err(|e| network_err(&io::Error::other(e)))
     }
 
     async fn vote(
@@ -112,7 +147,7 @@ impl RaftNetworkV2<TypeConfig> for Connection {
     ) -> Result<VoteResponse, RPCError> {
         info!("id:{} vote take client", self.node_id);
         let client = self.take_client().await?;
-        let resp = client.vote(context::current(), req).await.unwrap();
+        let resp = client.vote(rpc_context(), req).await.unwrap();
         Ok(resp)
     }
 }
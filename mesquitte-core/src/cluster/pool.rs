@@ -4,17 +4,21 @@ use dashmap::DashMap;
 use log::info;
 use mobc::{async_trait, Connection, Manager, Pool};
 use tarpc::{client::Config, serde_transport::Transport, tokio_serde::formats::Bincode};
-use tokio::net::TcpStream;
 
-use super::{app::RaftRPCClient, error::Error};
+use super::{
+    app::RaftRPCClient,
+    error::Error,
+    tls::{self, ClusterTls},
+};
 
 pub struct RPCClientManager {
     pub addr: SocketAddr,
+    tls: Option<ClusterTls>,
 }
 
 impl RPCClientManager {
-    pub fn new(addr: SocketAddr) -> Self {
-        Self { addr }
+    pub fn new(addr: SocketAddr, tls: Option<ClusterTls>) -> Self {
+        Self { addr, tls }
     }
 }
 
@@ -26,7 +30,7 @@ impl Manager for RPCClientManager {
     async fn connect(&self) -> Result<Self::Connection, Self::Error> {
         info!("Raft NetworkConnection connecting to target: {}", self.addr);
 
-        let stream = TcpStream::connect(&self.addr).await?;
+        let stream = tls::connect(self.addr, self.tls.as_ref()).await?;
         let transport = Transport::from((stream, Bincode::default()));
         let client_stub = RaftRPCClient::new(Config::default(), transport).spawn();
         Ok(client_stub)
@@ -41,6 +45,7 @@ impl Manager for RPCClientManager {
 pub struct ClientPool {
     max_open_connection: u64,
     rpc_client_pool: DashMap<String, Pool<RPCClientManager>>,
+    tls: Option<ClusterTls>,
 }
 
 impl ClientPool {
@@ -48,16 +53,23 @@ impl ClientPool {
         Self {
             max_open_connection,
             rpc_client_pool: DashMap::with_capacity(2),
+            tls: None,
         }
     }
 
+    /// Connects to the other nodes over mutual TLS
+    pub fn with_tls(mut self, tls: ClusterTls) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+
     pub async fn make_rpc_connection(
         &self,
         addr: SocketAddr,
     ) -> Result<Connection<RPCClientManager>, Error> {
         let key = addr.to_string();
         if !self.rpc_client_pool.contains_key(&key) {
-            let manager = RPCClientManager::new(addr);
+            let manager = RPCClientManager::new(addr, self.tls.clone());
             let pool = Pool::builder()
                 .max_open(self.max_open_connection)
                 .build(manager);
@@ -0,0 +1,97 @@
+use std::io;
+
+use serde::{de::DeserializeOwned, Serialize};
+
+/// Bytes of a compressed snapshot sent to a node in one RPC, a node which missed some resumes
+/// from the last byte it got
+pub(crate) const CHUNK_SIZE: usize = 1024 * 1024;
+
+const LEVEL: i32 = 3;
+// the magic number every zstd frame starts with
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+/// `value` serialized with bincode and compressed with zstd
+pub(crate) fn compress<T: Serialize>(value: &T) -> io::Result<Vec<u8>> {
+    let mut encoder = zstd::Encoder::new(Vec::new(), LEVEL)?;
+    bincode::serialize_into(&mut encoder, value).map_err(io::Error::other)?;
+    encoder.finish()
+}
+
+/// A value written by [`compress`], or serialized uncompressed as snapshots were before
+pub(crate) fn decompress<T: DeserializeOwned>(bytes: &[u8]) -> io::Result<T> {
+    if bytes.starts_with(&ZSTD_MAGIC) {
+        bincode::deserialize_from(zstd::Decoder::new(bytes)?).map_err(io::Error::other)
+    } else {
+        bincode::deserialize(bytes).map_err(io::Error::other)
+    }
+}
+
+/// A snapshot a node is receiving chunk by chunk
+#[derive(Debug, Default)]
+pub(crate) struct ReceivingSnapshot {
+    snapshot_id: String,
+    data: Vec<u8>,
+}
+
+impl ReceivingSnapshot {
+    /// Appends the chunk at `offset` of snapshot `snapshot_id` and answers the bytes received
+    /// so far, where the sender goes on from
+    ///
+    /// A chunk of another snapshot drops the one being received, a chunk which does not start
+    /// at the end of the bytes received is ignored.
+    pub(crate) fn receive(&mut self, snapshot_id: String, offset: u64, chunk: &[u8]) -> u64 {
+        if self.snapshot_id != snapshot_id {
+            self.snapshot_id = snapshot_id;
+            self.data.clear();
+        }
+        if offset == self.data.len() as u64 {
+            self.data.extend_from_slice(chunk);
+        }
+        self.data.len() as u64
+    }
+
+    /// The compressed snapshot `snapshot_id` once all its chunks were received
+    pub(crate) fn take(&mut self, snapshot_id: &str) -> Option<Vec<u8>> {
+        if self.snapshot_id != snapshot_id {
+            return None;
+        }
+        self.snapshot_id.clear();
+        Some(std::mem::take(&mut self.data))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+
+    #[test]
+    fn snapshots_are_compressed_in_chunks() {
+        let data: BTreeMap<String, String> = (0..10_000)
+            .map(|i| (format!("retain/{i}"), "payload".repeat(10)))
+            .collect();
+        let compressed = compress(&data).unwrap();
+        assert!(compressed.len() < bincode::serialize(&data).unwrap().len() / 10);
+
+        let mut receiving = ReceivingSnapshot::default();
+        let (first, rest) = compressed.split_at(compressed.len() / 2);
+        assert_eq!(
+            receiving.receive("1-5".to_owned(), 0, first),
+            first.len() as u64
+        );
+        // a resent chunk is not appended twice
+        assert_eq!(
+            receiving.receive("1-5".to_owned(), 0, first),
+            first.len() as u64
+        );
+        let received = receiving.receive("1-5".to_owned(), first.len() as u64, rest);
+        assert_eq!(received, compressed.len() as u64);
+        assert_eq!(receiving.take("2-7"), None);
+        let received = receiving.take("1-5").unwrap();
+        assert_eq!(decompress::<BTreeMap<_, _>>(&received).unwrap(), data);
+
+        let plain = bincode::serialize(&data).unwrap();
+        assert_eq!(decompress::<BTreeMap<_, _>>(&plain).unwrap(), data);
+    }
+}
@@ -12,7 +12,7 @@ use rand::Rng as _;
 use rust_rocksdb::{ColumnFamilyDescriptor, Options, DB};
 use serde::{Deserialize, Serialize};
 
-use crate::cluster::{typ, LogStore, TypeConfig};
+use crate::cluster::{snapshot, typ, LogStore, TypeConfig};
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum Request {
@@ -98,7 +98,7 @@ impl RaftSnapshotBuilder<TypeConfig> for Arc<StateMachineStore> {
             data: Box::new(sm.clone()),
         };
 
-        let serialized_snapshot = bincode::serialize(&snapshot)
+        let serialized_snapshot = snapshot::compress(&snapshot)
             .map_err(|e| StorageError::write_snapshot(Some(meta.signature()), &e))?;
 
         self.db
@@ -180,7 +180,7 @@ impl RaftStateMachine<TypeConfig> for Arc<StateMachineStore> {
             let mut sm = self.sm.write();
             *sm = updated_state_machine;
         }
-        let serialized_snapshot = bincode::serialize(&new_snapshot)
+        let serialized_snapshot = snapshot::compress(&new_snapshot)
             .map_err(|e| StorageError::write_snapshot(Some(meta.signature()), &e))?;
         self.db
             .put_cf(
@@ -207,7 +207,7 @@ impl RaftStateMachine<TypeConfig> for Arc<StateMachineStore> {
             None => return Ok(None),
         };
         let snapshot: StoredSnapshot =
-            bincode::deserialize(&bytes).map_err(|e| StorageError::write_snapshot(None, &e))?;
+            snapshot::decompress(&bytes).map_err(|e| StorageError::write_snapshot(None, &e))?;
         let data = snapshot.data.clone();
 
         Ok(Some(Snapshot {
@@ -0,0 +1,218 @@
+//! Cluster state as `$SYS` topics
+//!
+//! [`publish_cluster_status`] publishes the state of the cluster as a node sees it, derived
+//! from its [`RaftMetrics`], as retained messages under `$SYS/cluster/`:
+//!
+//! - `leader`: id of the current leader, empty while there is none
+//! - `term`: current term
+//! - `state`: raft state of the node, `Leader`, `Follower`, `Candidate` or `Learner`
+//! - `nodes`: ids of the members, comma separated
+//! - `nodes/<id>/rpc_addr`, `nodes/<id>/api_addr` and `nodes/<id>/voter`
+//! - `nodes/<id>/lag`: log entries the member is behind the leader, published by the leader
+//!   only
+//!
+//! The same state is answered as JSON by `GET /status` on the HTTP API of a node.
+
+use std::{collections::HashSet, time::Duration};
+
+use log::warn;
+use mqtt_codec_kit::common::{QualityOfService, TopicName};
+use serde::{Deserialize, Serialize};
+use tokio::time::{self, MissedTickBehavior};
+
+use super::{
+    typ::{Raft, RaftMetrics},
+    NodeId,
+};
+use crate::{
+    server::{state::GlobalState, topic_stats},
+    store::{message::PublishMessage, retain::RetainMessageStore, topic::TopicStore},
+};
+
+/// Prefix of the retained cluster state topics
+pub const CLUSTER_SYS_PREFIX: &str = "$SYS/cluster/";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ClusterStatus {
+    pub node_id: NodeId,
+    pub state: String,
+    pub leader: Option<NodeId>,
+    pub term: u64,
+    pub last_log_index: Option<u64>,
+    pub nodes: Vec<NodeStatus>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NodeStatus {
+    pub id: NodeId,
+    pub rpc_addr: String,
+    pub api_addr: String,
+    pub voter: bool,
+    /// Log entries the node is behind the leader, only known on the leader
+    pub lag: Option<u64>,
+}
+
+impl From<&RaftMetrics> for ClusterStatus {
+    fn from(metrics: &RaftMetrics) -> Self {
+        let membership = metrics.membership_config.membership();
+        let last_log_index = metrics.last_log_index;
+        let nodes = metrics
+            .membership_config
+            .nodes()
+            .map(|(id, node)| {
+                let matched = metrics
+                    .replication
+                    .as_ref()
+                    .and_then(|replication| replication.get(id));
+                let lag = match matched {
+                    Some(matched) => {
+                        let matched = matched.as_ref().map_or(0, |log_id| log_id.index + 1);
+                        last_log_index.map(|last| (last + 1).saturating_sub(matched))
+                    }
+                    // the leader does not replicate to itself
+                    None if metrics.current_leader == Some(*id) => Some(0),
+                    None => None,
+                };
+                NodeStatus {
+                    id: *id,
+                    rpc_addr: node.rpc_addr.clone(),
+                    api_addr: node.api_addr.clone(),
+                    voter: membership.is_voter(id),
+                    lag,
+                }
+            })
+            .collect();
+        Self {
+            node_id: metrics.id,
+            state: format!("{:?}", metrics.state),
+            leader: metrics.current_leader,
+            term: metrics.current_term,
+            last_log_index,
+            nodes,
+        }
+    }
+}
+
+/// Publishes the cluster state as seen by `raft` every `interval`, see
+/// [`self`](super::sys), runs until the task is dropped
+///
+/// The entries of a node which left the cluster are cleared.
+pub async fn publish_cluster_status<S>(global: &GlobalState<S>, raft: &Raft, interval: Duration)
+where
+    S: RetainMessageStore + TopicStore,
+{
+    let mut ticker = time::interval(interval);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut published = HashSet::new();
+    loop {
+        ticker.tick().await;
+        let status = ClusterStatus::from(&*raft.metrics().borrow());
+        let members: HashSet<NodeId> = status.nodes.iter().map(|node| node.id).collect();
+        for id in published.difference(&members) {
+            for (name, _) in node_entries(&removed_node(*id)) {
+                if let Err(err) = global.storage.remove(&name).await {
+                    warn!("clear cluster status of node {id}: {err}");
+                }
+            }
+        }
+        for (name, value) in entries(&status) {
+            let message =
+                PublishMessage::new(name, value.into_bytes(), QualityOfService::Level0, true);
+            if let Err(err) = topic_stats::publish(global, message).await {
+                warn!("publish cluster status: {err}");
+            }
+        }
+        published = members;
+    }
+}
+
+fn removed_node(id: NodeId) -> NodeStatus {
+    NodeStatus {
+        id,
+        rpc_addr: String::new(),
+        api_addr: String::new(),
+        voter: false,
+        lag: None,
+    }
+}
+
+fn entries(status: &ClusterStatus) -> Vec<(TopicName, String)> {
+    let nodes: Vec<String> = status
+        .nodes
+        .iter()
+        .map(|node| node.id.to_string())
+        .collect();
+    let mut entries: Vec<_> = [
+        ("leader", status.leader.map(|id| id.to_string())),
+        ("term", Some(status.term.to_string())),
+        ("state", Some(status.state.clone())),
+        ("nodes", Some(nodes.join(","))),
+    ]
+    .into_iter()
+    .filter_map(|(entry, value)| {
+        TopicName::new(format!("{CLUSTER_SYS_PREFIX}{entry}"))
+            .ok()
+            .map(|name| (name, value.unwrap_or_default()))
+    })
+    .collect();
+    for node in &status.nodes {
+        entries.extend(node_entries(node));
+    }
+    entries
+}
+
+fn node_entries(node: &NodeStatus) -> impl Iterator<Item = (TopicName, String)> + '_ {
+    [
+        ("rpc_addr", node.rpc_addr.clone()),
+        ("api_addr", node.api_addr.clone()),
+        ("voter", node.voter.to_string()),
+        (
+            "lag",
+            node.lag.map(|lag| lag.to_string()).unwrap_or_default(),
+        ),
+    ]
+    .into_iter()
+    .filter_map(move |(entry, value)| {
+        TopicName::new(format!("{CLUSTER_SYS_PREFIX}nodes/{}/{entry}", node.id))
+            .ok()
+            .map(|name| (name, value))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn status_entries() {
+        let status = ClusterStatus {
+            node_id: 1,
+            state: "Leader".to_owned(),
+            leader: Some(1),
+            term: 3,
+            last_log_index: Some(42),
+            nodes: vec![
+                NodeStatus {
+                    id: 1,
+                    rpc_addr: "10.0.0.1:21001".to_owned(),
+                    api_addr: "10.0.0.1:31001".to_owned(),
+                    voter: true,
+                    lag: Some(0),
+                },
+                NodeStatus {
+                    lag: Some(5),
+                    ..removed_node(2)
+                },
+            ],
+        };
+        let entries: Vec<_> = entries(&status)
+            .into_iter()
+            .map(|(name, value)| (name.to_string(), value))
+            .collect();
+        assert_eq!(entries.len(), 12);
+        assert!(entries.contains(&("$SYS/cluster/leader".to_owned(), "1".to_owned())));
+        assert!(entries.contains(&("$SYS/cluster/nodes".to_owned(), "1,2".to_owned())));
+        assert!(entries.contains(&("$SYS/cluster/nodes/2/lag".to_owned(), "5".to_owned())));
+        assert!(entries.contains(&("$SYS/cluster/nodes/2/voter".to_owned(), "false".to_owned())));
+    }
+}
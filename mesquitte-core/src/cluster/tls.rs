@@ -0,0 +1,306 @@
+use std::{
+    fs::File,
+    io::{self, BufReader},
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+#[cfg(not(feature = "cluster-sim"))]
+use futures::{future::BoxFuture, stream::FuturesUnordered, StreamExt as _};
+#[cfg(not(feature = "cluster-sim"))]
+use log::warn;
+use rustls::{
+    pki_types::{CertificateDer, PrivateKeyDer, ServerName},
+    server::WebPkiClientVerifier,
+    ClientConfig, RootCertStore, ServerConfig,
+};
+use tokio::time;
+use tokio_rustls::{TlsAcceptor, TlsConnector, TlsStream};
+use tokio_util::either::Either;
+
+#[cfg(not(feature = "cluster-sim"))]
+use super::net::TcpListener;
+use super::{
+    error::Error,
+    net::{TcpStream, ToSocketAddrs},
+};
+
+/// Time a peer has to complete the TLS handshake
+const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A raft RPC connection, plain TCP or mutual TLS
+pub(crate) type RpcStream = Either<TcpStream, TlsStream<TcpStream>>;
+
+pub(crate) fn peer_ip(stream: &RpcStream) -> Option<IpAddr> {
+    let stream = match stream {
+        Either::Left(stream) => stream,
+        Either::Right(stream) => stream.get_ref().0,
+    };
+    stream.peer_addr().ok().map(|addr| addr.ip())
+}
+
+/// Certificates of a node for the raft RPC between the nodes of a cluster
+#[derive(Clone, Debug)]
+pub struct ClusterTlsConfig {
+    /// CA which signed the certificates of every node of the cluster
+    pub ca_file: PathBuf,
+    /// Certificate of the node, presented to the nodes it connects to as well as to the ones
+    /// connecting to it
+    pub cert_file: PathBuf,
+    pub key_file: PathBuf,
+    /// Name the certificates of the other nodes are checked against, by default the IP
+    /// address a node is connected on
+    pub server_name: Option<String>,
+}
+
+impl ClusterTlsConfig {
+    pub fn new(ca_file: PathBuf, cert_file: PathBuf, key_file: PathBuf) -> Self {
+        Self {
+            ca_file,
+            cert_file,
+            key_file,
+            server_name: None,
+        }
+    }
+
+    pub fn with_server_name(mut self, server_name: impl Into<String>) -> Self {
+        self.server_name = Some(server_name.into());
+        self
+    }
+}
+
+/// Mutual TLS between nodes: both ends of a connection present their certificate and take
+/// only peers whose certificate is signed by the cluster CA
+#[derive(Clone)]
+pub struct ClusterTls {
+    acceptor: TlsAcceptor,
+    connector: TlsConnector,
+    server_name: Option<ServerName<'static>>,
+    handshake_timeout: Duration,
+}
+
+impl ClusterTls {
+    pub fn new(cfg: &ClusterTlsConfig) -> Result<Self, Error> {
+        let roots = Arc::new(root_store(&cfg.ca_file)?);
+        let cert_chain = certs(&cfg.cert_file)?;
+        let key_file = &mut BufReader::new(File::open(&cfg.key_file)?);
+        let key: PrivateKeyDer<'static> = rustls_pemfile::private_key(key_file)?
+            .ok_or_else(|| Error::InvalidCert("invalid node key".to_string()))?;
+
+        let verifier = WebPkiClientVerifier::builder(roots.clone())
+            .build()
+            .map_err(|e| Error::InvalidCert(e.to_string()))?;
+        let server = ServerConfig::builder()
+            .with_client_cert_verifier(verifier)
+            .with_single_cert(cert_chain.clone(), key.clone_key())?;
+        let client = ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_client_auth_cert(cert_chain, key)?;
+        let server_name = cfg
+            .server_name
+            .clone()
+            .map(ServerName::try_from)
+            .transpose()
+            .map_err(|e| Error::InvalidCert(e.to_string()))?;
+
+        Ok(Self {
+            acceptor: TlsAcceptor::from(Arc::new(server)),
+            connector: TlsConnector::from(Arc::new(client)),
+            server_name,
+            handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+        })
+    }
+
+    /// Drops connections whose peer does not complete the TLS handshake within `timeout`
+    pub fn with_handshake_timeout(mut self, timeout: Duration) -> Self {
+        self.handshake_timeout = timeout;
+        self
+    }
+
+    pub(crate) async fn accept(&self, stream: TcpStream) -> io::Result<TlsStream<TcpStream>> {
+        let handshake = self.acceptor.accept(stream);
+        match time::timeout(self.handshake_timeout, handshake).await {
+            Ok(stream) => stream.map(TlsStream::Server),
+            Err(_) => Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "TLS handshake timed out",
+            )),
+        }
+    }
+
+    pub(crate) async fn connect(&self, stream: TcpStream) -> io::Result<TlsStream<TcpStream>> {
+        let server_name = match &self.server_name {
+            Some(server_name) => server_name.clone(),
+            None => ServerName::IpAddress(stream.peer_addr()?.ip().into()),
+        };
+        self.connector
+            .connect(server_name, stream)
+            .await
+            .map(TlsStream::Client)
+    }
+}
+
+/// Opens a raft RPC connection to `addr`, over TLS if the cluster uses it
+pub(crate) async fn connect(
+    addr: impl ToSocketAddrs,
+    tls: Option<&ClusterTls>,
+) -> Result<RpcStream, Error> {
+    let stream = TcpStream::connect(addr).await?;
+    match tls {
+        Some(tls) => Ok(Either::Right(tls.connect(stream).await?)),
+        None => Ok(Either::Left(stream)),
+    }
+}
+
+/// Listener of the HTTP API of a node, with the mutual TLS of the raft RPC
+///
+/// The handshakes run concurrently, a peer which does not complete its own only holds up
+/// itself.
+#[cfg(not(feature = "cluster-sim"))]
+pub(crate) struct TlsListener {
+    listener: TcpListener,
+    tls: ClusterTls,
+    handshakes: FuturesUnordered<Handshake>,
+}
+
+#[cfg(not(feature = "cluster-sim"))]
+type Handshake = BoxFuture<'static, (io::Result<TlsStream<TcpStream>>, std::net::SocketAddr)>;
+
+#[cfg(not(feature = "cluster-sim"))]
+impl TlsListener {
+    pub(crate) fn new(listener: TcpListener, tls: ClusterTls) -> Self {
+        Self {
+            listener,
+            tls,
+            handshakes: FuturesUnordered::new(),
+        }
+    }
+}
+
+#[cfg(not(feature = "cluster-sim"))]
+impl axum::serve::Listener for TlsListener {
+    type Io = TlsStream<TcpStream>;
+    type Addr = std::net::SocketAddr;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        loop {
+            tokio::select! {
+                (stream, addr) = axum::serve::Listener::accept(&mut self.listener) => {
+                    let tls = self.tls.clone();
+                    self.handshakes
+                        .push(Box::pin(async move { (tls.accept(stream).await, addr) }));
+                }
+                Some((stream, addr)) = self.handshakes.next(), if !self.handshakes.is_empty() => {
+                    match stream {
+                        Ok(stream) => return (stream, addr),
+                        Err(e) => warn!("TLS handshake with {addr} failed: {e}"),
+                    }
+                }
+            }
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<Self::Addr> {
+        self.listener.local_addr()
+    }
+}
+
+fn certs(path: &Path) -> Result<Vec<CertificateDer<'static>>, Error> {
+    let file = &mut BufReader::new(File::open(path)?);
+    Ok(rustls_pemfile::certs(file).collect::<Result<Vec<_>, _>>()?)
+}
+
+fn root_store(path: &Path) -> Result<RootCertStore, Error> {
+    let mut roots = RootCertStore::empty();
+    for root in certs(path)? {
+        roots
+            .add(root)
+            .map_err(|e| Error::InvalidCert(e.to_string()))?;
+    }
+    Ok(roots)
+}
+
+#[cfg(all(test, not(feature = "cluster-sim")))]
+mod tests {
+    use axum::{routing::get, Router};
+    use tokio::{
+        io::{AsyncReadExt as _, AsyncWriteExt as _},
+        net::TcpListener,
+    };
+
+    use super::*;
+
+    fn tls(name: &str) -> ClusterTls {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/certs/cluster");
+        let cfg = ClusterTlsConfig::new(
+            dir.join("ca.pem"),
+            dir.join(format!("{name}.pem")),
+            dir.join(format!("{name}.key")),
+        );
+        ClusterTls::new(&cfg).unwrap()
+    }
+
+    async fn handshake(server: &ClusterTls, client: ClusterTls) -> io::Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = tokio::spawn(async move {
+            let stream = TcpStream::connect(addr).await?;
+            let mut stream = client.connect(stream).await?;
+            // with TLS 1.3 the client learns about a refused certificate on its first read
+            stream.read(&mut [0; 1]).await
+        });
+        let (stream, _) = listener.accept().await.unwrap();
+        let accepted = server.accept(stream).await.map(drop);
+        drop(client);
+        accepted
+    }
+
+    #[tokio::test]
+    async fn only_peers_signed_by_the_cluster_ca_are_accepted() {
+        let server = tls("node");
+        handshake(&server, tls("node")).await.unwrap();
+        assert!(handshake(&server, tls("rogue")).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn silent_peer_times_out() {
+        let server = tls("node").with_handshake_timeout(Duration::from_millis(50));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let _client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+        let err = server.accept(stream).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    }
+
+    async fn get_status(addr: std::net::SocketAddr, client: ClusterTls) -> io::Result<String> {
+        let stream = TcpStream::connect(addr).await?;
+        let mut stream = client.connect(stream).await?;
+        stream
+            .write_all(b"GET /status HTTP/1.1\r\nhost: node\r\nconnection: close\r\n\r\n")
+            .await?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await?;
+        Ok(response)
+    }
+
+    #[tokio::test]
+    async fn api_takes_only_peers_signed_by_the_cluster_ca() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = Router::new().route("/status", get(|| async { "ok" }));
+        let listener = TlsListener::new(listener, tls("node"));
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        // a peer which never starts its handshake holds up nobody else
+        let _silent = TcpStream::connect(addr).await.unwrap();
+        let response = get_status(addr, tls("node")).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{response}");
+        assert!(response.ends_with("ok"), "{response}");
+
+        assert!(get_status(addr, tls("rogue")).await.is_err());
+    }
+}
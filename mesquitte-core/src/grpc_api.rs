@@ -0,0 +1,380 @@
+//! gRPC API of the broker
+//!
+//! The `mesquitte.v1.Broker` service of `proto/mesquitte.proto` mirrors the HTTP API: `Publish`
+//! publishes a message without an MQTT connection, as `client_id` or `$grpc`, through the same
+//! checks as a publish of a client, and `GetWill` answers the will of a client. `Subscribe`
+//! opens a session of its own on the broker, subscribed with the filters of the request, and
+//! streams the messages it gets until the call is cancelled. Messages with QoS 1 and 2 are
+//! acknowledged once they are handed to the stream.
+//!
+//! A publish the publisher is not authorized for is answered `PERMISSION_DENIED`, like a
+//! refused subscription, and one rejected by an interceptor `FAILED_PRECONDITION`. Malformed
+//! requests are answered `INVALID_ARGUMENT` and a client without a will `NOT_FOUND`.
+//!
+//! With a token set, requests without `authorization: Bearer <token>` metadata are answered
+//! `UNAUTHENTICATED`.
+
+use std::{pin::Pin, sync::Arc};
+
+use futures::{SinkExt as _, Stream, StreamExt as _};
+use mqtt_codec_kit::{
+    common::{qos::QoSWithPacketIdentifier, TopicFilter},
+    v4::{
+        control::ConnectReturnCode,
+        packet::{
+            suback::SubscribeReturnCode, ConnectPacket, PubackPacket, PubcompPacket, PublishPacket,
+            PubrecPacket, SubscribePacket, VariablePacket,
+        },
+    },
+};
+use tokio::net::TcpListener;
+use tokio_stream::wrappers::TcpListenerStream;
+use tonic::{transport::Server, Request, Response, Status};
+
+use crate::{
+    debug,
+    server::{
+        api,
+        local::{connect_local, LocalSession},
+        state::{GlobalState, PublishVerdict},
+    },
+    store::{
+        message::{MessageStore, PublishMessage},
+        retain::RetainMessageStore,
+        topic::TopicStore,
+    },
+    warn,
+};
+
+pub mod proto {
+    tonic::include_proto!("mesquitte.v1");
+}
+
+use proto::{
+    broker_server::{Broker, BrokerServer},
+    GetWillRequest, Message, PublishRequest, PublishResponse, SubscribeRequest, Will,
+};
+
+/// Publisher of the messages which do not name one
+pub const GRPC_PUBLISHER: &str = "$grpc";
+
+pub struct GrpcApi<S> {
+    global: Arc<GlobalState<S>>,
+    token: Option<String>,
+}
+
+impl<S> GrpcApi<S>
+where
+    S: MessageStore + RetainMessageStore + TopicStore + 'static,
+{
+    pub fn new(global: Arc<GlobalState<S>>) -> Self {
+        Self {
+            global,
+            token: None,
+        }
+    }
+
+    /// Requires `authorization: Bearer <token>` metadata on every request
+    pub fn with_token(mut self, token: impl Into<String>) -> Self {
+        self.token = Some(token.into());
+        self
+    }
+
+    /// The service of the API, to serve it or add it to another server
+    pub fn service(self) -> BrokerServer<Self> {
+        BrokerServer::new(self)
+    }
+
+    pub async fn serve(self, listener: TcpListener) -> Result<(), tonic::transport::Error> {
+        Server::builder()
+            .add_service(self.service())
+            .serve_with_incoming(TcpListenerStream::new(listener))
+            .await
+    }
+
+    fn authorize<T>(&self, request: &Request<T>) -> Result<(), Status> {
+        let Some(token) = &self.token else {
+            return Ok(());
+        };
+        let authorized = request
+            .metadata()
+            .get("authorization")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .is_some_and(|bearer| bearer == token);
+        if authorized {
+            Ok(())
+        } else {
+            Err(Status::unauthenticated("missing or wrong token"))
+        }
+    }
+}
+
+type MessageStream = Pin<Box<dyn Stream<Item = Result<Message, Status>> + Send + 'static>>;
+
+#[tonic::async_trait]
+impl<S> Broker for GrpcApi<S>
+where
+    S: MessageStore + RetainMessageStore + TopicStore + 'static,
+{
+    async fn publish(
+        &self,
+        request: Request<PublishRequest>,
+    ) -> Result<Response<PublishResponse>, Status> {
+        self.authorize(&request)?;
+        let mut request = request.into_inner();
+        let publisher = request
+            .client_id
+            .take()
+            .unwrap_or_else(|| GRPC_PUBLISHER.to_owned());
+        let message = message(request).map_err(Status::invalid_argument)?;
+        if let Some(max) = self.global.max_payload_size() {
+            if message.payload().len() > max {
+                return Err(Status::invalid_argument("payload is too large"));
+            }
+        }
+
+        match self.global.publish(&publisher, message).await {
+            Ok(PublishVerdict::Accepted) => Ok(Response::new(PublishResponse {})),
+            Ok(PublishVerdict::NotAuthorized) => Err(Status::permission_denied("not authorized")),
+            Ok(PublishVerdict::Rejected) => Err(Status::failed_precondition("rejected")),
+            Err(err) => {
+                warn!("grpc publish of {publisher} failed: {err}");
+                Err(Status::internal("publish failed"))
+            }
+        }
+    }
+
+    async fn get_will(&self, request: Request<GetWillRequest>) -> Result<Response<Will>, Status> {
+        self.authorize(&request)?;
+        let client_id = request.into_inner().client_id;
+        match self.global.client_will(&client_id) {
+            Some(will) => Ok(Response::new(Will {
+                topic: will.topic_name.clone(),
+                payload: will.payload.clone(),
+                qos: will.qos.into(),
+                retain: will.retain,
+            })),
+            None => Err(Status::not_found("client has no will")),
+        }
+    }
+
+    type SubscribeStream = MessageStream;
+
+    async fn subscribe(
+        &self,
+        request: Request<SubscribeRequest>,
+    ) -> Result<Response<Self::SubscribeStream>, Status> {
+        self.authorize(&request)?;
+        let remote_addr = request.remote_addr();
+        let request = request.into_inner();
+        let mut subscribes = Vec::with_capacity(request.subscriptions.len());
+        for subscription in request.subscriptions {
+            let filter = TopicFilter::new(subscription.topic_filter)
+                .map_err(|err| Status::invalid_argument(err.to_string()))?;
+            let qos = api::qos(subscription.qos).map_err(Status::invalid_argument)?;
+            subscribes.push((filter, qos));
+        }
+        if subscribes.is_empty() {
+            return Err(Status::invalid_argument("no subscriptions"));
+        }
+
+        let mut connect = ConnectPacket::new(request.client_id);
+        connect.set_clean_session(true);
+        connect.set_username(request.username);
+        connect.set_password(request.password);
+        let Some((mut session, code)) =
+            connect_local(&self.global, remote_addr, None, connect).await
+        else {
+            return Err(Status::unavailable("broker did not answer"));
+        };
+        match code {
+            ConnectReturnCode::ConnectionAccepted => {}
+            ConnectReturnCode::BadUserNameOrPassword => {
+                return Err(Status::unauthenticated("bad username or password"))
+            }
+            ConnectReturnCode::NotAuthorized => {
+                return Err(Status::permission_denied("not authorized"))
+            }
+            ConnectReturnCode::IdentifierRejected => {
+                return Err(Status::invalid_argument("client id rejected"))
+            }
+            code => return Err(Status::unavailable(format!("connection refused: {code:?}"))),
+        }
+
+        let filters: Vec<_> = subscribes
+            .iter()
+            .map(|(filter, _)| filter.clone())
+            .collect();
+        let suback = async {
+            session
+                .send(SubscribePacket::new(1, subscribes))
+                .await
+                .ok()?;
+            loop {
+                if let VariablePacket::SubackPacket(suback) = session.next().await?.ok()? {
+                    return Some(suback);
+                }
+            }
+        };
+        let Some(suback) = suback.await else {
+            return Err(Status::unavailable("broker did not answer"));
+        };
+        let refused = suback
+            .return_codes()
+            .iter()
+            .zip(&filters)
+            .find(|(code, _)| **code == SubscribeReturnCode::Failure);
+        if let Some((_, filter)) = refused {
+            return Err(Status::permission_denied(format!(
+                "subscription to {filter} refused"
+            )));
+        }
+
+        Ok(Response::new(Box::pin(futures::stream::unfold(
+            session,
+            next_message,
+        ))))
+    }
+}
+
+/// The next message of a subscribing session, acknowledged to the broker, `None` once the
+/// broker closed the session
+async fn next_message(
+    mut session: LocalSession,
+) -> Option<(Result<Message, Status>, LocalSession)> {
+    loop {
+        match session.next().await? {
+            Ok(VariablePacket::PublishPacket(publish)) => {
+                let acked = match publish.qos() {
+                    QoSWithPacketIdentifier::Level0 => Ok(()),
+                    QoSWithPacketIdentifier::Level1(pkid) => {
+                        session.send(PubackPacket::new(pkid)).await
+                    }
+                    QoSWithPacketIdentifier::Level2(pkid) => {
+                        session.send(PubrecPacket::new(pkid)).await
+                    }
+                };
+                acked.ok()?;
+                return Some((Ok(stream_message(&publish)), session));
+            }
+            Ok(VariablePacket::PubrelPacket(pubrel)) => {
+                let pubcomp = PubcompPacket::new(pubrel.packet_identifier());
+                session.send(pubcomp).await.ok()?;
+            }
+            Ok(_) => {}
+            Err(err) => {
+                debug!("grpc subscription failed: {err}");
+                return None;
+            }
+        }
+    }
+}
+
+fn stream_message(publish: &PublishPacket) -> Message {
+    let (qos, _) = publish.qos().split();
+    Message {
+        topic: publish.topic_name().to_string(),
+        payload: publish.payload().to_vec(),
+        qos: qos as u32,
+        retain: publish.retain(),
+        dup: publish.dup(),
+    }
+}
+
+fn message(request: PublishRequest) -> Result<PublishMessage, String> {
+    api::publish_message(api::PublishRequest {
+        topic: request.topic,
+        payload: request.payload,
+        qos: request.qos,
+        retain: request.retain,
+        #[cfg(feature = "v5")]
+        properties: request
+            .properties
+            .map(|properties| api::PublishRequestProperties {
+                payload_format_indicator: properties.payload_format_indicator,
+                message_expiry_interval: properties.message_expiry_interval,
+                content_type: properties.content_type,
+                response_topic: properties.response_topic,
+                correlation_data: properties.correlation_data,
+                user_properties: properties
+                    .user_properties
+                    .into_iter()
+                    .map(|property| (property.key, property.value))
+                    .collect(),
+            }),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use tonic::{metadata::MetadataValue, transport::Endpoint, Code};
+
+    use super::{
+        proto::{broker_client::BrokerClient, Subscription},
+        *,
+    };
+    use crate::store::{memory::MemoryStore, memory_storage};
+
+    #[tokio::test]
+    async fn messages_are_streamed_to_subscribers() {
+        let global = Arc::new(GlobalState::new(memory_storage()));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(GrpcApi::new(global).with_token("secret").serve(listener));
+
+        let channel = Endpoint::from_shared(format!("http://{addr}"))
+            .unwrap()
+            .connect()
+            .await
+            .unwrap();
+        let mut anonymous = BrokerClient::new(channel.clone());
+        let publish = PublishRequest {
+            topic: "a/b".to_owned(),
+            payload: b"hi".to_vec(),
+            qos: 1,
+            ..Default::default()
+        };
+        let err = anonymous.publish(publish.clone()).await.unwrap_err();
+        assert_eq!(err.code(), Code::Unauthenticated);
+
+        let token: MetadataValue<_> = "Bearer secret".parse().unwrap();
+        let mut client = BrokerClient::with_interceptor(channel, move |mut request: Request<()>| {
+            request
+                .metadata_mut()
+                .insert("authorization", token.clone());
+            Ok(request)
+        });
+        let subscribe = SubscribeRequest {
+            client_id: "backend".to_owned(),
+            subscriptions: vec![Subscription {
+                topic_filter: "a/#".to_owned(),
+                qos: 1,
+            }],
+            ..Default::default()
+        };
+        let mut messages = client.subscribe(subscribe).await.unwrap().into_inner();
+        client.publish(publish).await.unwrap();
+        let message = messages.message().await.unwrap().unwrap();
+        assert_eq!(
+            (
+                message.topic.as_str(),
+                message.payload.as_slice(),
+                message.qos
+            ),
+            ("a/b", b"hi".as_slice(), 1)
+        );
+
+        let reserved = PublishRequest {
+            topic: "$SYS/a".to_owned(),
+            ..Default::default()
+        };
+        let err = client.publish(reserved).await.unwrap_err();
+        assert_eq!(err.code(), Code::PermissionDenied);
+        let will = GetWillRequest {
+            client_id: "backend".to_owned(),
+        };
+        let err = client.get_will(will).await.unwrap_err();
+        assert_eq!(err.code(), Code::NotFound);
+    }
+}
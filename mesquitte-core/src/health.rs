@@ -0,0 +1,234 @@
+//! HTTP health probes, e.g. for Kubernetes
+//!
+//! `GET /healthz` answers `200 OK` as long as the process serves HTTP at all, for a liveness
+//! probe.
+//!
+//! `GET /readyz` answers `200 OK` when the broker can take clients, `503 Service Unavailable`
+//! otherwise, with the result of each check:
+//!
+//! ```json
+//! {"ready": false, "listeners": true, "storage": true, "cluster": false}
+//! ```
+//!
+//! - `listeners`: the listeners of the broker are bound, see [`Health::with_listeners`]
+//! - `storage`: the storage answers a lookup of the retained messages in time
+//! - `cluster`: the raft cluster has a leader, see [`Health::with_raft`]
+//!
+//! A check which is not configured is `null` and does not make the broker unready.
+//!
+//! The probes are served on their own listener with [`Health::serve`], or merged into the
+//! router of the [`HttpApi`](crate::http_api::HttpApi). They are never authenticated.
+
+use std::{io, sync::Arc, time::Duration};
+
+use axum::{
+    extract::State,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::get,
+    Json, Router,
+};
+use mqtt_codec_kit::common::TopicFilter;
+use serde::Serialize;
+use tokio::{net::TcpListener, time::timeout};
+
+#[cfg(all(
+    feature = "cluster",
+    any(
+        all(feature = "heed-storage", not(feature = "rocksdb-storage")),
+        all(feature = "rocksdb-storage", not(feature = "heed-storage"))
+    )
+))]
+use crate::cluster::typ::Raft;
+use crate::{
+    broker::ListenersBound,
+    server::state::GlobalState,
+    store::{message::MessageStore, retain::RetainMessageStore, topic::TopicStore},
+    warn,
+};
+
+/// Time the storage has to answer a readiness check
+const STORAGE_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Serialize)]
+struct Readiness {
+    ready: bool,
+    listeners: Option<bool>,
+    storage: bool,
+    cluster: Option<bool>,
+}
+
+pub struct Health<S> {
+    global: Arc<GlobalState<S>>,
+    listeners: Option<ListenersBound>,
+    #[cfg(all(
+        feature = "cluster",
+        any(
+            all(feature = "heed-storage", not(feature = "rocksdb-storage")),
+            all(feature = "rocksdb-storage", not(feature = "heed-storage"))
+        )
+    ))]
+    raft: Option<Raft>,
+}
+
+impl<S> Health<S>
+where
+    S: MessageStore + RetainMessageStore + TopicStore + 'static,
+{
+    pub fn new(global: Arc<GlobalState<S>>) -> Self {
+        Self {
+            global,
+            listeners: None,
+            #[cfg(all(
+                feature = "cluster",
+                any(
+                    all(feature = "heed-storage", not(feature = "rocksdb-storage")),
+                    all(feature = "rocksdb-storage", not(feature = "heed-storage"))
+                )
+            ))]
+            raft: None,
+        }
+    }
+
+    /// Reports unready until the listeners of the broker are bound, see
+    /// [`Broker::listeners_bound`](crate::broker::Broker::listeners_bound)
+    pub fn with_listeners(mut self, listeners: ListenersBound) -> Self {
+        self.listeners = Some(listeners);
+        self
+    }
+
+    /// Reports unready while the cluster of `raft` has no leader
+    #[cfg(all(
+        feature = "cluster",
+        any(
+            all(feature = "heed-storage", not(feature = "rocksdb-storage")),
+            all(feature = "rocksdb-storage", not(feature = "heed-storage"))
+        )
+    ))]
+    pub fn with_raft(mut self, raft: Raft) -> Self {
+        self.raft = Some(raft);
+        self
+    }
+
+    /// The routes of the probes, to serve them or merge them into another router
+    pub fn router(self) -> Router {
+        Router::new()
+            .route("/healthz", get(healthz))
+            .route("/readyz", get(readyz::<S>))
+            .with_state(Arc::new(self))
+    }
+
+    pub async fn serve(self, listener: TcpListener) -> io::Result<()> {
+        axum::serve(listener, self.router()).await
+    }
+
+    async fn readiness(&self) -> Readiness {
+        let listeners = self.listeners.as_ref().map(ListenersBound::get);
+        let storage = self.storage_reachable().await;
+        #[allow(unused_mut)]
+        let mut cluster = None;
+        #[cfg(all(
+            feature = "cluster",
+            any(
+                all(feature = "heed-storage", not(feature = "rocksdb-storage")),
+                all(feature = "rocksdb-storage", not(feature = "heed-storage"))
+            )
+        ))]
+        if let Some(raft) = &self.raft {
+            cluster = Some(raft.metrics().borrow().current_leader.is_some());
+        }
+        Readiness {
+            ready: listeners != Some(false) && storage && cluster != Some(false),
+            listeners,
+            storage,
+            cluster,
+        }
+    }
+
+    async fn storage_reachable(&self) -> bool {
+        let Ok(filter) = TopicFilter::new("$SYS/health") else {
+            return false;
+        };
+        match timeout(
+            STORAGE_TIMEOUT,
+            self.global.retained_messages(&filter, None, 1),
+        )
+        .await
+        {
+            Ok(Ok(_)) => true,
+            Ok(Err(err)) => {
+                warn!("readiness check of the storage failed: {err}");
+                false
+            }
+            Err(_) => {
+                warn!("readiness check of the storage timed out");
+                false
+            }
+        }
+    }
+}
+
+async fn healthz() -> StatusCode {
+    StatusCode::OK
+}
+
+async fn readyz<S>(State(health): State<Arc<Health<S>>>) -> Response
+where
+    S: MessageStore + RetainMessageStore + TopicStore + 'static,
+{
+    let readiness = health.readiness().await;
+    let status = if readiness.ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, Json(readiness)).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+    };
+
+    use super::*;
+    use crate::store::memory_storage;
+
+    // the status code and body of the answer to a `GET` of `path`
+    async fn get(addr: &str, path: &str) -> (u16, String) {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let request = format!("GET {path} HTTP/1.1\r\nhost: {addr}\r\nconnection: close\r\n\r\n");
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        let body = response.split("\r\n\r\n").nth(1).unwrap_or_default();
+        (response[9..12].parse().unwrap(), body.to_owned())
+    }
+
+    #[tokio::test]
+    async fn ready_once_listeners_are_bound() {
+        let global = Arc::new(GlobalState::new(memory_storage()));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let bound = ListenersBound::default();
+        tokio::spawn(
+            Health::new(global)
+                .with_listeners(bound.clone())
+                .serve(listener),
+        );
+
+        assert_eq!(get(&addr, "/healthz").await.0, 200);
+        let (status, body) = get(&addr, "/readyz").await;
+        assert_eq!(status, 503);
+        assert_eq!(
+            body,
+            r#"{"ready":false,"listeners":false,"storage":true,"cluster":null}"#
+        );
+
+        bound.set(true);
+        let (status, body) = get(&addr, "/readyz").await;
+        assert_eq!(status, 200);
+        assert!(body.starts_with(r#"{"ready":true"#));
+    }
+}
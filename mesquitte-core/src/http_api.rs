@@ -0,0 +1,493 @@
+//! HTTP API of the broker
+//!
+//! `POST /api/v1/publish` publishes a message into the broker without an MQTT connection, e.g.
+//! from a serverless function:
+//!
+//! ```json
+//! {"topic": "a/b", "payload": "aGk=", "encoding": "base64", "qos": 1, "retain": false,
+//!  "client_id": "fn-1",
+//!  "properties": {"content_type": "text/plain", "user_properties": [["k", "v"]]}}
+//! ```
+//!
+//! Only `topic` is required, `payload` is plain text by default. The message is published as
+//! `client_id`, `$http` if none is given, and goes through the same checks as a publish of a
+//! client. The MQTT 5 `properties` are `payload_format_indicator`, `message_expiry_interval`,
+//! `content_type`, `response_topic`, `correlation_data` in base64 and `user_properties`.
+//!
+//! The answer is `{"result": "accepted"}`, or `"not_authorized"` with `403 Forbidden` and
+//! `"rejected"` by an interceptor with `422 Unprocessable Entity`. Malformed requests are
+//! answered `400 Bad Request`.
+//!
+//! `GET /api/v1/clients/{client_id}/will` answers the will the client leaves if its connection
+//! is lost now, as `{"topic": "a/b", "payload": "aGk=", "qos": 1, "retain": false}` with the
+//! payload in base64, or `404 Not Found` if it has none.
+//!
+//! `GET /api/v1/clients/{client_id}/status` answers whether the client is connected and when it
+//! was last seen, see [`GlobalState::client_status`], with the times in milliseconds since the
+//! Unix epoch:
+//!
+//! ```json
+//! {"online": false, "connected_at": 1736908800123, "disconnected_at": 1736908805678,
+//!  "ip_address": "10.0.0.7"}
+//! ```
+//!
+//! A client the broker does not know is answered `404 Not Found`.
+//!
+//! With the `session-export` feature, `GET /api/v1/clients/{client_id}/session` answers the
+//! session state of the client as JSON, see [`SessionExport::to_json`], or `404 Not Found` if
+//! the broker keeps nothing for it. `PUT` on the same path replaces the session state by such an
+//! export, e.g. from another broker, and is answered `204 No Content`, `400 Bad Request` for an
+//! export of another client or `409 Conflict` while the client is connected.
+//!
+//! With a token set, requests without `Authorization: Bearer <token>` are answered
+//! `401 Unauthorized`.
+
+use std::{
+    io,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+#[cfg(feature = "session-export")]
+use axum::http::header::CONTENT_TYPE;
+use axum::{
+    body::Bytes,
+    extract::{Path, State},
+    http::{header::AUTHORIZATION, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use serde::{Deserialize, Serialize};
+use tokio::net::TcpListener;
+
+#[cfg(feature = "session-export")]
+use crate::store::{error::StoreError, export::SessionExport};
+use crate::{
+    server::{
+        api,
+        state::{GlobalState, PublishVerdict},
+    },
+    store::{
+        message::{MessageStore, PublishMessage},
+        retain::RetainMessageStore,
+        topic::TopicStore,
+    },
+    warn,
+};
+
+/// Publisher of the messages which do not name one
+pub const HTTP_PUBLISHER: &str = "$http";
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Encoding {
+    #[default]
+    Plain,
+    Base64,
+}
+
+#[derive(Debug, Deserialize)]
+struct PublishRequest {
+    topic: String,
+    #[serde(default)]
+    payload: String,
+    #[serde(default)]
+    encoding: Encoding,
+    #[serde(default)]
+    qos: u8,
+    #[serde(default)]
+    retain: bool,
+    client_id: Option<String>,
+    #[cfg(feature = "v5")]
+    properties: Option<Properties>,
+}
+
+#[cfg(feature = "v5")]
+#[derive(Debug, Deserialize)]
+struct Properties {
+    payload_format_indicator: Option<u8>,
+    message_expiry_interval: Option<u32>,
+    content_type: Option<String>,
+    response_topic: Option<String>,
+    correlation_data: Option<String>,
+    #[serde(default)]
+    user_properties: Vec<(String, String)>,
+}
+
+#[derive(Serialize)]
+struct PublishResponse {
+    result: &'static str,
+}
+
+#[derive(Serialize)]
+struct WillResponse {
+    topic: String,
+    payload: String,
+    qos: u8,
+    retain: bool,
+}
+
+#[derive(Serialize)]
+struct StatusResponse {
+    online: bool,
+    connected_at: Option<u64>,
+    disconnected_at: Option<u64>,
+    ip_address: Option<String>,
+}
+
+pub struct HttpApi<S> {
+    global: Arc<GlobalState<S>>,
+    token: Option<String>,
+}
+
+impl<S> HttpApi<S>
+where
+    S: MessageStore + RetainMessageStore + TopicStore + 'static,
+{
+    pub fn new(global: Arc<GlobalState<S>>) -> Self {
+        Self {
+            global,
+            token: None,
+        }
+    }
+
+    /// Requires `Authorization: Bearer <token>` on every request
+    pub fn with_token(mut self, token: impl Into<String>) -> Self {
+        self.token = Some(token.into());
+        self
+    }
+
+    /// The routes of the API, to serve them or merge them into another router
+    pub fn router(self) -> Router {
+        let router = Router::new()
+            .route("/api/v1/publish", post(publish::<S>))
+            .route("/api/v1/clients/{client_id}/will", get(will::<S>))
+            .route("/api/v1/clients/{client_id}/status", get(status::<S>));
+        #[cfg(feature = "session-export")]
+        let router = router.route(
+            "/api/v1/clients/{client_id}/session",
+            get(export_session::<S>).put(import_session::<S>),
+        );
+        router.with_state(Arc::new(self))
+    }
+
+    pub async fn serve(self, listener: TcpListener) -> io::Result<()> {
+        axum::serve(listener, self.router()).await
+    }
+
+    fn is_authorized(&self, headers: &HeaderMap) -> bool {
+        let Some(token) = &self.token else {
+            return true;
+        };
+        headers
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .is_some_and(|bearer| bearer == token)
+    }
+}
+
+async fn publish<S>(State(api): State<Arc<HttpApi<S>>>, headers: HeaderMap, body: Bytes) -> Response
+where
+    S: MessageStore + RetainMessageStore + TopicStore + 'static,
+{
+    if !api.is_authorized(&headers) {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    let request: PublishRequest = match serde_json::from_slice(&body) {
+        Ok(request) => request,
+        Err(err) => return bad_request(err),
+    };
+    let publisher = request
+        .client_id
+        .as_deref()
+        .unwrap_or(HTTP_PUBLISHER)
+        .to_owned();
+    let message = match message(request) {
+        Ok(message) => message,
+        Err(err) => return bad_request(err),
+    };
+    if let Some(max) = api.global.max_payload_size() {
+        if message.payload().len() > max {
+            return StatusCode::PAYLOAD_TOO_LARGE.into_response();
+        }
+    }
+
+    let (status, result) = match api.global.publish(&publisher, message).await {
+        Ok(PublishVerdict::Accepted) => (StatusCode::OK, "accepted"),
+        Ok(PublishVerdict::NotAuthorized) => (StatusCode::FORBIDDEN, "not_authorized"),
+        Ok(PublishVerdict::Rejected) => (StatusCode::UNPROCESSABLE_ENTITY, "rejected"),
+        Err(err) => {
+            warn!("http publish of {publisher} failed: {err}");
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+    (status, Json(PublishResponse { result })).into_response()
+}
+
+async fn will<S>(
+    State(api): State<Arc<HttpApi<S>>>,
+    Path(client_id): Path<String>,
+    headers: HeaderMap,
+) -> Response
+where
+    S: MessageStore + RetainMessageStore + TopicStore + 'static,
+{
+    if !api.is_authorized(&headers) {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    match api.global.client_will(&client_id) {
+        Some(will) => Json(WillResponse {
+            topic: will.topic_name.clone(),
+            payload: STANDARD.encode(&will.payload),
+            qos: will.qos,
+            retain: will.retain,
+        })
+        .into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+async fn status<S>(
+    State(api): State<Arc<HttpApi<S>>>,
+    Path(client_id): Path<String>,
+    headers: HeaderMap,
+) -> Response
+where
+    S: MessageStore + RetainMessageStore + TopicStore + 'static,
+{
+    if !api.is_authorized(&headers) {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    let millis = |time: SystemTime| {
+        time.duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_millis() as u64)
+            .unwrap_or_default()
+    };
+    match api.global.client_status(&client_id).await {
+        Some(status) => Json(StatusResponse {
+            online: status.online,
+            connected_at: status.connected_at.map(millis),
+            disconnected_at: status.disconnected_at.map(millis),
+            ip_address: status.ip_address.map(|ip| ip.to_string()),
+        })
+        .into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+#[cfg(feature = "session-export")]
+async fn export_session<S>(
+    State(api): State<Arc<HttpApi<S>>>,
+    Path(client_id): Path<String>,
+    headers: HeaderMap,
+) -> Response
+where
+    S: MessageStore + RetainMessageStore + TopicStore + 'static,
+{
+    if !api.is_authorized(&headers) {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    let export = match api.global.export_session(&client_id).await {
+        Ok(export) => export,
+        Err(err) => {
+            warn!("http export of the session of {client_id} failed: {err}");
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+    if export.session.is_none()
+        && export.messages.received.is_empty()
+        && export.messages.pending.is_empty()
+    {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+    match export.to_json() {
+        Ok(json) => ([(CONTENT_TYPE, "application/json")], json).into_response(),
+        Err(err) => {
+            warn!("http export of the session of {client_id} failed: {err}");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+#[cfg(feature = "session-export")]
+async fn import_session<S>(
+    State(api): State<Arc<HttpApi<S>>>,
+    Path(client_id): Path<String>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response
+where
+    S: MessageStore + RetainMessageStore + TopicStore + 'static,
+{
+    if !api.is_authorized(&headers) {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    let json = match std::str::from_utf8(&body) {
+        Ok(json) => json,
+        Err(err) => return bad_request(err),
+    };
+    let export = match SessionExport::from_json(json) {
+        Ok(export) => export,
+        Err(err) => return bad_request(err),
+    };
+    if export.client_id != client_id {
+        return bad_request(format!("session of {} exported", export.client_id));
+    }
+
+    match api.global.import_session(export).await {
+        Ok(()) => StatusCode::NO_CONTENT.into_response(),
+        Err(StoreError::Conflict(reason)) => (StatusCode::CONFLICT, reason).into_response(),
+        Err(err) => {
+            warn!("http import of the session of {client_id} failed: {err}");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+fn bad_request(err: impl ToString) -> Response {
+    (StatusCode::BAD_REQUEST, err.to_string()).into_response()
+}
+
+fn message(request: PublishRequest) -> Result<PublishMessage, String> {
+    let payload = match request.encoding {
+        Encoding::Plain => request.payload.into_bytes(),
+        Encoding::Base64 => STANDARD
+            .decode(request.payload)
+            .map_err(|err| format!("payload: {err}"))?,
+    };
+    #[cfg(feature = "v5")]
+    let properties = match request.properties {
+        Some(properties) => Some(api::PublishRequestProperties {
+            payload_format_indicator: properties.payload_format_indicator.map(u32::from),
+            message_expiry_interval: properties.message_expiry_interval,
+            content_type: properties.content_type,
+            response_topic: properties.response_topic,
+            correlation_data: properties
+                .correlation_data
+                .map(|data| STANDARD.decode(data))
+                .transpose()
+                .map_err(|err| format!("correlation data: {err}"))?,
+            user_properties: properties.user_properties,
+        }),
+        None => None,
+    };
+    api::publish_message(api::PublishRequest {
+        topic: request.topic,
+        payload,
+        qos: request.qos.into(),
+        retain: request.retain,
+        #[cfg(feature = "v5")]
+        properties,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use mqtt_codec_kit::common::TopicFilter;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+    };
+
+    use super::*;
+    use crate::store::memory_storage;
+
+    // the status code and the body of the answer to `method` on `path`
+    async fn send(addr: &str, token: &str, method: &str, path: &str, body: &str) -> (u16, String) {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let request = format!(
+            "{method} {path} HTTP/1.1\r\nhost: {addr}\r\nauthorization: Bearer {token}\r\n\
+             content-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n\
+             {body}",
+            body.len()
+        );
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        let status = response[9..12].parse().unwrap();
+        let body = response
+            .split_once("\r\n\r\n")
+            .map(|(_, body)| body.to_owned())
+            .unwrap_or_default();
+        (status, body)
+    }
+
+    // the status code of the answer to a publish of `body`
+    async fn post(addr: &str, token: &str, body: &str) -> u16 {
+        send(addr, token, "POST", "/api/v1/publish", body).await.0
+    }
+
+    #[tokio::test]
+    async fn publishes_are_checked() {
+        let global = Arc::new(GlobalState::new(memory_storage()));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let api = HttpApi::new(global.clone()).with_token("secret");
+        tokio::spawn(api.serve(listener));
+
+        let retained =
+            r#"{"topic": "a/b", "payload": "aGk=", "encoding": "base64", "retain": true}"#;
+        assert_eq!(post(&addr, "wrong", retained).await, 401);
+        assert_eq!(post(&addr, "secret", retained).await, 200);
+        let reserved = r#"{"topic": "$SYS/a"}"#;
+        assert_eq!(post(&addr, "secret", reserved).await, 403);
+        assert_eq!(
+            post(&addr, "secret", r#"{"topic": "a", "qos": 3}"#).await,
+            400
+        );
+
+        let filter = TopicFilter::new("#").unwrap();
+        let page = global.retained_messages(&filter, None, 10).await.unwrap();
+        assert_eq!(page.items.len(), 1);
+        assert_eq!(page.items[0].payload(), b"hi");
+        assert_eq!(page.items[0].client_id(), HTTP_PUBLISHER);
+    }
+
+    #[cfg(feature = "session-export")]
+    #[tokio::test]
+    async fn sessions_are_exported_and_imported() {
+        use mqtt_codec_kit::common::{QualityOfService, TopicName};
+
+        use crate::store::message::ClientMessages;
+
+        let global = Arc::new(GlobalState::new(memory_storage()));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let api = HttpApi::new(global.clone()).with_token("secret");
+        tokio::spawn(api.serve(listener));
+        let path = "/api/v1/clients/c1/session";
+
+        assert_eq!(send(&addr, "secret", "GET", path, "").await.0, 404);
+
+        let message = PublishMessage::new(
+            TopicName::new("a/b").unwrap(),
+            b"hi".to_vec(),
+            QualityOfService::Level2,
+            false,
+        );
+        let export = SessionExport {
+            client_id: "c1".to_owned(),
+            session: None,
+            messages: ClientMessages {
+                received: vec![(9, message)],
+                pending: Vec::new(),
+            },
+        };
+        let json = export.to_json().unwrap();
+        assert_eq!(send(&addr, "wrong", "PUT", path, &json).await.0, 401);
+        let other = "/api/v1/clients/c2/session";
+        assert_eq!(send(&addr, "secret", "PUT", other, &json).await.0, 400);
+        assert_eq!(send(&addr, "secret", "PUT", path, &json).await.0, 204);
+
+        let (status, body) = send(&addr, "secret", "GET", path, "").await;
+        assert_eq!(status, 200);
+        let exported = SessionExport::from_json(&body).unwrap();
+        assert_eq!(exported.client_id, "c1");
+        assert_eq!(exported.messages.received.len(), 1);
+        assert_eq!(exported.messages.received[0].0, 9);
+        assert_eq!(exported.messages.received[0].1.payload(), b"hi");
+    }
+}
@@ -0,0 +1,287 @@
+//! Authentication and authorization by an external HTTP service
+//!
+//! Every connect, subscribe and publish is POSTed as JSON to the configured URL:
+//!
+//! ```json
+//! {"action": "connect", "client_id": "c1", "username": "user", "password": "secret"}
+//! {"action": "subscribe", "client_id": "c1", "topic": "a/#", "qos": 1}
+//! {"action": "publish", "client_id": "c1", "topic": "a/b", "qos": 1, "retain": false}
+//! ```
+//!
+//! The service answers `200 OK` with `{"result": "allow"}`, `"deny"`, `"bad_credentials"` or
+//! `"ignore"`, which leaves the decision to the script, if any. Any other answer, or none
+//! within the timeout, is a failure and handled by the [`FailPolicy`]. Decisions are cached
+//! for a while, so a chatty client does not cost a request per publish.
+
+use std::time::Duration;
+
+use dashmap::DashMap;
+use foldhash::fast::RandomState;
+use mqtt_codec_kit::common::{QualityOfService, TopicFilter};
+use reqwest::{header::CONTENT_TYPE, Client, StatusCode};
+use serde::{Deserialize, Serialize};
+use tokio::time::Instant;
+
+use crate::store::message::PublishMessage;
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+const DEFAULT_CACHE_TTL: Duration = Duration::from_secs(60);
+const DEFAULT_CACHE_CAPACITY: usize = 100_000;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("Http Error : {0}")]
+    Http(#[from] reqwest::Error),
+    #[error("Unexpected Status : {0}")]
+    Status(StatusCode),
+}
+
+/// What the service decided for a connect, subscribe or publish
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Decision {
+    Allow,
+    Deny,
+    /// Denied for a wrong user name or password, the same as `Deny` for anything but connect
+    BadCredentials,
+    /// No opinion, the script decides
+    Ignore,
+}
+
+/// What happens when the service can not be asked
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum FailPolicy {
+    /// Go on as if the service said `ignore`
+    Open,
+    /// Deny, connections are refused with Server Unavailable
+    #[default]
+    Closed,
+}
+
+#[derive(Debug, Clone)]
+pub struct HttpAuthConfig {
+    pub url: String,
+    pub timeout: Duration,
+    /// How long decisions are cached, zero asks the service every time
+    pub cache_ttl: Duration,
+    pub cache_capacity: usize,
+    pub fail_policy: FailPolicy,
+}
+
+impl HttpAuthConfig {
+    pub fn new(url: impl Into<String>) -> Self {
+        Self {
+            url: url.into(),
+            timeout: DEFAULT_TIMEOUT,
+            cache_ttl: DEFAULT_CACHE_TTL,
+            cache_capacity: DEFAULT_CACHE_CAPACITY,
+            fail_policy: FailPolicy::default(),
+        }
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn with_cache(mut self, ttl: Duration, capacity: usize) -> Self {
+        self.cache_ttl = ttl;
+        self.cache_capacity = capacity;
+        self
+    }
+
+    pub fn with_fail_policy(mut self, fail_policy: FailPolicy) -> Self {
+        self.fail_policy = fail_policy;
+        self
+    }
+}
+
+#[derive(Serialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+enum Request<'a> {
+    Connect {
+        client_id: &'a str,
+        username: Option<&'a str>,
+        password: Option<&'a str>,
+    },
+    Subscribe {
+        client_id: &'a str,
+        topic: &'a str,
+        qos: u8,
+    },
+    Publish {
+        client_id: &'a str,
+        topic: &'a str,
+        qos: u8,
+        retain: bool,
+    },
+}
+
+#[derive(Deserialize)]
+struct Response {
+    result: Decision,
+}
+
+pub struct HttpAuth {
+    client: Client,
+    config: HttpAuthConfig,
+    // decisions by the request they answer
+    cache: DashMap<String, (Decision, Instant), RandomState>,
+}
+
+impl HttpAuth {
+    pub fn new(config: HttpAuthConfig) -> Result<Self, Error> {
+        // reqwest comes without a crypto provider, the one of the listeners is used
+        let _ = rustls::crypto::aws_lc_rs::default_provider().install_default();
+        let client = Client::builder().timeout(config.timeout).build()?;
+        Ok(Self {
+            client,
+            config,
+            cache: DashMap::default(),
+        })
+    }
+
+    pub fn fail_policy(&self) -> FailPolicy {
+        self.config.fail_policy
+    }
+
+    pub async fn on_connect(
+        &self,
+        client_id: &str,
+        username: Option<&str>,
+        password: Option<&str>,
+    ) -> Result<Decision, Error> {
+        self.decide(Request::Connect {
+            client_id,
+            username,
+            password,
+        })
+        .await
+    }
+
+    pub async fn on_subscribe(
+        &self,
+        client_id: &str,
+        topic_filter: &TopicFilter,
+        qos: QualityOfService,
+    ) -> Result<Decision, Error> {
+        self.decide(Request::Subscribe {
+            client_id,
+            topic: topic_filter,
+            qos: qos as u8,
+        })
+        .await
+    }
+
+    pub async fn on_publish(
+        &self,
+        client_id: &str,
+        message: &PublishMessage,
+    ) -> Result<Decision, Error> {
+        self.decide(Request::Publish {
+            client_id,
+            topic: message.topic_name(),
+            qos: message.qos() as u8,
+            retain: message.retain(),
+        })
+        .await
+    }
+
+    async fn decide(&self, request: Request<'_>) -> Result<Decision, Error> {
+        let body = serde_json::to_string(&request).expect("requests serialize");
+        if let Some(entry) = self.cache.get(&body) {
+            let (decision, expires_at) = *entry;
+            if Instant::now() < expires_at {
+                return Ok(decision);
+            }
+        }
+
+        let response = self
+            .client
+            .post(&self.config.url)
+            .header(CONTENT_TYPE, "application/json")
+            .body(body.clone())
+            .send()
+            .await?;
+        if response.status() != StatusCode::OK {
+            return Err(Error::Status(response.status()));
+        }
+        let decision = response.json::<Response>().await?.result;
+        self.cache_decision(body, decision);
+        Ok(decision)
+    }
+
+    fn cache_decision(&self, body: String, decision: Decision) {
+        if self.config.cache_ttl.is_zero() {
+            return;
+        }
+        let now = Instant::now();
+        if self.cache.len() >= self.config.cache_capacity {
+            self.cache.retain(|_, (_, expires_at)| now < *expires_at);
+            if self.cache.len() >= self.config.cache_capacity {
+                return;
+            }
+        }
+        self.cache
+            .insert(body, (decision, now + self.config.cache_ttl));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::*;
+
+    // answers every request with `body`, counting them
+    async fn service(body: &'static str) -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/auth", listener.local_addr().unwrap());
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = requests.clone();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                counter.fetch_add(1, Ordering::Relaxed);
+                let mut buf = [0; 4096];
+                let _ = stream.read(&mut buf).await;
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\ncontent-type: application/json\r\n\
+                     content-length: {}\r\nconnection: close\r\n\r\n{body}",
+                    body.len()
+                );
+                let _ = stream.write_all(response.as_bytes()).await;
+            }
+        });
+        (url, requests)
+    }
+
+    #[tokio::test]
+    async fn decisions_are_cached() {
+        let (url, requests) = service(r#"{"result":"bad_credentials"}"#).await;
+        let auth = HttpAuth::new(HttpAuthConfig::new(url)).unwrap();
+        for _ in 0..2 {
+            let decision = auth.on_connect("c1", Some("user"), Some("wrong")).await;
+            assert_eq!(decision.unwrap(), Decision::BadCredentials);
+        }
+        assert_eq!(requests.load(Ordering::Relaxed), 1);
+
+        let decision = auth.on_connect("c1", Some("user"), Some("secret")).await;
+        assert_eq!(decision.unwrap(), Decision::BadCredentials);
+        assert_eq!(requests.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    async fn malformed_answer_is_a_failure() {
+        let (url, _) = service(r#"{"result":"maybe"}"#).await;
+        let auth = HttpAuth::new(HttpAuthConfig::new(url)).unwrap();
+        assert!(auth.on_connect("c1", None, None).await.is_err());
+    }
+}
@@ -0,0 +1,61 @@
+//! Optional `tracing` instrumentation
+//!
+//! Every connection runs in a span carrying its remote address, protocol and client identifier,
+//! so the events logged while serving it can be told apart. Without the `tracing` feature all of
+//! this compiles to nothing.
+
+use std::{future::Future, net::SocketAddr};
+
+use mqtt_codec_kit::common::ProtocolLevel;
+
+#[cfg(feature = "tracing")]
+pub(crate) fn connection_span(
+    remote_addr: Option<SocketAddr>,
+    protocol: ProtocolLevel,
+) -> tracing::Span {
+    tracing::info_span!(
+        "connection",
+        remote_addr = remote_addr.map(tracing::field::display),
+        protocol = ?protocol,
+        client_id = tracing::field::Empty,
+    )
+}
+
+/// Records the client identifier on the current connection span once CONNECT is handled
+#[inline]
+pub(crate) fn record_client_id(_client_id: &str) {
+    #[cfg(feature = "tracing")]
+    tracing::Span::current().record("client_id", _client_id);
+}
+
+pub(crate) trait InstrumentExt: Future + Sized {
+    /// Runs the future in the span of the connection it serves
+    #[cfg(feature = "tracing")]
+    fn in_connection(
+        self,
+        remote_addr: Option<SocketAddr>,
+        protocol: ProtocolLevel,
+    ) -> tracing::instrument::Instrumented<Self> {
+        tracing::Instrument::instrument(self, connection_span(remote_addr, protocol))
+    }
+
+    #[cfg(not(feature = "tracing"))]
+    #[inline]
+    fn in_connection(self, _remote_addr: Option<SocketAddr>, _protocol: ProtocolLevel) -> Self {
+        self
+    }
+
+    /// Keeps the current span for a spawned task
+    #[cfg(feature = "tracing")]
+    fn in_current_span(self) -> tracing::instrument::Instrumented<Self> {
+        tracing::Instrument::in_current_span(self)
+    }
+
+    #[cfg(not(feature = "tracing"))]
+    #[inline]
+    fn in_current_span(self) -> Self {
+        self
+    }
+}
+
+impl<F: Future> InstrumentExt for F {}
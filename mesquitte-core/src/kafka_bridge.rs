@@ -0,0 +1,352 @@
+//! Mirrors publishes into Kafka
+//!
+//! A [`KafkaBridge`] is a [`MessageInterceptor`] copying every publish matching the topic filter
+//! of a [`KafkaRoute`] into the Kafka topic of the route, keyed by the MQTT topic name, with the
+//! MQTT 5 user properties as headers. Add it after the interceptors which may still modify or
+//! reject a message, it never rejects one itself.
+//!
+//! Publishes are queued for a [`KafkaForwarder`], which produces them in batches. A full queue,
+//! when Kafka can not keep up, is handled by the [`Overflow`] policy.
+
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use futures::future::{join_all, BoxFuture};
+use kanal::{bounded_async, AsyncReceiver, AsyncSender};
+use mqtt_codec_kit::common::TopicFilter;
+use rdkafka::{
+    error::KafkaError,
+    message::{Header, OwnedHeaders},
+    producer::{FutureProducer, FutureRecord},
+    util::Timeout,
+    ClientConfig,
+};
+use tokio::time::{self, Instant};
+
+use crate::{
+    server::interceptor::{InterceptAction, MessageInterceptor},
+    store::message::PublishMessage,
+    warn,
+};
+
+const DEFAULT_BATCH_SIZE: usize = 1000;
+const DEFAULT_LINGER: Duration = Duration::from_millis(10);
+const DEFAULT_QUEUE_CAPACITY: usize = 100_000;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("Kafka Error : {0}")]
+    Kafka(#[from] KafkaError),
+    #[error("Invalid Topic Filter : {0}")]
+    TopicFilter(String),
+}
+
+/// Publishes matching `topic_filter` are mirrored into `kafka_topic`
+#[derive(Debug, Clone)]
+pub struct KafkaRoute {
+    pub topic_filter: String,
+    pub kafka_topic: String,
+}
+
+/// What happens to a publish when the queue to Kafka is full
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Overflow {
+    /// The publish is not mirrored, see [`KafkaBridge::dropped`]
+    #[default]
+    Drop,
+    /// The publisher waits for room in the queue, which slows it down to the pace of Kafka
+    Block,
+}
+
+#[derive(Debug, Clone)]
+pub struct KafkaBridgeConfig {
+    /// The `bootstrap.servers` of the producer
+    pub brokers: String,
+    /// A publish is mirrored by every route matching it
+    pub routes: Vec<KafkaRoute>,
+    /// Records produced at once, the forwarder waits for their delivery before the next batch
+    pub batch_size: usize,
+    /// How long a batch waits to fill up
+    pub linger: Duration,
+    pub queue_capacity: usize,
+    pub overflow: Overflow,
+    /// Other producer properties, e.g. `compression.type` or `security.protocol`
+    pub properties: Vec<(String, String)>,
+}
+
+impl KafkaBridgeConfig {
+    pub fn new(brokers: impl Into<String>) -> Self {
+        Self {
+            brokers: brokers.into(),
+            routes: Vec::new(),
+            batch_size: DEFAULT_BATCH_SIZE,
+            linger: DEFAULT_LINGER,
+            queue_capacity: DEFAULT_QUEUE_CAPACITY,
+            overflow: Overflow::default(),
+            properties: Vec::new(),
+        }
+    }
+
+    pub fn with_route(
+        mut self,
+        topic_filter: impl Into<String>,
+        kafka_topic: impl Into<String>,
+    ) -> Self {
+        self.routes.push(KafkaRoute {
+            topic_filter: topic_filter.into(),
+            kafka_topic: kafka_topic.into(),
+        });
+        self
+    }
+
+    pub fn with_batch(mut self, batch_size: usize, linger: Duration) -> Self {
+        self.batch_size = batch_size;
+        self.linger = linger;
+        self
+    }
+
+    pub fn with_queue(mut self, capacity: usize, overflow: Overflow) -> Self {
+        self.queue_capacity = capacity;
+        self.overflow = overflow;
+        self
+    }
+
+    pub fn with_property(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.properties.push((key.into(), value.into()));
+        self
+    }
+}
+
+#[derive(Debug)]
+struct Record {
+    kafka_topic: Arc<str>,
+    key: String,
+    payload: Vec<u8>,
+    headers: Vec<(String, String)>,
+}
+
+struct Route {
+    filter: TopicFilter,
+    kafka_topic: Arc<str>,
+}
+
+pub struct KafkaBridge {
+    routes: Vec<Route>,
+    overflow: Overflow,
+    sender: AsyncSender<Record>,
+    dropped: Arc<AtomicU64>,
+}
+
+/// Produces the records queued by a [`KafkaBridge`]
+pub struct KafkaForwarder {
+    producer: FutureProducer,
+    receiver: AsyncReceiver<Record>,
+    batch_size: usize,
+    linger: Duration,
+    dropped: Arc<AtomicU64>,
+}
+
+impl KafkaBridge {
+    /// The bridge to add with
+    /// [`GlobalState::with_interceptor`](crate::server::state::GlobalState::with_interceptor)
+    /// and the forwarder to run next to the broker
+    ///
+    /// The producer connects to Kafka lazily, unreachable brokers are not an error here.
+    pub fn new(config: KafkaBridgeConfig) -> Result<(Self, KafkaForwarder), Error> {
+        let routes = config
+            .routes
+            .iter()
+            .map(|route| {
+                let filter = TopicFilter::new(route.topic_filter.as_str())
+                    .map_err(|_| Error::TopicFilter(route.topic_filter.clone()))?;
+                Ok(Route {
+                    filter,
+                    kafka_topic: route.kafka_topic.as_str().into(),
+                })
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+
+        let mut client_config = ClientConfig::new();
+        client_config
+            .set("bootstrap.servers", &config.brokers)
+            .set("linger.ms", config.linger.as_millis().to_string())
+            .set("batch.num.messages", config.batch_size.to_string());
+        for (key, value) in &config.properties {
+            client_config.set(key, value);
+        }
+        let producer = client_config.create()?;
+
+        let (sender, receiver) = bounded_async(config.queue_capacity);
+        let dropped = Arc::new(AtomicU64::new(0));
+        let bridge = Self {
+            routes,
+            overflow: config.overflow,
+            sender,
+            dropped: dropped.clone(),
+        };
+        let forwarder = KafkaForwarder {
+            producer,
+            receiver,
+            batch_size: config.batch_size.max(1),
+            linger: config.linger,
+            dropped,
+        };
+        Ok((bridge, forwarder))
+    }
+
+    /// Publishes not mirrored, on a full queue or a failed delivery
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    async fn mirror(&self, message: &PublishMessage) {
+        for route in &self.routes {
+            if !route.filter.get_matcher().is_match(message.topic_name()) {
+                continue;
+            }
+            let record = Record {
+                kafka_topic: route.kafka_topic.clone(),
+                key: message.topic_name().to_string(),
+                payload: message.payload().to_vec(),
+                headers: headers(message),
+            };
+            let queued = match self.overflow {
+                Overflow::Drop => self.sender.try_send(record).unwrap_or(false),
+                Overflow::Block => self.sender.send(record).await.is_ok(),
+            };
+            if !queued {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+impl MessageInterceptor for KafkaBridge {
+    fn on_publish<'a>(
+        &'a self,
+        _client_id: &'a str,
+        message: &'a mut PublishMessage,
+    ) -> BoxFuture<'a, InterceptAction> {
+        Box::pin(async move {
+            self.mirror(message).await;
+            InterceptAction::Continue
+        })
+    }
+}
+
+#[cfg(feature = "v5")]
+fn headers(message: &PublishMessage) -> Vec<(String, String)> {
+    message
+        .properties()
+        .map(|properties| properties.user_properties().to_vec())
+        .unwrap_or_default()
+}
+
+// only MQTT 5 has user properties
+#[cfg(not(feature = "v5"))]
+fn headers(_message: &PublishMessage) -> Vec<(String, String)> {
+    Vec::new()
+}
+
+impl KafkaForwarder {
+    /// Produces the queued records until the bridge is dropped
+    pub async fn run(self) {
+        let mut batch = Vec::with_capacity(self.batch_size);
+        while let Ok(record) = self.receiver.recv().await {
+            batch.push(record);
+            let deadline = Instant::now() + self.linger;
+            while batch.len() < self.batch_size {
+                match time::timeout_at(deadline, self.receiver.recv()).await {
+                    Ok(Ok(record)) => batch.push(record),
+                    _ => break,
+                }
+            }
+            self.produce(&batch).await;
+            batch.clear();
+        }
+    }
+
+    async fn produce(&self, batch: &[Record]) {
+        let deliveries = batch.iter().map(|record| {
+            let headers = record.headers.iter().fold(
+                OwnedHeaders::new_with_capacity(record.headers.len()),
+                |headers, (key, value)| {
+                    headers.insert(Header {
+                        key,
+                        value: Some(value),
+                    })
+                },
+            );
+            let kafka_record = FutureRecord::to(&record.kafka_topic)
+                .key(&record.key)
+                .payload(&record.payload)
+                .headers(headers);
+            // waits while the producer queue is full, which holds the next batch back
+            self.producer.send(kafka_record, Timeout::Never)
+        });
+        for delivery in join_all(deliveries).await {
+            if let Err((err, _)) = delivery {
+                warn!("mirror publish to kafka failed: {err}");
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mqtt_codec_kit::common::{QualityOfService, TopicName};
+
+    use super::*;
+
+    fn message(topic_name: &str) -> PublishMessage {
+        PublishMessage::new(
+            TopicName::new(topic_name).unwrap(),
+            b"hi".to_vec(),
+            QualityOfService::Level1,
+            false,
+        )
+    }
+
+    #[tokio::test]
+    async fn matching_publishes_are_queued() {
+        let config = KafkaBridgeConfig::new("127.0.0.1:1")
+            .with_route("sensors/#", "sensors")
+            .with_route("sensors/+/temp", "temperatures")
+            .with_queue(2, Overflow::Drop);
+        let (bridge, forwarder) = KafkaBridge::new(config).unwrap();
+
+        for topic_name in ["sensors/a/temp", "lights/a", "sensors/b"] {
+            let mut message = message(topic_name);
+            let action = bridge.on_publish("c", &mut message).await;
+            assert_eq!(action, InterceptAction::Continue);
+        }
+        assert_eq!(bridge.dropped(), 1);
+
+        let mut queued = Vec::new();
+        while let Ok(Some(record)) = forwarder.receiver.try_recv() {
+            queued.push((record.kafka_topic.to_string(), record.key));
+        }
+        assert_eq!(
+            queued,
+            [
+                ("sensors".to_owned(), "sensors/a/temp".to_owned()),
+                ("temperatures".to_owned(), "sensors/a/temp".to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    fn invalid_route_is_rejected() {
+        let config = KafkaBridgeConfig::new("127.0.0.1:1").with_route("a/#/b", "a");
+        assert!(matches!(
+            KafkaBridge::new(config),
+            Err(Error::TopicFilter(_))
+        ));
+    }
+}
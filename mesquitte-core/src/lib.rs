@@ -1,14 +1,19 @@
 #[cfg(not(any(feature = "v4", feature = "v5")))]
 compile_error!("v4 or v5 must be enabled");
-#[cfg(not(any(
-    feature = "mqtt",
-    feature = "mqtts",
-    feature = "ws",
-    feature = "wss",
-    feature = "quic"
-)))]
-compile_error!("mqtt or mqtts or ws or wss or quic must be enabled");
+#[cfg(all(
+    feature = "server",
+    not(any(
+        feature = "mqtt",
+        feature = "mqtts",
+        feature = "ws",
+        feature = "wss",
+        feature = "quic",
+        feature = "mqtt-sn"
+    ))
+))]
+compile_error!("mqtt or mqtts or ws or wss or quic or mqtt-sn must be enabled");
 
+#[cfg(feature = "server")]
 pub mod broker;
 #[cfg(all(
     feature = "cluster",
@@ -18,42 +23,63 @@ pub mod broker;
     )
 ))]
 pub mod cluster;
+#[cfg(feature = "grpc-api")]
+pub mod grpc_api;
+#[cfg(feature = "http-api")]
+pub mod health;
+#[cfg(feature = "http-api")]
+pub mod http_api;
+#[cfg(feature = "http-auth")]
+pub mod http_auth;
+#[cfg(feature = "kafka-bridge")]
+pub mod kafka_bridge;
+#[cfg(feature = "script")]
+pub mod script;
+#[cfg(feature = "server")]
 pub mod server;
 pub mod store;
 
+#[cfg(feature = "server")]
+mod instrument;
+#[cfg(feature = "server")]
 mod protocols;
 
 #[macro_export]
-macro_rules! trace { ($($x:tt)*) => (
-    #[cfg(feature = "log")] {
-        log::trace!($($x)*)
-    }
-) }
+macro_rules! trace { ($($x:tt)*) => ({
+    #[cfg(feature = "tracing")]
+    tracing::trace!($($x)*);
+    #[cfg(all(feature = "log", not(feature = "tracing")))]
+    log::trace!($($x)*);
+}) }
 
 #[macro_export]
-macro_rules! debug { ($($x:tt)*) => (
-    #[cfg(feature = "log")] {
-        log::debug!($($x)*)
-    }
-) }
+macro_rules! debug { ($($x:tt)*) => ({
+    #[cfg(feature = "tracing")]
+    tracing::debug!($($x)*);
+    #[cfg(all(feature = "log", not(feature = "tracing")))]
+    log::debug!($($x)*);
+}) }
 
 #[macro_export]
-macro_rules! info { ($($x:tt)*) => (
-    #[cfg(feature = "log")] {
-        log::info!($($x)*)
-    }
-) }
+macro_rules! info { ($($x:tt)*) => ({
+    #[cfg(feature = "tracing")]
+    tracing::info!($($x)*);
+    #[cfg(all(feature = "log", not(feature = "tracing")))]
+    log::info!($($x)*);
+}) }
 
 #[macro_export]
-macro_rules! warn { ($($x:tt)*) => (
-    #[cfg(feature = "log")] {
-        log::warn!($($x)*)
-    }
-) }
+macro_rules! warn { ($($x:tt)*) => ({
+    #[cfg(feature = "tracing")]
+    tracing::warn!($($x)*);
+    #[cfg(all(feature = "log", not(feature = "tracing")))]
+    log::warn!($($x)*);
+}) }
 
 #[macro_export]
-macro_rules! error { ($($x:tt)*) => (
-    #[cfg(feature = "log")] {
-        log::error!($($x)*)
-    }
-) }
+macro_rules! error { ($($x:tt)*) => ({
+    #[cfg(feature = "tracing")]
+    tracing::error!($($x)*);
+    #[cfg(all(feature = "log", not(feature = "tracing")))]
+    log::error!($($x)*);
+}) }
@@ -0,0 +1,248 @@
+//! Steps of the MQTT flows which are the same for every protocol version
+
+use std::sync::Arc;
+
+use mqtt_codec_kit::common::{QualityOfService, TopicFilter};
+
+use crate::{
+    server::{dead_letter::DeadLetterReason, state::GlobalState},
+    store::{
+        error::StoreError,
+        message::{MessageStore, PublishMessage},
+        queue::{DeliverQueue, QueuedMessage, DELIVER_BATCH_SIZE},
+        retain::RetainMessageStore,
+        topic::TopicStore,
+    },
+};
+
+/// What a protocol loop does to complete an incoming QoS 2 publish, see [`complete_qos2`]
+pub(crate) trait Qos2Completion {
+    type Error;
+
+    /// Frees the packet identifier, returns the stored message unless it was released already
+    async fn release(&mut self, packet_id: u16) -> Result<Option<PublishMessage>, Self::Error>;
+
+    async fn send_pubcomp(&mut self, packet_id: u16) -> Result<(), Self::Error>;
+
+    /// Delivers the message to the subscribers
+    async fn forward(&mut self, message: PublishMessage) -> Result<(), Self::Error>;
+}
+
+/// Handles PUBREL: the packet identifier is released, PUBCOMP is sent, then the message is
+/// forwarded
+///
+/// The message leaves the store on release, so it is forwarded even when PUBCOMP cannot be
+/// sent, and a PUBREL resent for the same packet identifier only gets another PUBCOMP.
+pub(crate) async fn complete_qos2<C: Qos2Completion>(
+    completion: &mut C,
+    packet_id: u16,
+) -> Result<(), C::Error> {
+    let message = completion.release(packet_id).await?;
+    let sent = completion.send_pubcomp(packet_id).await;
+    if let Some(message) = message {
+        completion.forward(message).await?;
+    }
+    sent
+}
+
+/// The first packet identifier handed out by `next` which no message kept for the client
+/// holds, `None` if every identifier is held
+///
+/// The identifiers of a session wrap around after 65535, so with a large backlog they come
+/// back to ones still waiting for an acknowledgement; reusing one would let the client's
+/// acknowledgement complete the wrong message.
+pub(crate) async fn free_packet_id<S: MessageStore>(
+    storage: &S,
+    client_id: &str,
+    mut next: impl FnMut() -> u16,
+) -> Result<Option<u16>, StoreError> {
+    for _ in 0..u16::MAX {
+        let packet_id = next();
+        if !storage.is_packet_id_pending(client_id, packet_id).await? {
+            return Ok(Some(packet_id));
+        }
+    }
+    Ok(None)
+}
+
+/// Queues the retained messages matching a new subscription, loaded a page at a time, so they
+/// are sent through the inflight window behind the messages already queued; with `no_local`
+/// the ones the client published itself are left out
+///
+/// Retained messages the queue has no room for are dead-lettered like live ones.
+pub(crate) async fn queue_retained<S: MessageStore + RetainMessageStore + TopicStore>(
+    client_id: &str,
+    topic_filter: &TopicFilter,
+    subscribe_qos: QualityOfService,
+    no_local: bool,
+    deliver_queue: &DeliverQueue,
+    global: &GlobalState<S>,
+) -> Result<(), StoreError> {
+    let mut cursor = None;
+    loop {
+        let page = global
+            .storage
+            .search_page(topic_filter, cursor.as_deref(), DELIVER_BATCH_SIZE)
+            .await?;
+        let retained = page
+            .items
+            .into_iter()
+            .filter(|content| !(no_local && content.client_id() == client_id))
+            .map(|content| {
+                let mut message: PublishMessage = content.into();
+                message.set_retain(true);
+                QueuedMessage {
+                    topic_filter: topic_filter.clone(),
+                    subscribe_qos,
+                    message: Arc::new(message),
+                }
+            })
+            .collect();
+        for dropped in deliver_queue.push_batch(retained) {
+            global
+                .dead_letter(client_id, &dropped.message, DeadLetterReason::QueueFull)
+                .await;
+        }
+        cursor = page.next;
+        if cursor.is_none() {
+            return Ok(());
+        }
+    }
+}
+
+#[cfg(all(test, feature = "v4"))]
+mod tests {
+    use std::io;
+
+    use mqtt_codec_kit::{
+        common::{qos::QoSWithPacketIdentifier, TopicName},
+        v4::packet::PublishPacket,
+    };
+
+    use crate::{
+        protocols::{v4::session::Session, Error},
+        store::{
+            memory::message::MessageMemoryStore,
+            message::{PendingPublishMessage, ReceiveOutcome},
+        },
+    };
+
+    use super::*;
+
+    #[derive(Debug, PartialEq)]
+    enum Step {
+        Pubcomp(u16),
+        Forward(Vec<u8>),
+    }
+
+    struct Recorder {
+        store: MessageMemoryStore,
+        steps: Vec<Step>,
+        connected: bool,
+    }
+
+    impl Recorder {
+        async fn new(packet_id: u16, payload: &str) -> Self {
+            let store = MessageMemoryStore::new(16, 30, 3);
+            let packet = PublishPacket::new(
+                TopicName::new("a/b").unwrap(),
+                QoSWithPacketIdentifier::Level2(packet_id),
+                payload,
+            );
+            let outcome = store
+                .save_publish_message("c", packet_id, (&packet).into())
+                .await;
+            assert_eq!(outcome.unwrap(), ReceiveOutcome::Received);
+            Self {
+                store,
+                steps: Vec::new(),
+                connected: true,
+            }
+        }
+    }
+
+    impl Qos2Completion for Recorder {
+        type Error = Error;
+
+        async fn release(&mut self, packet_id: u16) -> Result<Option<PublishMessage>, Error> {
+            Ok(self.store.pubrel("c", packet_id).await?)
+        }
+
+        async fn send_pubcomp(&mut self, packet_id: u16) -> Result<(), Error> {
+            if !self.connected {
+                return Err(io::Error::from(io::ErrorKind::BrokenPipe).into());
+            }
+            self.steps.push(Step::Pubcomp(packet_id));
+            Ok(())
+        }
+
+        async fn forward(&mut self, message: PublishMessage) -> Result<(), Error> {
+            self.steps.push(Step::Forward(message.payload().to_vec()));
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn pubcomp_is_sent_before_forwarding() {
+        let mut recorder = Recorder::new(7, "m").await;
+        complete_qos2(&mut recorder, 7).await.unwrap();
+        assert_eq!(
+            recorder.steps,
+            vec![Step::Pubcomp(7), Step::Forward(b"m".to_vec())]
+        );
+    }
+
+    #[tokio::test]
+    async fn resent_pubrel_forwards_once() {
+        let mut recorder = Recorder::new(7, "m").await;
+        complete_qos2(&mut recorder, 7).await.unwrap();
+        complete_qos2(&mut recorder, 7).await.unwrap();
+        assert_eq!(
+            recorder.steps,
+            vec![
+                Step::Pubcomp(7),
+                Step::Forward(b"m".to_vec()),
+                Step::Pubcomp(7)
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn released_message_is_forwarded_when_pubcomp_fails() {
+        let mut recorder = Recorder::new(7, "m").await;
+        recorder.connected = false;
+        assert!(complete_qos2(&mut recorder, 7).await.is_err());
+        assert_eq!(recorder.steps, vec![Step::Forward(b"m".to_vec())]);
+    }
+
+    #[tokio::test]
+    async fn pending_packet_ids_are_skipped() {
+        let store = MessageMemoryStore::new(16, 30, 3);
+        for packet_id in [65535, 1] {
+            let packet = PublishPacket::new(
+                TopicName::new("a/b").unwrap(),
+                QoSWithPacketIdentifier::Level1(packet_id),
+                "m",
+            );
+            let pending = PendingPublishMessage::new(packet.qos(), (&packet).into());
+            store
+                .save_pending_publish_message("c", packet_id, pending)
+                .await
+                .unwrap();
+        }
+
+        let mut session = Session::new("c");
+        for _ in 1..65534 {
+            session.incr_server_packet_id();
+        }
+        let mut next = || session.incr_server_packet_id();
+        let packet_id = free_packet_id(&store, "c", &mut next).await.unwrap();
+        assert_eq!(packet_id, Some(65534));
+        // 65535 and 1 are held, the identifiers wrap around and skip 0
+        let packet_id = free_packet_id(&store, "c", &mut next).await.unwrap();
+        assert_eq!(packet_id, Some(2));
+
+        assert!(store.puback("c", 1).await.unwrap());
+        assert!(!store.is_packet_id_pending("c", 1).await.unwrap());
+    }
+}
@@ -0,0 +1,715 @@
+//! Delivery of queued messages to a session, the same for every protocol version
+//!
+//! [`DeliveryCore`] decides what is sent and when: the pending messages of the previous
+//! connection first, then the deliver queue, never more than the inflight window allows, and
+//! for an offline session it keeps the messages until the client reconnects. Messages of a
+//! session are sent in the order they were queued, QoS 0 ones included, and a QoS 1/2 message
+//! is saved as pending before it is sent, so the order holds across a reconnect too, unless
+//! the QoS 1 fast path keeps it unsaved, see [`UnsavedMessages`]. One the store can not keep
+//! is dead-lettered instead of sent. QoS 0
+//! messages for an offline session are dropped unless
+//! [`GlobalState::with_queue_qos0_messages`] is set, then they are kept as pending messages
+//! and sent once on reconnect. A
+//! [`ProtocolAdapter`] gives it the session details and builds the packets of its protocol
+//! version.
+
+use std::{cmp, collections::VecDeque, marker::PhantomData, sync::Arc};
+
+use mqtt_codec_kit::common::{qos::QoSWithPacketIdentifier, QualityOfService, TopicFilter};
+
+#[cfg(feature = "v4")]
+use crate::server::config::Qos1FastPath;
+use crate::{
+    debug,
+    server::{dead_letter::DeadLetterReason, state::GlobalState},
+    store::{
+        error::StoreError,
+        message::{MessageStore, PendingPublishMessage, PublishMessage},
+        queue::{DeliverQueue, QueuedMessage, DELIVER_BATCH_SIZE},
+        topic::TopicStore,
+    },
+    warn,
+};
+
+use super::{common::free_packet_id, inflight::InflightWindow, unsaved::UnsavedMessages};
+
+/// The protocol version specific side of [`DeliveryCore`], implemented by the sessions
+pub(crate) trait ProtocolAdapter {
+    /// What the protocol loop writes to the client
+    type Packet;
+
+    fn client_id(&self) -> &str;
+
+    /// Whether the session still has the subscription a message was queued for
+    fn is_subscribed(&self, topic_filter: &TopicFilter) -> bool;
+
+    fn next_packet_id(&mut self) -> u16;
+
+    /// PUBLISH of a message taken from the deliver queue
+    fn publish(&self, qos: QoSWithPacketIdentifier, message: PublishMessage) -> Self::Packet;
+
+    /// Resend of a message sent before and not completed: PUBREL once the client sent PUBREC,
+    /// PUBLISH otherwise
+    fn resend(&self, packet_id: u16, pending: PendingPublishMessage) -> Self::Packet;
+}
+
+// pending messages of the previous connection are loaded a page at a time, so a huge backlog
+// is not held in memory at once
+const PENDING_PAGE_SIZE: usize = DELIVER_BATCH_SIZE;
+
+pub(crate) struct DeliveryCore<P> {
+    // pending messages of the previous connection, sent before the queued ones
+    backlog: VecDeque<(u16, PendingPublishMessage)>,
+    // where to load the next page of the backlog, `None` once it is all loaded
+    backlog_cursor: Option<String>,
+    inflight: InflightWindow,
+    unsaved: UnsavedMessages,
+    _adapter: PhantomData<fn(&mut P)>,
+}
+
+impl<P: ProtocolAdapter> DeliveryCore<P> {
+    pub fn new(max_inflight: usize) -> Self {
+        Self {
+            backlog: VecDeque::new(),
+            backlog_cursor: None,
+            inflight: InflightWindow::new(max_inflight),
+            unsaved: UnsavedMessages::new(None),
+            _adapter: PhantomData,
+        }
+    }
+
+    /// Sends QoS 1 messages without saving them first, the session has to call
+    /// [`DeliveryCore::spill`] regularly and [`DeliveryCore::spill_all`] before it ends or hands
+    /// over its state
+    #[cfg(feature = "v4")]
+    pub fn with_qos1_fast_path(mut self, fast_path: Option<Qos1FastPath>) -> Self {
+        self.unsaved = UnsavedMessages::new(fast_path);
+        self
+    }
+
+    pub fn inflight_mut(&mut self) -> &mut InflightWindow {
+        &mut self.inflight
+    }
+
+    /// Frees the packet identifier on PUBACK or PUBCOMP, false if it was not in the window
+    pub fn complete(&mut self, packet_id: u16) -> bool {
+        self.inflight.remove(packet_id)
+    }
+
+    /// Drops the message of a PUBACK if it was never saved, then the store has nothing to
+    /// acknowledge
+    #[cfg(feature = "v4")]
+    pub fn complete_unsaved(&mut self, packet_id: u16) -> bool {
+        self.unsaved.complete(packet_id)
+    }
+
+    /// Saves the unsaved messages which waited for their acknowledgement too long
+    #[cfg(feature = "v4")]
+    pub async fn spill<S: MessageStore>(
+        &mut self,
+        adapter: &P,
+        global: &GlobalState<S>,
+    ) -> Result<(), StoreError> {
+        self.save_unsaved(adapter, global, false).await
+    }
+
+    /// Saves every unsaved message, so the next connection of the session resends them
+    #[cfg(feature = "v4")]
+    pub async fn spill_all<S: MessageStore>(
+        &mut self,
+        adapter: &P,
+        global: &GlobalState<S>,
+    ) -> Result<(), StoreError> {
+        self.save_unsaved(adapter, global, true).await
+    }
+
+    #[cfg(feature = "v4")]
+    async fn save_unsaved<S: MessageStore>(
+        &mut self,
+        adapter: &P,
+        global: &GlobalState<S>,
+        all: bool,
+    ) -> Result<(), StoreError> {
+        for (packet_id, pending) in self.unsaved.take_spilled(all) {
+            global
+                .storage
+                .save_pending_publish_message(adapter.client_id(), packet_id, pending)
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Takes the first page of the pending messages of the previous connection, the next
+    /// [`DeliveryCore::drain`] sends them first and loads the rest as the backlog empties
+    pub async fn load_pending<S: MessageStore>(
+        &mut self,
+        adapter: &P,
+        global: &GlobalState<S>,
+    ) -> Result<(), StoreError> {
+        self.load_pending_page(adapter, global, None).await
+    }
+
+    async fn load_pending_page<S: MessageStore>(
+        &mut self,
+        adapter: &P,
+        global: &GlobalState<S>,
+        cursor: Option<&str>,
+    ) -> Result<(), StoreError> {
+        let page = global
+            .storage
+            .get_pending_messages_page(adapter.client_id(), cursor, PENDING_PAGE_SIZE)
+            .await?;
+        self.backlog.extend(page.items);
+        self.backlog_cursor = page.next;
+        Ok(())
+    }
+
+    /// Takes pending messages, then queued messages, as long as the inflight window has room
+    pub async fn drain<S: MessageStore + TopicStore>(
+        &mut self,
+        adapter: &mut P,
+        deliver_queue: &DeliverQueue,
+        global: &GlobalState<S>,
+    ) -> Vec<P::Packet> {
+        let mut packets = Vec::new();
+        while self.inflight.has_room() {
+            if self.backlog.is_empty() {
+                let Some(cursor) = self.backlog_cursor.take() else {
+                    break;
+                };
+                if let Err(err) = self.load_pending_page(adapter, global, Some(&cursor)).await {
+                    warn!(
+                        "client#{} load pending messages failed: {err}",
+                        adapter.client_id()
+                    );
+                }
+                continue;
+            }
+            let Some((packet_id, pending)) = self.backlog.pop_front() else {
+                break;
+            };
+            // a QoS 0 message kept while offline, the store handed it out for the last time
+            if pending.qos() == QoSWithPacketIdentifier::Level0 {
+                packets.push(adapter.publish(pending.qos(), pending.message().clone()));
+                continue;
+            }
+            self.inflight.insert(packet_id);
+            packets.push(resend(adapter, packet_id, pending, global));
+        }
+        if !self.backlog.is_empty() || self.backlog_cursor.is_some() {
+            return packets;
+        }
+
+        for _ in 0..DELIVER_BATCH_SIZE {
+            if !self.inflight.has_room() {
+                return packets;
+            }
+            let Some(queued) = deliver_queue.pop() else {
+                return packets;
+            };
+            let Some(qos) = accept(adapter, &queued, global, &self.unsaved).await else {
+                continue;
+            };
+            let message = Arc::unwrap_or_clone(queued.message);
+            if let (QualityOfService::Level1, Some(packet_id)) = qos.split() {
+                if self.unsaved.has_room() {
+                    let mut pending = PendingPublishMessage::new_pooled(qos, &message);
+                    pending.record_send_attempt();
+                    self.unsaved.keep(packet_id, pending);
+                    self.inflight.insert(packet_id);
+                    packets.push(adapter.publish(qos, message));
+                    continue;
+                }
+            }
+            if let (_, Some(packet_id)) = qos.split() {
+                // saved before it is written, a message sent by a connection which then fails
+                // is resent by the next one ahead of the messages queued after it, so one the
+                // store did not keep is not sent at all
+                let mut pending = PendingPublishMessage::new_pooled(qos, &message);
+                pending.record_send_attempt();
+                match global
+                    .storage
+                    .save_pending_publish_message(adapter.client_id(), packet_id, pending)
+                    .await
+                {
+                    Ok(false) => {}
+                    Ok(true) => {
+                        global
+                            .dead_letter(adapter.client_id(), &message, DeadLetterReason::QueueFull)
+                            .await;
+                        continue;
+                    }
+                    Err(err) => {
+                        warn!(
+                            "client#{} save pending message failed, message dropped: {err}",
+                            adapter.client_id()
+                        );
+                        continue;
+                    }
+                }
+                self.inflight.insert(packet_id);
+            }
+            packets.push(adapter.publish(qos, message));
+        }
+        // let the session come back for the rest after serving its other events
+        if !deliver_queue.is_empty() {
+            deliver_queue.wake();
+        }
+        packets
+    }
+
+    /// The inflight messages which were not acknowledged in time
+    #[cfg(feature = "v4")]
+    pub async fn retry<S: MessageStore>(
+        &mut self,
+        adapter: &P,
+        global: &GlobalState<S>,
+    ) -> Result<Vec<P::Packet>, StoreError> {
+        // the unsaved messages are resent from the store once they are spilled
+        self.spill(adapter, global).await?;
+        let Some(messages) = global
+            .storage
+            .try_get_pending_messages(adapter.client_id())
+            .await?
+        else {
+            return Ok(Vec::new());
+        };
+        Ok(messages
+            .into_iter()
+            // not sent yet, waiting for room in the inflight window
+            .filter(|(packet_id, _)| self.inflight.contains(*packet_id))
+            .map(|(packet_id, pending)| resend(adapter, packet_id, pending, global))
+            .collect())
+    }
+
+    /// Keeps a QoS 1/2 message for an offline session until it reconnects, a QoS 0 one only
+    /// with [`GlobalState::with_queue_qos0_messages`]
+    pub async fn store_offline<S: MessageStore + TopicStore>(
+        adapter: &mut P,
+        queued: QueuedMessage,
+        global: &GlobalState<S>,
+    ) -> Result<(), StoreError> {
+        let Some(qos) = accept(adapter, &queued, global, &UnsavedMessages::new(None)).await else {
+            return Ok(());
+        };
+        let packet_id = match qos.split() {
+            (_, Some(packet_id)) => packet_id,
+            // only a key in the store, a QoS 0 message is sent without a packet identifier
+            (_, None) if global.queue_qos0_messages() => {
+                let Some(packet_id) = packet_id(
+                    adapter,
+                    &queued.message,
+                    global,
+                    &UnsavedMessages::new(None),
+                )
+                .await
+                else {
+                    return Ok(());
+                };
+                packet_id
+            }
+            (_, None) => return Ok(()),
+        };
+        let message = PendingPublishMessage::new_pooled(qos, &queued.message);
+        let full = global
+            .storage
+            .save_pending_publish_message(adapter.client_id(), packet_id, message)
+            .await?;
+        if full {
+            global
+                .dead_letter(
+                    adapter.client_id(),
+                    &queued.message,
+                    DeadLetterReason::QueueFull,
+                )
+                .await;
+        }
+        Ok(())
+    }
+}
+
+/// A pending message sent again, counted as a retransmission unless it is the first send of a
+/// message kept while the session was offline
+fn resend<P: ProtocolAdapter, S>(
+    adapter: &P,
+    packet_id: u16,
+    pending: PendingPublishMessage,
+    global: &GlobalState<S>,
+) -> P::Packet {
+    if pending.pubrec_at().is_some() {
+        global.metrics().record_pubrel_retransmission();
+    } else if pending.dup() {
+        global.metrics().record_publish_retransmission();
+    }
+    adapter.resend(packet_id, pending)
+}
+
+/// The QoS to send a queued message with, `None` drops the message
+async fn accept<P: ProtocolAdapter, S: MessageStore + TopicStore>(
+    adapter: &mut P,
+    queued: &QueuedMessage,
+    global: &GlobalState<S>,
+    unsaved: &UnsavedMessages,
+) -> Option<QoSWithPacketIdentifier> {
+    debug!(
+        r#"""client#{} receive deliver packet:
+                 topic filter : {:?},
+                subscribe qos : {:?},
+                   topic name : {:?},
+                      payload : [{} bytes]"""#,
+        adapter.client_id(),
+        queued.topic_filter,
+        queued.subscribe_qos,
+        queued.message.topic_name(),
+        queued.message.payload().len(),
+    );
+    // unsubscribed after the message was queued
+    if !adapter.is_subscribed(&queued.topic_filter) {
+        return None;
+    }
+    if queued.message.is_expired() {
+        global
+            .dead_letter(
+                adapter.client_id(),
+                &queued.message,
+                DeadLetterReason::Expired,
+            )
+            .await;
+        return None;
+    }
+    Some(match cmp::min(queued.message.qos(), queued.subscribe_qos) {
+        QualityOfService::Level0 => QoSWithPacketIdentifier::Level0,
+        QualityOfService::Level1 => QoSWithPacketIdentifier::Level1(
+            packet_id(adapter, &queued.message, global, unsaved).await?,
+        ),
+        QualityOfService::Level2 => QoSWithPacketIdentifier::Level2(
+            packet_id(adapter, &queued.message, global, unsaved).await?,
+        ),
+    })
+}
+
+/// A packet identifier no other message kept for the client holds, saved or `unsaved`, `None`
+/// drops the message
+async fn packet_id<P: ProtocolAdapter, S: MessageStore + TopicStore>(
+    adapter: &mut P,
+    message: &PublishMessage,
+    global: &GlobalState<S>,
+    unsaved: &UnsavedMessages,
+) -> Option<u16> {
+    let client_id = adapter.client_id().to_owned();
+    match free_packet_id(global.storage.as_ref(), &client_id, || loop {
+        let packet_id = adapter.next_packet_id();
+        if !unsaved.contains(packet_id) {
+            return packet_id;
+        }
+    })
+    .await
+    {
+        Ok(Some(packet_id)) => Some(packet_id),
+        Ok(None) => {
+            warn!("client#{client_id} has every packet identifier in use, message dropped");
+            global
+                .dead_letter(&client_id, message, DeadLetterReason::QueueFull)
+                .await;
+            None
+        }
+        Err(err) => {
+            warn!("client#{client_id} look up pending packet identifier failed: {err}");
+            None
+        }
+    }
+}
+
+#[cfg(all(test, feature = "v4"))]
+mod tests {
+    use std::time::Duration;
+
+    use mqtt_codec_kit::{common::TopicName, v4::packet::PublishPacket};
+
+    use super::*;
+    use crate::store::{
+        memory::{
+            message::MessageMemoryStore, retain::RetainMessageMemoryStore, topic::TopicMemoryStore,
+            MemoryStore,
+        },
+        memory_storage,
+        queue::QueueConfig,
+        Storage,
+    };
+
+    struct Subscriber {
+        packet_id: u16,
+    }
+
+    impl ProtocolAdapter for Subscriber {
+        // the packet identifier and the payload of a sent message
+        type Packet = (Option<u16>, String);
+
+        fn client_id(&self) -> &str {
+            "sub"
+        }
+
+        fn is_subscribed(&self, _topic_filter: &TopicFilter) -> bool {
+            true
+        }
+
+        fn next_packet_id(&mut self) -> u16 {
+            self.packet_id += 1;
+            self.packet_id
+        }
+
+        fn publish(&self, qos: QoSWithPacketIdentifier, message: PublishMessage) -> Self::Packet {
+            let payload = String::from_utf8(message.payload().to_vec()).unwrap();
+            (qos.split().1, payload)
+        }
+
+        fn resend(&self, packet_id: u16, pending: PendingPublishMessage) -> Self::Packet {
+            self.publish(
+                QoSWithPacketIdentifier::Level1(packet_id),
+                pending.message().clone(),
+            )
+        }
+    }
+
+    fn global() -> GlobalState<MemoryStore> {
+        GlobalState::new(memory_storage())
+    }
+
+    /// Queues the payloads in order, published on one topic with the given QoS levels
+    fn publish(queue: &DeliverQueue, messages: &[(&str, QualityOfService)]) {
+        for (payload, qos) in messages {
+            let qos = match qos {
+                QualityOfService::Level0 => QoSWithPacketIdentifier::Level0,
+                QualityOfService::Level1 => QoSWithPacketIdentifier::Level1(1),
+                QualityOfService::Level2 => QoSWithPacketIdentifier::Level2(1),
+            };
+            let packet = PublishPacket::new(TopicName::new("a/b").unwrap(), qos, *payload);
+            queue.push(QueuedMessage {
+                topic_filter: TopicFilter::new("a/#").unwrap(),
+                subscribe_qos: QualityOfService::Level2,
+                message: Arc::new((&packet).into()),
+            });
+        }
+    }
+
+    fn payloads(packets: &[(Option<u16>, String)]) -> Vec<&str> {
+        packets
+            .iter()
+            .map(|(_, payload)| payload.as_str())
+            .collect()
+    }
+
+    async fn saved_packet_ids(global: &GlobalState<MemoryStore>) -> Vec<u16> {
+        let pending = global
+            .storage
+            .get_all_pending_messages("sub")
+            .await
+            .unwrap();
+        let mut packet_ids: Vec<_> = pending
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(packet_id, _)| packet_id)
+            .collect();
+        packet_ids.sort_unstable();
+        packet_ids
+    }
+
+    #[tokio::test]
+    async fn mixed_qos_keeps_publish_order() {
+        let global = global();
+        let queue = DeliverQueue::new(QueueConfig::default());
+        let mut subscriber = Subscriber { packet_id: 0 };
+        let mut delivery = DeliveryCore::new(2);
+        publish(
+            &queue,
+            &[
+                ("0", QualityOfService::Level0),
+                ("1", QualityOfService::Level1),
+                ("2", QualityOfService::Level2),
+                ("3", QualityOfService::Level0),
+                ("4", QualityOfService::Level1),
+            ],
+        );
+
+        // a full inflight window holds back the QoS 0 messages queued behind it as well
+        let sent = delivery.drain(&mut subscriber, &queue, &global).await;
+        assert_eq!(payloads(&sent), ["0", "1", "2"]);
+        for packet_id in sent.iter().filter_map(|(packet_id, _)| *packet_id) {
+            assert!(delivery.complete(packet_id));
+        }
+        let sent = delivery.drain(&mut subscriber, &queue, &global).await;
+        assert_eq!(payloads(&sent), ["3", "4"]);
+    }
+
+    #[tokio::test]
+    async fn pending_messages_go_first_after_reconnect() {
+        let global = global();
+        let queue = DeliverQueue::new(QueueConfig::default());
+        let mut subscriber = Subscriber { packet_id: 0 };
+
+        // the first connection sends two messages and fails before they are acknowledged
+        let mut delivery = DeliveryCore::new(2);
+        publish(
+            &queue,
+            &[
+                ("0", QualityOfService::Level1),
+                ("1", QualityOfService::Level2),
+                ("2", QualityOfService::Level1),
+            ],
+        );
+        let sent = delivery.drain(&mut subscriber, &queue, &global).await;
+        assert_eq!(payloads(&sent), ["0", "1"]);
+
+        // the session is offline while another message is published
+        publish(&queue, &[("3", QualityOfService::Level2)]);
+        for queued in queue.pop_batch(DELIVER_BATCH_SIZE) {
+            DeliveryCore::store_offline(&mut subscriber, queued, &global)
+                .await
+                .unwrap();
+        }
+
+        // the next connection resends in order, ahead of the messages published meanwhile
+        let mut delivery = DeliveryCore::new(8);
+        delivery.load_pending(&subscriber, &global).await.unwrap();
+        publish(
+            &queue,
+            &[
+                ("4", QualityOfService::Level0),
+                ("5", QualityOfService::Level1),
+            ],
+        );
+        let sent = delivery.drain(&mut subscriber, &queue, &global).await;
+        assert_eq!(payloads(&sent), ["0", "1", "2", "3", "4", "5"]);
+        // "3" was never sent before
+        assert_eq!(global.metrics().publish_retransmissions(), 2);
+    }
+
+    #[tokio::test]
+    async fn offline_messages_keep_their_qos() {
+        let global = global();
+        let queue = DeliverQueue::new(QueueConfig::default());
+        let mut subscriber = Subscriber { packet_id: 0 };
+        publish(
+            &queue,
+            &[
+                ("1", QualityOfService::Level1),
+                ("2", QualityOfService::Level2),
+            ],
+        );
+        for queued in queue.pop_batch(DELIVER_BATCH_SIZE) {
+            DeliveryCore::store_offline(&mut subscriber, queued, &global)
+                .await
+                .unwrap();
+        }
+
+        let mut pending = global
+            .storage
+            .get_all_pending_messages("sub")
+            .await
+            .unwrap()
+            .unwrap_or_default();
+        pending.sort_unstable_by_key(|(packet_id, _)| *packet_id);
+        let qos: Vec<_> = pending.iter().map(|(_, pending)| pending.qos()).collect();
+        assert_eq!(
+            qos,
+            [
+                QoSWithPacketIdentifier::Level1(1),
+                QoSWithPacketIdentifier::Level2(2)
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn offline_qos0_messages_are_sent_once() {
+        let global = global().with_queue_qos0_messages(true);
+        let queue = DeliverQueue::new(QueueConfig::default());
+        let mut subscriber = Subscriber { packet_id: 0 };
+        publish(
+            &queue,
+            &[
+                ("0", QualityOfService::Level0),
+                ("1", QualityOfService::Level1),
+                ("2", QualityOfService::Level0),
+            ],
+        );
+        for queued in queue.pop_batch(DELIVER_BATCH_SIZE) {
+            DeliveryCore::store_offline(&mut subscriber, queued, &global)
+                .await
+                .unwrap();
+        }
+
+        let mut delivery = DeliveryCore::new(8);
+        delivery.load_pending(&subscriber, &global).await.unwrap();
+        let sent = delivery.drain(&mut subscriber, &queue, &global).await;
+        assert_eq!(payloads(&sent), ["0", "1", "2"]);
+        assert_eq!(sent[0].0, None);
+        assert_eq!(sent[2].0, None);
+
+        // only the QoS 1 message is still waiting for its acknowledgement
+        let mut delivery = DeliveryCore::new(8);
+        delivery.load_pending(&subscriber, &global).await.unwrap();
+        let sent = delivery.drain(&mut subscriber, &queue, &global).await;
+        assert_eq!(payloads(&sent), ["1"]);
+    }
+
+    #[tokio::test]
+    async fn messages_the_store_can_not_keep_are_not_sent() {
+        // the memory store takes one message more than its limit
+        let global = GlobalState::new(Storage::new(MemoryStore::new(
+            MessageMemoryStore::new(1, 30, 3),
+            RetainMessageMemoryStore::default(),
+            TopicMemoryStore::default(),
+        )));
+        let queue = DeliverQueue::new(QueueConfig::default());
+        let mut subscriber = Subscriber { packet_id: 0 };
+        let mut delivery = DeliveryCore::new(8);
+        publish(
+            &queue,
+            &[
+                ("0", QualityOfService::Level1),
+                ("1", QualityOfService::Level2),
+                ("2", QualityOfService::Level1),
+                ("3", QualityOfService::Level0),
+            ],
+        );
+
+        let sent = delivery.drain(&mut subscriber, &queue, &global).await;
+        assert_eq!(payloads(&sent), ["0", "1", "3"]);
+        assert_eq!(saved_packet_ids(&global).await, [1, 2]);
+        assert!(!delivery.complete(3));
+    }
+
+    #[tokio::test]
+    async fn fast_path_saves_only_unacknowledged_messages() {
+        let global = global();
+        let queue = DeliverQueue::new(QueueConfig::default());
+        let mut subscriber = Subscriber { packet_id: 0 };
+        let mut delivery = DeliveryCore::new(8).with_qos1_fast_path(Some(Qos1FastPath {
+            spill_after: Duration::from_secs(3600),
+            max_unsaved: 2,
+        }));
+        publish(
+            &queue,
+            &[
+                ("0", QualityOfService::Level1),
+                ("1", QualityOfService::Level2),
+                ("2", QualityOfService::Level1),
+                ("3", QualityOfService::Level1),
+            ],
+        );
+
+        // "3" finds no room left and is saved like the QoS 2 message
+        let sent = delivery.drain(&mut subscriber, &queue, &global).await;
+        assert_eq!(payloads(&sent), ["0", "1", "2", "3"]);
+        assert_eq!(saved_packet_ids(&global).await, [2, 4]);
+
+        // the acknowledged message never reaches the store, the other one is saved on spilling
+        assert!(delivery.complete_unsaved(1));
+        assert!(!delivery.complete_unsaved(4));
+        delivery.spill(&subscriber, &global).await.unwrap();
+        assert_eq!(saved_packet_ids(&global).await, [2, 4]);
+        delivery.spill_all(&subscriber, &global).await.unwrap();
+        assert_eq!(saved_packet_ids(&global).await, [2, 3, 4]);
+        assert!(!delivery.complete_unsaved(3));
+    }
+}
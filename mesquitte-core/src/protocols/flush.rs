@@ -0,0 +1,93 @@
+//! Flushing of the messages delivered to a client by its [`FlushPolicy`]
+//!
+//! The write loops feed delivered packets to their writer and ask [`Flusher::written`] whether
+//! to flush now. Packets held back are flushed once [`Flusher::due`] resolves, which the loops
+//! wait on next to their other events.
+
+use std::future::Future;
+
+use tokio::{
+    task,
+    time::{self, Instant},
+};
+
+use crate::server::config::FlushPolicy;
+
+pub(crate) struct Flusher {
+    policy: FlushPolicy,
+    unflushed: usize,
+    // when the first packet not flushed yet was written
+    since: Option<Instant>,
+}
+
+impl Flusher {
+    pub fn new(policy: FlushPolicy) -> Self {
+        Self {
+            policy,
+            unflushed: 0,
+            since: None,
+        }
+    }
+
+    /// Counts a packet fed to the writer, returns whether to flush now
+    pub fn written(&mut self) -> bool {
+        self.unflushed += 1;
+        let since = *self.since.get_or_insert_with(Instant::now);
+        match self.policy {
+            FlushPolicy::EveryPacket => true,
+            FlushPolicy::Packets(n) => self.unflushed >= n,
+            FlushPolicy::Interval(interval) => since.elapsed() >= interval,
+        }
+    }
+
+    pub fn flushed(&mut self) {
+        self.unflushed = 0;
+        self.since = None;
+    }
+
+    pub fn has_unflushed(&self) -> bool {
+        self.unflushed > 0
+    }
+
+    /// Resolves once the packets held back are due, with [`FlushPolicy::Packets`] as soon as
+    /// the write loop has no other event ready
+    pub fn due(&self) -> impl Future<Output = ()> + 'static {
+        let deadline = match (self.policy, self.since) {
+            (FlushPolicy::Interval(interval), Some(since)) => Some(since + interval),
+            _ => None,
+        };
+        async move {
+            match deadline {
+                Some(deadline) => time::sleep_until(deadline).await,
+                None => task::yield_now().await,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn packets_are_flushed_by_policy() {
+        let mut flusher = Flusher::new(FlushPolicy::EveryPacket);
+        assert!(flusher.written());
+
+        let mut flusher = Flusher::new(FlushPolicy::Packets(3));
+        assert!(!flusher.written());
+        assert!(!flusher.written());
+        assert!(flusher.written());
+        flusher.flushed();
+        assert!(!flusher.has_unflushed());
+        assert!(!flusher.written());
+        assert!(flusher.has_unflushed());
+
+        let mut flusher = Flusher::new(FlushPolicy::Interval(Duration::from_secs(60)));
+        assert!(!flusher.written());
+        let mut flusher = Flusher::new(FlushPolicy::Interval(Duration::ZERO));
+        assert!(flusher.written());
+    }
+}
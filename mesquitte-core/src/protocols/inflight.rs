@@ -0,0 +1,98 @@
+use std::sync::Arc;
+
+use foldhash::{HashSet, HashSetExt};
+
+use crate::server::client_info::ClientInfo;
+
+/// Packet identifiers of QoS 1/2 publishes sent to the client and not completed yet
+///
+/// Resent pending messages and live messages go through the same window, so a reconnecting
+/// client gets its pending messages first and never more than `max` at a time.
+#[derive(Debug, Clone)]
+pub(crate) struct InflightWindow {
+    max: usize,
+    packet_ids: HashSet<u16>,
+    // kept up to date with the number of packet identifiers in the window
+    info: Option<Arc<ClientInfo>>,
+}
+
+impl InflightWindow {
+    pub fn new(max: usize) -> Self {
+        Self {
+            max: max.max(1),
+            packet_ids: HashSet::new(),
+            info: None,
+        }
+    }
+
+    /// Reports the size of the window in [`ClientInfo::inflight`] from now on
+    pub fn report_to(&mut self, info: Arc<ClientInfo>) {
+        info.set_inflight(self.packet_ids.len());
+        self.info = Some(info);
+    }
+
+    pub fn has_room(&self) -> bool {
+        self.packet_ids.len() < self.max
+    }
+
+    #[cfg(feature = "v4")]
+    pub fn contains(&self, packet_id: u16) -> bool {
+        self.packet_ids.contains(&packet_id)
+    }
+
+    pub fn insert(&mut self, packet_id: u16) {
+        self.packet_ids.insert(packet_id);
+        self.report();
+    }
+
+    pub fn remove(&mut self, packet_id: u16) -> bool {
+        let removed = self.packet_ids.remove(&packet_id);
+        self.report();
+        removed
+    }
+
+    fn report(&self) {
+        if let Some(info) = &self.info {
+            info.set_inflight(self.packet_ids.len());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mqtt_codec_kit::common::ProtocolLevel;
+
+    use super::*;
+
+    #[test]
+    fn window_holds_at_most_max() {
+        let mut window = InflightWindow::new(2);
+        let info = Arc::new(ClientInfo::new(None, ProtocolLevel::Version311, true, None));
+        window.report_to(info.clone());
+        for packet_id in [1, 2] {
+            assert!(window.has_room());
+            window.insert(packet_id);
+        }
+        assert!(!window.has_room());
+        assert_eq!(info.inflight(), 2);
+
+        assert!(!window.remove(3));
+        assert!(!window.has_room());
+        assert!(window.remove(1));
+        #[cfg(feature = "v4")]
+        {
+            assert!(!window.contains(1));
+            assert!(window.contains(2));
+        }
+        assert!(window.has_room());
+        assert_eq!(info.inflight(), 1);
+    }
+
+    #[test]
+    fn window_has_room_for_one_at_least() {
+        let mut window = InflightWindow::new(0);
+        assert!(window.has_room());
+        window.insert(1);
+        assert!(!window.has_room());
+    }
+}
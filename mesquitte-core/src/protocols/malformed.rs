@@ -0,0 +1,164 @@
+//! Malformed packets of a connection
+//!
+//! The codec takes a packet off the buffer before it decodes it, so after a malformed packet the
+//! next one can still be read. [`SkipMalformed`] skips up to the limit set with
+//! [`GlobalState::with_max_decode_errors`](crate::server::state::GlobalState::with_max_decode_errors)
+//! of them and counts every one in the [`Metrics`].
+
+use std::{fmt::Display, sync::Arc};
+
+use bytes::BytesMut;
+use tokio_util::codec::Decoder;
+
+use crate::{server::metrics::Metrics, warn};
+
+/// Decode errors of one protocol version
+pub(crate) trait DecodeError: Display {
+    /// The packet could not be decoded, as opposed to the connection failing
+    fn is_malformed(&self) -> bool;
+
+    /// The packet was taken off the buffer, the next one can be decoded
+    fn is_skippable(&self) -> bool;
+
+    /// Reason string of the DISCONNECT sent for the packet
+    fn reason(&self) -> String;
+}
+
+macro_rules! impl_decode_error {
+    ($error:ty) => {
+        impl DecodeError for $error {
+            fn is_malformed(&self) -> bool {
+                !matches!(self, Self::IoError(_))
+            }
+
+            fn is_skippable(&self) -> bool {
+                // without a fixed header the end of the packet is unknown, and a client sending
+                // reserved packet types does not speak MQTT
+                self.is_malformed()
+                    && !matches!(
+                        self,
+                        Self::FixedHeaderError(_)
+                            | Self::PacketTooLarge(..)
+                            | Self::ReservedPacket(..)
+                    )
+            }
+
+            fn reason(&self) -> String {
+                match self {
+                    // the error shows the size of the body, the peer only needs the type
+                    Self::ReservedPacket(code, _) => format!("reserved packet type {code}"),
+                    err => err.to_string(),
+                }
+            }
+        }
+    };
+}
+
+#[cfg(feature = "v4")]
+impl_decode_error!(mqtt_codec_kit::v4::packet::VariablePacketError);
+#[cfg(feature = "v5")]
+impl_decode_error!(mqtt_codec_kit::v5::packet::VariablePacketError);
+
+/// Decoder skipping malformed packets until a connection sent `max_errors` of them
+pub(crate) struct SkipMalformed<D> {
+    decoder: D,
+    errors: usize,
+    max_errors: usize,
+    metrics: Arc<Metrics>,
+}
+
+impl<D> SkipMalformed<D> {
+    pub fn new(decoder: D, max_errors: usize, metrics: Arc<Metrics>) -> Self {
+        Self {
+            decoder,
+            errors: 0,
+            max_errors,
+            metrics,
+        }
+    }
+}
+
+impl<D> Decoder for SkipMalformed<D>
+where
+    D: Decoder,
+    D::Error: DecodeError,
+{
+    type Item = D::Item;
+    type Error = D::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        loop {
+            match self.decoder.decode(src) {
+                Err(err) if err.is_malformed() => {
+                    self.errors += 1;
+                    self.metrics.record_decode_error();
+                    if self.errors >= self.max_errors || !err.is_skippable() {
+                        self.metrics.record_malformed_disconnect();
+                        return Err(err);
+                    }
+                    warn!(
+                        "skip malformed packet {} of {}: {err}",
+                        self.errors, self.max_errors
+                    );
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+#[cfg(all(test, feature = "v4"))]
+mod tests {
+    use mqtt_codec_kit::{
+        common::Encodable,
+        v4::packet::{MqttDecoder, PingreqPacket, VariablePacket},
+    };
+
+    use super::*;
+
+    // a SUBSCRIBE with QoS 3
+    const MALFORMED: [u8; 8] = [0x82, 6, 0, 1, 0, 1, b'a', 3];
+
+    fn stream(malformed: usize) -> BytesMut {
+        let mut buf = Vec::new();
+        for _ in 0..malformed {
+            buf.extend_from_slice(&MALFORMED);
+        }
+        PingreqPacket::new().encode(&mut buf).unwrap();
+        BytesMut::from(&buf[..])
+    }
+
+    #[test]
+    fn skips_up_to_the_limit() {
+        let metrics = Arc::new(Metrics::default());
+        let mut decoder = SkipMalformed::new(MqttDecoder::new(), 3, metrics.clone());
+        let mut src = stream(2);
+        let packet: VariablePacket = PingreqPacket::new().into();
+        assert_eq!(decoder.decode(&mut src).unwrap(), Some(packet));
+        assert_eq!(metrics.decode_errors(), 2);
+        assert_eq!(metrics.malformed_disconnects(), 0);
+
+        let mut src = stream(1);
+        assert!(decoder.decode(&mut src).is_err());
+        assert_eq!(metrics.decode_errors(), 3);
+        assert_eq!(metrics.malformed_disconnects(), 1);
+    }
+
+    #[test]
+    fn reserved_packet_is_not_skipped() {
+        let metrics = Arc::new(Metrics::default());
+        let mut decoder = SkipMalformed::new(MqttDecoder::new(), 3, metrics.clone());
+        let mut src = BytesMut::from(&[0xf0, 1, 0][..]);
+        let err = decoder.decode(&mut src).unwrap_err();
+        assert_eq!(err.reason(), "reserved packet type 15");
+        assert_eq!(metrics.malformed_disconnects(), 1);
+    }
+
+    #[test]
+    fn first_error_closes_by_default() {
+        let metrics = Arc::new(Metrics::default());
+        let mut decoder = SkipMalformed::new(MqttDecoder::new(), 1, metrics.clone());
+        assert!(decoder.decode(&mut stream(1)).is_err());
+        assert_eq!(metrics.malformed_disconnects(), 1);
+    }
+}
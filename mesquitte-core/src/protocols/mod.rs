@@ -2,7 +2,20 @@ use std::io;
 
 use foldhash::HashSet;
 use mqtt_codec_kit::common::TopicFilter;
+#[cfg(feature = "v4")]
+use mqtt_codec_kit::v4::packet::VariablePacketError as V4VariablePacketError;
+#[cfg(feature = "v5")]
+use mqtt_codec_kit::v5::packet::VariablePacketError as V5VariablePacketError;
+
+use crate::{server::ErrorCategory, store::error::StoreError};
 
+pub(crate) mod common;
+pub(crate) mod delivery;
+pub(crate) mod flush;
+pub(crate) mod inflight;
+pub(crate) mod malformed;
+pub(crate) mod mount;
+pub(crate) mod unsaved;
 #[cfg(feature = "v4")]
 pub(crate) mod v4;
 #[cfg(feature = "v5")]
@@ -12,6 +25,8 @@ pub(crate) mod v5;
 pub enum Error {
     #[error("Io Error : {0}")]
     Io(#[from] io::Error),
+    #[error("Storage Error : {0}")]
+    Storage(#[from] StoreError),
     #[error("channel send error : {0}")]
     ChannelSend(#[from] kanal::SendError),
     #[cfg(feature = "v4")]
@@ -19,20 +34,53 @@ pub enum Error {
     V4InvalidPacket,
     #[error("client disconnected.")]
     Disconnect,
-    #[error("Invalid Topic : {0}")]
-    Topic(String),
     #[error("New Client : {0} ")]
     DupClient(String),
     #[error("Kick Client : {0} ")]
     Kick(String),
+    #[error("Keep Alive Timeout : {0} ")]
+    KeepAliveTimeout(String),
     #[error("Empty subscribes. ")]
     EmptySubscribes,
     #[cfg(feature = "v4")]
     #[error(transparent)]
-    V4VariablePacket(#[from] mqtt_codec_kit::v4::packet::VariablePacketError),
+    V4VariablePacket(#[from] V4VariablePacketError),
     #[cfg(feature = "v5")]
     #[error(transparent)]
-    V5VariablePacket(#[from] mqtt_codec_kit::v5::packet::VariablePacketError),
+    V5VariablePacket(#[from] V5VariablePacketError),
+}
+
+impl Error {
+    /// Stable numeric code of this error, see [`ErrorCategory`] for the code ranges.
+    pub fn code(&self) -> u16 {
+        match self {
+            #[cfg(feature = "v4")]
+            Error::V4InvalidPacket => 1101,
+            Error::Disconnect => 1102,
+            Error::EmptySubscribes => 1104,
+            Error::KeepAliveTimeout(_) => 1107,
+            #[cfg(feature = "v4")]
+            Error::V4VariablePacket(V4VariablePacketError::IoError(_)) => 4101,
+            #[cfg(feature = "v4")]
+            Error::V4VariablePacket(_) => 1105,
+            #[cfg(feature = "v5")]
+            Error::V5VariablePacket(V5VariablePacketError::IoError(_)) => 4101,
+            #[cfg(feature = "v5")]
+            Error::V5VariablePacket(_) => 1106,
+            Error::ChannelSend(_) => 2101,
+            Error::DupClient(_) => 2102,
+            Error::Kick(_) => 2103,
+            Error::Storage(StoreError::Backend(_)) => 3101,
+            Error::Storage(StoreError::NotFound(_)) => 3102,
+            Error::Storage(StoreError::Conflict(_)) => 3103,
+            Error::Storage(StoreError::Serialization(_)) => 3104,
+            Error::Io(_) => 4102,
+        }
+    }
+
+    pub fn category(&self) -> ErrorCategory {
+        ErrorCategory::from_code(self.code())
+    }
 }
 
 pub enum ProtocolSessionState {
@@ -46,7 +94,9 @@ impl ProtocolSessionState {
     pub fn subscriptions(&self) -> HashSet<TopicFilter> {
         match self {
             #[cfg(feature = "v4")]
-            ProtocolSessionState::V4(session_state) => session_state.subscriptions().clone(),
+            ProtocolSessionState::V4(session_state) => {
+                session_state.subscriptions().keys().cloned().collect()
+            }
             #[cfg(feature = "v5")]
             ProtocolSessionState::V5(session_state) => {
                 session_state.subscriptions().keys().cloned().collect()
@@ -0,0 +1,442 @@
+use std::io;
+
+use mqtt_codec_kit::common::{TopicFilter, TopicName};
+
+use crate::server::config::MountPoint;
+
+/// Codec applying the [`MountPoint`] of the listener
+///
+/// Topics of decoded packets get the mount point and topics of encoded publishes lose it, so the
+/// rest of the broker only ever sees mounted topics.
+pub(crate) struct Mounted<C> {
+    codec: C,
+    mount_point: Option<MountPoint>,
+}
+
+impl<C> Mounted<C> {
+    pub fn new(codec: C, mount_point: Option<MountPoint>) -> Self {
+        Self { codec, mount_point }
+    }
+}
+
+fn invalid_data<E>(err: E) -> io::Error
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    io::Error::new(io::ErrorKind::InvalidData, err)
+}
+
+fn mount_topic(mount_point: &MountPoint, topic: &str) -> io::Result<TopicName> {
+    TopicName::new(mount_point.mount_topic(topic)).map_err(invalid_data)
+}
+
+fn mount_filter(mount_point: &MountPoint, filter: &str) -> io::Result<TopicFilter> {
+    TopicFilter::new(mount_point.mount_filter(filter)).map_err(invalid_data)
+}
+
+// an invalid filter only fails its own subscription, so it stays unchecked once mounted
+fn mount_subscribe_filter(
+    mount_point: &MountPoint,
+    filter: &TopicFilter,
+) -> io::Result<TopicFilter> {
+    if filter.is_valid() {
+        return mount_filter(mount_point, filter);
+    }
+    Ok(unsafe { TopicFilter::new_unchecked(mount_point.mount_filter(filter)) })
+}
+
+fn unmount_topic(mount_point: &MountPoint, topic: &str) -> io::Result<Option<TopicName>> {
+    mount_point
+        .unmount(topic)
+        .map(|topic| TopicName::new(topic).map_err(invalid_data))
+        .transpose()
+}
+
+#[cfg(feature = "v4")]
+mod v4 {
+    use std::io;
+
+    use bytes::BytesMut;
+    use mqtt_codec_kit::v4::packet::{
+        connect::LastWill, MqttDecoder, MqttEncoder, SubscribePacket, UnsubscribePacket,
+        VariablePacket, VariablePacketError,
+    };
+    use tokio_util::codec::{Decoder, Encoder};
+
+    use crate::server::config::MountPoint;
+
+    use super::{
+        invalid_data, mount_filter, mount_subscribe_filter, mount_topic, unmount_topic, Mounted,
+    };
+
+    fn mount(mount_point: &MountPoint, packet: VariablePacket) -> io::Result<VariablePacket> {
+        let packet = match packet {
+            VariablePacket::ConnectPacket(mut packet) => {
+                if let Some(will) = packet.will() {
+                    let topic = mount_point.mount_topic(will.topic());
+                    let mounted =
+                        LastWill::new(topic, will.message().0.clone()).map_err(invalid_data)?;
+                    packet.set_will(Some(mounted));
+                    packet.set_will_qos(will.qos() as u8);
+                    packet.set_will_retain(will.retain());
+                }
+                packet.into()
+            }
+            VariablePacket::PublishPacket(mut packet) => {
+                packet.set_topic_name(mount_topic(mount_point, packet.topic_name())?);
+                packet.into()
+            }
+            VariablePacket::SubscribePacket(packet) => {
+                let subscribes = packet
+                    .subscribes()
+                    .iter()
+                    .map(|(filter, qos)| Ok((mount_subscribe_filter(mount_point, filter)?, *qos)))
+                    .collect::<io::Result<_>>()?;
+                SubscribePacket::new(packet.packet_identifier(), subscribes).into()
+            }
+            VariablePacket::UnsubscribePacket(packet) => {
+                let filters = packet
+                    .topic_filters()
+                    .iter()
+                    .map(|filter| mount_filter(mount_point, filter))
+                    .collect::<io::Result<_>>()?;
+                UnsubscribePacket::new(packet.packet_identifier(), filters).into()
+            }
+            packet => packet,
+        };
+        Ok(packet)
+    }
+
+    impl Decoder for Mounted<MqttDecoder> {
+        type Item = VariablePacket;
+        type Error = VariablePacketError;
+
+        fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+            let packet = self.codec.decode(src)?;
+            match (&self.mount_point, packet) {
+                (Some(mount_point), Some(packet)) => Ok(Some(mount(mount_point, packet)?)),
+                (_, packet) => Ok(packet),
+            }
+        }
+    }
+
+    impl<T: Into<VariablePacket>> Encoder<T> for Mounted<MqttEncoder> {
+        type Error = io::Error;
+
+        fn encode(&mut self, item: T, dst: &mut BytesMut) -> Result<(), Self::Error> {
+            let mut packet = item.into();
+            if let (Some(mount_point), VariablePacket::PublishPacket(publish)) =
+                (&self.mount_point, &mut packet)
+            {
+                if let Some(topic_name) = unmount_topic(mount_point, publish.topic_name())? {
+                    publish.set_topic_name(topic_name);
+                }
+            }
+            self.codec.encode(packet, dst)
+        }
+    }
+}
+
+#[cfg(feature = "v5")]
+mod v5 {
+    use std::io;
+
+    use bytes::BytesMut;
+    use mqtt_codec_kit::v5::packet::{
+        connect::LastWill, MqttDecoder, MqttEncoder, SubscribePacket, UnsubscribePacket,
+        VariablePacket, VariablePacketError,
+    };
+    use tokio_util::codec::{Decoder, Encoder};
+
+    use crate::server::config::MountPoint;
+
+    use super::{
+        invalid_data, mount_filter, mount_subscribe_filter, mount_topic, unmount_topic, Mounted,
+    };
+
+    fn mount(mount_point: &MountPoint, packet: VariablePacket) -> io::Result<VariablePacket> {
+        let packet = match packet {
+            VariablePacket::ConnectPacket(mut packet) => {
+                if let Some(will) = packet.will() {
+                    let topic = mount_point.mount_topic(will.topic());
+                    let mut mounted =
+                        LastWill::new(topic, will.message().0.clone()).map_err(invalid_data)?;
+                    mounted.set_properties(will.properties().clone());
+                    packet.set_will(Some(mounted));
+                    packet.set_will_qos(will.qos() as u8);
+                    packet.set_will_retain(will.retain());
+                }
+                packet.into()
+            }
+            // an empty topic name refers to a topic alias, which maps to a mounted topic
+            VariablePacket::PublishPacket(mut packet) if !packet.topic_name().is_empty() => {
+                packet.set_topic_name(mount_topic(mount_point, packet.topic_name())?);
+                packet.into()
+            }
+            VariablePacket::SubscribePacket(packet) => {
+                let subscribes = packet
+                    .subscribes()
+                    .iter()
+                    .map(|(filter, options)| {
+                        Ok((mount_subscribe_filter(mount_point, filter)?, *options))
+                    })
+                    .collect::<io::Result<_>>()?;
+                let mut mounted = SubscribePacket::new(packet.packet_identifier(), subscribes);
+                mounted.set_properties(packet.properties().clone());
+                mounted.into()
+            }
+            VariablePacket::UnsubscribePacket(packet) => {
+                let filters = packet
+                    .subscribes()
+                    .iter()
+                    .map(|filter| mount_filter(mount_point, filter))
+                    .collect::<io::Result<_>>()?;
+                let mut mounted = UnsubscribePacket::new(packet.packet_identifier(), filters);
+                mounted.set_properties(packet.properties().clone());
+                mounted.into()
+            }
+            packet => packet,
+        };
+        Ok(packet)
+    }
+
+    impl Decoder for Mounted<MqttDecoder> {
+        type Item = VariablePacket;
+        type Error = VariablePacketError;
+
+        fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+            let packet = self.codec.decode(src)?;
+            match (&self.mount_point, packet) {
+                (Some(mount_point), Some(packet)) => Ok(Some(mount(mount_point, packet)?)),
+                (_, packet) => Ok(packet),
+            }
+        }
+    }
+
+    impl<T: Into<VariablePacket>> Encoder<T> for Mounted<MqttEncoder> {
+        type Error = io::Error;
+
+        fn encode(&mut self, item: T, dst: &mut BytesMut) -> Result<(), Self::Error> {
+            let mut packet = item.into();
+            if let (Some(mount_point), VariablePacket::PublishPacket(publish)) =
+                (&self.mount_point, &mut packet)
+            {
+                if let Some(topic_name) = unmount_topic(mount_point, publish.topic_name())? {
+                    publish.set_topic_name(topic_name);
+                }
+            }
+            self.codec.encode(packet, dst)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::BytesMut;
+    use mqtt_codec_kit::common::qos::QoSWithPacketIdentifier;
+    use tokio_util::codec::{Decoder, Encoder};
+
+    use super::*;
+
+    fn mount_point() -> Option<MountPoint> {
+        Some(MountPoint::new("tenant/").unwrap())
+    }
+
+    fn filters(filters: &[&str]) -> Vec<TopicFilter> {
+        filters
+            .iter()
+            .map(|f| TopicFilter::new(*f).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn topics_outside_the_mount_point_are_not_unmounted() {
+        let mount_point = mount_point().unwrap();
+        assert_eq!(
+            unmount_topic(&mount_point, "tenant/a").unwrap(),
+            Some(TopicName::new("a").unwrap())
+        );
+        assert_eq!(unmount_topic(&mount_point, "other/a").unwrap(), None);
+        assert_eq!(unmount_topic(&mount_point, "$SYS/a").unwrap(), None);
+        assert_eq!(unmount_topic(&mount_point, "tenant/").unwrap(), None);
+    }
+
+    #[cfg(feature = "v4")]
+    mod v4 {
+        use mqtt_codec_kit::{
+            common::QualityOfService,
+            v4::packet::{
+                MqttDecoder, MqttEncoder, PublishPacket, SubscribePacket, UnsubscribePacket,
+                VariablePacket,
+            },
+        };
+
+        use super::*;
+
+        /// `packet` as the broker decodes it from a client of the listener
+        fn decode(mount_point: Option<MountPoint>, packet: VariablePacket) -> VariablePacket {
+            let mut buf = BytesMut::new();
+            MqttEncoder::new().encode(packet, &mut buf).unwrap();
+            let mut decoder = Mounted::new(MqttDecoder::new(), mount_point);
+            decoder.decode(&mut buf).unwrap().unwrap()
+        }
+
+        /// `packet` as a client of the listener receives it from the broker
+        fn encode(mount_point: Option<MountPoint>, packet: VariablePacket) -> VariablePacket {
+            let mut buf = BytesMut::new();
+            let mut encoder = Mounted::new(MqttEncoder::new(), mount_point);
+            encoder.encode(packet, &mut buf).unwrap();
+            MqttDecoder::new().decode(&mut buf).unwrap().unwrap()
+        }
+
+        fn publish(topic: &str) -> VariablePacket {
+            PublishPacket::new(
+                TopicName::new(topic).unwrap(),
+                QoSWithPacketIdentifier::Level1(1),
+                b"x".to_vec(),
+            )
+            .into()
+        }
+
+        fn subscribed(packet: VariablePacket) -> Vec<String> {
+            match packet {
+                VariablePacket::SubscribePacket(packet) => packet
+                    .subscribes()
+                    .iter()
+                    .map(|(filter, _)| filter.to_string())
+                    .collect(),
+                VariablePacket::UnsubscribePacket(packet) => packet
+                    .topic_filters()
+                    .iter()
+                    .map(|filter| filter.to_string())
+                    .collect(),
+                packet => panic!("unexpected packet {packet:?}"),
+            }
+        }
+
+        #[test]
+        fn publish_topics_round_trip() {
+            assert_eq!(decode(mount_point(), publish("a/b")), publish("tenant/a/b"));
+            assert_eq!(encode(mount_point(), publish("tenant/a/b")), publish("a/b"));
+            // published by another listener, outside of the mount point
+            assert_eq!(
+                encode(mount_point(), publish("other/a")),
+                publish("other/a")
+            );
+
+            assert_eq!(decode(None, publish("a/b")), publish("a/b"));
+            assert_eq!(encode(None, publish("tenant/a/b")), publish("tenant/a/b"));
+        }
+
+        #[test]
+        fn subscriptions_are_mounted() {
+            let topics = filters(&["a/+", "#", "$share/g/a/#"]);
+            let subscribe = SubscribePacket::new(
+                1,
+                topics
+                    .iter()
+                    .map(|filter| (filter.clone(), QualityOfService::Level1))
+                    .collect(),
+            );
+            let expected = ["tenant/a/+", "tenant/#", "$share/g/tenant/a/#"];
+            assert_eq!(
+                subscribed(decode(mount_point(), subscribe.into())),
+                expected
+            );
+            let unsubscribe = UnsubscribePacket::new(1, topics);
+            assert_eq!(
+                subscribed(decode(mount_point(), unsubscribe.into())),
+                expected
+            );
+        }
+
+        #[test]
+        fn sys_topics_are_mounted() {
+            // clients of the listener only see the `$SYS` topics under the mount point
+            let subscribe = SubscribePacket::new(
+                1,
+                vec![(
+                    TopicFilter::new("$SYS/#").unwrap(),
+                    QualityOfService::Level0,
+                )],
+            );
+            assert_eq!(
+                subscribed(decode(mount_point(), subscribe.into())),
+                ["tenant/$SYS/#"]
+            );
+            assert_eq!(
+                decode(mount_point(), publish("$SYS/a")),
+                publish("tenant/$SYS/a")
+            );
+            assert_eq!(
+                encode(mount_point(), publish("tenant/$SYS/a")),
+                publish("$SYS/a")
+            );
+            assert_eq!(
+                encode(mount_point(), publish("$SYS/broker/uptime")),
+                publish("$SYS/broker/uptime")
+            );
+        }
+    }
+
+    #[cfg(feature = "v5")]
+    mod v5 {
+        use mqtt_codec_kit::v5::packet::{
+            subscribe::SubscribeOptions, MqttDecoder, MqttEncoder, PublishPacket, SubscribePacket,
+            VariablePacket,
+        };
+
+        use super::*;
+
+        fn decode(packet: VariablePacket) -> VariablePacket {
+            let mut buf = BytesMut::new();
+            MqttEncoder::new().encode(packet, &mut buf).unwrap();
+            let mut decoder = Mounted::new(MqttDecoder::new(), mount_point());
+            decoder.decode(&mut buf).unwrap().unwrap()
+        }
+
+        fn encode(packet: VariablePacket) -> VariablePacket {
+            let mut buf = BytesMut::new();
+            let mut encoder = Mounted::new(MqttEncoder::new(), mount_point());
+            encoder.encode(packet, &mut buf).unwrap();
+            MqttDecoder::new().decode(&mut buf).unwrap().unwrap()
+        }
+
+        fn publish(topic: &str) -> PublishPacket {
+            PublishPacket::new(
+                TopicName::new(topic).unwrap(),
+                QoSWithPacketIdentifier::Level0,
+                b"x".to_vec(),
+            )
+        }
+
+        #[test]
+        fn publish_topics_round_trip() {
+            assert_eq!(decode(publish("a/b").into()), publish("tenant/a/b").into());
+            assert_eq!(encode(publish("tenant/a/b").into()), publish("a/b").into());
+            assert_eq!(
+                decode(publish("$SYS/a").into()),
+                publish("tenant/$SYS/a").into()
+            );
+        }
+
+        #[test]
+        fn shared_subscriptions_are_mounted() {
+            let subscribes = filters(&["$share/g/a/+", "$SYS/#"])
+                .into_iter()
+                .map(|filter| (filter, SubscribeOptions::default()))
+                .collect();
+            let VariablePacket::SubscribePacket(packet) =
+                decode(SubscribePacket::new(1, subscribes).into())
+            else {
+                panic!("not a subscribe");
+            };
+            let mounted: Vec<_> = packet
+                .subscribes()
+                .iter()
+                .map(|(filter, _)| filter.to_string())
+                .collect();
+            assert_eq!(mounted, ["$share/g/tenant/a/+", "tenant/$SYS/#"]);
+        }
+    }
+}
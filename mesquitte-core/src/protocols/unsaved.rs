@@ -0,0 +1,75 @@
+//! QoS 1 messages sent without saving them first, see
+//! [`GlobalState::with_qos1_fast_path`](crate::server::state::GlobalState::with_qos1_fast_path)
+//!
+//! [`DeliveryCore`](super::delivery::DeliveryCore) keeps a QoS 1 message here instead of saving
+//! it while there is room, a PUBACK drops it without asking the store. The messages left
+//! unacknowledged are spilled to the store in the order they were sent, once they are older
+//! than [`Qos1FastPath::spill_after`] or all of them when the connection ends.
+
+use std::collections::VecDeque;
+
+use tokio::time::Instant;
+
+use crate::{server::config::Qos1FastPath, store::message::PendingPublishMessage};
+
+pub(crate) struct UnsavedMessages {
+    fast_path: Option<Qos1FastPath>,
+    // in the order they were sent, a window holds few messages so lookups scan it
+    messages: VecDeque<(u16, PendingPublishMessage, Instant)>,
+}
+
+impl UnsavedMessages {
+    pub fn new(fast_path: Option<Qos1FastPath>) -> Self {
+        Self {
+            fast_path,
+            messages: VecDeque::new(),
+        }
+    }
+
+    /// Whether another message can be kept unsaved
+    pub fn has_room(&self) -> bool {
+        self.fast_path
+            .is_some_and(|fast_path| self.messages.len() < fast_path.max_unsaved)
+    }
+
+    pub fn contains(&self, packet_id: u16) -> bool {
+        self.messages.iter().any(|(id, ..)| *id == packet_id)
+    }
+
+    pub fn keep(&mut self, packet_id: u16, pending: PendingPublishMessage) {
+        self.messages
+            .push_back((packet_id, pending, Instant::now()));
+    }
+
+    /// Drops the message of a PUBACK, false if it is not kept here
+    #[cfg(feature = "v4")]
+    pub fn complete(&mut self, packet_id: u16) -> bool {
+        let Some(index) = self.messages.iter().position(|(id, ..)| *id == packet_id) else {
+            return false;
+        };
+        if let Some((_, pending, _)) = self.messages.remove(index) {
+            pending.recycle();
+        }
+        true
+    }
+
+    /// The messages to save now, the ones older than the spill age or all of them with `all`
+    #[cfg(feature = "v4")]
+    pub fn take_spilled(&mut self, all: bool) -> Vec<(u16, PendingPublishMessage)> {
+        let count = match self.fast_path {
+            _ if all => self.messages.len(),
+            Some(fast_path) => {
+                let now = Instant::now();
+                self.messages
+                    .iter()
+                    .take_while(|(.., sent_at)| now - *sent_at >= fast_path.spill_after)
+                    .count()
+            }
+            None => 0,
+        };
+        self.messages
+            .drain(..count)
+            .map(|(packet_id, pending, _)| (packet_id, pending))
+            .collect()
+    }
+}
@@ -0,0 +1,324 @@
+//! Checks a CONNECT packet before a session is created for it
+
+use std::net::SocketAddr;
+
+use mqtt_codec_kit::{
+    common::{ProtocolLevel, MATCH_ALL_STR, MATCH_ONE_STR},
+    v4::{control::ConnectReturnCode, packet::ConnectPacket},
+};
+use nanoid::nanoid;
+
+use crate::{
+    debug, error,
+    server::{
+        audit::AuditEvent,
+        state::{ConnectVerdict, GlobalState},
+    },
+};
+
+/// Longest client identifier of MQTT 3.1, which also requires one
+const MQTT31_MAX_CLIENT_ID_LEN: usize = 23;
+
+/// Returns the client identifier of the connection, or the return code the CONNACK refusing
+/// it is sent with
+///
+/// A client authenticated by its certificate, `cert_identity`, sends no credentials.
+pub(super) async fn check_connect<S>(
+    packet: &ConnectPacket,
+    global: &GlobalState<S>,
+    remote_addr: Option<SocketAddr>,
+    cert_identity: Option<&str>,
+) -> Result<String, ConnectReturnCode> {
+    let accepted = match (packet.protocol_level(), packet.protocol_name()) {
+        (ProtocolLevel::Version311, "MQTT") => true,
+        (ProtocolLevel::Version310, "MQIsdp") => global.mqtt31(),
+        _ => false,
+    };
+    if !accepted {
+        error!(
+            "unsupported protocol name or level: {:?} {:?}",
+            packet.protocol_name(),
+            packet.protocol_level()
+        );
+        return Err(ConnectReturnCode::UnacceptableProtocolVersion);
+    }
+
+    // MQTT 3.1.1 has no server reference to redirect to
+    if let Some(redirection) = global.redirection() {
+        debug!(
+            "client#{} refused, the broker redirects to {}",
+            packet.client_identifier(),
+            redirection.server_reference
+        );
+        return Err(ConnectReturnCode::ServiceUnavailable);
+    }
+
+    if let Err(reason) = global
+        .client_id_config()
+        .validate(packet.client_identifier())
+    {
+        debug!("client#{} {reason}", packet.client_identifier());
+        return Err(ConnectReturnCode::IdentifierRejected);
+    }
+
+    if packet.client_identifier().is_empty() && !packet.clean_session() {
+        debug!("empty client identifier without clean session");
+        return Err(ConnectReturnCode::IdentifierRejected);
+    }
+
+    if packet.protocol_level() == ProtocolLevel::Version310
+        && (packet.client_identifier().is_empty()
+            || packet.client_identifier().len() > MQTT31_MAX_CLIENT_ID_LEN)
+    {
+        debug!(
+            "client#{} MQTT 3.1 client identifier is not 1 to {MQTT31_MAX_CLIENT_ID_LEN} bytes",
+            packet.client_identifier()
+        );
+        return Err(ConnectReturnCode::IdentifierRejected);
+    }
+
+    let client_id = if packet.client_identifier().is_empty() {
+        nanoid!()
+    } else {
+        packet.client_identifier().to_string()
+    };
+
+    let verdict = match cert_identity {
+        Some(_) if packet.username().is_some() || packet.password().is_some() => {
+            debug!("client#{client_id} sent credentials to a certificate only listener");
+            ConnectVerdict::BadCredentials
+        }
+        Some(_) => ConnectVerdict::Accepted,
+        None => {
+            global
+                .authorize_connect(&client_id, packet.username(), packet.password())
+                .await
+        }
+    };
+    let code = match verdict {
+        ConnectVerdict::Accepted => None,
+        ConnectVerdict::BadCredentials => Some(ConnectReturnCode::BadUserNameOrPassword),
+        ConnectVerdict::NotAuthorized => Some(ConnectReturnCode::NotAuthorized),
+        ConnectVerdict::Unavailable => {
+            debug!("client#{client_id} connect could not be authorized");
+            return Err(ConnectReturnCode::ServiceUnavailable);
+        }
+    };
+    if let Some(code) = code {
+        debug!("client#{client_id} connect denied: {code:?}");
+        global.audit(
+            remote_addr,
+            AuditEvent::AuthFailure {
+                client_id: &client_id,
+                username: packet.username(),
+            },
+        );
+        return Err(code);
+    }
+
+    if let Some(last_will) = packet.will() {
+        let topic_name = last_will.topic();
+        if topic_name.is_empty() {
+            debug!("handle connect last will topic is empty");
+            return Err(ConnectReturnCode::IdentifierRejected);
+        }
+
+        if topic_name.contains(MATCH_ALL_STR) || topic_name.contains(MATCH_ONE_STR) {
+            debug!("handle connect last will topic contains illegal characters '+' or '#'");
+            return Err(ConnectReturnCode::IdentifierRejected);
+        }
+
+        if global.is_reserved_topic(topic_name) {
+            debug!("handle connect last will topic is reserved");
+            return Err(ConnectReturnCode::NotAuthorized);
+        }
+    }
+
+    Ok(client_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use mqtt_codec_kit::v4::packet::connect::LastWill;
+
+    use super::*;
+    use crate::store::{memory::MemoryStore, memory_storage};
+
+    fn global() -> GlobalState<MemoryStore> {
+        GlobalState::new(memory_storage())
+    }
+
+    fn connect(client_id: &str) -> ConnectPacket {
+        let mut packet = ConnectPacket::new(client_id);
+        packet.set_clean_session(true);
+        packet.set_username(Some("user".to_owned()));
+        packet.set_password(Some("secret".to_owned()));
+        packet
+    }
+
+    #[tokio::test]
+    async fn accepted() {
+        assert_eq!(
+            check_connect(&connect("c1"), &global(), None, None)
+                .await
+                .unwrap(),
+            "c1"
+        );
+        let assigned = check_connect(&connect(""), &global(), None, None)
+            .await
+            .unwrap();
+        assert!(!assigned.is_empty());
+    }
+
+    #[tokio::test]
+    async fn unacceptable_protocol_version() {
+        for packet in [
+            ConnectPacket::with_level("MQIsdp", "c1", 4).unwrap(),
+            ConnectPacket::with_level("MQTT", "c1", 3).unwrap(),
+            ConnectPacket::with_level("MQTX", "c1", 4).unwrap(),
+        ] {
+            assert_eq!(
+                check_connect(&packet, &global(), None, None).await,
+                Err(ConnectReturnCode::UnacceptableProtocolVersion)
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn mqtt31() {
+        let packet = ConnectPacket::with_level("MQIsdp", "c1", 3).unwrap();
+        assert_eq!(
+            check_connect(&packet, &global(), None, None).await.unwrap(),
+            "c1"
+        );
+        assert_eq!(
+            check_connect(&packet, &global().with_mqtt31(false), None, None).await,
+            Err(ConnectReturnCode::UnacceptableProtocolVersion)
+        );
+
+        // MQTT 3.1 has no assigned client identifiers
+        let mut packet = ConnectPacket::with_level("MQIsdp", "", 3).unwrap();
+        packet.set_clean_session(true);
+        let long = ConnectPacket::with_level("MQIsdp", "c".repeat(24), 3).unwrap();
+        for packet in [packet, long] {
+            assert_eq!(
+                check_connect(&packet, &global(), None, None).await,
+                Err(ConnectReturnCode::IdentifierRejected)
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn identifier_rejected() {
+        let mut packet = connect("");
+        packet.set_clean_session(false);
+        assert_eq!(
+            check_connect(&packet, &global(), None, None).await,
+            Err(ConnectReturnCode::IdentifierRejected)
+        );
+    }
+
+    #[tokio::test]
+    async fn reserved_will_topic_is_not_authorized() {
+        let mut packet = connect("c1");
+        packet.set_will(Some(LastWill::new("$SYS/will", b"bye".to_vec()).unwrap()));
+        assert_eq!(
+            check_connect(&packet, &global(), None, None).await,
+            Err(ConnectReturnCode::NotAuthorized)
+        );
+    }
+
+    #[tokio::test]
+    async fn certificate_refuses_credentials() {
+        assert_eq!(
+            check_connect(&connect("c1"), &global(), None, Some("sensor-17")).await,
+            Err(ConnectReturnCode::BadUserNameOrPassword)
+        );
+        let mut packet = connect("c1");
+        packet.set_password(None);
+        assert_eq!(
+            check_connect(&packet, &global(), None, Some("sensor-17")).await,
+            Err(ConnectReturnCode::BadUserNameOrPassword)
+        );
+        packet.set_username(None);
+        assert_eq!(
+            check_connect(&packet, &global(), None, Some("sensor-17"))
+                .await
+                .unwrap(),
+            "c1"
+        );
+    }
+
+    #[cfg(feature = "script")]
+    mod script {
+        use super::*;
+        use crate::script::ScriptHook;
+
+        const SCRIPT: &str = r#"
+            function on_connect(ctx)
+                if ctx.client_id == "broken" then
+                    error("auth backend down")
+                end
+                if ctx.password ~= "secret" then
+                    return "bad_credentials"
+                end
+                return ctx.username == "user"
+            end
+        "#;
+
+        fn global() -> GlobalState<MemoryStore> {
+            super::global().with_script(ScriptHook::new(SCRIPT).unwrap())
+        }
+
+        #[tokio::test]
+        async fn accepted() {
+            assert_eq!(
+                check_connect(&connect("c1"), &global(), None, None)
+                    .await
+                    .unwrap(),
+                "c1"
+            );
+        }
+
+        #[tokio::test]
+        async fn bad_user_name_or_password() {
+            let mut packet = connect("c1");
+            packet.set_password(Some("wrong".to_owned()));
+            assert_eq!(
+                check_connect(&packet, &global(), None, None).await,
+                Err(ConnectReturnCode::BadUserNameOrPassword)
+            );
+        }
+
+        #[tokio::test]
+        async fn not_authorized() {
+            let mut packet = connect("c1");
+            packet.set_username(Some("other".to_owned()));
+            assert_eq!(
+                check_connect(&packet, &global(), None, None).await,
+                Err(ConnectReturnCode::NotAuthorized)
+            );
+        }
+
+        #[tokio::test]
+        async fn service_unavailable() {
+            assert_eq!(
+                check_connect(&connect("broken"), &global(), None, None).await,
+                Err(ConnectReturnCode::ServiceUnavailable)
+            );
+        }
+
+        #[tokio::test]
+        async fn certificate_skips_script() {
+            let mut packet = connect("broken");
+            packet.set_username(None);
+            packet.set_password(None);
+            assert_eq!(
+                check_connect(&packet, &global(), None, Some("sensor-17"))
+                    .await
+                    .unwrap(),
+                "broken"
+            );
+        }
+    }
+}
@@ -1,30 +1,44 @@
+use std::{net::SocketAddr, sync::Arc};
+
 use futures::{SinkExt as _, StreamExt as _};
 use kanal::bounded_async;
 use mqtt_codec_kit::{
-    common::{ProtocolLevel, MATCH_ALL_STR, MATCH_ONE_STR, SHARED_PREFIX, SYS_PREFIX},
+    common::ProtocolLevel,
     v4::{
         control::ConnectReturnCode,
         packet::{ConnackPacket, MqttDecoder, MqttEncoder, PublishPacket, VariablePacket},
     },
 };
-use nanoid::nanoid;
 use read_loop::ReadLoop;
 use session::Session;
-use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    time,
+};
 use tokio_util::codec::{FramedRead, FramedWrite};
 use write_loop::WriteLoop;
 
 use crate::{
     debug, error,
-    protocols::ProtocolSessionState,
-    server::state::{AddClientReceipt, GlobalState},
+    instrument::{record_client_id, InstrumentExt as _},
+    protocols::{malformed::SkipMalformed, mount::Mounted, ProtocolSessionState},
+    server::{
+        audit::AuditEvent,
+        client_info::ClientInfo,
+        config::MountPoint,
+        slow_consumer::WriteTimeout,
+        state::{AddClientReceipt, GlobalState},
+        supervisor::log_panic,
+    },
     store::{
         message::{MessageStore, PendingPublishMessage},
         retain::RetainMessageStore,
         topic::TopicStore,
     },
+    warn,
 };
 
+mod connect;
 mod read_loop;
 mod write_loop;
 
@@ -33,13 +47,17 @@ pub mod session;
 #[derive(Debug)]
 pub(crate) enum WritePacket {
     VariablePacket(VariablePacket),
-    PendingMessage(PendingPublishMessage),
+    /// Packets written together and flushed once, see [`ReadLoop`](read_loop::ReadLoop)
+    Batch(Vec<WritePacket>),
 }
 
 pub(crate) struct EventLoop<R, W, S: 'static> {
     reader: R,
     writer: W,
-    global: &'static GlobalState<S>,
+    remote_addr: Option<SocketAddr>,
+    mount_point: Option<MountPoint>,
+    cert_identity: Option<String>,
+    global: Arc<GlobalState<S>>,
 }
 
 impl<R, W, S> EventLoop<R, W, S>
@@ -48,110 +66,108 @@ where
     W: AsyncWrite + Unpin + Send + Sync + 'static,
     S: MessageStore + RetainMessageStore + TopicStore,
 {
-    pub fn new(reader: R, writer: W, global: &'static GlobalState<S>) -> Self {
+    pub fn new(
+        reader: R,
+        writer: W,
+        remote_addr: Option<SocketAddr>,
+        mount_point: Option<MountPoint>,
+        cert_identity: Option<String>,
+        global: Arc<GlobalState<S>>,
+    ) -> Self {
         Self {
             reader,
             writer,
+            remote_addr,
+            mount_point,
+            cert_identity,
             global,
         }
     }
 
     pub async fn run(self) {
-        let mut frame_reader = FramedRead::new(self.reader, MqttDecoder::new());
-        let mut frame_writer = FramedWrite::new(self.writer, MqttEncoder::new());
+        let mut decoder = MqttDecoder::new();
+        if let Some(max) = self.global.max_remaining_length() {
+            decoder = decoder.with_max_remaining_length(max);
+        }
+        let mut frame_reader = FramedRead::new(
+            self.reader,
+            SkipMalformed::new(
+                Mounted::new(decoder, self.mount_point.clone()),
+                self.global.max_decode_errors(),
+                self.global.metrics().clone(),
+            ),
+        );
+        let writer = WriteTimeout::new(
+            self.writer,
+            self.global.slow_consumer().write_timeout,
+            self.global.metrics().clone(),
+        );
+        let mut frame_writer =
+            FramedWrite::new(writer, Mounted::new(MqttEncoder::new(), self.mount_point));
 
-        let packet = match frame_reader.next().await {
-            Some(Ok(VariablePacket::ConnectPacket(packet))) => packet,
-            _ => {
+        let packet = match time::timeout(self.global.handshake_timeout(), frame_reader.next()).await
+        {
+            Ok(Some(Ok(VariablePacket::ConnectPacket(packet)))) => packet,
+            Ok(_) => {
                 error!("first packet is not CONNECT packet");
                 return;
             }
+            Err(_) => {
+                warn!(
+                    "no CONNECT packet within {:?}",
+                    self.global.handshake_timeout()
+                );
+                return;
+            }
         };
 
-        if packet.protocol_level() != ProtocolLevel::Version311 || packet.protocol_name() != "MQTT"
-        {
-            error!(
-                "unsupported protocol name or level: {:?} {:?}",
-                packet.protocol_name(),
-                packet.protocol_level()
-            );
-            let _ = frame_writer
-                .send(ConnackPacket::new(
-                    false,
-                    ConnectReturnCode::UnacceptableProtocolVersion,
-                ))
-                .await;
-            return;
-        }
-
-        if packet.client_identifier().is_empty() && !packet.clean_session() {
-            let _ = frame_writer
-                .send(ConnackPacket::new(
-                    false,
-                    ConnectReturnCode::IdentifierRejected,
-                ))
-                .await;
+        let cert_identity = self.cert_identity.as_deref();
+        let client_id =
+            match connect::check_connect(&packet, &self.global, self.remote_addr, cert_identity)
+                .await
+            {
+                Ok(client_id) => client_id,
+                Err(code) => {
+                    let _ = frame_writer.send(ConnackPacket::new(false, code)).await;
+                    return;
+                }
+            };
+        // MQTT 3.1.1 has no return code for a protocol error, the connection is closed
+        if self.global.strict_handshake() && !frame_reader.read_buffer().is_empty() {
+            debug!("client#{client_id} sent packets before its CONNACK");
             return;
         }
 
-        // TODO: handle auth
-        let client_id = if packet.client_identifier().is_empty() {
-            nanoid!()
-        } else {
-            packet.client_identifier().to_string()
-        };
-
         let mut session = Session::new(&client_id);
+        session.set_remote_addr(self.remote_addr);
         session.set_clean_session(packet.clean_session());
-        session.set_username(packet.username().map(|name| name.to_owned()));
+        session.set_username(
+            cert_identity
+                .or(packet.username())
+                .map(|name| name.to_owned()),
+        );
         session.set_keep_alive(packet.keep_alive());
 
         if let Some(last_will) = packet.will() {
-            let topic_name = last_will.topic();
-            if topic_name.is_empty() {
-                debug!("handle connect last will topic is empty");
-                let _ = frame_writer
-                    .send(ConnackPacket::new(
-                        false,
-                        ConnectReturnCode::IdentifierRejected,
-                    ))
-                    .await;
-                return;
-            }
-
-            if topic_name.contains(MATCH_ALL_STR) || topic_name.contains(MATCH_ONE_STR) {
-                debug!("handle connect last will topic contains illegal characters '+' or '#'");
-                let _ = frame_writer
-                    .send(ConnackPacket::new(
-                        false,
-                        ConnectReturnCode::IdentifierRejected,
-                    ))
-                    .await;
-                return;
-            }
-
-            if topic_name.starts_with(SHARED_PREFIX) || topic_name.starts_with(SYS_PREFIX) {
-                debug!("handle connect last will topic start with '$SYS/' or '$share/'");
-                let _ = frame_writer
-                    .send(ConnackPacket::new(
-                        false,
-                        ConnectReturnCode::IdentifierRejected,
-                    ))
-                    .await;
-                return;
-            }
-
             session.set_last_will(last_will)
         }
 
         // FIXME: too many clients cause memory leak
 
-        // TODO: deliver channel size
-        let (deliver_tx, deliver_rx) = bounded_async(8);
+        let (deliver_tx, deliver_rx) =
+            bounded_async(self.global.channel_config().deliver_channel_size);
 
+        let info = Arc::new(ClientInfo::new(
+            self.remote_addr,
+            packet.protocol_level(),
+            session.clean_session(),
+            session.username(),
+        ));
+        session.set_info(info.clone());
+        let token = self.global.next_timer_token();
         let receipt = self
             .global
-            .add_client(session.client_id(), deliver_tx)
+            .add_client(session.client_id(), token, deliver_tx, info)
             .await;
         let session_present = match receipt {
             AddClientReceipt::Present(state) => {
@@ -172,6 +188,7 @@ where
                     );
                     false
                 };
+                // the state of a session not resumed is deleted before the new one starts
                 if !present {
                     for topic_filter in subscriptions {
                         if let Err(err) = self
@@ -183,11 +200,57 @@ where
                             debug!("handle connect unsubscribe old topic failed: {err}");
                         }
                     }
+                    if let Err(err) = self.global.storage.clear_all(session.client_id()).await {
+                        debug!("handle connect clear old messages failed: {err}");
+                    }
                 }
                 present
             }
-            AddClientReceipt::New => false,
+            AddClientReceipt::New if !session.clean_session() => {
+                match self.global.load_session(session.client_id()).await {
+                    Some(stored) => {
+                        session.restore(&stored);
+                        true
+                    }
+                    None => false,
+                }
+            }
+            AddClientReceipt::New => {
+                self.global
+                    .discard_stored_session(session.client_id())
+                    .await;
+                false
+            }
+            AddClientReceipt::Rejected => {
+                let _ = frame_writer
+                    .send(ConnackPacket::new(
+                        false,
+                        ConnectReturnCode::IdentifierRejected,
+                    ))
+                    .await;
+                return;
+            }
+            AddClientReceipt::Renamed(client_id) => {
+                session.set_client_id(client_id);
+                false
+            }
         };
+        record_client_id(session.client_id());
+        if session.clean_session() {
+            self.global.remove_session(session.client_id()).await;
+        } else {
+            self.global.save_session(session.to_stored()).await;
+        }
+        let Some(deliver_queue) = self.global.deliver_queue(session.client_id()) else {
+            error!("client#{} deliver queue not found", session.client_id());
+            return;
+        };
+        if !session_present {
+            deliver_queue.clear();
+        }
+        // the acknowledge flags of an MQTT 3.1 CONNACK are reserved
+        let session_present =
+            session_present && packet.protocol_level() == ProtocolLevel::Version311;
         if let Err(err) = frame_writer
             .send(ConnackPacket::new(
                 session_present,
@@ -198,39 +261,74 @@ where
             error!("write connect ack error: {err}");
             return;
         }
+        let event = AuditEvent::Connect {
+            client_id: session.client_id(),
+            username: session.username(),
+            protocol: packet.protocol_level() as u8,
+            clean_session: session.clean_session(),
+        };
+        self.global.audit(self.remote_addr, event);
+        self.global.presence_changed(self.remote_addr, event).await;
 
         debug!("{session}");
 
         let (write_tx, write_rx) = bounded_async(2024);
         let client_id = session.client_id().to_owned();
+        let clean_session = session.clean_session();
+        let global = self.global.clone();
         let mut read_task = tokio::spawn(
-            ReadLoop::new(frame_reader, session, deliver_rx, write_tx, self.global)
-                .read_from_client(),
+            ReadLoop::new(
+                frame_reader,
+                session,
+                deliver_rx,
+                deliver_queue,
+                write_tx,
+                token,
+                self.global.clone(),
+            )
+            .read_from_client()
+            .in_current_span(),
         );
 
-        let mut write_task = tokio::spawn(async {
-            WriteLoop::new(frame_writer, client_id, write_rx, self.global)
-                .write_to_client()
-                .await
-        });
+        let write_client_id = client_id.clone();
+        let mut write_task = tokio::spawn(
+            async move {
+                WriteLoop::new(frame_writer, write_client_id, write_rx, self.global)
+                    .write_to_client()
+                    .await
+            }
+            .in_current_span(),
+        );
 
-        if tokio::try_join!(&mut read_task, &mut write_task).is_err() {
-            error!("read_task/write_task terminated");
+        if let Err(err) = tokio::try_join!(&mut read_task, &mut write_task) {
             read_task.abort();
             write_task.abort();
+            if !err.is_panic() {
+                error!("read_task/write_task terminated");
+                return;
+            }
+            log_panic(
+                format_args!("client#{client_id} connection task"),
+                err.into_panic().as_ref(),
+            );
+            if global.release_client(&client_id, token, clean_session) && clean_session {
+                if let Err(err) = global.storage.clear_all(&client_id).await {
+                    warn!("client#{client_id} clear messages failed: {err}");
+                }
+            }
         };
     }
 }
 
 impl From<PendingPublishMessage> for PublishPacket {
     fn from(value: PendingPublishMessage) -> Self {
-        let mut pkt = PublishPacket::new(
-            value.message().topic_name().to_owned(),
-            value.qos(),
-            value.message().payload(),
-        );
-        pkt.set_dup(value.dup());
-        pkt.set_retain(value.message().retain());
+        let (qos, dup) = (value.qos(), value.dup());
+        let message = value.into_message();
+        let retain = message.retain();
+        let (topic_name, payload) = message.into_parts();
+        let mut pkt = PublishPacket::new(topic_name, qos, payload);
+        pkt.set_dup(dup);
+        pkt.set_retain(retain);
 
         pkt
     }
@@ -1,11 +1,10 @@
-use std::{cmp, time::Duration};
+use std::{mem, sync::Arc, time::Duration};
 
-use futures::StreamExt as _;
+use futures::{FutureExt as _, StreamExt as _};
 use kanal::{AsyncReceiver, AsyncSender};
 use mqtt_codec_kit::{
     common::{
         qos::QoSWithPacketIdentifier, QualityOfService, TopicFilter, MATCH_ALL_STR, MATCH_ONE_STR,
-        SHARED_PREFIX,
     },
     v4::packet::{
         suback::SubscribeReturnCode, DisconnectPacket, PingrespPacket, PubackPacket, PubcompPacket,
@@ -21,24 +20,43 @@ use tokio_util::codec::{Decoder, FramedRead};
 
 use crate::{
     debug, error,
-    protocols::{Error, ProtocolSessionState},
-    server::state::{DeliverMessage, GlobalState},
+    instrument::InstrumentExt as _,
+    protocols::{
+        common::{complete_qos2, queue_retained, Qos2Completion},
+        delivery::DeliveryCore,
+        malformed::DecodeError as _,
+        Error, ProtocolSessionState,
+    },
+    server::{
+        audit::AuditEvent,
+        state::{DeliverMessage, GlobalState, KickReason},
+        timer::TimerKind,
+    },
     store::{
-        message::{MessageStore, PendingPublishMessage, PublishMessage},
+        message::{MessageStore, PublishMessage, ReceiveOutcome},
+        queue::{DeliverQueue, DELIVER_BATCH_SIZE},
         retain::RetainMessageStore,
-        topic::TopicStore,
+        topic::{SubscriptionOptions, TopicStore},
     },
     warn,
 };
 
 use super::{session::Session, WritePacket};
 
+/// Packets to the client buffered before they are sent to the write loop together
+const WRITE_BATCH_SIZE: usize = 64;
+
 pub(crate) struct ReadLoop<T, D, S: 'static> {
     reader: FramedRead<T, D>,
     write_tx: AsyncSender<WritePacket>,
+    // packets to the client not yet sent to the write loop
+    writes: Vec<WritePacket>,
     deliver_rx: AsyncReceiver<DeliverMessage>,
+    deliver_queue: Arc<DeliverQueue>,
+    delivery: DeliveryCore<Session>,
     session: Session,
-    global: &'static GlobalState<S>,
+    global: Arc<GlobalState<S>>,
+    timer_token: u64,
 }
 
 impl<T, D, S> ReadLoop<T, D, S>
@@ -51,15 +69,26 @@ where
         reader: FramedRead<T, D>,
         session: Session,
         deliver_rx: AsyncReceiver<DeliverMessage>,
+        deliver_queue: Arc<DeliverQueue>,
         write_tx: AsyncSender<WritePacket>,
-        global: &'static GlobalState<S>,
+        timer_token: u64,
+        global: Arc<GlobalState<S>>,
     ) -> Self {
+        let mut delivery =
+            DeliveryCore::new(global.max_inflight()).with_qos1_fast_path(global.qos1_fast_path());
+        if let Some(info) = session.info() {
+            delivery.inflight_mut().report_to(info.clone());
+        }
         Self {
             reader,
             session,
             deliver_rx,
+            deliver_queue,
+            delivery,
             write_tx,
+            writes: Vec::new(),
             global,
+            timer_token,
         }
     }
 
@@ -67,107 +96,196 @@ where
         let interval = Duration::from_millis(500);
         let mut tick = interval_at(Instant::now() + interval, interval);
         if self.session.keep_alive() > 0 {
-            let half_interval = Duration::from_millis(self.session.keep_alive() as u64 * 500);
-            let mut keep_alive_tick = interval_at(Instant::now() + half_interval, half_interval);
-            let keep_alive_timeout = half_interval * 3;
-            loop {
-                tokio::select! {
-                    packet = self.reader.next() => match packet {
-                        Some(Ok(p)) => match self.handle_read_packet(&p).await {
-                            Ok(_) => continue,
-                            Err(err) => {
-                                warn!("handle read packet error: {err}");
-                                break;
-                            }
-                        },
-                        Some(Err(err)) => {
-                            error!("read form client failed: {err}");
-                            break;
-                        }
-                        None => {
-                            error!("reader closed");
-                            break;
-                        }
-                    },
-                    packet = self.deliver_rx.recv() => match packet {
-                        Ok(packet) => match self.handle_deliver_packet(packet).await {
-                            Ok(_) => continue,
-                            Err(err) => {
-                                warn!("handle deliver failed: {err}");
-                                break;
-                            }
-                        },
+            self.global.schedule_timer(
+                self.session.client_id(),
+                self.timer_token,
+                TimerKind::KeepAlive,
+                self.session.keep_alive_timeout(),
+            );
+        }
+        if let Err(err) = self.load_pending_messages().await {
+            warn!(
+                "load pending messages failed [{}#{}]: {err}",
+                err.category(),
+                err.code()
+            );
+            self.global.metrics().record_error(err.category());
+        }
+        loop {
+            if let Err(err) = self.send_writes().await {
+                warn!("send packets to the write loop failed: {err}");
+                break;
+            }
+            tokio::select! {
+                packet = self.reader.next() => if !self.handle_read(packet).await {
+                    break;
+                },
+                packet = self.deliver_rx.recv() => match packet {
+                    Ok(packet) => match self.handle_deliver_packet(packet).await {
+                        Ok(_) => continue,
                         Err(err) => {
-                            warn!("deliver receive channel: {err}");
-                            break;
-                        }
-                    },
-                    _ = tick.tick() => {
-                        match self.handle_pending_messages(false).await {
-                            Ok(_) => {},
-                            Err(_) => break,
-                        }
-                    },
-                    _ = keep_alive_tick.tick() => {
-                        if self.session.last_packet_at().elapsed() > keep_alive_timeout {
+                            warn!(
+                                "handle deliver failed [{}#{}]: {err}",
+                                err.category(),
+                                err.code()
+                            );
+                            self.record_error(&err);
                             break;
                         }
                     },
+                    Err(err) => {
+                        warn!("deliver receive channel: {err}");
+                        break;
+                    }
+                },
+                _ = self.deliver_queue.notified() => match self.drain_messages().await {
+                    Ok(_) => continue,
+                    Err(err) => {
+                        warn!(
+                            "handle queued messages failed [{}#{}]: {err}",
+                            err.category(),
+                            err.code()
+                        );
+                        self.record_error(&err);
+                        break;
+                    }
+                },
+                _ = tick.tick() => {
+                    if self.write_tx.is_disconnected() {
+                        // the write loop ended, e.g. a write to the client timed out
+                        self.session.set_server_disconnected_for("write failed");
+                        break;
+                    }
+                    if self.is_slow_consumer().await {
+                        break;
+                    }
+                    match self.handle_pending_messages().await {
+                        Ok(_) => {},
+                        Err(_) => break,
+                    }
+                },
+            }
+        }
+        // the answers to the last packets of the client, if the write loop still runs
+        let _ = self.send_writes().await;
+
+        tokio::spawn(
+            async move {
+                if let Err(err) = self.handle_clean_session().await {
+                    error!(
+                        "handle clean session [{}#{}]: {err}",
+                        err.category(),
+                        err.code()
+                    );
+                    self.global.metrics().record_error(err.category());
                 }
             }
-        } else {
-            loop {
-                tokio::select! {
-                    packet = self.reader.next() => match packet {
-                        Some(Ok(p)) => match self.handle_read_packet(&p).await {
-                            Ok(_) => continue,
-                            Err(err) => {
-                                warn!("handle read packet error: {err}");
-                                break;
-                            }
-                        },
-                        Some(Err(err)) => {
-                            error!("read form client failed: {err}");
-                            break;
-                        }
-                        None => {
-                            error!("reader closed");
-                            break;
-                        }
-                    },
-                    packet = self.deliver_rx.recv() => match packet {
-                        Ok(packet) => match self.handle_deliver_packet(packet).await {
-                            Ok(_) => continue,
-                            Err(err) => {
-                                warn!("handle deliver failed: {err}");
-                                break;
-                            }
-                        },
-                        Err(err) => {
-                            warn!("deliver receive channel: {err}");
-                            break;
-                        }
-                    },
-                    _ = tick.tick() => {
-                        match self.handle_pending_messages(false).await {
-                            Ok(_) => {},
-                            Err(_) => break,
-                        }
-                    },
+            .in_current_span(),
+        );
+    }
+
+    /// Handles a packet read from the client and the ones decoded from what is already buffered
+    /// behind it, false once the connection ends
+    async fn handle_read(
+        &mut self,
+        mut packet: Option<Result<VariablePacket, VariablePacketError>>,
+    ) -> bool {
+        for read in 1.. {
+            match packet {
+                Some(Ok(p)) => {
+                    if let Err(err) = self.handle_read_packet(&p).await {
+                        warn!(
+                            "handle read packet error [{}#{}]: {err}",
+                            err.category(),
+                            err.code()
+                        );
+                        self.record_error(&err);
+                        return false;
+                    }
+                }
+                // MQTT 3.1.1 has no DISCONNECT from the server, closing is all it gets
+                Some(Err(err)) if err.is_malformed() => {
+                    warn!(
+                        "client#{} sent a malformed packet: {}",
+                        self.session.client_id(),
+                        err.reason()
+                    );
+                    self.session.set_server_disconnected_for("malformed packet");
+                    return false;
+                }
+                Some(Err(err)) => {
+                    error!("read form client failed: {err}");
+                    return false;
+                }
+                None => {
+                    error!("reader closed");
+                    return false;
                 }
             }
+            if read == self.global.read_batch_size() {
+                // the other connections get a turn before the rest of the buffer
+                tokio::task::yield_now().await;
+                break;
+            }
+            // stops at the first packet which needs another read from the client
+            match self.reader.next().now_or_never() {
+                Some(next) => packet = next,
+                None => break,
+            }
+        }
+        true
+    }
+
+    /// Buffers a packet to the client, sent to the write loop with the others of this wakeup
+    async fn write(&mut self, packet: WritePacket) -> Result<(), Error> {
+        self.writes.push(packet);
+        if self.writes.len() >= WRITE_BATCH_SIZE {
+            // keeps the backpressure of the write channel on a long run of packets
+            self.send_writes().await?;
+        }
+        Ok(())
+    }
+
+    async fn send_writes(&mut self) -> Result<(), Error> {
+        let packet = match self.writes.len() {
+            0 => return Ok(()),
+            1 => self.writes.pop().expect("one buffered packet"),
+            _ => WritePacket::Batch(mem::take(&mut self.writes)),
         };
+        self.write_tx.send(packet).await?;
+        Ok(())
+    }
 
-        tokio::spawn(async move {
-            if let Err(err) = self.handle_clean_session().await {
-                error!("handle clean session: {err}");
-            }
-        });
+    /// Checks the backlog against the slow consumer limits, an evicted client's write loop is
+    /// stopped, so the connection closes without sending what is still buffered
+    async fn is_slow_consumer(&mut self) -> bool {
+        let config = self.global.slow_consumer();
+        if !config.checks_backlog() {
+            return false;
+        }
+        let Some(slow) = config
+            .check(
+                self.session.client_id(),
+                &self.deliver_queue,
+                &self.global.storage,
+            )
+            .await
+        else {
+            return false;
+        };
+        warn!(
+            "client#{} is a slow consumer: {slow}",
+            self.session.client_id()
+        );
+        self.global.metrics().record_slow_consumer_disconnect();
+        self.session.set_server_disconnected_for("slow consumer");
+        let _ = self.write_tx.close();
+        true
     }
 
     async fn handle_read_packet(&mut self, packet: &VariablePacket) -> Result<(), Error> {
         debug!(
-            r#"client#{} read packet: {:?}"#,
+            r#"client#{} read packet: {}"#,
             self.session.client_id(),
             packet,
         );
@@ -175,8 +293,7 @@ where
         self.session.renew_last_packet_at();
         match packet {
             VariablePacket::PingreqPacket(_packet) => {
-                self.write_tx
-                    .send(WritePacket::VariablePacket(PingrespPacket::new().into()))
+                self.write(WritePacket::VariablePacket(PingrespPacket::new().into()))
                     .await?;
             }
             VariablePacket::PublishPacket(packet) => self.handle_publish(packet).await?,
@@ -188,7 +305,7 @@ where
             VariablePacket::UnsubscribePacket(packet) => self.handle_unsubscribe(packet).await?,
             VariablePacket::DisconnectPacket(_packet) => self.handle_disconnect().await?,
             _ => {
-                debug!("invalid packet: {:?}", packet);
+                debug!("invalid packet: {}", packet);
                 return Err(Error::V4InvalidPacket);
             }
         };
@@ -196,41 +313,37 @@ where
         Ok(())
     }
 
+    /// Queues the messages which were not completed before the client reconnected, they are
+    /// sent ahead of any new message
+    async fn load_pending_messages(&mut self) -> Result<(), Error> {
+        self.delivery
+            .load_pending(&self.session, &self.global)
+            .await
+            .map_err(Error::Storage)?;
+        self.drain_messages().await
+    }
+
+    /// Sends pending messages, then queued messages, as long as the inflight window has room
+    async fn drain_messages(&mut self) -> Result<(), Error> {
+        let packets = self
+            .delivery
+            .drain(&mut self.session, &self.deliver_queue, &self.global)
+            .await;
+        for packet in packets {
+            self.write(packet).await?;
+        }
+        Ok(())
+    }
+
     async fn handle_deliver_packet(&mut self, packet: DeliverMessage) -> Result<(), Error> {
         match packet {
-            DeliverMessage::Publish(topic_filter, subscribe_qos, packet) => {
-                debug!(
-                    r#"""client#{} receive deliver packet:
-                         topic filter : {:?},
-                        subscribe qos : {:?},
-                               packet : {:?}"""#,
-                    self.session.client_id(),
-                    topic_filter,
-                    subscribe_qos,
-                    packet,
-                );
-                if !self.session.subscriptions().contains(&topic_filter) {
-                    return Err(Error::Topic(topic_filter.to_string()));
-                }
-                let final_qos = cmp::min(packet.qos(), subscribe_qos);
-                let qos = match final_qos {
-                    QualityOfService::Level0 => QoSWithPacketIdentifier::Level0,
-                    QualityOfService::Level1 => {
-                        QoSWithPacketIdentifier::Level1(self.session.incr_server_packet_id())
-                    }
-                    QualityOfService::Level2 => {
-                        QoSWithPacketIdentifier::Level2(self.session.incr_server_packet_id())
-                    }
-                };
-                self.write_tx
-                    .send(WritePacket::PendingMessage(PendingPublishMessage::new(
-                        qos, *packet,
-                    )))
-                    .await?;
-                Ok(())
-            }
             DeliverMessage::Online(sender) => {
                 debug!("client#{} receive online message", self.session.client_id(),);
+                // the new connection resends the unacknowledged messages from the store
+                self.delivery
+                    .spill_all(&self.session, &self.global)
+                    .await
+                    .map_err(Error::Storage)?;
                 if let Err(err) = sender
                     .send(ProtocolSessionState::V4(self.session.build_state()))
                     .await
@@ -250,21 +363,43 @@ where
                     self.session.client_id(),
                     reason,
                 );
+                self.audit_kick(&reason);
+                self.session.set_kicked();
                 self.remove_client().await?;
+                // the write loop would outlive the read loop with a persistent session, so the
+                // connection is closed here whether the session is kept or not
+                let _ = self.write_tx.close();
                 Err(Error::Kick(self.session.client_id().to_string()))
             }
+            DeliverMessage::Timeout(TimerKind::KeepAlive, token) if token == self.timer_token => {
+                let keep_alive_timeout = self.session.keep_alive_timeout();
+                let elapsed = self.session.last_packet_at().elapsed();
+                if elapsed > keep_alive_timeout {
+                    return Err(Error::KeepAliveTimeout(
+                        self.session.client_id().to_string(),
+                    ));
+                }
+                self.global.schedule_timer(
+                    self.session.client_id(),
+                    token,
+                    TimerKind::KeepAlive,
+                    keep_alive_timeout - elapsed,
+                );
+                Ok(())
+            }
+            DeliverMessage::Timeout(..) => Ok(()),
         }
     }
 
-    async fn handle_publish(&self, packet: &PublishPacket) -> Result<(), Error> {
+    async fn handle_publish(&mut self, packet: &PublishPacket) -> Result<(), Error> {
         debug!(
             r#"client#{} received a publish packet:
                 topic name : {:?}
-                   payload : {:?}
+                   payload : [{} bytes]
                      flags : qos={:?}, retain={}, dup={}"#,
             self.session.client_id(),
             packet.topic_name(),
-            packet.payload(),
+            packet.payload().len(),
             packet.qos(),
             packet.retain(),
             packet.dup(),
@@ -273,13 +408,13 @@ where
         let topic_name = packet.topic_name();
         if topic_name.is_empty() {
             debug!("Publish topic name cannot be empty");
-            self.write_tx
-                .send(WritePacket::VariablePacket(DisconnectPacket::new().into()))
+            self.write(WritePacket::VariablePacket(DisconnectPacket::new().into()))
                 .await?;
             return Ok(());
         }
 
-        if topic_name.starts_with(SHARED_PREFIX)
+        // MQTT 3.1.1 has no DISCONNECT from the server, closing is all it gets
+        if self.global.is_reserved_topic(topic_name)
             || topic_name.contains(MATCH_ALL_STR)
             || topic_name.contains(MATCH_ONE_STR)
         {
@@ -288,48 +423,88 @@ where
                 self.session.client_id(),
                 topic_name
             );
-            self.write_tx
-                .send(WritePacket::VariablePacket(DisconnectPacket::new().into()))
-                .await?;
-            return Ok(());
+            self.session
+                .set_server_disconnected_for("invalid topic name");
+            return Err(Error::V4InvalidPacket);
+        }
+        if let Err(err) = topic_name.validate_strict() {
+            debug!(
+                "client#{} invalid topic name {:?}: {err}",
+                self.session.client_id(),
+                topic_name
+            );
+            self.session
+                .set_server_disconnected_for("invalid topic name");
+            return Err(Error::V4InvalidPacket);
         }
         if packet.qos() == QoSWithPacketIdentifier::Level0 && packet.dup() {
             debug!(
                 "client#{} invalid duplicate flag in QoS 0 publish message",
                 self.session.client_id()
             );
-            self.write_tx
-                .send(WritePacket::VariablePacket(DisconnectPacket::new().into()))
+            self.write(WritePacket::VariablePacket(DisconnectPacket::new().into()))
                 .await?;
             return Ok(());
         }
 
+        let mut message: PublishMessage = packet.into();
+        // MQTT 3.1.1 has no way to refuse a publish, a too large one is acknowledged and dropped
+        let allowed = match self.global.max_payload_size() {
+            Some(max) if packet.payload().len() > max => {
+                debug!(
+                    "client#{} publish dropped, payload of {} bytes exceeds {max}",
+                    self.session.client_id(),
+                    packet.payload().len()
+                );
+                false
+            }
+            _ => self
+                .global
+                .authorize_publish(self.session.client_id(), &mut message)
+                .await
+                .is_accepted(),
+        };
+
         match packet.qos() {
             QoSWithPacketIdentifier::Level0 => {
-                self.deliver_publish_message(&packet.into()).await?;
+                if allowed {
+                    self.deliver_publish_message(&message).await?;
+                }
             }
             QoSWithPacketIdentifier::Level1(packet_id) => {
-                if !packet.dup() {
-                    self.deliver_publish_message(&packet.into()).await?;
+                if allowed && !packet.dup() {
+                    self.deliver_publish_message(&message).await?;
                 }
-                self.write_tx
-                    .send(WritePacket::VariablePacket(
-                        PubackPacket::new(packet_id).into(),
-                    ))
-                    .await?;
+                self.write(WritePacket::VariablePacket(
+                    PubackPacket::new(packet_id).into(),
+                ))
+                .await?;
             }
             QoSWithPacketIdentifier::Level2(packet_id) => {
-                if !packet.dup() {
-                    self.global
+                if allowed {
+                    // the DUP flag is not trusted, a resend is recognized by its packet id
+                    match self
+                        .global
                         .storage
-                        .save_publish_message(self.session.client_id(), packet_id, packet.into())
-                        .await?;
+                        .save_publish_message(self.session.client_id(), packet_id, message)
+                        .await
+                        .map_err(Error::Storage)?
+                    {
+                        ReceiveOutcome::Received => {}
+                        ReceiveOutcome::Duplicate => debug!(
+                            "client#{} resent publish {packet_id}, waiting for pubrel",
+                            self.session.client_id()
+                        ),
+                        ReceiveOutcome::Full => warn!(
+                            "client#{} publish {packet_id} dropped, too many unreleased messages",
+                            self.session.client_id()
+                        ),
+                    }
                 }
-                self.write_tx
-                    .send(WritePacket::VariablePacket(
-                        PubrecPacket::new(packet_id).into(),
-                    ))
-                    .await?;
+                self.write(WritePacket::VariablePacket(
+                    PubrecPacket::new(packet_id).into(),
+                ))
+                .await?;
             }
         }
         Ok(())
@@ -339,11 +514,11 @@ where
         debug!(
             r#"client#{} deliver publish message:
                 topic name : {:?}
-                   payload : {:?}
+                   payload : [{} bytes]
                      flags : qos={:?}, retain={}, dup={}"#,
             self.session.client_id(),
             packet.topic_name(),
-            packet.payload(),
+            packet.payload().len(),
             packet.qos(),
             packet.retain(),
             packet.dup(),
@@ -351,90 +526,65 @@ where
 
         if packet.retain() {
             if packet.payload().is_empty() {
-                self.global.storage.remove(packet.topic_name()).await?;
+                self.global
+                    .storage
+                    .remove(packet.topic_name())
+                    .await
+                    .map_err(Error::Storage)?;
             } else {
                 self.global
                     .storage
                     .insert((self.session.client_id(), packet).into())
-                    .await?;
+                    .await
+                    .map_err(Error::Storage)?;
             }
         }
 
-        let subscribes = self.global.storage.match_topic(packet.topic_name()).await?;
-        for topic_content in subscribes {
-            let topic_filter = if let Some(topic_filter) = topic_content.topic_filter {
-                match TopicFilter::new(topic_filter) {
-                    Ok(filter) => filter,
-                    Err(err) => {
-                        error!("deliver publish message new topic filter: {err}");
-                        continue;
-                    }
-                }
-            } else {
-                continue;
-            };
-            for (client_id, subscribe_qos) in topic_content.clients {
-                if let Some(sender) = self.global.get_deliver(&client_id) {
-                    if sender.is_closed() {
-                        warn!("client#{:?} deliver channel is closed", client_id,);
-                        continue;
-                    }
-                    if let Err(err) = sender
-                        .send(DeliverMessage::Publish(
-                            topic_filter.clone(),
-                            subscribe_qos,
-                            Box::new(packet.clone()),
-                        ))
-                        .await
-                    {
-                        error!("{} send publish: {}", client_id, err,)
-                    }
-                }
-            }
-        }
+        let subscribes = self
+            .global
+            .storage
+            .match_topic(packet.topic_name())
+            .await
+            .map_err(Error::Storage)?;
+        self.global
+            .fan_out(self.global.deliveries(subscribes), packet.clone())
+            .await;
 
         Ok(())
     }
 
-    async fn handle_pubrel(&self, packet: &PubrelPacket) -> Result<(), Error> {
+    async fn handle_pubrel(&mut self, packet: &PubrelPacket) -> Result<(), Error> {
         debug!(
             "client#{} received a pubrel packet, id : {}",
             self.session.client_id(),
             packet.packet_identifier()
         );
 
-        if let Some(msg) = self
-            .global
-            .storage
-            .pubrel(self.session.client_id(), packet.packet_identifier())
-            .await?
-        {
-            self.deliver_publish_message(&msg).await?;
-        }
-        self.write_tx
-            .send(WritePacket::VariablePacket(
-                PubcompPacket::new(packet.packet_identifier()).into(),
-            ))
-            .await?;
-        Ok(())
+        complete_qos2(self, packet.packet_identifier()).await
     }
 
-    async fn handle_puback(&self, packet: &PubackPacket) -> Result<(), Error> {
+    async fn handle_puback(&mut self, packet: &PubackPacket) -> Result<(), Error> {
         debug!(
             "client#{} received a puback packet, id : {}",
             self.session.client_id(),
             packet.packet_identifier()
         );
 
-        self.global
-            .storage
-            .puback(self.session.client_id(), packet.packet_identifier())
-            .await?;
+        if !self.delivery.complete_unsaved(packet.packet_identifier()) {
+            self.global
+                .storage
+                .puback(self.session.client_id(), packet.packet_identifier())
+                .await
+                .map_err(Error::Storage)?;
+        }
+        if self.delivery.complete(packet.packet_identifier()) {
+            self.drain_messages().await?;
+        }
 
         Ok(())
     }
 
-    async fn handle_pubrec(&self, packet: &PubrecPacket) -> Result<(), Error> {
+    async fn handle_pubrec(&mut self, packet: &PubrecPacket) -> Result<(), Error> {
         debug!(
             "client#{} received a pubrec packet, id : {}",
             self.session.client_id(),
@@ -444,16 +594,16 @@ where
         self.global
             .storage
             .pubrec(self.session.client_id(), packet.packet_identifier())
-            .await?;
-        self.write_tx
-            .send(WritePacket::VariablePacket(
-                PubrelPacket::new(packet.packet_identifier()).into(),
-            ))
-            .await?;
+            .await
+            .map_err(Error::Storage)?;
+        self.write(WritePacket::VariablePacket(
+            PubrelPacket::new(packet.packet_identifier()).into(),
+        ))
+        .await?;
         Ok(())
     }
 
-    async fn handle_pubcomp(&self, packet: &PubcompPacket) -> Result<(), Error> {
+    async fn handle_pubcomp(&mut self, packet: &PubcompPacket) -> Result<(), Error> {
         debug!(
             "client#{} received a pubcomp packet, id : {}",
             self.session.client_id(),
@@ -463,7 +613,11 @@ where
         self.global
             .storage
             .pubcomp(self.session.client_id(), packet.packet_identifier())
-            .await?;
+            .await
+            .map_err(Error::Storage)?;
+        if self.delivery.complete(packet.packet_identifier()) {
+            self.drain_messages().await?;
+        }
 
         Ok(())
     }
@@ -481,7 +635,15 @@ where
         );
 
         if let Some(last_will) = self.session.take_last_will() {
-            self.deliver_publish_message(&last_will.into()).await?;
+            let mut message: PublishMessage = last_will.into();
+            if self
+                .global
+                .authorize_publish(self.session.client_id(), &mut message)
+                .await
+                .is_accepted()
+            {
+                self.deliver_publish_message(&message).await?;
+            }
         }
         Ok(())
     }
@@ -499,51 +661,101 @@ where
             return Err(Error::EmptySubscribes);
         }
         let mut return_codes = Vec::with_capacity(packet.subscribes().len());
-        let mut pkts = Vec::new();
+        let mut granted = Vec::new();
         for (filter, subscribe_qos) in packet.subscribes() {
-            if filter.is_shared() {
-                warn!("mqtt v3.x don't support shared subscription");
-                return_codes.push(SubscribeReturnCode::Failure);
-                continue;
-            }
+            let return_code = match self.subscribe(filter, *subscribe_qos).await? {
+                Some(granted_qos) => {
+                    granted.push((filter, granted_qos));
+                    granted_qos.into()
+                }
+                None => SubscribeReturnCode::Failure,
+            };
+            self.global.audit(
+                self.session.remote_addr(),
+                AuditEvent::Subscribe {
+                    client_id: self.session.client_id(),
+                    topic_filter: filter,
+                    qos: *subscribe_qos as u8,
+                    granted: return_code != SubscribeReturnCode::Failure,
+                },
+            );
+            return_codes.push(return_code);
+        }
+        if !self.session.clean_session() {
+            self.global.save_session(self.session.to_stored()).await;
+        }
+        self.write(WritePacket::VariablePacket(
+            SubackPacket::new(packet.packet_identifier(), return_codes).into(),
+        ))
+        .await?;
+        for (filter, granted_qos) in granted {
+            queue_retained(
+                self.session.client_id(),
+                filter,
+                granted_qos,
+                false,
+                &self.deliver_queue,
+                &self.global,
+            )
+            .await
+            .map_err(Error::Storage)?;
+        }
+        self.drain_messages().await
+    }
 
-            // TODO: granted max qos from config
-            let granted_qos = subscribe_qos.to_owned();
-            self.global
-                .storage
-                .subscribe(self.session.client_id(), filter, granted_qos)
-                .await?;
-            self.session.subscribe(filter.clone());
-            let retain_messages =
-                RetainMessageStore::search(self.global.storage.as_ref(), filter).await?;
-            for msg in retain_messages {
-                let qos = match granted_qos {
-                    QualityOfService::Level0 => QoSWithPacketIdentifier::Level0,
-                    QualityOfService::Level1 => {
-                        QoSWithPacketIdentifier::Level1(self.session.incr_server_packet_id())
-                    }
-                    QualityOfService::Level2 => {
-                        QoSWithPacketIdentifier::Level2(self.session.incr_server_packet_id())
-                    }
-                };
-                let mut received_publish: PublishMessage = msg.into();
-                received_publish.set_retain(true);
+    /// Subscribes the session to one filter of a SUBSCRIBE, returns the granted QoS or `None`
+    /// when the subscription is refused
+    async fn subscribe(
+        &mut self,
+        filter: &TopicFilter,
+        subscribe_qos: QualityOfService,
+    ) -> Result<Option<QualityOfService>, Error> {
+        if filter.is_shared() {
+            warn!("mqtt v3.x don't support shared subscription");
+            return Ok(None);
+        }
 
-                let pending_message = PendingPublishMessage::new(qos, received_publish);
-                pkts.push(WritePacket::PendingMessage(pending_message));
-            }
+        if let Err(reason) = self.global.topic_filter_config().validate(filter) {
+            debug!(
+                "client#{} invalid topic filter {:?}: {reason}",
+                self.session.client_id(),
+                filter
+            );
+            return Ok(None);
+        }
 
-            return_codes.push(granted_qos.into());
+        if !self
+            .global
+            .allow_subscribe(self.session.client_id(), filter, subscribe_qos)
+            .await
+        {
+            debug!(
+                "client#{} subscribe to {} denied",
+                self.session.client_id(),
+                filter
+            );
+            return Ok(None);
         }
-        self.write_tx
-            .send(WritePacket::VariablePacket(
-                SubackPacket::new(packet.packet_identifier(), return_codes).into(),
-            ))
-            .await?;
-        for pkt in pkts {
-            self.write_tx.send(pkt).await?;
+
+        // TODO: granted max qos from config
+        let granted_qos = subscribe_qos.to_owned();
+        self.global
+            .storage
+            .subscribe(
+                self.session.client_id(),
+                filter,
+                SubscriptionOptions::V4(granted_qos),
+            )
+            .await
+            .map_err(Error::Storage)?;
+        if let Some(previous) = self.session.subscribe(filter.clone(), granted_qos) {
+            debug!(
+                "client#{} replaced its subscription to {filter} with QoS {previous:?}",
+                self.session.client_id(),
+            );
         }
-        Ok(())
+
+        Ok(Some(granted_qos))
     }
 
     async fn handle_unsubscribe(&mut self, packet: &UnsubscribePacket) -> Result<(), Error> {
@@ -560,13 +772,16 @@ where
             self.global
                 .storage
                 .unsubscribe(self.session.client_id(), filter)
-                .await?;
+                .await
+                .map_err(Error::Storage)?;
+        }
+        if !self.session.clean_session() {
+            self.global.save_session(self.session.to_stored()).await;
         }
-        self.write_tx
-            .send(WritePacket::VariablePacket(
-                UnsubackPacket::new(packet.packet_identifier()).into(),
-            ))
-            .await?;
+        self.write(WritePacket::VariablePacket(
+            UnsubackPacket::new(packet.packet_identifier()).into(),
+        ))
+        .await?;
         Ok(())
     }
 
@@ -581,19 +796,42 @@ where
         Err(Error::Disconnect)
     }
 
+    /// Counts an error which ends the connection in the metrics and keeps its code for the
+    /// audit log, a DISCONNECT of the client is not an error
+    fn record_error(&mut self, err: &Error) {
+        if matches!(err, Error::Disconnect) {
+            return;
+        }
+        self.global.metrics().record_error(err.category());
+        self.session.set_error_code(err.code());
+    }
+
+    fn audit_kick(&self, reason: &KickReason) {
+        self.global.audit(
+            self.session.remote_addr(),
+            AuditEvent::Kick {
+                client_id: self.session.client_id(),
+                reason: &reason.to_string(),
+            },
+        );
+    }
+
+    /// Removes a clean session with its subscriptions and messages, a persistent one is kept
     async fn remove_client(&self) -> Result<(), Error> {
         if self.session.clean_session() {
             self.global.remove_client(self.session.client_id());
-            for topic_filter in self.session.subscriptions() {
+            for topic_filter in self.session.subscriptions().keys() {
                 self.global
                     .storage
                     .unsubscribe(self.session.client_id(), topic_filter)
-                    .await?;
+                    .await
+                    .map_err(Error::Storage)?;
             }
             self.global
                 .storage
                 .clear_all(self.session.client_id())
-                .await?;
+                .await
+                .map_err(Error::Storage)?;
         }
         Ok(())
     }
@@ -610,8 +848,25 @@ where
         if !self.session.disconnected() {
             self.session.set_server_disconnected();
         }
+        let event = AuditEvent::Disconnect {
+            client_id: self.session.client_id(),
+            reason: if self.session.client_disconnected() {
+                "client disconnected"
+            } else {
+                self.session
+                    .disconnect_reason()
+                    .unwrap_or("connection closed")
+            },
+            code: self.session.error_code(),
+        };
+        self.global.audit(self.session.remote_addr(), event);
+        self.global
+            .presence_changed(self.session.remote_addr(), event)
+            .await;
 
-        if !self.session.client_disconnected() {
+        if self.session.kicked() && !self.global.will_on_kick() {
+            self.session.clear_last_will();
+        } else if !self.session.client_disconnected() {
             self.handle_will().await?;
         }
 
@@ -619,107 +874,95 @@ where
             self.remove_client().await?;
             return Ok(());
         }
-
-        while let Ok(packet) = self.deliver_rx.recv().await {
-            match packet {
-                DeliverMessage::Publish(topic_filter, subscribe_qos, packet) => {
-                    debug!(
-                        r#"""client#{} receive deliver packet:
-                                 topic filter : {:?},
-                                subscribe qos : {:?},
-                                       packet : {:?}"""#,
-                        self.session.client_id(),
-                        topic_filter,
-                        subscribe_qos,
-                        packet,
-                    );
-                    if !self.session.subscriptions().contains(&topic_filter) {
-                        continue;
-                    }
-                    let final_qos = cmp::min(packet.qos(), subscribe_qos);
-                    let (packet_id, qos) = match final_qos {
-                        QualityOfService::Level0 => continue,
-                        QualityOfService::Level1 => {
-                            let packet_id = self.session.incr_server_packet_id();
-                            (packet_id, QoSWithPacketIdentifier::Level1(packet_id))
-                        }
-                        QualityOfService::Level2 => {
-                            let packet_id = self.session.incr_server_packet_id();
-                            (packet_id, QoSWithPacketIdentifier::Level1(packet_id))
+        self.delivery
+            .spill_all(&self.session, &self.global)
+            .await
+            .map_err(Error::Storage)?;
+        self.global
+            .set_client_offline(self.session.client_id(), self.timer_token);
+        self.global.save_session(self.session.to_stored()).await;
+
+        loop {
+            tokio::select! {
+                packet = self.deliver_rx.recv() => match packet {
+                    Ok(DeliverMessage::Online(sender)) => {
+                        debug!("client#{} receive online message", self.session.client_id(),);
+                        if let Err(err) = sender
+                            .send(ProtocolSessionState::V4(self.session.build_state()))
+                            .await
+                        {
+                            error!(
+                                "client#{} send session state: {err}",
+                                self.session.client_id(),
+                            );
                         }
-                    };
 
-                    let message = PendingPublishMessage::new(qos, *packet);
-                    self.global
-                        .storage
-                        .save_pending_publish_message(self.session.client_id(), packet_id, message)
-                        .await?;
-                }
-                DeliverMessage::Online(sender) => {
-                    debug!("client#{} receive online message", self.session.client_id(),);
-                    if let Err(err) = sender
-                        .send(ProtocolSessionState::V4(self.session.build_state()))
-                        .await
-                    {
-                        error!(
-                            "client#{} send session state: {err}",
+                        self.remove_client().await?;
+                        break;
+                    }
+                    Ok(DeliverMessage::Kick(reason)) => {
+                        debug!(
+                            "client#{} receive kick message: {}",
                             self.session.client_id(),
+                            reason,
                         );
+                        // the session is already offline, it is kept like a connected one
+                        self.audit_kick(&reason);
                     }
-
-                    self.remove_client().await?;
-                    break;
-                }
-                DeliverMessage::Kick(reason) => {
-                    debug!(
-                        "client#{} receive kick message: {}",
-                        self.session.client_id(),
-                        reason,
-                    );
-                    self.remove_client().await?;
-                    break;
-                }
+                    Ok(DeliverMessage::Timeout(..)) => {}
+                    Err(_) => break,
+                },
+                _ = self.deliver_queue.notified() => {
+                    for queued in self.deliver_queue.pop_batch(DELIVER_BATCH_SIZE) {
+                        DeliveryCore::store_offline(&mut self.session, queued, &self.global)
+                            .await
+                            .map_err(Error::Storage)?;
+                    }
+                },
             }
         }
         Ok(())
     }
 
-    async fn handle_pending_messages(&mut self, all: bool) -> Result<(), Error> {
-        let ret = if all {
-            self.global
-                .storage
-                .get_all_pending_messages(self.session.client_id())
-                .await
-        } else {
-            self.global
-                .storage
-                .try_get_pending_messages(self.session.client_id())
-                .await
-        };
-
-        match ret {
-            Ok(Some(messages)) => {
-                for (packet_id, pending_message) in messages {
-                    match pending_message.pubrec_at() {
-                        Some(_) => {
-                            self.write_tx
-                                .send(WritePacket::VariablePacket(
-                                    PubrelPacket::new(packet_id).into(),
-                                ))
-                                .await?;
-                        }
-                        None => {
-                            let pkt: PublishPacket = pending_message.into();
-                            self.write_tx
-                                .send(WritePacket::VariablePacket(pkt.into()))
-                                .await?;
-                        }
-                    }
-                }
-                Ok(())
-            }
-            Ok(None) => Ok(()),
-            Err(err) => Err(Error::Io(err)),
+    /// Resends the inflight messages which were not acknowledged in time
+    async fn handle_pending_messages(&mut self) -> Result<(), Error> {
+        let packets = self
+            .delivery
+            .retry(&self.session, &self.global)
+            .await
+            .map_err(Error::Storage)?;
+        for packet in packets {
+            self.write(packet).await?;
         }
+        Ok(())
+    }
+}
+
+impl<T, D, S> Qos2Completion for ReadLoop<T, D, S>
+where
+    T: AsyncRead + Unpin + Send + Sync + 'static,
+    D: Decoder<Item = VariablePacket, Error = VariablePacketError> + Send + Sync + 'static,
+    S: MessageStore + RetainMessageStore + TopicStore,
+{
+    type Error = Error;
+
+    async fn release(&mut self, packet_id: u16) -> Result<Option<PublishMessage>, Error> {
+        self.global
+            .storage
+            .pubrel(self.session.client_id(), packet_id)
+            .await
+            .map_err(Error::Storage)
+    }
+
+    async fn send_pubcomp(&mut self, packet_id: u16) -> Result<(), Error> {
+        self.write(WritePacket::VariablePacket(
+            PubcompPacket::new(packet_id).into(),
+        ))
+        .await?;
+        Ok(())
+    }
+
+    async fn forward(&mut self, message: PublishMessage) -> Result<(), Error> {
+        self.deliver_publish_message(&message).await
     }
 }
@@ -1,9 +1,23 @@
-use std::{fmt, mem};
+use std::{fmt, mem, net::SocketAddr, sync::Arc, time::Duration};
 
-use foldhash::{HashSet, HashSetExt};
-use mqtt_codec_kit::{common::TopicFilter, v4::packet::connect::LastWill};
+use foldhash::{HashMap, HashMapExt};
+use mqtt_codec_kit::{
+    common::{qos::QoSWithPacketIdentifier, QualityOfService, TopicFilter},
+    v4::packet::{connect::LastWill, PublishPacket, PubrelPacket},
+};
 use tokio::time::Instant;
 
+use crate::{
+    protocols::delivery::ProtocolAdapter,
+    server::client_info::ClientInfo,
+    store::{
+        message::{PendingPublishMessage, PublishMessage},
+        session::{StoredSession, StoredSubscription, StoredWill},
+    },
+};
+
+use super::WritePacket;
+
 #[derive(Clone)]
 pub struct Session {
     connected_at: Instant,
@@ -11,16 +25,22 @@ pub struct Session {
     last_packet_at: Instant,
     // For record packet id send from server to client
     server_packet_id: u16,
+    remote_addr: Option<SocketAddr>,
+    info: Option<Arc<ClientInfo>>,
 
     client_id: String,
     username: Option<String>,
     keep_alive: u16,
     clean_session: bool,
     last_will: Option<LastWill>,
-    subscriptions: HashSet<TopicFilter>,
+    subscriptions: HashMap<TopicFilter, QualityOfService>,
 
     client_disconnected: bool,
     server_disconnected: bool,
+    disconnect_reason: Option<&'static str>,
+    // code of the error which ended the connection, see `protocols::Error::code`
+    error_code: Option<u16>,
+    kicked: bool,
 }
 
 impl Session {
@@ -29,16 +49,21 @@ impl Session {
             connected_at: Instant::now(),
             last_packet_at: Instant::now(),
             server_packet_id: 1,
+            remote_addr: None,
+            info: None,
 
             client_id: client_id.to_string(),
             username: None,
             keep_alive: 0,
             clean_session: true,
             last_will: None,
-            subscriptions: HashSet::new(),
+            subscriptions: HashMap::new(),
 
             client_disconnected: false,
             server_disconnected: false,
+            disconnect_reason: None,
+            error_code: None,
+            kicked: false,
         }
     }
 
@@ -48,12 +73,40 @@ impl Session {
 
     pub fn renew_last_packet_at(&mut self) {
         self.last_packet_at = Instant::now();
+        if let Some(info) = &self.info {
+            info.touch();
+        }
     }
 
     pub fn client_id(&self) -> &str {
         &self.client_id
     }
 
+    pub fn set_client_id(&mut self, client_id: String) {
+        self.client_id = client_id;
+    }
+
+    pub fn remote_addr(&self) -> Option<SocketAddr> {
+        self.remote_addr
+    }
+
+    pub fn set_remote_addr(&mut self, remote_addr: Option<SocketAddr>) {
+        self.remote_addr = remote_addr;
+    }
+
+    pub fn info(&self) -> Option<&Arc<ClientInfo>> {
+        self.info.as_ref()
+    }
+
+    pub fn set_info(&mut self, info: Arc<ClientInfo>) {
+        info.set_will(self.stored_will());
+        self.info = Some(info);
+    }
+
+    pub fn username(&self) -> Option<&str> {
+        self.username.as_deref()
+    }
+
     pub fn set_username(&mut self, username: Option<String>) {
         self.username = username
     }
@@ -62,6 +115,12 @@ impl Session {
         self.keep_alive
     }
 
+    /// Time without any packet from the client after which it is disconnected, one and a half
+    /// times the keep alive
+    pub fn keep_alive_timeout(&self) -> Duration {
+        Duration::from_millis(self.keep_alive as u64 * 1500)
+    }
+
     pub fn set_keep_alive(&mut self, keep_alive: u16) {
         // TODO: config: max keep alive?
         // TODO: config: min keep alive?
@@ -102,46 +161,104 @@ impl Session {
         self.server_disconnected = true
     }
 
+    /// Marks the session closed by the broker for `reason`, which goes to the audit log
+    pub fn set_server_disconnected_for(&mut self, reason: &'static str) {
+        self.server_disconnected = true;
+        self.disconnect_reason = Some(reason);
+    }
+
+    pub fn disconnect_reason(&self) -> Option<&'static str> {
+        self.disconnect_reason
+    }
+
+    pub fn error_code(&self) -> Option<u16> {
+        self.error_code
+    }
+
+    /// Keeps the code of the error which ended the connection for the audit log
+    pub fn set_error_code(&mut self, code: u16) {
+        self.error_code = Some(code);
+    }
+
+    /// Whether the connection was closed by [`GlobalState::kick_client`]
+    ///
+    /// [`GlobalState::kick_client`]: crate::server::state::GlobalState::kick_client
+    pub fn kicked(&self) -> bool {
+        self.kicked
+    }
+
+    pub fn set_kicked(&mut self) {
+        self.set_server_disconnected_for("kicked by admin");
+        self.kicked = true;
+    }
+
     pub fn last_will(&self) -> Option<&LastWill> {
         self.last_will.as_ref()
     }
 
     pub fn clear_last_will(&mut self) {
-        self.last_will = None
+        self.last_will = None;
+        self.sync_will();
     }
 
     pub fn take_last_will(&mut self) -> Option<LastWill> {
-        self.last_will.take()
+        let last_will = self.last_will.take();
+        self.sync_will();
+        last_will
     }
 
     pub fn set_last_will(&mut self, last_will: LastWill) {
         self.last_will = Some(last_will);
+        self.sync_will();
+    }
+
+    fn stored_will(&self) -> Option<StoredWill> {
+        self.last_will.as_ref().map(|will| StoredWill {
+            topic_name: will.topic().to_string(),
+            payload: will.message().0.clone(),
+            qos: will.qos() as u8,
+            retain: will.retain(),
+        })
+    }
+
+    // the will shown in the client info follows the one of the session
+    fn sync_will(&self) {
+        if let Some(info) = &self.info {
+            info.set_will(self.stored_will());
+        }
     }
 
     pub fn set_clean_session(&mut self, clean_session: bool) {
         self.clean_session = clean_session;
     }
 
-    pub fn subscriptions(&self) -> &HashSet<TopicFilter> {
+    pub fn subscriptions(&self) -> &HashMap<TopicFilter, QualityOfService> {
         &self.subscriptions
     }
 
-    pub fn subscribe(&mut self, topic: TopicFilter) -> bool {
-        self.subscriptions.insert(topic)
+    /// Returns the QoS of the subscription replaced, if the client was subscribed already
+    pub fn subscribe(
+        &mut self,
+        topic: TopicFilter,
+        qos: QualityOfService,
+    ) -> Option<QualityOfService> {
+        self.subscriptions.insert(topic, qos)
     }
 
     pub fn unsubscribe(&mut self, topic: &TopicFilter) -> bool {
-        self.subscriptions.remove(topic)
+        self.subscriptions.remove(topic).is_some()
     }
 
+    /// Packet identifiers go from 1 to 65535 and start over, the ones still held by a pending
+    /// message are skipped by [`free_packet_id`](crate::protocols::common::free_packet_id)
     pub fn incr_server_packet_id(&mut self) -> u16 {
-        let old_value = self.server_packet_id;
-        self.server_packet_id += 1;
+        let old_value = self.server_packet_id.max(1);
+        self.server_packet_id = old_value.checked_add(1).unwrap_or(1);
         old_value
     }
 
     pub fn build_state(&mut self) -> SessionState {
-        let mut subscriptions = HashSet::new();
+        let mut subscriptions = HashMap::new();
         mem::swap(&mut self.subscriptions, &mut subscriptions);
 
         SessionState {
@@ -154,6 +271,63 @@ impl Session {
         self.server_packet_id = state.server_packet_id;
         self.subscriptions = state.subscriptions;
     }
+
+    pub fn to_stored(&self) -> StoredSession {
+        StoredSession {
+            client_id: self.client_id.clone(),
+            server_packet_id: self.server_packet_id,
+            subscriptions: self
+                .subscriptions
+                .iter()
+                .map(|(filter, qos)| StoredSubscription::new(filter, &(*qos).into()))
+                .collect(),
+            will: self.stored_will(),
+            expire_at: None,
+        }
+    }
+
+    /// Restores a session persisted by an earlier broker run
+    pub fn restore(&mut self, stored: &StoredSession) {
+        self.server_packet_id = stored.server_packet_id;
+        self.subscriptions = stored
+            .subscriptions()
+            .filter_map(|(filter, options)| {
+                TopicFilter::new(filter)
+                    .ok()
+                    .map(|filter| (filter, options.qos()))
+            })
+            .collect();
+    }
+}
+
+impl ProtocolAdapter for Session {
+    type Packet = WritePacket;
+
+    fn client_id(&self) -> &str {
+        &self.client_id
+    }
+
+    fn is_subscribed(&self, topic_filter: &TopicFilter) -> bool {
+        self.subscriptions.contains_key(topic_filter)
+    }
+
+    fn next_packet_id(&mut self) -> u16 {
+        self.incr_server_packet_id()
+    }
+
+    fn publish(&self, qos: QoSWithPacketIdentifier, message: PublishMessage) -> WritePacket {
+        WritePacket::VariablePacket(
+            PublishPacket::from(PendingPublishMessage::new(qos, message)).into(),
+        )
+    }
+
+    fn resend(&self, packet_id: u16, pending: PendingPublishMessage) -> WritePacket {
+        let packet = match pending.pubrec_at() {
+            Some(_) => PubrelPacket::new(packet_id).into(),
+            None => PublishPacket::from(pending).into(),
+        };
+        WritePacket::VariablePacket(packet)
+    }
 }
 
 impl fmt::Display for Session {
@@ -171,11 +345,11 @@ impl fmt::Display for Session {
 
 pub struct SessionState {
     server_packet_id: u16,
-    subscriptions: HashSet<TopicFilter>,
+    subscriptions: HashMap<TopicFilter, QualityOfService>,
 }
 
 impl SessionState {
-    pub fn subscriptions(&self) -> &HashSet<TopicFilter> {
+    pub fn subscriptions(&self) -> &HashMap<TopicFilter, QualityOfService> {
         &self.subscriptions
     }
 }
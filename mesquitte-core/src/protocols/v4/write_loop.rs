@@ -1,16 +1,14 @@
-use std::io;
+use std::{io, sync::Arc, time::Duration};
 
 use futures::SinkExt as _;
 use kanal::AsyncReceiver;
-use mqtt_codec_kit::{
-    common::qos::QoSWithPacketIdentifier,
-    v4::packet::{PublishPacket, VariablePacket},
-};
-use tokio::io::AsyncWrite;
+use mqtt_codec_kit::v4::packet::VariablePacket;
+use tokio::{io::AsyncWrite, time};
 use tokio_util::codec::{Encoder, FramedWrite};
 
 use crate::{
     error,
+    protocols::{flush::Flusher, Error},
     server::state::GlobalState,
     store::{message::MessageStore, retain::RetainMessageStore, topic::TopicStore},
     warn,
@@ -18,11 +16,13 @@ use crate::{
 
 use super::WritePacket;
 
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(1);
+
 pub(crate) struct WriteLoop<T, E, S: 'static> {
     writer: FramedWrite<T, E>,
     client_id: String,
     write_rx: AsyncReceiver<WritePacket>,
-    global: &'static GlobalState<S>,
+    global: Arc<GlobalState<S>>,
 }
 
 impl<T, E, S> WriteLoop<T, E, S>
@@ -35,7 +35,7 @@ where
         writer: FramedWrite<T, E>,
         client_id: String,
         write_rx: AsyncReceiver<WritePacket>,
-        global: &'static GlobalState<S>,
+        global: Arc<GlobalState<S>>,
     ) -> Self {
         Self {
             writer,
@@ -50,48 +50,76 @@ where
         T: AsyncWrite + Unpin,
         E: Encoder<VariablePacket, Error = io::Error>,
     {
+        let mut flusher = Flusher::new(self.global.flush_policy());
         // TODO: config: resend interval
         loop {
-            match self.write_rx.recv().await {
-                Ok(message) => match message {
-                    WritePacket::VariablePacket(pkt) => {
-                        if let Err(err) = self.writer.send(pkt).await {
-                            warn!("client#{} write failed: {}", self.client_id, err);
-                            break;
-                        }
+            let message = tokio::select! {
+                message = self.write_rx.recv() => message,
+                _ = flusher.due(), if flusher.has_unflushed() => {
+                    if let Err(err) = self.writer.flush().await {
+                        warn!("client#{} flush failed: {}", self.client_id, err);
+                        break;
                     }
-                    WritePacket::PendingMessage(pending_message) => {
-                        let pkt: PublishPacket = (&pending_message).into();
-                        if let Err(err) = self.writer.send(pkt.into()).await {
-                            warn!("client#{} write failed: {}", self.client_id, err);
-                            break;
-                        }
-
-                        let packet_id = match pending_message.qos() {
-                            QoSWithPacketIdentifier::Level1(packet_id) => packet_id,
-                            QoSWithPacketIdentifier::Level2(packet_id) => packet_id,
-                            _ => continue,
-                        };
-                        if let Err(err) = self
-                            .global
-                            .storage
-                            .save_pending_publish_message(
-                                &self.client_id,
-                                packet_id,
-                                pending_message,
-                            )
-                            .await
-                        {
-                            error!("save pending publish message: {err}");
-                            break;
+                    flusher.flushed();
+                    continue;
+                }
+            };
+            let written = match message {
+                Ok(WritePacket::Batch(packets)) => {
+                    let mut flush = Ok(false);
+                    for packet in packets {
+                        match self.feed(packet, &mut flusher).await {
+                            Ok(due) => flush = flush.map(|flush| flush || due),
+                            Err(err) => {
+                                flush = Err(err);
+                                break;
+                            }
                         }
                     }
-                },
+                    flush
+                }
+                Ok(packet) => self.feed(packet, &mut flusher).await,
                 Err(err) => {
                     error!("client#{} write channel: {err}", self.client_id);
                     break;
                 }
+            };
+            let flushed = match written {
+                Ok(true) => self.writer.flush().await.map_err(Error::Io),
+                Ok(false) => continue,
+                Err(err) => Err(err),
+            };
+            match flushed {
+                Ok(()) => flusher.flushed(),
+                Err(Error::Storage(err)) => {
+                    error!("save pending publish message: {err}");
+                    break;
+                }
+                Err(err) => {
+                    warn!("client#{} write failed: {}", self.client_id, err);
+                    break;
+                }
             }
         }
+        // flushes what the flush policy held back and closes the connection even while the read
+        // half is kept by an offline session, a client which does not read may never take the
+        // close, so it is not waited for long
+        let _ = time::timeout(SHUTDOWN_TIMEOUT, self.writer.close()).await;
+    }
+
+    /// Feeds a packet to the writer, returns whether to flush now: delivered messages are
+    /// flushed by the flush policy, the other packets right away
+    async fn feed(&mut self, packet: WritePacket, flusher: &mut Flusher) -> Result<bool, Error> {
+        let packet = match packet {
+            WritePacket::VariablePacket(packet) => packet,
+            // the read loop never nests batches
+            WritePacket::Batch(_) => unreachable!("nested write batch"),
+        };
+        let delivered = matches!(
+            packet,
+            VariablePacket::PublishPacket(_) | VariablePacket::PubrelPacket(_)
+        );
+        self.writer.feed(packet).await?;
+        Ok(!delivered || flusher.written())
     }
 }
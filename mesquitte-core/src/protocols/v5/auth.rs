@@ -0,0 +1,111 @@
+use std::io;
+
+use futures::{Sink, SinkExt as _, Stream, StreamExt as _};
+use mqtt_codec_kit::v5::{
+    control::{AuthProperties, AuthenticateReasonCode, ConnectReasonCode},
+    packet::{AuthPacket, ConnackPacket, ConnectPacket, VariablePacket},
+};
+use tokio::time;
+
+use crate::{
+    debug,
+    server::{enhanced_auth::AuthStep, state::GlobalState},
+    warn,
+};
+
+/// A connection authenticated by an enhanced authentication method
+pub(super) struct Authenticated {
+    pub method: String,
+    pub username: Option<String>,
+    // sent to the client in CONNACK
+    pub data: Option<Vec<u8>>,
+}
+
+/// Runs the AUTH exchange of the enhanced authentication method CONNECT asks for, if any
+///
+/// Returns the CONNACK refusing the connection when the method is unknown or fails.
+pub(super) async fn authenticate<R, W, E, S>(
+    packet: &ConnectPacket,
+    reader: &mut R,
+    writer: &mut W,
+    global: &GlobalState<S>,
+) -> Result<Option<Authenticated>, ConnackPacket>
+where
+    R: Stream<Item = Result<VariablePacket, E>> + Unpin,
+    W: Sink<AuthPacket, Error = io::Error> + Unpin,
+{
+    let properties = packet.properties();
+    let Some(method) = properties.authentication_method() else {
+        return Ok(None);
+    };
+    let Some(auth) = global.enhanced_auth(method) else {
+        debug!("authentication method {method} is not supported");
+        return Err(ConnackPacket::new(
+            false,
+            ConnectReasonCode::BadAuthenticationMethod,
+        ));
+    };
+
+    let mut exchange = auth.start(packet.client_identifier());
+    let mut data = properties
+        .authentication_data()
+        .as_ref()
+        .map(|data| data.0.clone())
+        .unwrap_or_default();
+    loop {
+        match exchange.step(&data) {
+            AuthStep::Continue(challenge) => {
+                let mut properties = AuthProperties::default();
+                properties.set_authentication_method(Some(method.clone()));
+                properties.set_authentication_data(Some(challenge));
+                let mut auth_packet =
+                    AuthPacket::new(AuthenticateReasonCode::ContinueAuthentication);
+                auth_packet.set_properties(Some(properties));
+                if let Err(err) = writer.send(auth_packet).await {
+                    debug!("write auth packet: {err}");
+                    return Err(ConnackPacket::new(false, ConnectReasonCode::NotAuthorized));
+                }
+            }
+            AuthStep::Success { username, data } => {
+                return Ok(Some(Authenticated {
+                    method: method.clone(),
+                    username,
+                    data,
+                }));
+            }
+            AuthStep::Failure => {
+                debug!(
+                    "client#{} failed {method} authentication",
+                    packet.client_identifier()
+                );
+                return Err(ConnackPacket::new(false, ConnectReasonCode::NotAuthorized));
+            }
+        }
+
+        data = match time::timeout(global.handshake_timeout(), reader.next()).await {
+            Ok(Some(Ok(VariablePacket::AuthPacket(packet))))
+                if packet.reason_code() == AuthenticateReasonCode::ContinueAuthentication =>
+            {
+                let properties = packet.properties().clone().unwrap_or_default();
+                if properties.authentication_method().as_ref() != Some(method) {
+                    debug!("auth packet changed the authentication method");
+                    return Err(ConnackPacket::new(false, ConnectReasonCode::ProtocolError));
+                }
+                properties
+                    .authentication_data()
+                    .as_ref()
+                    .map(|data| data.0.clone())
+                    .unwrap_or_default()
+            }
+            Ok(Some(Ok(packet))) => {
+                debug!("expected an auth packet: {packet}");
+                return Err(ConnackPacket::new(false, ConnectReasonCode::ProtocolError));
+            }
+            Ok(_) => return Err(ConnackPacket::new(false, ConnectReasonCode::NotAuthorized)),
+            Err(_) => {
+                warn!("no AUTH packet within {:?}", global.handshake_timeout());
+                return Err(ConnackPacket::new(false, ConnectReasonCode::NotAuthorized));
+            }
+        };
+    }
+}
@@ -3,11 +3,19 @@ use mqtt_codec_kit::{
     v5::{
         control::{
             ConnackProperties, ConnectReasonCode, DisconnectProperties, DisconnectReasonCode,
+            PubackProperties, PubackReasonCode, PubcompProperties, PubcompReasonCode,
+            PubrecProperties, PubrecReasonCode, SubackProperties, UnsubackProperties,
+        },
+        packet::{
+            suback::SubscribeReasonCode, unsuback::UnsubscribeReasonCode, ConnackPacket,
+            DisconnectPacket, PubackPacket, PubcompPacket, PubrecPacket, SubackPacket,
+            UnsubackPacket,
         },
-        packet::{ConnackPacket, DisconnectPacket},
     },
 };
 
+use crate::server::state::Redirection;
+
 use super::session::Session;
 
 pub(crate) fn build_error_connack<S: Into<String>>(
@@ -50,3 +58,143 @@ pub(crate) fn build_error_disconnect<S: Into<String>>(
 
     disconnect_packet
 }
+
+/// The CONNACK sending a new connection to the server of `redirection`
+pub(crate) fn build_redirect_connack(redirection: &Redirection) -> ConnackPacket {
+    let reason_code = if redirection.permanent {
+        ConnectReasonCode::ServerMoved
+    } else {
+        ConnectReasonCode::UseAnotherServer
+    };
+    let mut connack_packet = ConnackPacket::new(false, reason_code);
+    let mut connack_properties = ConnackProperties::default();
+    connack_properties.set_server_reference(Some(redirection.server_reference.clone()));
+    connack_packet.set_properties(connack_properties);
+    connack_packet
+}
+
+/// The DISCONNECT sending the client to the server of `redirection`
+pub(crate) fn build_redirect_disconnect(
+    session: &mut Session,
+    redirection: &Redirection,
+) -> DisconnectPacket {
+    let reason_code = if redirection.permanent {
+        DisconnectReasonCode::ServerMoved
+    } else {
+        DisconnectReasonCode::UseAnotherServer
+    };
+    let mut disconnect_packet = DisconnectPacket::new(reason_code);
+    let mut disconnect_properties = DisconnectProperties::default();
+    disconnect_properties.set_server_reference(Some(redirection.server_reference.clone()));
+    disconnect_packet.set_properties(disconnect_properties);
+
+    if disconnect_packet.encoded_length() > session.max_packet_size() {
+        disconnect_packet.set_properties(DisconnectProperties::default());
+    }
+
+    disconnect_packet
+}
+
+/// Builds the acknowledgements the broker sends in reply to client packets
+///
+/// The reason string (quota details, the filters refused in a subscription, ...) is only
+/// attached when the client set Request Problem Information, and is dropped again if it would
+/// push the packet over the client's Maximum Packet Size.
+#[derive(Debug, Default, Clone)]
+pub(crate) struct AckBuilder {
+    reason_string: Option<String>,
+}
+
+macro_rules! build_ack {
+    ($name:ident, $packet:ident, $properties:ident, $reason:ty) => {
+        pub(crate) fn $name(self, session: &Session, packet_id: u16, reason: $reason) -> $packet {
+            let mut packet = $packet::new(packet_id, reason);
+            if !session.request_problem_info() {
+                return packet;
+            }
+
+            let mut properties = $properties::default();
+            properties.set_reason_string(self.reason_string);
+            packet.set_properties(properties);
+
+            if packet.encoded_length() > session.max_packet_size() {
+                packet.set_properties($properties::default());
+            }
+            packet
+        }
+    };
+}
+
+impl AckBuilder {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    pub(crate) fn reason_string<S: Into<String>>(mut self, reason_string: S) -> Self {
+        self.reason_string = Some(reason_string.into());
+        self
+    }
+
+    build_ack!(puback, PubackPacket, PubackProperties, PubackReasonCode);
+    build_ack!(pubrec, PubrecPacket, PubrecProperties, PubrecReasonCode);
+    build_ack!(pubcomp, PubcompPacket, PubcompProperties, PubcompReasonCode);
+    build_ack!(
+        suback,
+        SubackPacket,
+        SubackProperties,
+        Vec<SubscribeReasonCode>
+    );
+    build_ack!(
+        unsuback,
+        UnsubackPacket,
+        UnsubackProperties,
+        Vec<UnsubscribeReasonCode>
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ack_reason_string() {
+        let mut session = Session::new("c1".to_owned(), false, 32);
+        let puback = AckBuilder::new().reason_string("quota exceeded").puback(
+            &session,
+            1,
+            PubackReasonCode::QuotaExceeded,
+        );
+        assert_eq!(puback.packet_identifier(), 1);
+        assert_eq!(puback.reason_code(), PubackReasonCode::QuotaExceeded);
+        assert_eq!(
+            puback.properties().reason_string().as_deref(),
+            Some("quota exceeded")
+        );
+
+        // left out when it would not fit in the client's Maximum Packet Size
+        let unsuback = AckBuilder::new().reason_string("x".repeat(64)).unsuback(
+            &session,
+            2,
+            vec![UnsubscribeReasonCode::Success],
+        );
+        assert!(unsuback.properties().reason_string().is_some());
+        session.set_max_packet_size(32);
+        let unsuback = AckBuilder::new().reason_string("x".repeat(64)).unsuback(
+            &session,
+            2,
+            vec![UnsubscribeReasonCode::Success],
+        );
+        assert_eq!(unsuback.reason_code(), [UnsubscribeReasonCode::Success]);
+        assert!(unsuback.properties().reason_string().is_none());
+
+        // and unless the client asked for it
+        session.set_request_problem_info(false);
+        let suback = AckBuilder::new().reason_string("not authorized").suback(
+            &session,
+            3,
+            vec![SubscribeReasonCode::NotAuthorized],
+        );
+        assert_eq!(suback.reason_code(), [SubscribeReasonCode::NotAuthorized]);
+        assert!(suback.properties().reason_string().is_none());
+    }
+}
@@ -1,8 +1,8 @@
+use std::{net::SocketAddr, sync::Arc};
+
 use kanal::{bounded_async, AsyncReceiver};
 use mqtt_codec_kit::{
-    common::{
-        ProtocolLevel, QualityOfService, MATCH_ALL_STR, MATCH_ONE_STR, SHARED_PREFIX, SYS_PREFIX,
-    },
+    common::{ProtocolLevel, QualityOfService, MATCH_ALL_STR, MATCH_ONE_STR},
     v5::{
         control::{ConnackProperties, ConnectReasonCode, DisconnectReasonCode},
         packet::{ConnackPacket, ConnectPacket, DisconnectPacket},
@@ -12,16 +12,34 @@ use nanoid::nanoid;
 
 use crate::{
     debug, info,
+    instrument::record_client_id,
     protocols::ProtocolSessionState,
-    server::state::{AddClientReceipt, DeliverMessage, GlobalState},
+    server::{
+        audit::AuditEvent,
+        client_info::ClientInfo,
+        state::{
+            AddClientReceipt, CleanStartSemantics, ConnectVerdict, DeliverMessage, GlobalState,
+        },
+    },
+    store::{message::MessageStore, retain::RetainMessageStore, topic::TopicStore},
 };
 
-use super::{common::build_error_connack, session::Session};
+use super::{
+    auth::Authenticated,
+    common::{build_error_connack, build_error_disconnect, build_redirect_connack},
+    session::{Session, SessionState},
+};
 
-pub(super) async fn handle_connect(
+pub(super) async fn handle_connect<S>(
     packet: ConnectPacket,
-    global: &GlobalState,
-) -> Result<(ConnackPacket, Session, AsyncReceiver<DeliverMessage>), ConnackPacket> {
+    remote_addr: Option<SocketAddr>,
+    cert_identity: Option<String>,
+    authenticated: Option<Authenticated>,
+    global: &GlobalState<S>,
+) -> Result<(ConnackPacket, Session, AsyncReceiver<DeliverMessage>), ConnackPacket>
+where
+    S: MessageStore + RetainMessageStore + TopicStore,
+{
     debug!(
         r#"client#{} received a connect packet:
 protocol level : {:?}
@@ -62,7 +80,14 @@ protocol level : {:?}
         ));
     }
 
-    // TODO: handle auth
+    if let Some(redirection) = global.redirection() {
+        debug!(
+            "client#{} redirected to {}",
+            packet.client_identifier(),
+            redirection.server_reference
+        );
+        return Err(build_redirect_connack(&redirection));
+    }
 
     let (assigned_client_id, client_id) = if packet.client_identifier().is_empty() {
         (true, nanoid!())
@@ -70,10 +95,20 @@ protocol level : {:?}
         (false, packet.client_identifier().to_owned())
     };
 
-    // TODO: config: receive_maximum
-    let mut session = Session::new(client_id, assigned_client_id, 12);
-    session.set_clean_session(packet.clean_session());
-    session.set_username(packet.username().map(|name| name.to_owned()));
+    let mut session = Session::new(client_id, assigned_client_id, global.receive_maximum());
+    session.set_remote_addr(remote_addr);
+    session.set_clean_start(packet.clean_session());
+    // clients authenticated by their certificate or an enhanced authentication method
+    let cert_authenticated = cert_identity.is_some();
+    let method_authenticated = authenticated.is_some();
+    let username = authenticated
+        .as_ref()
+        .and_then(|authenticated| authenticated.username.clone());
+    session.set_username(
+        cert_identity
+            .or(username)
+            .or_else(|| packet.username().map(|name| name.to_owned())),
+    );
     session.set_keep_alive(packet.keep_alive());
     let server_keep_alive = session.keep_alive() != packet.keep_alive();
     session.set_server_keep_alive(server_keep_alive);
@@ -108,6 +143,70 @@ protocol level : {:?}
         ));
     }
 
+    if let Err(reason) = global
+        .client_id_config()
+        .validate(packet.client_identifier())
+    {
+        debug!("client#{} {reason}", packet.client_identifier());
+
+        return Err(build_error_connack(
+            &mut session,
+            false,
+            ConnectReasonCode::ClientIdentifierNotValid,
+            reason,
+        ));
+    }
+
+    let verdict = match (cert_authenticated, method_authenticated) {
+        // a client authenticated by its certificate sends no credentials
+        (true, _) if packet.username().is_some() || packet.password().is_some() => {
+            debug!(
+                "client#{} sent credentials to a certificate only listener",
+                session.client_id()
+            );
+            ConnectVerdict::BadCredentials
+        }
+        (true, _) | (_, true) => ConnectVerdict::Accepted,
+        (false, false) => {
+            global
+                .authorize_connect(session.client_id(), packet.username(), packet.password())
+                .await
+        }
+    };
+    let (code, reason) = match verdict {
+        ConnectVerdict::Accepted => (None, ""),
+        ConnectVerdict::BadCredentials => (
+            Some(ConnectReasonCode::BadUsernameOrPassword),
+            "bad user name or password",
+        ),
+        ConnectVerdict::NotAuthorized => (Some(ConnectReasonCode::NotAuthorized), "not authorized"),
+        ConnectVerdict::Unavailable => {
+            debug!(
+                "client#{} connect could not be authorized",
+                session.client_id()
+            );
+
+            return Err(build_error_connack(
+                &mut session,
+                false,
+                ConnectReasonCode::ServerUnavailable,
+                "authorization is unavailable",
+            ));
+        }
+    };
+    if let Some(code) = code {
+        debug!("client#{} connect denied: {code:?}", session.client_id());
+        global.audit(
+            remote_addr,
+            AuditEvent::AuthFailure {
+                client_id: session.client_id(),
+                username: packet.username(),
+            },
+        );
+
+        return Err(build_error_connack(&mut session, false, code, reason));
+    }
+
     if properties.authentication_data().is_some() && properties.authentication_method().is_none() {
         debug!("connect properties AuthenticationMethod is missing");
 
@@ -122,11 +221,18 @@ protocol level : {:?}
     if let Some(session_expiry_interval) = properties.session_expiry_interval() {
         session.set_session_expiry_interval(session_expiry_interval);
     }
+    let clean_session = match global.clean_start() {
+        // without an expiry interval the session ends with its connection, whatever Clean Start
+        CleanStartSemantics::Mqtt5 => session.session_expiry_interval() == 0,
+        CleanStartSemantics::Legacy => packet.clean_session(),
+    };
+    session.set_clean_session(clean_session);
     if let Some(receive_maximum) = properties.receive_maximum() {
         session.set_receive_maximum(receive_maximum);
     }
     if let Some(topic_alias_max) = properties.topic_alias_max() {
         session.set_topic_alias_max(topic_alias_max);
+        session.set_client_topic_alias_max(topic_alias_max);
     }
     if let Some(request_response_info) = properties.request_response_info() {
         session.set_request_response_info(request_response_info != 0);
@@ -162,14 +268,14 @@ protocol level : {:?}
             ));
         }
 
-        if topic_name.starts_with(SHARED_PREFIX) || topic_name.starts_with(SYS_PREFIX) {
-            debug!("last will topic start with '$SYS/' or '$share/'");
+        if global.is_reserved_topic(topic_name) {
+            debug!("last will topic is reserved");
 
             return Err(build_error_connack(
                 &mut session,
                 false,
                 ConnectReasonCode::TopicNameInvalid,
-                "last will topic start with '$SYS/' or '$share/'",
+                "last will topic is reserved",
             ));
         }
         // TODO: config: retain available
@@ -185,63 +291,126 @@ protocol level : {:?}
         //     return Err(Error::InvalidConnectPacket);
         // }
 
-        // TODO: config: max qos
-        // if last_will.qos() > max_qos {
-        //     let err_pkt = build_error_connack(
-        //         &mut session,
-        //         false,
-        //         ConnectReasonCode::QoSNotSupported,
-        //         "",
-        //     );
-        //     writer.send(err_pkt.into()).await?;
+        if last_will.qos() > global.max_qos() {
+            debug!("last will qos {:?} is not supported", last_will.qos());
 
-        //     return Err(Error::InvalidConnectPacket);
-        // }
+            return Err(build_error_connack(
+                &mut session,
+                false,
+                ConnectReasonCode::QoSNotSupported,
+                "last will qos is not supported",
+            ));
+        }
 
         session.set_last_will(last_will)
     }
-    // TODO: v5 auth
-
     // FIXME: too many clients cause memory leak
 
-    // TODO: deliver channel size
-    let (deliver_tx, deliver_rx) = bounded_async(8);
-    let receipt = global.add_client(session.client_id(), deliver_tx).await;
+    let (deliver_tx, deliver_rx) = bounded_async(global.channel_config().deliver_channel_size);
+    let info = Arc::new(ClientInfo::new(
+        remote_addr,
+        level,
+        session.clean_start(),
+        session.username(),
+    ));
+    session.set_info(info.clone());
+    session.set_timer_token(global.next_timer_token());
+    let receipt = global
+        .add_client(session.client_id(), session.timer_token(), deliver_tx, info)
+        .await;
 
     let session_present = match receipt {
         AddClientReceipt::Present(state) => {
-            if !session.clean_session() {
-                match state {
-                    ProtocolSessionState::V5(session_state) => {
-                        session.copy_state(session_state);
-                        true
+            let subscriptions = state.subscriptions();
+            let present = match state {
+                ProtocolSessionState::V5(session_state)
+                    if session_present(
+                        global.clean_start(),
+                        session.clean_start(),
+                        &session_state,
+                    ) =>
+                {
+                    session.copy_state(session_state);
+                    true
+                }
+                ProtocolSessionState::V5(_) => {
+                    info!(
+                        "{} session removed due to reconnect with clean start or after its expiry",
+                        packet.client_identifier(),
+                    );
+                    false
+                }
+                #[cfg(feature = "v4")]
+                ProtocolSessionState::V4(_) => false,
+            };
+            // the state of a session not resumed is deleted before the new one starts
+            if !present {
+                for topic_filter in subscriptions {
+                    if let Err(err) = global
+                        .storage
+                        .unsubscribe(session.client_id(), &topic_filter)
+                        .await
+                    {
+                        debug!("handle connect unsubscribe old topic failed: {err}");
                     }
-                    #[cfg(feature = "v4")]
-                    ProtocolSessionState::V4(_) => false,
                 }
-            } else {
-                info!(
-                    "{} session removed due to reconnect with clean session",
-                    packet.client_identifier(),
-                );
-                false
+                if let Err(err) = global.storage.clear_all(session.client_id()).await {
+                    debug!("handle connect clear old messages failed: {err}");
+                }
+            }
+            present
+        }
+        // a session persisted by an earlier broker run
+        AddClientReceipt::New if !session.clean_start() => {
+            match global.load_session(session.client_id()).await {
+                Some(stored) => {
+                    session.restore(&stored);
+                    true
+                }
+                None => false,
             }
         }
-        AddClientReceipt::New => false,
+        AddClientReceipt::New => {
+            global.discard_stored_session(session.client_id()).await;
+            false
+        }
+        AddClientReceipt::Rejected => {
+            return Err(build_error_connack(
+                &mut session,
+                false,
+                ConnectReasonCode::ClientIdentifierNotValid,
+                "client identifier is in use",
+            ));
+        }
+        AddClientReceipt::Renamed(client_id) => {
+            session.set_assigned_client_id(client_id);
+            false
+        }
     };
+    record_client_id(session.client_id());
+    if session.clean_session() {
+        global.remove_session(session.client_id()).await;
+    } else {
+        global.save_session(session.to_stored()).await;
+    }
+    if !session_present {
+        if let Some(deliver_queue) = global.deliver_queue(session.client_id()) {
+            deliver_queue.clear();
+        }
+    }
 
     // build and send connack packet
     let mut connack_properties = ConnackProperties::default();
     // TODO: config: max session_expiry_interval
     connack_properties.set_session_expiry_interval(Some(session.session_expiry_interval()));
-    // TODO: config: max receive_maximum
-    connack_properties.set_receive_maximum(Some(session.receive_maximum()));
-    // TODO: config: max qos
-    connack_properties.set_max_qos(Some(QualityOfService::Level2 as u8));
+    connack_properties.set_receive_maximum(Some(session.server_receive_maximum()));
+    // only 0 or 1 may be sent, an absent Maximum QoS means QoS 2
+    if global.max_qos() != QualityOfService::Level2 {
+        connack_properties.set_max_qos(Some(global.max_qos() as u8));
+    }
     // TODO: config: retain available
     connack_properties.set_retain_available(Some(1));
-    // TODO: config: max packet size
-    connack_properties.set_max_packet_size(Some(session.max_packet_size()));
+    connack_properties.set_max_packet_size(global.max_packet_size());
     if session.assigned_client_id() {
         connack_properties.set_assigned_client_identifier(Some(session.client_id().to_string()));
     }
@@ -260,7 +429,12 @@ protocol level : {:?}
         connack_properties.set_server_keep_alive(Some(session.keep_alive()));
     }
     if session.request_response_info() {
-        // TODO: handle ResponseTopic in plugin
+        connack_properties
+            .set_response_information(global.response_information(session.client_id()));
+    }
+    if let Some(authenticated) = authenticated {
+        connack_properties.set_authentication_method(Some(authenticated.method));
+        connack_properties.set_authentication_data(authenticated.data);
     }
     let mut connack_packet = ConnackPacket::new(session_present, ConnectReasonCode::Success);
     connack_packet.set_properties(connack_properties);
@@ -268,9 +442,24 @@ protocol level : {:?}
     Ok((connack_packet, session, deliver_rx))
 }
 
-pub(super) async fn handle_disconnect(
+/// Whether a connect resumes the session of `state`, which sets Session Present in the CONNACK
+fn session_present(
+    semantics: CleanStartSemantics,
+    clean_start: bool,
+    state: &SessionState,
+) -> bool {
+    match semantics {
+        _ if clean_start => false,
+        // the expiry timer of the session may not have removed it yet
+        CleanStartSemantics::Mqtt5 => !state.expired(),
+        CleanStartSemantics::Legacy => true,
+    }
+}
+
+pub(super) async fn handle_disconnect<S>(
     session: &mut Session,
     packet: DisconnectPacket,
+    global: &GlobalState<S>,
 ) -> Option<DisconnectPacket> {
     debug!(
         "client#{} received a disconnect packet",
@@ -278,8 +467,24 @@ pub(super) async fn handle_disconnect(
     );
 
     if let Some(value) = packet.properties().session_expiry_interval() {
+        match global.clean_start() {
+            // a session which ends with its connection can not be kept by the DISCONNECT
+            CleanStartSemantics::Mqtt5 if session.session_expiry_interval() == 0 && value > 0 => {
+                debug!(
+                    "client#{} set a session expiry interval after connecting with none",
+                    session.client_id()
+                );
+                session.set_server_disconnected_for("protocol error");
+                return Some(build_error_disconnect(
+                    session,
+                    DisconnectReasonCode::ProtocolError,
+                    "SessionExpiryInterval can not be set after connecting with 0",
+                ));
+            }
+            CleanStartSemantics::Mqtt5 => session.set_clean_session(value == 0),
+            CleanStartSemantics::Legacy => session.set_clean_session(true),
+        }
         session.set_session_expiry_interval(value);
-        session.set_clean_session(true);
     }
 
     if packet.reason_code() == DisconnectReasonCode::NormalDisconnection {
@@ -288,3 +493,201 @@ pub(super) async fn handle_disconnect(
     session.set_client_disconnected();
     None
 }
+
+#[cfg(test)]
+mod tests {
+    use mqtt_codec_kit::v5::control::DisconnectProperties;
+
+    use super::*;
+    use crate::store::{memory::MemoryStore, memory_storage};
+
+    fn global() -> GlobalState<MemoryStore> {
+        GlobalState::new(memory_storage())
+    }
+
+    fn disconnect(session_expiry_interval: u32) -> DisconnectPacket {
+        let mut properties = DisconnectProperties::default();
+        properties.set_session_expiry_interval(Some(session_expiry_interval));
+        let mut packet = DisconnectPacket::new(DisconnectReasonCode::NormalDisconnection);
+        packet.set_properties(properties);
+        packet
+    }
+
+    // the state a connect takes over, from a session connected or closed `expiry` seconds ago
+    fn session_state(disconnected: bool, expiry: u32) -> SessionState {
+        let mut session = Session::new("c1".to_owned(), false, 32);
+        session.set_session_expiry_interval(expiry);
+        if disconnected {
+            session.set_server_disconnected();
+        }
+        session.build_state()
+    }
+
+    #[test]
+    fn clean_start_discards_the_session() {
+        let state = session_state(false, 60);
+        assert!(!session_present(CleanStartSemantics::Mqtt5, true, &state));
+        assert!(!session_present(CleanStartSemantics::Legacy, true, &state));
+        assert!(session_present(CleanStartSemantics::Mqtt5, false, &state));
+        assert!(session_present(CleanStartSemantics::Legacy, false, &state));
+    }
+
+    #[test]
+    fn expired_session_is_not_resumed() {
+        // the interval elapses the moment the connection of a session with 0 closes
+        let expired = session_state(true, 0);
+        assert!(!session_present(
+            CleanStartSemantics::Mqtt5,
+            false,
+            &expired
+        ));
+        assert!(session_present(
+            CleanStartSemantics::Legacy,
+            false,
+            &expired
+        ));
+
+        let kept = session_state(true, 60);
+        assert!(session_present(CleanStartSemantics::Mqtt5, false, &kept));
+        // a connected session is taken over, its expiry starts only once it is closed
+        let connected = session_state(false, 0);
+        assert!(session_present(
+            CleanStartSemantics::Mqtt5,
+            false,
+            &connected
+        ));
+    }
+
+    #[tokio::test]
+    async fn disconnect_sets_the_session_expiry() {
+        let global = global();
+        let mut session = Session::new("c1".to_owned(), false, 32);
+        session.set_session_expiry_interval(60);
+        assert!(handle_disconnect(&mut session, disconnect(0), &global)
+            .await
+            .is_none());
+        assert!(session.clean_session());
+        assert!(session.client_disconnected());
+
+        // a session connected without an expiry can not be kept by its DISCONNECT
+        let mut session = Session::new("c1".to_owned(), false, 32);
+        let pkt = handle_disconnect(&mut session, disconnect(60), &global)
+            .await
+            .unwrap();
+        assert_eq!(pkt.reason_code(), DisconnectReasonCode::ProtocolError);
+        assert!(!session.client_disconnected());
+
+        let legacy = self::global().with_clean_start(CleanStartSemantics::Legacy);
+        let mut session = Session::new("c1".to_owned(), false, 32);
+        assert!(handle_disconnect(&mut session, disconnect(60), &legacy)
+            .await
+            .is_none());
+        assert_eq!(session.session_expiry_interval(), 60);
+    }
+
+    fn connect(client_id: &str) -> ConnectPacket {
+        let mut packet = ConnectPacket::new(client_id);
+        packet.set_clean_session(true);
+        packet.set_username(Some("user".to_owned()));
+        packet.set_password(Some("secret".to_owned()));
+        packet
+    }
+
+    // the reason code of the CONNACK, `Success` when the connection is accepted
+    async fn connect_reason_code(
+        packet: ConnectPacket,
+        global: &GlobalState<MemoryStore>,
+        cert_identity: Option<&str>,
+    ) -> ConnectReasonCode {
+        let cert_identity = cert_identity.map(|identity| identity.to_owned());
+        match handle_connect(packet, None, cert_identity, None, global).await {
+            Ok((connack, _, _)) => connack.connect_reason_code(),
+            Err(connack) => connack.connect_reason_code(),
+        }
+    }
+
+    // the CONNACK of a connection accepted by `global`, encoded and decoded again
+    async fn decoded_connack(global: &GlobalState<MemoryStore>) -> ConnackPacket {
+        use mqtt_codec_kit::common::{Decodable as _, Encodable as _};
+
+        let Ok((connack, _, _)) = handle_connect(connect("c1"), None, None, None, global).await
+        else {
+            panic!("connection refused");
+        };
+        let mut buf = Vec::new();
+        connack.encode(&mut buf).unwrap();
+        ConnackPacket::decode(&mut &buf[..]).unwrap()
+    }
+
+    #[tokio::test]
+    async fn connack_max_qos_is_valid() {
+        let connack = decoded_connack(&global()).await;
+        assert_eq!(connack.connect_reason_code(), ConnectReasonCode::Success);
+        assert_eq!(connack.properties().max_qos(), None);
+
+        let global = global().with_max_qos(QualityOfService::Level1);
+        assert_eq!(
+            decoded_connack(&global).await.properties().max_qos(),
+            Some(1)
+        );
+    }
+
+    #[tokio::test]
+    async fn certificate_refuses_credentials() {
+        let global = global();
+        assert_eq!(
+            connect_reason_code(connect("c1"), &global, Some("sensor-17")).await,
+            ConnectReasonCode::BadUsernameOrPassword
+        );
+        let mut packet = connect("c2");
+        packet.set_username(None);
+        packet.set_password(None);
+        assert_eq!(
+            connect_reason_code(packet, &global, Some("sensor-17")).await,
+            ConnectReasonCode::Success
+        );
+    }
+
+    #[cfg(feature = "script")]
+    #[tokio::test]
+    async fn connect_is_authorized() {
+        use crate::script::ScriptHook;
+
+        const SCRIPT: &str = r#"
+            function on_connect(ctx)
+                if ctx.client_id == "broken" then
+                    error("auth backend down")
+                end
+                if ctx.password ~= "secret" then
+                    return "bad_credentials"
+                end
+                return ctx.username == "user"
+            end
+        "#;
+
+        let global = global().with_script(ScriptHook::new(SCRIPT).unwrap());
+        assert_eq!(
+            connect_reason_code(connect("c1"), &global, None).await,
+            ConnectReasonCode::Success
+        );
+
+        let mut packet = connect("c2");
+        packet.set_password(Some("wrong".to_owned()));
+        assert_eq!(
+            connect_reason_code(packet, &global, None).await,
+            ConnectReasonCode::BadUsernameOrPassword
+        );
+
+        let mut packet = connect("c3");
+        packet.set_username(Some("other".to_owned()));
+        assert_eq!(
+            connect_reason_code(packet, &global, None).await,
+            ConnectReasonCode::NotAuthorized
+        );
+
+        assert_eq!(
+            connect_reason_code(connect("broken"), &global, None).await,
+            ConnectReasonCode::ServerUnavailable
+        );
+    }
+}
@@ -1,7 +1,9 @@
+mod auth;
 mod common;
 mod connect;
 mod publish;
 mod subscribe;
+mod topic_alias;
 
 pub mod read_write_loop;
 pub mod session;
@@ -1,24 +1,29 @@
-use std::{cmp, io};
+use std::io;
 
+use futures::SinkExt as _;
 use mqtt_codec_kit::{
-    common::{qos::QoSWithPacketIdentifier, QualityOfService, MATCH_ALL_STR, MATCH_ONE_STR},
+    common::{qos::QoSWithPacketIdentifier, MATCH_ALL_STR, MATCH_ONE_STR},
     v5::{
         control::{
             DisconnectReasonCode, PubackReasonCode, PubcompReasonCode, PubrecReasonCode,
             PubrelReasonCode,
         },
-        packet::{
-            PubackPacket, PubcompPacket, PublishPacket, PubrecPacket, PubrelPacket, VariablePacket,
-        },
+        packet::{PublishPacket, PubrelPacket, VariablePacket},
     },
 };
+use tokio::io::AsyncWrite;
+use tokio_util::codec::{Encoder, FramedWrite};
 
 use crate::{
     debug,
-    protocols::v5::common::build_error_disconnect,
-    server::state::GlobalState,
+    protocols::{
+        common::{complete_qos2, Qos2Completion},
+        v5::common::{build_error_disconnect, AckBuilder},
+        Error,
+    },
+    server::state::{GlobalState, PublishVerdict},
     store::{
-        message::{MessageStore, PublishMessage},
+        message::{MessageStore, PublishMessage, ReceiveOutcome},
         retain::RetainMessageStore,
         topic::TopicStore,
         Storage,
@@ -27,36 +32,37 @@ use crate::{
 
 use super::session::Session;
 
-pub(super) async fn handle_publish<'a, S>(
+pub(super) async fn handle_publish<S>(
     session: &mut Session,
     packet: &PublishPacket,
-    global: &'a GlobalState,
-    storage: &'a Storage<S>,
-) -> io::Result<(bool, Option<VariablePacket>)>
+    global: &GlobalState<S>,
+) -> Result<(bool, Option<VariablePacket>), Error>
 where
     S: MessageStore + RetainMessageStore + TopicStore,
 {
     debug!(
         r#"client#{} received a publish packet:
 topic name : {:?}
-   payload : {:?}
+   payload : [{} bytes]
      flags : qos={:?}, retain={}, dup={}"#,
         session.client_id(),
         packet.topic_name(),
-        packet.payload(),
+        packet.payload().len(),
         packet.qos(),
         packet.retain(),
         packet.dup(),
     );
 
-    let message_count = storage.message_count(session.client_id()).await?;
-    if message_count >= session.receive_maximum().into() {
-        let err_pkt = build_error_disconnect(
-            session,
-            DisconnectReasonCode::ReceiveMaximumExceeded,
-            "received more than Receive Maximum publication",
-        );
-        return Ok((true, Some(err_pkt.into())));
+    // counted until PUBACK or PUBCOMP is sent, only for this connection
+    if let (_, Some(packet_id)) = packet.qos().split() {
+        if !session.receive_publish(packet_id) {
+            let err_pkt = build_error_disconnect(
+                session,
+                DisconnectReasonCode::ReceiveMaximumExceeded,
+                "received more than Receive Maximum publication",
+            );
+            return Ok((true, Some(err_pkt.into())));
+        }
     }
 
     let topic_name = packet.topic_name();
@@ -79,6 +85,24 @@ topic name : {:?}
         return Ok((true, Some(err_pkt.into())));
     }
 
+    if global.is_reserved_topic(topic_name) {
+        let err_pkt = build_error_disconnect(
+            session,
+            DisconnectReasonCode::TopicNameInvalid,
+            "topic name is reserved",
+        );
+        return Ok((true, Some(err_pkt.into())));
+    }
+
+    if let Err(err) = topic_name.validate_strict() {
+        let err_pkt = build_error_disconnect(
+            session,
+            DisconnectReasonCode::TopicNameInvalid,
+            err.to_string(),
+        );
+        return Ok((true, Some(err_pkt.into())));
+    }
+
     if packet.qos() == QoSWithPacketIdentifier::Level0 && packet.dup() {
         let err_pkt = build_error_disconnect(
             session,
@@ -88,52 +112,154 @@ topic name : {:?}
         return Ok((true, Some(err_pkt.into())));
     }
 
+    if let Some(max) = global.max_payload_size() {
+        if packet.payload().len() > max {
+            let reason = format!("payload exceeds {max} bytes");
+            debug!("client#{} publish dropped, {reason}", session.client_id());
+            let ack: VariablePacket = match packet.qos() {
+                QoSWithPacketIdentifier::Level0 => {
+                    let err_pkt = build_error_disconnect(
+                        session,
+                        DisconnectReasonCode::PacketTooLarge,
+                        reason,
+                    );
+                    return Ok((true, Some(err_pkt.into())));
+                }
+                QoSWithPacketIdentifier::Level1(packet_id) => {
+                    session.complete_publish(packet_id);
+                    AckBuilder::new()
+                        .reason_string(reason)
+                        .puback(
+                            session,
+                            packet_id,
+                            PubackReasonCode::ImplementationSpecificError,
+                        )
+                        .into()
+                }
+                QoSWithPacketIdentifier::Level2(packet_id) => {
+                    session.complete_publish(packet_id);
+                    AckBuilder::new()
+                        .reason_string(reason)
+                        .pubrec(
+                            session,
+                            packet_id,
+                            PubrecReasonCode::ImplementationSpecificError,
+                        )
+                        .into()
+                }
+            };
+            return Ok((false, Some(ack)));
+        }
+    }
+
+    let mut message: PublishMessage = packet.into();
+    let verdict = global
+        .authorize_publish(session.client_id(), &mut message)
+        .await;
+
     match packet.qos() {
         QoSWithPacketIdentifier::Level0 => {
-            deliver_publish_message(session, packet.into(), global, storage).await?;
+            if verdict.is_accepted() {
+                deliver_publish_message(session, message, global).await?;
+            }
             Ok((false, None))
         }
         QoSWithPacketIdentifier::Level1(packet_id) => {
-            if !packet.dup() {
-                deliver_publish_message(session, packet.into(), global, storage).await?;
-            }
+            let reason_code = match verdict {
+                PublishVerdict::NotAuthorized => PubackReasonCode::NotAuthorized,
+                PublishVerdict::Rejected => PubackReasonCode::ImplementationSpecificError,
+                // a resend was forwarded already, whether it matched is not known any more
+                PublishVerdict::Accepted if packet.dup() => PubackReasonCode::Success,
+                PublishVerdict::Accepted => {
+                    match deliver_publish_message(session, message, global).await? {
+                        Forwarded::Delivered => PubackReasonCode::Success,
+                        Forwarded::NoMatchingSubscribers => PubackReasonCode::NoMatchingSubscribers,
+                    }
+                }
+            };
+            session.complete_publish(packet_id);
             Ok((
                 false,
-                Some(PubackPacket::new(packet_id, PubackReasonCode::Success).into()),
+                Some(
+                    AckBuilder::new()
+                        .puback(session, packet_id, reason_code)
+                        .into(),
+                ),
             ))
         }
         QoSWithPacketIdentifier::Level2(packet_id) => {
-            if !packet.dup() {
-                storage
-                    .save_publish_message(session.client_id(), packet_id, packet.into())
-                    .await?;
+            let reason_code = match verdict {
+                PublishVerdict::NotAuthorized => PubrecReasonCode::NotAuthorized,
+                PublishVerdict::Rejected => PubrecReasonCode::ImplementationSpecificError,
+                PublishVerdict::Accepted => {
+                    // forwarded on PUBREL, the subscribers are only matched here to tell the
+                    // publisher nobody is listening
+                    let matched = !global
+                        .storage
+                        .match_topic(message.topic_name())
+                        .await?
+                        .is_empty();
+                    // the DUP flag is not trusted, a resend is recognized by its packet id
+                    match global
+                        .storage
+                        .save_publish_message(session.client_id(), packet_id, message)
+                        .await?
+                    {
+                        ReceiveOutcome::Received if !matched => {
+                            PubrecReasonCode::NoMatchingSubscribers
+                        }
+                        ReceiveOutcome::Received => PubrecReasonCode::Success,
+                        ReceiveOutcome::Duplicate => {
+                            debug!(
+                                "client#{} resent publish {packet_id}, waiting for pubrel",
+                                session.client_id()
+                            );
+                            PubrecReasonCode::Success
+                        }
+                        ReceiveOutcome::Full => PubrecReasonCode::QuotaExceeded,
+                    }
+                }
+            };
+            // a PUBREC with an error ends the flow, no PUBREL follows
+            if u8::from(reason_code) >= 0x80 {
+                session.complete_publish(packet_id);
             }
             Ok((
                 false,
-                Some(PubrecPacket::new(packet_id, PubrecReasonCode::Success).into()),
+                Some(
+                    AckBuilder::new()
+                        .pubrec(session, packet_id, reason_code)
+                        .into(),
+                ),
             ))
         }
     }
 }
 
-pub(super) async fn deliver_publish_message<'a, S>(
+/// What became of a forwarded publish, a QoS 1 publish reports it in PUBACK
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Forwarded {
+    Delivered,
+    NoMatchingSubscribers,
+}
+
+pub(super) async fn deliver_publish_message<S>(
     session: &mut Session,
     packet: PublishMessage,
-    global: &'a GlobalState,
-    storage: &'a Storage<S>,
-) -> io::Result<()>
+    global: &GlobalState<S>,
+) -> Result<Forwarded, Error>
 where
     S: MessageStore + RetainMessageStore + TopicStore,
 {
     debug!(
         r#"client#{} dispatch publish message:
 topic name : {:?}
-   payload : {:?}
+   payload : [{} bytes]
 properties : {:?}
      flags : qos={:?}, retain={}, dup={}"#,
         session.client_id(),
         packet.topic_name(),
-        packet.payload(),
+        packet.payload().len(),
         packet.properties(),
         packet.qos(),
         packet.retain(),
@@ -142,128 +268,90 @@ properties : {:?}
 
     if packet.retain() {
         if packet.payload().is_empty() {
-            storage.remove(packet.topic_name()).await?;
+            global.storage.remove(packet.topic_name()).await?;
         } else {
-            storage
+            global
+                .storage
                 .insert((session.client_id(), &packet).into())
                 .await?;
         }
     }
 
-    // let (mut senders, shared_subscribes) = storage.match_topic(packet.topic_name()).await?;
-
-    // // TODO: config: shared subscription available
-    // for (_group_name, shared_clients) in shared_subscribes {
-    //     // TODO: config: shared subscription mode
-    //     // TODO: shared subscription index by group_name?
-    //     for (client_id, qos) in shared_clients {
-    //         senders.push((client_id.to_owned(), qos));
-    //         break;
-    //     }
-    // }
-
-    // for (receiver_client_id, qos) in senders {
-    //     if let Some(sender) = global.get_deliver(&receiver_client_id) {
-    //         if sender.is_closed() {
-    //             warn!("client#{:?} deliver channel is closed", receiver_client_id,);
-    //             continue;
-    //         }
-    //         if let Err(err) = sender
-    //             .send(DeliverMessage::Publish(qos, Box::new(packet.clone())))
-    //             .await
-    //         {
-    //             error!("{} send publish: {}", receiver_client_id, err,)
-    //         }
-    //     }
-    // }
+    let deliveries = global.deliveries(global.storage.match_topic(packet.topic_name()).await?);
+    if deliveries.is_empty() {
+        return Ok(Forwarded::NoMatchingSubscribers);
+    }
+    global.fan_out(deliveries, packet).await;
 
-    Ok(())
+    Ok(Forwarded::Delivered)
 }
 
-pub(super) async fn handle_pubrel<'a, S>(
-    session: &mut Session,
-    packet_id: u16,
-    storage: &'a Storage<S>,
-) -> io::Result<PubcompPacket>
+struct PubrelCompletion<'a, W, E, S> {
+    writer: &'a mut FramedWrite<W, E>,
+    session: &'a mut Session,
+    global: &'a GlobalState<S>,
+}
+
+impl<W, E, S> Qos2Completion for PubrelCompletion<'_, W, E, S>
 where
+    W: AsyncWrite + Unpin,
+    E: Encoder<VariablePacket, Error = io::Error>,
     S: MessageStore + RetainMessageStore + TopicStore,
 {
-    debug!(
-        "client#{} received a pubrel packet, id : {}",
-        session.client_id(),
-        packet_id
-    );
+    type Error = Error;
+
+    async fn release(&mut self, packet_id: u16) -> Result<Option<PublishMessage>, Error> {
+        self.global
+            .storage
+            .pubrel(self.session.client_id(), packet_id)
+            .await
+            .map_err(Error::Storage)
+    }
 
-    storage.pubrel(session.client_id(), packet_id).await?;
+    async fn send_pubcomp(&mut self, packet_id: u16) -> Result<(), Error> {
+        self.session.complete_publish(packet_id);
+        let pkt = AckBuilder::new().pubcomp(self.session, packet_id, PubcompReasonCode::Success);
+        debug!("write pubcomp packet: {}", pkt);
+        self.writer.send(pkt.into()).await?;
+        Ok(())
+    }
 
-    Ok(PubcompPacket::new(packet_id, PubcompReasonCode::Success))
+    async fn forward(&mut self, message: PublishMessage) -> Result<(), Error> {
+        deliver_publish_message(self.session, message, self.global).await?;
+        Ok(())
+    }
 }
 
-pub(super) async fn handle_deliver_publish<'a, S>(
+pub(super) async fn handle_pubrel<W, E, S>(
+    writer: &mut FramedWrite<W, E>,
     session: &mut Session,
-    subscribe_qos: QualityOfService,
-    // retain_as_published: bool,
-    message: &PublishMessage,
-    storage: &'a Storage<S>,
-) -> io::Result<PublishPacket>
+    packet_id: u16,
+    global: &GlobalState<S>,
+) -> Result<(), Error>
 where
+    W: AsyncWrite + Unpin,
+    E: Encoder<VariablePacket, Error = io::Error>,
     S: MessageStore + RetainMessageStore + TopicStore,
 {
     debug!(
-        r#"client#{} receive deliver publish message:
-topic name : {:?}
-   payload : {:?}
-properties : {:?}
-     flags : qos={:?}, subscribe_qos={:?}, retain={}, dup={}"#,
+        "client#{} received a pubrel packet, id : {}",
         session.client_id(),
-        message.topic_name(),
-        message.payload(),
-        message.properties(),
-        message.qos(),
-        subscribe_qos,
-        message.retain(),
-        message.dup(),
+        packet_id
     );
 
-    // let subscription_identifiers =
-    //     if let Some(sub) = session.subscribes.get(message.subscribe_filter) {
-    //         sub.id
-    //     } else {
-    //         // the client already unsubscribed.
-    //         return None;
-    //     };
-
-    let properties = message.properties().cloned().unwrap_or_default();
-    // TODO: add_subscription_identifiers?
-    // for identifier in subscription_identifiers {
-    //     properties.add_subscription_identifier(identifier);
-    // }
-
-    let final_qos = cmp::min(subscribe_qos, message.qos());
-    let (packet_id, qos) = match final_qos {
-        QualityOfService::Level0 => (None, QoSWithPacketIdentifier::Level0),
-        QualityOfService::Level1 => {
-            let packet_id = session.incr_server_packet_id();
-            (Some(packet_id), QoSWithPacketIdentifier::Level1(packet_id))
-        }
-        QualityOfService::Level2 => {
-            let packet_id = session.incr_server_packet_id();
-            (Some(packet_id), QoSWithPacketIdentifier::Level2(packet_id))
-        }
+    let mut completion = PubrelCompletion {
+        writer,
+        session,
+        global,
     };
-
-    let mut packet = PublishPacket::new(message.topic_name().to_owned(), qos, message.payload());
-    packet.set_dup(message.dup());
-    packet.set_properties(properties);
-
-    Ok(packet)
+    complete_qos2(&mut completion, packet_id).await
 }
 
-pub(super) async fn handle_puback<'a, S>(
+pub(super) async fn handle_puback<S>(
     session: &mut Session,
     packet_id: u16,
-    storage: &'a Storage<S>,
-) -> io::Result<()>
+    storage: &Storage<S>,
+) -> Result<(), Error>
 where
     S: MessageStore + RetainMessageStore + TopicStore,
 {
@@ -278,11 +366,11 @@ where
     Ok(())
 }
 
-pub(super) async fn handle_pubrec<'a, S>(
+pub(super) async fn handle_pubrec<S>(
     session: &mut Session,
     packet_id: u16,
-    storage: &'a Storage<S>,
-) -> io::Result<PubrelPacket>
+    storage: &Storage<S>,
+) -> Result<PubrelPacket, Error>
 where
     S: MessageStore + RetainMessageStore + TopicStore,
 {
@@ -304,11 +392,11 @@ where
     }
 }
 
-pub(super) async fn handle_pubcomp<'a, S>(
+pub(super) async fn handle_pubcomp<S>(
     session: &mut Session,
     packet_id: u16,
-    storage: &'a Storage<S>,
-) -> io::Result<()>
+    storage: &Storage<S>,
+) -> Result<(), Error>
 where
     S: MessageStore + RetainMessageStore + TopicStore,
 {
@@ -323,11 +411,10 @@ where
     Ok(())
 }
 
-pub(super) async fn handle_will<'a, S>(
+pub(super) async fn handle_will<S>(
     session: &mut Session,
-    global: &'a GlobalState,
-    storage: &'a Storage<S>,
-) -> io::Result<()>
+    global: &GlobalState<S>,
+) -> Result<(), Error>
 where
     S: MessageStore + RetainMessageStore + TopicStore,
 {
@@ -343,38 +430,92 @@ server side disconnected : {}
     );
 
     if let Some(last_will) = session.take_last_will() {
-        deliver_publish_message(session, last_will.into(), global, storage).await?;
+        let mut message: PublishMessage = last_will.into();
+        if global
+            .authorize_publish(session.client_id(), &mut message)
+            .await
+            .is_accepted()
+        {
+            deliver_publish_message(session, message, global).await?;
+        }
         session.clear_last_will();
     }
     Ok(())
 }
 
-pub(crate) async fn retrieve_all_pending_messages<'a, S>(
-    client_id: &str,
-    storage: &'a Storage<S>,
-) -> io::Result<Vec<VariablePacket>>
-where
-    S: MessageStore + RetainMessageStore + TopicStore,
-{
-    let mut packets = Vec::new();
-    let ret = storage.get_all_pending_messages(client_id).await?;
-    if let Some(messages) = ret {
-        for (packet_id, msg) in messages {
-            match msg.pubrec_at() {
-                Some(_) => {
-                    packets.push(PubcompPacket::new(packet_id, PubcompReasonCode::Success).into());
-                }
-                None => {
-                    let topic_name = msg.message().topic_name().to_owned();
-                    let mut packet =
-                        PublishPacket::new(topic_name, msg.qos(), msg.message().payload());
-                    packet.set_dup(msg.message().dup());
-
-                    packets.push(packet.into());
+#[cfg(test)]
+mod tests {
+    use mqtt_codec_kit::common::TopicName;
+
+    use super::*;
+    use crate::store::memory_storage;
+
+    #[tokio::test]
+    async fn reserved_topics_are_refused() {
+        for (prefixes, topic, refused) in [
+            (None, "$SYS/brokers", true),
+            (None, "$share/g/a", true),
+            (None, "a/b", false),
+            (Some("private/"), "private/a", true),
+            (Some("private/"), "$SYS/brokers", false),
+        ] {
+            let mut global = GlobalState::new(memory_storage());
+            if let Some(prefix) = prefixes {
+                global = global.with_reserved_topic_prefixes([prefix]);
+            }
+            let mut session = Session::new("c1".to_owned(), false, 32);
+            let packet = PublishPacket::new(
+                TopicName::new(topic).unwrap(),
+                QoSWithPacketIdentifier::Level0,
+                b"m".to_vec(),
+            );
+            match handle_publish(&mut session, &packet, &global).await {
+                Ok((true, Some(VariablePacket::DisconnectPacket(disconnect)))) => {
+                    assert!(refused, "{topic}");
+                    assert_eq!(
+                        disconnect.reason_code(),
+                        DisconnectReasonCode::TopicNameInvalid
+                    );
                 }
+                Ok((false, None)) => assert!(!refused, "{topic}"),
+                other => panic!("unexpected {other:?}"),
             }
         }
     }
 
-    Ok(packets)
+    #[tokio::test]
+    async fn error_pubrec_frees_its_receive_slot() {
+        let global = GlobalState::new(memory_storage()).with_max_payload_size(4);
+        let mut session = Session::new("c1".to_owned(), false, 1);
+        let publish = |packet_id, payload: &str| {
+            PublishPacket::new(
+                TopicName::new("a/b").unwrap(),
+                QoSWithPacketIdentifier::Level2(packet_id),
+                payload,
+            )
+        };
+
+        // refused for its size, the flow ends with the PUBREC
+        match handle_publish(&mut session, &publish(1, "too large"), &global).await {
+            Ok((false, Some(VariablePacket::PubrecPacket(pubrec)))) => assert_eq!(
+                pubrec.reason_code(),
+                PubrecReasonCode::ImplementationSpecificError
+            ),
+            other => panic!("unexpected {other:?}"),
+        }
+        // accepted, the slot is held until PUBREL
+        match handle_publish(&mut session, &publish(2, "m"), &global).await {
+            Ok((false, Some(VariablePacket::PubrecPacket(pubrec)))) => {
+                assert!(u8::from(pubrec.reason_code()) < 0x80)
+            }
+            other => panic!("unexpected {other:?}"),
+        }
+        match handle_publish(&mut session, &publish(3, "m"), &global).await {
+            Ok((true, Some(VariablePacket::DisconnectPacket(disconnect)))) => assert_eq!(
+                disconnect.reason_code(),
+                DisconnectReasonCode::ReceiveMaximumExceeded
+            ),
+            other => panic!("unexpected {other:?}"),
+        }
+    }
 }
@@ -1,98 +1,173 @@
-use std::{io, time::Duration};
+use std::{io, net::SocketAddr, sync::Arc, time::Duration};
 
 use futures::{SinkExt as _, StreamExt as _};
 use kanal::{bounded_async, AsyncReceiver, AsyncSender};
-use mqtt_codec_kit::v5::{
-    control::DisconnectReasonCode,
-    packet::{
-        DisconnectPacket, MqttDecoder, MqttEncoder, PingrespPacket, VariablePacket,
-        VariablePacketError,
+use mqtt_codec_kit::{
+    common::ProtocolLevel,
+    v5::{
+        control::{ConnectReasonCode, DisconnectReasonCode},
+        packet::{
+            ConnackPacket, DisconnectPacket, MqttDecoder, MqttEncoder, PingrespPacket,
+            VariablePacket, VariablePacketError,
+        },
     },
 };
 use tokio::{
     io::{AsyncRead, AsyncWrite},
-    time::{interval_at, Instant},
+    time,
 };
 use tokio_util::codec::{Decoder, Encoder, FramedRead, FramedWrite};
 
 use crate::{
     debug, error, info,
-    protocols::ProtocolSessionState,
-    server::state::{DeliverMessage, GlobalState},
-    store::{message::MessageStore, retain::RetainMessageStore, topic::TopicStore, Storage},
+    instrument::InstrumentExt as _,
+    protocols::{
+        delivery::DeliveryCore,
+        flush::Flusher,
+        malformed::{DecodeError as _, SkipMalformed},
+        mount::Mounted,
+        Error, ProtocolSessionState,
+    },
+    server::{
+        audit::AuditEvent,
+        config::MountPoint,
+        metrics::Metrics,
+        slow_consumer::WriteTimeout,
+        state::{DeliverMessage, GlobalState, KickReason},
+        supervisor::log_panic,
+        timer::TimerKind,
+    },
+    store::{
+        message::MessageStore,
+        queue::{DeliverQueue, DELIVER_BATCH_SIZE},
+        retain::RetainMessageStore,
+        topic::TopicStore,
+    },
     warn,
 };
 
 use super::{
+    auth::authenticate,
+    common::{build_error_disconnect, build_redirect_disconnect},
     connect::{handle_connect, handle_disconnect},
     publish::{
-        handle_deliver_publish, handle_puback, handle_pubcomp, handle_publish, handle_pubrec,
-        handle_pubrel, handle_will, retrieve_all_pending_messages,
+        handle_puback, handle_pubcomp, handle_publish, handle_pubrec, handle_pubrel, handle_will,
     },
     session::Session,
     subscribe::{handle_subscribe, handle_unsubscribe, SubscribeAck},
+    topic_alias::TopicAliases,
 };
 
-async fn read_from_client<T, D>(mut reader: FramedRead<T, D>, sender: AsyncSender<VariablePacket>)
-where
+// how often the backlog is checked against the slow consumer limits
+const BACKLOG_CHECK_INTERVAL: Duration = Duration::from_millis(500);
+// how long the messages held back by the flush policy may take to go out once the loop ends
+const FINAL_FLUSH_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Forwards the packets read and a malformed packet, which ends the connection, yielding after
+/// `batch_size` packets in a row
+async fn read_from_client<T, D>(
+    mut reader: FramedRead<T, D>,
+    sender: AsyncSender<Result<VariablePacket, VariablePacketError>>,
+    batch_size: usize,
+    metrics: Arc<Metrics>,
+) where
     T: AsyncRead + Unpin,
     D: Decoder<Item = VariablePacket, Error = VariablePacketError>,
 {
+    let mut read = 0;
     loop {
         match reader.next().await {
             None => {
                 info!("client closed");
                 break;
             }
+            Some(Err(e)) if e.is_malformed() => {
+                let _ = sender.send(Err(e)).await;
+                break;
+            }
             Some(Err(e)) => {
                 warn!("read from client: {}", e);
                 break;
             }
             Some(Ok(packet)) => {
-                if let Err(err) = sender.send(packet).await {
+                if sender.is_full() {
+                    metrics.record_read_channel_full();
+                }
+                if let Err(err) = sender.send(Ok(packet)).await {
                     warn!("receiver closed: {err}");
                     break;
                 }
+                read += 1;
+                if read == batch_size {
+                    read = 0;
+                    tokio::task::yield_now().await;
+                }
             }
         }
     }
 }
 
-async fn remove_client<'a, S>(
-    session: &Session,
-    global: &'a GlobalState,
-    storage: &'a Storage<S>,
-) -> io::Result<()>
+/// Removes a clean session with its subscriptions and messages, a persistent one is kept until
+/// it expires
+async fn remove_client<S>(session: &Session, global: &GlobalState<S>) -> Result<(), Error>
 where
     S: MessageStore + RetainMessageStore + TopicStore,
 {
-    global.remove_client(session.client_id());
     if session.clean_session() {
+        global.remove_client(session.client_id());
         for topic_filter in session.subscriptions().keys() {
-            storage
+            global
+                .storage
                 .unsubscribe(session.client_id(), topic_filter)
                 .await?;
         }
-        storage.clear_all(session.client_id()).await?;
+        global.storage.clear_all(session.client_id()).await?;
+        global.remove_session(session.client_id()).await;
     }
 
     Ok(())
 }
 
-pub(super) async fn handle_read_packet<'a, W, E, S>(
+// the error is counted in the metrics and its code kept for the audit log. The stored state of
+// the client may be inconsistent after a storage failure, the client is told so and reconnects
+async fn disconnect_on_error<T, E>(
+    writer: &mut FramedWrite<T, E>,
+    session: &mut Session,
+    err: &Error,
+    metrics: &Metrics,
+) where
+    T: AsyncWrite + Unpin,
+    E: Encoder<VariablePacket, Error = io::Error>,
+{
+    metrics.record_error(err.category());
+    session.set_error_code(err.code());
+    if let Error::Storage(err) = err {
+        let pkt = build_error_disconnect(
+            session,
+            DisconnectReasonCode::ImplementationSpecificError,
+            format!("storage error: {err}"),
+        );
+        if let Err(err) = writer.send(pkt.into()).await {
+            error!("write disconnect packet failed: {err}");
+        }
+    }
+}
+
+pub(super) async fn handle_read_packet<W, E, S>(
     writer: &mut FramedWrite<W, E>,
     session: &mut Session,
     packet: VariablePacket,
-    global: &'a GlobalState,
-    storage: &'a Storage<S>,
-) -> io::Result<bool>
+    delivery: &mut DeliveryCore<Session>,
+    deliver_queue: &DeliverQueue,
+    global: &GlobalState<S>,
+) -> Result<bool, Error>
 where
     W: AsyncWrite + Unpin,
     E: Encoder<VariablePacket, Error = io::Error>,
     S: MessageStore + RetainMessageStore + TopicStore,
 {
     debug!(
-        r#"client#{} receive mqtt client incoming message: {:?}"#,
+        r#"client#{} receive mqtt client incoming message: {}"#,
         session.client_id(),
         packet,
     );
@@ -101,92 +176,97 @@ where
     match packet {
         VariablePacket::PingreqPacket(_packet) => {
             let pkt = PingrespPacket::new();
-            debug!("write pingresp packet: {:?}", pkt);
+            debug!("write pingresp packet: {}", pkt);
             writer.send(pkt.into()).await?;
         }
         VariablePacket::PublishPacket(packet) => {
-            let (stop, ack) = handle_publish(session, &packet, global, storage).await?;
+            let (stop, ack) = handle_publish(session, &packet, global).await?;
             if let Some(pkt) = ack {
-                debug!("write puback packet: {:?}", pkt);
+                debug!("write puback packet: {}", pkt);
                 writer.send(pkt).await?;
             }
             should_stop = stop;
         }
         VariablePacket::PubrelPacket(packet) => {
-            let pkt = handle_pubrel(session, packet.packet_identifier(), storage).await?;
-            debug!("write pubcomp packet: {:?}", pkt);
-            writer.send(pkt.into()).await?;
+            handle_pubrel(writer, session, packet.packet_identifier(), global).await?;
         }
         VariablePacket::PubackPacket(packet) => {
-            handle_puback(session, packet.packet_identifier(), storage).await?;
+            handle_puback(session, packet.packet_identifier(), &global.storage).await?;
+            if delivery.complete(packet.packet_identifier()) {
+                deliver_queue.wake();
+            }
         }
         VariablePacket::PubrecPacket(packet) => {
-            let pkt = handle_pubrec(session, packet.packet_identifier(), storage).await?;
-            debug!("write pubrel packet: {:?}", pkt);
+            let pkt = handle_pubrec(session, packet.packet_identifier(), &global.storage).await?;
+            debug!("write pubrel packet: {}", pkt);
             writer.send(pkt.into()).await?;
         }
         VariablePacket::SubscribePacket(packet) => {
-            let ret = handle_subscribe(session, packet, storage).await?;
+            let ret = handle_subscribe(session, packet, deliver_queue, global).await?;
+            if !session.clean_session() {
+                global.save_session(session.to_stored()).await;
+            }
             match ret {
-                SubscribeAck::Success(packets) => {
-                    debug!("write suback packets: {:?}", packets);
-                    for pkt in packets {
-                        writer.send(pkt).await?;
-                    }
+                SubscribeAck::Success(pkt) => {
+                    debug!("write suback packet: {}", pkt);
+                    writer.send(pkt.into()).await?;
                 }
                 SubscribeAck::Disconnect(pkt) => {
-                    debug!("write disconnect packet: {:?}", pkt);
+                    debug!("write disconnect packet: {}", pkt);
                     writer.send(pkt.into()).await?;
                     should_stop = true;
                 }
             }
         }
         VariablePacket::PubcompPacket(packet) => {
-            handle_pubcomp(session, packet.packet_identifier(), storage).await?;
+            handle_pubcomp(session, packet.packet_identifier(), &global.storage).await?;
+            if delivery.complete(packet.packet_identifier()) {
+                deliver_queue.wake();
+            }
         }
         VariablePacket::UnsubscribePacket(packet) => {
-            let pkt = handle_unsubscribe(session, storage, &packet).await?;
-            debug!("write unsuback packet: {:?}", pkt);
+            let pkt = handle_unsubscribe(session, &global.storage, &packet).await?;
+            if !session.clean_session() {
+                global.save_session(session.to_stored()).await;
+            }
+            debug!("write unsuback packet: {}", pkt);
             writer.send(pkt.into()).await?;
         }
         VariablePacket::DisconnectPacket(packet) => {
-            if let Some(pkt) = handle_disconnect(session, packet).await {
-                debug!("write disconnect packet: {:?}", pkt);
+            if let Some(pkt) = handle_disconnect(session, packet, global).await {
+                debug!("write disconnect packet: {}", pkt);
                 writer.send(pkt.into()).await?;
             }
             should_stop = true;
         }
         VariablePacket::AuthPacket(_packet) => {
-            unimplemented!()
+            // re-authentication is not supported
+            let pkt = build_error_disconnect(
+                session,
+                DisconnectReasonCode::ProtocolError,
+                "re-authentication is not supported",
+            );
+            writer.send(pkt.into()).await?;
+            should_stop = true;
         }
         _ => {
-            debug!("unsupported packet: {:?}", packet);
+            debug!("unsupported packet: {}", packet);
             should_stop = true;
         }
     };
     Ok(should_stop)
 }
 
-pub(super) async fn receive_deliver_message<'a, S>(
+pub(super) async fn receive_deliver_message<S>(
     session: &mut Session,
     packet: DeliverMessage,
-    global: &'a GlobalState,
-    storage: &'a Storage<S>,
-) -> io::Result<(bool, Option<VariablePacket>)>
+    global: &GlobalState<S>,
+) -> Result<(bool, Option<VariablePacket>), Error>
 where
     S: MessageStore + RetainMessageStore + TopicStore,
 {
     let mut should_stop = false;
     let resp = match packet {
-        DeliverMessage::Publish(topic_filter, subscribe_qos, packet) => {
-            let resp = handle_deliver_publish(session, subscribe_qos, &packet, storage).await?;
-            if session.disconnected() {
-                None
-            } else {
-                Some(resp.into())
-            }
-        }
-
         DeliverMessage::Online(sender) => {
             debug!(
                 "handle deliver client#{} receive new client online",
@@ -203,7 +283,8 @@ where
                 );
             }
 
-            remove_client(session, global, storage).await?;
+            // the new connection resumes the stored state or deletes it, see `handle_connect`
+            global.remove_client(session.client_id());
 
             should_stop = true;
 
@@ -219,36 +300,95 @@ where
                 session.client_id(),
                 reason,
             );
+            global.audit(
+                session.remote_addr(),
+                AuditEvent::Kick {
+                    client_id: session.client_id(),
+                    reason: &reason.to_string(),
+                },
+            );
 
             if session.disconnected() && !session.clean_session() {
                 None
             } else {
-                remove_client(session, global, storage).await?;
+                session.set_kicked();
+                remove_client(session, global).await?;
 
                 should_stop = true;
 
-                Some(DisconnectPacket::new(DisconnectReasonCode::AdministrativeAction).into())
+                let disconnect_packet = match reason {
+                    KickReason::FromAdmin(reason) => build_error_disconnect(
+                        session,
+                        DisconnectReasonCode::AdministrativeAction,
+                        reason,
+                    ),
+                    KickReason::Redirected(redirection) => {
+                        build_redirect_disconnect(session, &redirection)
+                    }
+                };
+                Some(disconnect_packet.into())
+            }
+        }
+        DeliverMessage::Timeout(TimerKind::KeepAlive, token)
+            if token == session.timer_token() && !session.disconnected() =>
+        {
+            let keep_alive_timeout = session.keep_alive_timeout();
+            let elapsed = session.last_packet_at().elapsed();
+            if elapsed > keep_alive_timeout {
+                debug!(
+                    "handle deliver client#{} keep alive timeout",
+                    session.client_id(),
+                );
+                should_stop = true;
+                Some(DisconnectPacket::new(DisconnectReasonCode::KeepAliveTimeout).into())
+            } else {
+                global.schedule_timer(
+                    session.client_id(),
+                    token,
+                    TimerKind::KeepAlive,
+                    keep_alive_timeout - elapsed,
+                );
+                None
             }
         }
+        DeliverMessage::Timeout(TimerKind::SessionExpiry, token)
+            if token == session.timer_token() && session.disconnected() =>
+        {
+            debug!(
+                "handle deliver client#{} session expired",
+                session.client_id(),
+            );
+            global.remove_client(session.client_id());
+            for topic_filter in session.subscriptions().keys() {
+                global
+                    .storage
+                    .unsubscribe(session.client_id(), topic_filter)
+                    .await?;
+            }
+            global.storage.clear_all(session.client_id()).await?;
+            global.remove_session(session.client_id()).await;
+            should_stop = true;
+            None
+        }
+        DeliverMessage::Timeout(..) => None,
     };
     Ok((should_stop, resp))
 }
 
-pub(super) async fn handle_deliver_packet<'a, T, E, S>(
+pub(super) async fn handle_deliver_packet<T, E, S>(
     writer: &mut FramedWrite<T, E>,
     session: &mut Session,
     packet: DeliverMessage,
-    global: &'a GlobalState,
-    storage: &'a Storage<S>,
-) -> io::Result<bool>
+    global: &GlobalState<S>,
+) -> Result<bool, Error>
 where
     T: AsyncWrite + Unpin,
     E: Encoder<VariablePacket, Error = io::Error>,
     S: MessageStore + RetainMessageStore + TopicStore,
 {
-    let (should_stop, resp) = receive_deliver_message(session, packet, global, storage).await?;
+    let (should_stop, resp) = receive_deliver_message(session, packet, global).await?;
     if let Some(packet) = resp {
-        debug!("write packet: {:?}", packet);
+        debug!("write packet: {}", packet);
         if let Err(err) = writer.send(packet).await {
             error!("write packet failed: {err}");
             return Ok(true);
@@ -258,12 +398,12 @@ where
     Ok(should_stop)
 }
 
-pub(super) async fn handle_clean_session<'a, S>(
+pub(super) async fn handle_clean_session<S>(
     mut session: Session,
     deliver_rx: AsyncReceiver<DeliverMessage>,
-    global: &'a GlobalState,
-    storage: &'a Storage<S>,
-) -> io::Result<()>
+    deliver_queue: Arc<DeliverQueue>,
+    global: &GlobalState<S>,
+) -> Result<(), Error>
 where
     S: MessageStore + RetainMessageStore + TopicStore,
 {
@@ -280,48 +420,54 @@ session expiry : {}"#,
     if !session.disconnected() {
         session.set_server_disconnected();
     }
+    let event = AuditEvent::Disconnect {
+        client_id: session.client_id(),
+        reason: if session.client_disconnected() {
+            "client disconnected"
+        } else {
+            session.disconnect_reason().unwrap_or("connection closed")
+        },
+        code: session.error_code(),
+    };
+    global.audit(session.remote_addr(), event);
+    global.presence_changed(session.remote_addr(), event).await;
 
-    if !session.client_disconnected() {
-        handle_will(&mut session, global, storage).await?;
+    if session.kicked() && !global.will_on_kick() {
+        session.clear_last_will();
+    } else if !session.client_disconnected() {
+        handle_will(&mut session, global).await?;
     }
 
     if session.session_expiry_interval() > 0 {
-        let dur = Duration::from_secs(session.session_expiry_interval() as u64);
-        let mut tick = interval_at(Instant::now() + dur, dur);
-
-        loop {
-            tokio::select! {
-                out = deliver_rx.recv() => {
-                    match out {
-                        Ok(p) => {
-                            let (stop, _) = receive_deliver_message(&mut session, p, global, storage).await?;
-                            if stop {
-                                break;
-                            }
-                        },
-                        Err(err) => {
-                            error!("handle deliver failed: {err}");
-                            break;
-                        },
+        global.schedule_timer(
+            session.client_id(),
+            session.timer_token(),
+            TimerKind::SessionExpiry,
+            Duration::from_secs(session.session_expiry_interval() as u64),
+        );
+    } else if session.clean_session() {
+        remove_client(&session, global).await?;
+        return Ok(());
+    }
+    global.set_client_offline(session.client_id(), session.timer_token());
+    global.save_session(session.to_stored()).await;
+
+    loop {
+        tokio::select! {
+            packet = deliver_rx.recv() => match packet {
+                Ok(p) => {
+                    let (stop, _) = receive_deliver_message(&mut session, p, global).await?;
+                    if stop {
+                        break;
                     }
                 }
-                _ = tick.tick() => {
-                    debug!("handle clean session client#{} session expired", session.client_id());
-                    break;
+                Err(_) => break,
+            },
+            _ = deliver_queue.notified() => {
+                for queued in deliver_queue.pop_batch(DELIVER_BATCH_SIZE) {
+                    DeliveryCore::store_offline(&mut session, queued, global).await?;
                 }
-            }
-        }
-    } else {
-        if session.clean_session() {
-            remove_client(&session, global, storage).await?;
-            return Ok(());
-        }
-
-        while let Ok(p) = deliver_rx.recv().await {
-            let (stop, _) = receive_deliver_message(&mut session, p, global, storage).await?;
-            if stop {
-                break;
-            }
+            },
         }
     }
     Ok(())
@@ -330,164 +476,286 @@ session expiry : {}"#,
 async fn write_to_client<T, E, S>(
     mut session: Session,
     mut writer: FramedWrite<T, E>,
-    incoming_rx: AsyncReceiver<VariablePacket>,
+    incoming_rx: AsyncReceiver<Result<VariablePacket, VariablePacketError>>,
     deliver_rx: AsyncReceiver<DeliverMessage>,
-    global: &'static GlobalState,
-    storage: &'static Storage<S>,
+    deliver_queue: Arc<DeliverQueue>,
+    global: Arc<GlobalState<S>>,
 ) where
     T: AsyncWrite + Unpin,
     E: Encoder<VariablePacket, Error = io::Error>,
-    S: MessageStore + RetainMessageStore + TopicStore,
+    S: MessageStore + RetainMessageStore + TopicStore + 'static,
 {
     if session.keep_alive() > 0 {
-        let half_interval = Duration::from_millis(session.keep_alive() as u64 * 500);
-        let mut keep_alive_tick = interval_at(Instant::now() + half_interval, half_interval);
-        let keep_alive_timeout = half_interval * 3;
-        loop {
-            tokio::select! {
-                packet = incoming_rx.recv() => match packet {
-                    Ok(p) => match handle_read_packet(&mut writer, &mut session, p, global, storage).await {
-                        Ok(true) => break,
-                        Ok(false) => continue,
-                        Err(err) => {
-                            error!("handle incoming failed: {err}");
-                            break;
-                        },
-                    }
-                    Err(err) => {
-                        info!("client#{} receive channel: {err}", session.client_id());
-                        break;
-                    }
-                },
-                packet = deliver_rx.recv() => match packet {
-                    Ok(p) => match handle_deliver_packet(&mut writer, &mut session, p, global, storage).await {
-                        Ok(should_stop) => if should_stop {
-                            break;
-                        },
-                        Err(err) => {
-                            error!("handle deliver failed: {err}");
-                            break;
-                        },
+        global.schedule_timer(
+            session.client_id(),
+            session.timer_token(),
+            TimerKind::KeepAlive,
+            session.keep_alive_timeout(),
+        );
+    }
+    let mut delivery = DeliveryCore::new(session.receive_maximum() as usize);
+    if let Some(info) = session.info() {
+        delivery.inflight_mut().report_to(info.clone());
+    }
+    if let Err(err) = delivery.load_pending(&session, &global).await {
+        warn!("load pending messages failed: {err}");
+    }
+    // pending messages of the previous connection go out before anything queued since
+    deliver_queue.wake();
+    let slow_consumer = *global.slow_consumer();
+    let mut backlog_check = time::interval(BACKLOG_CHECK_INTERVAL);
+    let mut flusher = Flusher::new(global.flush_policy());
+    loop {
+        tokio::select! {
+            packet = incoming_rx.recv() => match packet {
+                Ok(Err(err)) => {
+                    warn!("client#{} sent a malformed packet: {err}", session.client_id());
+                    session.set_server_disconnected_for("malformed packet");
+                    let reason_code = match err {
+                        VariablePacketError::PacketTooLarge(..) => DisconnectReasonCode::PacketTooLarge,
+                        _ => DisconnectReasonCode::MalformedPacket,
+                    };
+                    let pkt = build_error_disconnect(&mut session, reason_code, err.reason());
+                    if let Err(err) = writer.send(pkt.into()).await {
+                        error!("write disconnect packet failed: {err}");
                     }
+                    break;
+                }
+                Ok(Ok(p)) => match handle_read_packet(&mut writer, &mut session, p, &mut delivery, &deliver_queue, &global).await {
+                    Ok(true) => break,
+                    Ok(false) => continue,
                     Err(err) => {
-                        info!("client#{} deliver channel: {err}", session.client_id());
+                        error!("handle incoming failed [{}#{}]: {err}", err.category(), err.code());
+                        disconnect_on_error(&mut writer, &mut session, &err, global.metrics()).await;
                         break;
-                    }
-                },
-                _ = keep_alive_tick.tick() => {
-                    if session.last_packet_at().elapsed() > keep_alive_timeout {
+                    },
+                }
+                Err(err) => {
+                    info!("client#{} receive channel: {err}", session.client_id());
+                    break;
+                }
+            },
+            packet = deliver_rx.recv() => match packet {
+                Ok(p) => match handle_deliver_packet(&mut writer, &mut session, p, &global).await {
+                    Ok(should_stop) => if should_stop {
                         break;
-                    }
-                },
-            }
-        }
-    } else {
-        loop {
-            tokio::select! {
-                packet = incoming_rx.recv() => match packet {
-                    Ok(p) => match handle_read_packet(&mut writer, &mut session, p, global, storage).await {
-                        Ok(true) => break,
-                        Ok(false) => continue,
-                        Err(err) => {
-                            error!("handle incoming failed: {err}");
-                            break;
-                        },
-                    }
+                    },
                     Err(err) => {
-                        info!("client#{} receive channel: {err}", session.client_id());
+                        error!("handle deliver failed [{}#{}]: {err}", err.category(), err.code());
+                        disconnect_on_error(&mut writer, &mut session, &err, global.metrics()).await;
                         break;
+                    },
+                }
+                Err(err) => {
+                    info!("client#{} deliver channel: {err}", session.client_id());
+                    break;
+                }
+            },
+            _ = backlog_check.tick(), if slow_consumer.checks_backlog() => {
+                if let Some(slow) = slow_consumer.check(session.client_id(), &deliver_queue, &global.storage).await {
+                    warn!("client#{} is a slow consumer: {slow}", session.client_id());
+                    global.metrics().record_slow_consumer_disconnect();
+                    session.set_server_disconnected_for("slow consumer");
+                    let pkt = build_error_disconnect(
+                        &mut session,
+                        DisconnectReasonCode::QuotaExceeded,
+                        slow.to_string(),
+                    );
+                    if let Err(err) = writer.send(pkt.into()).await {
+                        error!("write disconnect packet failed: {err}");
                     }
-                },
-                packet = deliver_rx.recv() => match packet {
-                    Ok(p) => match handle_deliver_packet(&mut writer, &mut session, p, global, storage).await {
-                        Ok(should_stop) => if should_stop {
-                            break;
-                        },
-                        Err(err) => {
-                            error!("handle deliver failed: {err}");
-                            break;
-                        },
+                    break;
+                }
+            },
+            _ = deliver_queue.notified() => {
+                let packets = delivery.drain(&mut session, &deliver_queue, &global).await;
+                let mut failed = false;
+                for packet in packets {
+                    debug!("write packet: {}", packet);
+                    let mut written = writer.feed(packet).await;
+                    if written.is_ok() && flusher.written() {
+                        written = writer.flush().await;
+                        flusher.flushed();
                     }
-                    Err(err) => {
-                        info!("client#{} deliver channel: {err}", session.client_id());
+                    if let Err(err) = written {
+                        error!("write packet failed: {err}");
+                        failed = true;
                         break;
                     }
-                },
-            }
+                }
+                if failed {
+                    break;
+                }
+            },
+            _ = flusher.due(), if flusher.has_unflushed() => {
+                if let Err(err) = writer.flush().await {
+                    error!("flush failed: {err}");
+                    break;
+                }
+                flusher.flushed();
+            },
         }
-    };
+    }
+    // the messages the flush policy held back, a client which does not read is not waited for
+    if flusher.has_unflushed() {
+        let _ = time::timeout(FINAL_FLUSH_TIMEOUT, writer.flush()).await;
+    }
 
-    tokio::spawn(async move {
-        if let Err(err) = handle_clean_session(session, deliver_rx, global, storage).await {
-            error!("handle clean session: {err}");
+    tokio::spawn(
+        async move {
+            if let Err(err) =
+                handle_clean_session(session, deliver_rx, deliver_queue, &global).await
+            {
+                error!(
+                    "handle clean session [{}#{}]: {err}",
+                    err.category(),
+                    err.code()
+                );
+                global.metrics().record_error(err.category());
+            }
         }
-    });
+        .in_current_span(),
+    );
 }
 
 pub async fn read_write_loop<R, W, S>(
     reader: R,
     writer: W,
-    global: &'static GlobalState,
-    storage: &'static Storage<S>,
+    remote_addr: Option<SocketAddr>,
+    mount_point: Option<MountPoint>,
+    cert_identity: Option<String>,
+    global: Arc<GlobalState<S>>,
 ) where
     R: AsyncRead + Unpin + Send + 'static,
     W: AsyncWrite + Unpin + Send + 'static,
     S: MessageStore + RetainMessageStore + TopicStore + 'static,
 {
-    let mut frame_reader = FramedRead::new(reader, MqttDecoder::new());
-    let mut frame_writer = FramedWrite::new(writer, MqttEncoder::new());
+    let mut decoder = MqttDecoder::new();
+    if let Some(max) = global.max_remaining_length() {
+        decoder = decoder.with_max_remaining_length(max);
+    }
+    let mut frame_reader = FramedRead::new(
+        reader,
+        SkipMalformed::new(
+            Mounted::new(decoder, mount_point.clone()),
+            global.max_decode_errors(),
+            global.metrics().clone(),
+        ),
+    );
+    let writer = WriteTimeout::new(
+        writer,
+        global.slow_consumer().write_timeout,
+        global.metrics().clone(),
+    );
+    let mut frame_writer = FramedWrite::new(
+        writer,
+        TopicAliases::new(Mounted::new(MqttEncoder::new(), mount_point)),
+    );
 
-    let packet = match frame_reader.next().await {
-        Some(Ok(VariablePacket::ConnectPacket(packet))) => packet,
-        _ => {
+    let packet = match time::timeout(global.handshake_timeout(), frame_reader.next()).await {
+        Ok(Some(Ok(VariablePacket::ConnectPacket(packet)))) => packet,
+        Ok(_) => {
             error!("first packet is not CONNECT packet");
             return;
         }
+        Err(_) => {
+            warn!("no CONNECT packet within {:?}", global.handshake_timeout());
+            return;
+        }
     };
 
-    let (session, deliver_rx) = match handle_connect(packet, global).await {
-        Ok((pkt, session, deliver_rx)) => {
-            if let Err(err) = frame_writer.send(pkt).await {
-                error!("handle connect write connect ack: {err}");
+    let authenticated =
+        match authenticate(&packet, &mut frame_reader, &mut frame_writer, &global).await {
+            Ok(authenticated) => authenticated,
+            Err(pkt) => {
+                if let Err(err) = frame_writer.send(pkt).await {
+                    error!("handle connect write connect ack: {err}");
+                }
                 return;
             }
-            (session, deliver_rx)
+        };
+    // AUTH packets are read by the authentication, anything else is early
+    if global.strict_handshake() && !frame_reader.read_buffer().is_empty() {
+        debug!(
+            "client#{} sent packets before its CONNACK",
+            packet.client_identifier()
+        );
+        let pkt = ConnackPacket::new(false, ConnectReasonCode::ProtocolError);
+        if let Err(err) = frame_writer.send(pkt).await {
+            error!("handle connect write connect ack: {err}");
         }
-        Err(pkt) => {
-            if let Err(err) = frame_writer.send(pkt).await {
-                error!("handle connect write connect ack: {err}");
-            }
-            return;
-        }
-    };
+        return;
+    }
 
-    match retrieve_all_pending_messages(session.client_id(), storage).await {
-        Ok(packets) => {
-            for pkt in packets {
+    let (session, deliver_rx) =
+        match handle_connect(packet, remote_addr, cert_identity, authenticated, &global).await {
+            Ok((pkt, session, deliver_rx)) => {
                 if let Err(err) = frame_writer.send(pkt).await {
-                    error!("write pending packet failed: {err}");
+                    error!("handle connect write connect ack: {err}");
                     return;
                 }
+                frame_writer
+                    .encoder_mut()
+                    .set_max(session.client_topic_alias_max());
+                let event = AuditEvent::Connect {
+                    client_id: session.client_id(),
+                    username: session.username(),
+                    protocol: ProtocolLevel::Version50 as u8,
+                    clean_session: session.clean_start(),
+                };
+                global.audit(remote_addr, event);
+                global.presence_changed(remote_addr, event).await;
+                (session, deliver_rx)
             }
-        }
-        Err(err) => {
-            error!("retrieve pending messages: {err}");
-            return;
-        }
-    }
+            Err(pkt) => {
+                if let Err(err) = frame_writer.send(pkt).await {
+                    error!("handle connect write connect ack: {err}");
+                }
+                return;
+            }
+        };
 
-    let (msg_tx, msg_rx) = bounded_async(8);
-    let mut read_task = tokio::spawn(async move {
-        read_from_client(frame_reader, msg_tx).await;
-    });
+    let Some(deliver_queue) = global.deliver_queue(session.client_id()) else {
+        error!("client#{} deliver queue not found", session.client_id());
+        return;
+    };
+
+    let client_id = session.client_id().to_owned();
+    let token = session.timer_token();
+    let clean_session = session.clean_session();
+    let (msg_tx, msg_rx) = bounded_async(global.channel_config().read_channel_size);
+    let metrics = global.metrics().clone();
+    let mut read_task = tokio::spawn(
+        read_from_client(frame_reader, msg_tx, global.read_batch_size(), metrics).in_current_span(),
+    );
 
-    let mut write_task = tokio::spawn(async move {
-        write_to_client(session, frame_writer, msg_rx, deliver_rx, global, storage).await;
-    });
+    let mut write_task = tokio::spawn(
+        write_to_client(
+            session,
+            frame_writer,
+            msg_rx,
+            deliver_rx,
+            deliver_queue,
+            global.clone(),
+        )
+        .in_current_span(),
+    );
 
-    if tokio::try_join!(&mut read_task, &mut write_task).is_err() {
-        warn!("read_task/write_task terminated");
+    if let Err(err) = tokio::try_join!(&mut read_task, &mut write_task) {
         read_task.abort();
         write_task.abort();
+        if !err.is_panic() {
+            warn!("read_task/write_task terminated");
+            return;
+        }
+        log_panic(
+            format_args!("client#{client_id} connection task"),
+            err.into_panic().as_ref(),
+        );
+        if global.release_client(&client_id, token, clean_session) && clean_session {
+            if let Err(err) = global.storage.clear_all(&client_id).await {
+                warn!("client#{client_id} clear messages failed: {err}");
+            }
+        }
     };
 }
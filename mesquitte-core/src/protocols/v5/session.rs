@@ -1,12 +1,22 @@
-use std::{fmt, mem};
+use std::{fmt, mem, net::SocketAddr, sync::Arc, time::Duration};
 
-use foldhash::{HashMap, HashMapExt};
+use foldhash::{HashMap, HashMapExt, HashSet, HashSetExt};
 use mqtt_codec_kit::{
-    common::TopicFilter,
-    v5::packet::{connect::LastWill, subscribe::SubscribeOptions},
+    common::{qos::QoSWithPacketIdentifier, TopicFilter},
+    v5::packet::{connect::LastWill, PublishPacket, PubrelPacket, VariablePacket},
 };
 use tokio::time::Instant;
 
+use crate::{
+    protocols::delivery::ProtocolAdapter,
+    server::client_info::ClientInfo,
+    store::{
+        message::{get_unix_ts, PendingPublishMessage, PublishMessage},
+        session::{StoredSession, StoredSubscription, StoredWill},
+        topic::SubscriptionOptions,
+    },
+};
+
 pub const DEFAULT_MAX_PACKET_SIZE: u32 = 5 + 268_435_455;
 
 pub(super) struct Session {
@@ -15,24 +25,44 @@ pub(super) struct Session {
     last_packet_at: Instant,
     // For record packet id send from server to client
     server_packet_id: u16,
+    // For ignoring timers of an earlier connection with the same client id
+    timer_token: u64,
+    remote_addr: Option<SocketAddr>,
+    info: Option<Arc<ClientInfo>>,
 
     client_id: String,
     username: Option<String>,
     keep_alive: u16,
+    // Clean Start of the CONNECT
+    clean_start: bool,
+    // the state is discarded when the session ends instead of kept for a later connection, see
+    // `CleanStartSemantics`
     clean_session: bool,
     last_will: Option<LastWill>,
-    subscriptions: HashMap<TopicFilter, SubscribeOptions>,
+    subscriptions: HashMap<TopicFilter, SubscriptionOptions>,
 
     authorized: bool,
     assigned_client_id: bool,
     client_disconnected: bool,
     server_disconnected: bool,
+    disconnected_at: Option<Instant>,
+    disconnect_reason: Option<&'static str>,
+    // code of the error which ended the connection, see `protocols::Error::code`
+    error_code: Option<u16>,
+    kicked: bool,
 
     server_keep_alive: bool,
     session_expiry_interval: u32,
+    // unacknowledged QoS 1/2 publishes the client accepts
     receive_maximum: u16,
+    // unacknowledged QoS 1/2 publishes the broker accepts from the client, told in CONNACK
+    server_receive_maximum: u16,
+    // QoS 1/2 publishes of this connection not completed by PUBACK or PUBCOMP yet
+    unacknowledged_publishes: HashSet<u16>,
     max_packet_size: u32,
     topic_alias_max: u16,
+    // aliases the client accepts on the PUBLISH packets sent to it
+    client_topic_alias_max: u16,
     request_response_info: bool,
     request_problem_info: bool,
     user_properties: Vec<(String, String)>,
@@ -41,16 +71,20 @@ pub(super) struct Session {
 }
 
 impl Session {
-    pub fn new(client_id: String, assigned_client_id: bool, receive_maximum: u16) -> Self {
+    pub fn new(client_id: String, assigned_client_id: bool, server_receive_maximum: u16) -> Self {
         Self {
             connected_at: Instant::now(),
             last_packet_at: Instant::now(),
             server_packet_id: 1,
+            timer_token: 0,
+            remote_addr: None,
+            info: None,
 
             client_id,
             assigned_client_id,
             username: None,
             keep_alive: 0,
+            clean_start: true,
             clean_session: true,
             last_will: None,
             subscriptions: HashMap::new(),
@@ -58,13 +92,20 @@ impl Session {
             authorized: false,
             client_disconnected: false,
             server_disconnected: false,
+            disconnected_at: None,
+            disconnect_reason: None,
+            error_code: None,
+            kicked: false,
             server_keep_alive: false,
 
             session_expiry_interval: 0,
-            receive_maximum,
+            receive_maximum: u16::MAX,
+            server_receive_maximum,
+            unacknowledged_publishes: HashSet::new(),
             max_packet_size: DEFAULT_MAX_PACKET_SIZE,
             // TODO: config: max topic alias
             topic_alias_max: 65535,
+            client_topic_alias_max: 0,
             request_response_info: false,
             request_problem_info: true,
             user_properties: Vec::new(),
@@ -78,12 +119,42 @@ impl Session {
 
     pub fn renew_last_packet_at(&mut self) {
         self.last_packet_at = Instant::now();
+        if let Some(info) = &self.info {
+            info.touch();
+        }
     }
 
     pub fn client_id(&self) -> &str {
         &self.client_id
     }
 
+    /// Replaces the client identifier, the client learns it from CONNACK
+    pub fn set_assigned_client_id(&mut self, client_id: String) {
+        self.client_id = client_id;
+        self.assigned_client_id = true;
+    }
+
+    pub fn remote_addr(&self) -> Option<SocketAddr> {
+        self.remote_addr
+    }
+
+    pub fn set_remote_addr(&mut self, remote_addr: Option<SocketAddr>) {
+        self.remote_addr = remote_addr;
+    }
+
+    pub fn info(&self) -> Option<&Arc<ClientInfo>> {
+        self.info.as_ref()
+    }
+
+    pub fn set_info(&mut self, info: Arc<ClientInfo>) {
+        info.set_will(self.stored_will());
+        self.info = Some(info);
+    }
+
+    pub fn username(&self) -> Option<&str> {
+        self.username.as_deref()
+    }
+
     pub fn set_username(&mut self, username: Option<String>) {
         self.username = username
     }
@@ -110,6 +181,14 @@ impl Session {
         self.keep_alive = keep_alive;
     }
 
+    pub fn clean_start(&self) -> bool {
+        self.clean_start
+    }
+
+    pub fn set_clean_start(&mut self, clean_start: bool) {
+        self.clean_start = clean_start;
+    }
+
     pub fn clean_session(&self) -> bool {
         self.clean_session
     }
@@ -131,7 +210,8 @@ impl Session {
     }
 
     pub fn set_client_disconnected(&mut self) {
-        self.client_disconnected = true
+        self.client_disconnected = true;
+        self.disconnected_at.get_or_insert_with(Instant::now);
     }
 
     pub fn server_disconnected(&self) -> bool {
@@ -139,7 +219,39 @@ impl Session {
     }
 
     pub fn set_server_disconnected(&mut self) {
-        self.server_disconnected = true
+        self.server_disconnected = true;
+        self.disconnected_at.get_or_insert_with(Instant::now);
+    }
+
+    /// Marks the session closed by the broker for `reason`, which goes to the audit log
+    pub fn set_server_disconnected_for(&mut self, reason: &'static str) {
+        self.set_server_disconnected();
+        self.disconnect_reason = Some(reason);
+    }
+
+    pub fn disconnect_reason(&self) -> Option<&'static str> {
+        self.disconnect_reason
+    }
+
+    pub fn error_code(&self) -> Option<u16> {
+        self.error_code
+    }
+
+    /// Keeps the code of the error which ended the connection for the audit log
+    pub fn set_error_code(&mut self, code: u16) {
+        self.error_code = Some(code);
+    }
+
+    /// Whether the connection was closed by [`GlobalState::kick_client`]
+    ///
+    /// [`GlobalState::kick_client`]: crate::server::state::GlobalState::kick_client
+    pub fn kicked(&self) -> bool {
+        self.kicked
+    }
+
+    pub fn set_kicked(&mut self) {
+        self.set_server_disconnected_for("kicked by admin");
+        self.kicked = true;
     }
 
     pub fn set_server_keep_alive(&mut self, server_keep_alive: bool) {
@@ -151,36 +263,63 @@ impl Session {
     }
 
     pub fn clear_last_will(&mut self) {
-        self.last_will = None
+        self.last_will = None;
+        self.sync_will();
     }
 
     pub fn take_last_will(&mut self) -> Option<LastWill> {
-        self.last_will.take()
+        let last_will = self.last_will.take();
+        self.sync_will();
+        last_will
     }
 
     pub fn set_last_will(&mut self, last_will: LastWill) {
         self.last_will = Some(last_will);
+        self.sync_will();
+    }
+
+    fn stored_will(&self) -> Option<StoredWill> {
+        self.last_will.as_ref().map(|will| StoredWill {
+            topic_name: will.topic().to_string(),
+            payload: will.message().0.clone(),
+            qos: will.qos() as u8,
+            retain: will.retain(),
+        })
+    }
+
+    // the will shown in the client info follows the one of the session
+    fn sync_will(&self) {
+        if let Some(info) = &self.info {
+            info.set_will(self.stored_will());
+        }
     }
 
     pub fn set_clean_session(&mut self, clean_session: bool) {
         self.clean_session = clean_session;
     }
 
-    pub fn subscriptions(&self) -> &HashMap<TopicFilter, SubscribeOptions> {
+    pub fn subscriptions(&self) -> &HashMap<TopicFilter, SubscriptionOptions> {
         &self.subscriptions
     }
 
-    pub fn subscribe(&mut self, topic: TopicFilter, options: SubscribeOptions) -> bool {
-        self.subscriptions.insert(topic, options).is_some()
+    /// Returns the options of the subscription replaced, if the client was subscribed already
+    pub fn subscribe(
+        &mut self,
+        topic: TopicFilter,
+        options: SubscriptionOptions,
+    ) -> Option<SubscriptionOptions> {
+        self.subscriptions.insert(topic, options)
     }
 
-    pub fn unsubscribe(&mut self, topic: &TopicFilter) {
-        self.subscriptions.remove(topic);
+    pub fn unsubscribe(&mut self, topic: &TopicFilter) -> bool {
+        self.subscriptions.remove(topic).is_some()
     }
 
+    /// Packet identifiers go from 1 to 65535 and start over, the ones still held by a pending
+    /// message are skipped by [`free_packet_id`](crate::protocols::common::free_packet_id)
     pub fn incr_server_packet_id(&mut self) -> u16 {
-        let old_value = self.server_packet_id;
-        self.server_packet_id += 1;
+        let old_value = self.server_packet_id.max(1);
+        self.server_packet_id = old_value.checked_add(1).unwrap_or(1);
         old_value
     }
 
@@ -192,6 +331,20 @@ impl Session {
         self.server_keep_alive
     }
 
+    pub fn timer_token(&self) -> u64 {
+        self.timer_token
+    }
+
+    pub fn set_timer_token(&mut self, timer_token: u64) {
+        self.timer_token = timer_token;
+    }
+
+    /// Time without any packet from the client after which it is disconnected, one and a half
+    /// times the keep alive
+    pub fn keep_alive_timeout(&self) -> Duration {
+        Duration::from_millis(self.keep_alive as u64 * 1500)
+    }
+
     pub fn session_expiry_interval(&self) -> u32 {
         self.session_expiry_interval
     }
@@ -208,6 +361,30 @@ impl Session {
         self.receive_maximum = receive_maximum;
     }
 
+    pub fn server_receive_maximum(&self) -> u16 {
+        self.server_receive_maximum
+    }
+
+    /// Counts a QoS 1/2 publish of the client until [`Session::complete_publish`], false if it
+    /// goes beyond the Receive Maximum of the broker
+    ///
+    /// A resent publish whose packet identifier is still counted does not count again.
+    pub fn receive_publish(&mut self, packet_id: u16) -> bool {
+        if self.unacknowledged_publishes.contains(&packet_id) {
+            return true;
+        }
+        if self.unacknowledged_publishes.len() >= usize::from(self.server_receive_maximum) {
+            return false;
+        }
+        self.unacknowledged_publishes.insert(packet_id);
+        true
+    }
+
+    /// The broker sent PUBACK or PUBCOMP, or a PUBREC with an error, for the publish
+    pub fn complete_publish(&mut self, packet_id: u16) {
+        self.unacknowledged_publishes.remove(&packet_id);
+    }
+
     pub fn max_packet_size(&self) -> u32 {
         self.max_packet_size
     }
@@ -226,6 +403,14 @@ impl Session {
         self.topic_alias_max = topic_alias_max;
     }
 
+    pub fn client_topic_alias_max(&self) -> u16 {
+        self.client_topic_alias_max
+    }
+
+    pub fn set_client_topic_alias_max(&mut self, client_topic_alias_max: u16) {
+        self.client_topic_alias_max = client_topic_alias_max;
+    }
+
     pub fn request_response_info(&self) -> bool {
         self.request_response_info
     }
@@ -258,9 +443,14 @@ impl Session {
         let mut subscriptions = HashMap::new();
         mem::swap(&mut self.subscriptions, &mut subscriptions);
 
+        // the Session Expiry Interval counts from the end of the connection, `None` never expires
+        let expires_at = self.disconnected_at.and_then(|disconnected_at| {
+            disconnected_at.checked_add(Duration::from_secs(self.session_expiry_interval.into()))
+        });
         SessionState {
             server_packet_id: self.server_packet_id,
             subscriptions,
+            expires_at,
         }
     }
 
@@ -268,6 +458,73 @@ impl Session {
         self.server_packet_id = state.server_packet_id;
         self.subscriptions = state.subscriptions;
     }
+
+    /// The state persisted across broker restarts
+    pub fn to_stored(&self) -> StoredSession {
+        // the Session Expiry Interval counts once the connection is closed
+        let expire_at = (self.disconnected() && self.session_expiry_interval != u32::MAX)
+            .then(|| get_unix_ts() + u64::from(self.session_expiry_interval));
+        StoredSession {
+            client_id: self.client_id.clone(),
+            server_packet_id: self.server_packet_id,
+            subscriptions: self
+                .subscriptions
+                .iter()
+                .map(|(filter, options)| StoredSubscription::new(filter, options))
+                .collect(),
+            will: self.stored_will(),
+            expire_at,
+        }
+    }
+
+    /// Restores a session persisted by an earlier broker run
+    pub fn restore(&mut self, stored: &StoredSession) {
+        self.server_packet_id = stored.server_packet_id;
+        self.subscriptions = stored
+            .subscriptions()
+            .filter_map(|(filter, options)| Some((TopicFilter::new(filter).ok()?, options)))
+            .collect();
+    }
+}
+
+impl ProtocolAdapter for Session {
+    type Packet = VariablePacket;
+
+    fn client_id(&self) -> &str {
+        &self.client_id
+    }
+
+    fn is_subscribed(&self, topic_filter: &TopicFilter) -> bool {
+        self.subscriptions.contains_key(topic_filter)
+    }
+
+    fn next_packet_id(&mut self) -> u16 {
+        self.incr_server_packet_id()
+    }
+
+    fn publish(&self, qos: QoSWithPacketIdentifier, message: PublishMessage) -> VariablePacket {
+        // the first send of the message to this client
+        publish_packet(qos, &message, false).into()
+    }
+
+    fn resend(&self, packet_id: u16, pending: PendingPublishMessage) -> VariablePacket {
+        match pending.pubrec_at() {
+            Some(_) => PubrelPacket::new_success(packet_id).into(),
+            None => publish_packet(pending.qos(), pending.message(), pending.dup()).into(),
+        }
+    }
+}
+
+fn publish_packet(
+    qos: QoSWithPacketIdentifier,
+    message: &PublishMessage,
+    dup: bool,
+) -> PublishPacket {
+    let mut packet = PublishPacket::new(message.topic_name().to_owned(), qos, message.payload());
+    packet.set_dup(dup);
+    packet.set_retain(message.retain());
+    packet.set_properties(message.properties().cloned().unwrap_or_default());
+    packet
 }
 
 impl fmt::Display for Session {
@@ -290,11 +547,79 @@ impl fmt::Display for Session {
 
 pub struct SessionState {
     server_packet_id: u16,
-    subscriptions: HashMap<TopicFilter, SubscribeOptions>,
+    subscriptions: HashMap<TopicFilter, SubscriptionOptions>,
+    expires_at: Option<Instant>,
 }
 
 impl SessionState {
-    pub fn subscriptions(&self) -> &HashMap<TopicFilter, SubscribeOptions> {
+    pub fn subscriptions(&self) -> &HashMap<TopicFilter, SubscriptionOptions> {
         &self.subscriptions
     }
+
+    /// Whether the Session Expiry Interval elapsed since the connection of the session ended,
+    /// the expiry timer may not have removed it yet
+    pub fn expired(&self) -> bool {
+        self.expires_at
+            .is_some_and(|expires_at| expires_at <= Instant::now())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mqtt_codec_kit::{
+        common::QualityOfService,
+        v5::packet::subscribe::{RetainHandling, SubscribeOptions},
+    };
+
+    use super::*;
+
+    #[test]
+    fn stored_session_round_trip() {
+        let mut session = Session::new("c1".to_owned(), false, 32);
+        session.set_session_expiry_interval(60);
+        let mut options = SubscribeOptions::default();
+        options.set_qos(QualityOfService::Level2);
+        options.set_no_local(true);
+        options.set_retain_as_published(true);
+        options.set_retain_handling(RetainHandling::SendAtSubscribeIfNotExist);
+        session.subscribe(
+            TopicFilter::new("a/#").unwrap(),
+            SubscriptionOptions::V5 {
+                options,
+                identifier: Some(3),
+            },
+        );
+        session.incr_server_packet_id();
+
+        // the expiry counts once the connection is closed
+        assert_eq!(session.to_stored().expire_at, None);
+        session.set_server_disconnected();
+        let stored = session.to_stored();
+        assert!(stored.expire_at.is_some_and(|at| at >= get_unix_ts() + 59));
+
+        let mut restored = Session::new("c1".to_owned(), false, 32);
+        restored.restore(&stored);
+        assert_eq!(restored.subscriptions(), session.subscriptions());
+        assert_eq!(
+            restored.incr_server_packet_id(),
+            session.incr_server_packet_id()
+        );
+    }
+
+    #[test]
+    fn receive_maximum_is_enforced() {
+        let mut session = Session::new("c1".to_owned(), false, 2);
+        assert!(session.receive_publish(1));
+        assert!(session.receive_publish(2));
+        assert!(!session.receive_publish(3));
+        // a resent publish is already counted
+        assert!(session.receive_publish(2));
+
+        session.complete_publish(1);
+        assert!(session.receive_publish(3));
+        assert!(!session.receive_publish(4));
+        // completing an unknown publish frees nothing
+        session.complete_publish(5);
+        assert!(!session.receive_publish(4));
+    }
 }
@@ -1,34 +1,63 @@
-use std::{collections::VecDeque, io};
-
 use mqtt_codec_kit::{
-    common::QualityOfService,
+    common::{QualityOfService, TopicFilter},
     v5::{
         control::DisconnectReasonCode,
         packet::{
-            suback::SubscribeReasonCode, subscribe::RetainHandling, DisconnectPacket, SubackPacket,
-            SubscribePacket, UnsubackPacket, UnsubscribePacket, VariablePacket,
+            suback::SubscribeReasonCode, subscribe::RetainHandling,
+            unsuback::UnsubscribeReasonCode, DisconnectPacket, SubackPacket, SubscribePacket,
+            UnsubackPacket, UnsubscribePacket,
         },
     },
 };
 
 use crate::{
     debug,
-    protocols::v5::common::build_error_disconnect,
-    store::{message::MessageStore, retain::RetainMessageStore, topic::TopicStore, Storage},
+    protocols::{
+        common::queue_retained,
+        v5::common::{build_error_disconnect, AckBuilder},
+        Error,
+    },
+    server::{audit::AuditEvent, state::GlobalState},
+    store::{
+        message::MessageStore,
+        queue::DeliverQueue,
+        retain::RetainMessageStore,
+        topic::{SubscriptionOptions, TopicStore},
+        Storage,
+    },
 };
 
-use super::{publish::handle_deliver_publish, session::Session};
+use super::session::Session;
+
+fn audit_subscribe<S>(
+    session: &Session,
+    global: &GlobalState<S>,
+    filter: &TopicFilter,
+    qos: QualityOfService,
+    granted: bool,
+) {
+    global.audit(
+        session.remote_addr(),
+        AuditEvent::Subscribe {
+            client_id: session.client_id(),
+            topic_filter: filter,
+            qos: qos as u8,
+            granted,
+        },
+    );
+}
 
 pub(super) enum SubscribeAck {
-    Success(Vec<VariablePacket>),
+    Success(SubackPacket),
     Disconnect(DisconnectPacket),
 }
 
-pub(super) async fn handle_subscribe<'a, S>(
+pub(super) async fn handle_subscribe<S>(
     session: &mut Session,
     packet: SubscribePacket,
-    storage: &'a Storage<S>,
-) -> io::Result<SubscribeAck>
+    deliver_queue: &DeliverQueue,
+    global: &GlobalState<S>,
+) -> Result<SubscribeAck, Error>
 where
     S: MessageStore + RetainMessageStore + TopicStore,
 {
@@ -57,40 +86,73 @@ properties : {:?}"#,
     // properties.identifier().is_some() && !config.subscription_id_available()
 
     let mut reason_codes = Vec::with_capacity(packet.subscribes().len());
-    let mut retain_packets: Vec<VariablePacket> = Vec::new();
+    let mut problems = Vec::new();
     for (filter, subscribe_opts) in packet.subscribes() {
         // TODO: shared subscribe
         // SubscribeReasonCode::SharedSubscriptionNotSupported
         // SubscribeReasonCode::WildcardSubscriptionsNotSupported topic contain +/#
 
+        if let Err(reason) = global.topic_filter_config().validate(filter) {
+            debug!(
+                "{} invalid topic filter {:?}: {reason}",
+                session.client_id(),
+                filter
+            );
+            problems.push(format!("{filter:?}: {reason}"));
+            reason_codes.push(SubscribeReasonCode::TopicFilterInvalid);
+            audit_subscribe(session, global, filter, subscribe_opts.qos(), false);
+            continue;
+        }
+
+        if !global
+            .allow_subscribe(session.client_id(), filter, subscribe_opts.qos())
+            .await
+        {
+            debug!(
+                "{} is not authorized to subscribe to {filter}",
+                session.client_id()
+            );
+            reason_codes.push(SubscribeReasonCode::NotAuthorized);
+            audit_subscribe(session, global, filter, subscribe_opts.qos(), false);
+            continue;
+        }
+
         let granted_qos = subscribe_opts.qos().to_owned();
         // TODO: granted max qos from config
-        storage
-            .subscribe(session.client_id(), filter, granted_qos)
+        let options = SubscriptionOptions::V5 {
+            options: *subscribe_opts,
+            identifier: properties.identifier(),
+        };
+        global
+            .storage
+            .subscribe(session.client_id(), filter, options)
             .await?;
-        let exist = session.subscribe(filter.clone(), *subscribe_opts);
+        let previous = session.subscribe(filter.clone(), options);
+        if let Some(previous) = previous {
+            debug!(
+                "{} replaced its subscription to {filter} with {previous:?}",
+                session.client_id(),
+            );
+        }
 
         // TODO: config: retain available?
         let send_retain = !filter.is_shared()
             && match subscribe_opts.retain_handling() {
                 RetainHandling::SendAtSubscribe => true,
-                RetainHandling::SendAtSubscribeIfNotExist => exist,
+                RetainHandling::SendAtSubscribeIfNotExist => previous.is_none(),
                 RetainHandling::DoNotSend => false,
             };
 
         if send_retain {
-            let retain_messages = RetainMessageStore::search(storage.as_ref(), filter).await?;
-            for msg in retain_messages {
-                if subscribe_opts.no_local() && msg.client_id().eq(session.client_id()) {
-                    continue;
-                }
-
-                let mut packet =
-                    handle_deliver_publish(session, granted_qos, &msg.into(), storage).await?;
-                packet.set_retain(true);
-
-                retain_packets.push(packet.into());
-            }
+            queue_retained(
+                session.client_id(),
+                filter,
+                granted_qos,
+                subscribe_opts.no_local(),
+                deliver_queue,
+                global,
+            )
+            .await?;
         }
 
         let reason_code = match granted_qos {
@@ -100,20 +162,22 @@ properties : {:?}"#,
         };
 
         reason_codes.push(reason_code);
+        audit_subscribe(session, global, filter, granted_qos, true);
     }
 
-    let mut queue: VecDeque<VariablePacket> = VecDeque::from(retain_packets);
-    let suback_packet = SubackPacket::new(packet.packet_identifier(), reason_codes);
-    // TODO: user properties
-    queue.push_front(suback_packet.into());
-    Ok(SubscribeAck::Success(queue.into()))
+    let mut ack = AckBuilder::new();
+    if !problems.is_empty() {
+        ack = ack.reason_string(problems.join("; "));
+    }
+    let suback_packet = ack.suback(session, packet.packet_identifier(), reason_codes);
+    Ok(SubscribeAck::Success(suback_packet))
 }
 
-pub(super) async fn handle_unsubscribe<'a, S>(
+pub(super) async fn handle_unsubscribe<S>(
     session: &mut Session,
-    storage: &'a Storage<S>,
+    storage: &Storage<S>,
     packet: &UnsubscribePacket,
-) -> io::Result<UnsubackPacket>
+) -> Result<UnsubackPacket, Error>
 where
     S: MessageStore + RetainMessageStore + TopicStore,
 {
@@ -126,14 +190,120 @@ packet id : {}
         packet.subscribes(),
     );
 
-    let reason_codes = Vec::new();
+    let mut reason_codes = Vec::with_capacity(packet.subscribes().len());
     for filter in packet.subscribes() {
-        session.unsubscribe(filter);
+        let subscribed = session.unsubscribe(filter);
         storage.unsubscribe(session.client_id(), filter).await?;
+        reason_codes.push(if subscribed {
+            UnsubscribeReasonCode::Success
+        } else {
+            UnsubscribeReasonCode::NoSubscriptionExisted
+        });
     }
 
-    Ok(UnsubackPacket::new(
-        packet.packet_identifier(),
-        reason_codes,
-    ))
+    Ok(AckBuilder::new().unsuback(session, packet.packet_identifier(), reason_codes))
+}
+
+#[cfg(test)]
+mod tests {
+    use mqtt_codec_kit::{common::TopicName, v5::packet::subscribe::SubscribeOptions};
+
+    use super::*;
+    use crate::store::{memory_storage, message::PublishMessage, queue::QueueConfig};
+
+    #[tokio::test]
+    async fn foreign_response_topics_are_not_authorized() {
+        let global = GlobalState::new(memory_storage()).with_response_topic_prefix("resp");
+        let mut options = SubscribeOptions::default();
+        options.set_qos(QualityOfService::Level1);
+        let subscribes = ["#", "+/c2/x", "resp/c1/#"]
+            .into_iter()
+            .map(|filter| (TopicFilter::new(filter).unwrap(), options))
+            .collect();
+
+        let mut session = Session::new("c1".to_owned(), false, 32);
+        let packet = SubscribePacket::new(1, subscribes);
+        let queue = DeliverQueue::new(QueueConfig::default());
+        let Ok(SubscribeAck::Success(suback)) =
+            handle_subscribe(&mut session, packet, &queue, &global).await
+        else {
+            panic!("subscribe is not acknowledged");
+        };
+        assert_eq!(
+            suback.reason_code(),
+            [
+                SubscribeReasonCode::NotAuthorized,
+                SubscribeReasonCode::NotAuthorized,
+                SubscribeReasonCode::GrantedQos1
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn retained_messages_are_queued() {
+        let global = GlobalState::new(memory_storage());
+        for (publisher, topic) in [("c1", "a/1"), ("c2", "a/2")] {
+            let message = PublishMessage::new(
+                TopicName::new(topic).unwrap(),
+                topic.into(),
+                QualityOfService::Level2,
+                true,
+            );
+            global
+                .storage
+                .insert((publisher, &message).into())
+                .await
+                .unwrap();
+        }
+        let mut options = SubscribeOptions::default();
+        options.set_qos(QualityOfService::Level1);
+        options.set_no_local(true);
+        let packet = SubscribePacket::new(1, vec![(TopicFilter::new("a/+").unwrap(), options)]);
+
+        let mut session = Session::new("c1".to_owned(), false, 32);
+        let queue = DeliverQueue::new(QueueConfig::default());
+        let Ok(SubscribeAck::Success(_)) =
+            handle_subscribe(&mut session, packet, &queue, &global).await
+        else {
+            panic!("subscribe is not acknowledged");
+        };
+        let queued = queue.pop_batch(8);
+        match &queued[..] {
+            [queued] => {
+                assert_eq!(queued.message.topic_name().to_string(), "a/2");
+                assert_eq!(queued.subscribe_qos, QualityOfService::Level1);
+                assert!(queued.message.retain());
+            }
+            other => panic!("unexpected {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn unsubscribe_reports_missing_subscriptions() {
+        let global = GlobalState::new(memory_storage());
+        let mut session = Session::new("c1".to_owned(), false, 32);
+        let packet = SubscribePacket::new(
+            1,
+            vec![(TopicFilter::new("a").unwrap(), SubscribeOptions::default())],
+        );
+        let queue = DeliverQueue::new(QueueConfig::default());
+        handle_subscribe(&mut session, packet, &queue, &global)
+            .await
+            .unwrap();
+
+        let filters = ["a", "b", "a"].map(|filter| TopicFilter::new(filter).unwrap());
+        let packet = UnsubscribePacket::new(2, filters.to_vec());
+        let unsuback = handle_unsubscribe(&mut session, &global.storage, &packet)
+            .await
+            .unwrap();
+        assert_eq!(
+            unsuback.reason_code(),
+            [
+                UnsubscribeReasonCode::Success,
+                UnsubscribeReasonCode::NoSubscriptionExisted,
+                UnsubscribeReasonCode::NoSubscriptionExisted
+            ]
+        );
+        assert!(session.subscriptions().is_empty());
+    }
 }
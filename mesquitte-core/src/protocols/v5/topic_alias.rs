@@ -0,0 +1,158 @@
+//! Topic aliases of the PUBLISH packets sent to a client
+//!
+//! A client which accepts topic aliases, with a Topic Alias Maximum in its CONNECT, gets the
+//! topic name of a PUBLISH with an alias the first time, later ones to the same topic carry the
+//! alias alone. Once every alias is taken the least recently used one is mapped to the next new
+//! topic. Aliases belong to the network connection, a reconnecting client starts over.
+
+use std::{collections::BTreeMap, io};
+
+use bytes::BytesMut;
+use foldhash::{HashMap, HashMapExt};
+use mqtt_codec_kit::{
+    common::TopicName,
+    v5::packet::{PublishPacket, VariablePacket},
+};
+use tokio_util::codec::Encoder;
+
+struct Alias {
+    alias: u16,
+    used_at: u64,
+}
+
+/// Encoder assigning the topic aliases of the connection, off until [`TopicAliases::set_max`]
+pub(crate) struct TopicAliases<E> {
+    encoder: E,
+    max: u16,
+    aliases: HashMap<TopicName, Alias>,
+    // topics by their last use, the first one is evicted
+    lru: BTreeMap<u64, TopicName>,
+    clock: u64,
+}
+
+impl<E> TopicAliases<E> {
+    pub fn new(encoder: E) -> Self {
+        Self {
+            encoder,
+            max: 0,
+            aliases: HashMap::new(),
+            lru: BTreeMap::new(),
+            clock: 0,
+        }
+    }
+
+    /// The Topic Alias Maximum of the client, 0 sends every topic name
+    pub fn set_max(&mut self, max: u16) {
+        self.max = max;
+        self.aliases.clear();
+        self.lru.clear();
+    }
+
+    fn apply(&mut self, packet: &mut PublishPacket) {
+        // an alias of the publisher's connection means nothing on this one
+        if packet.properties().topic_alias().is_some() {
+            let mut properties = packet.properties().clone();
+            properties.set_topic_alias(None);
+            packet.set_properties(properties);
+        }
+        if self.max == 0 {
+            return;
+        }
+
+        self.clock += 1;
+        let topic_name = packet.topic_name();
+        if let Some(alias) = self.aliases.get_mut(topic_name) {
+            self.lru.remove(&alias.used_at);
+            self.lru.insert(self.clock, topic_name.clone());
+            alias.used_at = self.clock;
+            let alias = alias.alias;
+            packet.set_topic_alias_only(alias);
+            return;
+        }
+
+        let alias = if self.aliases.len() < self.max as usize {
+            self.aliases.len() as u16 + 1
+        } else {
+            let (_, evicted) = self.lru.pop_first().expect("aliases are in use");
+            self.aliases.remove(&evicted).expect("evicted alias").alias
+        };
+        self.lru.insert(self.clock, topic_name.clone());
+        self.aliases.insert(
+            topic_name.clone(),
+            Alias {
+                alias,
+                used_at: self.clock,
+            },
+        );
+        let mut properties = packet.properties().clone();
+        properties.set_topic_alias(Some(alias));
+        packet.set_properties(properties);
+    }
+}
+
+impl<T, E> Encoder<T> for TopicAliases<E>
+where
+    T: Into<VariablePacket>,
+    E: Encoder<VariablePacket, Error = io::Error>,
+{
+    type Error = io::Error;
+
+    fn encode(&mut self, item: T, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        let mut packet = item.into();
+        if let VariablePacket::PublishPacket(publish) = &mut packet {
+            self.apply(publish);
+        }
+        self.encoder.encode(packet, dst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mqtt_codec_kit::{common::qos::QoSWithPacketIdentifier, v5::packet::MqttEncoder};
+
+    use super::*;
+
+    fn publish(aliases: &mut TopicAliases<MqttEncoder>, topic_name: &str) -> (String, Option<u16>) {
+        let mut packet = PublishPacket::new(
+            TopicName::new(topic_name).unwrap(),
+            QoSWithPacketIdentifier::Level0,
+            b"x".to_vec(),
+        );
+        aliases.apply(&mut packet);
+        (
+            packet.topic_name().to_string(),
+            packet.properties().topic_alias(),
+        )
+    }
+
+    #[test]
+    fn least_recently_used_alias_is_reused() {
+        let mut aliases = TopicAliases::new(MqttEncoder::new());
+        assert_eq!(publish(&mut aliases, "a"), ("a".to_owned(), None));
+
+        aliases.set_max(2);
+        assert_eq!(publish(&mut aliases, "a"), ("a".to_owned(), Some(1)));
+        assert_eq!(publish(&mut aliases, "b"), ("b".to_owned(), Some(2)));
+        assert_eq!(publish(&mut aliases, "a"), ("".to_owned(), Some(1)));
+
+        // "b" was used least recently
+        assert_eq!(publish(&mut aliases, "c"), ("c".to_owned(), Some(2)));
+        assert_eq!(publish(&mut aliases, "a"), ("".to_owned(), Some(1)));
+        assert_eq!(publish(&mut aliases, "b"), ("b".to_owned(), Some(2)));
+    }
+
+    #[test]
+    fn publisher_alias_is_not_forwarded() {
+        let mut aliases = TopicAliases::new(MqttEncoder::new());
+        let mut packet = PublishPacket::new(
+            TopicName::new("a").unwrap(),
+            QoSWithPacketIdentifier::Level0,
+            b"x".to_vec(),
+        );
+        let mut properties = packet.properties().clone();
+        properties.set_topic_alias(Some(7));
+        packet.set_properties(properties);
+        aliases.apply(&mut packet);
+        assert_eq!(packet.properties().topic_alias(), None);
+    }
+}
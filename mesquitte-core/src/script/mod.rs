@@ -0,0 +1,305 @@
+//! Lua scripting hooks
+//!
+//! A script may define any of the global functions `on_connect`, `on_subscribe` and
+//! `on_publish`. Each hook receives a table describing the event and returns `false` to
+//! deny it, any other value (including `nil`) allows it. `on_connect` also gets the password
+//! and may return `"bad_credentials"` to refuse a wrong user name or password.
+//!
+//! ```lua
+//! function on_publish(msg)
+//!     if msg.topic == "secret" then
+//!         return false
+//!     end
+//!     msg.topic = "rewritten/" .. msg.topic
+//!     msg.user_properties["via"] = "script"
+//! end
+//! ```
+//!
+//! Only the `table`, `string`, `math` and `utf8` standard libraries are loaded. Hooks run on
+//! the blocking thread pool, each call on a VM of its own taken from a pool, so the globals of
+//! a script are not shared between calls running at the same time. A hook still running after
+//! the timeout (100ms by default) is aborted and fails, as does its event.
+
+use std::{
+    fs, io,
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use mlua::{Function, HookTriggers, LuaOptions, StdLib, Value};
+use mqtt_codec_kit::common::{QualityOfService, TopicFilter, TopicName};
+use parking_lot::Mutex;
+
+use crate::store::message::PublishMessage;
+
+const DEFAULT_MEMORY_LIMIT: usize = 16 * 1024 * 1024;
+const DEFAULT_TIMEOUT: Duration = Duration::from_millis(100);
+/// Number of instructions between two checks of the deadline
+const DEADLINE_CHECK_INSTRUCTIONS: u32 = 1024;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("Io Error : {0}")]
+    Io(#[from] io::Error),
+    #[error("Lua Error : {0}")]
+    Lua(#[from] mlua::Error),
+    #[error("Invalid Topic Name : {0}")]
+    TopicName(String),
+}
+
+/// What `on_connect` decided for a connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectDecision {
+    Allow,
+    Deny,
+    /// Denied for a wrong user name or password
+    BadCredentials,
+}
+
+/// Instant after which the running hook is aborted
+struct Deadline(Instant);
+
+pub struct ScriptHook {
+    source: String,
+    timeout: Duration,
+    // idle VMs, one is taken out for each call
+    pool: Mutex<Vec<mlua::Lua>>,
+}
+
+impl ScriptHook {
+    pub fn new(source: &str) -> Result<Self, Error> {
+        let hook = Self {
+            source: source.to_owned(),
+            timeout: DEFAULT_TIMEOUT,
+            pool: Mutex::new(Vec::new()),
+        };
+        // a script which does not load is refused up front
+        let lua = hook.load()?;
+        hook.pool.lock().push(lua);
+        Ok(hook)
+    }
+
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let source = fs::read_to_string(path)?;
+        Self::new(&source)
+    }
+
+    /// Aborts hooks running longer than `timeout`
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    fn load(&self) -> Result<mlua::Lua, Error> {
+        let lua = mlua::Lua::new_with(
+            StdLib::TABLE | StdLib::STRING | StdLib::MATH | StdLib::UTF8,
+            LuaOptions::default(),
+        )?;
+        lua.set_memory_limit(DEFAULT_MEMORY_LIMIT)?;
+        lua.set_hook(
+            HookTriggers::new().every_nth_instruction(DEADLINE_CHECK_INSTRUCTIONS),
+            |lua, _| match lua.app_data_ref::<Deadline>() {
+                Some(deadline) if Instant::now() >= deadline.0 => {
+                    Err(mlua::Error::RuntimeError("script timed out".to_owned()))
+                }
+                _ => Ok(()),
+            },
+        );
+        lua.set_app_data(Deadline(Instant::now() + self.timeout));
+        lua.load(&self.source).set_name("script").exec()?;
+        Ok(lua)
+    }
+
+    /// Runs `f` on an idle VM, a VM whose hook failed may be left half way and is dropped
+    fn with_lua<T>(&self, f: impl FnOnce(&mlua::Lua) -> Result<T, Error>) -> Result<T, Error> {
+        let idle = self.pool.lock().pop();
+        let lua = match idle {
+            Some(lua) => lua,
+            None => self.load()?,
+        };
+        lua.set_app_data(Deadline(Instant::now() + self.timeout));
+        let ret = f(&lua);
+        if ret.is_ok() {
+            self.pool.lock().push(lua);
+        }
+        ret
+    }
+
+    pub fn on_connect(
+        &self,
+        client_id: &str,
+        username: Option<&str>,
+        password: Option<&str>,
+    ) -> Result<ConnectDecision, Error> {
+        self.with_lua(|lua| {
+            let Some(hook) = lua.globals().get::<_, Option<Function>>("on_connect")? else {
+                return Ok(ConnectDecision::Allow);
+            };
+
+            let ctx = lua.create_table()?;
+            ctx.set("client_id", client_id)?;
+            ctx.set("username", username)?;
+            ctx.set("password", password)?;
+            let ret: Value = hook.call(ctx)?;
+            let decision = match &ret {
+                Value::String(ret) if ret.as_bytes() == b"bad_credentials" => {
+                    ConnectDecision::BadCredentials
+                }
+                Value::Boolean(false) => ConnectDecision::Deny,
+                _ => ConnectDecision::Allow,
+            };
+            Ok(decision)
+        })
+    }
+
+    pub fn on_subscribe(
+        &self,
+        client_id: &str,
+        topic_filter: &TopicFilter,
+        qos: QualityOfService,
+    ) -> Result<bool, Error> {
+        self.with_lua(|lua| {
+            let Some(hook) = lua.globals().get::<_, Option<Function>>("on_subscribe")? else {
+                return Ok(true);
+            };
+
+            let ctx = lua.create_table()?;
+            ctx.set("client_id", client_id)?;
+            ctx.set("topic_filter", topic_filter.to_string())?;
+            ctx.set("qos", qos as u8)?;
+            let allowed = is_allowed(hook.call(ctx)?);
+            Ok(allowed)
+        })
+    }
+
+    /// Runs `on_publish`, applying topic rewrites and added user properties to `message`.
+    pub fn on_publish(&self, client_id: &str, message: &mut PublishMessage) -> Result<bool, Error> {
+        self.with_lua(|lua| {
+            let Some(hook) = lua.globals().get::<_, Option<Function>>("on_publish")? else {
+                return Ok(true);
+            };
+
+            let ctx = lua.create_table()?;
+            ctx.set("client_id", client_id)?;
+            ctx.set("topic", message.topic_name().to_string())?;
+            ctx.set("qos", message.qos() as u8)?;
+            ctx.set("retain", message.retain())?;
+            ctx.set("payload", lua.create_string(message.payload())?)?;
+            ctx.set("user_properties", lua.create_table()?)?;
+
+            if !is_allowed(hook.call(ctx.clone())?) {
+                return Ok(false);
+            }
+
+            let topic: String = ctx.get("topic")?;
+            if topic != ***message.topic_name() {
+                let topic_name = TopicName::new(topic).map_err(|err| Error::TopicName(err.0))?;
+                message.set_topic_name(topic_name);
+            }
+
+            #[cfg(feature = "v5")]
+            {
+                let user_properties: mlua::Table = ctx.get("user_properties")?;
+                for pair in user_properties.pairs::<String, String>() {
+                    let (key, value) = pair?;
+                    message.add_user_property(key, value);
+                }
+            }
+
+            Ok(true)
+        })
+    }
+}
+
+#[inline]
+fn is_allowed(ret: Value) -> bool {
+    !matches!(ret, Value::Boolean(false))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SCRIPT: &str = r#"
+        function on_subscribe(ctx)
+            return ctx.topic_filter ~= "secret/#"
+        end
+
+        function on_publish(msg)
+            if msg.topic == "loop" then
+                while true do end
+            end
+            if msg.topic == "secret" then
+                return false
+            end
+            msg.topic = "rewritten/" .. msg.topic
+        end
+    "#;
+
+    fn publish(topic: &str) -> PublishMessage {
+        PublishMessage::new(
+            TopicName::new(topic).unwrap(),
+            b"payload".to_vec(),
+            QualityOfService::Level1,
+            false,
+        )
+    }
+
+    #[test]
+    fn subscribe_is_allowed_or_denied() {
+        let hook = ScriptHook::new(SCRIPT).unwrap();
+        let qos = QualityOfService::Level1;
+        let allowed = hook.on_subscribe("c1", &TopicFilter::new("a/#").unwrap(), qos);
+        assert!(allowed.unwrap());
+        let denied = hook.on_subscribe("c1", &TopicFilter::new("secret/#").unwrap(), qos);
+        assert!(!denied.unwrap());
+        // without the hook everything is allowed
+        assert_eq!(
+            hook.on_connect("c1", None, None).unwrap(),
+            ConnectDecision::Allow
+        );
+    }
+
+    #[test]
+    fn publish_is_rewritten_or_denied() {
+        let hook = ScriptHook::new(SCRIPT).unwrap();
+        let mut message = publish("a/b");
+        assert!(hook.on_publish("c1", &mut message).unwrap());
+        assert_eq!(&***message.topic_name(), "rewritten/a/b");
+        assert_eq!(message.payload(), b"payload");
+
+        let mut message = publish("secret");
+        assert!(!hook.on_publish("c1", &mut message).unwrap());
+        assert_eq!(&***message.topic_name(), "secret");
+    }
+
+    #[test]
+    fn runaway_hook_is_aborted() {
+        let hook = ScriptHook::new(SCRIPT)
+            .unwrap()
+            .with_timeout(Duration::from_millis(20));
+        assert!(hook.on_publish("c1", &mut publish("loop")).is_err());
+        // the aborted VM is dropped, the next call gets a fresh one
+        assert!(hook.pool.lock().is_empty());
+        assert!(hook.on_publish("c1", &mut publish("a")).unwrap());
+        assert_eq!(hook.pool.lock().len(), 1);
+    }
+
+    #[test]
+    fn concurrent_calls_get_their_own_vm() {
+        let hook = ScriptHook::new(SCRIPT).unwrap();
+        hook.with_lua(|outer| {
+            hook.with_lua(|inner| {
+                assert!(!std::ptr::eq(outer, inner));
+                Ok(())
+            })
+        })
+        .unwrap();
+        assert_eq!(hook.pool.lock().len(), 2);
+    }
+
+    #[test]
+    fn invalid_script_is_refused() {
+        assert!(ScriptHook::new("function (").is_err());
+    }
+}
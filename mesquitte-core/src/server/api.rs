@@ -0,0 +1,134 @@
+//! Checks shared by the HTTP and gRPC APIs on the publishes they take
+
+use mqtt_codec_kit::common::{QualityOfService, TopicName};
+#[cfg(feature = "v5")]
+use mqtt_codec_kit::v5::control::PublishProperties;
+
+use crate::store::message::PublishMessage;
+
+/// A publish of an API, its payload and correlation data already decoded
+pub(crate) struct PublishRequest {
+    pub topic: String,
+    pub payload: Vec<u8>,
+    pub qos: u32,
+    pub retain: bool,
+    #[cfg(feature = "v5")]
+    pub properties: Option<PublishRequestProperties>,
+}
+
+#[cfg(feature = "v5")]
+pub(crate) struct PublishRequestProperties {
+    pub payload_format_indicator: Option<u32>,
+    pub message_expiry_interval: Option<u32>,
+    pub content_type: Option<String>,
+    pub response_topic: Option<String>,
+    pub correlation_data: Option<Vec<u8>>,
+    pub user_properties: Vec<(String, String)>,
+}
+
+pub(crate) fn qos(qos: u32) -> Result<QualityOfService, String> {
+    match qos {
+        0 => Ok(QualityOfService::Level0),
+        1 => Ok(QualityOfService::Level1),
+        2 => Ok(QualityOfService::Level2),
+        qos => Err(format!("invalid qos {qos}")),
+    }
+}
+
+/// The message of a publish, or why it is invalid
+pub(crate) fn publish_message(request: PublishRequest) -> Result<PublishMessage, String> {
+    let topic_name = TopicName::new(request.topic).map_err(|err| err.to_string())?;
+    let qos = qos(request.qos)?;
+    #[allow(unused_mut)]
+    let mut message = PublishMessage::new(topic_name, request.payload, qos, request.retain);
+    #[cfg(feature = "v5")]
+    if let Some(properties) = request.properties {
+        message.set_properties(Some(publish_properties(properties)?));
+    }
+    Ok(message)
+}
+
+#[cfg(feature = "v5")]
+fn publish_properties(properties: PublishRequestProperties) -> Result<PublishProperties, String> {
+    let mut publish_properties = PublishProperties::default();
+    let payload_format_indicator = properties
+        .payload_format_indicator
+        .map(u8::try_from)
+        .transpose()
+        .map_err(|_| "invalid payload format indicator".to_owned())?;
+    publish_properties.set_payload_format_indicator(payload_format_indicator);
+    publish_properties.set_message_expiry_interval(properties.message_expiry_interval);
+    publish_properties.set_content_type(properties.content_type);
+    if let Some(response_topic) = properties.response_topic {
+        TopicName::new(response_topic.as_str()).map_err(|err| err.to_string())?;
+        publish_properties.set_response_topic(Some(response_topic));
+    }
+    publish_properties.set_correlation_data(properties.correlation_data);
+    for (key, value) in properties.user_properties {
+        publish_properties.add_user_property(key, value);
+    }
+    Ok(publish_properties)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(topic: &str, qos: u32) -> PublishRequest {
+        PublishRequest {
+            topic: topic.to_owned(),
+            payload: b"m".to_vec(),
+            qos,
+            retain: true,
+            #[cfg(feature = "v5")]
+            properties: None,
+        }
+    }
+
+    #[test]
+    fn publishes_are_checked() {
+        let message = publish_message(request("a/b", 1)).unwrap();
+        assert_eq!(&message.topic_name()[..], "a/b");
+        assert_eq!(message.qos(), QualityOfService::Level1);
+        assert!(message.retain());
+
+        assert!(publish_message(request("a/b", 3)).is_err());
+        assert!(publish_message(request("a/+", 0)).is_err());
+        assert!(publish_message(request("", 0)).is_err());
+    }
+
+    #[cfg(feature = "v5")]
+    #[test]
+    fn properties_are_checked() {
+        let properties = |payload_format_indicator, response_topic: &str| {
+            Some(PublishRequestProperties {
+                payload_format_indicator,
+                message_expiry_interval: Some(60),
+                content_type: None,
+                response_topic: Some(response_topic.to_owned()),
+                correlation_data: Some(b"id".to_vec()),
+                user_properties: vec![("k".to_owned(), "v".to_owned())],
+            })
+        };
+        let mut valid = request("a/b", 0);
+        valid.properties = properties(Some(1), "reply/a");
+        let message = publish_message(valid).unwrap();
+        let published = message.properties().unwrap();
+        assert_eq!(published.payload_format_indicator(), Some(1));
+        assert_eq!(published.response_topic().as_deref(), Some("reply/a"));
+        assert_eq!(
+            published
+                .correlation_data()
+                .as_ref()
+                .map(|data| &data.0[..]),
+            Some(&b"id"[..])
+        );
+
+        let mut indicator = request("a/b", 0);
+        indicator.properties = properties(Some(256), "reply/a");
+        assert!(publish_message(indicator).is_err());
+        let mut response_topic = request("a/b", 0);
+        response_topic.properties = properties(None, "reply/#");
+        assert!(publish_message(response_topic).is_err());
+    }
+}
@@ -0,0 +1,281 @@
+//! Append-only audit log of connection events
+//!
+//! With an [`AuditLog`] set on [`GlobalState`](super::state::GlobalState), connects,
+//! disconnects, authentication failures, kicks and subscribes are written to a file as JSON
+//! lines:
+//!
+//! ```text
+//! {"timestamp":1736908800123,"event":"connect","remote_addr":"10.0.0.7:51234","client_id":"sensor-1","username":"alice","protocol":4,"clean_session":true}
+//! ```
+//!
+//! `timestamp` is in milliseconds since the Unix epoch. Once the file would grow over
+//! [`AuditConfig::max_size`] it is rotated: `audit.log` becomes `audit.log.1`, `audit.log.1`
+//! becomes `audit.log.2` and so on, dropping the oldest past [`AuditConfig::max_files`].
+//!
+//! Records are written and rotated on a thread of their own, the connections only queue them.
+//! Past [`AuditConfig::queue_size`] waiting records new ones are dropped with a warning.
+
+use std::{
+    fmt::Write as _,
+    fs::{self, File, OpenOptions},
+    io::{self, Write as _},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    thread,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use kanal::{bounded, Receiver, Sender};
+
+use crate::warn;
+
+#[derive(Debug, Clone)]
+pub struct AuditConfig {
+    pub path: PathBuf,
+    /// Size in bytes above which the file is rotated, 0 never rotates
+    pub max_size: u64,
+    /// Number of rotated files kept next to the current one
+    pub max_files: usize,
+    /// Number of records waiting to be written before new ones are dropped
+    pub queue_size: usize,
+}
+
+impl AuditConfig {
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        Self {
+            path: path.into(),
+            max_size: 64 * 1024 * 1024,
+            max_files: 8,
+            queue_size: 4096,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuditEvent<'a> {
+    Connect {
+        client_id: &'a str,
+        username: Option<&'a str>,
+        protocol: u8,
+        clean_session: bool,
+    },
+    Disconnect {
+        client_id: &'a str,
+        reason: &'a str,
+        /// Code of the error which ended the connection, see [`ErrorCategory`](super::ErrorCategory)
+        code: Option<u16>,
+    },
+    AuthFailure {
+        client_id: &'a str,
+        username: Option<&'a str>,
+    },
+    Kick {
+        client_id: &'a str,
+        reason: &'a str,
+    },
+    Subscribe {
+        client_id: &'a str,
+        topic_filter: &'a str,
+        qos: u8,
+        granted: bool,
+    },
+}
+
+impl AuditEvent<'_> {
+    pub fn name(&self) -> &'static str {
+        match self {
+            AuditEvent::Connect { .. } => "connect",
+            AuditEvent::Disconnect { .. } => "disconnect",
+            AuditEvent::AuthFailure { .. } => "auth-failure",
+            AuditEvent::Kick { .. } => "kick",
+            AuditEvent::Subscribe { .. } => "subscribe",
+        }
+    }
+
+    /// The record of the event as one line of JSON, without the trailing newline
+    fn to_json(self, timestamp: u128, remote_addr: Option<SocketAddr>) -> String {
+        let mut line = format!(r#"{{"timestamp":{timestamp},"event":"{}""#, self.name());
+        match remote_addr {
+            Some(addr) => write_field(&mut line, "remote_addr", &addr.to_string()),
+            None => line.push_str(r#","remote_addr":null"#),
+        }
+        match self {
+            AuditEvent::Connect {
+                client_id,
+                username,
+                protocol,
+                clean_session,
+            } => {
+                write_field(&mut line, "client_id", client_id);
+                write_optional_field(&mut line, "username", username);
+                let _ = write!(
+                    line,
+                    r#","protocol":{protocol},"clean_session":{clean_session}"#
+                );
+            }
+            AuditEvent::Disconnect {
+                client_id,
+                reason,
+                code,
+            } => {
+                write_field(&mut line, "client_id", client_id);
+                write_field(&mut line, "reason", reason);
+                match code {
+                    Some(code) => {
+                        let _ = write!(line, r#","code":{code}"#);
+                    }
+                    None => line.push_str(r#","code":null"#),
+                }
+            }
+            AuditEvent::Kick { client_id, reason } => {
+                write_field(&mut line, "client_id", client_id);
+                write_field(&mut line, "reason", reason);
+            }
+            AuditEvent::AuthFailure {
+                client_id,
+                username,
+            } => {
+                write_field(&mut line, "client_id", client_id);
+                write_optional_field(&mut line, "username", username);
+            }
+            AuditEvent::Subscribe {
+                client_id,
+                topic_filter,
+                qos,
+                granted,
+            } => {
+                write_field(&mut line, "client_id", client_id);
+                write_field(&mut line, "topic_filter", topic_filter);
+                let _ = write!(line, r#","qos":{qos},"granted":{granted}"#);
+            }
+        }
+        line.push('}');
+        line
+    }
+}
+
+/// Milliseconds since the Unix epoch
+pub(super) fn now_millis() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_millis())
+        .unwrap_or_default()
+}
+
+pub(super) fn write_field(line: &mut String, key: &str, value: &str) {
+    let _ = write!(line, r#","{key}":""#);
+    for c in value.chars() {
+        match c {
+            '"' => line.push_str(r#"\""#),
+            '\\' => line.push_str(r"\\"),
+            '\n' => line.push_str(r"\n"),
+            '\r' => line.push_str(r"\r"),
+            '\t' => line.push_str(r"\t"),
+            c if c.is_control() => {
+                let _ = write!(line, r"\u{:04x}", c as u32);
+            }
+            c => line.push(c),
+        }
+    }
+    line.push('"');
+}
+
+pub(super) fn write_optional_field(line: &mut String, key: &str, value: Option<&str>) {
+    match value {
+        Some(value) => write_field(line, key, value),
+        None => {
+            let _ = write!(line, r#","{key}":null"#);
+        }
+    }
+}
+
+pub struct AuditLog {
+    path: PathBuf,
+    sender: Sender<String>,
+}
+
+impl AuditLog {
+    /// Opens the audit log for appending, the file is created if missing
+    ///
+    /// The writer thread stops once the log is dropped and its queued records are written.
+    pub fn open(config: AuditConfig) -> io::Result<Self> {
+        let file = open_append(&config.path)?;
+        let size = file.metadata()?.len();
+        let (sender, receiver) = bounded(config.queue_size);
+        let path = config.path.clone();
+        let writer = AuditWriter { config, file, size };
+        thread::Builder::new()
+            .name("audit-log".to_owned())
+            .spawn(move || writer.run(receiver))?;
+        Ok(Self { path, sender })
+    }
+
+    /// Queues the record of `event` for the writer, failures are logged and otherwise ignored
+    pub fn record(&self, remote_addr: Option<SocketAddr>, event: AuditEvent<'_>) {
+        let mut line = event.to_json(now_millis(), remote_addr);
+        line.push('\n');
+        match self.sender.try_send(line) {
+            Ok(true) => {}
+            Ok(false) => warn!("audit log {:?} queue is full, record dropped", self.path),
+            Err(_) => warn!("audit log {:?} writer stopped, record dropped", self.path),
+        }
+    }
+}
+
+/// Owns the file of an [`AuditLog`] on the writer thread
+struct AuditWriter {
+    config: AuditConfig,
+    file: File,
+    size: u64,
+}
+
+impl AuditWriter {
+    fn run(mut self, receiver: Receiver<String>) {
+        while let Ok(line) = receiver.recv() {
+            if let Err(err) = self.append(line.as_bytes()) {
+                warn!("write audit log {:?} failed: {err}", self.config.path);
+            }
+        }
+    }
+
+    fn append(&mut self, line: &[u8]) -> io::Result<()> {
+        let len = line.len() as u64;
+        if self.config.max_size > 0 && self.size > 0 && self.size + len > self.config.max_size {
+            self.rotate()?;
+            self.file = open_append(&self.config.path)?;
+            self.size = 0;
+        }
+        self.file.write_all(line)?;
+        self.size += len;
+        Ok(())
+    }
+
+    /// Shifts every rotated file one number up, then moves the current file to `.1`
+    fn rotate(&self) -> io::Result<()> {
+        let path = &self.config.path;
+        if self.config.max_files == 0 {
+            return fs::remove_file(path);
+        }
+        let oldest = rotated_path(path, self.config.max_files);
+        if oldest.exists() {
+            fs::remove_file(oldest)?;
+        }
+        for n in (1..self.config.max_files).rev() {
+            let from = rotated_path(path, n);
+            if from.exists() {
+                fs::rename(from, rotated_path(path, n + 1))?;
+            }
+        }
+        fs::rename(path, rotated_path(path, 1))
+    }
+}
+
+fn open_append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+fn rotated_path(path: &Path, n: usize) -> PathBuf {
+    let mut rotated = path.as_os_str().to_owned();
+    rotated.push(format!(".{n}"));
+    PathBuf::from(rotated)
+}
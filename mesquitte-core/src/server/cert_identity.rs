@@ -0,0 +1,152 @@
+//! The identity of a client authenticated by its TLS certificate
+
+use rustls::pki_types::CertificateDer;
+
+/// The identity of a client authenticated by its certificate alone, the common name of the
+/// subject of the certificate it presented in the handshake
+pub(crate) fn certificate_identity(certificates: Option<&[CertificateDer<'_>]>) -> Option<String> {
+    subject_common_name(certificates?.first()?)
+}
+
+const SEQUENCE: u8 = 0x30;
+const SET: u8 = 0x31;
+const OBJECT_IDENTIFIER: u8 = 0x06;
+const UTF8_STRING: u8 = 0x0c;
+const PRINTABLE_STRING: u8 = 0x13;
+const IA5_STRING: u8 = 0x16;
+// [0], the version of a v2 or v3 certificate
+const EXPLICIT_VERSION: u8 = 0xa0;
+// 2.5.4.3
+const OID_COMMON_NAME: &[u8] = &[0x55, 0x04, 0x03];
+
+/// Splits the DER element at the start of `der` into its tag, its contents and the bytes
+/// after it
+fn der_element(der: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, rest) = der.split_first()?;
+    let (&len, mut rest) = rest.split_first()?;
+    let len = if len & 0x80 == 0 {
+        len as usize
+    } else {
+        let octets = (len & 0x7f) as usize;
+        if octets == 0 || octets > 4 || rest.len() < octets {
+            return None;
+        }
+        let (octets, after) = rest.split_at(octets);
+        rest = after;
+        octets
+            .iter()
+            .fold(0usize, |len, &octet| len << 8 | octet as usize)
+    };
+    if rest.len() < len {
+        return None;
+    }
+    let (contents, rest) = rest.split_at(len);
+    Some((tag, contents, rest))
+}
+
+/// The first common name of the subject of an X.509 certificate, read without validating the
+/// certificate: rustls verified it in the handshake
+fn subject_common_name(certificate: &[u8]) -> Option<String> {
+    let (SEQUENCE, certificate, _) = der_element(certificate)? else {
+        return None;
+    };
+    let (SEQUENCE, mut fields, _) = der_element(certificate)? else {
+        return None;
+    };
+    if fields.first() == Some(&EXPLICIT_VERSION) {
+        fields = der_element(fields)?.2;
+    }
+    // serial number, signature algorithm, issuer and validity come before the subject
+    for _ in 0..4 {
+        fields = der_element(fields)?.2;
+    }
+    let (SEQUENCE, mut names, _) = der_element(fields)? else {
+        return None;
+    };
+    while !names.is_empty() {
+        let (SET, mut attributes, rest) = der_element(names)? else {
+            return None;
+        };
+        names = rest;
+        while !attributes.is_empty() {
+            let (SEQUENCE, attribute, rest) = der_element(attributes)? else {
+                return None;
+            };
+            attributes = rest;
+            let (OBJECT_IDENTIFIER, oid, value) = der_element(attribute)? else {
+                return None;
+            };
+            if oid != OID_COMMON_NAME {
+                continue;
+            }
+            return match der_element(value)? {
+                (UTF8_STRING | PRINTABLE_STRING | IA5_STRING, name, _) => {
+                    String::from_utf8(name.to_vec()).ok()
+                }
+                _ => None,
+            };
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn element(tag: u8, contents: &[&[u8]]) -> Vec<u8> {
+        let contents = contents.concat();
+        let mut der = vec![tag];
+        if contents.len() < 0x80 {
+            der.push(contents.len() as u8);
+        } else {
+            der.push(0x82);
+            der.extend_from_slice(&(contents.len() as u16).to_be_bytes());
+        }
+        der.extend_from_slice(&contents);
+        der
+    }
+
+    fn name(attributes: &[(&[u8], &str)]) -> Vec<u8> {
+        let names: Vec<_> = attributes
+            .iter()
+            .map(|(oid, value)| {
+                let attribute = element(
+                    SEQUENCE,
+                    &[
+                        &element(OBJECT_IDENTIFIER, &[oid]),
+                        &element(UTF8_STRING, &[value.as_bytes()]),
+                    ],
+                );
+                element(SET, &[&attribute])
+            })
+            .collect();
+        let names: Vec<&[u8]> = names.iter().map(Vec::as_slice).collect();
+        element(SEQUENCE, &names)
+    }
+
+    #[test]
+    fn common_name_of_subject() {
+        // 2.5.4.10, organization
+        let organization: &[u8] = &[0x55, 0x04, 0x0a];
+        let tbs = element(
+            SEQUENCE,
+            &[
+                &element(EXPLICIT_VERSION, &[&element(0x02, &[&[2]])]),
+                &element(0x02, &[&[1, 2, 3]]),
+                &element(SEQUENCE, &[&element(OBJECT_IDENTIFIER, &[&[0x2a]])]),
+                &name(&[(OID_COMMON_NAME, "ca")]),
+                &element(SEQUENCE, &[&[0; 200]]),
+                &name(&[(organization, "plant 7"), (OID_COMMON_NAME, "sensor-17")]),
+            ],
+        );
+        let certificate = element(SEQUENCE, &[&tbs, &element(SEQUENCE, &[])]);
+        assert_eq!(
+            subject_common_name(&certificate).as_deref(),
+            Some("sensor-17")
+        );
+
+        let truncated = &certificate[..certificate.len() / 2];
+        assert_eq!(subject_common_name(truncated), None);
+    }
+}
@@ -0,0 +1,122 @@
+//! Details of the connection of each client, for admin queries
+//!
+//! A [`ClientInfo`] is registered with the client in
+//! [`GlobalState::add_client`](super::state::GlobalState::add_client) and kept after the
+//! connection is closed, so for a disconnected client with a persistent session it tells when
+//! the client was last seen.
+
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use arc_swap::ArcSwapOption;
+use mqtt_codec_kit::common::ProtocolLevel;
+
+use crate::store::session::StoredWill;
+
+/// Whether a client is connected and when it was last seen, see
+/// [`GlobalState::client_status`](super::state::GlobalState::client_status)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientStatus {
+    pub online: bool,
+    pub connected_at: Option<SystemTime>,
+    /// Before `connected_at` while the client is online again
+    pub disconnected_at: Option<SystemTime>,
+    /// IP address of the last connection
+    pub ip_address: Option<IpAddr>,
+}
+
+#[derive(Debug)]
+pub struct ClientInfo {
+    connected_at: SystemTime,
+    remote_addr: Option<SocketAddr>,
+    protocol_level: ProtocolLevel,
+    clean_session: bool,
+    username: Option<String>,
+    // milliseconds since the Unix epoch
+    last_packet_at: AtomicU64,
+    inflight: AtomicUsize,
+    will: ArcSwapOption<StoredWill>,
+}
+
+impl ClientInfo {
+    pub(crate) fn new(
+        remote_addr: Option<SocketAddr>,
+        protocol_level: ProtocolLevel,
+        clean_session: bool,
+        username: Option<&str>,
+    ) -> Self {
+        let connected_at = SystemTime::now();
+        Self {
+            connected_at,
+            remote_addr,
+            protocol_level,
+            clean_session,
+            username: username.map(|name| name.to_owned()),
+            last_packet_at: AtomicU64::new(unix_millis(connected_at)),
+            inflight: AtomicUsize::new(0),
+            will: ArcSwapOption::empty(),
+        }
+    }
+
+    pub fn connected_at(&self) -> SystemTime {
+        self.connected_at
+    }
+
+    pub fn remote_addr(&self) -> Option<SocketAddr> {
+        self.remote_addr
+    }
+
+    pub fn protocol_level(&self) -> ProtocolLevel {
+        self.protocol_level
+    }
+
+    /// Clean session of MQTT 3.1.1, clean start of MQTT 5
+    pub fn clean_session(&self) -> bool {
+        self.clean_session
+    }
+
+    pub fn username(&self) -> Option<&str> {
+        self.username.as_deref()
+    }
+
+    /// When the last packet was received from the client, the connect time until then
+    pub fn last_packet_at(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_millis(self.last_packet_at.load(Ordering::Relaxed))
+    }
+
+    /// Number of QoS 1/2 publishes sent to the client and not acknowledged yet
+    pub fn inflight(&self) -> usize {
+        self.inflight.load(Ordering::Relaxed)
+    }
+
+    /// The will published if the connection is lost now, `None` once the client disconnected
+    /// normally or the will was published
+    pub fn will(&self) -> Option<Arc<StoredWill>> {
+        self.will.load_full()
+    }
+
+    pub(crate) fn touch(&self) {
+        self.last_packet_at
+            .store(unix_millis(SystemTime::now()), Ordering::Relaxed);
+    }
+
+    pub(crate) fn set_inflight(&self, inflight: usize) {
+        self.inflight.store(inflight, Ordering::Relaxed);
+    }
+
+    pub(crate) fn set_will(&self, will: Option<StoredWill>) {
+        self.will.store(will.map(Arc::new));
+    }
+}
+
+fn unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_millis() as u64)
+        .unwrap_or_default()
+}
@@ -1,24 +1,188 @@
-use std::{net::SocketAddr, path::PathBuf};
+use std::{net::SocketAddr, path::PathBuf, sync::Arc, time::Duration};
 
-use mqtt_codec_kit::common::ProtocolLevel;
+#[cfg(feature = "mqtt-sn")]
+use foldhash::HashMap;
+use mqtt_codec_kit::common::{
+    ProtocolLevel, TopicFilter, LEVEL_SEP, MATCH_ALL_STR, MATCH_ONE_STR, SHARED_PREFIX,
+};
 
 use super::Error;
 
 #[derive(Clone, Debug)]
 pub struct ServerConfig {
-    pub addr: SocketAddr,
+    /// Addresses to listen on, e.g. an IPv4 and an IPv6 address or one per network interface
+    pub addrs: Vec<SocketAddr>,
+    /// Addresses always served with TLS, see [`ServerConfig::with_tls_addr`]
+    pub tls_addrs: Vec<SocketAddr>,
     pub tls: Option<TlsConfig>,
     pub version: ProtocolLevel,
+    pub mount_point: Option<MountPoint>,
+    /// Socket options of the TCP based listeners
+    pub socket: SocketConfig,
+    /// Authenticates clients by their TLS certificate alone, see [`ServerConfig::with_cert_auth`]
+    pub cert_auth: bool,
+    /// WebSocket ping frames sent to the clients of a ws listener, see
+    /// [`ServerConfig::with_ws_ping`]
+    pub ws_ping: Option<WsPing>,
 }
 
 impl ServerConfig {
     pub fn new(addr: SocketAddr, tls: Option<TlsConfig>, version: &str) -> Result<Self, Error> {
         Ok(Self {
-            addr,
+            addrs: vec![addr],
+            tls_addrs: Vec::new(),
             tls,
             version: version.parse::<u8>()?.try_into()?,
+            mount_point: None,
+            socket: SocketConfig::default(),
+            cert_auth: false,
+            ws_ping: None,
         })
     }
+
+    /// Also listens on `addr`
+    pub fn with_addr(mut self, addr: SocketAddr) -> Self {
+        self.addrs.push(addr);
+        self
+    }
+
+    /// Also listens with TLS on `addr`, for a [`WsServer`](super::ws::server::WsServer) serving
+    /// ws on `addrs` and wss on the TLS addresses
+    pub fn with_tls_addr(mut self, addr: SocketAddr) -> Self {
+        self.tls_addrs.push(addr);
+        self
+    }
+
+    pub fn with_mount_point(mut self, mount_point: MountPoint) -> Self {
+        self.mount_point = Some(mount_point);
+        self
+    }
+
+    pub fn with_socket(mut self, socket: SocketConfig) -> Self {
+        self.socket = socket;
+        self
+    }
+
+    /// Authenticates the clients of an mqtts listener by their TLS client certificate alone
+    ///
+    /// The common name of the certificate subject becomes the username of the client, CONNECT
+    /// packets carrying a username or a password are refused and the authentication script is
+    /// not asked. The [`TlsConfig`] has to require client certificates.
+    pub fn with_cert_auth(mut self) -> Self {
+        self.cert_auth = true;
+        self
+    }
+
+    /// Pings the clients of a ws listener with WebSocket frames, independently of the MQTT keep
+    /// alive
+    ///
+    /// Proxies and load balancers often close WebSocket connections idle for less than a long
+    /// keep alive, the pings keep them open and find dead peers early.
+    pub fn with_ws_ping(mut self, ws_ping: WsPing) -> Self {
+        self.ws_ping = Some(ws_ping);
+        self
+    }
+}
+
+/// WebSocket level keep alive of a ws listener
+#[derive(Clone, Copy, Debug)]
+pub struct WsPing {
+    /// Time between a pong, or the start of the connection, and the next ping
+    pub interval: Duration,
+    /// Time a client has to answer a ping before the connection is closed
+    pub timeout: Duration,
+}
+
+impl WsPing {
+    pub fn new(interval: Duration, timeout: Duration) -> Self {
+        Self { interval, timeout }
+    }
+}
+
+/// Options of the sockets of a TCP based listener, `None` keeps the system default
+#[derive(Clone, Debug)]
+pub struct SocketConfig {
+    /// `TCP_NODELAY` on accepted connections, so small packets like PUBACK are not delayed to be
+    /// coalesced
+    pub nodelay: bool,
+    /// `SO_KEEPALIVE` on accepted connections, finds dead peers faster than a long MQTT keep
+    /// alive
+    pub keepalive: Option<TcpKeepalive>,
+    /// `SO_SNDBUF` in bytes
+    pub send_buffer_size: Option<usize>,
+    /// `SO_RCVBUF` in bytes
+    pub recv_buffer_size: Option<usize>,
+    /// Connections waiting to be accepted
+    pub backlog: u32,
+}
+
+impl Default for SocketConfig {
+    fn default() -> Self {
+        Self {
+            nodelay: false,
+            keepalive: None,
+            send_buffer_size: None,
+            recv_buffer_size: None,
+            backlog: 1024,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct TcpKeepalive {
+    /// Idle time before the first probe
+    pub idle: Duration,
+    /// Time between probes, ignored where the system does not support it
+    pub interval: Option<Duration>,
+}
+
+/// Prefix added to the topics of every client of a listener, like mosquitto's `mount_point`
+///
+/// Clients of a mounted listener publish and subscribe in their own part of the topic tree and
+/// never see the prefix, e.g. with the mount point `tenant-a/` a publish to `sensors/1` is routed
+/// as `tenant-a/sensors/1`. Clients of other listeners see the prefixed topics.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MountPoint(Arc<str>);
+
+impl MountPoint {
+    #[allow(clippy::result_large_err)]
+    pub fn new(prefix: &str) -> Result<Self, Error> {
+        if prefix.is_empty()
+            || prefix.starts_with('$')
+            || prefix.contains(MATCH_ALL_STR)
+            || prefix.contains(MATCH_ONE_STR)
+        {
+            return Err(Error::InvalidMountPoint(prefix.to_owned()));
+        }
+        Ok(Self(prefix.into()))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    pub fn mount_topic(&self, topic: &str) -> String {
+        format!("{}{topic}", self.0)
+    }
+
+    /// Mounts a topic filter, the `$share/{group}/` of a shared subscription stays in front
+    pub fn mount_filter(&self, filter: &str) -> String {
+        if let Some((group, filter)) = filter
+            .strip_prefix(SHARED_PREFIX)
+            .and_then(|rest| rest.split_once('/'))
+        {
+            return format!("{SHARED_PREFIX}{group}/{}{filter}", self.0);
+        }
+        self.mount_topic(filter)
+    }
+
+    /// The topic as seen by the clients of the listener, `None` if it is not under the mount
+    /// point
+    pub fn unmount<'a>(&self, topic: &'a str) -> Option<&'a str> {
+        topic
+            .strip_prefix(self.as_str())
+            .filter(|topic| !topic.is_empty())
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -27,6 +191,9 @@ pub struct TlsConfig {
     pub cert_file: PathBuf,
     pub key_file: PathBuf,
     pub fail_if_no_peer_cert: bool,
+    /// Protocols offered with ALPN, e.g. `b"mqtt"` or `b"http/1.1"`, in order of preference
+    pub alpn_protocols: Vec<Vec<u8>>,
+    pub resumption: TlsResumption,
 }
 
 impl TlsConfig {
@@ -41,6 +208,287 @@ impl TlsConfig {
             cert_file,
             key_file,
             fail_if_no_peer_cert,
+            alpn_protocols: Vec::new(),
+            resumption: TlsResumption::default(),
+        }
+    }
+
+    pub fn with_alpn_protocols(mut self, protocols: Vec<Vec<u8>>) -> Self {
+        self.alpn_protocols = protocols;
+        self
+    }
+
+    pub fn with_resumption(mut self, resumption: TlsResumption) -> Self {
+        self.resumption = resumption;
+        self
+    }
+}
+
+/// TLS session resumption, which lets a reconnecting client skip the key exchange and
+/// certificate checks of a full handshake
+///
+/// The defaults are the ones of rustls: a session cache and no stateless tickets.
+#[derive(Clone, Debug)]
+pub struct TlsResumption {
+    /// Sessions the listener keeps to resume by session id or by TLS 1.3 ticket, 0 keeps none
+    pub session_cache_size: usize,
+    /// Stateless tickets: the session is encrypted into the ticket the client keeps, so
+    /// resumption costs the server no memory and works across listeners sharing the config
+    pub tickets: bool,
+    /// How long a ticket key encrypts new tickets before a new random key replaces it, tickets
+    /// are accepted for twice as long
+    pub ticket_key_lifetime: Duration,
+    /// Tickets sent to a TLS 1.3 client after its handshake, each one resumes a connection once
+    pub tls13_tickets: usize,
+}
+
+impl Default for TlsResumption {
+    fn default() -> Self {
+        Self {
+            session_cache_size: 256,
+            tickets: false,
+            ticket_key_lifetime: Duration::from_secs(6 * 60 * 60),
+            tls13_tickets: 2,
+        }
+    }
+}
+
+/// Listener of an MQTT-SN gateway, see [`MqttSnServer`](super::mqtt_sn::server::MqttSnServer)
+#[cfg(feature = "mqtt-sn")]
+#[derive(Clone, Debug)]
+pub struct MqttSnConfig {
+    /// UDP addresses to listen on
+    pub addrs: Vec<SocketAddr>,
+    /// Id of the gateway, answered to SEARCHGW
+    pub gateway_id: u8,
+    /// Topics the clients publish and subscribe to by id without registering them, e.g. for a
+    /// publish with QoS -1
+    pub predefined_topics: HashMap<u16, String>,
+    pub mount_point: Option<MountPoint>,
+    /// Messages kept for a sleeping client, the session stops taking messages from the broker
+    /// once this many wait for the client to wake up
+    pub max_sleep_messages: usize,
+}
+
+#[cfg(feature = "mqtt-sn")]
+impl MqttSnConfig {
+    pub fn new(addr: SocketAddr, gateway_id: u8) -> Self {
+        Self {
+            addrs: vec![addr],
+            gateway_id,
+            predefined_topics: HashMap::default(),
+            mount_point: None,
+            max_sleep_messages: 1024,
+        }
+    }
+
+    /// Also listens on `addr`
+    pub fn with_addr(mut self, addr: SocketAddr) -> Self {
+        self.addrs.push(addr);
+        self
+    }
+
+    pub fn with_predefined_topic(mut self, topic_id: u16, topic_name: &str) -> Self {
+        self.predefined_topics
+            .insert(topic_id, topic_name.to_owned());
+        self
+    }
+
+    pub fn with_mount_point(mut self, mount_point: MountPoint) -> Self {
+        self.mount_point = Some(mount_point);
+        self
+    }
+
+    /// The predefined topic id of a topic name
+    pub(crate) fn predefined_topic_id(&self, topic_name: &str) -> Option<u16> {
+        self.predefined_topics
+            .iter()
+            .find_map(|(id, name)| (name == topic_name).then_some(*id))
+    }
+}
+
+/// Characters accepted in client identifiers
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum ClientIdCharset {
+    #[default]
+    Any,
+    /// `0-9a-zA-Z`, the characters every server has to accept
+    Alphanumeric,
+    /// Alphanumeric characters and the given characters
+    AlphanumericAnd(String),
+}
+
+impl ClientIdCharset {
+    fn allows(&self, c: char) -> bool {
+        match self {
+            ClientIdCharset::Any => true,
+            ClientIdCharset::Alphanumeric => c.is_ascii_alphanumeric(),
+            ClientIdCharset::AlphanumericAnd(extra) => {
+                c.is_ascii_alphanumeric() || extra.contains(c)
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ClientIdConfig {
+    /// Maximum length in bytes
+    pub max_len: usize,
+    pub charset: ClientIdCharset,
+    /// Whether clients may connect without a client identifier and get one assigned, MQTT 3.1.1
+    /// clients also need clean session
+    pub allow_empty: bool,
+}
+
+impl Default for ClientIdConfig {
+    fn default() -> Self {
+        Self {
+            max_len: u16::MAX as usize,
+            charset: ClientIdCharset::Any,
+            allow_empty: true,
+        }
+    }
+}
+
+impl ClientIdConfig {
+    /// Checks a client identifier sent by a client, returns why it is rejected
+    pub fn validate(&self, client_id: &str) -> Result<(), &'static str> {
+        if client_id.is_empty() {
+            return if self.allow_empty {
+                Ok(())
+            } else {
+                Err("client identifier is empty")
+            };
+        }
+        if client_id.len() > self.max_len {
+            return Err("client identifier is too long");
+        }
+        if !client_id.chars().all(|c| self.charset.allows(c)) {
+            return Err("client identifier contains invalid characters");
+        }
+        Ok(())
+    }
+}
+
+/// When the packets written to a client are flushed to its connection
+///
+/// Packets written without a flush are sent together, which saves system calls and network
+/// packets on high-rate streams at the cost of latency. Packets written outside the delivery of
+/// messages, like acknowledgements, flush the connection right away whatever the policy.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FlushPolicy {
+    /// Every packet is flushed as it is written
+    #[default]
+    EveryPacket,
+    /// Flushed every `n` packets, and when the connection has nothing more to write right away
+    Packets(usize),
+    /// Flushed once the first packet not flushed yet waited for the interval
+    Interval(Duration),
+}
+
+/// QoS 1 messages to MQTT 3.1.1 clients sent without saving them first, see
+/// [`GlobalState::with_qos1_fast_path`](super::state::GlobalState::with_qos1_fast_path)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Qos1FastPath {
+    /// Age at which a message not acknowledged yet is saved to the store
+    pub spill_after: Duration,
+    /// Messages of a connection kept unsaved at most, more are saved before they are sent
+    pub max_unsaved: usize,
+}
+
+impl Default for Qos1FastPath {
+    fn default() -> Self {
+        Self {
+            spill_after: Duration::from_secs(1),
+            max_unsaved: 64,
+        }
+    }
+}
+
+/// Rules for the topic filters of SUBSCRIBE packets, a filter breaking them is refused in the
+/// SUBACK while the other filters of the packet are still subscribed
+#[derive(Clone, Debug)]
+pub struct TopicFilterConfig {
+    /// Maximum length in bytes
+    pub max_len: usize,
+    /// Maximum number of levels, a shared subscription counts the levels after its group
+    pub max_levels: usize,
+    /// Whether levels may be empty, like the middle one of `a//b`
+    pub allow_empty_levels: bool,
+}
+
+impl Default for TopicFilterConfig {
+    fn default() -> Self {
+        Self {
+            max_len: u16::MAX as usize,
+            max_levels: usize::MAX,
+            allow_empty_levels: true,
+        }
+    }
+}
+
+impl TopicFilterConfig {
+    /// Checks a topic filter sent by a client, returns why it is refused
+    pub fn validate(&self, filter: &TopicFilter) -> Result<(), &'static str> {
+        if !filter.is_valid() {
+            return Err("topic filter has misplaced wildcards");
+        }
+        if filter.validate_strict().is_err() {
+            return Err("topic filter contains invalid characters");
+        }
+        if filter.len() > self.max_len {
+            return Err("topic filter is too long");
+        }
+        let levels: &str = match filter.shared_info() {
+            Some((_, filter)) => filter,
+            None => filter,
+        };
+        if levels.split(LEVEL_SEP).count() > self.max_levels {
+            return Err("topic filter has too many levels");
+        }
+        if !self.allow_empty_levels && levels.split(LEVEL_SEP).any(str::is_empty) {
+            return Err("topic filter has empty levels");
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn client_id_validation() {
+        let config = |max_len, charset, allow_empty| ClientIdConfig {
+            max_len,
+            charset,
+            allow_empty,
+        };
+        let extra = || ClientIdCharset::AlphanumericAnd("-_".to_owned());
+        for (config, client_id, valid) in [
+            (ClientIdConfig::default(), "", true),
+            (config(8, ClientIdCharset::Any, false), "", false),
+            (config(8, ClientIdCharset::Any, false), "12345678", true),
+            (config(8, ClientIdCharset::Any, false), "123456789", false),
+            // bytes are counted, not characters
+            (config(8, ClientIdCharset::Any, false), "ééééé", false),
+            (config(8, ClientIdCharset::Any, false), "a/b c#é", true),
+            (
+                config(8, ClientIdCharset::Alphanumeric, false),
+                "aZ09",
+                true,
+            ),
+            (
+                config(8, ClientIdCharset::Alphanumeric, false),
+                "a-b",
+                false,
+            ),
+            (config(8, ClientIdCharset::Alphanumeric, false), "é", false),
+            (config(8, extra(), false), "a-b_c", true),
+            (config(8, extra(), false), "a.b", false),
+            (config(8, extra(), true), "", true),
+        ] {
+            assert_eq!(config.validate(client_id).is_ok(), valid, "{client_id:?}");
         }
     }
 }
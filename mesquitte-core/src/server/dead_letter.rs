@@ -0,0 +1,52 @@
+//! Republishing of messages which could not be delivered
+//!
+//! With a dead-letter topic configured on [`GlobalState`](super::state::GlobalState), expired
+//! messages, messages dropped by a full queue and messages rejected by an interceptor are
+//! republished to it. MQTT 5 subscribers find the original topic, the reason and the client in
+//! the user properties `original_topic`, `reason` and `client_id`.
+
+use mqtt_codec_kit::common::TopicName;
+
+use crate::store::message::PublishMessage;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeadLetterReason {
+    /// The message expiry interval passed before the message was delivered
+    Expired,
+    /// The queue of the subscriber was full
+    QueueFull,
+    /// An interceptor rejected the publish
+    Rejected,
+}
+
+impl DeadLetterReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DeadLetterReason::Expired => "expired",
+            DeadLetterReason::QueueFull => "queue-full",
+            DeadLetterReason::Rejected => "rejected",
+        }
+    }
+}
+
+/// Copy of `message` for the dead-letter topic, `client_id` is the publisher of a rejected
+/// message and the subscriber otherwise
+pub(crate) fn dead_letter(
+    topic: &TopicName,
+    client_id: &str,
+    message: &PublishMessage,
+    reason: DeadLetterReason,
+) -> PublishMessage {
+    let mut letter = message.clone();
+    letter.set_topic_name(topic.clone());
+    letter.set_retain(false);
+    #[cfg(feature = "v5")]
+    {
+        letter.add_user_property("original_topic", &message.topic_name()[..]);
+        letter.add_user_property("reason", reason.as_str());
+        letter.add_user_property("client_id", client_id);
+    }
+    #[cfg(not(feature = "v5"))]
+    let _ = (client_id, reason);
+    letter
+}
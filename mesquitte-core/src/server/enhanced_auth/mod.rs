@@ -0,0 +1,38 @@
+//! MQTT 5 enhanced authentication
+//!
+//! A client asking for enhanced authentication names an Authentication Method in its CONNECT.
+//! The method registered for it with
+//! [`GlobalState::with_enhanced_auth`](super::state::GlobalState::with_enhanced_auth) starts an
+//! [`AuthExchange`], which gets the Authentication Data of CONNECT and then of every AUTH packet
+//! of the client until it succeeds or fails. CONNECTs naming no method skip all of this.
+
+#[cfg(feature = "scram")]
+pub mod scram;
+
+/// What an [`AuthExchange`] answers to the data of the client
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuthStep {
+    /// Sent to the client in an AUTH packet, the exchange goes on with its answer
+    Continue(Vec<u8>),
+    /// The client is authenticated, `data` is sent to it in CONNACK
+    Success {
+        /// Replaces the user name of CONNECT
+        username: Option<String>,
+        data: Option<Vec<u8>>,
+    },
+    /// The connection is refused with Not Authorized
+    Failure,
+}
+
+pub trait EnhancedAuth: Send + Sync {
+    /// The Authentication Method clients ask for, e.g. `SCRAM-SHA-256`
+    fn method(&self) -> &str;
+
+    fn start(&self, client_id: &str) -> Box<dyn AuthExchange + '_>;
+}
+
+/// The exchange authenticating one connection
+pub trait AuthExchange: Send {
+    /// Takes the Authentication Data of CONNECT on the first call, of AUTH on the next ones
+    fn step(&mut self, data: &[u8]) -> AuthStep;
+}
@@ -0,0 +1,447 @@
+//! SCRAM-SHA-256 (RFC 5802, RFC 7677) as an enhanced authentication method
+//!
+//! The broker keeps no passwords, only the salted keys derived from them, loaded from a file
+//! with a line per user in the format of RFC 5803:
+//!
+//! ```text
+//! # username:SCRAM-SHA-256$<iterations>:<salt>$<StoredKey>:<ServerKey>
+//! sensor-17:SCRAM-SHA-256$4096:W22ZaJ0SNY7soEsUEjb6gQ==$WG5d8oPm3OtcPnkdi4Uo7BkeZkBFzpcXkuLmtbsT4qY=:wfPLwcE6nTWhTAmQ7tl2KeoiWGPlZqQxSrmfPwDl2dU=
+//! ```
+//!
+//! [`ScramCredential`] computes the part after the user name from a password. Channel binding
+//! is not supported, the user name of the exchange becomes the user name of the connection.
+//!
+//! An unknown user gets a server-first-message like a known one, with a salt derived from its
+//! name, and fails only at the client-final-message, so the users can not be enumerated (RFC
+//! 5802, section 5.1).
+
+use std::{fmt, fs, io, mem, path::Path, str, str::FromStr};
+
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use foldhash::{HashMap, HashMapExt};
+use hmac::{Hmac, Mac};
+use rand::Rng;
+use sha2::{Digest, Sha256};
+
+use crate::debug;
+
+use super::{AuthExchange, AuthStep, EnhancedAuth};
+
+pub const METHOD: &str = "SCRAM-SHA-256";
+pub const DEFAULT_ITERATIONS: u32 = 4096;
+const NONCE_LEN: usize = 18;
+const SALT_LEN: usize = 16;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("Io Error : {0}")]
+    Io(#[from] io::Error),
+    #[error("Invalid Credential : {0}")]
+    InvalidCredential(String),
+}
+
+type Key = [u8; 32];
+
+/// The salted keys of a password
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScramCredential {
+    iterations: u32,
+    salt: Vec<u8>,
+    stored_key: Key,
+    server_key: Key,
+}
+
+impl ScramCredential {
+    /// Derives the credential of `password` with a random salt and [`DEFAULT_ITERATIONS`]
+    pub fn new(password: &str) -> Self {
+        let salt: [u8; SALT_LEN] = rand::thread_rng().gen();
+        Self::with_salt(password, &salt, DEFAULT_ITERATIONS)
+    }
+
+    pub fn with_salt(password: &str, salt: &[u8], iterations: u32) -> Self {
+        let mut salted_password = Key::default();
+        pbkdf2::pbkdf2_hmac::<Sha256>(password.as_bytes(), salt, iterations, &mut salted_password);
+        let client_key = hmac(&salted_password, b"Client Key");
+        Self {
+            iterations,
+            salt: salt.to_vec(),
+            stored_key: Sha256::digest(client_key).into(),
+            server_key: hmac(&salted_password, b"Server Key"),
+        }
+    }
+}
+
+impl fmt::Display for ScramCredential {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{METHOD}${}:{}${}:{}",
+            self.iterations,
+            STANDARD.encode(&self.salt),
+            STANDARD.encode(self.stored_key),
+            STANDARD.encode(self.server_key)
+        )
+    }
+}
+
+impl FromStr for ScramCredential {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = |reason: &str| Error::InvalidCredential(reason.to_owned());
+        let rest = s
+            .strip_prefix(METHOD)
+            .and_then(|rest| rest.strip_prefix('$'))
+            .ok_or_else(|| invalid("not a SCRAM-SHA-256 credential"))?;
+        let (iterations, salt, stored_key, server_key) = rest
+            .split_once('$')
+            .and_then(|(params, keys)| {
+                let (iterations, salt) = params.split_once(':')?;
+                let (stored_key, server_key) = keys.split_once(':')?;
+                Some((iterations, salt, stored_key, server_key))
+            })
+            .ok_or_else(|| invalid("missing fields"))?;
+        let key = |key: &str| {
+            STANDARD
+                .decode(key)
+                .ok()
+                .and_then(|key| Key::try_from(key).ok())
+                .ok_or_else(|| invalid("invalid key"))
+        };
+        Ok(Self {
+            iterations: iterations
+                .parse()
+                .map_err(|_| invalid("invalid iteration count"))?,
+            salt: STANDARD.decode(salt).map_err(|_| invalid("invalid salt"))?,
+            stored_key: key(stored_key)?,
+            server_key: key(server_key)?,
+        })
+    }
+}
+
+/// The SCRAM-SHA-256 method, register it with
+/// [`GlobalState::with_enhanced_auth`](crate::server::state::GlobalState::with_enhanced_auth)
+pub struct ScramSha256 {
+    credentials: HashMap<String, ScramCredential>,
+    // derives the salts of the unknown users
+    mock_key: Key,
+}
+
+impl Default for ScramSha256 {
+    fn default() -> Self {
+        Self {
+            credentials: HashMap::new(),
+            mock_key: rand::thread_rng().gen(),
+        }
+    }
+}
+
+impl ScramSha256 {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let content = fs::read_to_string(path)?;
+        Self::parse(&content)
+    }
+
+    /// Reads a `username:credential` line per user, blank lines and `#` comments are skipped
+    pub fn parse(content: &str) -> Result<Self, Error> {
+        let mut auth = Self::new();
+        for (number, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (username, credential) = line.split_once(':').ok_or_else(|| {
+                Error::InvalidCredential(format!("no user name on line {}", number + 1))
+            })?;
+            auth.add_user(username, credential.parse()?);
+        }
+        Ok(auth)
+    }
+
+    pub fn add_user(&mut self, username: impl Into<String>, credential: ScramCredential) {
+        self.credentials.insert(username.into(), credential);
+    }
+}
+
+impl EnhancedAuth for ScramSha256 {
+    fn method(&self) -> &str {
+        METHOD
+    }
+
+    fn start(&self, _client_id: &str) -> Box<dyn AuthExchange + '_> {
+        let server_nonce: [u8; NONCE_LEN] = rand::thread_rng().gen();
+        Box::new(ScramExchange {
+            credentials: &self.credentials,
+            mock_key: &self.mock_key,
+            server_nonce: STANDARD.encode(server_nonce),
+            state: State::ClientFirst,
+        })
+    }
+}
+
+enum State<'a> {
+    ClientFirst,
+    ClientFinal {
+        username: String,
+        // `None` for an unknown user, which fails once it sends its proof
+        credential: Option<&'a ScramCredential>,
+        gs2_header: String,
+        nonce: String,
+        // client-first-message-bare and server-first-message
+        auth_message: String,
+    },
+    Done,
+}
+
+struct ScramExchange<'a> {
+    credentials: &'a HashMap<String, ScramCredential>,
+    mock_key: &'a Key,
+    server_nonce: String,
+    state: State<'a>,
+}
+
+impl ScramExchange<'_> {
+    fn client_first(&mut self, message: &str) -> Option<AuthStep> {
+        let (flag, rest) = message.split_once(',')?;
+        // "p=..." asks for channel binding
+        if !matches!(flag, "n" | "y") {
+            return None;
+        }
+        let (authzid, bare) = rest.split_once(',')?;
+        if !authzid.is_empty() {
+            return None;
+        }
+        let gs2_header = &message[..message.len() - bare.len()];
+
+        let mut attributes = bare.split(',');
+        let username = decode_saslname(attributes.next()?.strip_prefix("n=")?)?;
+        let client_nonce = attributes.next()?.strip_prefix("r=")?;
+        if client_nonce.is_empty() {
+            return None;
+        }
+        let credentials = self.credentials;
+        let credential = credentials.get(&username);
+        // the same salt every time a user is tried, like a stored one
+        let (salt, iterations) = match credential {
+            Some(credential) => (STANDARD.encode(&credential.salt), credential.iterations),
+            None => (
+                STANDARD.encode(&hmac(self.mock_key, username.as_bytes())[..SALT_LEN]),
+                DEFAULT_ITERATIONS,
+            ),
+        };
+
+        let nonce = format!("{client_nonce}{}", self.server_nonce);
+        let server_first = format!("r={nonce},s={salt},i={iterations}");
+        self.state = State::ClientFinal {
+            username,
+            credential,
+            gs2_header: gs2_header.to_owned(),
+            nonce,
+            auth_message: format!("{bare},{server_first}"),
+        };
+        Some(AuthStep::Continue(server_first.into_bytes()))
+    }
+}
+
+impl AuthExchange for ScramExchange<'_> {
+    fn step(&mut self, data: &[u8]) -> AuthStep {
+        let Ok(message) = str::from_utf8(data) else {
+            return AuthStep::Failure;
+        };
+        let step = match mem::replace(&mut self.state, State::Done) {
+            State::ClientFirst => self.client_first(message),
+            State::ClientFinal {
+                username,
+                credential,
+                gs2_header,
+                nonce,
+                auth_message,
+            } => client_final(
+                message,
+                username,
+                credential,
+                &gs2_header,
+                &nonce,
+                &auth_message,
+            ),
+            State::Done => None,
+        };
+        step.unwrap_or(AuthStep::Failure)
+    }
+}
+
+fn client_final(
+    message: &str,
+    username: String,
+    credential: Option<&ScramCredential>,
+    gs2_header: &str,
+    nonce: &str,
+    auth_message: &str,
+) -> Option<AuthStep> {
+    let (without_proof, proof) = message.rsplit_once(",p=")?;
+    let mut attributes = without_proof.split(',');
+    let channel_binding = STANDARD
+        .decode(attributes.next()?.strip_prefix("c=")?)
+        .ok()?;
+    if channel_binding != gs2_header.as_bytes() || attributes.next()?.strip_prefix("r=")? != nonce {
+        return None;
+    }
+    let proof = Key::try_from(STANDARD.decode(proof).ok()?).ok()?;
+    let Some(credential) = credential else {
+        debug!("scram user {username} is unknown");
+        return None;
+    };
+
+    let auth_message = format!("{auth_message},{without_proof}");
+    let client_signature = hmac(&credential.stored_key, auth_message.as_bytes());
+    let mut client_key = proof;
+    for (byte, signature) in client_key.iter_mut().zip(client_signature) {
+        *byte ^= signature;
+    }
+    if !constant_time_eq(&Sha256::digest(client_key), &credential.stored_key) {
+        debug!("scram user {username} sent a wrong proof");
+        return None;
+    }
+
+    let server_signature = hmac(&credential.server_key, auth_message.as_bytes());
+    Some(AuthStep::Success {
+        username: Some(username),
+        data: Some(format!("v={}", STANDARD.encode(server_signature)).into_bytes()),
+    })
+}
+
+fn hmac(key: &[u8], message: &[u8]) -> Key {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any size");
+    mac.update(message);
+    mac.finalize().into_bytes().into()
+}
+
+/// Compares the whole of `a` and `b`, the time taken tells nothing of where they differ
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/// Undoes the escaping of `,` and `=` in a user name
+fn decode_saslname(name: &str) -> Option<String> {
+    let mut decoded = String::with_capacity(name.len());
+    let mut rest = name;
+    while let Some(at) = rest.find('=') {
+        decoded.push_str(&rest[..at]);
+        match rest.get(at..at + 3)? {
+            "=2C" => decoded.push(','),
+            "=3D" => decoded.push('='),
+            _ => return None,
+        }
+        rest = &rest[at + 3..];
+    }
+    decoded.push_str(rest);
+    (!decoded.is_empty()).then_some(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the example exchange of RFC 7677
+    const SALT: &str = "W22ZaJ0SNY7soEsUEjb6gQ==";
+    const SERVER_NONCE: &str = "%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0";
+    const CLIENT_FIRST: &str = "n,,n=user,r=rOprNGfwEbeRWgbNEkqO";
+    const SERVER_FIRST: &str =
+        "r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4096";
+    const CLIENT_FINAL: &str = "c=biws,r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,\
+                                p=dHzbZapWIk4jUhN+Ute9ytag9zjfMHgsqmmiz7AndVQ=";
+    const SERVER_FINAL: &str = "v=6rriTRBi23WpRR/wtup+mMhUZUn/dB5nLTJRsjl95G4=";
+
+    const MOCK_KEY: Key = [7; 32];
+
+    fn exchange(credentials: &HashMap<String, ScramCredential>) -> ScramExchange<'_> {
+        ScramExchange {
+            credentials,
+            mock_key: &MOCK_KEY,
+            server_nonce: SERVER_NONCE.to_owned(),
+            state: State::ClientFirst,
+        }
+    }
+
+    fn credentials(password: &str) -> HashMap<String, ScramCredential> {
+        let salt = STANDARD.decode(SALT).unwrap();
+        let mut credentials = HashMap::new();
+        credentials.insert(
+            "user".to_owned(),
+            ScramCredential::with_salt(password, &salt, 4096),
+        );
+        credentials
+    }
+
+    #[test]
+    fn rfc_7677_exchange() {
+        let credentials = credentials("pencil");
+        let mut exchange = exchange(&credentials);
+        assert_eq!(
+            exchange.step(CLIENT_FIRST.as_bytes()),
+            AuthStep::Continue(SERVER_FIRST.as_bytes().to_vec())
+        );
+        assert_eq!(
+            exchange.step(CLIENT_FINAL.as_bytes()),
+            AuthStep::Success {
+                username: Some("user".to_owned()),
+                data: Some(SERVER_FINAL.as_bytes().to_vec()),
+            }
+        );
+        assert_eq!(exchange.step(CLIENT_FINAL.as_bytes()), AuthStep::Failure);
+    }
+
+    #[test]
+    fn wrong_password_fails() {
+        let credentials = credentials("pen");
+        let mut exchange = exchange(&credentials);
+        assert!(matches!(
+            exchange.step(CLIENT_FIRST.as_bytes()),
+            AuthStep::Continue(_)
+        ));
+        assert_eq!(exchange.step(CLIENT_FINAL.as_bytes()), AuthStep::Failure);
+    }
+
+    #[test]
+    fn unknown_user_fails_at_client_final() {
+        let credentials = credentials("pencil");
+        let unknown = CLIENT_FIRST.replace("n=user", "n=other");
+        let mut exchange = exchange(&credentials);
+        let AuthStep::Continue(server_first) = exchange.step(unknown.as_bytes()) else {
+            panic!("an unknown user gets a server-first-message");
+        };
+        let server_first = String::from_utf8(server_first).unwrap();
+        assert!(server_first.ends_with(",i=4096"), "{server_first}");
+        assert_ne!(server_first, SERVER_FIRST);
+        assert_eq!(exchange.step(CLIENT_FINAL.as_bytes()), AuthStep::Failure);
+
+        // the salt does not change between attempts
+        let mut again = self::exchange(&credentials);
+        assert_eq!(
+            again.step(unknown.as_bytes()),
+            AuthStep::Continue(server_first.into_bytes())
+        );
+    }
+
+    #[test]
+    fn credentials_file() {
+        let credential = ScramCredential::new("pencil");
+        let auth = ScramSha256::parse(&format!("# users\n\nuser:{credential}\n")).unwrap();
+        assert_eq!(auth.credentials["user"], credential);
+        assert_ne!(auth.mock_key, Key::default());
+
+        assert!(ScramSha256::parse("user:SCRAM-SHA-256$4096:c2FsdA==$a2V5:a2V5").is_err());
+        assert!(ScramSha256::parse("user").is_err());
+    }
+
+    #[test]
+    fn escaped_user_name() {
+        assert_eq!(decode_saslname("a=2Cb=3Dc").as_deref(), Some("a,b=c"));
+        assert_eq!(decode_saslname("a=b"), None);
+        assert_eq!(decode_saslname(""), None);
+    }
+}
@@ -0,0 +1,338 @@
+//! Delivery of a publish to its matched subscribers
+//!
+//! Matched subscriptions are grouped by client, so a client matched by several filters gets all
+//! its copies with one queue operation. Large fan-outs are split into partitions which are
+//! queued on several tasks at the same time.
+
+use std::{num::NonZeroUsize, sync::Arc, thread};
+
+use foldhash::{HashMap, HashMapExt};
+use futures::StreamExt as _;
+use mqtt_codec_kit::common::{QualityOfService, TopicFilter, SHARED_PREFIX};
+
+use crate::{
+    debug, error,
+    store::{message::PublishMessage, queue::QueuedMessage, topic::TopicContent},
+};
+
+use super::{shared::SharedGroups, state::Clients};
+
+#[derive(Debug, Clone, Copy)]
+pub struct FanOutConfig {
+    /// Clients per partition, fan-outs to fewer clients are queued inline
+    pub partition_size: usize,
+    /// Maximum number of partitions queued at the same time
+    pub max_concurrency: usize,
+}
+
+impl Default for FanOutConfig {
+    fn default() -> Self {
+        Self {
+            partition_size: 1024,
+            max_concurrency: thread::available_parallelism().map_or(1, NonZeroUsize::get),
+        }
+    }
+}
+
+/// A subscription matched by a publish
+#[derive(Debug)]
+pub(crate) struct Delivery {
+    pub client_id: String,
+    pub topic_filter: TopicFilter,
+    pub subscribe_qos: QualityOfService,
+}
+
+type Batch = (String, Vec<QueuedMessage>);
+
+/// Messages dropped by full queues, with the client they were dropped for
+pub(crate) type Dropped = Vec<(String, Arc<PublishMessage>)>;
+
+/// Deliveries to the clients subscribed with the matched topic filters, and to the member of
+/// every matched shared subscription group whose turn it is
+pub(crate) fn deliveries(
+    topics: Vec<TopicContent>,
+    shared_groups: &SharedGroups,
+    clients: &Clients,
+) -> Vec<Delivery> {
+    let mut deliveries = Vec::new();
+    for topic_content in topics {
+        let Some(filter) = topic_content.topic_filter else {
+            continue;
+        };
+        let topic_filter = match TopicFilter::new(filter.as_str()) {
+            Ok(filter) => filter,
+            Err(err) => {
+                error!("deliver publish message new topic filter: {err}");
+                continue;
+            }
+        };
+        for (client_id, options) in topic_content.clients {
+            deliveries.push(Delivery {
+                client_id,
+                topic_filter: topic_filter.clone(),
+                subscribe_qos: options.qos(),
+            });
+        }
+        for (group, members) in topic_content.shared_clients {
+            let shared_filter = format!("{SHARED_PREFIX}{group}/{filter}");
+            let Some((client_id, options)) = shared_groups.pick(&shared_filter, members, |id| {
+                clients.get(id).is_some_and(|handle| handle.is_connected())
+            }) else {
+                continue;
+            };
+            deliveries.push(Delivery {
+                client_id,
+                // valid like the shared filter the members subscribed with
+                topic_filter: unsafe { TopicFilter::new_unchecked(shared_filter) },
+                subscribe_qos: options.qos(),
+            });
+        }
+    }
+    deliveries
+}
+
+pub(crate) async fn fan_out(
+    clients: &Arc<Clients>,
+    config: FanOutConfig,
+    deliveries: Vec<Delivery>,
+    message: PublishMessage,
+) -> Dropped {
+    let message = Arc::new(message);
+    let mut batches: HashMap<String, Vec<QueuedMessage>> = HashMap::with_capacity(deliveries.len());
+    for delivery in deliveries {
+        batches
+            .entry(delivery.client_id)
+            .or_default()
+            .push(QueuedMessage {
+                topic_filter: delivery.topic_filter,
+                subscribe_qos: delivery.subscribe_qos,
+                message: message.clone(),
+            });
+    }
+
+    let partition_size = config.partition_size.max(1);
+    if batches.len() <= partition_size {
+        return queue_batches(clients, batches);
+    }
+
+    let mut partitions = Vec::with_capacity(batches.len().div_ceil(partition_size));
+    let mut partition = Vec::with_capacity(partition_size);
+    for batch in batches {
+        partition.push(batch);
+        if partition.len() == partition_size {
+            partitions.push(std::mem::replace(
+                &mut partition,
+                Vec::with_capacity(partition_size),
+            ));
+        }
+    }
+    if !partition.is_empty() {
+        partitions.push(partition);
+    }
+
+    futures::stream::iter(partitions)
+        .map(|partition: Vec<Batch>| {
+            let clients = clients.clone();
+            tokio::spawn(async move { queue_batches(&clients, partition) })
+        })
+        .buffer_unordered(config.max_concurrency.max(1))
+        .fold(Vec::new(), |mut all, ret| async move {
+            match ret {
+                Ok(dropped) => all.extend(dropped),
+                Err(err) => error!("fan out task failed: {err}"),
+            }
+            all
+        })
+        .await
+}
+
+fn queue_batches(clients: &Clients, batches: impl IntoIterator<Item = Batch>) -> Dropped {
+    let mut all = Vec::new();
+    for (client_id, batch) in batches {
+        let Some(handle) = clients.get(&client_id) else {
+            continue;
+        };
+        let dropped = handle.queue().push_batch(batch);
+        if !dropped.is_empty() {
+            debug!(
+                "client#{client_id} deliver queue is full, {} messages dropped",
+                dropped.len()
+            );
+            all.extend(dropped.into_iter().map(|q| (client_id.clone(), q.message)));
+        }
+    }
+    all
+}
+
+#[cfg(test)]
+mod tests {
+    use kanal::{bounded_async, AsyncReceiver};
+    use mqtt_codec_kit::common::{ProtocolLevel, TopicName};
+
+    use super::*;
+    use crate::{
+        server::{
+            client_info::ClientInfo,
+            state::{DeliverMessage, GlobalState},
+        },
+        store::{
+            memory::MemoryStore,
+            memory_storage,
+            queue::{OverflowPolicy, QueueConfig},
+            topic::SubscriptionOptions,
+        },
+    };
+
+    fn state(capacity: usize) -> GlobalState<MemoryStore> {
+        GlobalState::new(memory_storage()).with_queue_config(QueueConfig {
+            capacity,
+            high_watermark: capacity,
+            overflow: OverflowPolicy::DropNewest,
+        })
+    }
+
+    /// Connects `client_ids`, the receivers keep the connections open
+    async fn connect(
+        global: &GlobalState<MemoryStore>,
+        client_ids: &[&str],
+    ) -> Vec<AsyncReceiver<DeliverMessage>> {
+        let mut receivers = Vec::new();
+        for (token, client_id) in client_ids.iter().enumerate() {
+            let (sender, receiver) = bounded_async(1);
+            let info = Arc::new(ClientInfo::new(None, ProtocolLevel::Version311, true, None));
+            global
+                .add_client(client_id, token as u64, sender, info)
+                .await;
+            receivers.push(receiver);
+        }
+        receivers
+    }
+
+    fn delivery(client_id: &str, filter: &str) -> Delivery {
+        Delivery {
+            client_id: client_id.to_owned(),
+            topic_filter: TopicFilter::new(filter).unwrap(),
+            subscribe_qos: QualityOfService::Level1,
+        }
+    }
+
+    fn message() -> PublishMessage {
+        PublishMessage::new(
+            TopicName::new("a/b").unwrap(),
+            b"x".to_vec(),
+            QualityOfService::Level1,
+            false,
+        )
+    }
+
+    /// The topic filters of the messages queued for `client_id`
+    fn queued(global: &GlobalState<MemoryStore>, client_id: &str) -> Vec<String> {
+        let queue = global.deliver_queue(client_id).unwrap();
+        queue
+            .pop_batch(usize::MAX)
+            .iter()
+            .map(|queued| queued.topic_filter.to_string())
+            .collect()
+    }
+
+    fn partition_size(partition_size: usize) -> FanOutConfig {
+        FanOutConfig {
+            partition_size,
+            max_concurrency: 2,
+        }
+    }
+
+    #[tokio::test]
+    async fn deliveries_are_batched_by_client() {
+        let global = state(16);
+        let _receivers = connect(&global, &["c1", "c2"]).await;
+        let deliveries = vec![
+            delivery("c1", "a/+"),
+            delivery("c2", "a/b"),
+            delivery("c1", "a/#"),
+            // not connected, nothing to queue
+            delivery("c3", "a/b"),
+        ];
+        let dropped = fan_out(global.clients(), partition_size(16), deliveries, message()).await;
+        assert!(dropped.is_empty());
+        assert_eq!(queued(&global, "c1"), ["a/+", "a/#"]);
+        assert_eq!(queued(&global, "c2"), ["a/b"]);
+    }
+
+    #[tokio::test]
+    async fn large_fan_outs_are_partitioned() {
+        let global = state(16);
+        let client_ids: Vec<_> = (0..5).map(|i| format!("c{i}")).collect();
+        let ids: Vec<_> = client_ids.iter().map(String::as_str).collect();
+        let _receivers = connect(&global, &ids).await;
+        let deliveries = ids
+            .iter()
+            .flat_map(|id| [delivery(id, "a/+"), delivery(id, "a/#")])
+            .collect();
+
+        // one client per partition, the partitions are queued on their own tasks
+        let dropped = fan_out(global.clients(), partition_size(1), deliveries, message()).await;
+        assert!(dropped.is_empty());
+        for id in ids {
+            assert_eq!(queued(&global, id), ["a/+", "a/#"], "{id}");
+        }
+    }
+
+    #[tokio::test]
+    async fn dropped_messages_are_reported() {
+        let global = state(1);
+        let _receivers = connect(&global, &["c1", "c2"]).await;
+        let deliveries = || {
+            vec![
+                delivery("c1", "a/+"),
+                delivery("c1", "a/#"),
+                delivery("c2", "a/b"),
+            ]
+        };
+        // inline, then on the tasks of the partitions
+        for config in [partition_size(16), partition_size(1)] {
+            let dropped = fan_out(global.clients(), config, deliveries(), message()).await;
+            assert_eq!(dropped.len(), 1);
+            assert_eq!(dropped[0].0, "c1");
+            assert_eq!(dropped[0].1.topic_name().to_string(), "a/b");
+            assert_eq!(queued(&global, "c1"), ["a/+"]);
+            assert_eq!(queued(&global, "c2"), ["a/b"]);
+        }
+    }
+
+    #[tokio::test]
+    async fn shared_group_members_take_turns() {
+        let global = state(16);
+        let _receivers = connect(&global, &["c1", "c2"]).await;
+        let topics = || {
+            let mut content = TopicContent {
+                topic_filter: Some("a/+".to_owned()),
+                ..Default::default()
+            };
+            content
+                .clients
+                .insert("c3".to_owned(), QualityOfService::Level0.into());
+            let members: HashMap<String, SubscriptionOptions> = ["c1", "c2", "offline"]
+                .into_iter()
+                .map(|id| (id.to_owned(), QualityOfService::Level1.into()))
+                .collect();
+            content.shared_clients.insert("g".to_owned(), members);
+            vec![content]
+        };
+
+        let mut picked = Vec::new();
+        for _ in 0..4 {
+            let deliveries = global.deliveries(topics());
+            assert_eq!(deliveries.len(), 2);
+            assert_eq!(deliveries[0].client_id, "c3");
+            assert_eq!(deliveries[0].topic_filter.to_string(), "a/+");
+            let shared = &deliveries[1];
+            assert_eq!(shared.topic_filter.to_string(), "$share/g/a/+");
+            assert_eq!(shared.subscribe_qos, QualityOfService::Level1);
+            picked.push(shared.client_id.clone());
+        }
+        // the member without a connection is never picked
+        picked.sort_unstable();
+        assert_eq!(picked, ["c1", "c1", "c2", "c2"]);
+    }
+}
@@ -0,0 +1,143 @@
+//! Hooks run on every publish before it is stored or delivered
+//!
+//! Interceptors run in the order they were added to [`GlobalState`](super::state::GlobalState).
+//! Each one may modify the message, e.g. to validate its payload or add user properties, and
+//! the first one returning [`InterceptAction::Reject`] drops it.
+
+use futures::future::BoxFuture;
+
+use crate::store::message::PublishMessage;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InterceptAction {
+    /// Pass the message on to the next interceptor, then deliver it
+    Continue,
+    /// Drop the message, the publisher is still acknowledged
+    Reject,
+}
+
+pub trait MessageInterceptor: Send + Sync {
+    fn on_publish<'a>(
+        &'a self,
+        client_id: &'a str,
+        message: &'a mut PublishMessage,
+    ) -> BoxFuture<'a, InterceptAction>;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use mqtt_codec_kit::common::{QualityOfService, TopicName};
+    use parking_lot::Mutex;
+
+    use super::*;
+    use crate::{
+        server::state::GlobalState,
+        store::{memory::MemoryStore, memory_storage},
+    };
+
+    type Calls = Arc<Mutex<Vec<String>>>;
+
+    /// Records the topic it sees, then renames the message if asked to
+    struct Step {
+        name: &'static str,
+        calls: Calls,
+        rename: Option<&'static str>,
+        action: InterceptAction,
+    }
+
+    impl MessageInterceptor for Step {
+        fn on_publish<'a>(
+            &'a self,
+            client_id: &'a str,
+            message: &'a mut PublishMessage,
+        ) -> BoxFuture<'a, InterceptAction> {
+            Box::pin(async move {
+                self.calls.lock().push(format!(
+                    "{}:{client_id}:{}",
+                    self.name,
+                    message.topic_name()
+                ));
+                if let Some(topic) = self.rename {
+                    message.set_topic_name(TopicName::new(topic).unwrap());
+                    message.set_qos(QualityOfService::Level0);
+                }
+                self.action
+            })
+        }
+    }
+
+    fn step(name: &'static str, calls: &Calls, action: InterceptAction) -> Step {
+        Step {
+            name,
+            calls: calls.clone(),
+            rename: None,
+            action,
+        }
+    }
+
+    fn message() -> PublishMessage {
+        PublishMessage::new(
+            TopicName::new("a").unwrap(),
+            b"x".to_vec(),
+            QualityOfService::Level1,
+            false,
+        )
+    }
+
+    async fn intercept(global: &GlobalState<MemoryStore>) -> (InterceptAction, PublishMessage) {
+        let mut message = message();
+        let action = global.intercept_publish("c1", &mut message).await;
+        (action, message)
+    }
+
+    #[tokio::test]
+    async fn interceptors_run_in_order() {
+        let calls = Calls::default();
+        let global = GlobalState::new(memory_storage())
+            .with_interceptor(step("first", &calls, InterceptAction::Continue))
+            .with_interceptor(step("second", &calls, InterceptAction::Continue))
+            .with_interceptor(step("third", &calls, InterceptAction::Continue));
+        let (action, _) = intercept(&global).await;
+        assert_eq!(action, InterceptAction::Continue);
+        assert_eq!(*calls.lock(), ["first:c1:a", "second:c1:a", "third:c1:a"]);
+    }
+
+    #[tokio::test]
+    async fn first_rejection_stops_the_chain() {
+        let calls = Calls::default();
+        let global = GlobalState::new(memory_storage())
+            .with_interceptor(step("first", &calls, InterceptAction::Continue))
+            .with_interceptor(step("second", &calls, InterceptAction::Reject))
+            .with_interceptor(step("third", &calls, InterceptAction::Continue));
+        let (action, _) = intercept(&global).await;
+        assert_eq!(action, InterceptAction::Reject);
+        assert_eq!(*calls.lock(), ["first:c1:a", "second:c1:a"]);
+    }
+
+    #[tokio::test]
+    async fn later_interceptors_see_the_changes() {
+        let calls = Calls::default();
+        let global = GlobalState::new(memory_storage())
+            .with_interceptor(Step {
+                rename: Some("b"),
+                ..step("first", &calls, InterceptAction::Continue)
+            })
+            .with_interceptor(step("second", &calls, InterceptAction::Continue));
+        let (action, message) = intercept(&global).await;
+        assert_eq!(action, InterceptAction::Continue);
+        assert_eq!(*calls.lock(), ["first:c1:a", "second:c1:b"]);
+        assert_eq!(message.topic_name().to_string(), "b");
+        assert_eq!(message.qos(), QualityOfService::Level0);
+        assert_eq!(message.payload(), b"x");
+    }
+
+    #[tokio::test]
+    async fn no_interceptor_continues() {
+        let global = GlobalState::new(memory_storage());
+        let (action, message) = intercept(&global).await;
+        assert_eq!(action, InterceptAction::Continue);
+        assert_eq!(message.topic_name().to_string(), "a");
+    }
+}
@@ -0,0 +1,126 @@
+//! Periodic clean-up of expired sessions
+//!
+//! A persistent session expires on a timer of its connection task, so a session whose task is
+//! gone, most of all one restored with
+//! [`GlobalState::restore_sessions`](super::state::GlobalState::restore_sessions) after a
+//! restart, would keep its subscriptions and pending messages forever. [`run_janitor`] sweeps
+//! the session store for them.
+
+use std::time::Duration;
+
+use tokio::time::{self, MissedTickBehavior};
+
+use super::state::GlobalState;
+use crate::{
+    info,
+    store::{message::MessageStore, topic::TopicStore},
+    warn,
+};
+
+/// Removes the expired sessions every `interval`, see
+/// [`GlobalState::remove_expired_sessions`], runs until the task is dropped
+///
+/// Finds nothing to remove without a session store.
+pub async fn run_janitor<S>(global: &GlobalState<S>, interval: Duration)
+where
+    S: MessageStore + TopicStore,
+{
+    let mut ticker = time::interval(interval);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        ticker.tick().await;
+        match global.remove_expired_sessions().await {
+            Ok(0) => {}
+            Ok(removed) => info!("janitor removed {removed} expired sessions"),
+            Err(err) => warn!("janitor failed: {err}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use mqtt_codec_kit::common::TopicName;
+    use parking_lot::Mutex;
+
+    use super::*;
+    use crate::store::{
+        error::StoreError,
+        memory_storage,
+        message::get_unix_ts,
+        session::{SessionStore, StoredSession, StoredSubscription},
+    };
+
+    #[derive(Clone, Default)]
+    struct Sessions(Arc<Mutex<Vec<StoredSession>>>);
+
+    impl SessionStore for Sessions {
+        fn save_session(&self, session: &StoredSession) -> Result<(), StoreError> {
+            self.0.lock().push(session.clone());
+            Ok(())
+        }
+
+        fn load_session(&self, client_id: &str) -> Result<Option<StoredSession>, StoreError> {
+            Ok(self
+                .0
+                .lock()
+                .iter()
+                .find(|s| s.client_id == client_id)
+                .cloned())
+        }
+
+        fn remove_session(&self, client_id: &str) -> Result<(), StoreError> {
+            self.0.lock().retain(|s| s.client_id != client_id);
+            Ok(())
+        }
+
+        fn load_all_sessions(&self) -> Result<Vec<StoredSession>, StoreError> {
+            Ok(self.0.lock().clone())
+        }
+    }
+
+    fn session(client_id: &str, expire_at: u64) -> StoredSession {
+        StoredSession {
+            client_id: client_id.to_owned(),
+            server_packet_id: 1,
+            subscriptions: vec![StoredSubscription {
+                topic_filter: "a/#".to_owned(),
+                qos: 1,
+                v5: None,
+            }],
+            will: None,
+            expire_at: Some(expire_at),
+        }
+    }
+
+    #[tokio::test]
+    async fn restored_session_is_removed_after_expiry() {
+        let sessions = Sessions::default();
+        let global = GlobalState::new(memory_storage()).with_session_store(sessions.clone());
+        let now = get_unix_ts();
+        sessions.save_session(&session("live", now + 3600)).unwrap();
+        sessions.save_session(&session("gone", now + 3600)).unwrap();
+        assert_eq!(global.restore_sessions().await.unwrap(), 2);
+
+        // "gone" expires while nobody is connected to observe it
+        sessions.0.lock()[1].expire_at = Some(now - 1);
+        assert_eq!(global.remove_expired_sessions().await.unwrap(), 1);
+        assert_eq!(global.remove_expired_sessions().await.unwrap(), 0);
+
+        let remaining: Vec<_> = sessions
+            .load_all_sessions()
+            .unwrap()
+            .into_iter()
+            .map(|s| s.client_id)
+            .collect();
+        assert_eq!(remaining, ["live"]);
+        let contents = global
+            .storage
+            .match_topic(&TopicName::new("a/b").unwrap())
+            .await
+            .unwrap();
+        let clients: Vec<_> = contents.iter().flat_map(|c| c.clients.keys()).collect();
+        assert_eq!(clients, ["live"]);
+    }
+}
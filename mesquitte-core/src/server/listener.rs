@@ -0,0 +1,164 @@
+//! Listening sockets shared by the TCP based servers
+
+use std::{io, net::SocketAddr, num::NonZeroUsize, time::Duration};
+
+use socket2::{Domain, Protocol, SockRef, Socket, Type};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    time,
+};
+
+use super::config::SocketConfig;
+use crate::warn;
+
+const MIN_ACCEPT_BACKOFF: Duration = Duration::from_millis(5);
+const MAX_ACCEPT_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Number of accept loops per address, every loop has its own socket bound with `SO_REUSEPORT`
+pub(crate) fn workers() -> usize {
+    #[cfg(all(unix, not(target_os = "solaris"), not(target_os = "illumos")))]
+    let worker = std::thread::available_parallelism().map_or(1, NonZeroUsize::get);
+    #[cfg(any(target_os = "solaris", target_os = "illumos"))]
+    let worker = 1;
+    worker
+}
+
+/// Binds `worker` listeners to each address
+///
+/// An IPv6 address accepts IPv4 connections too, unless an IPv4 address is also configured, so
+/// `[::]:1883` alone and `0.0.0.0:1883` together with `[::]:1883` both work. For port 0 the
+/// port assigned to the first listener is used by the other workers.
+pub(crate) fn bind_tcp(
+    addrs: &[SocketAddr],
+    config: &SocketConfig,
+    worker: usize,
+) -> io::Result<Vec<TcpListener>> {
+    let dual_stack = !addrs.iter().any(SocketAddr::is_ipv4);
+    let mut listeners = Vec::with_capacity(addrs.len() * worker);
+    for addr in addrs {
+        let mut addr = *addr;
+        for _ in 0..worker {
+            let listener = bind_one(addr, config, dual_stack)?;
+            addr = listener.local_addr()?;
+            listeners.push(listener);
+        }
+    }
+    Ok(listeners)
+}
+
+fn bind_one(addr: SocketAddr, config: &SocketConfig, dual_stack: bool) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(!dual_stack)?;
+    }
+    #[cfg(all(unix, not(target_os = "solaris"), not(target_os = "illumos")))]
+    socket.set_reuse_port(true)?;
+    // accepted connections inherit the buffer sizes, the receive buffer has to be set before
+    // listen for the TCP window scale to match it
+    if let Some(size) = config.send_buffer_size {
+        socket.set_send_buffer_size(size)?;
+    }
+    if let Some(size) = config.recv_buffer_size {
+        socket.set_recv_buffer_size(size)?;
+    }
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(config.backlog.try_into().unwrap_or(i32::MAX))?;
+    TcpListener::from_std(socket.into())
+}
+
+/// Applies the options which are not inherited from the listener to an accepted connection
+pub(crate) fn configure_stream(stream: &TcpStream, config: &SocketConfig) -> io::Result<()> {
+    if config.nodelay {
+        stream.set_nodelay(true)?;
+    }
+    if let Some(keepalive) = &config.keepalive {
+        #[allow(unused_mut)]
+        let mut params = socket2::TcpKeepalive::new().with_time(keepalive.idle);
+        #[cfg(any(
+            target_os = "android",
+            target_os = "freebsd",
+            target_os = "ios",
+            target_os = "linux",
+            target_os = "macos",
+            target_os = "netbsd",
+            target_os = "windows",
+        ))]
+        if let Some(interval) = keepalive.interval {
+            params = params.with_interval(interval);
+        }
+        SockRef::from(stream).set_tcp_keepalive(&params)?;
+    }
+    Ok(())
+}
+
+/// Accepts the next connection, errors like `EMFILE` are retried with a growing delay instead
+/// of ending the accept loop
+pub(crate) async fn accept(listener: &TcpListener) -> (TcpStream, SocketAddr) {
+    let mut backoff = MIN_ACCEPT_BACKOFF;
+    loop {
+        match listener.accept().await {
+            Ok(accepted) => return accepted,
+            Err(err) => {
+                warn!("accept failed, retry in {backoff:?}: {err}");
+                time::sleep(backoff).await;
+                backoff = (backoff * 2).min(MAX_ACCEPT_BACKOFF);
+            }
+        }
+    }
+}
+
+/// Runs the TLS or WebSocket handshake of a connection from `addr`, `None` if it failed or did
+/// not finish within `timeout`
+#[cfg(any(feature = "mqtts", feature = "ws", feature = "wss"))]
+pub(crate) async fn handshake<T, E, F>(
+    addr: SocketAddr,
+    kind: &str,
+    timeout: Duration,
+    handshake: F,
+) -> Option<T>
+where
+    E: std::fmt::Display,
+    F: std::future::Future<Output = Result<T, E>>,
+{
+    match time::timeout(timeout, handshake).await {
+        Ok(Ok(stream)) => Some(stream),
+        Ok(Err(err)) => {
+            warn!("{kind} handshake with {addr} failed: {err}");
+            None
+        }
+        Err(_) => {
+            warn!("{kind} handshake with {addr} timed out after {timeout:?}");
+            None
+        }
+    }
+}
+
+/// The distinct local addresses of the listeners, in the order they were bound
+pub(crate) fn local_addrs(listeners: &[TcpListener]) -> Vec<SocketAddr> {
+    let mut addrs: Vec<SocketAddr> = Vec::new();
+    for addr in listeners.iter().filter_map(|l| l.local_addr().ok()) {
+        if !addrs.contains(&addr) {
+            addrs.push(addr);
+        }
+    }
+    addrs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn workers_share_the_assigned_port() {
+        let addrs = [
+            "127.0.0.1:0".parse().unwrap(),
+            "127.0.0.1:0".parse().unwrap(),
+        ];
+        let listeners = bind_tcp(&addrs, &SocketConfig::default(), 3).unwrap();
+        assert_eq!(listeners.len(), 6);
+        let bound = local_addrs(&listeners);
+        assert_eq!(bound.len(), 2);
+        assert!(bound.iter().all(|addr| addr.port() != 0));
+    }
+}
@@ -0,0 +1,64 @@
+//! Sessions the broker opens for itself through an in-memory stream
+//!
+//! Gateways and APIs which do not speak MQTT to their clients open a regular MQTT 3.1.1 session
+//! per client, so the client goes through the same authentication, authorization and delivery
+//! as any other.
+
+use std::{net::SocketAddr, sync::Arc};
+
+use futures::{SinkExt as _, StreamExt as _};
+use mqtt_codec_kit::{
+    common::ProtocolLevel,
+    v4::{
+        control::ConnectReturnCode,
+        packet::{ConnectPacket, MqttCodec, VariablePacket},
+    },
+};
+use tokio::{
+    io::{duplex, DuplexStream},
+    time,
+};
+use tokio_util::codec::Framed;
+
+use super::{config::MountPoint, process_client, state::GlobalState};
+use crate::store::{message::MessageStore, retain::RetainMessageStore, topic::TopicStore};
+
+/// Size of the in-memory stream between a local session and the broker
+const BUFFER_SIZE: usize = 64 * 1024;
+
+/// A session on the broker, spoken to with MQTT 3.1.1 packets
+pub(crate) type LocalSession = Framed<DuplexStream, MqttCodec>;
+
+/// Opens a session on the broker with `connect`, returns it with the return code of the
+/// CONNACK or `None` if the broker did not answer in time
+pub(crate) async fn connect_local<S>(
+    global: &Arc<GlobalState<S>>,
+    addr: Option<SocketAddr>,
+    mount_point: Option<MountPoint>,
+    connect: ConnectPacket,
+) -> Option<(LocalSession, ConnectReturnCode)>
+where
+    S: MessageStore + RetainMessageStore + TopicStore + 'static,
+{
+    let (client, server) = duplex(BUFFER_SIZE);
+    tokio::spawn(process_client(
+        server,
+        addr,
+        ProtocolLevel::Version311,
+        mount_point,
+        None,
+        global.clone(),
+    ));
+    let mut session = Framed::new(client, MqttCodec::new());
+    let connack = async {
+        session.send(connect).await.ok()?;
+        match session.next().await {
+            Some(Ok(VariablePacket::ConnackPacket(connack))) => Some(connack.connect_return_code()),
+            _ => None,
+        }
+    };
+    let code = time::timeout(global.handshake_timeout(), connack)
+        .await
+        .ok()??;
+    Some((session, code))
+}
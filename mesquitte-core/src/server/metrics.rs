@@ -0,0 +1,107 @@
+//! Counters of the broker, read with [`GlobalState::metrics`](super::state::GlobalState::metrics)
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use super::ErrorCategory;
+
+#[derive(Debug, Default)]
+pub struct Metrics {
+    decode_errors: AtomicU64,
+    malformed_disconnects: AtomicU64,
+    slow_consumer_disconnects: AtomicU64,
+    publish_retransmissions: AtomicU64,
+    pubrel_retransmissions: AtomicU64,
+    read_channel_full: AtomicU64,
+    deliver_channel_full: AtomicU64,
+    // indexed by `category_index`
+    errors: [AtomicU64; 4],
+}
+
+impl Metrics {
+    /// Packets which could not be decoded, over all connections
+    pub fn decode_errors(&self) -> u64 {
+        self.decode_errors.load(Ordering::Relaxed)
+    }
+
+    /// Connections closed because of a malformed packet
+    pub fn malformed_disconnects(&self) -> u64 {
+        self.malformed_disconnects.load(Ordering::Relaxed)
+    }
+
+    /// Connections closed because the client did not keep up with its messages, see
+    /// [`super::slow_consumer`]
+    pub fn slow_consumer_disconnects(&self) -> u64 {
+        self.slow_consumer_disconnects.load(Ordering::Relaxed)
+    }
+
+    /// QoS 1/2 PUBLISH packets sent again with the DUP flag, after a reconnect or because they
+    /// were not acknowledged in time
+    pub fn publish_retransmissions(&self) -> u64 {
+        self.publish_retransmissions.load(Ordering::Relaxed)
+    }
+
+    /// PUBREL packets sent again for QoS 2 messages which were not completed
+    pub fn pubrel_retransmissions(&self) -> u64 {
+        self.pubrel_retransmissions.load(Ordering::Relaxed)
+    }
+
+    /// Packets read from an MQTT 5 client which waited for room in the channel to the writer of
+    /// the connection, see [`ChannelConfig`](super::state::ChannelConfig)
+    pub fn read_channel_full(&self) -> u64 {
+        self.read_channel_full.load(Ordering::Relaxed)
+    }
+
+    /// Control messages which waited for room in the deliver channel of a session
+    pub fn deliver_channel_full(&self) -> u64 {
+        self.deliver_channel_full.load(Ordering::Relaxed)
+    }
+
+    /// Errors of the connection loops in `category`, such as failed storage calls or invalid
+    /// packets, over all connections
+    pub fn errors(&self, category: ErrorCategory) -> u64 {
+        self.errors[category_index(category)].load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn record_decode_error(&self) {
+        self.decode_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_malformed_disconnect(&self) {
+        self.malformed_disconnects.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_slow_consumer_disconnect(&self) {
+        self.slow_consumer_disconnects
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_publish_retransmission(&self) {
+        self.publish_retransmissions.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_pubrel_retransmission(&self) {
+        self.pubrel_retransmissions.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[cfg(feature = "v5")]
+    pub(crate) fn record_read_channel_full(&self) {
+        self.read_channel_full.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_deliver_channel_full(&self) {
+        self.deliver_channel_full.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_error(&self, category: ErrorCategory) {
+        self.errors[category_index(category)].fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+fn category_index(category: ErrorCategory) -> usize {
+    match category {
+        ErrorCategory::ClientError => 0,
+        ErrorCategory::ServerError => 1,
+        ErrorCategory::Storage => 2,
+        ErrorCategory::Transport => 3,
+    }
+}
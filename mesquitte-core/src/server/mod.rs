@@ -1,35 +1,117 @@
-use std::{io, num::ParseIntError};
+use std::{
+    fmt::{self, Display},
+    io,
+    net::SocketAddr,
+    num::ParseIntError,
+    sync::Arc,
+};
 
+use config::MountPoint;
 use mqtt_codec_kit::common::{protocol_level::ProtocolLevelError, ProtocolLevel};
 use state::GlobalState;
-use tokio::io::{split, AsyncRead, AsyncWrite};
+use supervisor::supervise;
+use tokio::{
+    io::{split, AsyncRead, AsyncReadExt as _, AsyncWrite, AsyncWriteExt as _},
+    time,
+};
 
 #[cfg(feature = "v4")]
 use crate::protocols::v4;
 #[cfg(feature = "v5")]
 use crate::protocols::v5;
 use crate::{
+    debug,
+    instrument::InstrumentExt as _,
     store::{message::MessageStore, retain::RetainMessageStore, topic::TopicStore},
     warn,
 };
 
+#[cfg(any(feature = "http-api", feature = "grpc-api"))]
+pub(crate) mod api;
+pub mod audit;
+#[cfg(feature = "mqtts")]
+pub(crate) mod cert_identity;
+pub mod client_info;
 pub mod config;
+pub mod dead_letter;
+#[cfg(feature = "v5")]
+pub mod enhanced_auth;
+pub mod fanout;
+pub mod interceptor;
+pub mod janitor;
+#[cfg(any(feature = "mqtt", feature = "mqtts", feature = "ws", feature = "wss"))]
+pub(crate) mod listener;
+#[cfg(any(feature = "mqtt-sn", feature = "grpc-api"))]
+pub(crate) mod local;
+pub mod metrics;
+#[cfg(feature = "mqtt-sn")]
+pub mod mqtt_sn;
+pub mod presence;
 #[cfg(feature = "quic")]
 pub mod quic;
+pub mod reload;
+pub mod rules;
 #[cfg(feature = "rustls")]
 pub mod rustls;
+pub mod shared;
+pub mod slow_consumer;
 pub mod state;
+pub(crate) mod supervisor;
 #[cfg(any(feature = "mqtt", feature = "mqtts"))]
 pub mod tcp;
+#[cfg(any(feature = "mqtts", feature = "ws", feature = "wss"))]
+pub mod tenant;
+pub mod timer;
+pub mod topic_stats;
 #[cfg(any(feature = "ws", feature = "wss"))]
 pub mod ws;
 
+/// Coarse classification of an error, used as a stable label for logs and alerting.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ErrorCategory {
+    /// The peer sent something invalid or broke the protocol.
+    ClientError,
+    /// The broker itself misbehaved or is misconfigured.
+    ServerError,
+    /// The storage backend failed.
+    Storage,
+    /// The underlying connection failed.
+    Transport,
+}
+
+impl ErrorCategory {
+    /// Categories are encoded in the thousands digit of an error code.
+    pub fn from_code(code: u16) -> Self {
+        match code / 1000 {
+            1 => ErrorCategory::ClientError,
+            3 => ErrorCategory::Storage,
+            4 => ErrorCategory::Transport,
+            _ => ErrorCategory::ServerError,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ErrorCategory::ClientError => "client-error",
+            ErrorCategory::ServerError => "server-error",
+            ErrorCategory::Storage => "storage",
+            ErrorCategory::Transport => "transport",
+        }
+    }
+}
+
+impl Display for ErrorCategory {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
     #[error("Io Error : {0}")]
     Io(#[from] io::Error),
-    #[error("Parse protocol level error : {0}")]
-    WrongConfig(#[from] ParseIntError),
+    #[error("Wrong config : {0}")]
+    WrongConfig(String),
     #[error("Wrong protocol level set : {0}")]
     ProtocolLevel(#[from] ProtocolLevelError),
     #[cfg(any(feature = "ws", feature = "wss"))]
@@ -37,6 +119,8 @@ pub enum Error {
     Accept(#[from] tungstenite::Error),
     #[error("Missing tls config")]
     MissingTlsConfig,
+    #[error("Invalid mount point: {0}")]
+    InvalidMountPoint(String),
     #[cfg(feature = "rustls")]
     #[error("Wrong tls config: {0}")]
     Rustls(#[from] crate::server::rustls::Error),
@@ -57,6 +141,12 @@ pub enum Error {
     #[cfg(feature = "quic")]
     #[error("Connection broken")]
     ConnectionBroken,
+    #[error("{name} server failed: {source}")]
+    Listener {
+        /// `mqtt`, `mqtts`, `ws`, `wss`, `quic` or `mqtt-sn`
+        name: &'static str,
+        source: Box<Error>,
+    },
     #[cfg(feature = "v4")]
     #[error(transparent)]
     V4VariablePacket(#[from] mqtt_codec_kit::v4::packet::VariablePacketError),
@@ -65,33 +155,1327 @@ pub enum Error {
     V5VariablePacket(#[from] mqtt_codec_kit::v5::packet::VariablePacketError),
 }
 
+impl From<ParseIntError> for Error {
+    fn from(err: ParseIntError) -> Self {
+        Error::WrongConfig(format!("protocol level: {err}"))
+    }
+}
+
+impl Error {
+    /// Stable numeric code of this error, codes are never reused.
+    pub fn code(&self) -> u16 {
+        match self {
+            Error::Io(_) => 4001,
+            #[cfg(any(feature = "ws", feature = "wss"))]
+            Error::Accept(_) => 4002,
+            #[cfg(feature = "quic")]
+            Error::Connection(_) => 4003,
+            #[cfg(feature = "quic")]
+            Error::ConnectionBroken => 4004,
+            // the failure of the listener itself decides
+            Error::Listener { source, .. } => source.code(),
+            Error::UnsupportProtocol(_) => 1001,
+            #[cfg(feature = "v4")]
+            Error::V4VariablePacket(_) => 1002,
+            #[cfg(feature = "v5")]
+            Error::V5VariablePacket(_) => 1003,
+            Error::WrongConfig(_) => 2001,
+            Error::ProtocolLevel(_) => 2002,
+            Error::MissingTlsConfig => 2003,
+            Error::InvalidMountPoint(_) => 2008,
+            #[cfg(feature = "rustls")]
+            Error::Rustls(_) => 2004,
+            #[cfg(feature = "quic")]
+            Error::Infallible(_) => 2005,
+            #[cfg(feature = "quic")]
+            Error::StartError(_) => 2006,
+            #[cfg(feature = "quic")]
+            Error::QuicTls(_) => 2007,
+        }
+    }
+
+    pub fn category(&self) -> ErrorCategory {
+        ErrorCategory::from_code(self.code())
+    }
+}
+
+/// CONNACK with return code `UnacceptableProtocolVersion`, written without the codec because
+/// the broker may be built without the v4 packets
+const V4_UNACCEPTABLE_PROTOCOL_VERSION: [u8; 4] = [0x20, 0x02, 0x00, 0x01];
+/// CONNACK with reason code `UnsupportedProtocolVersion` and no properties
+const V5_UNSUPPORTED_PROTOCOL_VERSION: [u8; 5] = [0x20, 0x03, 0x00, 0x84, 0x00];
+
+/// Reads the CONNECT of a protocol the broker was built without and answers it with `connack`,
+/// so the client gets a reason instead of a closed socket
+async fn refuse_connect<R, W>(mut reader: R, mut writer: W, connack: &[u8])
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    // the CONNECT is read before the reply, closing with unread data resets the connection
+    // and the client may never see the CONNACK
+    let refused = async {
+        skip_packet(&mut reader).await?;
+        writer.write_all(connack).await?;
+        writer.shutdown().await
+    };
+    if let Err(err) = refused.await {
+        debug!("refuse connect failed: {err}");
+    }
+}
+
+/// Skips one packet, the remaining length is a variable byte integer of up to four bytes
+async fn skip_packet<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<()> {
+    reader.read_u8().await?;
+    let mut remaining_length = 0u64;
+    for shift in (0..28).step_by(7) {
+        let byte = reader.read_u8().await?;
+        remaining_length |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            break;
+        }
+    }
+    tokio::io::copy(&mut reader.take(remaining_length), &mut tokio::io::sink()).await?;
+    Ok(())
+}
+
 async fn process_client<S, T>(
     stream: S,
+    remote_addr: Option<SocketAddr>,
     level: ProtocolLevel,
-    global: &'static GlobalState<T>,
+    mount_point: Option<MountPoint>,
+    // the client was authenticated by its TLS certificate, see `ServerConfig::with_cert_auth`
+    cert_identity: Option<String>,
+    global: Arc<GlobalState<T>>,
 ) -> Result<(), Error>
 where
     S: AsyncRead + AsyncWrite + Send + Sync + 'static,
-    T: MessageStore + RetainMessageStore + TopicStore,
+    T: MessageStore + RetainMessageStore + TopicStore + 'static,
 {
     let (rd, wr) = split(stream);
     match level {
         ProtocolLevel::Version310 | ProtocolLevel::Version311 => {
             if cfg!(feature = "v5") && !cfg!(feature = "v4") {
                 warn!("this broker does not support v4");
+                let _ = time::timeout(
+                    global.handshake_timeout(),
+                    refuse_connect(rd, wr, &V4_UNACCEPTABLE_PROTOCOL_VERSION),
+                )
+                .await;
                 return Err(Error::UnsupportProtocol("v4".to_string()));
             }
             #[cfg(feature = "v4")]
-            v4::EventLoop::new(rd, wr, global).run().await;
+            supervise(
+                remote_addr,
+                v4::EventLoop::new(rd, wr, remote_addr, mount_point, cert_identity, global).run(),
+            )
+            .in_connection(remote_addr, level)
+            .await;
         }
         ProtocolLevel::Version50 => {
             if cfg!(feature = "v4") && !cfg!(feature = "v5") {
                 warn!("this broker does not support v5");
+                let _ = time::timeout(
+                    global.handshake_timeout(),
+                    refuse_connect(rd, wr, &V5_UNSUPPORTED_PROTOCOL_VERSION),
+                )
+                .await;
                 return Err(Error::UnsupportProtocol("v5".to_string()));
             }
             #[cfg(feature = "v5")]
-            v5::read_write_loop::read_write_loop(rd, wr, global, storage).await
+            supervise(
+                remote_addr,
+                v5::read_write_loop::read_write_loop(
+                    rd,
+                    wr,
+                    remote_addr,
+                    mount_point,
+                    cert_identity,
+                    global,
+                ),
+            )
+            .in_connection(remote_addr, level)
+            .await
         }
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::duplex;
+
+    #[cfg(feature = "v4")]
+    use crate::store::{
+        error::StoreError,
+        session::{SessionStore, StoredSession},
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn unsupported_protocol_gets_connack() {
+        let (mut client, server) = duplex(64);
+        let (rd, wr) = split(server);
+        // MQTT 5 CONNECT with an empty client identifier
+        let connect = [
+            0x10, 0x0d, 0x00, 0x04, b'M', b'Q', b'T', b'T', 0x05, 0x02, 0x00, 0x3c, 0x00, 0x00,
+            0x00,
+        ];
+        client.write_all(&connect).await.unwrap();
+        refuse_connect(rd, wr, &V5_UNSUPPORTED_PROTOCOL_VERSION).await;
+
+        let mut connack = Vec::new();
+        client.read_to_end(&mut connack).await.unwrap();
+        assert_eq!(connack, V5_UNSUPPORTED_PROTOCOL_VERSION);
+    }
+
+    #[cfg(feature = "v4")]
+    fn memory_state() -> GlobalState<crate::store::memory::MemoryStore> {
+        GlobalState::new(crate::store::memory_storage())
+    }
+
+    #[cfg(feature = "v4")]
+    async fn connect_v4(
+        global: &Arc<GlobalState<crate::store::memory::MemoryStore>>,
+        client_id: &str,
+    ) -> tokio_util::codec::Framed<tokio::io::DuplexStream, mqtt_codec_kit::v4::packet::MqttCodec>
+    {
+        let mut connect = mqtt_codec_kit::v4::packet::ConnectPacket::new(client_id);
+        connect.set_clean_session(true);
+        connect_v4_with(global, connect).await
+    }
+
+    #[cfg(feature = "v4")]
+    async fn connect_v4_with(
+        global: &Arc<GlobalState<crate::store::memory::MemoryStore>>,
+        connect: mqtt_codec_kit::v4::packet::ConnectPacket,
+    ) -> tokio_util::codec::Framed<tokio::io::DuplexStream, mqtt_codec_kit::v4::packet::MqttCodec>
+    {
+        use futures::{SinkExt as _, StreamExt as _};
+        use mqtt_codec_kit::v4::packet::{MqttCodec, VariablePacket};
+        use tokio_util::codec::Framed;
+
+        let (client, server) = duplex(1024);
+        tokio::spawn(process_client(
+            server,
+            None,
+            ProtocolLevel::Version311,
+            None,
+            None,
+            global.clone(),
+        ));
+        let mut client = Framed::new(client, MqttCodec::new());
+
+        client.send(connect).await.unwrap();
+        assert!(matches!(
+            client.next().await,
+            Some(Ok(VariablePacket::ConnackPacket(_)))
+        ));
+        client
+    }
+
+    #[cfg(feature = "v4")]
+    #[tokio::test]
+    async fn silent_connection_is_closed() {
+        use std::time::Duration;
+
+        let global = memory_state().with_handshake_timeout(Duration::from_millis(50));
+        let (mut client, server) = duplex(64);
+        let connection = process_client(
+            server,
+            None,
+            ProtocolLevel::Version311,
+            None,
+            None,
+            Arc::new(global),
+        );
+        time::timeout(Duration::from_secs(5), connection)
+            .await
+            .expect("connection without CONNECT is not closed")
+            .unwrap();
+
+        let mut buf = Vec::new();
+        assert_eq!(client.read_to_end(&mut buf).await.unwrap(), 0);
+    }
+
+    #[cfg(feature = "v4")]
+    #[tokio::test]
+    async fn oversized_payload_is_dropped() {
+        use futures::{SinkExt as _, StreamExt as _};
+        use mqtt_codec_kit::{
+            common::{qos::QoSWithPacketIdentifier, QualityOfService, TopicFilter, TopicName},
+            v4::packet::{PublishPacket, SubscribePacket, VariablePacket},
+        };
+
+        let global = Arc::new(memory_state().with_max_payload_size(4));
+        let mut client = connect_v4(&global, "c1").await;
+        client
+            .send(SubscribePacket::new(
+                1,
+                vec![(TopicFilter::new("a").unwrap(), QualityOfService::Level0)],
+            ))
+            .await
+            .unwrap();
+        assert!(matches!(
+            client.next().await,
+            Some(Ok(VariablePacket::SubackPacket(_)))
+        ));
+
+        let topic_name = TopicName::new("a").unwrap();
+        for (packet_id, payload) in [(1, b"too large".to_vec()), (2, b"fits".to_vec())] {
+            client
+                .send(PublishPacket::new(
+                    topic_name.clone(),
+                    QoSWithPacketIdentifier::Level1(packet_id),
+                    payload,
+                ))
+                .await
+                .unwrap();
+        }
+
+        // both are acknowledged, only the one which fits is forwarded
+        let mut acked = Vec::new();
+        let mut forwarded = Vec::new();
+        while acked.len() < 2 || forwarded.is_empty() {
+            match client.next().await {
+                Some(Ok(VariablePacket::PubackPacket(ack))) => acked.push(ack.packet_identifier()),
+                Some(Ok(VariablePacket::PublishPacket(publish))) => {
+                    forwarded.push(publish.payload().to_vec())
+                }
+                other => panic!("unexpected {other:?}"),
+            }
+        }
+        assert_eq!(acked, [1, 2]);
+        assert_eq!(forwarded, [b"fits".to_vec()]);
+    }
+
+    #[cfg(feature = "v4")]
+    #[tokio::test]
+    async fn oversized_packet_closes_connection() {
+        use futures::{SinkExt as _, StreamExt as _};
+        use mqtt_codec_kit::{
+            common::{qos::QoSWithPacketIdentifier, TopicName},
+            v4::packet::{PublishPacket, VariablePacket},
+        };
+
+        let global = Arc::new(memory_state().with_max_packet_size(32));
+        let mut client = connect_v4(&global, "c1").await;
+        let topic_name = TopicName::new("a").unwrap();
+        client
+            .send(PublishPacket::new(
+                topic_name.clone(),
+                QoSWithPacketIdentifier::Level1(1),
+                b"fits".to_vec(),
+            ))
+            .await
+            .unwrap();
+        assert!(matches!(
+            client.next().await,
+            Some(Ok(VariablePacket::PubackPacket(_)))
+        ));
+
+        client
+            .send(PublishPacket::new(
+                topic_name,
+                QoSWithPacketIdentifier::Level1(2),
+                vec![0; 64],
+            ))
+            .await
+            .unwrap();
+        assert!(client.next().await.is_none());
+    }
+
+    #[cfg(feature = "v4")]
+    #[tokio::test]
+    async fn resubscribe_replaces_subscription() {
+        use std::time::Duration;
+
+        use futures::{SinkExt as _, StreamExt as _};
+        use mqtt_codec_kit::{
+            common::{qos::QoSWithPacketIdentifier, QualityOfService, TopicFilter, TopicName},
+            v4::packet::{SubscribePacket, VariablePacket},
+        };
+
+        use crate::store::message::PublishMessage;
+
+        let global = Arc::new(memory_state());
+        let topic_name = TopicName::new("a").unwrap();
+        let retained = PublishMessage::new(
+            topic_name.clone(),
+            b"retained".to_vec(),
+            QualityOfService::Level0,
+            true,
+        );
+        global.publish("p", retained).await.unwrap();
+
+        let mut client = connect_v4(&global, "c1").await;
+        for (packet_id, qos) in [(1, QualityOfService::Level1), (2, QualityOfService::Level0)] {
+            client
+                .send(SubscribePacket::new(
+                    packet_id,
+                    vec![(TopicFilter::new("a").unwrap(), qos)],
+                ))
+                .await
+                .unwrap();
+            assert!(matches!(
+                client.next().await,
+                Some(Ok(VariablePacket::SubackPacket(_)))
+            ));
+            // MQTT 3.1.1 sends the retained messages again for a replaced subscription
+            match client.next().await {
+                Some(Ok(VariablePacket::PublishPacket(publish))) => {
+                    assert_eq!(publish.payload(), b"retained")
+                }
+                other => panic!("unexpected {other:?}"),
+            }
+        }
+
+        let message = PublishMessage::new(
+            topic_name,
+            b"once".to_vec(),
+            QualityOfService::Level1,
+            false,
+        );
+        global.publish("p", message).await.unwrap();
+        match client.next().await {
+            Some(Ok(VariablePacket::PublishPacket(publish))) => {
+                assert_eq!(publish.payload(), b"once");
+                assert_eq!(publish.qos(), QoSWithPacketIdentifier::Level0);
+            }
+            other => panic!("unexpected {other:?}"),
+        }
+        let again = time::timeout(Duration::from_millis(100), client.next()).await;
+        assert!(again.is_err(), "delivered twice: {again:?}");
+    }
+
+    #[cfg(feature = "v4")]
+    #[tokio::test]
+    async fn invalid_filter_fails_only_its_subscription() {
+        use futures::{SinkExt as _, StreamExt as _};
+        use mqtt_codec_kit::{
+            common::{QualityOfService, TopicFilter},
+            v4::packet::{suback::SubscribeReturnCode, SubscribePacket, VariablePacket},
+        };
+
+        use crate::server::config::TopicFilterConfig;
+
+        let global = Arc::new(memory_state().with_topic_filter_config(TopicFilterConfig {
+            allow_empty_levels: false,
+            ..Default::default()
+        }));
+        let mut client = connect_v4(&global, "c1").await;
+        client
+            .send(SubscribePacket::new(
+                1,
+                vec![
+                    (TopicFilter::new("a//b").unwrap(), QualityOfService::Level1),
+                    (TopicFilter::new("a").unwrap(), QualityOfService::Level1),
+                ],
+            ))
+            .await
+            .unwrap();
+        match client.next().await {
+            Some(Ok(VariablePacket::SubackPacket(suback))) => assert_eq!(
+                suback.return_codes(),
+                [
+                    SubscribeReturnCode::Failure,
+                    SubscribeReturnCode::MaximumQoSLevel1
+                ]
+            ),
+            other => panic!("unexpected {other:?}"),
+        }
+    }
+
+    #[cfg(feature = "v4")]
+    #[tokio::test]
+    async fn foreign_response_topics_are_refused() {
+        use futures::{SinkExt as _, StreamExt as _};
+        use mqtt_codec_kit::{
+            common::{QualityOfService, TopicFilter},
+            v4::packet::{suback::SubscribeReturnCode, SubscribePacket, VariablePacket},
+        };
+
+        let global = Arc::new(memory_state().with_response_topic_prefix("resp"));
+        for (client_id, filter, return_code) in [
+            ("c1", "#", SubscribeReturnCode::Failure),
+            ("c1", "+/c2/x", SubscribeReturnCode::Failure),
+            ("c1", "resp/c1/#", SubscribeReturnCode::MaximumQoSLevel1),
+            ("a/b", "resp/a/#", SubscribeReturnCode::Failure),
+        ] {
+            let mut client = connect_v4(&global, client_id).await;
+            client
+                .send(SubscribePacket::new(
+                    1,
+                    vec![(TopicFilter::new(filter).unwrap(), QualityOfService::Level1)],
+                ))
+                .await
+                .unwrap();
+            match client.next().await {
+                Some(Ok(VariablePacket::SubackPacket(suback))) => {
+                    assert_eq!(suback.return_codes(), [return_code], "{filter}")
+                }
+                other => panic!("unexpected {other:?}"),
+            }
+        }
+    }
+
+    #[cfg(feature = "v4")]
+    #[tokio::test]
+    async fn invalid_topic_publish_closes_connection() {
+        use futures::{SinkExt as _, StreamExt as _};
+        use mqtt_codec_kit::{
+            common::{qos::QoSWithPacketIdentifier, TopicName},
+            v4::packet::{PublishPacket, VariablePacket},
+        };
+
+        for (prefixes, topic, refused) in [
+            (None, "$SYS/brokers", true),
+            (None, "$share/g/a", true),
+            (None, "a/b", false),
+            // a control character fails the strict check
+            (None, "a/\u{1}", true),
+            (Some("private/"), "private/a", true),
+            (Some("private/"), "$SYS/brokers", false),
+        ] {
+            let mut global = memory_state();
+            if let Some(prefix) = prefixes {
+                global = global.with_reserved_topic_prefixes([prefix]);
+            }
+            let mut client = connect_v4(&Arc::new(global), "c1").await;
+            client
+                .send(PublishPacket::new(
+                    TopicName::new(topic).unwrap(),
+                    QoSWithPacketIdentifier::Level1(1),
+                    b"m".to_vec(),
+                ))
+                .await
+                .unwrap();
+            match client.next().await {
+                None => assert!(refused, "{topic}"),
+                Some(Ok(VariablePacket::PubackPacket(_))) => assert!(!refused, "{topic}"),
+                other => panic!("unexpected {other:?}"),
+            }
+        }
+    }
+
+    #[cfg(feature = "v4")]
+    #[tokio::test]
+    async fn held_back_messages_are_flushed() {
+        use std::time::Duration;
+
+        use futures::{SinkExt as _, StreamExt as _};
+        use mqtt_codec_kit::{
+            common::{QualityOfService, TopicFilter, TopicName},
+            v4::packet::{SubscribePacket, VariablePacket},
+        };
+
+        use crate::{server::config::FlushPolicy, store::message::PublishMessage};
+
+        let global = Arc::new(memory_state().with_flush_policy(FlushPolicy::Packets(100)));
+        let mut client = connect_v4(&global, "c1").await;
+        client
+            .send(SubscribePacket::new(
+                1,
+                vec![(TopicFilter::new("a").unwrap(), QualityOfService::Level0)],
+            ))
+            .await
+            .unwrap();
+        assert!(matches!(
+            client.next().await,
+            Some(Ok(VariablePacket::SubackPacket(_)))
+        ));
+
+        // far fewer than 100 packets, flushed once the connection has nothing more to write
+        let message = PublishMessage::new(
+            TopicName::new("a").unwrap(),
+            b"m".to_vec(),
+            QualityOfService::Level0,
+            false,
+        );
+        global.publish("p", message).await.unwrap();
+        match time::timeout(Duration::from_secs(5), client.next()).await {
+            Ok(Some(Ok(VariablePacket::PublishPacket(publish)))) => {
+                assert_eq!(publish.payload(), b"m")
+            }
+            other => panic!("unexpected {other:?}"),
+        }
+    }
+
+    #[cfg(feature = "v4")]
+    #[tokio::test]
+    async fn pipelined_packets_are_all_answered() {
+        use std::time::Duration;
+
+        use futures::{SinkExt as _, StreamExt as _};
+        use mqtt_codec_kit::{
+            common::{qos::QoSWithPacketIdentifier, TopicName},
+            v4::packet::{PublishPacket, VariablePacket},
+        };
+
+        let global = Arc::new(memory_state());
+        let mut client = connect_v4(&global, "c1").await;
+        // sent in one write, the read loop decodes several of them per wakeup
+        for packet_id in 1..=100 {
+            let publish = PublishPacket::new(
+                TopicName::new("a").unwrap(),
+                QoSWithPacketIdentifier::Level1(packet_id),
+                b"m".to_vec(),
+            );
+            if packet_id < 100 {
+                client.feed(publish).await.unwrap();
+            } else {
+                client.send(publish).await.unwrap();
+            }
+        }
+        for packet_id in 1..=100 {
+            match time::timeout(Duration::from_secs(5), client.next()).await {
+                Ok(Some(Ok(VariablePacket::PubackPacket(puback)))) => {
+                    assert_eq!(puback.packet_identifier(), packet_id)
+                }
+                other => panic!("unexpected {other:?}"),
+            }
+        }
+    }
+
+    #[cfg(feature = "v4")]
+    #[tokio::test]
+    async fn mqtt31_session_is_never_present() {
+        use futures::{SinkExt as _, StreamExt as _};
+        use mqtt_codec_kit::v4::packet::{ConnectPacket, MqttCodec, VariablePacket};
+        use tokio_util::codec::Framed;
+
+        let global = Arc::new(memory_state());
+        // the second connect resumes the session of the first one
+        for _ in 0..2 {
+            let (client, server) = duplex(1024);
+            tokio::spawn(process_client(
+                server,
+                None,
+                ProtocolLevel::Version311,
+                None,
+                None,
+                global.clone(),
+            ));
+            let mut client = Framed::new(client, MqttCodec::new());
+            let mut connect = ConnectPacket::with_level("MQIsdp", "c1", 3).unwrap();
+            connect.set_clean_session(false);
+            client.send(connect).await.unwrap();
+            match client.next().await {
+                Some(Ok(VariablePacket::ConnackPacket(connack))) => {
+                    assert!(!connack.connack_flags().session_present)
+                }
+                other => panic!("unexpected {other:?}"),
+            }
+        }
+    }
+
+    #[cfg(feature = "v4")]
+    #[tokio::test]
+    async fn kicked_client_is_disconnected() {
+        use futures::StreamExt as _;
+
+        let global = Arc::new(memory_state());
+        let mut client = connect_v4(&global, "c1").await;
+
+        assert!(!global.kick_client("c2", "maintenance").await);
+        assert!(global.kick_client("c1", "maintenance").await);
+        let closed = time::timeout(std::time::Duration::from_secs(5), client.next())
+            .await
+            .expect("kicked client is not disconnected");
+        assert!(closed.is_none(), "unexpected {closed:?}");
+        assert!(global.client_info("c1").is_none());
+    }
+
+    #[cfg(feature = "v4")]
+    #[tokio::test]
+    async fn kick_can_suppress_will() {
+        use std::time::Duration;
+
+        use futures::{SinkExt as _, StreamExt as _};
+        use mqtt_codec_kit::{
+            common::{QualityOfService, TopicFilter},
+            v4::packet::{connect::LastWill, ConnectPacket, SubscribePacket, VariablePacket},
+        };
+
+        for will_on_kick in [true, false] {
+            let global = Arc::new(memory_state().with_will_on_kick(will_on_kick));
+            let mut subscriber = connect_v4(&global, "s").await;
+            subscriber
+                .send(SubscribePacket::new(
+                    1,
+                    vec![(TopicFilter::new("w").unwrap(), QualityOfService::Level0)],
+                ))
+                .await
+                .unwrap();
+            assert!(matches!(
+                subscriber.next().await,
+                Some(Ok(VariablePacket::SubackPacket(_)))
+            ));
+
+            let mut connect = ConnectPacket::new("c1");
+            connect.set_clean_session(true);
+            connect.set_will(Some(LastWill::new("w", b"gone".to_vec()).unwrap()));
+            let _client = connect_v4_with(&global, connect).await;
+            let will = global.client_will("c1").expect("will is not inspectable");
+            assert_eq!(
+                (will.topic_name.as_str(), &will.payload[..]),
+                ("w", &b"gone"[..])
+            );
+
+            assert!(global.kick_client("c1", "maintenance").await);
+            let received = time::timeout(Duration::from_millis(200), subscriber.next()).await;
+            match received {
+                Ok(Some(Ok(VariablePacket::PublishPacket(publish)))) => {
+                    assert!(will_on_kick, "suppressed will is published");
+                    assert_eq!(publish.payload(), b"gone");
+                }
+                Err(_) => assert!(!will_on_kick, "will is not published"),
+                other => panic!("unexpected {other:?}"),
+            }
+        }
+    }
+
+    #[cfg(feature = "v4")]
+    #[tokio::test]
+    async fn kicked_persistent_session_is_kept() {
+        use futures::{SinkExt as _, StreamExt as _};
+        use mqtt_codec_kit::v4::packet::{ConnectPacket, MqttCodec, VariablePacket};
+        use tokio_util::codec::Framed;
+
+        let global = Arc::new(memory_state());
+        let mut connect = ConnectPacket::new("c1");
+        connect.set_clean_session(false);
+        let mut client = connect_v4_with(&global, connect.clone()).await;
+        assert!(global.kick_client("c1", "maintenance").await);
+        let closed = time::timeout(std::time::Duration::from_secs(5), client.next())
+            .await
+            .expect("kicked client is not disconnected");
+        assert!(closed.is_none(), "unexpected {closed:?}");
+        // kicked again while offline
+        assert!(global.kick_client("c1", "maintenance").await);
+
+        let (client, server) = duplex(1024);
+        tokio::spawn(process_client(
+            server,
+            None,
+            ProtocolLevel::Version311,
+            None,
+            None,
+            global.clone(),
+        ));
+        let mut client = Framed::new(client, MqttCodec::new());
+        client.send(connect).await.unwrap();
+        match client.next().await {
+            Some(Ok(VariablePacket::ConnackPacket(connack))) => {
+                assert!(connack.connack_flags().session_present)
+            }
+            other => panic!("unexpected {other:?}"),
+        }
+    }
+
+    #[cfg(feature = "v4")]
+    #[tokio::test]
+    async fn clean_session_clears_the_messages_of_the_old_one() {
+        use std::time::Duration;
+
+        use mqtt_codec_kit::{
+            common::{qos::QoSWithPacketIdentifier, QualityOfService, TopicName},
+            v4::packet::ConnectPacket,
+        };
+
+        use crate::store::message::{MessageStore, PendingPublishMessage, PublishMessage};
+
+        let global = Arc::new(memory_state());
+        let mut connect = ConnectPacket::new("c1");
+        connect.set_clean_session(false);
+        drop(connect_v4_with(&global, connect).await);
+        time::timeout(Duration::from_secs(5), async {
+            while global
+                .client_status("c1")
+                .await
+                .is_none_or(|status| status.online)
+            {
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("session is not offline");
+        let message = PublishMessage::new(
+            TopicName::new("a").unwrap(),
+            b"kept".to_vec(),
+            QualityOfService::Level1,
+            false,
+        );
+        let pending = PendingPublishMessage::new(QoSWithPacketIdentifier::Level1(1), message);
+        global
+            .storage
+            .save_pending_publish_message("c1", 1, pending)
+            .await
+            .unwrap();
+
+        let _client = connect_v4(&global, "c1").await;
+        assert_eq!(global.storage.message_count("c1").await.unwrap(), 0);
+    }
+
+    #[cfg(feature = "v5")]
+    #[tokio::test]
+    async fn kicked_v5_persistent_session_is_kept() {
+        use futures::{SinkExt as _, StreamExt as _};
+        use mqtt_codec_kit::{
+            common::QualityOfService,
+            v5::packet::{connect::ConnectProperties, ConnectPacket, MqttCodec, VariablePacket},
+        };
+        use tokio_util::codec::Framed;
+
+        let global = Arc::new(
+            GlobalState::new(crate::store::memory_storage()).with_max_qos(QualityOfService::Level1),
+        );
+        let mut properties = ConnectProperties::default();
+        properties.set_session_expiry_interval(Some(60));
+        let mut connect = ConnectPacket::new("c1");
+        connect.set_clean_session(false);
+        connect.set_properties(properties);
+        for session_present in [false, true] {
+            let (client, server) = duplex(1024);
+            tokio::spawn(process_client(
+                server,
+                None,
+                ProtocolLevel::Version50,
+                None,
+                None,
+                global.clone(),
+            ));
+            let mut client = Framed::new(client, MqttCodec::new());
+            client.send(connect.clone()).await.unwrap();
+            match client.next().await {
+                Some(Ok(VariablePacket::ConnackPacket(connack))) => {
+                    assert_eq!(connack.connack_flags().session_present, session_present)
+                }
+                other => panic!("unexpected {other:?}"),
+            }
+            if session_present {
+                break;
+            }
+
+            assert!(global.kick_client("c1", "maintenance").await);
+            assert!(matches!(
+                client.next().await,
+                Some(Ok(VariablePacket::DisconnectPacket(_)))
+            ));
+        }
+    }
+
+    #[cfg(feature = "v5")]
+    #[tokio::test]
+    async fn panicked_v5_connection_releases_client() {
+        use std::time::Duration;
+
+        use futures::{future::BoxFuture, SinkExt as _, StreamExt as _};
+        use mqtt_codec_kit::{
+            common::{qos::QoSWithPacketIdentifier, TopicName},
+            v5::{
+                control::ConnectReasonCode,
+                packet::{ConnectPacket, MqttCodec, PublishPacket, VariablePacket},
+            },
+        };
+        use tokio_util::codec::Framed;
+
+        use crate::{
+            server::{
+                interceptor::{InterceptAction, MessageInterceptor},
+                state::DuplicateClientIdPolicy,
+            },
+            store::message::PublishMessage,
+        };
+
+        struct Panicking;
+
+        impl MessageInterceptor for Panicking {
+            fn on_publish<'a>(
+                &'a self,
+                _client_id: &'a str,
+                _message: &'a mut PublishMessage,
+            ) -> BoxFuture<'a, InterceptAction> {
+                panic!("interceptor failed")
+            }
+        }
+
+        let global = Arc::new(
+            GlobalState::new(crate::store::memory_storage())
+                .with_duplicate_client_id(DuplicateClientIdPolicy::Reject)
+                .with_interceptor(Panicking),
+        );
+        let connect = |global: &Arc<GlobalState<_>>| {
+            let (client, server) = duplex(1024);
+            tokio::spawn(process_client(
+                server,
+                None,
+                ProtocolLevel::Version50,
+                None,
+                None,
+                global.clone(),
+            ));
+            Framed::new(client, MqttCodec::new())
+        };
+
+        let mut client = connect(&global);
+        client.send(ConnectPacket::new("c1")).await.unwrap();
+        assert!(matches!(
+            client.next().await,
+            Some(Ok(VariablePacket::ConnackPacket(_)))
+        ));
+        client
+            .send(PublishPacket::new(
+                TopicName::new("a").unwrap(),
+                QoSWithPacketIdentifier::Level0,
+                b"hello".to_vec(),
+            ))
+            .await
+            .unwrap();
+        time::timeout(Duration::from_secs(5), async {
+            while global.client_info("c1").is_some() {
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("client of the panicked connection is not released");
+
+        // the client id is free again despite the reject policy
+        let mut client = connect(&global);
+        client.send(ConnectPacket::new("c1")).await.unwrap();
+        match client.next().await {
+            Some(Ok(VariablePacket::ConnackPacket(connack))) => {
+                assert_eq!(connack.connect_reason_code(), ConnectReasonCode::Success)
+            }
+            other => panic!("unexpected {other:?}"),
+        }
+    }
+
+    #[cfg(feature = "v4")]
+    #[tokio::test]
+    async fn presence_events_are_published() {
+        use futures::{SinkExt as _, StreamExt as _};
+        use mqtt_codec_kit::{
+            common::{QualityOfService, TopicFilter},
+            v4::packet::{DisconnectPacket, SubscribePacket, VariablePacket},
+        };
+
+        use crate::server::presence::PresenceConfig;
+
+        let global = Arc::new(memory_state().with_presence_events(PresenceConfig::new("n1")));
+        let mut watcher = connect_v4(&global, "watcher").await;
+        watcher
+            .send(SubscribePacket::new(
+                1,
+                vec![(
+                    TopicFilter::new("$SYS/brokers/+/clients/+/+").unwrap(),
+                    QualityOfService::Level0,
+                )],
+            ))
+            .await
+            .unwrap();
+        assert!(matches!(
+            watcher.next().await,
+            Some(Ok(VariablePacket::SubackPacket(_)))
+        ));
+
+        let mut client = connect_v4(&global, "c1").await;
+        client.send(DisconnectPacket::new()).await.unwrap();
+        for (event, field) in [
+            ("connected", r#""clean_start":true"#),
+            ("disconnected", r#""reason":"client disconnected""#),
+        ] {
+            match time::timeout(std::time::Duration::from_secs(5), watcher.next()).await {
+                Ok(Some(Ok(VariablePacket::PublishPacket(publish)))) => {
+                    assert_eq!(
+                        &publish.topic_name()[..],
+                        format!("$SYS/brokers/n1/clients/c1/{event}")
+                    );
+                    let payload = String::from_utf8(publish.payload().to_vec()).unwrap();
+                    assert!(payload.contains(field), "{payload}");
+                }
+                other => panic!("unexpected {other:?}"),
+            }
+        }
+    }
+
+    #[cfg(feature = "v4")]
+    #[tokio::test]
+    async fn client_status_outlives_clean_session() {
+        use std::{collections::HashMap, time::Duration};
+
+        use futures::{SinkExt as _, StreamExt as _};
+        use mqtt_codec_kit::v4::packet::DisconnectPacket;
+        use parking_lot::Mutex;
+
+        use crate::store::{
+            error::StoreError,
+            session::{SessionStore, StoredPresence, StoredSession},
+        };
+
+        #[derive(Default)]
+        struct Presences(Mutex<HashMap<String, StoredPresence>>);
+
+        impl SessionStore for Presences {
+            fn save_session(&self, _session: &StoredSession) -> Result<(), StoreError> {
+                Ok(())
+            }
+
+            fn load_session(&self, _client_id: &str) -> Result<Option<StoredSession>, StoreError> {
+                Ok(None)
+            }
+
+            fn remove_session(&self, _client_id: &str) -> Result<(), StoreError> {
+                Ok(())
+            }
+
+            fn load_all_sessions(&self) -> Result<Vec<StoredSession>, StoreError> {
+                Ok(Vec::new())
+            }
+
+            fn save_presence(&self, presence: &StoredPresence) -> Result<(), StoreError> {
+                let mut presences = self.0.lock();
+                presences.insert(presence.client_id.clone(), presence.clone());
+                Ok(())
+            }
+
+            fn load_presence(&self, client_id: &str) -> Result<Option<StoredPresence>, StoreError> {
+                Ok(self.0.lock().get(client_id).cloned())
+            }
+        }
+
+        let global = Arc::new(memory_state().with_session_store(Presences::default()));
+        assert!(global.client_status("c1").await.is_none());
+        let mut client = connect_v4(&global, "c1").await;
+        let status = global.client_status("c1").await.unwrap();
+        assert!(status.online);
+        assert!(status.connected_at.is_some());
+        assert!(status.disconnected_at.is_none());
+
+        client.send(DisconnectPacket::new()).await.unwrap();
+        assert!(client.next().await.is_none());
+        // the clean session is gone, its presence is kept
+        let status = time::timeout(Duration::from_secs(5), async {
+            loop {
+                match global.client_status("c1").await {
+                    Some(status) if status.disconnected_at.is_some() => break status,
+                    _ => time::sleep(Duration::from_millis(10)).await,
+                }
+            }
+        })
+        .await
+        .expect("disconnect is not recorded");
+        assert!(!status.online);
+        assert!(status.connected_at <= status.disconnected_at);
+    }
+
+    #[cfg(feature = "v4")]
+    #[tokio::test]
+    async fn pending_messages_go_first_on_reconnect() {
+        use std::time::Duration;
+
+        use futures::{SinkExt as _, StreamExt as _};
+        use mqtt_codec_kit::{
+            common::{QualityOfService, TopicFilter, TopicName},
+            v4::packet::{ConnectPacket, MqttCodec, PubackPacket, SubscribePacket, VariablePacket},
+        };
+        use tokio::io::DuplexStream;
+        use tokio_util::codec::Framed;
+
+        use crate::store::message::PublishMessage;
+
+        // the payloads and packet identifiers of the next `count` publishes, then checks that
+        // no more are sent while they are unacknowledged
+        async fn receive(
+            client: &mut Framed<DuplexStream, MqttCodec>,
+            count: usize,
+        ) -> Vec<(String, u16)> {
+            let mut received = Vec::new();
+            for _ in 0..count {
+                match time::timeout(Duration::from_secs(5), client.next()).await {
+                    Ok(Some(Ok(VariablePacket::PublishPacket(publish)))) => received.push((
+                        String::from_utf8(publish.payload().to_vec()).unwrap(),
+                        publish.qos().split().1.unwrap(),
+                    )),
+                    other => panic!("unexpected {other:?}"),
+                }
+            }
+            assert!(time::timeout(Duration::from_millis(200), client.next())
+                .await
+                .is_err());
+            received
+        }
+
+        async fn publish(global: &GlobalState<crate::store::memory::MemoryStore>, payload: &str) {
+            let message = PublishMessage::new(
+                TopicName::new("a/b").unwrap(),
+                payload.as_bytes().to_vec(),
+                QualityOfService::Level1,
+                false,
+            );
+            global.publish("p", message).await.unwrap();
+        }
+
+        let global = Arc::new(memory_state().with_max_inflight(2));
+        let mut connect = ConnectPacket::new("c1");
+        connect.set_clean_session(false);
+        let mut client = connect_v4_with(&global, connect.clone()).await;
+        client
+            .send(SubscribePacket::new(
+                1,
+                vec![(TopicFilter::new("a/#").unwrap(), QualityOfService::Level1)],
+            ))
+            .await
+            .unwrap();
+        assert!(matches!(
+            client.next().await,
+            Some(Ok(VariablePacket::SubackPacket(_)))
+        ));
+
+        for payload in ["0", "1", "2"] {
+            publish(&global, payload).await;
+        }
+        let received = receive(&mut client, 2).await;
+        assert_eq!(received[0].0, "0");
+        assert_eq!(received[1].0, "1");
+        drop(client);
+        time::timeout(Duration::from_secs(5), async {
+            while global.is_client_connected("c1") {
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("client is not disconnected");
+
+        // the unacknowledged messages are resent ahead of the ones queued and published since
+        publish(&global, "3").await;
+        let mut client = connect_v4_with(&global, connect).await;
+        publish(&global, "4").await;
+        let mut payloads = Vec::new();
+        for count in [2, 2, 1] {
+            for (payload, packet_id) in receive(&mut client, count).await {
+                payloads.push(payload);
+                client.send(PubackPacket::new(packet_id)).await.unwrap();
+            }
+        }
+        assert_eq!(payloads, ["0", "1", "2", "3", "4"]);
+    }
+
+    /// A session store kept across the brokers of a test, as a restart would
+    #[cfg(feature = "v4")]
+    #[derive(Clone, Default)]
+    struct Sessions(Arc<parking_lot::Mutex<std::collections::HashMap<String, StoredSession>>>);
+
+    #[cfg(feature = "v4")]
+    impl SessionStore for Sessions {
+        fn save_session(&self, session: &StoredSession) -> Result<(), StoreError> {
+            let mut sessions = self.0.lock();
+            sessions.insert(session.client_id.clone(), session.clone());
+            Ok(())
+        }
+
+        fn load_session(&self, client_id: &str) -> Result<Option<StoredSession>, StoreError> {
+            Ok(self.0.lock().get(client_id).cloned())
+        }
+
+        fn remove_session(&self, client_id: &str) -> Result<(), StoreError> {
+            self.0.lock().remove(client_id);
+            Ok(())
+        }
+
+        fn load_all_sessions(&self) -> Result<Vec<StoredSession>, StoreError> {
+            Ok(self.0.lock().values().cloned().collect())
+        }
+    }
+
+    #[cfg(feature = "v4")]
+    #[tokio::test]
+    async fn persistent_session_survives_restart() {
+        use futures::{SinkExt as _, StreamExt as _};
+        use mqtt_codec_kit::{
+            common::{qos::QoSWithPacketIdentifier, QualityOfService, TopicFilter, TopicName},
+            v4::packet::{
+                ConnectPacket, MqttCodec, PublishPacket, SubscribePacket, VariablePacket,
+            },
+        };
+        use tokio_util::codec::Framed;
+
+        let sessions = Sessions::default();
+        let mut connect = ConnectPacket::new("c1");
+        connect.set_clean_session(false);
+
+        let global = Arc::new(memory_state().with_session_store(sessions.clone()));
+        let mut client = connect_v4_with(&global, connect.clone()).await;
+        client
+            .send(SubscribePacket::new(
+                1,
+                vec![(TopicFilter::new("a/#").unwrap(), QualityOfService::Level1)],
+            ))
+            .await
+            .unwrap();
+        assert!(matches!(
+            client.next().await,
+            Some(Ok(VariablePacket::SubackPacket(_)))
+        ));
+        drop(client);
+
+        // a new broker on the same session store
+        let global = Arc::new(memory_state().with_session_store(sessions.clone()));
+        assert_eq!(global.restore_sessions().await.unwrap(), 1);
+        let (client, server) = duplex(1024);
+        tokio::spawn(process_client(
+            server,
+            None,
+            ProtocolLevel::Version311,
+            None,
+            None,
+            global.clone(),
+        ));
+        let mut client = Framed::new(client, MqttCodec::new());
+        client.send(connect).await.unwrap();
+        match client.next().await {
+            Some(Ok(VariablePacket::ConnackPacket(connack))) => {
+                assert!(connack.connack_flags().session_present)
+            }
+            other => panic!("unexpected {other:?}"),
+        }
+
+        let mut publisher = connect_v4(&global, "c2").await;
+        publisher
+            .send(PublishPacket::new(
+                TopicName::new("a/b").unwrap(),
+                QoSWithPacketIdentifier::Level0,
+                b"hello".to_vec(),
+            ))
+            .await
+            .unwrap();
+        match client.next().await {
+            Some(Ok(VariablePacket::PublishPacket(publish))) => {
+                assert_eq!(publish.payload(), b"hello")
+            }
+            other => panic!("unexpected {other:?}"),
+        }
+    }
+
+    #[cfg(feature = "v4")]
+    #[tokio::test]
+    async fn clean_connect_discards_restored_session() {
+        use futures::{SinkExt as _, StreamExt as _};
+        use mqtt_codec_kit::{
+            common::{QualityOfService, TopicFilter, TopicName},
+            v4::packet::{ConnectPacket, SubscribePacket, VariablePacket},
+        };
+
+        use crate::store::topic::TopicStore as _;
+
+        let sessions = Sessions::default();
+        let mut connect = ConnectPacket::new("c1");
+        connect.set_clean_session(false);
+
+        let global = Arc::new(memory_state().with_session_store(sessions.clone()));
+        let mut client = connect_v4_with(&global, connect).await;
+        client
+            .send(SubscribePacket::new(
+                1,
+                vec![(TopicFilter::new("a/#").unwrap(), QualityOfService::Level1)],
+            ))
+            .await
+            .unwrap();
+        assert!(matches!(
+            client.next().await,
+            Some(Ok(VariablePacket::SubackPacket(_)))
+        ));
+        drop(client);
+
+        // a new broker on the same session store, the client comes back with a clean session
+        let global = Arc::new(memory_state().with_session_store(sessions.clone()));
+        assert_eq!(global.restore_sessions().await.unwrap(), 1);
+        let subscribed = |topics: Vec<crate::store::topic::TopicContent>| {
+            topics.iter().any(|topic| topic.clients.contains_key("c1"))
+        };
+        let topic_name = TopicName::new("a/b").unwrap();
+        assert!(subscribed(
+            global.storage.match_topic(&topic_name).await.unwrap()
+        ));
+
+        let _client = connect_v4(&global, "c1").await;
+        assert!(!subscribed(
+            global.storage.match_topic(&topic_name).await.unwrap()
+        ));
+        assert_eq!(global.storage.message_count("c1").await.unwrap(), 0);
+    }
+
+    #[cfg(feature = "v4")]
+    #[tokio::test]
+    async fn drain_refuses_new_connections() {
+        use futures::{SinkExt as _, StreamExt as _};
+        use mqtt_codec_kit::v4::{
+            control::ConnectReturnCode,
+            packet::{ConnectPacket, MqttCodec, VariablePacket},
+        };
+        use tokio_util::codec::Framed;
+
+        use crate::server::state::Redirection;
+
+        let global = Arc::new(memory_state());
+        let mut client = connect_v4(&global, "c1").await;
+        let redirection = Redirection {
+            server_reference: "other:1883".to_owned(),
+            permanent: false,
+        };
+        assert_eq!(global.drain(redirection).await, 1);
+        assert!(client.next().await.is_none(), "drained client is connected");
+
+        let (refused, server) = duplex(1024);
+        tokio::spawn(process_client(
+            server,
+            None,
+            ProtocolLevel::Version311,
+            None,
+            None,
+            global.clone(),
+        ));
+        let mut refused = Framed::new(refused, MqttCodec::new());
+        refused.send(ConnectPacket::new("c2")).await.unwrap();
+        match refused.next().await {
+            Some(Ok(VariablePacket::ConnackPacket(connack))) => assert_eq!(
+                connack.connect_return_code(),
+                ConnectReturnCode::ServiceUnavailable
+            ),
+            other => panic!("unexpected {other:?}"),
+        }
+
+        global.stop_draining();
+        connect_v4(&global, "c2").await;
+    }
+
+    #[cfg(feature = "v4")]
+    #[tokio::test]
+    async fn strict_handshake_refuses_early_packets() {
+        use futures::{SinkExt as _, StreamExt as _};
+        use mqtt_codec_kit::v4::packet::{ConnectPacket, MqttCodec, PingreqPacket, VariablePacket};
+        use tokio_util::codec::Framed;
+
+        let global = Arc::new(memory_state().with_strict_handshake(true));
+        let (client, server) = duplex(1024);
+        let connection = process_client(
+            server,
+            None,
+            ProtocolLevel::Version311,
+            None,
+            None,
+            global.clone(),
+        );
+        let mut client = Framed::new(client, MqttCodec::new());
+        let connect = VariablePacket::from(ConnectPacket::new("c1"));
+        client.feed(connect).await.unwrap();
+        let ping = VariablePacket::from(PingreqPacket::new());
+        // written at once with the buffered CONNECT
+        client.send(ping).await.unwrap();
+        connection.await.unwrap();
+
+        assert!(client.next().await.is_none(), "early packet is accepted");
+        assert!(global.client_info("c1").is_none());
+    }
+}
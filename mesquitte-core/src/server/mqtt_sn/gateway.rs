@@ -0,0 +1,679 @@
+//! Sessions of the clients of an MQTT-SN gateway
+//!
+//! Every client gets a regular MQTT 3.1.1 session on the broker, connected through an
+//! in-memory stream: the gateway translates the packets of the client into packets of the
+//! session and back. Topic ids are resolved to topic names on the way in and registered with the
+//! client on the way out. Messages for a sleeping client are kept until it wakes up, while the
+//! gateway keeps its session alive.
+
+use std::{collections::VecDeque, io, net::SocketAddr, sync::Arc, time::Duration};
+
+use foldhash::{HashMap, HashMapExt as _, HashSet, HashSetExt as _};
+use futures::{SinkExt as _, StreamExt as _};
+use kanal::AsyncReceiver;
+use mqtt_codec_kit::{
+    common::{qos::QoSWithPacketIdentifier, QualityOfService, TopicFilter, TopicName},
+    v4::{
+        control::ConnectReturnCode,
+        packet::{
+            connect::LastWill, suback::SubscribeReturnCode, ConnectPacket, DisconnectPacket,
+            PingreqPacket, PubackPacket, PubcompPacket, PublishPacket, PubrecPacket, PubrelPacket,
+            SubscribePacket, UnsubscribePacket, VariablePacket,
+        },
+    },
+};
+use tokio::{
+    net::UdpSocket,
+    time::{self, Instant},
+};
+
+use super::packet::{Flags, Packet, ReturnCode, Topic};
+use crate::{
+    debug,
+    server::{
+        config::MqttSnConfig,
+        local::{connect_local, LocalSession},
+        state::GlobalState,
+    },
+    store::{message::MessageStore, retain::RetainMessageStore, topic::TopicStore},
+};
+
+/// A client of the gateway, reached with datagrams on the socket it sent to
+#[derive(Clone)]
+pub(crate) struct Peer {
+    pub socket: Arc<UdpSocket>,
+    pub addr: SocketAddr,
+}
+
+impl Peer {
+    pub async fn send(&self, packet: &Packet) {
+        if let Err(err) = self.socket.send_to(&packet.encode(), self.addr).await {
+            debug!("mqtt-sn send to {} failed: {err}", self.addr);
+        }
+    }
+}
+
+/// Runs the session of the client which sent `connect` until it disconnects or is lost, a
+/// client connecting again with a new session replaces the session on the broker
+pub(crate) async fn run<S>(
+    global: Arc<GlobalState<S>>,
+    config: Arc<MqttSnConfig>,
+    peer: Peer,
+    mut connect: Packet,
+    inbox: AsyncReceiver<Packet>,
+) where
+    S: MessageStore + RetainMessageStore + TopicStore + 'static,
+{
+    loop {
+        let Some(session) = Session::connect(&global, &config, &peer, connect, &inbox).await else {
+            return;
+        };
+        match session.serve(&inbox).await {
+            Some(next) => connect = next,
+            None => return,
+        }
+    }
+}
+
+/// Topic ids registered with a client, by the client or by the gateway
+#[derive(Default)]
+struct Topics {
+    names: HashMap<u16, String>,
+    ids: HashMap<String, u16>,
+    last_id: u16,
+}
+
+impl Topics {
+    /// The id of a topic name, registered if it has none yet, `None` once every id is taken
+    fn register(&mut self, name: &str) -> Option<u16> {
+        if let Some(id) = self.ids.get(name) {
+            return Some(*id);
+        }
+        self.last_id = self.last_id.checked_add(1)?;
+        self.names.insert(self.last_id, name.to_owned());
+        self.ids.insert(name.to_owned(), self.last_id);
+        Some(self.last_id)
+    }
+
+    fn forget(&mut self, id: u16) {
+        if let Some(name) = self.names.remove(&id) {
+            self.ids.remove(&name);
+        }
+    }
+}
+
+enum State {
+    Active,
+    /// Sleeping, lost if it does not wake up before the deadline
+    Asleep {
+        until: Instant,
+    },
+}
+
+enum Flow {
+    Continue,
+    Stop,
+    /// The client connected again with a new session
+    Reconnect(Packet),
+}
+
+struct Session {
+    peer: Peer,
+    config: Arc<MqttSnConfig>,
+    broker: LocalSession,
+    client_id: String,
+    keep_alive: u16,
+    state: State,
+    topics: Topics,
+    /// Messages from the broker kept while the client sleeps
+    kept: VecDeque<PublishPacket>,
+    /// Messages waiting for the client to accept the REGISTER of their topic, with the topic id
+    /// by message id of the REGISTER
+    registering: HashMap<u16, (u16, Vec<PublishPacket>)>,
+    last_register_id: u16,
+    /// Topic ids of the QoS 1 publishes of the client, sent back in the PUBACK
+    publishing: HashMap<u16, u16>,
+    /// Topics of the subscriptions waiting for their SUBACK
+    subscribing: HashMap<u16, Topic>,
+    /// QoS 2 messages acknowledged to the broker for a client which refused their topic
+    refused: HashSet<u16>,
+    /// When the gateway pings the broker for a sleeping client
+    ping_at: Instant,
+}
+
+impl Session {
+    /// Connects the client to the broker, the will is asked from the client first if it has one
+    async fn connect<S>(
+        global: &Arc<GlobalState<S>>,
+        config: &Arc<MqttSnConfig>,
+        peer: &Peer,
+        connect: Packet,
+        inbox: &AsyncReceiver<Packet>,
+    ) -> Option<Self>
+    where
+        S: MessageStore + RetainMessageStore + TopicStore + 'static,
+    {
+        let Packet::Connect {
+            flags,
+            duration,
+            client_id,
+        } = connect
+        else {
+            return None;
+        };
+        let mut packet = ConnectPacket::new(client_id.as_str());
+        packet.set_clean_session(flags.clean_session);
+        packet.set_keep_alive(duration);
+        if flags.will {
+            let will = time::timeout(global.handshake_timeout(), ask_will(peer, inbox)).await;
+            match will {
+                Ok(Some(Some((flags, will)))) => {
+                    packet.set_will(Some(will));
+                    packet.set_will_qos(flags.qos.map_or(0, |qos| qos as u8));
+                    packet.set_will_retain(flags.retain);
+                }
+                Ok(Some(None)) => {}
+                Ok(None) => {
+                    let return_code = ReturnCode::NotSupported;
+                    peer.send(&Packet::Connack { return_code }).await;
+                    return None;
+                }
+                Err(_) => {
+                    debug!("mqtt-sn client {client_id} did not send its will in time");
+                    return None;
+                }
+            }
+        }
+
+        let mount_point = config.mount_point.clone();
+        let Some((broker, code)) =
+            connect_local(global, Some(peer.addr), mount_point, packet).await
+        else {
+            let return_code = ReturnCode::Congestion;
+            peer.send(&Packet::Connack { return_code }).await;
+            return None;
+        };
+        let return_code = match code {
+            ConnectReturnCode::ConnectionAccepted => ReturnCode::Accepted,
+            ConnectReturnCode::ServiceUnavailable => ReturnCode::Congestion,
+            _ => ReturnCode::NotSupported,
+        };
+        peer.send(&Packet::Connack { return_code }).await;
+        if return_code != ReturnCode::Accepted {
+            return None;
+        }
+
+        Some(Self {
+            peer: peer.clone(),
+            config: config.clone(),
+            broker,
+            client_id,
+            keep_alive: duration,
+            state: State::Active,
+            topics: Topics::default(),
+            kept: VecDeque::new(),
+            registering: HashMap::new(),
+            last_register_id: 0,
+            publishing: HashMap::new(),
+            subscribing: HashMap::new(),
+            refused: HashSet::new(),
+            ping_at: Instant::now(),
+        })
+    }
+
+    /// Translates packets until the session ends, returns the CONNECT of a client connecting
+    /// again with a new session
+    async fn serve(mut self, inbox: &AsyncReceiver<Packet>) -> Option<Packet> {
+        loop {
+            let (asleep, until) = match self.state {
+                State::Active => (false, self.ping_at),
+                State::Asleep { until } => (true, until),
+            };
+            let ping = asleep && self.keep_alive > 0;
+            // a sleeping client which has many messages waiting leaves the rest with the broker
+            let take = !asleep || self.kept.len() < self.config.max_sleep_messages;
+            let flow = tokio::select! {
+                packet = inbox.recv() => match packet {
+                    Ok(packet) => self.client_packet(packet).await,
+                    Err(_) => Ok(Flow::Stop),
+                },
+                packet = self.broker.next(), if take => match packet {
+                    Some(Ok(packet)) => self.broker_packet(packet).await.map(|_| Flow::Continue),
+                    _ => {
+                        debug!("broker closed the session of mqtt-sn client {}", self.client_id);
+                        self.peer.send(&Packet::Disconnect { duration: None }).await;
+                        Ok(Flow::Stop)
+                    }
+                },
+                _ = time::sleep_until(self.ping_at), if ping => {
+                    self.ping_at += self.ping_interval();
+                    self.broker.send(PingreqPacket::new()).await.map(|_| Flow::Continue)
+                }
+                _ = time::sleep_until(until), if asleep => {
+                    // the session is dropped without a DISCONNECT, the broker publishes the will
+                    debug!("sleeping mqtt-sn client {} is lost", self.client_id);
+                    Ok(Flow::Stop)
+                }
+            };
+            match flow {
+                Ok(Flow::Continue) => {}
+                Ok(Flow::Stop) => return None,
+                Ok(Flow::Reconnect(connect)) => {
+                    let _ = self.broker.send(DisconnectPacket::new()).await;
+                    return Some(connect);
+                }
+                Err(err) => {
+                    debug!("session of mqtt-sn client {} failed: {err}", self.client_id);
+                    return None;
+                }
+            }
+        }
+    }
+
+    fn ping_interval(&self) -> Duration {
+        Duration::from_secs(u64::from(self.keep_alive / 2).max(1))
+    }
+
+    async fn client_packet(&mut self, packet: Packet) -> io::Result<Flow> {
+        match packet {
+            Packet::Connect {
+                ref client_id,
+                flags,
+                ..
+            } => {
+                // a sleeping client connecting with its session becomes active again
+                let asleep = matches!(self.state, State::Asleep { .. });
+                if !asleep || flags.clean_session || *client_id != self.client_id {
+                    return Ok(Flow::Reconnect(packet));
+                }
+                self.state = State::Active;
+                let return_code = ReturnCode::Accepted;
+                self.peer.send(&Packet::Connack { return_code }).await;
+                self.deliver_kept().await?;
+            }
+            Packet::Register {
+                msg_id, topic_name, ..
+            } => {
+                let (topic_id, return_code) = match TopicName::new(topic_name.as_str()) {
+                    Ok(_) => match self.topics.register(&topic_name) {
+                        Some(topic_id) => (topic_id, ReturnCode::Accepted),
+                        None => (0, ReturnCode::Congestion),
+                    },
+                    Err(_) => (0, ReturnCode::NotSupported),
+                };
+                let regack = Packet::Regack {
+                    topic_id,
+                    msg_id,
+                    return_code,
+                };
+                self.peer.send(&regack).await;
+            }
+            Packet::Regack {
+                msg_id,
+                return_code,
+                ..
+            } => {
+                let Some((topic_id, messages)) = self.registering.remove(&msg_id) else {
+                    return Ok(Flow::Continue);
+                };
+                for publish in messages {
+                    if return_code == ReturnCode::Accepted {
+                        self.send_publish(Topic::Id(topic_id), publish).await;
+                    } else {
+                        self.refuse(publish).await?;
+                    }
+                }
+                if return_code != ReturnCode::Accepted {
+                    self.topics.forget(topic_id);
+                }
+            }
+            Packet::Publish {
+                flags,
+                topic,
+                msg_id,
+                data,
+            } => self.publish(flags, topic, msg_id, data).await?,
+            Packet::Puback {
+                topic_id,
+                msg_id,
+                return_code,
+            } => {
+                // a client which lost the topic id gets it registered again with the next
+                // message
+                if return_code == ReturnCode::InvalidTopicId {
+                    self.topics.forget(topic_id);
+                }
+                self.broker.send(PubackPacket::new(msg_id)).await?;
+            }
+            Packet::Pubrec { msg_id } => self.broker.send(PubrecPacket::new(msg_id)).await?,
+            Packet::Pubrel { msg_id } => self.broker.send(PubrelPacket::new(msg_id)).await?,
+            Packet::Pubcomp { msg_id } => self.broker.send(PubcompPacket::new(msg_id)).await?,
+            Packet::Subscribe {
+                flags,
+                msg_id,
+                topic,
+            } => {
+                let Some(filter) = self
+                    .topic_name(&topic)
+                    .and_then(|f| TopicFilter::new(f).ok())
+                else {
+                    let suback = Packet::Suback {
+                        flags: Flags::default(),
+                        topic_id: 0,
+                        msg_id,
+                        return_code: ReturnCode::InvalidTopicId,
+                    };
+                    self.peer.send(&suback).await;
+                    return Ok(Flow::Continue);
+                };
+                let qos = flags.qos.unwrap_or(QualityOfService::Level0);
+                self.subscribing.insert(msg_id, topic);
+                let subscribe = SubscribePacket::new(msg_id, vec![(filter, qos)]);
+                self.broker.send(subscribe).await?;
+            }
+            Packet::Unsubscribe { msg_id, topic } => {
+                let Some(filter) = self
+                    .topic_name(&topic)
+                    .and_then(|f| TopicFilter::new(f).ok())
+                else {
+                    self.peer.send(&Packet::Unsuback { msg_id }).await;
+                    return Ok(Flow::Continue);
+                };
+                let unsubscribe = UnsubscribePacket::new(msg_id, vec![filter]);
+                self.broker.send(unsubscribe).await?;
+            }
+            Packet::Pingreq { client_id } => match self.state {
+                // the awake client gets the messages kept for it, the PINGRESP sends it back to
+                // sleep
+                State::Asleep { .. } if client_id.is_some() => {
+                    self.state = State::Asleep {
+                        until: self.sleep_deadline(self.keep_alive),
+                    };
+                    self.deliver_kept().await?;
+                    self.peer.send(&Packet::Pingresp).await;
+                }
+                _ => self.broker.send(PingreqPacket::new()).await?,
+            },
+            Packet::Disconnect { duration: None } => {
+                self.broker.send(DisconnectPacket::new()).await?;
+                self.peer.send(&Packet::Disconnect { duration: None }).await;
+                return Ok(Flow::Stop);
+            }
+            Packet::Disconnect {
+                duration: Some(duration),
+            } => {
+                debug!("mqtt-sn client {} sleeps for {duration}s", self.client_id);
+                self.state = State::Asleep {
+                    until: self.sleep_deadline(duration),
+                };
+                self.ping_at = Instant::now() + self.ping_interval();
+                self.peer.send(&Packet::Disconnect { duration: None }).await;
+            }
+            packet => debug!(
+                "mqtt-sn client {} sent unexpected packet {packet:?}",
+                self.client_id
+            ),
+        }
+        Ok(Flow::Continue)
+    }
+
+    /// A sleeping client is lost once it slept half as long again as it said
+    fn sleep_deadline(&self, duration: u16) -> Instant {
+        Instant::now() + Duration::from_secs(u64::from(duration) * 3 / 2)
+    }
+
+    async fn publish(
+        &mut self,
+        flags: Flags,
+        topic: Topic,
+        msg_id: u16,
+        data: Vec<u8>,
+    ) -> io::Result<()> {
+        let topic_id = topic_id(&topic);
+        let Some(topic_name) = self
+            .topic_name(&topic)
+            .and_then(|name| TopicName::new(name).ok())
+        else {
+            let puback = Packet::Puback {
+                topic_id,
+                msg_id,
+                return_code: ReturnCode::InvalidTopicId,
+            };
+            self.peer.send(&puback).await;
+            return Ok(());
+        };
+        let qos = match flags.qos {
+            Some(QualityOfService::Level1) => {
+                self.publishing.insert(msg_id, topic_id);
+                QoSWithPacketIdentifier::Level1(msg_id)
+            }
+            Some(QualityOfService::Level2) => QoSWithPacketIdentifier::Level2(msg_id),
+            Some(QualityOfService::Level0) | None => QoSWithPacketIdentifier::Level0,
+        };
+        let mut publish = PublishPacket::new(topic_name, qos, data);
+        publish.set_dup(flags.dup);
+        publish.set_retain(flags.retain);
+        self.broker.send(publish).await
+    }
+
+    /// The topic name or filter a packet of the client refers to
+    fn topic_name(&self, topic: &Topic) -> Option<String> {
+        match topic {
+            Topic::Id(id) => self.topics.names.get(id).cloned(),
+            Topic::Predefined(id) => self.config.predefined_topics.get(id).cloned(),
+            Topic::Short(name) => String::from_utf8(name.to_vec()).ok(),
+            Topic::Name(name) => Some(name.clone()),
+        }
+    }
+
+    async fn broker_packet(&mut self, packet: VariablePacket) -> io::Result<()> {
+        match packet {
+            VariablePacket::PublishPacket(publish) => match self.state {
+                State::Active => self.deliver(publish).await?,
+                State::Asleep { .. } => self.kept.push_back(publish),
+            },
+            VariablePacket::PubackPacket(puback) => {
+                let msg_id = puback.packet_identifier();
+                let puback = Packet::Puback {
+                    topic_id: self.publishing.remove(&msg_id).unwrap_or_default(),
+                    msg_id,
+                    return_code: ReturnCode::Accepted,
+                };
+                self.peer.send(&puback).await;
+            }
+            VariablePacket::PubrecPacket(pubrec) => {
+                let msg_id = pubrec.packet_identifier();
+                self.peer.send(&Packet::Pubrec { msg_id }).await;
+            }
+            VariablePacket::PubrelPacket(pubrel) => {
+                let msg_id = pubrel.packet_identifier();
+                if self.refused.remove(&msg_id) {
+                    self.broker.send(PubcompPacket::new(msg_id)).await?;
+                } else {
+                    self.peer.send(&Packet::Pubrel { msg_id }).await;
+                }
+            }
+            VariablePacket::PubcompPacket(pubcomp) => {
+                let msg_id = pubcomp.packet_identifier();
+                self.peer.send(&Packet::Pubcomp { msg_id }).await;
+            }
+            VariablePacket::SubackPacket(suback) => {
+                let msg_id = suback.packet_identifier();
+                let topic = self.subscribing.remove(&msg_id);
+                let (qos, return_code) = match suback.return_codes().first() {
+                    Some(SubscribeReturnCode::MaximumQoSLevel0) => {
+                        (QualityOfService::Level0, ReturnCode::Accepted)
+                    }
+                    Some(SubscribeReturnCode::MaximumQoSLevel1) => {
+                        (QualityOfService::Level1, ReturnCode::Accepted)
+                    }
+                    Some(SubscribeReturnCode::MaximumQoSLevel2) => {
+                        (QualityOfService::Level2, ReturnCode::Accepted)
+                    }
+                    _ => (QualityOfService::Level0, ReturnCode::NotSupported),
+                };
+                // a topic name without wildcards gets its id right away
+                let topic_id = match topic {
+                    Some(Topic::Name(name))
+                        if return_code == ReturnCode::Accepted
+                            && TopicName::new(&*name).is_ok() =>
+                    {
+                        self.topics.register(&name).unwrap_or_default()
+                    }
+                    Some(Topic::Predefined(id)) => id,
+                    _ => 0,
+                };
+                let suback = Packet::Suback {
+                    flags: Flags {
+                        qos: Some(qos),
+                        ..Default::default()
+                    },
+                    topic_id,
+                    msg_id,
+                    return_code,
+                };
+                self.peer.send(&suback).await;
+            }
+            VariablePacket::UnsubackPacket(unsuback) => {
+                let msg_id = unsuback.packet_identifier();
+                self.peer.send(&Packet::Unsuback { msg_id }).await;
+            }
+            VariablePacket::PingrespPacket(_) => {
+                // the pings of a sleeping client are the gateway's own
+                if matches!(self.state, State::Active) {
+                    self.peer.send(&Packet::Pingresp).await;
+                }
+            }
+            packet => debug!(
+                "broker sent unexpected packet to mqtt-sn client {}: {packet}",
+                self.client_id
+            ),
+        }
+        Ok(())
+    }
+
+    async fn deliver_kept(&mut self) -> io::Result<()> {
+        while let Some(publish) = self.kept.pop_front() {
+            self.deliver(publish).await?;
+        }
+        Ok(())
+    }
+
+    /// Sends a message to the client, its topic is registered with the client first if the
+    /// client has no id for it
+    async fn deliver(&mut self, publish: PublishPacket) -> io::Result<()> {
+        let name = publish.topic_name().to_string();
+        let known = match name.as_bytes() {
+            [a, b] => Some(Topic::Short([*a, *b])),
+            _ => self
+                .config
+                .predefined_topic_id(&name)
+                .map(Topic::Predefined),
+        };
+        if let Some(topic) = known {
+            self.send_publish(topic, publish).await;
+            return Ok(());
+        }
+        // messages for a topic being registered wait for the REGACK
+        let topic_id = self.topics.ids.get(&name).copied();
+        if let Some((_, waiting)) = self
+            .registering
+            .values_mut()
+            .find(|(id, _)| Some(*id) == topic_id)
+        {
+            waiting.push(publish);
+            return Ok(());
+        }
+        if let Some(id) = topic_id {
+            self.send_publish(Topic::Id(id), publish).await;
+            return Ok(());
+        }
+
+        let Some(topic_id) = self.topics.register(&name) else {
+            debug!("mqtt-sn client {} has no topic id left", self.client_id);
+            return self.refuse(publish).await;
+        };
+        self.last_register_id = self.last_register_id.wrapping_add(1).max(1);
+        let register = Packet::Register {
+            topic_id,
+            msg_id: self.last_register_id,
+            topic_name: name,
+        };
+        self.peer.send(&register).await;
+        self.registering
+            .insert(self.last_register_id, (topic_id, vec![publish]));
+        Ok(())
+    }
+
+    async fn send_publish(&self, topic: Topic, publish: PublishPacket) {
+        let (qos, msg_id) = publish.qos().split();
+        let flags = Flags {
+            dup: publish.dup(),
+            qos: Some(qos),
+            retain: publish.retain(),
+            ..Default::default()
+        };
+        let publish = Packet::Publish {
+            flags,
+            topic,
+            msg_id: msg_id.unwrap_or_default(),
+            data: publish.payload().to_vec(),
+        };
+        self.peer.send(&publish).await;
+    }
+
+    /// Acknowledges a message the client can not get to the broker, so it is not delivered
+    /// again
+    async fn refuse(&mut self, publish: PublishPacket) -> io::Result<()> {
+        match publish.qos() {
+            QoSWithPacketIdentifier::Level0 => Ok(()),
+            QoSWithPacketIdentifier::Level1(pkid) => {
+                self.broker.send(PubackPacket::new(pkid)).await
+            }
+            QoSWithPacketIdentifier::Level2(pkid) => {
+                self.refused.insert(pkid);
+                self.broker.send(PubrecPacket::new(pkid)).await
+            }
+        }
+    }
+}
+
+/// The id sent back in the PUBACK of a message
+fn topic_id(topic: &Topic) -> u16 {
+    match topic {
+        Topic::Id(id) | Topic::Predefined(id) => *id,
+        Topic::Short(name) => u16::from_be_bytes(*name),
+        Topic::Name(_) => 0,
+    }
+}
+
+/// Asks the client for its will, `None` if it sent an invalid will topic and `Some(None)` if it
+/// sent no will after all
+async fn ask_will(peer: &Peer, inbox: &AsyncReceiver<Packet>) -> Option<Option<(Flags, LastWill)>> {
+    peer.send(&Packet::WillTopicReq).await;
+    let (flags, topic) = loop {
+        match inbox.recv().await.ok()? {
+            Packet::WillTopic { flags, topic } => break (flags, topic),
+            packet => debug!(
+                "mqtt-sn client {} sent {packet:?} instead of its will",
+                peer.addr
+            ),
+        }
+    };
+    if topic.is_empty() {
+        return Some(None);
+    }
+    peer.send(&Packet::WillMsgReq).await;
+    let message = loop {
+        match inbox.recv().await.ok()? {
+            Packet::WillMsg { message } => break message,
+            packet => debug!(
+                "mqtt-sn client {} sent {packet:?} instead of its will",
+                peer.addr
+            ),
+        }
+    };
+    LastWill::new(topic, message)
+        .ok()
+        .map(|will| Some((flags, will)))
+}
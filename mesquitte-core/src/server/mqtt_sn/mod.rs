@@ -0,0 +1,4 @@
+mod gateway;
+mod packet;
+
+pub mod server;
@@ -0,0 +1,654 @@
+//! MQTT-SN 1.2 packets exchanged by the gateway with its clients
+//!
+//! A packet starts with its length, one byte or `0x01` and two bytes for packets longer than
+//! 255 bytes, followed by the message type.
+
+use mqtt_codec_kit::common::QualityOfService;
+
+/// Protocol id in the CONNECT of MQTT-SN 1.2
+pub(crate) const PROTOCOL_ID: u8 = 0x01;
+
+const SEARCHGW: u8 = 0x01;
+const GWINFO: u8 = 0x02;
+const CONNECT: u8 = 0x04;
+const CONNACK: u8 = 0x05;
+const WILLTOPICREQ: u8 = 0x06;
+const WILLTOPIC: u8 = 0x07;
+const WILLMSGREQ: u8 = 0x08;
+const WILLMSG: u8 = 0x09;
+const REGISTER: u8 = 0x0a;
+const REGACK: u8 = 0x0b;
+const PUBLISH: u8 = 0x0c;
+const PUBACK: u8 = 0x0d;
+const PUBCOMP: u8 = 0x0e;
+const PUBREC: u8 = 0x0f;
+const PUBREL: u8 = 0x10;
+const SUBSCRIBE: u8 = 0x12;
+const SUBACK: u8 = 0x13;
+const UNSUBSCRIBE: u8 = 0x14;
+const UNSUBACK: u8 = 0x15;
+const PINGREQ: u8 = 0x16;
+const PINGRESP: u8 = 0x17;
+const DISCONNECT: u8 = 0x18;
+
+const FLAG_DUP: u8 = 0x80;
+const FLAG_RETAIN: u8 = 0x10;
+const FLAG_WILL: u8 = 0x08;
+const FLAG_CLEAN_SESSION: u8 = 0x04;
+const QOS_SHIFT: u8 = 5;
+const TOPIC_ID_TYPE_MASK: u8 = 0x03;
+const TOPIC_ID_NORMAL: u8 = 0x00;
+const TOPIC_ID_PREDEFINED: u8 = 0x01;
+const TOPIC_ID_SHORT: u8 = 0x02;
+
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+pub(crate) enum PacketError {
+    #[error("packet is truncated")]
+    Truncated,
+    #[error("packet length {0} does not match the datagram")]
+    Length(usize),
+    #[error("unsupported message type {0:#04x}")]
+    MessageType(u8),
+    #[error("unsupported protocol id {0:#04x}")]
+    ProtocolId(u8),
+    #[error("reserved topic id type")]
+    TopicIdType,
+    #[error("invalid utf-8 string")]
+    Utf8,
+}
+
+/// Flags of CONNECT, WILLTOPIC, PUBLISH, SUBSCRIBE and SUBACK, the topic id type is part of
+/// the [`Topic`] of the packet
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Flags {
+    pub dup: bool,
+    /// `None` is QoS -1, a PUBLISH sent without connecting first
+    pub qos: Option<QualityOfService>,
+    pub retain: bool,
+    pub will: bool,
+    pub clean_session: bool,
+}
+
+impl Default for Flags {
+    fn default() -> Self {
+        Self {
+            dup: false,
+            qos: Some(QualityOfService::Level0),
+            retain: false,
+            will: false,
+            clean_session: false,
+        }
+    }
+}
+
+impl Flags {
+    fn decode(byte: u8) -> Self {
+        let qos = match (byte >> QOS_SHIFT) & 0x03 {
+            0 => Some(QualityOfService::Level0),
+            1 => Some(QualityOfService::Level1),
+            2 => Some(QualityOfService::Level2),
+            _ => None,
+        };
+        Self {
+            dup: byte & FLAG_DUP != 0,
+            qos,
+            retain: byte & FLAG_RETAIN != 0,
+            will: byte & FLAG_WILL != 0,
+            clean_session: byte & FLAG_CLEAN_SESSION != 0,
+        }
+    }
+
+    fn encode(&self, topic_id_type: u8) -> u8 {
+        let qos = match self.qos {
+            Some(qos) => qos as u8,
+            None => 0x03,
+        };
+        let mut byte = qos << QOS_SHIFT | topic_id_type;
+        if self.dup {
+            byte |= FLAG_DUP;
+        }
+        if self.retain {
+            byte |= FLAG_RETAIN;
+        }
+        if self.will {
+            byte |= FLAG_WILL;
+        }
+        if self.clean_session {
+            byte |= FLAG_CLEAN_SESSION;
+        }
+        byte
+    }
+}
+
+/// How a PUBLISH, SUBSCRIBE or UNSUBSCRIBE names its topic
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Topic {
+    /// Registered with REGISTER or assigned in a SUBACK
+    Id(u16),
+    /// Configured in the gateway, known to the client beforehand
+    Predefined(u16),
+    /// A topic name of two characters sent in place of an id
+    Short([u8; 2]),
+    /// A topic name or filter, only in SUBSCRIBE and UNSUBSCRIBE
+    Name(String),
+}
+
+impl Topic {
+    fn id_type(&self) -> u8 {
+        match self {
+            Topic::Id(_) | Topic::Name(_) => TOPIC_ID_NORMAL,
+            Topic::Predefined(_) => TOPIC_ID_PREDEFINED,
+            Topic::Short(_) => TOPIC_ID_SHORT,
+        }
+    }
+
+    fn encode(&self, buf: &mut Vec<u8>) {
+        match self {
+            Topic::Id(id) | Topic::Predefined(id) => buf.extend_from_slice(&id.to_be_bytes()),
+            Topic::Short(name) => buf.extend_from_slice(name),
+            Topic::Name(name) => buf.extend_from_slice(name.as_bytes()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ReturnCode {
+    Accepted = 0x00,
+    Congestion = 0x01,
+    InvalidTopicId = 0x02,
+    NotSupported = 0x03,
+}
+
+impl From<u8> for ReturnCode {
+    fn from(code: u8) -> Self {
+        match code {
+            0x00 => ReturnCode::Accepted,
+            0x01 => ReturnCode::Congestion,
+            0x02 => ReturnCode::InvalidTopicId,
+            _ => ReturnCode::NotSupported,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Packet {
+    SearchGw {
+        radius: u8,
+    },
+    GwInfo {
+        gateway_id: u8,
+    },
+    Connect {
+        flags: Flags,
+        duration: u16,
+        client_id: String,
+    },
+    Connack {
+        return_code: ReturnCode,
+    },
+    WillTopicReq,
+    /// An empty WILLTOPIC removes the will
+    WillTopic {
+        flags: Flags,
+        topic: String,
+    },
+    WillMsgReq,
+    WillMsg {
+        message: Vec<u8>,
+    },
+    Register {
+        topic_id: u16,
+        msg_id: u16,
+        topic_name: String,
+    },
+    Regack {
+        topic_id: u16,
+        msg_id: u16,
+        return_code: ReturnCode,
+    },
+    Publish {
+        flags: Flags,
+        topic: Topic,
+        msg_id: u16,
+        data: Vec<u8>,
+    },
+    Puback {
+        topic_id: u16,
+        msg_id: u16,
+        return_code: ReturnCode,
+    },
+    Pubcomp {
+        msg_id: u16,
+    },
+    Pubrec {
+        msg_id: u16,
+    },
+    Pubrel {
+        msg_id: u16,
+    },
+    Subscribe {
+        flags: Flags,
+        msg_id: u16,
+        topic: Topic,
+    },
+    Suback {
+        flags: Flags,
+        topic_id: u16,
+        msg_id: u16,
+        return_code: ReturnCode,
+    },
+    Unsubscribe {
+        msg_id: u16,
+        topic: Topic,
+    },
+    Unsuback {
+        msg_id: u16,
+    },
+    /// A sleeping client wakes up with its client id to get the messages kept for it
+    Pingreq {
+        client_id: Option<String>,
+    },
+    Pingresp,
+    /// A client going to sleep for `duration` seconds
+    Disconnect {
+        duration: Option<u16>,
+    },
+}
+
+/// Reads the fields of a packet in order
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn u8(&mut self) -> Result<u8, PacketError> {
+        let (&byte, rest) = self.0.split_first().ok_or(PacketError::Truncated)?;
+        self.0 = rest;
+        Ok(byte)
+    }
+
+    fn u16(&mut self) -> Result<u16, PacketError> {
+        Ok(u16::from_be_bytes([self.u8()?, self.u8()?]))
+    }
+
+    fn rest(&mut self) -> &'a [u8] {
+        std::mem::take(&mut self.0)
+    }
+
+    fn string(&mut self) -> Result<String, PacketError> {
+        String::from_utf8(self.rest().to_vec()).map_err(|_| PacketError::Utf8)
+    }
+
+    /// The topic of a PUBLISH, SUBSCRIBE or UNSUBSCRIBE, `name` for whether a normal topic is
+    /// a name instead of an id
+    fn topic(&mut self, flags: u8, name: bool) -> Result<Topic, PacketError> {
+        match flags & TOPIC_ID_TYPE_MASK {
+            TOPIC_ID_NORMAL if name => Ok(Topic::Name(self.string()?)),
+            TOPIC_ID_NORMAL => Ok(Topic::Id(self.u16()?)),
+            TOPIC_ID_PREDEFINED => Ok(Topic::Predefined(self.u16()?)),
+            TOPIC_ID_SHORT => Ok(Topic::Short([self.u8()?, self.u8()?])),
+            _ => Err(PacketError::TopicIdType),
+        }
+    }
+}
+
+impl Packet {
+    pub fn decode(datagram: &[u8]) -> Result<Self, PacketError> {
+        let mut reader = Reader(datagram);
+        let length = match reader.u8()? {
+            0x01 => reader.u16()? as usize,
+            length => length as usize,
+        };
+        if length != datagram.len() {
+            return Err(PacketError::Length(length));
+        }
+        let packet = match reader.u8()? {
+            SEARCHGW => Packet::SearchGw {
+                radius: reader.u8()?,
+            },
+            GWINFO => Packet::GwInfo {
+                gateway_id: reader.u8()?,
+            },
+            CONNECT => {
+                let flags = Flags::decode(reader.u8()?);
+                let protocol_id = reader.u8()?;
+                if protocol_id != PROTOCOL_ID {
+                    return Err(PacketError::ProtocolId(protocol_id));
+                }
+                Packet::Connect {
+                    flags,
+                    duration: reader.u16()?,
+                    client_id: reader.string()?,
+                }
+            }
+            CONNACK => Packet::Connack {
+                return_code: reader.u8()?.into(),
+            },
+            WILLTOPICREQ => Packet::WillTopicReq,
+            WILLTOPIC => match reader.0.is_empty() {
+                true => Packet::WillTopic {
+                    flags: Flags::default(),
+                    topic: String::new(),
+                },
+                false => Packet::WillTopic {
+                    flags: Flags::decode(reader.u8()?),
+                    topic: reader.string()?,
+                },
+            },
+            WILLMSGREQ => Packet::WillMsgReq,
+            WILLMSG => Packet::WillMsg {
+                message: reader.rest().to_vec(),
+            },
+            REGISTER => Packet::Register {
+                topic_id: reader.u16()?,
+                msg_id: reader.u16()?,
+                topic_name: reader.string()?,
+            },
+            REGACK => Packet::Regack {
+                topic_id: reader.u16()?,
+                msg_id: reader.u16()?,
+                return_code: reader.u8()?.into(),
+            },
+            PUBLISH => {
+                let flags = reader.u8()?;
+                Packet::Publish {
+                    topic: reader.topic(flags, false)?,
+                    flags: Flags::decode(flags),
+                    msg_id: reader.u16()?,
+                    data: reader.rest().to_vec(),
+                }
+            }
+            PUBACK => Packet::Puback {
+                topic_id: reader.u16()?,
+                msg_id: reader.u16()?,
+                return_code: reader.u8()?.into(),
+            },
+            PUBCOMP => Packet::Pubcomp {
+                msg_id: reader.u16()?,
+            },
+            PUBREC => Packet::Pubrec {
+                msg_id: reader.u16()?,
+            },
+            PUBREL => Packet::Pubrel {
+                msg_id: reader.u16()?,
+            },
+            SUBSCRIBE => {
+                let flags = reader.u8()?;
+                Packet::Subscribe {
+                    flags: Flags::decode(flags),
+                    msg_id: reader.u16()?,
+                    topic: reader.topic(flags, true)?,
+                }
+            }
+            SUBACK => Packet::Suback {
+                flags: Flags::decode(reader.u8()?),
+                topic_id: reader.u16()?,
+                msg_id: reader.u16()?,
+                return_code: reader.u8()?.into(),
+            },
+            UNSUBSCRIBE => {
+                let flags = reader.u8()?;
+                Packet::Unsubscribe {
+                    msg_id: reader.u16()?,
+                    topic: reader.topic(flags, true)?,
+                }
+            }
+            UNSUBACK => Packet::Unsuback {
+                msg_id: reader.u16()?,
+            },
+            PINGREQ => Packet::Pingreq {
+                client_id: match reader.0.is_empty() {
+                    true => None,
+                    false => Some(reader.string()?),
+                },
+            },
+            PINGRESP => Packet::Pingresp,
+            DISCONNECT => Packet::Disconnect {
+                duration: match reader.0.is_empty() {
+                    true => None,
+                    false => Some(reader.u16()?),
+                },
+            },
+            message_type => return Err(PacketError::MessageType(message_type)),
+        };
+        Ok(packet)
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut body = Vec::new();
+        let message_type = match self {
+            Packet::SearchGw { radius } => {
+                body.push(*radius);
+                SEARCHGW
+            }
+            Packet::GwInfo { gateway_id } => {
+                body.push(*gateway_id);
+                GWINFO
+            }
+            Packet::Connect {
+                flags,
+                duration,
+                client_id,
+            } => {
+                body.extend_from_slice(&[flags.encode(0), PROTOCOL_ID]);
+                body.extend_from_slice(&duration.to_be_bytes());
+                body.extend_from_slice(client_id.as_bytes());
+                CONNECT
+            }
+            Packet::Connack { return_code } => {
+                body.push(*return_code as u8);
+                CONNACK
+            }
+            Packet::WillTopicReq => WILLTOPICREQ,
+            Packet::WillTopic { flags, topic } => {
+                if !topic.is_empty() {
+                    body.push(flags.encode(0));
+                    body.extend_from_slice(topic.as_bytes());
+                }
+                WILLTOPIC
+            }
+            Packet::WillMsgReq => WILLMSGREQ,
+            Packet::WillMsg { message } => {
+                body.extend_from_slice(message);
+                WILLMSG
+            }
+            Packet::Register {
+                topic_id,
+                msg_id,
+                topic_name,
+            } => {
+                body.extend_from_slice(&topic_id.to_be_bytes());
+                body.extend_from_slice(&msg_id.to_be_bytes());
+                body.extend_from_slice(topic_name.as_bytes());
+                REGISTER
+            }
+            Packet::Regack {
+                topic_id,
+                msg_id,
+                return_code,
+            } => {
+                body.extend_from_slice(&topic_id.to_be_bytes());
+                body.extend_from_slice(&msg_id.to_be_bytes());
+                body.push(*return_code as u8);
+                REGACK
+            }
+            Packet::Publish {
+                flags,
+                topic,
+                msg_id,
+                data,
+            } => {
+                body.push(flags.encode(topic.id_type()));
+                topic.encode(&mut body);
+                body.extend_from_slice(&msg_id.to_be_bytes());
+                body.extend_from_slice(data);
+                PUBLISH
+            }
+            Packet::Puback {
+                topic_id,
+                msg_id,
+                return_code,
+            } => {
+                body.extend_from_slice(&topic_id.to_be_bytes());
+                body.extend_from_slice(&msg_id.to_be_bytes());
+                body.push(*return_code as u8);
+                PUBACK
+            }
+            Packet::Pubcomp { msg_id } => {
+                body.extend_from_slice(&msg_id.to_be_bytes());
+                PUBCOMP
+            }
+            Packet::Pubrec { msg_id } => {
+                body.extend_from_slice(&msg_id.to_be_bytes());
+                PUBREC
+            }
+            Packet::Pubrel { msg_id } => {
+                body.extend_from_slice(&msg_id.to_be_bytes());
+                PUBREL
+            }
+            Packet::Subscribe {
+                flags,
+                msg_id,
+                topic,
+            } => {
+                body.push(flags.encode(topic.id_type()));
+                body.extend_from_slice(&msg_id.to_be_bytes());
+                topic.encode(&mut body);
+                SUBSCRIBE
+            }
+            Packet::Suback {
+                flags,
+                topic_id,
+                msg_id,
+                return_code,
+            } => {
+                body.push(flags.encode(0));
+                body.extend_from_slice(&topic_id.to_be_bytes());
+                body.extend_from_slice(&msg_id.to_be_bytes());
+                body.push(*return_code as u8);
+                SUBACK
+            }
+            Packet::Unsubscribe { msg_id, topic } => {
+                body.push(topic.id_type());
+                body.extend_from_slice(&msg_id.to_be_bytes());
+                topic.encode(&mut body);
+                UNSUBSCRIBE
+            }
+            Packet::Unsuback { msg_id } => {
+                body.extend_from_slice(&msg_id.to_be_bytes());
+                UNSUBACK
+            }
+            Packet::Pingreq { client_id } => {
+                if let Some(client_id) = client_id {
+                    body.extend_from_slice(client_id.as_bytes());
+                }
+                PINGREQ
+            }
+            Packet::Pingresp => PINGRESP,
+            Packet::Disconnect { duration } => {
+                if let Some(duration) = duration {
+                    body.extend_from_slice(&duration.to_be_bytes());
+                }
+                DISCONNECT
+            }
+        };
+
+        let mut packet = Vec::with_capacity(body.len() + 4);
+        // the length counts itself and the message type
+        match body.len() + 2 {
+            length if length <= u8::MAX as usize => packet.push(length as u8),
+            length => {
+                packet.push(0x01);
+                packet.extend_from_slice(&(length as u16 + 2).to_be_bytes());
+            }
+        }
+        packet.push(message_type);
+        packet.extend_from_slice(&body);
+        packet
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn packets_round_trip() {
+        let qos1 = Flags {
+            qos: Some(QualityOfService::Level1),
+            ..Default::default()
+        };
+        let packets = [
+            Packet::Connect {
+                flags: Flags {
+                    will: true,
+                    clean_session: true,
+                    ..Default::default()
+                },
+                duration: 60,
+                client_id: "sensor-1".to_owned(),
+            },
+            Packet::WillTopic {
+                flags: qos1,
+                topic: "sensors/1/state".to_owned(),
+            },
+            Packet::Publish {
+                flags: Flags {
+                    qos: None,
+                    retain: true,
+                    ..Default::default()
+                },
+                topic: Topic::Short(*b"t1"),
+                msg_id: 0,
+                data: b"on".to_vec(),
+            },
+            Packet::Publish {
+                flags: qos1,
+                topic: Topic::Id(7),
+                msg_id: 1,
+                data: vec![0xaa; 300],
+            },
+            Packet::Subscribe {
+                flags: qos1,
+                msg_id: 2,
+                topic: Topic::Name("sensors/+/state".to_owned()),
+            },
+            Packet::Unsubscribe {
+                msg_id: 3,
+                topic: Topic::Predefined(1),
+            },
+            Packet::Pingreq {
+                client_id: Some("sensor-1".to_owned()),
+            },
+            Packet::Pingreq { client_id: None },
+            Packet::Disconnect {
+                duration: Some(600),
+            },
+            Packet::Disconnect { duration: None },
+        ];
+        for packet in packets {
+            let encoded = packet.encode();
+            assert_eq!(Packet::decode(&encoded), Ok(packet));
+        }
+    }
+
+    #[test]
+    fn long_packets_have_three_byte_length() {
+        let publish = Packet::Publish {
+            flags: Flags::default(),
+            topic: Topic::Id(1),
+            msg_id: 0,
+            data: vec![0; 300],
+        }
+        .encode();
+        assert_eq!(&publish[..4], &[0x01, 0x01, 0x35, PUBLISH]);
+        assert_eq!(publish.len(), 0x0135);
+
+        assert_eq!(
+            Packet::decode(&publish[..publish.len() - 1]),
+            Err(PacketError::Length(0x0135))
+        );
+        assert_eq!(
+            Packet::decode(&[0x03, CONNECT, 0x04]),
+            Err(PacketError::Truncated)
+        );
+    }
+}
@@ -0,0 +1,466 @@
+use std::{mem, net::SocketAddr, sync::Arc};
+
+use foldhash::{HashMap, HashMapExt as _};
+use futures::{SinkExt as _, StreamExt as _};
+use kanal::{bounded_async, AsyncSender};
+use mqtt_codec_kit::{
+    common::{qos::QoSWithPacketIdentifier, TopicName},
+    v4::{
+        control::ConnectReturnCode,
+        packet::{ConnectPacket, PublishPacket},
+    },
+};
+use tokio::net::UdpSocket;
+
+use super::{
+    gateway::{self, Peer},
+    packet::{Flags, Packet, Topic},
+};
+use crate::{
+    debug, info,
+    server::{config::MqttSnConfig, local::connect_local, state::GlobalState, Error},
+    store::{message::MessageStore, retain::RetainMessageStore, topic::TopicStore},
+    warn,
+};
+
+/// Largest UDP payload
+const MAX_DATAGRAM: usize = 65535;
+/// Packets of a client waiting for its session, more are dropped like lost datagrams
+const INBOX_SIZE: usize = 64;
+
+/// MQTT-SN gateway, translating the clients on its UDP sockets into regular sessions
+pub struct MqttSnServer<S: 'static> {
+    config: MqttSnConfig,
+    global: Arc<GlobalState<S>>,
+    sockets: Vec<UdpSocket>,
+}
+
+impl<S> MqttSnServer<S>
+where
+    S: MessageStore + RetainMessageStore + TopicStore,
+{
+    pub fn new(config: MqttSnConfig, global: Arc<GlobalState<S>>) -> Self {
+        Self {
+            config,
+            global,
+            sockets: Vec::new(),
+        }
+    }
+
+    /// Binds the sockets and returns their addresses, with the port assigned by the system for
+    /// port 0, [`MqttSnServer::serve`] binds them itself if this was not called
+    #[allow(clippy::result_large_err)]
+    pub fn bind(&mut self) -> Result<Vec<SocketAddr>, Error> {
+        if self.sockets.is_empty() {
+            for addr in &self.config.addrs {
+                let socket = std::net::UdpSocket::bind(addr)?;
+                socket.set_nonblocking(true)?;
+                self.sockets.push(UdpSocket::from_std(socket)?);
+            }
+        }
+        Ok(self.local_addrs())
+    }
+
+    pub fn local_addrs(&self) -> Vec<SocketAddr> {
+        self.sockets
+            .iter()
+            .filter_map(|socket| socket.local_addr().ok())
+            .collect()
+    }
+
+    pub(crate) fn config(&self) -> &MqttSnConfig {
+        &self.config
+    }
+
+    pub async fn serve(mut self) -> Result<(), Error> {
+        self.bind()?;
+        let sockets = mem::take(&mut self.sockets);
+        let config = Arc::new(self.config);
+        let mut tasks = Vec::with_capacity(sockets.len());
+        for socket in sockets {
+            info!("mqtt-sn listener on {} starting...", socket.local_addr()?);
+            let listener = Listener {
+                socket: Arc::new(socket),
+                config: config.clone(),
+                global: self.global.clone(),
+                sessions: HashMap::new(),
+                anonymous: None,
+            };
+            tasks.push(tokio::spawn(listener.run()));
+        }
+        for task in tasks {
+            let _ = task.await;
+        }
+        Ok(())
+    }
+}
+
+/// Receives the datagrams of one socket and hands them to the sessions of their clients
+struct Listener<S: 'static> {
+    socket: Arc<UdpSocket>,
+    config: Arc<MqttSnConfig>,
+    global: Arc<GlobalState<S>>,
+    sessions: HashMap<SocketAddr, AsyncSender<Packet>>,
+    /// Publishes the messages sent with QoS -1, which have no session
+    anonymous: Option<AsyncSender<PublishPacket>>,
+}
+
+impl<S> Listener<S>
+where
+    S: MessageStore + RetainMessageStore + TopicStore,
+{
+    async fn run(mut self) {
+        let mut buf = vec![0; MAX_DATAGRAM];
+        loop {
+            let (len, addr) = match self.socket.recv_from(&mut buf).await {
+                Ok(received) => received,
+                Err(err) => {
+                    warn!("mqtt-sn receive failed: {err}");
+                    continue;
+                }
+            };
+            match Packet::decode(&buf[..len]) {
+                Ok(packet) => self.dispatch(addr, packet).await,
+                Err(err) => debug!("mqtt-sn packet from {addr} dropped: {err}"),
+            }
+        }
+    }
+
+    async fn dispatch(&mut self, addr: SocketAddr, packet: Packet) {
+        let peer = Peer {
+            socket: self.socket.clone(),
+            addr,
+        };
+        match packet {
+            Packet::SearchGw { .. } => {
+                let gateway_id = self.config.gateway_id;
+                peer.send(&Packet::GwInfo { gateway_id }).await;
+                return;
+            }
+            Packet::Publish {
+                flags: Flags { qos: None, .. },
+                ..
+            } => {
+                self.publish_anonymous(addr, packet);
+                return;
+            }
+            _ => {}
+        }
+
+        if let Some(session) = self.sessions.get(&addr) {
+            if !session.is_closed() {
+                if !session.try_send(packet).unwrap_or(true) {
+                    debug!("session of mqtt-sn client {addr} is busy, packet dropped");
+                }
+                return;
+            }
+            self.sessions.remove(&addr);
+        }
+        match packet {
+            Packet::Connect { .. } => {
+                self.sessions.retain(|_, session| !session.is_closed());
+                let (tx, rx) = bounded_async(INBOX_SIZE);
+                self.sessions.insert(addr, tx);
+                let global = self.global.clone();
+                tokio::spawn(gateway::run(global, self.config.clone(), peer, packet, rx));
+            }
+            Packet::Disconnect { .. } => {}
+            // the client has to connect first
+            _ => peer.send(&Packet::Disconnect { duration: None }).await,
+        }
+    }
+
+    /// Publishes a message sent with QoS -1 to a predefined or short topic
+    fn publish_anonymous(&mut self, addr: SocketAddr, packet: Packet) {
+        let Packet::Publish {
+            flags, topic, data, ..
+        } = packet
+        else {
+            return;
+        };
+        let topic_name = match topic {
+            Topic::Predefined(id) => self.config.predefined_topics.get(&id).cloned(),
+            Topic::Short(name) => String::from_utf8(name.to_vec()).ok(),
+            Topic::Id(_) | Topic::Name(_) => None,
+        };
+        let Some(topic_name) = topic_name.and_then(|name| TopicName::new(name).ok()) else {
+            debug!("QoS -1 publish from {addr} has an unknown topic");
+            return;
+        };
+        let mut publish = PublishPacket::new(topic_name, QoSWithPacketIdentifier::Level0, data);
+        publish.set_retain(flags.retain);
+
+        if self.anonymous.as_ref().is_none_or(AsyncSender::is_closed) {
+            self.anonymous = Some(self.spawn_anonymous());
+        }
+        let sent = self
+            .anonymous
+            .as_ref()
+            .is_some_and(|anonymous| anonymous.try_send(publish).unwrap_or(false));
+        if !sent {
+            debug!("QoS -1 publish from {addr} dropped");
+        }
+    }
+
+    /// A session of the gateway itself publishing the messages sent with QoS -1, the broker
+    /// assigns its client id
+    fn spawn_anonymous(&self) -> AsyncSender<PublishPacket> {
+        let (tx, rx) = bounded_async::<PublishPacket>(INBOX_SIZE);
+        let global = self.global.clone();
+        let mount_point = self.config.mount_point.clone();
+        tokio::spawn(async move {
+            let mut connect = ConnectPacket::new("");
+            connect.set_clean_session(true);
+            let Some((mut broker, ConnectReturnCode::ConnectionAccepted)) =
+                connect_local(&global, None, mount_point, connect).await
+            else {
+                warn!("mqtt-sn gateway could not connect to publish QoS -1 messages");
+                return;
+            };
+            loop {
+                tokio::select! {
+                    publish = rx.recv() => {
+                        let Ok(publish) = publish else {
+                            break;
+                        };
+                        if broker.send(publish).await.is_err() {
+                            break;
+                        }
+                    }
+                    packet = broker.next() => if !matches!(packet, Some(Ok(_))) {
+                        break;
+                    },
+                }
+            }
+        });
+        tx
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use mqtt_codec_kit::common::QualityOfService;
+    use tokio::time;
+
+    use super::*;
+    use crate::server::mqtt_sn::packet::ReturnCode;
+    use crate::store::memory_storage;
+
+    async fn gateway() -> SocketAddr {
+        let global = Arc::new(GlobalState::new(memory_storage()));
+        let config = MqttSnConfig::new("127.0.0.1:0".parse().unwrap(), 1)
+            .with_predefined_topic(7, "sensors/7");
+        let mut server = MqttSnServer::new(config, global);
+        let addr = server.bind().unwrap()[0];
+        tokio::spawn(server.serve());
+        addr
+    }
+
+    struct Client(UdpSocket);
+
+    impl Client {
+        async fn new(gateway: SocketAddr) -> Self {
+            let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            socket.connect(gateway).await.unwrap();
+            Self(socket)
+        }
+
+        async fn send(&self, packet: Packet) {
+            self.0.send(&packet.encode()).await.unwrap();
+        }
+
+        async fn recv(&self) -> Packet {
+            let mut buf = vec![0; MAX_DATAGRAM];
+            let len = time::timeout(Duration::from_secs(5), self.0.recv(&mut buf))
+                .await
+                .expect("gateway did not answer")
+                .unwrap();
+            Packet::decode(&buf[..len]).unwrap()
+        }
+
+        async fn connect(gateway: SocketAddr, client_id: &str) -> Self {
+            let client = Self::new(gateway).await;
+            client
+                .send(Packet::Connect {
+                    flags: Flags {
+                        clean_session: true,
+                        ..Default::default()
+                    },
+                    duration: 60,
+                    client_id: client_id.to_owned(),
+                })
+                .await;
+            assert_eq!(
+                client.recv().await,
+                Packet::Connack {
+                    return_code: ReturnCode::Accepted
+                }
+            );
+            client
+        }
+    }
+
+    #[tokio::test]
+    async fn clients_publish_by_topic_id() {
+        let gateway = gateway().await;
+        let qos1 = Flags {
+            qos: Some(QualityOfService::Level1),
+            ..Default::default()
+        };
+
+        let subscriber = Client::connect(gateway, "subscriber").await;
+        subscriber
+            .send(Packet::Subscribe {
+                flags: qos1,
+                msg_id: 1,
+                topic: Topic::Name("sensors/+".to_owned()),
+            })
+            .await;
+        assert!(matches!(
+            subscriber.recv().await,
+            Packet::Suback {
+                topic_id: 0,
+                msg_id: 1,
+                return_code: ReturnCode::Accepted,
+                ..
+            }
+        ));
+
+        let publisher = Client::connect(gateway, "publisher").await;
+        publisher
+            .send(Packet::Register {
+                topic_id: 0,
+                msg_id: 1,
+                topic_name: "sensors/1".to_owned(),
+            })
+            .await;
+        let Packet::Regack {
+            topic_id,
+            return_code: ReturnCode::Accepted,
+            ..
+        } = publisher.recv().await
+        else {
+            panic!("topic is not registered");
+        };
+        publisher
+            .send(Packet::Publish {
+                flags: qos1,
+                topic: Topic::Id(topic_id),
+                msg_id: 2,
+                data: b"21.5".to_vec(),
+            })
+            .await;
+        assert_eq!(
+            publisher.recv().await,
+            Packet::Puback {
+                topic_id,
+                msg_id: 2,
+                return_code: ReturnCode::Accepted
+            }
+        );
+
+        // the subscriber has no id for the topic yet
+        let Packet::Register {
+            topic_id, msg_id, ..
+        } = subscriber.recv().await
+        else {
+            panic!("topic is not registered with the subscriber");
+        };
+        subscriber
+            .send(Packet::Regack {
+                topic_id,
+                msg_id,
+                return_code: ReturnCode::Accepted,
+            })
+            .await;
+        let Packet::Publish {
+            topic,
+            msg_id,
+            data,
+            ..
+        } = subscriber.recv().await
+        else {
+            panic!("message is not delivered");
+        };
+        assert_eq!((topic, data), (Topic::Id(topic_id), b"21.5".to_vec()));
+        subscriber
+            .send(Packet::Puback {
+                topic_id,
+                msg_id,
+                return_code: ReturnCode::Accepted,
+            })
+            .await;
+
+        // QoS -1 needs no connection
+        let anonymous = Client::new(gateway).await;
+        anonymous
+            .send(Packet::Publish {
+                flags: Flags {
+                    qos: None,
+                    ..Default::default()
+                },
+                topic: Topic::Predefined(7),
+                msg_id: 0,
+                data: b"on".to_vec(),
+            })
+            .await;
+        assert!(matches!(
+            subscriber.recv().await,
+            Packet::Publish {
+                topic: Topic::Predefined(7),
+                ..
+            }
+        ));
+    }
+
+    #[tokio::test]
+    async fn sleeping_client_gets_messages_when_awake() {
+        let gateway = gateway().await;
+        let sleeper = Client::connect(gateway, "sleeper").await;
+        sleeper
+            .send(Packet::Subscribe {
+                flags: Flags::default(),
+                msg_id: 1,
+                topic: Topic::Short(*b"ab"),
+            })
+            .await;
+        assert!(matches!(sleeper.recv().await, Packet::Suback { .. }));
+        sleeper
+            .send(Packet::Disconnect {
+                duration: Some(600),
+            })
+            .await;
+        assert_eq!(sleeper.recv().await, Packet::Disconnect { duration: None });
+
+        let publisher = Client::connect(gateway, "publisher").await;
+        for data in [b"1", b"2"] {
+            publisher
+                .send(Packet::Publish {
+                    flags: Flags::default(),
+                    topic: Topic::Short(*b"ab"),
+                    msg_id: 0,
+                    data: data.to_vec(),
+                })
+                .await;
+        }
+        let mut buf = [0; 64];
+        assert!(
+            time::timeout(Duration::from_millis(200), sleeper.0.recv(&mut buf))
+                .await
+                .is_err(),
+            "sleeping client got a message"
+        );
+
+        sleeper
+            .send(Packet::Pingreq {
+                client_id: Some("sleeper".to_owned()),
+            })
+            .await;
+        for data in [b"1", b"2"] {
+            assert!(matches!(sleeper.recv().await, Packet::Publish { data: d, .. } if d == data));
+        }
+        assert_eq!(sleeper.recv().await, Packet::Pingresp);
+    }
+}
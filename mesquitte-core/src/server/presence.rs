@@ -0,0 +1,159 @@
+//! Presence events of the clients, published by the broker itself
+//!
+//! With [`GlobalState::with_presence_events`](super::state::GlobalState::with_presence_events)
+//! every connect and every end of a connection is published as JSON, on the topics EMQX uses:
+//!
+//! ```text
+//! $SYS/brokers/<node>/clients/<client id>/connected
+//! {"clientid":"sensor-1","username":"alice","ipaddress":"10.0.0.7","proto_ver":4,"clean_start":true,"connected_at":1736908800123,"ts":1736908800123}
+//!
+//! $SYS/brokers/<node>/clients/<client id>/disconnected
+//! {"clientid":"sensor-1","ipaddress":"10.0.0.7","reason":"client disconnected","disconnected_at":1736908805678,"ts":1736908805678}
+//! ```
+//!
+//! Timestamps are in milliseconds since the Unix epoch, `ipaddress` is `null` for a client
+//! connected without a socket address. The events are QoS 0 and not retained, an application
+//! tracks the fleet by subscribing to `$SYS/brokers/+/clients/+/+`. A client identifier which is
+//! not valid in a topic name, one with `+` or `#`, gets no events.
+
+use std::{fmt::Write as _, net::SocketAddr};
+
+use mqtt_codec_kit::common::{QualityOfService, TopicName};
+
+use super::audit::{now_millis, write_field, write_optional_field, AuditEvent};
+use crate::{debug, store::message::PublishMessage};
+
+/// Prefix of the presence topics
+pub const PRESENCE_PREFIX: &str = "$SYS/brokers/";
+
+#[derive(Debug, Clone)]
+pub struct PresenceConfig {
+    /// Name of this broker in the topics, tells the brokers of a cluster apart
+    pub node: String,
+}
+
+impl PresenceConfig {
+    pub fn new<N: Into<String>>(node: N) -> Self {
+        Self { node: node.into() }
+    }
+}
+
+/// The presence event of a connect or a disconnect, `None` for the other events
+pub(crate) fn presence_message(
+    config: &PresenceConfig,
+    remote_addr: Option<SocketAddr>,
+    event: AuditEvent<'_>,
+) -> Option<PublishMessage> {
+    let ts = now_millis();
+    let ipaddress = remote_addr.map(|addr| addr.ip().to_string());
+    let (client_id, name, mut payload) = match event {
+        AuditEvent::Connect {
+            client_id,
+            username,
+            protocol,
+            clean_session,
+        } => {
+            let mut payload = String::new();
+            write_field(&mut payload, "clientid", client_id);
+            write_optional_field(&mut payload, "username", username);
+            write_optional_field(&mut payload, "ipaddress", ipaddress.as_deref());
+            let _ = write!(
+                payload,
+                r#","proto_ver":{protocol},"clean_start":{clean_session},"connected_at":{ts}"#
+            );
+            (client_id, "connected", payload)
+        }
+        AuditEvent::Disconnect {
+            client_id, reason, ..
+        } => {
+            let mut payload = String::new();
+            write_field(&mut payload, "clientid", client_id);
+            write_optional_field(&mut payload, "ipaddress", ipaddress.as_deref());
+            write_field(&mut payload, "reason", reason);
+            let _ = write!(payload, r#","disconnected_at":{ts}"#);
+            (client_id, "disconnected", payload)
+        }
+        _ => return None,
+    };
+    let _ = write!(payload, r#","ts":{ts}}}"#);
+    // every field is written with a leading comma, the first one opens the object instead
+    payload.replace_range(..1, "{");
+    let topic = format!(
+        "{PRESENCE_PREFIX}{}/clients/{client_id}/{name}",
+        config.node
+    );
+    match TopicName::new(topic) {
+        Ok(topic_name) => Some(PublishMessage::new(
+            topic_name,
+            payload.into_bytes(),
+            QualityOfService::Level0,
+            false,
+        )),
+        Err(err) => {
+            debug!("client#{client_id} gets no presence events: {err}");
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn events_are_published_like_emqx() {
+        let config = PresenceConfig::new("node1");
+        let remote_addr = Some("10.0.0.7:51234".parse().unwrap());
+        let connected = presence_message(
+            &config,
+            remote_addr,
+            AuditEvent::Connect {
+                client_id: "sensor-1",
+                username: Some("alice"),
+                protocol: 4,
+                clean_session: true,
+            },
+        )
+        .unwrap();
+        assert_eq!(
+            &connected.topic_name()[..],
+            "$SYS/brokers/node1/clients/sensor-1/connected"
+        );
+        let payload = String::from_utf8(connected.payload().to_vec()).unwrap();
+        assert!(
+            payload.starts_with(
+                r#"{"clientid":"sensor-1","username":"alice","ipaddress":"10.0.0.7","proto_ver":4,"clean_start":true,"connected_at":"#
+            ),
+            "{payload}"
+        );
+        assert!(!connected.retain());
+
+        let disconnected = presence_message(
+            &config,
+            None,
+            AuditEvent::Disconnect {
+                client_id: "sensor-1",
+                reason: "keep alive timeout",
+                code: Some(1107),
+            },
+        )
+        .unwrap();
+        assert_eq!(
+            &disconnected.topic_name()[..],
+            "$SYS/brokers/node1/clients/sensor-1/disconnected"
+        );
+        let payload = String::from_utf8(disconnected.payload().to_vec()).unwrap();
+        assert!(
+            payload.starts_with(
+                r#"{"clientid":"sensor-1","ipaddress":null,"reason":"keep alive timeout","disconnected_at":"#
+            ),
+            "{payload}"
+        );
+
+        let kick = AuditEvent::Kick {
+            client_id: "sensor-1",
+            reason: "maintenance",
+        };
+        assert!(presence_message(&config, None, kick).is_none());
+    }
+}
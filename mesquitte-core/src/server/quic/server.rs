@@ -1,57 +1,132 @@
-use std::num::NonZeroUsize;
+use std::{mem, net::SocketAddr, num::NonZeroUsize, sync::Arc};
 
 use s2n_quic::Server;
+use tokio::time;
 
 use crate::{
     info,
     server::{config::ServerConfig, process_client, state::GlobalState, Error},
     store::{message::MessageStore, retain::RetainMessageStore, topic::TopicStore},
+    warn,
 };
 
 pub struct QuicServer<S: 'static> {
     config: ServerConfig,
-    global: &'static GlobalState<S>,
+    global: Arc<GlobalState<S>>,
+    servers: Vec<Server>,
 }
 
 impl<S> QuicServer<S>
 where
     S: MessageStore + RetainMessageStore + TopicStore,
 {
-    pub fn new(config: ServerConfig, global: &'static GlobalState<S>) -> Result<Self, Error> {
-        Ok(QuicServer { config, global })
+    pub fn new(config: ServerConfig, global: Arc<GlobalState<S>>) -> Result<Self, Error> {
+        Ok(QuicServer {
+            config,
+            global,
+            servers: Vec::new(),
+        })
     }
 
-    pub async fn serve(self) -> Result<(), Error> {
+    /// Binds the endpoints and returns their addresses, with the port assigned by the system for
+    /// port 0, [`QuicServer::serve`] binds them itself if this was not called
+    #[allow(clippy::result_large_err)]
+    pub fn bind(&mut self) -> Result<Vec<SocketAddr>, Error> {
+        if !self.servers.is_empty() {
+            return Ok(self.local_addrs());
+        }
+        let tls = match &self.config.tls {
+            Some(tls) => (tls.cert_file.as_path(), tls.key_file.as_path()),
+            None => return Err(Error::MissingTlsConfig),
+        };
         #[cfg(all(unix, not(target_os = "solaris"), not(target_os = "illumos")))]
         let worker = std::thread::available_parallelism().map_or(1, NonZeroUsize::get);
         #[cfg(any(target_os = "solaris", target_os = "illumos"))]
         let worker = 1;
-        let mut tasks = Vec::with_capacity(worker);
-        for i in 0..worker {
-            info!("quic worker {} staring...", i);
-            let tls = match &self.config.tls {
-                Some(tls) => (tls.cert_file.as_path(), tls.key_file.as_path()),
-                None => return Err(Error::MissingTlsConfig),
-            };
-            let tls = s2n_quic::provider::tls::default::Server::builder()
-                .with_certificate(tls.0, tls.1)?
-                .build()?;
-            #[cfg(all(unix, not(target_os = "solaris"), not(target_os = "illumos")))]
-            let io = s2n_quic::provider::io::Default::builder()
-                .with_receive_address(self.config.addr)?
-                .with_reuse_port()?
-                .build()?;
-            #[cfg(any(target_os = "solaris", target_os = "illumos"))]
-            let io = s2n_quic::provider::io::Default::builder()
-                .with_receive_address(self.config.addr)?
-                .build()?;
-            let mut server = Server::builder().with_tls(tls)?.with_io(io)?.start()?;
+        let mut servers = Vec::with_capacity(self.config.addrs.len() * worker);
+        for addr in &self.config.addrs {
+            // the port assigned for port 0 is used by the other workers
+            let mut addr = *addr;
+            for _ in 0..worker {
+                let tls = s2n_quic::provider::tls::default::Server::builder()
+                    .with_certificate(tls.0, tls.1)?
+                    .build()?;
+                #[cfg(all(unix, not(target_os = "solaris"), not(target_os = "illumos")))]
+                let io = s2n_quic::provider::io::Default::builder()
+                    .with_receive_address(addr)?
+                    .with_reuse_port()?
+                    .build()?;
+                #[cfg(any(target_os = "solaris", target_os = "illumos"))]
+                let io = s2n_quic::provider::io::Default::builder()
+                    .with_receive_address(addr)?
+                    .build()?;
+                let server = Server::builder().with_tls(tls)?.with_io(io)?.start()?;
+                addr = server.local_addr()?;
+                servers.push(server);
+            }
+        }
+        self.servers = servers;
+        Ok(self.local_addrs())
+    }
+
+    pub fn local_addrs(&self) -> Vec<SocketAddr> {
+        let mut addrs: Vec<SocketAddr> = Vec::new();
+        for addr in self.servers.iter().filter_map(|s| s.local_addr().ok()) {
+            if !addrs.contains(&addr) {
+                addrs.push(addr);
+            }
+        }
+        addrs
+    }
+
+    pub(crate) fn config(&self) -> &ServerConfig {
+        &self.config
+    }
+
+    pub async fn serve(mut self) -> Result<(), Error> {
+        self.bind()?;
+        let servers = mem::take(&mut self.servers);
+        let mut tasks = Vec::with_capacity(servers.len());
+        for mut server in servers {
+            info!("quic endpoint on {} starting...", server.local_addr()?);
+            let mount_point = self.config.mount_point.clone();
+            let version = self.config.version;
+            let global = self.global.clone();
             let task = tokio::spawn(async move {
                 while let Some(mut connection) = server.accept().await {
+                    let mount_point = mount_point.clone();
+                    let global = global.clone();
                     tokio::spawn(async move {
-                        while let Ok(Some(stream)) = connection.accept_bidirectional_stream().await
-                        {
-                            match process_client(stream, self.config.version, self.global).await {
+                        let remote_addr = connection.remote_addr().ok();
+                        let timeout = global.handshake_timeout();
+                        loop {
+                            // a connection which opens no stream is closed like a silent TCP
+                            // connection
+                            let stream = match time::timeout(
+                                timeout,
+                                connection.accept_bidirectional_stream(),
+                            )
+                            .await
+                            {
+                                Ok(Ok(Some(stream))) => stream,
+                                Ok(_) => break,
+                                Err(_) => {
+                                    warn!(
+                                        "quic connection from {remote_addr:?} opened no stream within {timeout:?}"
+                                    );
+                                    break;
+                                }
+                            };
+                            match process_client(
+                                stream,
+                                remote_addr,
+                                version,
+                                mount_point.clone(),
+                                None,
+                                global.clone(),
+                            )
+                            .await
+                            {
                                 Ok(v) => v,
                                 Err(e) => return Err(e),
                             }
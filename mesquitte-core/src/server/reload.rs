@@ -0,0 +1,76 @@
+//! Changing settings of a running broker
+//!
+//! [`GlobalState::reload`] swaps the settings of a [`Reload`] in place. Connected clients are
+//! not dropped, the new settings apply to the next packet they send: a reloaded script decides
+//! on the next connect, subscribe and publish, the client identifier rules on the next connect.
+//!
+//! On Unix, [`reload_on_sighup`] reloads whenever the process receives `SIGHUP`, the usual way
+//! to tell a daemon to read its configuration again.
+
+use std::io;
+
+#[cfg(feature = "script")]
+use crate::script::ScriptHook;
+use crate::{info, warn};
+
+use super::{config::ClientIdConfig, state::GlobalState};
+
+/// Settings to change, `None` keeps the current value
+#[derive(Default)]
+pub struct Reload {
+    /// Maximum level of the `log` records, unused with the `tracing` feature whose filters are
+    /// set up by the application
+    #[cfg(feature = "log")]
+    pub log_level: Option<log::LevelFilter>,
+    pub client_id_config: Option<ClientIdConfig>,
+    pub reserved_topic_prefixes: Option<Vec<String>>,
+    /// Authentication and authorization hooks, see [`crate::script`]
+    #[cfg(feature = "script")]
+    pub script: Option<ScriptHook>,
+}
+
+impl<S> GlobalState<S> {
+    /// Applies the settings of `reload`, see [`self`](super::reload)
+    pub fn reload(&self, reload: Reload) {
+        #[cfg(feature = "log")]
+        if let Some(level) = reload.log_level {
+            log::set_max_level(level);
+            info!("reload: log level {level}");
+        }
+        if let Some(client_id_config) = reload.client_id_config {
+            info!("reload: client id config {client_id_config:?}");
+            self.set_client_id_config(client_id_config);
+        }
+        if let Some(prefixes) = reload.reserved_topic_prefixes {
+            info!("reload: reserved topic prefixes {prefixes:?}");
+            self.set_reserved_topic_prefixes(prefixes);
+        }
+        #[cfg(feature = "script")]
+        if let Some(script) = reload.script {
+            info!("reload: script");
+            self.set_script(script);
+        }
+    }
+}
+
+/// Calls `load` and applies the returned settings on every `SIGHUP`, runs until the signal
+/// handler can no longer be polled
+///
+/// A failing `load` keeps the current settings.
+#[cfg(unix)]
+pub async fn reload_on_sighup<S, F, E>(global: &GlobalState<S>, load: F) -> io::Result<()>
+where
+    F: Fn() -> Result<Reload, E>,
+    E: std::fmt::Display,
+{
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangup = signal(SignalKind::hangup())?;
+    while hangup.recv().await.is_some() {
+        match load() {
+            Ok(reload) => global.reload(reload),
+            Err(err) => warn!("reload failed, keeping the current settings: {err}"),
+        }
+    }
+    Ok(())
+}
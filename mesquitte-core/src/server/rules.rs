@@ -0,0 +1,387 @@
+//! Topic rewriting and filtering rules
+//!
+//! A [`RuleEngine`] is a [`MessageInterceptor`] applying the first [`Rule`] whose topic filter
+//! matches a publish. A rule either drops the message or rewrites its topic, QoS and retain
+//! flag. Rewritten topics are templates where `{n}` is replaced by the topic levels matched by
+//! the n-th wildcard of the filter, e.g. the filter `sensors/+/temp` with the template
+//! `metrics/{1}/temperature` republishes `sensors/kitchen/temp` to
+//! `metrics/kitchen/temperature`. A `#` wildcard captures all the remaining levels.
+
+use futures::future::BoxFuture;
+use mqtt_codec_kit::common::{QualityOfService, TopicFilter, TopicName};
+
+use crate::{store::message::PublishMessage, warn};
+
+use super::interceptor::{InterceptAction, MessageInterceptor};
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("Invalid Topic Filter : {0}")]
+    TopicFilter(String),
+    #[error("Invalid QoS : {0}")]
+    Qos(u8),
+    #[error("Invalid Topic Template : {0}")]
+    Template(String),
+}
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Rule {
+    /// Topic filter selecting the messages, may contain wildcards
+    pub topic: String,
+    pub action: RuleAction,
+}
+
+#[derive(Debug, Clone)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(tag = "type", rename_all = "snake_case")
+)]
+pub enum RuleAction {
+    Drop,
+    /// Changes the given parts of the message, the others are kept
+    Rewrite {
+        topic: Option<String>,
+        qos: Option<u8>,
+        retain: Option<bool>,
+    },
+}
+
+#[derive(Debug)]
+enum Segment {
+    Literal(String),
+    Capture(usize),
+}
+
+#[derive(Debug)]
+enum CompiledAction {
+    Drop,
+    Rewrite {
+        topic: Option<Vec<Segment>>,
+        qos: Option<QualityOfService>,
+        retain: Option<bool>,
+    },
+}
+
+#[derive(Debug)]
+struct CompiledRule {
+    filter: TopicFilter,
+    action: CompiledAction,
+}
+
+#[derive(Debug)]
+pub struct RuleEngine {
+    rules: Vec<CompiledRule>,
+}
+
+impl RuleEngine {
+    /// Checks the rules, they are applied in the given order
+    pub fn new(rules: Vec<Rule>) -> Result<Self, Error> {
+        let rules = rules
+            .into_iter()
+            .map(compile)
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Self { rules })
+    }
+
+    fn apply(&self, message: &mut PublishMessage) -> InterceptAction {
+        let Some(rule) = self
+            .rules
+            .iter()
+            .find(|rule| rule.filter.get_matcher().is_match(message.topic_name()))
+        else {
+            return InterceptAction::Continue;
+        };
+
+        let (topic, qos, retain) = match &rule.action {
+            CompiledAction::Drop => return InterceptAction::Reject,
+            CompiledAction::Rewrite { topic, qos, retain } => (topic, qos, retain),
+        };
+        if let Some(template) = topic {
+            let captures = captures(&rule.filter, message.topic_name());
+            let rewritten = render(template, &captures);
+            match TopicName::new(rewritten) {
+                Ok(topic_name) => message.set_topic_name(topic_name),
+                Err(err) => {
+                    warn!("rule for {:?} rewrote an invalid topic: {err}", rule.filter);
+                    return InterceptAction::Reject;
+                }
+            }
+        }
+        if let Some(qos) = qos {
+            message.set_qos(*qos);
+        }
+        if let Some(retain) = retain {
+            message.set_retain(*retain);
+        }
+        InterceptAction::Continue
+    }
+}
+
+impl MessageInterceptor for RuleEngine {
+    fn on_publish<'a>(
+        &'a self,
+        _client_id: &'a str,
+        message: &'a mut PublishMessage,
+    ) -> BoxFuture<'a, InterceptAction> {
+        let action = self.apply(message);
+        Box::pin(async move { action })
+    }
+}
+
+fn compile(rule: Rule) -> Result<CompiledRule, Error> {
+    let filter = TopicFilter::new(rule.topic.as_str())
+        .map_err(|_| Error::TopicFilter(rule.topic.clone()))?;
+    let wildcards = filter.split('/').filter(|l| *l == "+" || *l == "#").count();
+
+    let action = match rule.action {
+        RuleAction::Drop => CompiledAction::Drop,
+        RuleAction::Rewrite { topic, qos, retain } => CompiledAction::Rewrite {
+            topic: topic
+                .map(|template| parse_template(&template, wildcards))
+                .transpose()?,
+            qos: qos
+                .map(|qos| match qos {
+                    0 => Ok(QualityOfService::Level0),
+                    1 => Ok(QualityOfService::Level1),
+                    2 => Ok(QualityOfService::Level2),
+                    _ => Err(Error::Qos(qos)),
+                })
+                .transpose()?,
+            retain,
+        },
+    };
+    Ok(CompiledRule { filter, action })
+}
+
+fn parse_template(template: &str, wildcards: usize) -> Result<Vec<Segment>, Error> {
+    let invalid = || Error::Template(template.to_owned());
+    let mut segments = Vec::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        if start > 0 {
+            segments.push(Segment::Literal(rest[..start].to_owned()));
+        }
+        let end = rest[start..].find('}').ok_or_else(invalid)? + start;
+        let index: usize = rest[start + 1..end].parse().map_err(|_| invalid())?;
+        if index == 0 || index > wildcards {
+            return Err(invalid());
+        }
+        segments.push(Segment::Capture(index - 1));
+        rest = &rest[end + 1..];
+    }
+    if !rest.is_empty() {
+        segments.push(Segment::Literal(rest.to_owned()));
+    }
+    if segments.is_empty() {
+        return Err(invalid());
+    }
+    Ok(segments)
+}
+
+/// Topic levels matched by each wildcard of `filter`, which must match `topic_name`
+fn captures<'a>(filter: &str, topic_name: &'a str) -> Vec<&'a str> {
+    let mut captures = Vec::new();
+    let mut offset = 0;
+    let mut levels = topic_name.split('/');
+    for filter_level in filter.split('/') {
+        match filter_level {
+            "#" => {
+                captures.push(topic_name.get(offset..).unwrap_or_default());
+                break;
+            }
+            "+" => {
+                let level = levels.next().unwrap_or_default();
+                offset += level.len() + 1;
+                captures.push(level);
+            }
+            _ => {
+                offset += levels.next().map_or(0, |level| level.len() + 1);
+            }
+        }
+    }
+    captures
+}
+
+fn render(template: &[Segment], captures: &[&str]) -> String {
+    let mut topic = String::new();
+    for segment in template {
+        match segment {
+            Segment::Literal(literal) => topic.push_str(literal),
+            Segment::Capture(index) => topic.push_str(captures.get(*index).unwrap_or(&"")),
+        }
+    }
+    topic
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rewrite(filter: &str, topic: &str) -> Rule {
+        Rule {
+            topic: filter.to_owned(),
+            action: RuleAction::Rewrite {
+                topic: Some(topic.to_owned()),
+                qos: None,
+                retain: None,
+            },
+        }
+    }
+
+    fn message(topic: &str) -> PublishMessage {
+        PublishMessage::new(
+            TopicName::new(topic).unwrap(),
+            b"payload".to_vec(),
+            QualityOfService::Level0,
+            false,
+        )
+    }
+
+    /// The action of `rules` on a publish to `topic`, and the topic it ends up with
+    fn apply(rules: Vec<Rule>, topic: &str) -> (InterceptAction, String) {
+        let engine = RuleEngine::new(rules).unwrap();
+        let mut message = message(topic);
+        let action = engine.apply(&mut message);
+        (action, message.topic_name().to_string())
+    }
+
+    #[test]
+    fn wildcards_are_captured() {
+        for (filter, topic, expected) in [
+            ("a/+/c", "a/b/c", vec!["b"]),
+            ("+/+", "a/b", vec!["a", "b"]),
+            ("a/+", "a/", vec![""]),
+            ("a/#", "a/b/c", vec!["b/c"]),
+            ("a/#", "a", vec![""]),
+            ("#", "a/b", vec!["a/b"]),
+            ("+/b/#", "a/b/c/d", vec!["a", "c/d"]),
+            ("a/b", "a/b", vec![]),
+        ] {
+            assert_eq!(captures(filter, topic), expected, "{filter} on {topic}");
+        }
+    }
+
+    #[test]
+    fn topics_are_rewritten() {
+        for (filter, template, topic, expected) in [
+            (
+                "sensors/+/temp",
+                "metrics/{1}/temperature",
+                "sensors/kitchen/temp",
+                "metrics/kitchen/temperature",
+            ),
+            ("+/+", "{2}/{1}", "a/b", "b/a"),
+            ("+/+", "{1}-{1}", "a/b", "a-a"),
+            ("in/#", "out/{1}", "in/a/b", "out/a/b"),
+            ("a/+", "fixed", "a/b", "fixed"),
+        ] {
+            assert_eq!(
+                apply(vec![rewrite(filter, template)], topic),
+                (InterceptAction::Continue, expected.to_owned()),
+                "{template} for {topic}"
+            );
+        }
+    }
+
+    #[test]
+    fn malformed_templates_are_refused() {
+        for (filter, template) in [
+            ("a/+", "b/{2}"),
+            ("a/+", "b/{0}"),
+            ("a/b", "b/{1}"),
+            ("a/+", "b/{1"),
+            ("a/+", "b/{x}"),
+            ("a/+", "b/{}"),
+            ("a/+", ""),
+        ] {
+            assert!(
+                matches!(
+                    RuleEngine::new(vec![rewrite(filter, template)]),
+                    Err(Error::Template(t)) if t == template
+                ),
+                "{template}"
+            );
+        }
+    }
+
+    #[test]
+    fn invalid_rules_are_refused() {
+        assert!(matches!(
+            RuleEngine::new(vec![rewrite("a/#/b", "b")]),
+            Err(Error::TopicFilter(_))
+        ));
+        let rule = Rule {
+            topic: "a".to_owned(),
+            action: RuleAction::Rewrite {
+                topic: None,
+                qos: Some(3),
+                retain: None,
+            },
+        };
+        assert!(matches!(RuleEngine::new(vec![rule]), Err(Error::Qos(3))));
+    }
+
+    #[test]
+    fn unmatched_messages_are_kept() {
+        let rules = vec![
+            rewrite("a/+", "b/{1}"),
+            Rule {
+                topic: "c/#".to_owned(),
+                action: RuleAction::Drop,
+            },
+        ];
+        assert_eq!(
+            apply(rules, "d/e"),
+            (InterceptAction::Continue, "d/e".to_owned())
+        );
+    }
+
+    #[test]
+    fn first_matching_rule_applies() {
+        let rules = vec![
+            Rule {
+                topic: "a/secret".to_owned(),
+                action: RuleAction::Drop,
+            },
+            rewrite("a/+", "b/{1}"),
+            rewrite("#", "c"),
+        ];
+        assert_eq!(apply(rules.clone(), "a/secret").0, InterceptAction::Reject);
+        assert_eq!(
+            apply(rules.clone(), "a/x"),
+            (InterceptAction::Continue, "b/x".to_owned())
+        );
+        assert_eq!(
+            apply(rules, "x"),
+            (InterceptAction::Continue, "c".to_owned())
+        );
+    }
+
+    #[test]
+    fn qos_and_retain_are_rewritten() {
+        let engine = RuleEngine::new(vec![Rule {
+            topic: "a".to_owned(),
+            action: RuleAction::Rewrite {
+                topic: None,
+                qos: Some(1),
+                retain: Some(true),
+            },
+        }])
+        .unwrap();
+        let mut message = message("a");
+        assert_eq!(engine.apply(&mut message), InterceptAction::Continue);
+        assert_eq!(message.topic_name().to_string(), "a");
+        assert_eq!(message.qos(), QualityOfService::Level1);
+        assert!(message.retain());
+    }
+
+    #[test]
+    fn invalid_rewritten_topic_is_rejected() {
+        // the capture brings a wildcard into the topic name
+        let (action, topic) = apply(vec![rewrite("a/#", "b/{1}/#")], "a/c");
+        assert_eq!(action, InterceptAction::Reject);
+        assert_eq!(topic, "a/c");
+    }
+}
@@ -1,12 +1,18 @@
 use std::{fs::File, io::BufReader, sync::Arc};
 
-use rustls::{server::WebPkiClientVerifier, RootCertStore};
+use rustls::{
+    crypto::{aws_lc_rs::Ticketer, GetRandomFailed},
+    server::{
+        NoServerSessionStorage, ProducesTickets, ServerSessionMemoryCache, WebPkiClientVerifier,
+    },
+    RootCertStore, TicketRotator,
+};
 use tokio_rustls::{
     rustls::{Error as RustlsError, ServerConfig},
     TlsAcceptor,
 };
 
-use super::config::TlsConfig;
+use super::config::{TlsConfig, TlsResumption};
 
 #[derive(Debug, thiserror::Error)]
 #[error("Acceptor error")]
@@ -50,12 +56,116 @@ pub fn rustls_server_config(cfg: &TlsConfig) -> Result<ServerConfig, Error> {
         WebPkiClientVerifier::no_client_auth()
     };
 
-    ServerConfig::builder()
+    let mut config = ServerConfig::builder()
         .with_client_cert_verifier(client_auth)
         .with_single_cert(cert_chain, key)
-        .map_err(|e| Error::InvalidCACert(e.to_string()))
+        .map_err(|e| Error::InvalidCACert(e.to_string()))?;
+    config.alpn_protocols = cfg.alpn_protocols.clone();
+    set_resumption(&mut config, &cfg.resumption)?;
+    Ok(config)
+}
+
+fn set_resumption(config: &mut ServerConfig, resumption: &TlsResumption) -> Result<(), Error> {
+    config.session_storage = match resumption.session_cache_size {
+        0 => Arc::new(NoServerSessionStorage {}),
+        size => ServerSessionMemoryCache::new(size),
+    };
+    if resumption.tickets {
+        let lifetime = u32::try_from(resumption.ticket_key_lifetime.as_secs())
+            .unwrap_or(u32::MAX)
+            .max(1);
+        config.ticketer = Arc::new(TicketRotator::new(lifetime, ticket_key)?);
+    }
+    config.send_tls13_tickets = resumption.tls13_tickets;
+    Ok(())
+}
+
+// rustls only hands out its ticket encryption through `Ticketer`, which rotates its own keys
+// every six hours; a new one comes with a new random key
+fn ticket_key() -> Result<Box<dyn ProducesTickets>, GetRandomFailed> {
+    Ticketer::new()
+        .map(|ticketer| Box::new(TicketKey(ticketer)) as Box<dyn ProducesTickets>)
+        .map_err(|_| GetRandomFailed)
+}
+
+#[derive(Debug)]
+struct TicketKey(Arc<dyn ProducesTickets>);
+
+impl ProducesTickets for TicketKey {
+    fn enabled(&self) -> bool {
+        self.0.enabled()
+    }
+
+    fn lifetime(&self) -> u32 {
+        self.0.lifetime()
+    }
+
+    fn encrypt(&self, plain: &[u8]) -> Option<Vec<u8>> {
+        self.0.encrypt(plain)
+    }
+
+    fn decrypt(&self, cipher: &[u8]) -> Option<Vec<u8>> {
+        self.0.decrypt(cipher)
+    }
 }
 
 pub fn rustls_acceptor(cfg: &TlsConfig) -> Result<TlsAcceptor, Error> {
     Ok(TlsAcceptor::from(Arc::new(rustls_server_config(cfg)?)))
 }
+
+/// The acceptor of a listener: the shared rustls config if one is set, otherwise one built from
+/// the [`TlsConfig`] of the listener
+#[cfg(any(feature = "mqtts", feature = "wss"))]
+#[allow(clippy::result_large_err)]
+pub(crate) fn listener_acceptor(
+    shared: Option<&Arc<ServerConfig>>,
+    cfg: Option<&TlsConfig>,
+) -> Result<TlsAcceptor, super::Error> {
+    match (shared, cfg) {
+        (Some(config), _) => Ok(TlsAcceptor::from(config.clone())),
+        (None, Some(cfg)) => Ok(rustls_acceptor(cfg)?),
+        (None, None) => Err(super::Error::MissingTlsConfig),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resumption_is_configured() {
+        use rustls::{
+            server::{ClientHello, ResolvesServerCert},
+            sign::CertifiedKey,
+        };
+
+        #[derive(Debug)]
+        struct NoCert;
+
+        impl ResolvesServerCert for NoCert {
+            fn resolve(&self, _: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+                None
+            }
+        }
+
+        let mut config = ServerConfig::builder()
+            .with_no_client_auth()
+            .with_cert_resolver(Arc::new(NoCert));
+        set_resumption(&mut config, &TlsResumption::default()).unwrap();
+        assert!(!config.ticketer.enabled());
+        assert!(config.session_storage.can_cache());
+
+        let resumption = TlsResumption {
+            session_cache_size: 0,
+            tickets: true,
+            tls13_tickets: 4,
+            ..Default::default()
+        };
+        set_resumption(&mut config, &resumption).unwrap();
+        assert!(config.ticketer.enabled());
+        assert!(!config.session_storage.can_cache());
+        assert_eq!(config.send_tls13_tickets, 4);
+        let ticket = config.ticketer.encrypt(b"session").unwrap();
+        assert_eq!(config.ticketer.decrypt(&ticket).unwrap(), b"session");
+    }
+}
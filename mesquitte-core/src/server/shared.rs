@@ -0,0 +1,187 @@
+//! Shared subscription groups
+//!
+//! A publish matching `$share/{group}/{filter}` is delivered to one member of the group. The
+//! members take turns in client identifier order, skipping the persistent members without a
+//! connection while another member is connected. The members are the ones the topic store
+//! matched, so a member joining or leaving the group gets its turn, or stops getting one, from
+//! the next publish on; every such change counts as a rebalance of the group.
+//!
+//! The members and delivery counters of every group are read with
+//! [`GlobalState::shared_groups`](super::state::GlobalState::shared_groups).
+
+use std::collections::BTreeMap;
+
+use foldhash::HashMap;
+use parking_lot::Mutex;
+
+use crate::{debug, store::topic::SubscriptionOptions};
+
+use super::state::Clients;
+
+/// A member of a shared subscription group
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SharedMember {
+    pub client_id: String,
+    pub connected: bool,
+    /// Publishes the group delivered to the member since it joined
+    pub deliveries: u64,
+    /// Messages queued for the member and not written yet, from this group or any other
+    /// subscription
+    pub lag: usize,
+}
+
+/// A shared subscription group and its members as of the last publish it matched
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SharedGroup {
+    /// The shared topic filter, `$share/{group}/{filter}`
+    pub topic_filter: String,
+    /// Publishes delivered to a member of the group
+    pub deliveries: u64,
+    /// Times a member joined or left
+    pub rebalances: u64,
+    pub members: Vec<SharedMember>,
+}
+
+#[derive(Debug, Default)]
+struct Group {
+    // deliveries per member, in turn order
+    members: BTreeMap<String, u64>,
+    next: usize,
+    deliveries: u64,
+    rebalances: u64,
+}
+
+impl Group {
+    fn update_members(&mut self, topic_filter: &str, members: &[(String, SubscriptionOptions)]) {
+        if self.members.keys().eq(members.iter().map(|(id, _)| id)) {
+            return;
+        }
+        if !self.members.is_empty() {
+            self.rebalances += 1;
+            debug!(
+                "shared group {topic_filter} rebalanced from {} to {} members",
+                self.members.len(),
+                members.len()
+            );
+        }
+        self.members = members
+            .iter()
+            .map(|(id, _)| (id.clone(), self.members.get(id).copied().unwrap_or(0)))
+            .collect();
+    }
+}
+
+/// Turns and counters of the shared subscription groups, see [`self`](super::shared)
+#[derive(Debug, Default)]
+pub(crate) struct SharedGroups {
+    groups: Mutex<HashMap<String, Group>>,
+}
+
+impl SharedGroups {
+    /// The member of the group of `topic_filter` whose turn it is to get a publish
+    pub(crate) fn pick(
+        &self,
+        topic_filter: &str,
+        members: HashMap<String, SubscriptionOptions>,
+        is_connected: impl Fn(&str) -> bool,
+    ) -> Option<(String, SubscriptionOptions)> {
+        let mut members: Vec<_> = members.into_iter().collect();
+        members.sort_unstable_by(|(a, _), (b, _)| a.cmp(b));
+
+        let mut groups = self.groups.lock();
+        if members.is_empty() {
+            groups.remove(topic_filter);
+            return None;
+        }
+        let group = groups.entry(topic_filter.to_owned()).or_default();
+        group.update_members(topic_filter, &members);
+
+        let start = group.next % members.len();
+        let index = (0..members.len())
+            .map(|offset| (start + offset) % members.len())
+            .find(|&index| is_connected(&members[index].0))
+            .unwrap_or(start);
+        group.next = index + 1;
+        group.deliveries += 1;
+        let (client_id, options) = members.swap_remove(index);
+        if let Some(deliveries) = group.members.get_mut(&client_id) {
+            *deliveries += 1;
+        }
+        Some((client_id, options))
+    }
+
+    /// The groups with their members, members whose session is gone are left out
+    pub(crate) fn view(&self, clients: &Clients) -> Vec<SharedGroup> {
+        let mut view: Vec<_> = self
+            .groups
+            .lock()
+            .iter()
+            .map(|(topic_filter, group)| SharedGroup {
+                topic_filter: topic_filter.clone(),
+                deliveries: group.deliveries,
+                rebalances: group.rebalances,
+                members: group
+                    .members
+                    .iter()
+                    .filter_map(|(client_id, deliveries)| {
+                        let handle = clients.get(client_id)?;
+                        Some(SharedMember {
+                            client_id: client_id.clone(),
+                            connected: handle.is_connected(),
+                            deliveries: *deliveries,
+                            lag: handle.queue().len(),
+                        })
+                    })
+                    .collect(),
+            })
+            .collect();
+        view.sort_unstable_by(|a, b| a.topic_filter.cmp(&b.topic_filter));
+        view
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mqtt_codec_kit::common::QualityOfService;
+
+    use super::*;
+
+    fn members(ids: &[&str]) -> HashMap<String, SubscriptionOptions> {
+        ids.iter()
+            .map(|id| (id.to_string(), QualityOfService::Level1.into()))
+            .collect()
+    }
+
+    fn pick(groups: &SharedGroups, ids: &[&str], offline: &[&str]) -> String {
+        groups
+            .pick("$share/g/a", members(ids), |id| !offline.contains(&id))
+            .unwrap()
+            .0
+    }
+
+    #[test]
+    fn members_take_turns() {
+        let groups = SharedGroups::default();
+        let picked: Vec<_> = (0..4).map(|_| pick(&groups, &["c1", "c2"], &[])).collect();
+        assert_eq!(picked, ["c1", "c2", "c1", "c2"]);
+
+        // a member without a connection is skipped while another one is connected
+        assert_eq!(pick(&groups, &["c1", "c2"], &["c1"]), "c2");
+        assert_eq!(pick(&groups, &["c1", "c2"], &["c1", "c2"]), "c1");
+
+        // c3 joins and gets its turn, then c1 leaves
+        let picked: Vec<_> = (0..3)
+            .map(|_| pick(&groups, &["c1", "c2", "c3"], &[]))
+            .collect();
+        assert_eq!(picked, ["c2", "c3", "c1"]);
+        assert_eq!(pick(&groups, &["c2", "c3"], &[]), "c3");
+
+        let group = &groups.groups.lock()["$share/g/a"];
+        assert_eq!(group.deliveries, 10);
+        assert_eq!(group.rebalances, 2);
+        assert_eq!(
+            group.members.iter().collect::<Vec<_>>(),
+            [(&"c2".to_owned(), &4), (&"c3".to_owned(), &2)]
+        );
+    }
+}
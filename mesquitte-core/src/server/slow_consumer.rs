@@ -0,0 +1,252 @@
+//! Eviction of clients which stop reading what the broker sends them
+//!
+//! A client which does not read fills its deliver queue and its pending messages, and once the
+//! socket buffers are full every write to it blocks. Such a client is disconnected as soon as one
+//! of the limits of [`SlowConsumerConfig`] is exceeded, instead of holding broker memory until it
+//! leaves on its own. MQTT 5 clients get a DISCONNECT with Quota Exceeded first. Evictions are
+//! counted in [`Metrics::slow_consumer_disconnects`].
+
+use std::{
+    fmt::{self, Display},
+    future::Future,
+    io,
+    pin::Pin,
+    sync::Arc,
+    task::{ready, Context, Poll},
+    time::Duration,
+};
+
+use tokio::{
+    io::AsyncWrite,
+    time::{self, Sleep},
+};
+
+use super::metrics::Metrics;
+use crate::store::{message::MessageStore, queue::DeliverQueue, Storage};
+
+/// Limits of a connected session, every limit is off by default
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SlowConsumerConfig {
+    /// Longest a write to the client may block
+    pub write_timeout: Option<Duration>,
+    /// Longest a message may wait in the deliver queue
+    pub max_queue_latency: Option<Duration>,
+    /// Most payload bytes waiting in the deliver queue
+    pub max_queue_bytes: Option<usize>,
+    /// Most messages kept in the message store for the session, sent and not acknowledged ones
+    /// and received QoS 2 ones which were not released
+    pub max_stored_messages: Option<usize>,
+}
+
+impl SlowConsumerConfig {
+    /// Whether any limit is checked periodically, the write timeout is checked on every write
+    pub(crate) fn checks_backlog(&self) -> bool {
+        self.max_queue_latency.is_some()
+            || self.max_queue_bytes.is_some()
+            || self.max_stored_messages.is_some()
+    }
+
+    /// Which limit the backlog of a session exceeds
+    pub(crate) async fn check<S: MessageStore>(
+        &self,
+        client_id: &str,
+        queue: &DeliverQueue,
+        storage: &Storage<S>,
+    ) -> Option<SlowConsumer> {
+        if let Some(max) = self.max_queue_latency {
+            if let Some(age) = queue.oldest_age().filter(|age| *age > max) {
+                return Some(SlowConsumer::QueueLatency(age));
+            }
+        }
+        if let Some(max) = self.max_queue_bytes {
+            let bytes = queue.bytes();
+            if bytes > max {
+                return Some(SlowConsumer::QueueBytes(bytes));
+            }
+        }
+        if let Some(max) = self.max_stored_messages {
+            // a failing store is not the client's fault
+            let count = storage.message_count(client_id).await.unwrap_or(0);
+            if count > max {
+                return Some(SlowConsumer::StoredMessages(count));
+            }
+        }
+        None
+    }
+}
+
+/// Why a session is a slow consumer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum SlowConsumer {
+    QueueLatency(Duration),
+    QueueBytes(usize),
+    StoredMessages(usize),
+}
+
+impl Display for SlowConsumer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SlowConsumer::QueueLatency(age) => write!(f, "oldest queued message waits {age:?}"),
+            SlowConsumer::QueueBytes(bytes) => write!(f, "{bytes} bytes queued"),
+            SlowConsumer::StoredMessages(count) => write!(f, "{count} messages stored"),
+        }
+    }
+}
+
+/// Fails writes and flushes to the client which block longer than the write timeout with
+/// [`io::ErrorKind::TimedOut`]
+///
+/// Once timed out every later write fails right away, so a DISCONNECT attempted after the error
+/// does not block again.
+pub(crate) struct WriteTimeout<W> {
+    inner: W,
+    timeout: Option<Duration>,
+    // armed while the inner writer is blocked
+    sleep: Option<Pin<Box<Sleep>>>,
+    timed_out: bool,
+    metrics: Arc<Metrics>,
+}
+
+impl<W> WriteTimeout<W> {
+    pub fn new(inner: W, timeout: Option<Duration>, metrics: Arc<Metrics>) -> Self {
+        Self {
+            inner,
+            timeout,
+            sleep: None,
+            timed_out: false,
+            metrics,
+        }
+    }
+
+    fn poll_timed<T>(
+        &mut self,
+        cx: &mut Context<'_>,
+        poll: impl FnOnce(Pin<&mut W>, &mut Context<'_>) -> Poll<io::Result<T>>,
+    ) -> Poll<io::Result<T>>
+    where
+        W: Unpin,
+    {
+        if self.timed_out {
+            return Poll::Ready(Err(timed_out()));
+        }
+        let Some(timeout) = self.timeout else {
+            return poll(Pin::new(&mut self.inner), cx);
+        };
+        if let Poll::Ready(ret) = poll(Pin::new(&mut self.inner), cx) {
+            self.sleep = None;
+            return Poll::Ready(ret);
+        }
+        let sleep = self
+            .sleep
+            .get_or_insert_with(|| Box::pin(time::sleep(timeout)));
+        ready!(sleep.as_mut().poll(cx));
+        self.timed_out = true;
+        self.metrics.record_slow_consumer_disconnect();
+        Poll::Ready(Err(timed_out()))
+    }
+}
+
+fn timed_out() -> io::Error {
+    io::Error::new(io::ErrorKind::TimedOut, "slow consumer: write timed out")
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for WriteTimeout<W> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.get_mut()
+            .poll_timed(cx, |inner, cx| inner.poll_write(cx, buf))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.get_mut()
+            .poll_timed(cx, |inner, cx| inner.poll_flush(cx))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{duplex, AsyncWriteExt as _};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn blocked_write_times_out() {
+        let metrics = Arc::new(Metrics::default());
+        let (_client, server) = duplex(16);
+        let mut writer =
+            WriteTimeout::new(server, Some(Duration::from_millis(20)), metrics.clone());
+
+        // nobody reads, the first write fills the pipe and the second one blocks
+        writer.write_all(&[0; 16]).await.unwrap();
+        let err = writer.write_all(&[0; 16]).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert_eq!(metrics.slow_consumer_disconnects(), 1);
+
+        let err = writer.write_all(&[0]).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert_eq!(metrics.slow_consumer_disconnects(), 1);
+    }
+
+    #[cfg(feature = "v4")]
+    #[tokio::test]
+    async fn queue_backlog_is_detected() {
+        use mqtt_codec_kit::{
+            common::{qos::QoSWithPacketIdentifier, QualityOfService, TopicFilter, TopicName},
+            v4::packet::PublishPacket,
+        };
+
+        use crate::store::{
+            memory_storage,
+            queue::{QueueConfig, QueuedMessage},
+        };
+
+        let storage = memory_storage();
+        let queue = DeliverQueue::new(QueueConfig::default());
+        let packet = PublishPacket::new(
+            TopicName::new("a/b").unwrap(),
+            QoSWithPacketIdentifier::Level1(1),
+            vec![0u8; 100],
+        );
+        for _ in 0..3 {
+            queue.push(QueuedMessage {
+                topic_filter: TopicFilter::new("a/#").unwrap(),
+                subscribe_qos: QualityOfService::Level1,
+                message: Arc::new((&packet).into()),
+            });
+        }
+        assert_eq!(queue.bytes(), 300);
+
+        let config = SlowConsumerConfig {
+            max_queue_bytes: Some(300),
+            ..Default::default()
+        };
+        assert_eq!(config.check("c1", &queue, &storage).await, None);
+        let config = SlowConsumerConfig {
+            max_queue_bytes: Some(299),
+            ..Default::default()
+        };
+        assert_eq!(
+            config.check("c1", &queue, &storage).await,
+            Some(SlowConsumer::QueueBytes(300))
+        );
+
+        queue.pop_batch(2);
+        assert_eq!(queue.bytes(), 100);
+        let config = SlowConsumerConfig {
+            max_queue_latency: Some(Duration::ZERO),
+            ..Default::default()
+        };
+        time::sleep(Duration::from_millis(1)).await;
+        assert!(matches!(
+            config.check("c1", &queue, &storage).await,
+            Some(SlowConsumer::QueueLatency(_))
+        ));
+    }
+}
@@ -1,48 +1,279 @@
-use std::{fmt::Display, time::Duration};
+use std::{
+    fmt::Display,
+    mem,
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, UNIX_EPOCH},
+};
 
-use dashmap::DashMap;
-use kanal::{bounded_async, AsyncSender};
-use mqtt_codec_kit::common::{QualityOfService, TopicFilter};
-use tokio::time;
+use arc_swap::{ArcSwap, ArcSwapOption};
+use dashmap::{mapref::entry::Entry, DashMap};
+#[cfg(feature = "v5")]
+use foldhash::{HashMap, HashMapExt};
+use kanal::{bounded_async, AsyncSender, SendError};
+use mqtt_codec_kit::common::{
+    QualityOfService, TopicFilter, TopicName, MATCH_ALL_CHAR, MATCH_ALL_STR, MATCH_ONE_CHAR,
+    MATCH_ONE_STR, SHARED_PREFIX, SYS_PREFIX,
+};
+use nanoid::nanoid;
+use tokio::{task, time};
 
+#[cfg(feature = "http-auth")]
+use crate::http_auth::{self, Decision, FailPolicy, HttpAuth};
+#[cfg(feature = "script")]
+use crate::script::{ConnectDecision, Error as ScriptError, ScriptHook};
+#[cfg(feature = "session-export")]
+use crate::store::{export::SessionExport, message::ReceiveOutcome};
 use crate::{
+    debug,
     protocols::ProtocolSessionState,
-    store::{message::PublishMessage, Storage},
+    store::{
+        error::StoreError,
+        message::{MessageStore, PublishMessage},
+        queue::{DeliverQueue, QueueConfig},
+        retain::{RetainContent, RetainMessageStore},
+        session::{SessionStore, StoredPresence, StoredSession, StoredWill},
+        topic::{TopicContent, TopicStore},
+        Page, Storage,
+    },
     warn,
 };
 
+#[cfg(feature = "v4")]
+use super::config::Qos1FastPath;
+#[cfg(feature = "v5")]
+use super::enhanced_auth::EnhancedAuth;
+use super::{
+    audit::{now_millis, AuditEvent, AuditLog},
+    client_info::{ClientInfo, ClientStatus},
+    config::{ClientIdConfig, FlushPolicy, TopicFilterConfig},
+    dead_letter::{self, DeadLetterReason},
+    fanout::{self, Delivery, FanOutConfig},
+    interceptor::{InterceptAction, MessageInterceptor},
+    metrics::Metrics,
+    presence::{self, PresenceConfig},
+    shared::{SharedGroup, SharedGroups},
+    slow_consumer::SlowConsumerConfig,
+    timer::{TimerKind, Timers},
+    topic_stats::TopicStats,
+};
+
+pub const DEFAULT_MAX_INFLIGHT: usize = 32;
+pub const DEFAULT_RECEIVE_MAXIMUM: u16 = 32;
+pub const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Packets of one client handled in a row before its task yields, see
+/// [`GlobalState::with_read_batch_size`]
+pub const DEFAULT_READ_BATCH_SIZE: usize = 64;
+
+// publisher of the retained messages the broker stores itself
+pub(crate) const BROKER_CLIENT_ID: &str = "$broker";
+// retained messages looked up at once by a purge
+const PURGE_BATCH: usize = 256;
+
 pub enum AddClientReceipt {
     Present(ProtocolSessionState),
     New,
+    /// Another connection uses the client identifier and [`DuplicateClientIdPolicy::Reject`]
+    /// is configured
+    Rejected,
+    /// Another connection uses the client identifier, the new connection was added with this
+    /// client identifier instead
+    Renamed(String),
+}
+
+/// What to do when a client connects with the client identifier of a connected client
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DuplicateClientIdPolicy {
+    /// The new connection takes over the session and the old connection is closed
+    #[default]
+    TakeOver,
+    /// The new connection is rejected
+    Reject,
+    /// The new connection gets the client identifier with a random suffix
+    ///
+    /// MQTT 5 clients are told the new identifier in CONNACK. MQTT 3.1.1 has no way to tell a
+    /// client, so an MQTT 3.1.1 client is renamed silently and a persistent session it starts
+    /// can't be resumed by reconnecting with its own identifier.
+    Suffix,
+}
+
+/// How an MQTT 5 connection treats Clean Start and the Session Expiry Interval
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CleanStartSemantics {
+    /// As MQTT 5 specifies: a session ends when the Session Expiry Interval has elapsed after
+    /// its connection closed, right away for an interval of 0, and Clean Start discards the
+    /// session at connect
+    #[default]
+    Mqtt5,
+    /// Clean Start is the clean session of MQTT 3.1.1: the session of a connection without it
+    /// is kept even with a Session Expiry Interval of 0, and resumed until its expiry timer
+    /// removes it
+    Legacy,
 }
 
 #[derive(Debug, PartialEq)]
 pub enum KickReason {
-    FromAdmin,
+    /// Kicked with [`GlobalState::kick_client`], MQTT 5 clients get the reason as the reason
+    /// string of the DISCONNECT
+    FromAdmin(String),
+    /// Sent away by [`GlobalState::drain`], MQTT 5 clients get a DISCONNECT with the server
+    /// reference of the redirection
+    Redirected(Arc<Redirection>),
 }
 
 impl Display for KickReason {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            KickReason::FromAdmin => write!(f, "kicked by admin"),
+            KickReason::FromAdmin(reason) if reason.is_empty() => write!(f, "kicked by admin"),
+            KickReason::FromAdmin(reason) => write!(f, "kicked by admin: {reason}"),
+            KickReason::Redirected(redirection) => {
+                write!(f, "redirected to {}", redirection.server_reference)
+            }
+        }
+    }
+}
+
+/// Capacities of the channels of every connection
+///
+/// Larger channels absorb bursts at the cost of memory per connection, the
+/// [`Metrics`] count how often a channel was full.
+#[derive(Debug, Clone, Copy)]
+pub struct ChannelConfig {
+    /// Packets read from an MQTT 5 client and not handled yet, MQTT 3.1.1 connections handle
+    /// packets where they are read
+    pub read_channel_size: usize,
+    /// Control messages to a session, like kicks, takeovers and timers, publishes go through
+    /// its [`DeliverQueue`]
+    pub deliver_channel_size: usize,
+}
+
+impl Default for ChannelConfig {
+    fn default() -> Self {
+        Self {
+            read_channel_size: 8,
+            deliver_channel_size: 8,
         }
     }
 }
 
+/// Where clients are sent while the broker is drained, see [`GlobalState::drain`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Redirection {
+    /// The Server Reference property, e.g. `broker-2.example.com:1883`
+    pub server_reference: String,
+    /// Server Moved instead of Use Another Server: the clients should use the other server
+    /// from now on, not only this time
+    pub permanent: bool,
+}
+
+/// Control messages to a session, publishes go through its [`DeliverQueue`]
 #[derive(Debug)]
 pub enum DeliverMessage {
-    Publish(TopicFilter, QualityOfService, Box<PublishMessage>),
     Online(AsyncSender<ProtocolSessionState>),
     Kick(KickReason),
+    /// A timer scheduled with [`GlobalState::schedule_timer`] fired, carries the timer token
+    Timeout(TimerKind, u64),
+}
+
+pub(crate) struct ClientHandle {
+    sender: AsyncSender<DeliverMessage>,
+    queue: Arc<DeliverQueue>,
+    // token of the connection which owns the session
+    token: u64,
+    // false once the connection is closed and the session is kept for a later reconnect
+    connected: bool,
+    info: Arc<ClientInfo>,
+}
+
+impl ClientHandle {
+    pub(crate) fn sender(&self) -> &AsyncSender<DeliverMessage> {
+        &self.sender
+    }
+
+    pub(crate) fn queue(&self) -> &DeliverQueue {
+        &self.queue
+    }
+
+    pub(crate) fn is_connected(&self) -> bool {
+        self.connected && !self.sender.is_closed()
+    }
+}
+
+pub(crate) type Clients = DashMap<String, ClientHandle, foldhash::fast::RandomState>;
+
+/// What [`GlobalState::authorize_connect`] decided for a connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(not(any(feature = "script", feature = "http-auth")), allow(dead_code))]
+pub(crate) enum ConnectVerdict {
+    Accepted,
+    /// The script or the auth service refused the user name or password
+    BadCredentials,
+    /// Denied by the script or the auth service
+    NotAuthorized,
+    /// The script or the auth service failed, so the connection could not be checked
+    Unavailable,
+}
+
+/// What [`GlobalState::authorize_publish`] decided for a publish, MQTT 5 publishers are told
+/// in PUBACK or PUBREC
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PublishVerdict {
+    Accepted,
+    /// Denied by the script or the auth service
+    NotAuthorized,
+    /// Rejected by an interceptor
+    Rejected,
+}
+
+impl PublishVerdict {
+    pub fn is_accepted(self) -> bool {
+        self == PublishVerdict::Accepted
+    }
+}
+
+/// Whether the auth service allowed a subscribe or a publish, `None` leaves it to the script
+#[cfg(feature = "http-auth")]
+fn http_allowed(
+    http_auth: &HttpAuth,
+    action: &str,
+    decision: Result<Decision, http_auth::Error>,
+) -> Option<bool> {
+    match decision {
+        Ok(Decision::Allow) => Some(true),
+        Ok(Decision::Deny | Decision::BadCredentials) => Some(false),
+        Ok(Decision::Ignore) => None,
+        Err(err) => {
+            warn!("http auth of {action} failed: {err}");
+            match http_auth.fail_policy() {
+                FailPolicy::Open => None,
+                FailPolicy::Closed => Some(false),
+            }
+        }
+    }
+}
+
+/// Runs a hook of `script` on the blocking thread pool, a hook may keep its thread busy up to
+/// the timeout of the script
+#[cfg(feature = "script")]
+async fn script_blocking<T, F>(script: Arc<ScriptHook>, f: F) -> Result<T, ScriptError>
+where
+    T: Send + 'static,
+    F: FnOnce(&ScriptHook) -> Result<T, ScriptError> + Send + 'static,
+{
+    task::spawn_blocking(move || f(&script))
+        .await
+        .unwrap_or_else(|err| Err(std::io::Error::other(err).into()))
+}
+
+/// Whether `client_id` fits in one topic level, the level of its response topic
+fn is_topic_level(client_id: &str) -> bool {
+    !client_id.contains(['/', MATCH_ONE_CHAR, MATCH_ALL_CHAR])
 }
 
 pub struct GlobalState<S> {
-    // TODO: metrics?
     // TODO: config content
     // max qos
     // max connection ?
-    // read channel size
-    // deliver channel size
     // max packet size-> v3?
     // max inflight size
     // max inflight message size
@@ -56,35 +287,877 @@ pub struct GlobalState<S> {
     // min keep alive
     // config: Arc<Config>,
     pub storage: Storage<S>,
-    clients: DashMap<String, AsyncSender<DeliverMessage>, foldhash::fast::RandomState>,
+    clients: Arc<Clients>,
+    queue_config: QueueConfig,
+    fan_out_config: FanOutConfig,
+    shared_groups: SharedGroups,
+    channel_config: ChannelConfig,
+    flush_policy: FlushPolicy,
+    #[cfg(feature = "v4")]
+    max_inflight: usize,
+    receive_maximum: u16,
+    #[cfg(feature = "v4")]
+    qos1_fast_path: Option<Qos1FastPath>,
+    max_qos: QualityOfService,
+    duplicate_client_id: DuplicateClientIdPolicy,
+    clean_start: CleanStartSemantics,
+    client_id_config: ArcSwap<ClientIdConfig>,
+    topic_filter_config: TopicFilterConfig,
+    timers: Timers,
+    #[cfg(feature = "script")]
+    script: ArcSwapOption<ScriptHook>,
+    #[cfg(feature = "http-auth")]
+    http_auth: Option<HttpAuth>,
+    #[cfg(feature = "v5")]
+    enhanced_auth: HashMap<String, Box<dyn EnhancedAuth>>,
+    response_topic_prefix: Option<String>,
+    session_store: Option<Arc<dyn SessionStore>>,
+    interceptors: Vec<Box<dyn MessageInterceptor>>,
+    dead_letter_topic: Option<TopicName>,
+    reserved_topic_prefixes: ArcSwap<Vec<String>>,
+    audit_log: Option<AuditLog>,
+    presence: Option<PresenceConfig>,
+    max_decode_errors: usize,
+    metrics: Arc<Metrics>,
+    handshake_timeout: Duration,
+    slow_consumer: SlowConsumerConfig,
+    max_payload_size: Option<usize>,
+    max_packet_size: Option<u32>,
+    read_batch_size: usize,
+    topic_stats: Option<TopicStats>,
+    queue_qos0_messages: bool,
+    will_on_kick: bool,
+    #[cfg(feature = "v4")]
+    mqtt31: bool,
+    strict_handshake: bool,
+    redirection: ArcSwapOption<Redirection>,
 }
 
 impl<S> GlobalState<S> {
     pub fn new(storage: Storage<S>) -> Self {
         Self {
             storage,
-            clients: DashMap::default(),
+            clients: Arc::default(),
+            queue_config: QueueConfig::default(),
+            fan_out_config: FanOutConfig::default(),
+            shared_groups: SharedGroups::default(),
+            channel_config: ChannelConfig::default(),
+            flush_policy: FlushPolicy::default(),
+            #[cfg(feature = "v4")]
+            max_inflight: DEFAULT_MAX_INFLIGHT,
+            receive_maximum: DEFAULT_RECEIVE_MAXIMUM,
+            #[cfg(feature = "v4")]
+            qos1_fast_path: None,
+            max_qos: QualityOfService::Level2,
+            duplicate_client_id: DuplicateClientIdPolicy::default(),
+            clean_start: CleanStartSemantics::default(),
+            client_id_config: ArcSwap::default(),
+            topic_filter_config: TopicFilterConfig::default(),
+            timers: Timers::new(),
+            #[cfg(feature = "script")]
+            script: ArcSwapOption::empty(),
+            #[cfg(feature = "http-auth")]
+            http_auth: None,
+            #[cfg(feature = "v5")]
+            enhanced_auth: HashMap::new(),
+            response_topic_prefix: None,
+            session_store: None,
+            interceptors: Vec::new(),
+            dead_letter_topic: None,
+            reserved_topic_prefixes: ArcSwap::from_pointee(vec![
+                SYS_PREFIX.to_owned(),
+                SHARED_PREFIX.to_owned(),
+            ]),
+            audit_log: None,
+            presence: None,
+            max_decode_errors: 1,
+            metrics: Arc::default(),
+            handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+            slow_consumer: SlowConsumerConfig::default(),
+            max_payload_size: None,
+            max_packet_size: None,
+            read_batch_size: DEFAULT_READ_BATCH_SIZE,
+            topic_stats: None,
+            queue_qos0_messages: false,
+            will_on_kick: true,
+            #[cfg(feature = "v4")]
+            mqtt31: true,
+            strict_handshake: false,
+            redirection: ArcSwapOption::empty(),
+        }
+    }
+
+    /// Limits and overflow policy of the per-session deliver queues
+    pub fn with_queue_config(mut self, queue_config: QueueConfig) -> Self {
+        self.queue_config = queue_config;
+        self
+    }
+
+    /// Partitioning of publishes matched by many subscribers
+    pub fn with_fan_out_config(mut self, fan_out_config: FanOutConfig) -> Self {
+        self.fan_out_config = fan_out_config;
+        self
+    }
+
+    pub fn with_channel_config(mut self, channel_config: ChannelConfig) -> Self {
+        self.channel_config = channel_config;
+        self
+    }
+
+    pub(crate) fn channel_config(&self) -> ChannelConfig {
+        self.channel_config
+    }
+
+    /// When the messages delivered to a client are flushed to its connection, every packet is
+    /// flushed by default
+    pub fn with_flush_policy(mut self, flush_policy: FlushPolicy) -> Self {
+        self.flush_policy = flush_policy;
+        self
+    }
+
+    pub(crate) fn flush_policy(&self) -> FlushPolicy {
+        self.flush_policy
+    }
+
+    /// Maximum number of unacknowledged QoS 1/2 publishes to an MQTT 3.1.1 client, MQTT 5
+    /// clients use their Receive Maximum instead
+    #[cfg(feature = "v4")]
+    pub fn with_max_inflight(mut self, max_inflight: usize) -> Self {
+        self.max_inflight = max_inflight;
+        self
+    }
+
+    #[cfg(feature = "v4")]
+    pub(crate) fn max_inflight(&self) -> usize {
+        self.max_inflight
+    }
+
+    /// Receive Maximum told to MQTT 5 clients in CONNACK, a client with more unacknowledged
+    /// QoS 1/2 publishes is disconnected with Receive Maximum exceeded; MQTT 5 does not allow 0,
+    /// it is raised to 1
+    pub fn with_receive_maximum(mut self, receive_maximum: u16) -> Self {
+        self.receive_maximum = receive_maximum.max(1);
+        self
+    }
+
+    pub fn receive_maximum(&self) -> u16 {
+        self.receive_maximum
+    }
+
+    /// Sends QoS 1 messages to MQTT 3.1.1 clients without saving them to the store first
+    ///
+    /// A message is kept by its connection until it is acknowledged and only saved once it is
+    /// older than [`Qos1FastPath::spill_after`], when the connection closes or when a new
+    /// connection takes over the session, so most messages never reach the store. Messages
+    /// kept unsaved are lost if the broker stops, and are saved after the messages saved while
+    /// they were kept. QoS 2 messages are always saved first.
+    #[cfg(feature = "v4")]
+    pub fn with_qos1_fast_path(mut self, fast_path: Qos1FastPath) -> Self {
+        self.qos1_fast_path = Some(fast_path);
+        self
+    }
+
+    #[cfg(feature = "v4")]
+    pub(crate) fn qos1_fast_path(&self) -> Option<Qos1FastPath> {
+        self.qos1_fast_path
+    }
+
+    /// Keeps QoS 0 messages for offline persistent sessions as well, like QoS 1/2 messages and
+    /// up to the same message store limit, instead of dropping them
+    pub fn with_queue_qos0_messages(mut self, queue_qos0_messages: bool) -> Self {
+        self.queue_qos0_messages = queue_qos0_messages;
+        self
+    }
+
+    pub(crate) fn queue_qos0_messages(&self) -> bool {
+        self.queue_qos0_messages
+    }
+
+    /// Time a connection has for its TLS or WebSocket handshake and again for its CONNECT
+    /// packet, connections which stay silent longer are closed
+    pub fn with_handshake_timeout(mut self, handshake_timeout: Duration) -> Self {
+        self.handshake_timeout = handshake_timeout;
+        self
+    }
+
+    pub(crate) fn handshake_timeout(&self) -> Duration {
+        self.handshake_timeout
+    }
+
+    /// Refuses clients which send other packets than AUTH before their CONNACK, off by default
+    ///
+    /// MQTT lets clients send packets right after CONNECT without waiting for the CONNACK, so
+    /// this only suits brokers whose clients are known to wait. Only packets received by the
+    /// time the CONNECT is accepted are seen. MQTT 5 clients get a CONNACK with Protocol Error,
+    /// MQTT 3.1.1 connections are closed.
+    pub fn with_strict_handshake(mut self, strict_handshake: bool) -> Self {
+        self.strict_handshake = strict_handshake;
+        self
+    }
+
+    pub(crate) fn strict_handshake(&self) -> bool {
+        self.strict_handshake
+    }
+
+    /// Limits which disconnect clients that do not keep up with their messages, see
+    /// [`super::slow_consumer`]
+    pub fn with_slow_consumer(mut self, slow_consumer: SlowConsumerConfig) -> Self {
+        self.slow_consumer = slow_consumer;
+        self
+    }
+
+    pub(crate) fn slow_consumer(&self) -> &SlowConsumerConfig {
+        &self.slow_consumer
+    }
+
+    /// Largest payload a client may publish, unlimited by default
+    ///
+    /// Applies on top of the maximum packet size of the codec. Larger publishes are dropped,
+    /// MQTT 5 clients are told so with Implementation Specific Error in the PUBACK or PUBREC and
+    /// with a DISCONNECT with Packet Too Large for QoS 0.
+    pub fn with_max_payload_size(mut self, max_payload_size: usize) -> Self {
+        self.max_payload_size = Some(max_payload_size);
+        self
+    }
+
+    pub(crate) fn max_payload_size(&self) -> Option<usize> {
+        self.max_payload_size
+    }
+
+    /// Largest packet a client may send, fixed header included, unlimited by default
+    ///
+    /// The codec refuses larger packets from their fixed header, before their body is buffered,
+    /// and the connection is closed, MQTT 5 clients get a DISCONNECT with Packet Too Large.
+    /// MQTT 5 clients are told the limit in the CONNACK.
+    pub fn with_max_packet_size(mut self, max_packet_size: u32) -> Self {
+        self.max_packet_size = Some(max_packet_size);
+        self
+    }
+
+    #[cfg(feature = "v5")]
+    pub(crate) fn max_packet_size(&self) -> Option<u32> {
+        self.max_packet_size
+    }
+
+    /// The limit of the codec for [`GlobalState::with_max_packet_size`]
+    ///
+    /// A fixed header takes 2 to 5 bytes, the codec only sees the remaining length so a packet
+    /// with a long fixed header may exceed the limit by up to 3 bytes.
+    pub(crate) fn max_remaining_length(&self) -> Option<u32> {
+        self.max_packet_size.map(|max| max.saturating_sub(2))
+    }
+
+    /// Packets of one client handled in a row before its task yields to the other connections,
+    /// [`DEFAULT_READ_BATCH_SIZE`] by default
+    ///
+    /// The codec decodes every packet already buffered, so a client keeping its buffer full
+    /// would hold the worker otherwise.
+    pub fn with_read_batch_size(mut self, read_batch_size: usize) -> Self {
+        self.read_batch_size = read_batch_size.max(1);
+        self
+    }
+
+    pub(crate) fn read_batch_size(&self) -> usize {
+        self.read_batch_size
+    }
+
+    /// Whether the will of a client kicked with [`GlobalState::kick_client`] is published, as
+    /// on any other connection loss, true by default
+    pub fn with_will_on_kick(mut self, will_on_kick: bool) -> Self {
+        self.will_on_kick = will_on_kick;
+        self
+    }
+
+    pub(crate) fn will_on_kick(&self) -> bool {
+        self.will_on_kick
+    }
+
+    /// Whether MQTT 3.1 clients, which connect with the protocol name `MQIsdp` and level 3, are
+    /// accepted on the MQTT 3.1.1 listeners, true by default
+    #[cfg(feature = "v4")]
+    pub fn with_mqtt31(mut self, mqtt31: bool) -> Self {
+        self.mqtt31 = mqtt31;
+        self
+    }
+
+    #[cfg(feature = "v4")]
+    pub(crate) fn mqtt31(&self) -> bool {
+        self.mqtt31
+    }
+
+    /// Counts publishes, payload bytes and matched subscriptions of the `max_topics` busiest
+    /// topics, see [`super::topic_stats`]
+    pub fn with_topic_stats(mut self, max_topics: usize) -> Self {
+        self.topic_stats = Some(TopicStats::new(max_topics));
+        self
+    }
+
+    /// Statistics of the busiest topics, `None` unless enabled with
+    /// [`GlobalState::with_topic_stats`]
+    pub fn topic_stats(&self) -> Option<&TopicStats> {
+        self.topic_stats.as_ref()
+    }
+
+    /// Malformed packets a connection may send before it is closed, the ones before are skipped
+    ///
+    /// 1 by default, which closes the connection on the first one as MQTT requires. Packets
+    /// whose fixed header can not be read always close the connection.
+    pub fn with_max_decode_errors(mut self, max_decode_errors: usize) -> Self {
+        self.max_decode_errors = max_decode_errors.max(1);
+        self
+    }
+
+    pub(crate) fn max_decode_errors(&self) -> usize {
+        self.max_decode_errors
+    }
+
+    pub fn metrics(&self) -> &Arc<Metrics> {
+        &self.metrics
+    }
+
+    /// Highest QoS of the publishes and wills forwarded to subscribers, higher ones are
+    /// downgraded; MQTT 5 clients are told in CONNACK
+    pub fn with_max_qos(mut self, max_qos: QualityOfService) -> Self {
+        self.max_qos = max_qos;
+        self
+    }
+
+    pub fn max_qos(&self) -> QualityOfService {
+        self.max_qos
+    }
+
+    /// What to do when a client connects with the client identifier of a connected client
+    pub fn with_duplicate_client_id(mut self, policy: DuplicateClientIdPolicy) -> Self {
+        self.duplicate_client_id = policy;
+        self
+    }
+
+    /// How MQTT 5 connections treat Clean Start, the MQTT 5 semantics by default
+    pub fn with_clean_start(mut self, semantics: CleanStartSemantics) -> Self {
+        self.clean_start = semantics;
+        self
+    }
+
+    pub fn clean_start(&self) -> CleanStartSemantics {
+        self.clean_start
+    }
+
+    /// Rules for the topic filters sent in SUBSCRIBE
+    pub fn with_topic_filter_config(mut self, topic_filter_config: TopicFilterConfig) -> Self {
+        self.topic_filter_config = topic_filter_config;
+        self
+    }
+
+    pub(crate) fn topic_filter_config(&self) -> &TopicFilterConfig {
+        &self.topic_filter_config
+    }
+
+    /// Rules for the client identifiers sent in CONNECT
+    pub fn with_client_id_config(self, client_id_config: ClientIdConfig) -> Self {
+        self.set_client_id_config(client_id_config);
+        self
+    }
+
+    pub(crate) fn set_client_id_config(&self, client_id_config: ClientIdConfig) {
+        self.client_id_config.store(Arc::new(client_id_config));
+    }
+
+    pub(crate) fn client_id_config(&self) -> Arc<ClientIdConfig> {
+        self.client_id_config.load_full()
+    }
+
+    /// Persists non-clean sessions so they survive broker restarts
+    pub fn with_session_store<T: SessionStore + 'static>(mut self, session_store: T) -> Self {
+        self.session_store = Some(Arc::new(session_store));
+        self
+    }
+
+    /// Runs `f` on the session store on the blocking thread pool, a store such as RocksDB
+    /// blocks on disk, `None` without a session store
+    async fn with_session_store_blocking<T, F>(&self, f: F) -> Option<Result<T, StoreError>>
+    where
+        T: Send + 'static,
+        F: FnOnce(&dyn SessionStore) -> Result<T, StoreError> + Send + 'static,
+    {
+        let store = self.session_store.clone()?;
+        let ret = task::spawn_blocking(move || f(store.as_ref()))
+            .await
+            .unwrap_or_else(|err| Err(StoreError::backend(err)));
+        Some(ret)
+    }
+
+    pub(crate) async fn save_session(&self, session: StoredSession) {
+        let client_id = session.client_id.clone();
+        let saved = self
+            .with_session_store_blocking(move |store| store.save_session(&session))
+            .await;
+        if let Some(Err(err)) = saved {
+            warn!("save session#{client_id} failed: {err}");
+        }
+    }
+
+    /// Loads a persisted session, expired sessions are removed and not returned
+    pub(crate) async fn load_session(&self, client_id: &str) -> Option<StoredSession> {
+        let id = client_id.to_owned();
+        let loaded = self
+            .with_session_store_blocking(move |store| store.load_session(&id))
+            .await?;
+        match loaded {
+            Ok(Some(session)) if session.is_expired() => {
+                self.remove_session(client_id).await;
+                None
+            }
+            Ok(session) => session,
+            Err(err) => {
+                warn!("load session#{client_id} failed: {err}");
+                None
+            }
+        }
+    }
+
+    pub(crate) async fn remove_session(&self, client_id: &str) {
+        let id = client_id.to_owned();
+        let removed = self
+            .with_session_store_blocking(move |store| store.remove_session(&id))
+            .await;
+        if let Some(Err(err)) = removed {
+            warn!("remove session#{client_id} failed: {err}");
+        }
+    }
+
+    async fn load_presence(&self, client_id: &str) -> Option<StoredPresence> {
+        let id = client_id.to_owned();
+        self.with_session_store_blocking(move |store| store.load_presence(&id))
+            .await?
+            .unwrap_or_else(|err| {
+                warn!("load presence#{client_id} failed: {err}");
+                None
+            })
+    }
+
+    /// Saves the time of a connect or a disconnect and the IP address of the client
+    async fn record_presence(&self, remote_addr: Option<SocketAddr>, event: AuditEvent<'_>) {
+        let (client_id, connected) = match event {
+            AuditEvent::Connect { client_id, .. } => (client_id, true),
+            AuditEvent::Disconnect { client_id, .. } => (client_id, false),
+            _ => return,
+        };
+        let id = client_id.to_owned();
+        let now = Some(now_millis() as u64);
+        let saved = self
+            .with_session_store_blocking(move |store| {
+                let mut presence = store.load_presence(&id)?.unwrap_or_else(|| StoredPresence {
+                    client_id: id,
+                    ..Default::default()
+                });
+                if connected {
+                    presence.connected_at = now;
+                } else {
+                    presence.disconnected_at = now;
+                }
+                if let Some(addr) = remote_addr {
+                    presence.ip_address = Some(addr.ip().to_string());
+                }
+                store.save_presence(&presence)
+            })
+            .await;
+        if let Some(Err(err)) = saved {
+            warn!("save presence#{client_id} failed: {err}");
+        }
+    }
+
+    /// Whether the client is connected, when it last connected and disconnected and from which
+    /// IP address, `None` for a client the broker does not know
+    ///
+    /// With a session store set by [`GlobalState::with_session_store`] the times are kept there
+    /// for every client, also after a clean session ends and across restarts. Without one only
+    /// a client the broker holds a session for is known, with the time of its last connect.
+    pub async fn client_status(&self, client_id: &str) -> Option<ClientStatus> {
+        let presence = self.load_presence(client_id).await;
+        let handle = self
+            .clients
+            .get(client_id)
+            .map(|handle| (handle.connected, handle.info.clone()));
+        let online = handle.as_ref().is_some_and(|(connected, _)| *connected);
+        let at = |millis: u64| UNIX_EPOCH + Duration::from_millis(millis);
+        match (presence, handle) {
+            (Some(presence), _) => Some(ClientStatus {
+                online,
+                connected_at: presence.connected_at.map(at),
+                disconnected_at: presence.disconnected_at.map(at),
+                ip_address: presence.ip_address.and_then(|ip| ip.parse().ok()),
+            }),
+            (None, Some((_, info))) => Some(ClientStatus {
+                online,
+                connected_at: Some(info.connected_at()),
+                disconnected_at: None,
+                ip_address: info.remote_addr().map(|addr| addr.ip()),
+            }),
+            (None, None) => None,
+        }
+    }
+
+    /// Appends `interceptor` to the interceptors every publish goes through
+    pub fn with_interceptor<T: MessageInterceptor + 'static>(mut self, interceptor: T) -> Self {
+        self.interceptors.push(Box::new(interceptor));
+        self
+    }
+
+    /// Runs the interceptors on a publish, stops at the first one rejecting it
+    pub(crate) async fn intercept_publish(
+        &self,
+        client_id: &str,
+        message: &mut PublishMessage,
+    ) -> InterceptAction {
+        for interceptor in &self.interceptors {
+            if interceptor.on_publish(client_id, message).await == InterceptAction::Reject {
+                debug!(
+                    "client#{client_id} publish to {} rejected",
+                    message.topic_name()
+                );
+                return InterceptAction::Reject;
+            }
+        }
+        InterceptAction::Continue
+    }
+
+    /// Republishes undeliverable messages to `topic`, see [`dead_letter`]
+    pub fn with_dead_letter_topic(mut self, topic: TopicName) -> Self {
+        self.dead_letter_topic = Some(topic);
+        self
+    }
+
+    /// Topic prefixes clients may not publish to, `$SYS/` and `$share/` by default
+    ///
+    /// Publishes and wills to a reserved topic are refused, which keeps clients from injecting
+    /// messages into the topics the broker publishes itself.
+    pub fn with_reserved_topic_prefixes<P: Into<String>>(
+        self,
+        prefixes: impl IntoIterator<Item = P>,
+    ) -> Self {
+        self.set_reserved_topic_prefixes(prefixes.into_iter().map(Into::into).collect());
+        self
+    }
+
+    pub(crate) fn set_reserved_topic_prefixes(&self, prefixes: Vec<String>) {
+        self.reserved_topic_prefixes.store(Arc::new(prefixes));
+    }
+
+    pub(crate) fn is_reserved_topic(&self, topic_name: &str) -> bool {
+        self.reserved_topic_prefixes
+            .load()
+            .iter()
+            .any(|prefix| topic_name.starts_with(prefix.as_str()))
+    }
+
+    /// Writes connection events to `audit_log`, see [`super::audit`]
+    pub fn with_audit_log(mut self, audit_log: AuditLog) -> Self {
+        self.audit_log = Some(audit_log);
+        self
+    }
+
+    pub(crate) fn audit(&self, remote_addr: Option<SocketAddr>, event: AuditEvent<'_>) {
+        if let Some(audit_log) = &self.audit_log {
+            audit_log.record(remote_addr, event);
+        }
+    }
+
+    /// Publishes an event whenever a client connects or its connection ends, see
+    /// [`super::presence`]
+    pub fn with_presence_events(mut self, config: PresenceConfig) -> Self {
+        self.presence = Some(config);
+        self
+    }
+
+    /// Enables MQTT 5 request/response support
+    ///
+    /// Clients which set Request Response Information get `{prefix}/{client_id}` as Response
+    /// Information in CONNACK. Only the owning client may subscribe below its response topic,
+    /// while any client may publish responses to it, both as far as the HTTP and script ACLs
+    /// allow. A filter which could match the response topic of another client, e.g. `#` or
+    /// `{prefix}/+`, is refused. A client identifier with a `/` spans several topic levels, such
+    /// a client gets no response topic.
+    pub fn with_response_topic_prefix<P: Into<String>>(mut self, prefix: P) -> Self {
+        self.response_topic_prefix = Some(prefix.into().trim_end_matches('/').to_owned());
+        self
+    }
+
+    #[cfg(feature = "v5")]
+    pub(crate) fn response_information(&self, client_id: &str) -> Option<String> {
+        if !is_topic_level(client_id) {
+            return None;
+        }
+        self.response_topic_prefix
+            .as_ref()
+            .map(|prefix| format!("{prefix}/{client_id}"))
+    }
+
+    /// Whether `client_id` may subscribe to `topic_filter` as far as the response topics are
+    /// concerned, `None` when the filter matches none of them
+    fn response_topic_access(&self, client_id: &str, topic_filter: &TopicFilter) -> Option<bool> {
+        let prefix = self.response_topic_prefix.as_deref()?;
+        let filter = match topic_filter.shared_info() {
+            Some((_, filter)) => filter,
+            None => &topic_filter[..],
+        };
+        let mut levels = filter.split('/');
+        for (index, prefix_level) in prefix.split('/').enumerate() {
+            match levels.next()? {
+                // a filter starting with a wildcard matches no topic starting with `$`
+                MATCH_ALL_STR | MATCH_ONE_STR if index == 0 && prefix_level.starts_with('$') => {
+                    return None
+                }
+                MATCH_ALL_STR => return Some(false),
+                MATCH_ONE_STR => {}
+                level if level == prefix_level => {}
+                _ => return None,
+            }
+        }
+        // the level after the prefix is the client the response topic belongs to
+        let owner = levels.next()?;
+        Some(is_topic_level(client_id) && owner == client_id)
+    }
+
+    #[cfg(feature = "script")]
+    pub fn with_script(self, script: ScriptHook) -> Self {
+        self.set_script(script);
+        self
+    }
+
+    #[cfg(feature = "script")]
+    pub(crate) fn set_script(&self, script: ScriptHook) {
+        self.script.store(Some(Arc::new(script)));
+    }
+
+    /// Asks `http_auth` about connects, subscribes and publishes before the script
+    #[cfg(feature = "http-auth")]
+    pub fn with_http_auth(mut self, http_auth: HttpAuth) -> Self {
+        self.http_auth = Some(http_auth);
+        self
+    }
+
+    /// Registers an MQTT 5 enhanced authentication method under the name it reports
+    #[cfg(feature = "v5")]
+    pub fn with_enhanced_auth(mut self, auth: impl EnhancedAuth + 'static) -> Self {
+        self.enhanced_auth
+            .insert(auth.method().to_owned(), Box::new(auth));
+        self
+    }
+
+    #[cfg(feature = "v5")]
+    pub(crate) fn enhanced_auth(&self, method: &str) -> Option<&dyn EnhancedAuth> {
+        self.enhanced_auth.get(method).map(|auth| auth.as_ref())
+    }
+
+    pub(crate) async fn authorize_connect(
+        &self,
+        client_id: &str,
+        username: Option<&str>,
+        password: Option<&str>,
+    ) -> ConnectVerdict {
+        #[cfg(feature = "http-auth")]
+        if let Some(http_auth) = &self.http_auth {
+            match http_auth.on_connect(client_id, username, password).await {
+                Ok(Decision::Allow) => return ConnectVerdict::Accepted,
+                Ok(Decision::Deny) => return ConnectVerdict::NotAuthorized,
+                Ok(Decision::BadCredentials) => return ConnectVerdict::BadCredentials,
+                Ok(Decision::Ignore) => {}
+                Err(err) => {
+                    warn!("http auth of connect failed: {err}");
+                    if http_auth.fail_policy() == FailPolicy::Closed {
+                        return ConnectVerdict::Unavailable;
+                    }
+                }
+            }
+        }
+        self.script_connect(client_id, username, password).await
+    }
+
+    #[cfg(feature = "script")]
+    async fn script_connect(
+        &self,
+        client_id: &str,
+        username: Option<&str>,
+        password: Option<&str>,
+    ) -> ConnectVerdict {
+        let Some(script) = self.script.load_full() else {
+            return ConnectVerdict::Accepted;
+        };
+        let (client_id, username, password) = (
+            client_id.to_owned(),
+            username.map(|username| username.to_owned()),
+            password.map(|password| password.to_owned()),
+        );
+        let decision = script_blocking(script, move |script| {
+            script.on_connect(&client_id, username.as_deref(), password.as_deref())
+        })
+        .await;
+        match decision {
+            Ok(ConnectDecision::Allow) => ConnectVerdict::Accepted,
+            Ok(ConnectDecision::Deny) => ConnectVerdict::NotAuthorized,
+            Ok(ConnectDecision::BadCredentials) => ConnectVerdict::BadCredentials,
+            Err(err) => {
+                warn!("script on_connect failed: {err}");
+                ConnectVerdict::Unavailable
+            }
+        }
+    }
+
+    #[cfg(not(feature = "script"))]
+    #[inline]
+    async fn script_connect(
+        &self,
+        _client_id: &str,
+        _username: Option<&str>,
+        _password: Option<&str>,
+    ) -> ConnectVerdict {
+        ConnectVerdict::Accepted
+    }
+
+    pub(crate) async fn allow_subscribe(
+        &self,
+        client_id: &str,
+        topic_filter: &TopicFilter,
+        qos: QualityOfService,
+    ) -> bool {
+        // the owner of a response topic still goes through the ACLs
+        if self.response_topic_access(client_id, topic_filter) == Some(false) {
+            return false;
+        }
+        #[cfg(feature = "http-auth")]
+        if let Some(http_auth) = &self.http_auth {
+            let decision = http_auth.on_subscribe(client_id, topic_filter, qos).await;
+            if let Some(allowed) = http_allowed(http_auth, "subscribe", decision) {
+                return allowed;
+            }
+        }
+        self.script_subscribe(client_id, topic_filter, qos).await
+    }
+
+    #[cfg(feature = "script")]
+    async fn script_subscribe(
+        &self,
+        client_id: &str,
+        topic_filter: &TopicFilter,
+        qos: QualityOfService,
+    ) -> bool {
+        let Some(script) = self.script.load_full() else {
+            return true;
+        };
+        let (client_id, topic_filter) = (client_id.to_owned(), topic_filter.clone());
+        script_blocking(script, move |script| {
+            script.on_subscribe(&client_id, &topic_filter, qos)
+        })
+        .await
+        .unwrap_or_else(|err| {
+            warn!("script on_subscribe failed: {err}");
+            false
+        })
+    }
+
+    #[cfg(not(feature = "script"))]
+    #[inline]
+    async fn script_subscribe(
+        &self,
+        _client_id: &str,
+        _topic_filter: &TopicFilter,
+        _qos: QualityOfService,
+    ) -> bool {
+        true
+    }
+
+    async fn allow_publish(&self, client_id: &str, message: &mut PublishMessage) -> bool {
+        #[cfg(feature = "http-auth")]
+        if let Some(http_auth) = &self.http_auth {
+            let decision = http_auth.on_publish(client_id, message).await;
+            if let Some(allowed) = http_allowed(http_auth, "publish", decision) {
+                return allowed;
+            }
+        }
+        self.script_publish(client_id, message).await
+    }
+
+    #[cfg(feature = "script")]
+    async fn script_publish(&self, client_id: &str, message: &mut PublishMessage) -> bool {
+        let Some(script) = self.script.load_full() else {
+            return true;
+        };
+        // the hook may rewrite the message, the rewritten one replaces it
+        let (client_id, mut rewritten) = (client_id.to_owned(), message.clone());
+        let ret = script_blocking(script, move |script| {
+            let allowed = script.on_publish(&client_id, &mut rewritten)?;
+            Ok((allowed, rewritten))
+        })
+        .await;
+        match ret {
+            Ok((allowed, rewritten)) => {
+                *message = rewritten;
+                allowed
+            }
+            Err(err) => {
+                warn!("script on_publish failed: {err}");
+                false
+            }
         }
     }
 
+    #[cfg(not(feature = "script"))]
+    #[inline]
+    async fn script_publish(&self, _client_id: &str, _message: &mut PublishMessage) -> bool {
+        true
+    }
+
+    /// Adds a connection, `token` identifies the connection in [`GlobalState::set_client_offline`]
+    /// and is also its timer token
     pub async fn add_client(
         &self,
         client_id: &str,
+        token: u64,
         new_sender: AsyncSender<DeliverMessage>,
+        info: Arc<ClientInfo>,
     ) -> AddClientReceipt {
-        if let Some(old_sender) = self.get_deliver(client_id) {
+        let old_sender = match self.duplicate_client_id {
+            DuplicateClientIdPolicy::TakeOver => self.get_deliver(client_id),
+            // checked and claimed under the lock of the entry, of two connections with the same
+            // client identifier only one is added with it
+            policy => match self.clients.entry(client_id.to_owned()) {
+                Entry::Occupied(entry) if entry.get().is_connected() => {
+                    drop(entry);
+                    if policy == DuplicateClientIdPolicy::Reject {
+                        debug!("client#{client_id} is connected, reject the new connection");
+                        return AddClientReceipt::Rejected;
+                    }
+                    return AddClientReceipt::Renamed(
+                        self.insert_suffixed_client(client_id, token, new_sender, info),
+                    );
+                }
+                Entry::Occupied(mut entry) => {
+                    let handle = entry.get_mut();
+                    handle.token = token;
+                    handle.connected = true;
+                    handle.info = info.clone();
+                    Some(mem::replace(&mut handle.sender, new_sender.clone()))
+                }
+                Entry::Vacant(entry) => {
+                    entry.insert(self.new_client_handle(token, new_sender, info));
+                    return AddClientReceipt::New;
+                }
+            },
+        };
+
+        if let Some(old_sender) = old_sender {
             if !old_sender.is_closed() {
                 // TODO: config: build session state timeout
                 let receive_timeout = Duration::from_secs(10);
                 let (control_sender, control_receiver) = bounded_async(1);
-                let ret = old_sender
-                    .send(DeliverMessage::Online(control_sender))
+                let ret = self
+                    .send_control(&old_sender, DeliverMessage::Online(control_sender))
                     .await;
                 match ret {
                     Ok(_) => match time::timeout(receive_timeout, control_receiver.recv()).await {
                         Ok(data) => match data {
                             Ok(state) => {
-                                self.clients.insert(client_id.to_owned(), new_sender);
+                                self.insert_client(client_id, token, new_sender, info);
                                 return AddClientReceipt::Present(state);
                             }
                             Err(err) => {
@@ -102,15 +1175,855 @@ impl<S> GlobalState<S> {
             }
         }
 
-        self.clients.insert(client_id.to_owned(), new_sender);
+        self.insert_client(client_id, token, new_sender, info);
         AddClientReceipt::New
     }
 
+    /// The deliver queue is kept when a client reconnects, so messages queued for the old
+    /// connection are delivered to the new one
+    fn insert_client(
+        &self,
+        client_id: &str,
+        token: u64,
+        sender: AsyncSender<DeliverMessage>,
+        info: Arc<ClientInfo>,
+    ) {
+        self.clients
+            .entry(client_id.to_owned())
+            .and_modify(|handle| {
+                handle.sender = sender.clone();
+                handle.token = token;
+                handle.connected = true;
+                handle.info = info.clone();
+            })
+            .or_insert_with(|| self.new_client_handle(token, sender, info));
+    }
+
+    fn insert_suffixed_client(
+        &self,
+        client_id: &str,
+        token: u64,
+        sender: AsyncSender<DeliverMessage>,
+        info: Arc<ClientInfo>,
+    ) -> String {
+        loop {
+            let suffixed = format!("{client_id}-{}", nanoid!(8));
+            if let Entry::Vacant(entry) = self.clients.entry(suffixed.clone()) {
+                debug!("client#{client_id} is connected, add the new connection as {suffixed}");
+                entry.insert(self.new_client_handle(token, sender, info));
+                return suffixed;
+            }
+        }
+    }
+
+    fn new_client_handle(
+        &self,
+        token: u64,
+        sender: AsyncSender<DeliverMessage>,
+        info: Arc<ClientInfo>,
+    ) -> ClientHandle {
+        ClientHandle {
+            sender,
+            queue: Arc::new(DeliverQueue::new(self.queue_config)),
+            token,
+            connected: true,
+            info,
+        }
+    }
+
+    pub fn is_client_connected(&self, client_id: &str) -> bool {
+        self.clients
+            .get(client_id)
+            .is_some_and(|handle| handle.is_connected())
+    }
+
+    /// Marks the session as kept without a connection, unless another connection took it over
+    pub(crate) fn set_client_offline(&self, client_id: &str, token: u64) {
+        if let Some(mut handle) = self.clients.get_mut(client_id) {
+            if handle.token == token {
+                handle.connected = false;
+            }
+        }
+    }
+
+    /// Releases a client whose connection task panicked, unless another connection took it over
+    ///
+    /// A clean session is removed, a persistent one is kept offline for the client to resume.
+    /// Returns whether the client was released.
+    pub(crate) fn release_client(&self, client_id: &str, token: u64, clean_session: bool) -> bool {
+        if clean_session {
+            return self
+                .clients
+                .remove_if(client_id, |_, handle| handle.token == token)
+                .is_some();
+        }
+        match self.clients.get_mut(client_id) {
+            Some(mut handle) if handle.token == token => {
+                handle.connected = false;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Connection details of the client, `None` once its session is removed
+    pub fn client_info(&self, client_id: &str) -> Option<Arc<ClientInfo>> {
+        self.clients.get(client_id).map(|s| s.info.clone())
+    }
+
+    /// The will of the client, see [`ClientInfo::will`]
+    pub fn client_will(&self, client_id: &str) -> Option<Arc<StoredWill>> {
+        self.client_info(client_id)?.will()
+    }
+
+    /// Connection details of every client with a session, connected or not
+    pub fn clients_info(&self) -> Vec<(String, Arc<ClientInfo>)> {
+        self.clients
+            .iter()
+            .map(|entry| (entry.key().clone(), entry.info.clone()))
+            .collect()
+    }
+
+    /// Members, turns and delivery counters of the shared subscription groups, see
+    /// [`super::shared`]
+    pub fn shared_groups(&self) -> Vec<SharedGroup> {
+        self.shared_groups.view(&self.clients)
+    }
+
     pub fn remove_client(&self, client_id: &str) {
         self.clients.remove(client_id);
     }
 
+    /// Disconnects the client, MQTT 5 clients get a DISCONNECT with Administrative Action and
+    /// `reason`
+    ///
+    /// A clean session is removed with the connection, a persistent one is kept like after any
+    /// other disconnect, until it expires or the client resumes it.
+    ///
+    /// Returns `false` if the client has no session or its connection is already gone.
+    pub async fn kick_client<R: Into<String>>(&self, client_id: &str, reason: R) -> bool {
+        let Some(sender) = self.get_deliver(client_id) else {
+            return false;
+        };
+        let kick = DeliverMessage::Kick(KickReason::FromAdmin(reason.into()));
+        self.send_control(&sender, kick).await.is_ok()
+    }
+
+    /// Sends every connected client to the server of `redirection`, returns how many were told
+    ///
+    /// Connected clients are kicked, MQTT 5 clients with a DISCONNECT with Use Another Server
+    /// or Server Moved and the server reference. Until [`GlobalState::stop_draining`], new
+    /// connections are refused: MQTT 5 clients get the same redirection in the CONNACK, MQTT
+    /// 3.1.1 clients Server Unavailable.
+    pub async fn drain(&self, redirection: Redirection) -> usize {
+        let redirection = Arc::new(redirection);
+        self.redirection.store(Some(redirection.clone()));
+        let senders: Vec<_> = self
+            .clients
+            .iter()
+            .filter(|handle| handle.connected)
+            .map(|handle| handle.sender.clone())
+            .collect();
+
+        let mut redirected = 0;
+        for sender in senders {
+            let kick = DeliverMessage::Kick(KickReason::Redirected(redirection.clone()));
+            if self.send_control(&sender, kick).await.is_ok() {
+                redirected += 1;
+            }
+        }
+        redirected
+    }
+
+    /// Accepts new connections again after [`GlobalState::drain`]
+    pub fn stop_draining(&self) {
+        self.redirection.store(None);
+    }
+
+    /// The redirection of new connections while the broker is drained
+    pub(crate) fn redirection(&self) -> Option<Arc<Redirection>> {
+        self.redirection.load_full()
+    }
+
+    async fn send_control(
+        &self,
+        sender: &AsyncSender<DeliverMessage>,
+        message: DeliverMessage,
+    ) -> Result<(), SendError> {
+        if sender.is_full() {
+            self.metrics.record_deliver_channel_full();
+        }
+        sender.send(message).await
+    }
+
     pub fn get_deliver(&self, client_id: &str) -> Option<AsyncSender<DeliverMessage>> {
-        self.clients.get(client_id).map(|s| s.value().clone())
+        self.clients.get(client_id).map(|s| s.sender.clone())
+    }
+
+    pub fn deliver_queue(&self, client_id: &str) -> Option<Arc<DeliverQueue>> {
+        self.clients.get(client_id).map(|s| s.queue.clone())
+    }
+
+    #[cfg(test)]
+    pub(crate) fn clients(&self) -> &Arc<Clients> {
+        &self.clients
+    }
+
+    pub(crate) fn next_timer_token(&self) -> u64 {
+        self.timers.next_token()
+    }
+
+    /// Delivers `DeliverMessage::Timeout(kind, token)` to the client after `after`
+    pub(crate) fn schedule_timer(
+        &self,
+        client_id: &str,
+        token: u64,
+        kind: TimerKind,
+        after: Duration,
+    ) {
+        self.timers
+            .schedule(&self.clients, client_id, token, kind, after);
+    }
+}
+
+impl<S> GlobalState<S>
+where
+    S: TopicStore,
+{
+    /// Deliveries to the subscribers of the matched topic filters, one member of every matched
+    /// shared subscription group takes its turn
+    pub(crate) fn deliveries(&self, topics: Vec<TopicContent>) -> Vec<Delivery> {
+        fanout::deliveries(topics, &self.shared_groups, &self.clients)
+    }
+
+    /// Queues `message` for the matched subscribers without waiting on any of them
+    pub(crate) async fn fan_out(&self, deliveries: Vec<Delivery>, message: PublishMessage) {
+        if let Some(stats) = &self.topic_stats {
+            stats.record(
+                message.topic_name(),
+                message.payload().len(),
+                deliveries.len(),
+            );
+        }
+        let dropped =
+            fanout::fan_out(&self.clients, self.fan_out_config, deliveries, message).await;
+        for (client_id, message) in dropped {
+            self.dead_letter(&client_id, &message, DeadLetterReason::QueueFull)
+                .await;
+        }
+    }
+
+    /// Runs a publish or a will through the script and the interceptors, and caps its QoS to
+    /// the one set with [`GlobalState::with_max_qos`]
+    ///
+    /// Messages which are not accepted must be dropped, rejected messages are dead-lettered.
+    pub(crate) async fn authorize_publish(
+        &self,
+        client_id: &str,
+        message: &mut PublishMessage,
+    ) -> PublishVerdict {
+        if !self.allow_publish(client_id, message).await {
+            debug!(
+                "client#{client_id} publish to {} denied",
+                message.topic_name()
+            );
+            return PublishVerdict::NotAuthorized;
+        }
+        if self.intercept_publish(client_id, message).await == InterceptAction::Reject {
+            self.dead_letter(client_id, message, DeadLetterReason::Rejected)
+                .await;
+            return PublishVerdict::Rejected;
+        }
+        if message.qos() > self.max_qos {
+            message.set_qos(self.max_qos);
+        }
+        PublishVerdict::Accepted
+    }
+
+    /// Republishes `message` to the dead-letter topic if one is configured
+    pub(crate) async fn dead_letter(
+        &self,
+        client_id: &str,
+        message: &PublishMessage,
+        reason: DeadLetterReason,
+    ) {
+        let Some(topic) = &self.dead_letter_topic else {
+            return;
+        };
+        // never dead-letter the dead letters
+        if message.topic_name() == topic {
+            return;
+        }
+        debug!(
+            "client#{client_id} message to {} dead-lettered: {}",
+            message.topic_name(),
+            reason.as_str()
+        );
+        let letter = dead_letter::dead_letter(topic, client_id, message, reason);
+        if let Err(err) = self.forward_internal(letter).await {
+            warn!("dead-letter match topic failed: {err}");
+        }
+    }
+
+    /// Queues a message the broker publishes itself for the matched subscribers, it is neither
+    /// dead-lettered nor counted in the topic statistics
+    pub(crate) async fn forward_internal(&self, message: PublishMessage) -> Result<(), StoreError> {
+        let topics = self.storage.match_topic(message.topic_name()).await?;
+        let deliveries = self.deliveries(topics);
+        fanout::fan_out(&self.clients, self.fan_out_config, deliveries, message).await;
+        Ok(())
+    }
+
+    /// Records a connect or a disconnect for [`GlobalState::client_status`] and publishes its
+    /// presence event, if enabled with [`GlobalState::with_presence_events`]
+    pub(crate) async fn presence_changed(
+        &self,
+        remote_addr: Option<SocketAddr>,
+        event: AuditEvent<'_>,
+    ) {
+        self.record_presence(remote_addr, event).await;
+        let Some(config) = &self.presence else {
+            return;
+        };
+        let Some(message) = presence::presence_message(config, remote_addr, event) else {
+            return;
+        };
+        if let Err(err) = self.forward_internal(message).await {
+            warn!("publish presence event of {event:?}: {err}");
+        }
+    }
+
+    /// Restores the subscriptions of persisted sessions, call once at startup before serving
+    ///
+    /// Returns the number of restored sessions.
+    pub async fn restore_sessions(&self) -> Result<usize, StoreError> {
+        let Some(sessions) = self
+            .with_session_store_blocking(|store| store.load_all_sessions())
+            .await
+        else {
+            return Ok(0);
+        };
+
+        let mut restored = 0;
+        for session in sessions? {
+            if session.is_expired() {
+                let client_id = session.client_id.clone();
+                self.with_session_store_blocking(move |store| store.remove_session(&client_id))
+                    .await
+                    .transpose()?;
+                continue;
+            }
+            for (filter, options) in session.subscriptions() {
+                match TopicFilter::new(filter) {
+                    Ok(filter) => {
+                        self.storage
+                            .subscribe(&session.client_id, &filter, options)
+                            .await?;
+                    }
+                    Err(err) => {
+                        warn!("restore session#{}: {err}", session.client_id);
+                    }
+                }
+            }
+            restored += 1;
+        }
+        Ok(restored)
+    }
+
+    /// Drops the subscriptions and the messages of a persisted session which a client does not
+    /// resume, they were restored with [`GlobalState::restore_sessions`] without a connection
+    /// owning them
+    pub(crate) async fn discard_stored_session(&self, client_id: &str)
+    where
+        S: MessageStore,
+    {
+        let Some(stored) = self.load_session(client_id).await else {
+            return;
+        };
+        for (filter, _) in stored.subscriptions() {
+            let Ok(filter) = TopicFilter::new(filter) else {
+                continue;
+            };
+            if let Err(err) = self.storage.unsubscribe(client_id, &filter).await {
+                debug!("discard session#{client_id} unsubscribe failed: {err}");
+            }
+        }
+        if let Err(err) = self.storage.clear_all(client_id).await {
+            debug!("discard session#{client_id} clear messages failed: {err}");
+        }
+    }
+
+    /// Removes the persisted sessions past their expiry whose client is not connected, with
+    /// their subscriptions and pending messages
+    ///
+    /// A session normally expires on a timer of its connection task, this catches the ones
+    /// without a task, such as sessions restored after a restart whose client never came back.
+    /// Returns the number of removed sessions.
+    pub async fn remove_expired_sessions(&self) -> Result<usize, StoreError>
+    where
+        S: MessageStore,
+    {
+        let Some(sessions) = self
+            .with_session_store_blocking(|store| store.load_all_sessions())
+            .await
+        else {
+            return Ok(0);
+        };
+
+        let mut removed = 0;
+        for session in sessions? {
+            if !session.is_expired() || self.is_client_connected(&session.client_id) {
+                continue;
+            }
+            debug!("session#{} expired, removing it", session.client_id);
+            for (filter, _) in session.subscriptions() {
+                if let Ok(filter) = TopicFilter::new(filter) {
+                    self.storage
+                        .unsubscribe(&session.client_id, &filter)
+                        .await?;
+                }
+            }
+            self.storage.clear_all(&session.client_id).await?;
+            let client_id = session.client_id.clone();
+            self.with_session_store_blocking(move |store| store.remove_session(&client_id))
+                .await
+                .transpose()?;
+            self.clients.remove_if(&session.client_id, |_, handle| {
+                !handle.connected || handle.sender.is_closed()
+            });
+            removed += 1;
+        }
+        Ok(removed)
+    }
+
+    /// The session state of a client: its persisted session and the messages kept for it
+    ///
+    /// The state of a connected client keeps changing, the export is a snapshot of it.
+    #[cfg(feature = "session-export")]
+    pub async fn export_session(&self, client_id: &str) -> Result<SessionExport, StoreError>
+    where
+        S: MessageStore,
+    {
+        let id = client_id.to_owned();
+        let session = self
+            .with_session_store_blocking(move |store| store.load_session(&id))
+            .await
+            .transpose()?
+            .flatten();
+        Ok(SessionExport {
+            client_id: client_id.to_owned(),
+            session,
+            messages: self.storage.inspect_messages(client_id).await?,
+        })
+    }
+
+    /// Replaces the session state of a client by an export, e.g. of another broker
+    ///
+    /// The client must not be connected, it picks the session up when it connects without
+    /// a clean start.
+    #[cfg(feature = "session-export")]
+    pub async fn import_session(&self, export: SessionExport) -> Result<(), StoreError>
+    where
+        S: MessageStore,
+    {
+        let client_id = export.client_id.as_str();
+        if self.is_client_connected(client_id) {
+            return Err(StoreError::Conflict(format!(
+                "client#{client_id} is connected"
+            )));
+        }
+
+        let id = client_id.to_owned();
+        let previous = self
+            .with_session_store_blocking(move |store| store.load_session(&id))
+            .await
+            .transpose()?
+            .flatten();
+        if let Some(session) = previous {
+            for (filter, _) in session.subscriptions() {
+                if let Ok(filter) = TopicFilter::new(filter) {
+                    self.storage.unsubscribe(client_id, &filter).await?;
+                }
+            }
+        }
+        self.storage.clear_all(client_id).await?;
+
+        if let Some(session) = &export.session {
+            for (filter, options) in session.subscriptions() {
+                let filter = TopicFilter::new(filter).map_err(StoreError::serialization)?;
+                self.storage.subscribe(client_id, &filter, options).await?;
+            }
+            let session = session.clone();
+            self.with_session_store_blocking(move |store| store.save_session(&session))
+                .await
+                .transpose()?;
+        }
+        let full = || StoreError::Conflict(format!("messages of client#{client_id} do not fit"));
+        for (packet_id, message) in export.messages.received {
+            let outcome = self
+                .storage
+                .save_publish_message(client_id, packet_id, message)
+                .await?;
+            if outcome == ReceiveOutcome::Full {
+                return Err(full());
+            }
+        }
+        for (packet_id, pending) in export.messages.pending {
+            if self
+                .storage
+                .save_pending_publish_message(client_id, packet_id, pending)
+                .await?
+            {
+                return Err(full());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Publishes from outside a connection and management of the retained messages, for any
+/// [`RetainMessageStore`]
+impl<S> GlobalState<S>
+where
+    S: RetainMessageStore + TopicStore,
+{
+    /// Publishes a message which does not come from a connection, e.g. pushed over HTTP, as
+    /// `publisher` would
+    ///
+    /// The message goes through the same checks as a publish of a client, a reserved topic is
+    /// not authorized.
+    pub async fn publish(
+        &self,
+        publisher: &str,
+        mut message: PublishMessage,
+    ) -> Result<PublishVerdict, StoreError> {
+        if self.is_reserved_topic(message.topic_name()) {
+            debug!(
+                "{publisher} publish to reserved topic {} denied",
+                message.topic_name()
+            );
+            return Ok(PublishVerdict::NotAuthorized);
+        }
+        let verdict = self.authorize_publish(publisher, &mut message).await;
+        if !verdict.is_accepted() {
+            return Ok(verdict);
+        }
+
+        if message.retain() {
+            if message.payload().is_empty() {
+                self.storage.remove(message.topic_name()).await?;
+            } else {
+                self.storage.insert((publisher, &message).into()).await?;
+            }
+        }
+        let topics = self.storage.match_topic(message.topic_name()).await?;
+        self.fan_out(self.deliveries(topics), message).await;
+        Ok(verdict)
+    }
+
+    /// The retained messages matching `topic_filter`, at most `limit` of them in topic name
+    /// order starting after `cursor`
+    pub async fn retained_messages(
+        &self,
+        topic_filter: &TopicFilter,
+        cursor: Option<&str>,
+        limit: usize,
+    ) -> Result<Page<Arc<RetainContent>>, StoreError> {
+        self.storage.search_page(topic_filter, cursor, limit).await
+    }
+
+    /// Deletes the retained message of `topic_name`, returns whether there was one
+    pub async fn remove_retained(&self, topic_name: &TopicName) -> Result<bool, StoreError> {
+        Ok(self.storage.remove(topic_name).await?.is_some())
+    }
+
+    /// Deletes the retained messages of `prefix` and of every topic below it, returns how many
+    /// were deleted
+    pub async fn purge_retained(&self, prefix: &TopicName) -> Result<usize, StoreError> {
+        let filter = TopicFilter::new(format!("{prefix}/#")).map_err(StoreError::serialization)?;
+        let mut purged = 0;
+        let mut cursor = None;
+        loop {
+            let page = self
+                .storage
+                .search_page(&filter, cursor.as_deref(), PURGE_BATCH)
+                .await?;
+            for content in &page.items {
+                if self.storage.remove(content.topic_name()).await?.is_some() {
+                    purged += 1;
+                }
+            }
+            match page.next {
+                Some(next) => cursor = Some(next),
+                None => break,
+            }
+        }
+        debug!("purged {purged} retained messages below {prefix}");
+        Ok(purged)
+    }
+
+    /// Stores a retained message published by the broker and delivers it to the current
+    /// subscribers, an empty payload deletes the retained message of the topic instead
+    pub async fn inject_retained(
+        &self,
+        topic_name: TopicName,
+        payload: Vec<u8>,
+        qos: QualityOfService,
+    ) -> Result<(), StoreError> {
+        let message = PublishMessage::new(topic_name, payload, qos, true);
+        if message.payload().is_empty() {
+            self.storage.remove(message.topic_name()).await?;
+        } else {
+            self.storage
+                .insert((BROKER_CLIENT_ID, &message).into())
+                .await?;
+        }
+        self.forward_internal(message).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mqtt_codec_kit::common::ProtocolLevel;
+
+    use super::*;
+    use crate::store::{memory::MemoryStore, memory_storage};
+
+    fn topic(name: &str) -> TopicName {
+        TopicName::new(name).unwrap()
+    }
+
+    async fn retained(global: &GlobalState<MemoryStore>) -> Vec<String> {
+        let filter = TopicFilter::new("#").unwrap();
+        let page = global.retained_messages(&filter, None, 10).await.unwrap();
+        page.items
+            .iter()
+            .map(|content| content.topic_name().to_string())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn retained_messages_are_managed() {
+        let global = GlobalState::new(memory_storage());
+        for name in ["a", "a/b", "a/b/c", "ab", "d"] {
+            let payload = name.as_bytes().to_vec();
+            let injected = global.inject_retained(topic(name), payload, QualityOfService::Level1);
+            injected.await.unwrap();
+        }
+        assert_eq!(retained(&global).await, ["a", "a/b", "a/b/c", "ab", "d"]);
+
+        assert_eq!(global.purge_retained(&topic("a")).await.unwrap(), 3);
+        assert_eq!(retained(&global).await, ["ab", "d"]);
+        assert!(global.remove_retained(&topic("d")).await.unwrap());
+        assert!(!global.remove_retained(&topic("d")).await.unwrap());
+
+        let cleared = global.inject_retained(topic("ab"), Vec::new(), QualityOfService::Level0);
+        cleared.await.unwrap();
+        assert!(retained(&global).await.is_empty());
+    }
+
+    fn client_info() -> Arc<ClientInfo> {
+        Arc::new(ClientInfo::new(None, ProtocolLevel::Version311, true, None))
+    }
+
+    fn duplicate_client_id_state(policy: DuplicateClientIdPolicy) -> GlobalState<MemoryStore> {
+        GlobalState::new(memory_storage()).with_duplicate_client_id(policy)
+    }
+
+    #[tokio::test]
+    async fn duplicate_client_id_takes_over() {
+        let global = duplicate_client_id_state(DuplicateClientIdPolicy::TakeOver);
+        let (sender, old) = bounded_async(1);
+        let added = global.add_client("c1", 1, sender, client_info()).await;
+        assert!(matches!(added, AddClientReceipt::New));
+
+        // the old connection is asked for its session state, and has none to give
+        let old = tokio::spawn(async move {
+            let message = old.recv().await.unwrap();
+            matches!(message, DeliverMessage::Online(_))
+        });
+        let (sender, _new) = bounded_async(1);
+        let added = global.add_client("c1", 2, sender, client_info()).await;
+        assert!(matches!(added, AddClientReceipt::New));
+        assert!(old.await.unwrap());
+        assert_eq!(global.clients.get("c1").unwrap().token, 2);
+    }
+
+    #[tokio::test]
+    async fn duplicate_client_id_is_rejected() {
+        let global = duplicate_client_id_state(DuplicateClientIdPolicy::Reject);
+        let (sender, old) = bounded_async(1);
+        let added = global.add_client("c1", 1, sender, client_info()).await;
+        assert!(matches!(added, AddClientReceipt::New));
+
+        let (sender, _rejected) = bounded_async(1);
+        let added = global.add_client("c1", 2, sender, client_info()).await;
+        assert!(matches!(added, AddClientReceipt::Rejected));
+        assert_eq!(global.clients.get("c1").unwrap().token, 1);
+
+        // added once the old connection is gone
+        drop(old);
+        global.set_client_offline("c1", 1);
+        let (sender, _new) = bounded_async(1);
+        let added = global.add_client("c1", 3, sender, client_info()).await;
+        assert!(matches!(added, AddClientReceipt::New));
+        assert_eq!(global.clients.get("c1").unwrap().token, 3);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn concurrent_duplicate_client_id_is_added_once() {
+        let global = Arc::new(duplicate_client_id_state(DuplicateClientIdPolicy::Reject));
+        let adds = (0..16).map(|token| {
+            let global = global.clone();
+            tokio::spawn(async move {
+                let (sender, receiver) = bounded_async(1);
+                let added = global.add_client("c1", token, sender, client_info()).await;
+                (matches!(added, AddClientReceipt::New), receiver)
+            })
+        });
+        let mut receivers = Vec::new();
+        let mut added = 0;
+        for add in adds.collect::<Vec<_>>() {
+            let (new, receiver) = add.await.unwrap();
+            added += usize::from(new);
+            receivers.push(receiver);
+        }
+        assert_eq!(added, 1);
+    }
+
+    #[tokio::test]
+    async fn duplicate_client_id_is_suffixed() {
+        let global = duplicate_client_id_state(DuplicateClientIdPolicy::Suffix);
+        let (sender, _old) = bounded_async(1);
+        let added = global.add_client("c1", 1, sender, client_info()).await;
+        assert!(matches!(added, AddClientReceipt::New));
+
+        let (sender, _new) = bounded_async(1);
+        let AddClientReceipt::Renamed(renamed) =
+            global.add_client("c1", 2, sender, client_info()).await
+        else {
+            panic!("client is not renamed");
+        };
+        assert!(renamed.starts_with("c1-"), "{renamed}");
+        assert_eq!(global.clients.get("c1").unwrap().token, 1);
+        assert_eq!(global.clients.get(renamed.as_str()).unwrap().token, 2);
+        assert!(global.is_client_connected("c1"));
+        assert!(global.is_client_connected(&renamed));
+    }
+
+    #[test]
+    fn reserved_topic_prefixes() {
+        let global = GlobalState::new(memory_storage());
+        assert!(global.is_reserved_topic("$SYS/brokers"));
+        assert!(global.is_reserved_topic("$share/g/a"));
+        assert!(!global.is_reserved_topic("$SYSTEM"));
+        assert!(!global.is_reserved_topic("a/$SYS/b"));
+
+        let global = global.with_reserved_topic_prefixes(["private/"]);
+        assert!(global.is_reserved_topic("private/a"));
+        assert!(!global.is_reserved_topic("$SYS/brokers"));
+        assert!(!global.is_reserved_topic("privateer"));
+    }
+
+    #[tokio::test]
+    async fn only_the_owner_subscribes_to_its_response_topic() {
+        let global = GlobalState::new(memory_storage()).with_response_topic_prefix("resp");
+        let allowed = |client_id: &'static str, filter: &'static str| {
+            let global = &global;
+            async move {
+                let filter = TopicFilter::new(filter).unwrap();
+                global
+                    .allow_subscribe(client_id, &filter, QualityOfService::Level1)
+                    .await
+            }
+        };
+
+        for filter in [
+            "resp/c1",
+            "resp/c1/x",
+            "resp/c1/#",
+            "$share/g/resp/c1",
+            "a/b",
+            "resp",
+            "+",
+            "+/c1/x",
+        ] {
+            assert!(allowed("c1", filter).await, "{filter}");
+        }
+        for filter in [
+            "resp/c2",
+            "#",
+            "+/c2/x",
+            "resp/+",
+            "resp/#",
+            "$share/g/resp/#",
+        ] {
+            assert!(!allowed("c1", filter).await, "{filter}");
+        }
+        // a client identifier spanning several levels owns no response topic
+        assert!(!allowed("a/b", "resp/a/b").await);
+        assert!(!allowed("a/b", "resp/a/#").await);
+        assert!(allowed("a/b", "a/b").await);
+
+        // a wildcard at the first level does not match a prefix starting with `$`
+        let global = global.with_response_topic_prefix("$resp");
+        assert!(
+            global
+                .allow_subscribe(
+                    "c1",
+                    &TopicFilter::new("#").unwrap(),
+                    QualityOfService::Level0
+                )
+                .await
+        );
+        assert!(
+            !global
+                .allow_subscribe(
+                    "c1",
+                    &TopicFilter::new("$resp/+").unwrap(),
+                    QualityOfService::Level0
+                )
+                .await
+        );
+    }
+
+    #[cfg(feature = "script")]
+    #[tokio::test]
+    async fn response_topics_go_through_the_script() {
+        let script = ScriptHook::new(
+            r#"
+            function on_subscribe(ctx)
+                return ctx.topic_filter ~= "resp/c1/private"
+            end
+
+            function on_publish(msg)
+                return msg.topic ~= "resp/c1/private"
+            end
+        "#,
+        )
+        .unwrap();
+        let global = GlobalState::new(memory_storage())
+            .with_response_topic_prefix("resp")
+            .with_script(script);
+        let subscribe = |filter: &str| TopicFilter::new(filter).unwrap();
+        let qos = QualityOfService::Level1;
+        assert!(
+            global
+                .allow_subscribe("c1", &subscribe("resp/c1/a"), qos)
+                .await
+        );
+        assert!(
+            !global
+                .allow_subscribe("c1", &subscribe("resp/c1/private"), qos)
+                .await
+        );
+
+        let publish = |name: &str| PublishMessage::new(topic(name), b"m".to_vec(), qos, false);
+        assert!(global.allow_publish("c2", &mut publish("resp/c1/a")).await);
+        assert!(
+            !global
+                .allow_publish("c2", &mut publish("resp/c1/private"))
+                .await
+        );
     }
 }
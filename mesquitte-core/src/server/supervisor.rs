@@ -0,0 +1,45 @@
+//! Keeps the connection tasks from failing silently
+//!
+//! A panic in a connection task is caught and logged with the connection it belongs to, the
+//! protocol loops then release the client from the [`GlobalState`](super::state::GlobalState).
+
+use std::{any::Any, fmt, future::Future, net::SocketAddr, panic::AssertUnwindSafe};
+
+use futures::FutureExt as _;
+
+use crate::error;
+
+/// Runs the task of a connection, a panic is logged instead of unwinding into the runtime
+pub(crate) async fn supervise<F>(remote_addr: Option<SocketAddr>, task: F)
+where
+    F: Future<Output = ()>,
+{
+    if let Err(payload) = AssertUnwindSafe(task).catch_unwind().await {
+        log_panic(
+            format_args!(
+                "connection from {}",
+                remote_addr.map_or_else(|| "unknown".to_owned(), |addr| addr.to_string())
+            ),
+            payload.as_ref(),
+        );
+    }
+}
+
+/// Logs the panic of the task described by `task`
+pub(crate) fn log_panic(task: fmt::Arguments<'_>, payload: &(dyn Any + Send)) {
+    #[cfg(any(feature = "log", feature = "tracing"))]
+    error!("{task} panicked: {}", panic_message(payload));
+    #[cfg(not(any(feature = "log", feature = "tracing")))]
+    let _ = (task, payload);
+}
+
+#[cfg(any(feature = "log", feature = "tracing"))]
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message
+    } else {
+        "unknown panic"
+    }
+}
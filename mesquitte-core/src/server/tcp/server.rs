@@ -1,53 +1,120 @@
-use std::{net::SocketAddr, num::NonZeroUsize};
+use std::{mem, net::SocketAddr, sync::Arc};
 
-use tokio::net::TcpSocket;
+use tokio::net::TcpListener;
+#[cfg(feature = "mqtts")]
+use tokio_rustls::rustls::ServerConfig as RustlsConfig;
 
+#[cfg(feature = "mqtts")]
+use crate::server::{
+    cert_identity::certificate_identity, listener::handshake, rustls::listener_acceptor,
+    tenant::Tenants,
+};
 use crate::{
     info,
-    server::{config::ServerConfig, process_client, state::GlobalState, Error},
+    server::{
+        config::ServerConfig,
+        listener::{accept, bind_tcp, configure_stream, local_addrs, workers},
+        process_client,
+        state::GlobalState,
+        Error,
+    },
     store::{message::MessageStore, retain::RetainMessageStore, topic::TopicStore},
+    warn,
 };
-#[cfg(feature = "mqtts")]
-use crate::{server::rustls::rustls_acceptor, warn};
 
 pub struct TcpServer<S: 'static> {
     config: ServerConfig,
-    global: &'static GlobalState<S>,
+    global: Arc<GlobalState<S>>,
+    listeners: Vec<TcpListener>,
+    #[cfg(feature = "mqtts")]
+    rustls: Option<Arc<RustlsConfig>>,
+    #[cfg(feature = "mqtts")]
+    tenants: Arc<Tenants<S>>,
 }
 
 impl<S> TcpServer<S>
 where
     S: MessageStore + RetainMessageStore + TopicStore,
 {
-    pub async fn new(config: ServerConfig, global: &'static GlobalState<S>) -> Result<Self, Error> {
-        Ok(Self { config, global })
+    pub async fn new(config: ServerConfig, global: Arc<GlobalState<S>>) -> Result<Self, Error> {
+        Ok(Self {
+            config,
+            global,
+            listeners: Vec::new(),
+            #[cfg(feature = "mqtts")]
+            rustls: None,
+            #[cfg(feature = "mqtts")]
+            tenants: Arc::new(Tenants::new()),
+        })
+    }
+
+    /// Selects the state of TLS connections by their SNI name, connections without a known name
+    /// use the server's own state
+    #[cfg(feature = "mqtts")]
+    pub fn with_tenants(mut self, tenants: Tenants<S>) -> Self {
+        self.tenants = Arc::new(tenants);
+        self
+    }
+
+    /// Uses `config` for TLS instead of building it from the [`TlsConfig`], e.g. to share one
+    /// config with the wss listener
+    ///
+    /// [`TlsConfig`]: crate::server::config::TlsConfig
+    #[cfg(feature = "mqtts")]
+    pub fn with_rustls_config(mut self, config: Arc<RustlsConfig>) -> Self {
+        self.rustls = Some(config);
+        self
+    }
+
+    /// Binds the listening sockets and returns their addresses, with the port assigned by the
+    /// system for port 0, [`TcpServer::serve`] binds them itself if this was not called
+    #[allow(clippy::result_large_err)]
+    pub fn bind(&mut self) -> Result<Vec<SocketAddr>, Error> {
+        if self.listeners.is_empty() {
+            self.listeners = bind_tcp(&self.config.addrs, &self.config.socket, workers())?;
+        }
+        Ok(self.local_addrs())
+    }
+
+    pub fn local_addrs(&self) -> Vec<SocketAddr> {
+        local_addrs(&self.listeners)
+    }
+
+    pub(crate) fn config(&self) -> &ServerConfig {
+        &self.config
+    }
+
+    /// Whether a TLS config was given, built from the [`ServerConfig`] or set directly
+    #[cfg(feature = "mqtts")]
+    pub(crate) fn has_tls(&self) -> bool {
+        self.rustls.is_some() || self.config.tls.is_some()
     }
 
     #[cfg(feature = "mqtt")]
-    pub async fn serve(self) -> Result<(), Error> {
-        #[cfg(all(unix, not(target_os = "solaris"), not(target_os = "illumos")))]
-        let worker = std::thread::available_parallelism().map_or(1, NonZeroUsize::get);
-        #[cfg(any(target_os = "solaris", target_os = "illumos"))]
-        let worker = 1;
-        let mut tasks = Vec::with_capacity(worker);
-        for i in 0..worker {
-            info!("tcp worker {} starting...", i);
-            let socket = match self.config.addr {
-                SocketAddr::V4(_) => TcpSocket::new_v4()?,
-                SocketAddr::V6(_) => TcpSocket::new_v6()?,
-            };
-            #[cfg(all(unix, not(target_os = "solaris"), not(target_os = "illumos")))]
-            socket.set_reuseport(true)?;
-            socket.bind(self.config.addr)?;
-            let listener = socket.listen(1024)?;
+    pub async fn serve(mut self) -> Result<(), Error> {
+        self.bind()?;
+        let listeners = mem::take(&mut self.listeners);
+        let mut tasks = Vec::with_capacity(listeners.len());
+        for listener in listeners {
+            info!("tcp listener on {} starting...", listener.local_addr()?);
+            let mount_point = self.config.mount_point.clone();
+            let version = self.config.version;
+            let socket = self.config.socket.clone();
+            let global = self.global.clone();
             let task = tokio::spawn(async move {
-                while let Ok((stream, _addr)) = listener.accept().await {
+                loop {
+                    let (stream, addr) = accept(&listener).await;
+                    if let Err(err) = configure_stream(&stream, &socket) {
+                        warn!("configure socket of {addr} failed: {err}");
+                    }
+                    let mount_point = mount_point.clone();
+                    let global = global.clone();
                     tokio::spawn(async move {
-                        process_client(stream, self.config.version, self.global).await?;
+                        process_client(stream, Some(addr), version, mount_point, None, global)
+                            .await?;
                         Ok::<(), Error>(())
                     });
                 }
-                Ok::<(), Error>(())
             });
             tasks.push(task);
         }
@@ -58,43 +125,63 @@ where
     }
 
     #[cfg(feature = "mqtts")]
-    pub async fn serve_tls(self) -> Result<(), Error> {
-        let tls = match &self.config.tls {
-            Some(tls) => tls,
-            None => return Err(Error::MissingTlsConfig),
-        };
-        #[cfg(all(unix, not(target_os = "solaris"), not(target_os = "illumos")))]
-        let worker = std::thread::available_parallelism().map_or(1, NonZeroUsize::get);
-        #[cfg(any(target_os = "solaris", target_os = "illumos"))]
-        let worker = 1;
-        let mut tasks = Vec::with_capacity(worker);
-        for i in 0..worker {
-            info!("tcp worker {} starting...", i);
-            let acceptor = rustls_acceptor(tls)?;
-            let socket = match self.config.addr {
-                SocketAddr::V4(_) => TcpSocket::new_v4()?,
-                SocketAddr::V6(_) => TcpSocket::new_v6()?,
-            };
-            #[cfg(all(unix, not(target_os = "solaris"), not(target_os = "illumos")))]
-            socket.set_reuseport(true)?;
-            socket.bind(self.config.addr)?;
-            let listener = socket.listen(1024)?;
+    pub async fn serve_tls(mut self) -> Result<(), Error> {
+        self.bind()?;
+        let acceptor = listener_acceptor(self.rustls.as_ref(), self.config.tls.as_ref())?;
+        let listeners = mem::take(&mut self.listeners);
+        let mut tasks = Vec::with_capacity(listeners.len());
+        for listener in listeners {
+            info!("tcp listener on {} starting...", listener.local_addr()?);
+            let acceptor = acceptor.clone();
+            let tenants = self.tenants.clone();
+            let mount_point = self.config.mount_point.clone();
+            let version = self.config.version;
+            let socket = self.config.socket.clone();
+            let cert_auth = self.config.cert_auth;
+            let default_global = self.global.clone();
             let task = tokio::spawn(async move {
-                while let Ok((stream, _addr)) = listener.accept().await {
-                    match acceptor.accept(stream).await {
-                        Ok(stream) => {
-                            tokio::spawn(async move {
-                                process_client(stream, self.config.version, self.global).await?;
-                                Ok::<(), Error>(())
-                            });
-                        }
-                        Err(err) => {
-                            warn!("accept tls stream failed: {err}");
-                            continue;
-                        }
+                loop {
+                    let (stream, addr) = accept(&listener).await;
+                    if let Err(err) = configure_stream(&stream, &socket) {
+                        warn!("configure socket of {addr} failed: {err}");
                     }
+                    let acceptor = acceptor.clone();
+                    let tenants = tenants.clone();
+                    let default_global = default_global.clone();
+                    let mount_point = mount_point.clone();
+                    // the handshake runs on the connection's task, a silent client must not
+                    // hold up the accept loop
+                    tokio::spawn(async move {
+                        let timeout = default_global.handshake_timeout();
+                        let Some(stream) =
+                            handshake(addr, "tls", timeout, acceptor.accept(stream)).await
+                        else {
+                            return Ok(());
+                        };
+                        let (_, connection) = stream.get_ref();
+                        let cert_identity = if cert_auth {
+                            let identity = certificate_identity(connection.peer_certificates());
+                            if identity.is_none() {
+                                warn!("client certificate of {addr} has no common name");
+                                return Ok(());
+                            }
+                            identity
+                        } else {
+                            None
+                        };
+                        let global = tenants.resolve(connection.server_name(), &default_global);
+                        process_client(
+                            stream,
+                            Some(addr),
+                            version,
+                            mount_point,
+                            cert_identity,
+                            global,
+                        )
+                        .await?;
+                        Ok::<(), Error>(())
+                    });
                 }
-                Ok::<(), Error>(())
             });
             tasks.push(task);
         }
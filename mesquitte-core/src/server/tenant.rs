@@ -0,0 +1,97 @@
+//! Virtual hosting of several tenants on one listener
+//!
+//! The TLS SNI name of a connection, or the `Host` header of a WebSocket handshake, selects the
+//! tenant it belongs to. Every tenant has its own [`GlobalState`], so clients, sessions,
+//! subscriptions, retained messages, the authentication script and the limits of one tenant are
+//! never visible to another. Connections without a known host name are served by the listener's
+//! own [`GlobalState`].
+
+use std::sync::Arc;
+
+use foldhash::{HashMap, HashMapExt};
+
+use super::state::GlobalState;
+
+pub struct Tenants<S: 'static> {
+    hosts: HashMap<String, Arc<GlobalState<S>>>,
+}
+
+impl<S> Default for Tenants<S> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<S> Tenants<S> {
+    pub fn new() -> Self {
+        Self {
+            hosts: HashMap::new(),
+        }
+    }
+
+    /// Serves the connections made to `host` with `global`, host names are case insensitive
+    pub fn with_tenant(mut self, host: &str, global: Arc<GlobalState<S>>) -> Self {
+        self.hosts.insert(normalize(host), global);
+        self
+    }
+
+    /// The state of the tenant `host` belongs to, `host` may carry a port as in a `Host` header
+    pub fn get(&self, host: &str) -> Option<&Arc<GlobalState<S>>> {
+        self.hosts.get(&normalize(host))
+    }
+
+    /// The state of the tenant `host` belongs to, or `default` for unknown hosts
+    pub(crate) fn resolve(
+        &self,
+        host: Option<&str>,
+        default: &Arc<GlobalState<S>>,
+    ) -> Arc<GlobalState<S>> {
+        host.and_then(|host| self.get(host))
+            .unwrap_or(default)
+            .clone()
+    }
+}
+
+/// Lowercases a host name and strips its port and trailing dot
+fn normalize(host: &str) -> String {
+    let host = if let Some(rest) = host.strip_prefix('[') {
+        // IPv6 literal, `[::1]:1883`
+        rest.split(']').next().unwrap_or(rest)
+    } else {
+        match host.rsplit_once(':') {
+            // a bare IPv6 address has more than one colon and no port
+            Some((name, port))
+                if !name.contains(':') && port.bytes().all(|b| b.is_ascii_digit()) =>
+            {
+                name
+            }
+            _ => host,
+        }
+    };
+    host.strip_suffix('.').unwrap_or(host).to_ascii_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hosts_are_normalized() {
+        for (host, expected) in [
+            ("example.com", "example.com"),
+            ("example.com:1883", "example.com"),
+            ("example.com.", "example.com"),
+            ("example.com.:8883", "example.com"),
+            ("Example.COM:8883", "example.com"),
+            ("127.0.0.1:1883", "127.0.0.1"),
+            ("[::1]:1883", "::1"),
+            ("[::1]", "::1"),
+            ("[FE80::1]:443", "fe80::1"),
+            ("::1", "::1"),
+            ("2001:DB8::1", "2001:db8::1"),
+            ("example.com:mqtt", "example.com:mqtt"),
+        ] {
+            assert_eq!(normalize(host), expected, "{host}");
+        }
+    }
+}
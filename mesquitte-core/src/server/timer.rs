@@ -0,0 +1,273 @@
+//! Shared timers for keep-alive checks and session expiry
+//!
+//! Instead of a tokio timer per connection, every timer lives in one hierarchical timing wheel
+//! which is driven by a single task. Inserting and firing a timer is O(1), cancelled timers are
+//! not removed but ignored by their receiver.
+
+use std::{
+    mem,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Once, Weak,
+    },
+    time::Duration,
+};
+
+use parking_lot::Mutex;
+use tokio::time::{self, Instant, MissedTickBehavior};
+
+use super::state::{Clients, DeliverMessage};
+
+/// Resolution of the timers
+pub const TICK: Duration = Duration::from_millis(100);
+
+const SLOT_BITS: u32 = 6;
+const SLOTS: usize = 1 << SLOT_BITS;
+const SLOT_MASK: u64 = SLOTS as u64 - 1;
+const LEVELS: usize = 4;
+/// Deadlines beyond the wheel are parked in the last level and cascaded again
+const MAX_SPAN: u64 = 1 << (SLOT_BITS * LEVELS as u32);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimerKind {
+    KeepAlive,
+    SessionExpiry,
+}
+
+struct Entry<T> {
+    deadline: u64,
+    value: T,
+}
+
+/// Hierarchical timing wheel counting in ticks
+///
+/// Level `n` has `SLOTS` slots of `SLOTS^n` ticks each. Entries are cascaded into the lower
+/// levels when the wheel reaches their slot and fire from level 0.
+pub(crate) struct TimerWheel<T> {
+    elapsed: u64,
+    levels: Vec<Vec<Vec<Entry<T>>>>,
+}
+
+impl<T> TimerWheel<T> {
+    pub fn new() -> Self {
+        Self {
+            elapsed: 0,
+            levels: (0..LEVELS)
+                .map(|_| (0..SLOTS).map(|_| Vec::new()).collect())
+                .collect(),
+        }
+    }
+
+    pub fn elapsed(&self) -> u64 {
+        self.elapsed
+    }
+
+    /// Inserts `value` to fire at tick `deadline`, past deadlines fire on the next tick
+    pub fn insert(&mut self, deadline: u64, value: T) {
+        let deadline = deadline.max(self.elapsed + 1);
+        self.place(Entry { deadline, value });
+    }
+
+    fn place(&mut self, entry: Entry<T>) {
+        // beyond the current span, parked in the first slot of the last level which is only
+        // cascaded when the next span starts
+        if entry.deadline > self.elapsed | (MAX_SPAN - 1) {
+            self.levels[LEVELS - 1][0].push(entry);
+            return;
+        }
+        let masked = (self.elapsed ^ entry.deadline) | SLOT_MASK;
+        let level = ((63 - masked.leading_zeros()) / SLOT_BITS) as usize;
+        let slot = ((entry.deadline >> (level as u32 * SLOT_BITS)) & SLOT_MASK) as usize;
+        self.levels[level][slot].push(entry);
+    }
+
+    /// Advances the wheel to tick `now`, returning the values which are due
+    pub fn advance(&mut self, now: u64) -> Vec<T> {
+        let mut fired = Vec::new();
+        while self.elapsed < now {
+            self.elapsed += 1;
+            for level in (1..LEVELS).rev() {
+                let shift = level as u32 * SLOT_BITS;
+                if self.elapsed & ((1 << shift) - 1) != 0 {
+                    continue;
+                }
+                let slot = ((self.elapsed >> shift) & SLOT_MASK) as usize;
+                for entry in mem::take(&mut self.levels[level][slot]) {
+                    self.place(entry);
+                }
+            }
+            let slot = (self.elapsed & SLOT_MASK) as usize;
+            for entry in mem::take(&mut self.levels[0][slot]) {
+                if entry.deadline <= self.elapsed {
+                    fired.push(entry.value);
+                } else {
+                    self.place(entry);
+                }
+            }
+        }
+        fired
+    }
+}
+
+struct TimerEntry {
+    client_id: String,
+    token: u64,
+    kind: TimerKind,
+}
+
+/// Timers of all the sessions of a broker
+///
+/// A fired timer is delivered to the session as [`DeliverMessage::Timeout`]. Each connection
+/// takes a token from [`Timers::next_token`] and ignores timeouts carrying another token, which
+/// covers timers of a previous connection with the same client identifier.
+pub(crate) struct Timers {
+    wheel: Arc<Mutex<TimerWheel<TimerEntry>>>,
+    started_at: Instant,
+    driver: Once,
+    next_token: AtomicU64,
+}
+
+impl Timers {
+    pub fn new() -> Self {
+        Self {
+            wheel: Arc::new(Mutex::new(TimerWheel::new())),
+            started_at: Instant::now(),
+            driver: Once::new(),
+            next_token: AtomicU64::new(1),
+        }
+    }
+
+    pub fn next_token(&self) -> u64 {
+        self.next_token.fetch_add(1, Ordering::Relaxed)
+    }
+
+    pub fn schedule(
+        &self,
+        clients: &Arc<Clients>,
+        client_id: &str,
+        token: u64,
+        kind: TimerKind,
+        after: Duration,
+    ) {
+        self.driver.call_once(|| {
+            tokio::spawn(drive(
+                self.wheel.clone(),
+                Arc::downgrade(clients),
+                self.started_at,
+            ));
+        });
+        let deadline = (self.started_at.elapsed() + after).as_millis() / TICK.as_millis();
+        self.wheel.lock().insert(
+            deadline.try_into().unwrap_or(u64::MAX),
+            TimerEntry {
+                client_id: client_id.to_owned(),
+                token,
+                kind,
+            },
+        );
+    }
+}
+
+/// Fires the due timers every tick, until the broker holding `clients` is dropped
+async fn drive(
+    wheel: Arc<Mutex<TimerWheel<TimerEntry>>>,
+    clients: Weak<Clients>,
+    started_at: Instant,
+) {
+    let mut interval = time::interval_at(started_at + TICK, TICK);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+    loop {
+        interval.tick().await;
+        let now = (started_at.elapsed().as_millis() / TICK.as_millis()) as u64;
+        let Some(clients) = clients.upgrade() else {
+            return;
+        };
+        let fired = wheel.lock().advance(now);
+        let mut retry = Vec::new();
+        for entry in fired {
+            let Some(sender) = clients.get(&entry.client_id).map(|s| s.sender().clone()) else {
+                continue;
+            };
+            // a full deliver channel must not stall the other timers, try again on the next tick
+            if let Ok(false) = sender.try_send(DeliverMessage::Timeout(entry.kind, entry.token)) {
+                retry.push(entry);
+            }
+        }
+        if !retry.is_empty() {
+            let mut wheel = wheel.lock();
+            let next = wheel.elapsed() + 1;
+            for entry in retry {
+                wheel.insert(next, entry);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // advances one tick at a time, recording the tick each value fired at
+    fn fire_until(wheel: &mut TimerWheel<u64>, until: u64) -> Vec<(u64, u64)> {
+        let mut fired = Vec::new();
+        for now in wheel.elapsed() + 1..=until {
+            fired.extend(wheel.advance(now).into_iter().map(|value| (now, value)));
+        }
+        fired
+    }
+
+    #[test]
+    fn timers_fire_at_their_deadline() {
+        let mut wheel = TimerWheel::new();
+        // level 0, across the first boundaries of levels 1, 2 and 3
+        let deadlines = [1, 5, 63, 64, 65, 100, 4095, 4096, 4097, 262_144, 300_000];
+        for deadline in deadlines.into_iter().rev() {
+            wheel.insert(deadline, deadline);
+        }
+        let fired = fire_until(&mut wheel, 300_001);
+        assert_eq!(fired, deadlines.map(|deadline| (deadline, deadline)));
+    }
+
+    #[test]
+    fn timers_inserted_later_fire_at_their_deadline() {
+        let mut wheel = TimerWheel::new();
+        assert!(wheel.advance(4000).is_empty());
+        // past deadlines fire on the next tick
+        wheel.insert(10, 10);
+        wheel.insert(4100, 4100);
+        wheel.insert(8200, 8200);
+        let fired = fire_until(&mut wheel, 8200);
+        assert_eq!(fired, [(4001, 10), (4100, 4100), (8200, 8200)]);
+    }
+
+    #[test]
+    fn timers_beyond_the_wheel_are_cascaded() {
+        let mut wheel = TimerWheel::new();
+        wheel.insert(MAX_SPAN + 5, 1);
+        wheel.insert(2 * MAX_SPAN + 1, 2);
+        assert!(wheel.advance(MAX_SPAN + 4).is_empty());
+        assert_eq!(wheel.advance(MAX_SPAN + 5), [1]);
+        assert!(wheel.advance(2 * MAX_SPAN).is_empty());
+        assert_eq!(wheel.advance(2 * MAX_SPAN + 1), [2]);
+    }
+
+    #[test]
+    fn retried_timer_fires_on_the_next_tick() {
+        let mut wheel = TimerWheel::new();
+        wheel.insert(3, 1);
+        assert_eq!(wheel.advance(3), [1]);
+        // as the driver re-inserts a timeout the session could not take
+        wheel.insert(wheel.elapsed() + 1, 1);
+        assert_eq!(wheel.advance(4), [1]);
+        assert!(wheel.advance(100).is_empty());
+    }
+
+    #[tokio::test]
+    async fn driver_stops_with_the_broker() {
+        let clients = Arc::new(Clients::default());
+        let wheel = Arc::new(Mutex::new(TimerWheel::new()));
+        let driver = tokio::spawn(drive(wheel, Arc::downgrade(&clients), Instant::now()));
+        drop(clients);
+        time::timeout(TICK * 10, driver).await.unwrap().unwrap();
+    }
+}
@@ -0,0 +1,205 @@
+//! Publish statistics per topic
+//!
+//! With [`GlobalState::with_topic_stats`] every publish forwarded to subscribers is counted
+//! under its topic name. Only the `max_topics` busiest topics are kept: when a publish to an
+//! untracked topic arrives and the collector is full, the topic with the fewest publishes is
+//! replaced and the new topic starts from its publish count (the space-saving algorithm). The
+//! publish count of a topic is therefore an upper bound, while any topic which gets more than
+//! `1 / max_topics` of all publishes is guaranteed to be tracked.
+//!
+//! The counters are read with [`GlobalState::topic_stats`], or published as retained messages
+//! under `$SYS/broker/topics/` with [`publish_topic_stats`].
+
+use std::{collections::HashSet, time::Duration};
+
+use foldhash::{HashMap, HashMapExt};
+use mqtt_codec_kit::common::{QualityOfService, TopicName};
+use parking_lot::Mutex;
+use tokio::time::{self, MissedTickBehavior};
+
+use super::state::{GlobalState, BROKER_CLIENT_ID};
+use crate::{
+    store::{
+        error::StoreError, message::PublishMessage, retain::RetainMessageStore, topic::TopicStore,
+    },
+    warn,
+};
+
+/// Prefix of the retained statistics topics
+pub const TOPIC_STATS_PREFIX: &str = "$SYS/broker/topics/";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TopicCounters {
+    /// Publishes forwarded, an upper bound for a topic which replaced another one
+    pub publishes: u64,
+    /// Payload bytes forwarded since the topic is tracked
+    pub bytes: u64,
+    /// Subscriptions matched by the last publish
+    pub subscribers: usize,
+}
+
+/// Counters of the busiest topics, see [`self`](super::topic_stats)
+#[derive(Debug)]
+pub struct TopicStats {
+    max_topics: usize,
+    topics: Mutex<HashMap<String, TopicCounters>>,
+}
+
+impl TopicStats {
+    pub(crate) fn new(max_topics: usize) -> Self {
+        let max_topics = max_topics.max(1);
+        Self {
+            max_topics,
+            topics: Mutex::new(HashMap::with_capacity(max_topics)),
+        }
+    }
+
+    pub(crate) fn record(&self, topic_name: &str, bytes: usize, subscribers: usize) {
+        let mut topics = self.topics.lock();
+        let counters = match topics.get_mut(topic_name) {
+            Some(counters) => counters,
+            None => {
+                let mut counters = TopicCounters::default();
+                if topics.len() >= self.max_topics {
+                    // a linear scan, the collector is meant to hold tens or hundreds of topics
+                    let least = topics
+                        .iter()
+                        .min_by_key(|(_, counters)| counters.publishes)
+                        .map(|(topic_name, _)| topic_name.clone());
+                    if let Some(least) = least {
+                        counters.publishes = topics.remove(&least).map_or(0, |c| c.publishes);
+                    }
+                }
+                topics.entry(topic_name.to_owned()).or_insert(counters)
+            }
+        };
+        counters.publishes += 1;
+        counters.bytes += bytes as u64;
+        counters.subscribers = subscribers;
+    }
+
+    /// Counters of the topic, `None` if it is not tracked
+    pub fn get(&self, topic_name: &str) -> Option<TopicCounters> {
+        self.topics.lock().get(topic_name).copied()
+    }
+
+    /// The tracked topics, busiest first
+    pub fn top(&self) -> Vec<(String, TopicCounters)> {
+        let mut topics: Vec<_> = self
+            .topics
+            .lock()
+            .iter()
+            .map(|(topic_name, counters)| (topic_name.clone(), *counters))
+            .collect();
+        topics.sort_unstable_by(|(a_name, a), (b_name, b)| {
+            b.publishes
+                .cmp(&a.publishes)
+                .then_with(|| a_name.cmp(b_name))
+        });
+        topics
+    }
+}
+
+/// Publishes the counters of every tracked topic as retained messages every `interval`, runs
+/// until the task is dropped
+///
+/// A topic is published as `$SYS/broker/topics/<topic>/publishes`, `.../bytes` and
+/// `.../subscribers`. The entries of a topic which is no longer tracked are cleared. Does
+/// nothing if topic statistics are not enabled.
+pub async fn publish_topic_stats<S>(global: &GlobalState<S>, interval: Duration)
+where
+    S: RetainMessageStore + TopicStore,
+{
+    let Some(stats) = global.topic_stats() else {
+        return;
+    };
+    let mut ticker = time::interval(interval);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut published = HashSet::new();
+    loop {
+        ticker.tick().await;
+        let top = stats.top();
+        let tracked: HashSet<String> = top.iter().map(|(name, _)| name.clone()).collect();
+        for topic_name in published.difference(&tracked) {
+            for (name, _) in entries(topic_name, &TopicCounters::default()) {
+                if let Err(err) = global.storage.remove(&name).await {
+                    warn!("clear topic stats of {topic_name}: {err}");
+                }
+            }
+        }
+        for (topic_name, counters) in &top {
+            for (name, value) in entries(topic_name, counters) {
+                let message =
+                    PublishMessage::new(name, value.into_bytes(), QualityOfService::Level0, true);
+                if let Err(err) = publish(global, message).await {
+                    warn!("publish topic stats of {topic_name}: {err}");
+                }
+            }
+        }
+        published = tracked;
+    }
+}
+
+fn entries<'a>(
+    topic_name: &'a str,
+    counters: &TopicCounters,
+) -> impl Iterator<Item = (TopicName, String)> + 'a {
+    [
+        ("publishes", counters.publishes.to_string()),
+        ("bytes", counters.bytes.to_string()),
+        ("subscribers", counters.subscribers.to_string()),
+    ]
+    .into_iter()
+    .filter_map(move |(counter, value)| {
+        TopicName::new(format!("{TOPIC_STATS_PREFIX}{topic_name}/{counter}"))
+            .ok()
+            .map(|name| (name, value))
+    })
+}
+
+/// Retains a message of the broker and forwards it to the subscribers of its topic
+pub(crate) async fn publish<S>(
+    global: &GlobalState<S>,
+    message: PublishMessage,
+) -> Result<(), StoreError>
+where
+    S: RetainMessageStore + TopicStore,
+{
+    global
+        .storage
+        .insert((BROKER_CLIENT_ID, &message).into())
+        .await?;
+    global.forward_internal(message).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn busiest_topics_are_kept() {
+        let stats = TopicStats::new(2);
+        for _ in 0..10 {
+            stats.record("a", 4, 1);
+        }
+        stats.record("b", 2, 3);
+        stats.record("b", 2, 5);
+        assert_eq!(
+            stats.get("b"),
+            Some(TopicCounters {
+                publishes: 2,
+                bytes: 4,
+                subscribers: 5
+            })
+        );
+
+        // "b" is replaced and "c" inherits its publishes
+        stats.record("c", 1, 0);
+        assert_eq!(stats.get("b"), None);
+        assert_eq!(stats.get("c").unwrap().publishes, 3);
+        assert_eq!(stats.get("c").unwrap().bytes, 1);
+
+        let top: Vec<_> = stats.top().into_iter().map(|(name, _)| name).collect();
+        assert_eq!(top, ["a", "c"]);
+    }
+}
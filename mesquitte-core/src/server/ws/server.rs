@@ -1,61 +1,125 @@
-use std::{net::SocketAddr, num::NonZeroUsize};
+use std::{mem, net::SocketAddr, sync::Arc};
 
-use tokio::net::TcpSocket;
+use tokio::{net::TcpListener, task::JoinHandle};
+#[cfg(feature = "wss")]
+use tokio_rustls::{rustls::ServerConfig as RustlsConfig, TlsAcceptor};
 use tokio_tungstenite::accept_hdr_async;
 #[cfg(any(feature = "ws", feature = "wss"))]
 use tungstenite::{handshake::server::ErrorResponse, http};
 
+#[cfg(feature = "wss")]
+use crate::server::rustls::listener_acceptor;
 use crate::{
     info,
-    server::{config::ServerConfig, process_client, state::GlobalState, Error},
+    server::{
+        config::ServerConfig,
+        listener::{accept, bind_tcp, configure_stream, handshake, local_addrs, workers},
+        process_client,
+        state::GlobalState,
+        tenant::Tenants,
+        Error,
+    },
     store::{message::MessageStore, retain::RetainMessageStore, topic::TopicStore},
+    warn,
 };
-#[cfg(feature = "wss")]
-use crate::{server::rustls::rustls_acceptor, warn};
 
 use super::ws_stream::WsByteStream;
 
 pub struct WsServer<S: 'static> {
     config: ServerConfig,
-    global: &'static GlobalState<S>,
+    global: Arc<GlobalState<S>>,
+    listeners: Vec<TcpListener>,
+    // listeners of `ServerConfig::tls_addrs`
+    #[cfg(feature = "wss")]
+    tls_listeners: Vec<TcpListener>,
+    #[cfg(feature = "wss")]
+    rustls: Option<Arc<RustlsConfig>>,
+    tenants: Arc<Tenants<S>>,
 }
 
 impl<S> WsServer<S>
 where
     S: MessageStore + RetainMessageStore + TopicStore,
 {
-    pub async fn new(config: ServerConfig, global: &'static GlobalState<S>) -> Result<Self, Error> {
-        Ok(Self { config, global })
+    pub async fn new(config: ServerConfig, global: Arc<GlobalState<S>>) -> Result<Self, Error> {
+        Ok(Self {
+            config,
+            global,
+            listeners: Vec::new(),
+            #[cfg(feature = "wss")]
+            tls_listeners: Vec::new(),
+            #[cfg(feature = "wss")]
+            rustls: None,
+            tenants: Arc::new(Tenants::new()),
+        })
+    }
+
+    /// Selects the state of connections by their TLS SNI name or the `Host` header of their
+    /// handshake, connections without a known name use the server's own state
+    pub fn with_tenants(mut self, tenants: Tenants<S>) -> Self {
+        self.tenants = Arc::new(tenants);
+        self
+    }
+
+    /// Uses `config` for TLS instead of building it from the [`TlsConfig`], e.g. to share one
+    /// config with the mqtts listener
+    ///
+    /// [`TlsConfig`]: crate::server::config::TlsConfig
+    #[cfg(feature = "wss")]
+    pub fn with_rustls_config(mut self, config: Arc<RustlsConfig>) -> Self {
+        self.rustls = Some(config);
+        self
     }
 
+    /// Binds the listening sockets and returns their addresses, with the port assigned by the
+    /// system for port 0, [`WsServer::serve`] binds them itself if this was not called
+    #[allow(clippy::result_large_err)]
+    pub fn bind(&mut self) -> Result<Vec<SocketAddr>, Error> {
+        if self.listeners.is_empty() {
+            self.listeners = bind_tcp(&self.config.addrs, &self.config.socket, workers())?;
+        }
+        #[cfg(feature = "wss")]
+        if self.tls_listeners.is_empty() && !self.config.tls_addrs.is_empty() {
+            self.tls_listeners = bind_tcp(&self.config.tls_addrs, &self.config.socket, workers())?;
+        }
+        Ok(self.local_addrs())
+    }
+
+    pub fn local_addrs(&self) -> Vec<SocketAddr> {
+        #[allow(unused_mut)]
+        let mut addrs = local_addrs(&self.listeners);
+        #[cfg(feature = "wss")]
+        addrs.extend(local_addrs(&self.tls_listeners));
+        addrs
+    }
+
+    pub(crate) fn config(&self) -> &ServerConfig {
+        &self.config
+    }
+
+    /// Whether a TLS config was given, built from the [`ServerConfig`] or set directly
+    pub(crate) fn has_tls(&self) -> bool {
+        #[cfg(feature = "wss")]
+        if self.rustls.is_some() {
+            return true;
+        }
+        self.config.tls.is_some()
+    }
+
+    /// Serves ws on the addresses of the config, and wss on its TLS addresses
     #[cfg(feature = "ws")]
-    pub async fn serve(self) -> Result<(), Error> {
-        #[cfg(all(unix, not(target_os = "solaris"), not(target_os = "illumos")))]
-        let worker = std::thread::available_parallelism().map_or(1, NonZeroUsize::get);
-        #[cfg(any(target_os = "solaris", target_os = "illumos"))]
-        let worker = 1;
-        let mut tasks = Vec::with_capacity(worker);
-        for i in 0..worker {
-            info!("ws worker {} starting...", i);
-            let socket = match self.config.addr {
-                SocketAddr::V4(_) => TcpSocket::new_v4()?,
-                SocketAddr::V6(_) => TcpSocket::new_v6()?,
-            };
-            #[cfg(all(unix, not(target_os = "solaris"), not(target_os = "illumos")))]
-            socket.set_reuseport(true)?;
-            socket.bind(self.config.addr)?;
-            let listener = socket.listen(1024)?;
-            let task = tokio::spawn(async move {
-                while let Ok((stream, _addr)) = listener.accept().await {
-                    let ws_stream = WsByteStream::new(accept_hdr_async(stream, ws_callback).await?);
-                    tokio::spawn(async move {
-                        process_client(ws_stream, self.config.version, self.global).await?;
-                        Ok::<(), Error>(())
-                    });
-                }
-                Ok::<(), Error>(())
-            });
-            tasks.push(task);
+    pub async fn serve(mut self) -> Result<(), Error> {
+        self.bind()?;
+        let mut tasks = Vec::new();
+        #[cfg(feature = "wss")]
+        if !self.tls_listeners.is_empty() {
+            let acceptor = listener_acceptor(self.rustls.as_ref(), self.config.tls.as_ref())?;
+            for listener in mem::take(&mut self.tls_listeners) {
+                tasks.push(self.spawn_wss(listener, acceptor.clone())?);
+            }
+        }
+        for listener in mem::take(&mut self.listeners) {
+            tasks.push(self.spawn_ws(listener)?);
         }
         for task in tasks {
             let _ = task.await;
@@ -63,54 +127,150 @@ where
         Ok(())
     }
 
+    /// Serves wss on every address of the config
     #[cfg(feature = "wss")]
-    pub async fn serve_tls(self) -> Result<(), Error> {
-        let tls = match &self.config.tls {
-            Some(tls) => tls,
-            None => return Err(Error::MissingTlsConfig),
-        };
-        #[cfg(all(unix, not(target_os = "solaris"), not(target_os = "illumos")))]
-        let worker = std::thread::available_parallelism().map_or(1, NonZeroUsize::get);
-        #[cfg(any(target_os = "solaris", target_os = "illumos"))]
-        let worker = 1;
-        let mut tasks = Vec::with_capacity(worker);
-        for i in 0..worker {
-            info!("ws worker {} starting...", i);
-            let acceptor = rustls_acceptor(tls)?;
-            let socket = match self.config.addr {
-                SocketAddr::V4(_) => TcpSocket::new_v4()?,
-                SocketAddr::V6(_) => TcpSocket::new_v6()?,
-            };
-            #[cfg(all(unix, not(target_os = "solaris"), not(target_os = "illumos")))]
-            socket.set_reuseport(true)?;
-            socket.bind(self.config.addr)?;
-            let listener = socket.listen(1024)?;
-            let task = tokio::spawn(async move {
-                while let Ok((stream, _addr)) = listener.accept().await {
-                    match acceptor.accept(stream).await {
-                        Ok(stream) => {
-                            let ws_stream =
-                                WsByteStream::new(accept_hdr_async(stream, ws_callback).await?);
-                            tokio::spawn(async move {
-                                process_client(ws_stream, self.config.version, self.global).await?;
-                                Ok::<(), Error>(())
-                            });
-                        }
-                        Err(err) => {
-                            warn!("accept WebSocket tls stream failed: {err}");
-                            continue;
-                        }
-                    }
-                }
-                Ok::<(), Error>(())
-            });
-            tasks.push(task);
+    pub async fn serve_tls(mut self) -> Result<(), Error> {
+        self.bind()?;
+        let acceptor = listener_acceptor(self.rustls.as_ref(), self.config.tls.as_ref())?;
+        let mut listeners = mem::take(&mut self.listeners);
+        listeners.append(&mut self.tls_listeners);
+        let mut tasks = Vec::with_capacity(listeners.len());
+        for listener in listeners {
+            tasks.push(self.spawn_wss(listener, acceptor.clone())?);
         }
         for task in tasks {
             let _ = task.await;
         }
         Ok(())
     }
+
+    #[cfg(feature = "ws")]
+    #[allow(clippy::result_large_err)]
+    fn spawn_ws(&self, listener: TcpListener) -> Result<JoinHandle<()>, Error> {
+        info!("ws listener on {} starting...", listener.local_addr()?);
+        let tenants = self.tenants.clone();
+        let mount_point = self.config.mount_point.clone();
+        let version = self.config.version;
+        let socket = self.config.socket.clone();
+        let ws_ping = self.config.ws_ping;
+        let default_global = self.global.clone();
+        Ok(tokio::spawn(async move {
+            loop {
+                let (stream, addr) = accept(&listener).await;
+                if let Err(err) = configure_stream(&stream, &socket) {
+                    warn!("configure socket of {addr} failed: {err}");
+                }
+                let tenants = tenants.clone();
+                let default_global = default_global.clone();
+                let mount_point = mount_point.clone();
+                tokio::spawn(async move {
+                    let timeout = default_global.handshake_timeout();
+                    let mut host = None;
+                    let Some(ws_stream) = handshake(
+                        addr,
+                        "WebSocket",
+                        timeout,
+                        accept_hdr_async(stream, with_host(&mut host)),
+                    )
+                    .await
+                    else {
+                        return Ok(());
+                    };
+                    let global = tenants.resolve(host.as_deref(), &default_global);
+                    process_client(
+                        WsByteStream::new(ws_stream).with_ping(ws_ping),
+                        Some(addr),
+                        version,
+                        mount_point,
+                        None,
+                        global,
+                    )
+                    .await?;
+                    Ok::<(), Error>(())
+                });
+            }
+        }))
+    }
+
+    #[cfg(feature = "wss")]
+    #[allow(clippy::result_large_err)]
+    fn spawn_wss(
+        &self,
+        listener: TcpListener,
+        acceptor: TlsAcceptor,
+    ) -> Result<JoinHandle<()>, Error> {
+        info!("wss listener on {} starting...", listener.local_addr()?);
+        let tenants = self.tenants.clone();
+        let mount_point = self.config.mount_point.clone();
+        let version = self.config.version;
+        let socket = self.config.socket.clone();
+        let ws_ping = self.config.ws_ping;
+        let default_global = self.global.clone();
+        Ok(tokio::spawn(async move {
+            loop {
+                let (stream, addr) = accept(&listener).await;
+                if let Err(err) = configure_stream(&stream, &socket) {
+                    warn!("configure socket of {addr} failed: {err}");
+                }
+                let acceptor = acceptor.clone();
+                let tenants = tenants.clone();
+                let default_global = default_global.clone();
+                let mount_point = mount_point.clone();
+                tokio::spawn(async move {
+                    // both handshakes share the timeout
+                    let timeout = default_global.handshake_timeout();
+                    let deadline = std::time::Instant::now() + timeout;
+                    let Some(stream) =
+                        handshake(addr, "tls", timeout, acceptor.accept(stream)).await
+                    else {
+                        return Ok(());
+                    };
+                    let mut host = stream.get_ref().1.server_name().map(str::to_owned);
+                    let Some(ws_stream) = handshake(
+                        addr,
+                        "WebSocket",
+                        deadline.saturating_duration_since(std::time::Instant::now()),
+                        accept_hdr_async(stream, with_host(&mut host)),
+                    )
+                    .await
+                    else {
+                        return Ok(());
+                    };
+                    let global = tenants.resolve(host.as_deref(), &default_global);
+                    process_client(
+                        WsByteStream::new(ws_stream).with_ping(ws_ping),
+                        Some(addr),
+                        version,
+                        mount_point,
+                        None,
+                        global,
+                    )
+                    .await?;
+                    Ok::<(), Error>(())
+                });
+            }
+        }))
+    }
+}
+
+/// [`ws_callback`] which also stores the `Host` header of the request in `host`, unless a host
+/// name, e.g. from SNI, is already set
+#[allow(clippy::result_large_err)]
+#[cfg(any(feature = "ws", feature = "wss"))]
+fn with_host(
+    host: &mut Option<String>,
+) -> impl FnOnce(&http::Request<()>, http::Response<()>) -> Result<http::Response<()>, ErrorResponse> + '_
+{
+    move |req, resp| {
+        if host.is_none() {
+            *host = req
+                .headers()
+                .get(http::header::HOST)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_owned);
+        }
+        ws_callback(req, resp)
+    }
 }
 
 #[allow(clippy::result_large_err)]
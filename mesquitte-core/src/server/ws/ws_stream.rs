@@ -1,18 +1,27 @@
 use std::{
-    cmp, io,
+    cmp,
+    future::Future,
+    io,
     pin::Pin,
     task::{Context, Poll},
+    time::Duration,
 };
 
 use bytes::Bytes;
 use futures::{ready, Sink, Stream};
 use pin_project_lite::pin_project;
-use tokio::io::{AsyncBufRead, AsyncRead, AsyncWrite, ReadBuf};
+use tokio::{
+    io::{AsyncBufRead, AsyncRead, AsyncWrite, ReadBuf},
+    time::{sleep, Instant, Sleep},
+};
 use tungstenite::Message;
 
+use crate::server::config::WsPing;
+
 struct State {
     read: ReadState,
     write: WriteState,
+    ping: Option<PingState>,
 }
 
 enum ReadState {
@@ -26,6 +35,87 @@ enum WriteState {
     Closed,
 }
 
+/// Pings sent while reading, a missing pong fails the read with [`io::ErrorKind::TimedOut`]
+struct PingState {
+    config: WsPing,
+    // next ping, or the deadline of the pong while `awaiting_pong`
+    timer: Pin<Box<Sleep>>,
+    awaiting_pong: bool,
+    // the ping was due while the sink was not ready
+    send: bool,
+    flush: bool,
+}
+
+impl PingState {
+    fn new(config: WsPing) -> Self {
+        Self {
+            config,
+            timer: Box::pin(sleep(config.interval)),
+            awaiting_pong: false,
+            send: false,
+            flush: false,
+        }
+    }
+
+    fn poll_ping<S>(&mut self, mut inner: Pin<&mut S>, cx: &mut Context<'_>) -> io::Result<()>
+    where
+        S: Sink<Message, Error = tungstenite::Error>,
+    {
+        if self.timer.as_mut().poll(cx).is_ready() {
+            if self.awaiting_pong {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "WebSocket pong timeout",
+                ));
+            }
+            self.awaiting_pong = true;
+            self.send = true;
+            self.reset(self.config.timeout, cx);
+        }
+        if self.send {
+            if let Poll::Ready(res) = inner.as_mut().poll_ready(cx) {
+                res.map_err(into_io_error)?;
+                inner
+                    .as_mut()
+                    .start_send(Message::Ping(Bytes::new()))
+                    .map_err(into_io_error)?;
+                self.send = false;
+                self.flush = true;
+            }
+        }
+        if self.flush {
+            if let Poll::Ready(res) = inner.poll_flush(cx) {
+                res.map_err(into_io_error)?;
+                self.flush = false;
+            }
+        }
+        Ok(())
+    }
+
+    fn pong(&mut self, cx: &mut Context<'_>) {
+        if self.awaiting_pong {
+            self.awaiting_pong = false;
+            self.reset(self.config.interval, cx);
+        }
+    }
+
+    fn reset(&mut self, after: Duration, cx: &mut Context<'_>) {
+        self.timer.as_mut().reset(Instant::now() + after);
+        // registers the waker for the new deadline
+        let _ = self.timer.as_mut().poll(cx);
+    }
+}
+
+fn into_io_error(err: tungstenite::Error) -> io::Error {
+    match err {
+        tungstenite::Error::Io(e) => e,
+        tungstenite::Error::ConnectionClosed | tungstenite::Error::AlreadyClosed => {
+            io::Error::new(io::ErrorKind::NotConnected, "Already closed")
+        }
+        err => io::Error::other(err),
+    }
+}
+
 pin_project! {
     pub struct WsByteStream<S> {
         #[pin]
@@ -46,16 +136,29 @@ where
             state: State {
                 read: ReadState::Pending,
                 write: WriteState::Ready,
+                ping: None,
             },
         }
     }
 
+    /// Sends WebSocket pings while the stream is read, see [`WsPing`]
+    pub fn with_ping(mut self, ping: Option<WsPing>) -> Self {
+        self.state.ping = ping.map(PingState::new);
+        self
+    }
+
     fn fill_buf_with_next_msg(
         self: Pin<&mut Self>,
         cx: &mut Context<'_>,
     ) -> Poll<Option<io::Result<()>>> {
         let mut this = self.project();
         loop {
+            if let Some(ping) = this.state.ping.as_mut() {
+                if let Err(e) = ping.poll_ping(this.inner.as_mut(), cx) {
+                    this.state.read = ReadState::Terminated;
+                    return Poll::Ready(Some(Err(e)));
+                }
+            }
             let res = ready!(this.inner.as_mut().poll_next(cx));
             let Some(res) = res else {
                 this.state.read = ReadState::Terminated;
@@ -74,6 +177,12 @@ where
                         this.state.read = ReadState::Terminated;
                         return Poll::Ready(None);
                     }
+                    Message::Pong(_) => {
+                        if let Some(ping) = this.state.ping.as_mut() {
+                            ping.pong(cx);
+                        }
+                        continue;
+                    }
                     _ => continue,
                 },
                 Err(e) => match e {
@@ -280,3 +389,53 @@ where
         Poll::Ready(Ok(()))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use futures::{SinkExt as _, StreamExt as _};
+    use tokio::io::{duplex, AsyncReadExt as _};
+    use tokio_tungstenite::WebSocketStream;
+    use tungstenite::protocol::Role;
+
+    use super::*;
+
+    fn ping() -> WsPing {
+        WsPing::new(Duration::from_millis(20), Duration::from_millis(20))
+    }
+
+    #[tokio::test]
+    async fn answered_pings_keep_the_stream_open() {
+        let (client, server) = duplex(1024);
+        let server = WebSocketStream::from_raw_socket(server, Role::Server, None).await;
+        let mut client = WebSocketStream::from_raw_socket(client, Role::Client, None).await;
+        let mut stream = WsByteStream::new(server).with_ping(Some(ping()));
+
+        let client = tokio::spawn(async move {
+            // reading answers the pings
+            let mut pings = 0;
+            while pings < 3 {
+                if let Some(Ok(Message::Ping(_))) = client.next().await {
+                    pings += 1;
+                }
+            }
+            client.send(Message::binary(vec![1, 2, 3])).await.unwrap();
+            client
+        });
+        let mut buf = [0; 3];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, [1, 2, 3]);
+        drop(client.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn missing_pong_times_out() {
+        let (_client, server) = duplex(1024);
+        let server = WebSocketStream::from_raw_socket(server, Role::Server, None).await;
+        let mut stream = WsByteStream::new(server).with_ping(Some(ping()));
+
+        // the client never reads its pings
+        let err = stream.read(&mut [0; 8]).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert_eq!(stream.read(&mut [0; 8]).await.unwrap(), 0);
+    }
+}
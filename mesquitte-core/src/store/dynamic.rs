@@ -0,0 +1,579 @@
+//! Storage backend chosen at runtime
+//!
+//! The store traits return `impl Future`, so a broker is generic over its storage. A
+//! [`DynStorage`] holds any backend behind a box and implements the store traits itself, so a
+//! `Broker<DynStorage>` can run with the backend named in a configuration file. Backends are
+//! looked up by name in a [`StorageRegistry`], which knows `"memory"` and takes the others with
+//! [`StorageRegistry::register`].
+
+use std::sync::Arc;
+
+use foldhash::{HashMap, HashMapExt};
+use futures::future::BoxFuture;
+use mqtt_codec_kit::common::{TopicFilter, TopicName};
+
+use super::{
+    error::StoreError,
+    memory::{
+        message::MessageMemoryStore, retain::RetainMessageMemoryStore, topic::TopicMemoryStore,
+        MemoryStore,
+    },
+    message::{
+        ClientMessages, MessageStore, PendingPublishMessage, PublishMessage, ReceiveOutcome,
+    },
+    retain::{RetainContent, RetainMessageStore},
+    topic::{SubscribeOutcome, SubscriptionOptions, TopicContent, TopicStore},
+    Page,
+};
+
+type StoreFuture<'a, T> = BoxFuture<'a, Result<T, StoreError>>;
+
+// object safe mirror of the store traits, implemented for every store
+trait ErasedStore: Send + Sync {
+    fn save_publish_message<'a>(
+        &'a self,
+        client_id: &'a str,
+        packet_id: u16,
+        message: PublishMessage,
+    ) -> StoreFuture<'a, ReceiveOutcome>;
+
+    fn pubrel<'a>(
+        &'a self,
+        client_id: &'a str,
+        packet_id: u16,
+    ) -> StoreFuture<'a, Option<PublishMessage>>;
+
+    fn save_pending_publish_message<'a>(
+        &'a self,
+        client_id: &'a str,
+        packet_id: u16,
+        message: PendingPublishMessage,
+    ) -> StoreFuture<'a, bool>;
+
+    fn try_get_pending_messages<'a>(
+        &'a self,
+        client_id: &'a str,
+    ) -> StoreFuture<'a, Option<Vec<(u16, PendingPublishMessage)>>>;
+
+    fn get_all_pending_messages<'a>(
+        &'a self,
+        client_id: &'a str,
+    ) -> StoreFuture<'a, Option<Vec<(u16, PendingPublishMessage)>>>;
+
+    fn get_pending_messages_page<'a>(
+        &'a self,
+        client_id: &'a str,
+        cursor: Option<&'a str>,
+        limit: usize,
+    ) -> StoreFuture<'a, Page<(u16, PendingPublishMessage)>>;
+
+    fn inspect_messages<'a>(&'a self, client_id: &'a str) -> StoreFuture<'a, ClientMessages>;
+
+    fn puback<'a>(&'a self, client_id: &'a str, packet_id: u16) -> StoreFuture<'a, bool>;
+
+    fn pubrec<'a>(&'a self, client_id: &'a str, packet_id: u16) -> StoreFuture<'a, bool>;
+
+    fn pubcomp<'a>(&'a self, client_id: &'a str, packet_id: u16) -> StoreFuture<'a, bool>;
+
+    fn is_packet_id_pending<'a>(
+        &'a self,
+        client_id: &'a str,
+        packet_id: u16,
+    ) -> StoreFuture<'a, bool>;
+
+    fn is_full<'a>(&'a self, client_id: &'a str) -> StoreFuture<'a, bool>;
+
+    fn message_count<'a>(&'a self, client_id: &'a str) -> StoreFuture<'a, usize>;
+
+    fn clear_all<'a>(&'a self, client_id: &'a str) -> StoreFuture<'a, ()>;
+
+    fn search<'a>(
+        &'a self,
+        topic_filter: &'a TopicFilter,
+    ) -> StoreFuture<'a, Vec<Arc<RetainContent>>>;
+
+    fn search_page<'a>(
+        &'a self,
+        topic_filter: &'a TopicFilter,
+        cursor: Option<&'a str>,
+        limit: usize,
+    ) -> StoreFuture<'a, Page<Arc<RetainContent>>>;
+
+    fn insert_retain(&self, content: RetainContent) -> StoreFuture<'_, Option<Arc<RetainContent>>>;
+
+    fn remove_retain<'a>(
+        &'a self,
+        topic_name: &'a TopicName,
+    ) -> StoreFuture<'a, Option<Arc<RetainContent>>>;
+
+    fn match_topic<'a>(&'a self, topic_name: &'a TopicName) -> StoreFuture<'a, Vec<TopicContent>>;
+
+    fn subscribe<'a>(
+        &'a self,
+        client_id: &'a str,
+        topic_filter: &'a TopicFilter,
+        options: SubscriptionOptions,
+    ) -> StoreFuture<'a, SubscribeOutcome>;
+
+    fn unsubscribe<'a>(
+        &'a self,
+        client_id: &'a str,
+        topic_filter: &'a TopicFilter,
+    ) -> StoreFuture<'a, bool>;
+}
+
+impl<S> ErasedStore for S
+where
+    S: MessageStore + RetainMessageStore + TopicStore,
+{
+    fn save_publish_message<'a>(
+        &'a self,
+        client_id: &'a str,
+        packet_id: u16,
+        message: PublishMessage,
+    ) -> StoreFuture<'a, ReceiveOutcome> {
+        Box::pin(MessageStore::save_publish_message(
+            self, client_id, packet_id, message,
+        ))
+    }
+
+    fn pubrel<'a>(
+        &'a self,
+        client_id: &'a str,
+        packet_id: u16,
+    ) -> StoreFuture<'a, Option<PublishMessage>> {
+        Box::pin(MessageStore::pubrel(self, client_id, packet_id))
+    }
+
+    fn save_pending_publish_message<'a>(
+        &'a self,
+        client_id: &'a str,
+        packet_id: u16,
+        message: PendingPublishMessage,
+    ) -> StoreFuture<'a, bool> {
+        Box::pin(MessageStore::save_pending_publish_message(
+            self, client_id, packet_id, message,
+        ))
+    }
+
+    fn try_get_pending_messages<'a>(
+        &'a self,
+        client_id: &'a str,
+    ) -> StoreFuture<'a, Option<Vec<(u16, PendingPublishMessage)>>> {
+        Box::pin(MessageStore::try_get_pending_messages(self, client_id))
+    }
+
+    fn get_all_pending_messages<'a>(
+        &'a self,
+        client_id: &'a str,
+    ) -> StoreFuture<'a, Option<Vec<(u16, PendingPublishMessage)>>> {
+        Box::pin(MessageStore::get_all_pending_messages(self, client_id))
+    }
+
+    fn get_pending_messages_page<'a>(
+        &'a self,
+        client_id: &'a str,
+        cursor: Option<&'a str>,
+        limit: usize,
+    ) -> StoreFuture<'a, Page<(u16, PendingPublishMessage)>> {
+        Box::pin(MessageStore::get_pending_messages_page(
+            self, client_id, cursor, limit,
+        ))
+    }
+
+    fn inspect_messages<'a>(&'a self, client_id: &'a str) -> StoreFuture<'a, ClientMessages> {
+        Box::pin(MessageStore::inspect_messages(self, client_id))
+    }
+
+    fn puback<'a>(&'a self, client_id: &'a str, packet_id: u16) -> StoreFuture<'a, bool> {
+        Box::pin(MessageStore::puback(self, client_id, packet_id))
+    }
+
+    fn pubrec<'a>(&'a self, client_id: &'a str, packet_id: u16) -> StoreFuture<'a, bool> {
+        Box::pin(MessageStore::pubrec(self, client_id, packet_id))
+    }
+
+    fn pubcomp<'a>(&'a self, client_id: &'a str, packet_id: u16) -> StoreFuture<'a, bool> {
+        Box::pin(MessageStore::pubcomp(self, client_id, packet_id))
+    }
+
+    fn is_packet_id_pending<'a>(
+        &'a self,
+        client_id: &'a str,
+        packet_id: u16,
+    ) -> StoreFuture<'a, bool> {
+        Box::pin(MessageStore::is_packet_id_pending(
+            self, client_id, packet_id,
+        ))
+    }
+
+    fn is_full<'a>(&'a self, client_id: &'a str) -> StoreFuture<'a, bool> {
+        Box::pin(MessageStore::is_full(self, client_id))
+    }
+
+    fn message_count<'a>(&'a self, client_id: &'a str) -> StoreFuture<'a, usize> {
+        Box::pin(MessageStore::message_count(self, client_id))
+    }
+
+    fn clear_all<'a>(&'a self, client_id: &'a str) -> StoreFuture<'a, ()> {
+        Box::pin(MessageStore::clear_all(self, client_id))
+    }
+
+    fn search<'a>(
+        &'a self,
+        topic_filter: &'a TopicFilter,
+    ) -> StoreFuture<'a, Vec<Arc<RetainContent>>> {
+        Box::pin(RetainMessageStore::search(self, topic_filter))
+    }
+
+    fn search_page<'a>(
+        &'a self,
+        topic_filter: &'a TopicFilter,
+        cursor: Option<&'a str>,
+        limit: usize,
+    ) -> StoreFuture<'a, Page<Arc<RetainContent>>> {
+        Box::pin(RetainMessageStore::search_page(
+            self,
+            topic_filter,
+            cursor,
+            limit,
+        ))
+    }
+
+    fn insert_retain(&self, content: RetainContent) -> StoreFuture<'_, Option<Arc<RetainContent>>> {
+        Box::pin(RetainMessageStore::insert(self, content))
+    }
+
+    fn remove_retain<'a>(
+        &'a self,
+        topic_name: &'a TopicName,
+    ) -> StoreFuture<'a, Option<Arc<RetainContent>>> {
+        Box::pin(RetainMessageStore::remove(self, topic_name))
+    }
+
+    fn match_topic<'a>(&'a self, topic_name: &'a TopicName) -> StoreFuture<'a, Vec<TopicContent>> {
+        Box::pin(TopicStore::match_topic(self, topic_name))
+    }
+
+    fn subscribe<'a>(
+        &'a self,
+        client_id: &'a str,
+        topic_filter: &'a TopicFilter,
+        options: SubscriptionOptions,
+    ) -> StoreFuture<'a, SubscribeOutcome> {
+        Box::pin(TopicStore::subscribe(
+            self,
+            client_id,
+            topic_filter,
+            options,
+        ))
+    }
+
+    fn unsubscribe<'a>(
+        &'a self,
+        client_id: &'a str,
+        topic_filter: &'a TopicFilter,
+    ) -> StoreFuture<'a, bool> {
+        Box::pin(TopicStore::unsubscribe(self, client_id, topic_filter))
+    }
+}
+
+/// Any storage backend, see [`self`](super::dynamic)
+///
+/// Each call goes through a boxed future, the price of not knowing the backend at compile
+/// time.
+pub struct DynStorage(Box<dyn ErasedStore>);
+
+impl DynStorage {
+    pub fn new<S>(store: S) -> Self
+    where
+        S: MessageStore + RetainMessageStore + TopicStore + 'static,
+    {
+        Self(Box::new(store))
+    }
+}
+
+impl MessageStore for DynStorage {
+    async fn save_publish_message(
+        &self,
+        client_id: &str,
+        packet_id: u16,
+        message: PublishMessage,
+    ) -> Result<ReceiveOutcome, StoreError> {
+        self.0
+            .save_publish_message(client_id, packet_id, message)
+            .await
+    }
+
+    async fn pubrel(
+        &self,
+        client_id: &str,
+        packet_id: u16,
+    ) -> Result<Option<PublishMessage>, StoreError> {
+        self.0.pubrel(client_id, packet_id).await
+    }
+
+    async fn save_pending_publish_message(
+        &self,
+        client_id: &str,
+        packet_id: u16,
+        message: PendingPublishMessage,
+    ) -> Result<bool, StoreError> {
+        self.0
+            .save_pending_publish_message(client_id, packet_id, message)
+            .await
+    }
+
+    async fn try_get_pending_messages(
+        &self,
+        client_id: &str,
+    ) -> Result<Option<Vec<(u16, PendingPublishMessage)>>, StoreError> {
+        self.0.try_get_pending_messages(client_id).await
+    }
+
+    async fn get_all_pending_messages(
+        &self,
+        client_id: &str,
+    ) -> Result<Option<Vec<(u16, PendingPublishMessage)>>, StoreError> {
+        self.0.get_all_pending_messages(client_id).await
+    }
+
+    async fn get_pending_messages_page(
+        &self,
+        client_id: &str,
+        cursor: Option<&str>,
+        limit: usize,
+    ) -> Result<Page<(u16, PendingPublishMessage)>, StoreError> {
+        self.0
+            .get_pending_messages_page(client_id, cursor, limit)
+            .await
+    }
+
+    async fn inspect_messages(&self, client_id: &str) -> Result<ClientMessages, StoreError> {
+        self.0.inspect_messages(client_id).await
+    }
+
+    async fn puback(&self, client_id: &str, packet_id: u16) -> Result<bool, StoreError> {
+        self.0.puback(client_id, packet_id).await
+    }
+
+    async fn pubrec(&self, client_id: &str, packet_id: u16) -> Result<bool, StoreError> {
+        self.0.pubrec(client_id, packet_id).await
+    }
+
+    async fn pubcomp(&self, client_id: &str, packet_id: u16) -> Result<bool, StoreError> {
+        self.0.pubcomp(client_id, packet_id).await
+    }
+
+    async fn is_packet_id_pending(
+        &self,
+        client_id: &str,
+        packet_id: u16,
+    ) -> Result<bool, StoreError> {
+        self.0.is_packet_id_pending(client_id, packet_id).await
+    }
+
+    async fn is_full(&self, client_id: &str) -> Result<bool, StoreError> {
+        self.0.is_full(client_id).await
+    }
+
+    async fn message_count(&self, client_id: &str) -> Result<usize, StoreError> {
+        self.0.message_count(client_id).await
+    }
+
+    async fn clear_all(&self, client_id: &str) -> Result<(), StoreError> {
+        self.0.clear_all(client_id).await
+    }
+}
+
+impl RetainMessageStore for DynStorage {
+    async fn search(
+        &self,
+        topic_filter: &TopicFilter,
+    ) -> Result<Vec<Arc<RetainContent>>, StoreError> {
+        self.0.search(topic_filter).await
+    }
+
+    async fn search_page(
+        &self,
+        topic_filter: &TopicFilter,
+        cursor: Option<&str>,
+        limit: usize,
+    ) -> Result<Page<Arc<RetainContent>>, StoreError> {
+        self.0.search_page(topic_filter, cursor, limit).await
+    }
+
+    async fn insert(
+        &self,
+        content: RetainContent,
+    ) -> Result<Option<Arc<RetainContent>>, StoreError> {
+        self.0.insert_retain(content).await
+    }
+
+    async fn remove(
+        &self,
+        topic_name: &TopicName,
+    ) -> Result<Option<Arc<RetainContent>>, StoreError> {
+        self.0.remove_retain(topic_name).await
+    }
+}
+
+impl TopicStore for DynStorage {
+    async fn match_topic(&self, topic_name: &TopicName) -> Result<Vec<TopicContent>, StoreError> {
+        self.0.match_topic(topic_name).await
+    }
+
+    async fn subscribe(
+        &self,
+        client_id: &str,
+        topic_filter: &TopicFilter,
+        options: SubscriptionOptions,
+    ) -> Result<SubscribeOutcome, StoreError> {
+        self.0.subscribe(client_id, topic_filter, options).await
+    }
+
+    async fn unsubscribe(
+        &self,
+        client_id: &str,
+        topic_filter: &TopicFilter,
+    ) -> Result<bool, StoreError> {
+        self.0.unsubscribe(client_id, topic_filter).await
+    }
+}
+
+type StorageFactory = Box<dyn Fn() -> Result<DynStorage, StoreError> + Send + Sync>;
+
+/// Storage backends by name, `"memory"` is always known
+pub struct StorageRegistry {
+    factories: HashMap<String, StorageFactory>,
+}
+
+impl Default for StorageRegistry {
+    fn default() -> Self {
+        let mut registry = Self {
+            factories: HashMap::new(),
+        };
+        registry.register("memory", || {
+            let message_store = MessageMemoryStore::new(102400, 30, 3);
+            Ok(DynStorage::new(MemoryStore::new(
+                message_store,
+                RetainMessageMemoryStore::default(),
+                TopicMemoryStore::default(),
+            )))
+        });
+        registry
+    }
+}
+
+impl StorageRegistry {
+    /// Makes `factory` build the backend called `name`, replacing a backend of the same name
+    pub fn register<F>(&mut self, name: &str, factory: F)
+    where
+        F: Fn() -> Result<DynStorage, StoreError> + Send + Sync + 'static,
+    {
+        self.factories.insert(name.to_owned(), Box::new(factory));
+    }
+
+    /// Builds the backend called `name`, [`StoreError::NotFound`] if it was not registered
+    pub fn build(&self, name: &str) -> Result<DynStorage, StoreError> {
+        match self.factories.get(name) {
+            Some(factory) => factory(),
+            None => Err(StoreError::NotFound(format!("storage backend {name}"))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mqtt_codec_kit::common::{QualityOfService, TopicFilter, TopicName};
+
+    // not `super::*`, the methods of `ErasedStore` would shadow the store traits
+    use super::{DynStorage, StorageRegistry};
+    use crate::store::{
+        error::StoreError,
+        memory_storage,
+        message::{MessageStore, PublishMessage, ReceiveOutcome},
+        retain::RetainMessageStore,
+        topic::TopicStore,
+    };
+
+    /// Goes through the message, retain and topic stores of `storage`
+    async fn round_trip(storage: &DynStorage) {
+        let topic_name = TopicName::new("a/b").unwrap();
+        let message = PublishMessage::new(
+            topic_name.clone(),
+            b"x".to_vec(),
+            QualityOfService::Level2,
+            true,
+        );
+
+        assert_eq!(
+            storage
+                .save_publish_message("c1", 1, message.clone())
+                .await
+                .unwrap(),
+            ReceiveOutcome::Received
+        );
+        assert_eq!(
+            storage
+                .save_publish_message("c1", 1, message.clone())
+                .await
+                .unwrap(),
+            ReceiveOutcome::Duplicate
+        );
+        let released = storage.pubrel("c1", 1).await.unwrap().unwrap();
+        assert_eq!(released.topic_name(), &topic_name);
+        assert!(storage.pubrel("c1", 1).await.unwrap().is_none());
+
+        let filter = TopicFilter::new("a/+").unwrap();
+        RetainMessageStore::insert(storage, ("c1", &message).into())
+            .await
+            .unwrap();
+        let retained = storage.search(&filter).await.unwrap();
+        assert_eq!(retained.len(), 1);
+        assert_eq!(retained[0].topic_name(), &topic_name);
+        assert!(RetainMessageStore::remove(storage, &topic_name)
+            .await
+            .unwrap()
+            .is_some());
+        assert!(storage.search(&filter).await.unwrap().is_empty());
+
+        storage
+            .subscribe("c2", &filter, QualityOfService::Level1.into())
+            .await
+            .unwrap();
+        let contents = storage.match_topic(&topic_name).await.unwrap();
+        assert!(contents.iter().any(|c| c.clients.contains_key("c2")));
+        assert!(storage.unsubscribe("c2", &filter).await.unwrap());
+        let contents = storage.match_topic(&topic_name).await.unwrap();
+        assert!(contents.iter().all(|c| c.clients.is_empty()));
+    }
+
+    #[tokio::test]
+    async fn memory_backend() {
+        let storage = StorageRegistry::default().build("memory").unwrap();
+        round_trip(&storage).await;
+    }
+
+    #[tokio::test]
+    async fn registered_backend() {
+        let mut registry = StorageRegistry::default();
+        registry.register("custom", || Ok(DynStorage::new(memory_storage().0)));
+        round_trip(&registry.build("custom").unwrap()).await;
+    }
+
+    #[tokio::test]
+    async fn nested_dyn_storage() {
+        let inner = DynStorage::new(memory_storage().0);
+        round_trip(&DynStorage::new(inner)).await;
+    }
+
+    #[test]
+    fn unknown_backend_is_not_found() {
+        let registry = StorageRegistry::default();
+        assert!(matches!(
+            registry.build("rocksdb"),
+            Err(StoreError::NotFound(_))
+        ));
+    }
+}
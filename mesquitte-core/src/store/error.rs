@@ -0,0 +1,36 @@
+use std::{error::Error as StdError, io};
+
+/// Error of the store traits
+///
+/// Backends wrap their own errors in [`StoreError::Backend`] or [`StoreError::Serialization`],
+/// the protocol loops turn it into [`crate::protocols::Error::Storage`].
+#[derive(Debug, thiserror::Error)]
+pub enum StoreError {
+    #[error("not found: {0}")]
+    NotFound(String),
+    /// The write conflicts with the stored state, e.g. a concurrent update of the same key
+    #[error("conflict: {0}")]
+    Conflict(String),
+    /// The backend failed, e.g. a database or network error
+    #[error("backend error: {0}")]
+    Backend(Box<dyn StdError + Send + Sync>),
+    /// A stored value could not be encoded or decoded
+    #[error("serialization error: {0}")]
+    Serialization(Box<dyn StdError + Send + Sync>),
+}
+
+impl StoreError {
+    pub fn backend<E: Into<Box<dyn StdError + Send + Sync>>>(err: E) -> Self {
+        Self::Backend(err.into())
+    }
+
+    pub fn serialization<E: Into<Box<dyn StdError + Send + Sync>>>(err: E) -> Self {
+        Self::Serialization(err.into())
+    }
+}
+
+impl From<io::Error> for StoreError {
+    fn from(err: io::Error) -> Self {
+        Self::Backend(Box::new(err))
+    }
+}
@@ -0,0 +1,148 @@
+//! Session state as JSON, to move a session to another broker or look into it
+//!
+//! An export holds the persisted session of a client, with its subscriptions and will, and the
+//! messages kept for it: the QoS 2 messages it sent which are not released yet and the messages
+//! to it which are not acknowledged. See
+//! [`GlobalState::export_session`](crate::server::state::GlobalState::export_session) and
+//! [`GlobalState::import_session`](crate::server::state::GlobalState::import_session).
+
+use serde::{Deserialize, Serialize};
+
+use super::{error::StoreError, format::v2, message::ClientMessages, session::StoredSession};
+
+/// Version written by [`SessionExport::to_json`], version 2 keeps the MQTT 5 options of the
+/// subscriptions
+pub const EXPORT_VERSION: u8 = 2;
+
+#[derive(Debug)]
+pub struct SessionExport {
+    pub client_id: String,
+    /// `None` when the session is not persisted, the subscriptions are then not exported
+    pub session: Option<StoredSession>,
+    pub messages: ClientMessages,
+}
+
+// messages keep the records of format version 2 whatever the store writes, an export only
+// changes with its own version
+#[derive(Serialize, Deserialize)]
+struct Export {
+    version: u8,
+    client_id: String,
+    session: Option<StoredSession>,
+    received: Vec<(u16, v2::PublishMessage)>,
+    pending: Vec<v2::PendingPublishMessage>,
+}
+
+impl SessionExport {
+    pub fn to_json(&self) -> Result<String, StoreError> {
+        let export = Export {
+            version: EXPORT_VERSION,
+            client_id: self.client_id.clone(),
+            session: self.session.clone(),
+            received: self
+                .messages
+                .received
+                .iter()
+                .map(|(packet_id, message)| (*packet_id, message.into()))
+                .collect(),
+            pending: self
+                .messages
+                .pending
+                .iter()
+                .map(|(_, pending)| pending.into())
+                .collect(),
+        };
+        serde_json::to_string_pretty(&export).map_err(StoreError::serialization)
+    }
+
+    pub fn from_json(json: &str) -> Result<Self, StoreError> {
+        let export: Export = serde_json::from_str(json).map_err(StoreError::serialization)?;
+        if export.version != EXPORT_VERSION {
+            return Err(StoreError::serialization(format!(
+                "export version {}, expected {EXPORT_VERSION}",
+                export.version
+            )));
+        }
+        if let Some(session) = &export.session {
+            if session.client_id != export.client_id {
+                return Err(StoreError::serialization(format!(
+                    "session of {} exported for {}",
+                    session.client_id, export.client_id
+                )));
+            }
+        }
+
+        let mut messages = ClientMessages::default();
+        for (packet_id, message) in export.received {
+            messages.received.push((packet_id, message.try_into()?));
+        }
+        for pending in export.pending {
+            messages
+                .pending
+                .push((pending.packet_id, pending.try_into()?));
+        }
+        Ok(Self {
+            client_id: export.client_id,
+            session: export.session,
+            messages,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mqtt_codec_kit::common::{qos::QoSWithPacketIdentifier, QualityOfService, TopicName};
+
+    use super::*;
+    use crate::store::{
+        message::{PendingPublishMessage, PublishMessage},
+        session::{StoredSubscribeOptions, StoredSubscription},
+    };
+
+    #[test]
+    fn json_round_trip() {
+        let message = PublishMessage::new(
+            TopicName::new("a/b").unwrap(),
+            b"hi".to_vec(),
+            QualityOfService::Level2,
+            false,
+        );
+        let pending =
+            PendingPublishMessage::new(QoSWithPacketIdentifier::Level1(3), message.clone());
+        let export = SessionExport {
+            client_id: "c".to_owned(),
+            session: Some(StoredSession {
+                client_id: "c".to_owned(),
+                server_packet_id: 4,
+                subscriptions: vec![StoredSubscription {
+                    topic_filter: "a/+".to_owned(),
+                    qos: 1,
+                    v5: Some(StoredSubscribeOptions {
+                        no_local: true,
+                        ..Default::default()
+                    }),
+                }],
+                will: None,
+                expire_at: None,
+            }),
+            messages: ClientMessages {
+                received: vec![(9, message)],
+                pending: vec![(3, pending)],
+            },
+        };
+
+        let json = export.to_json().unwrap();
+        let imported = SessionExport::from_json(&json).unwrap();
+        assert_eq!(imported.client_id, "c");
+        assert_eq!(imported.session, export.session);
+        let (packet_id, message) = &imported.messages.received[0];
+        assert_eq!(*packet_id, 9);
+        assert_eq!(message.payload(), b"hi");
+        let (packet_id, pending) = &imported.messages.pending[0];
+        assert_eq!(*packet_id, 3);
+        assert_eq!(pending.qos(), QoSWithPacketIdentifier::Level1(3));
+
+        let newer = json.replace("\"version\": 2", "\"version\": 3");
+        assert!(SessionExport::from_json(&newer).is_err());
+    }
+}
@@ -0,0 +1,609 @@
+//! Versioned binary format of the stored state
+//!
+//! A record starts with a header of the magic bytes `MQ`, the format version and the kind of the
+//! record, followed by the bincode encoding of the record as defined by that version. The
+//! persistent stores, the cluster snapshots and migration tools share the format, and a record
+//! written by an older version is upgraded when it is decoded.
+//!
+//! The definitions of a released version never change, a change of a record adds a new version
+//! with its own definitions and an upgrade from the previous one.
+
+use mqtt_codec_kit::common::{qos::QoSWithPacketIdentifier, QualityOfService, TopicName};
+#[cfg(feature = "v5")]
+use mqtt_codec_kit::v5::control::PublishProperties;
+use serde::{de::DeserializeOwned, Serialize};
+
+use super::{
+    error::StoreError,
+    message::{PendingPublishMessage, PublishMessage},
+    session::{
+        StoredPresence, StoredSession, StoredSubscribeOptions, StoredSubscription, StoredWill,
+    },
+};
+
+/// Version written by [`encode`], [`decode`] reads it and every version before it
+pub const FORMAT_VERSION: u8 = 3;
+
+const MAGIC: [u8; 2] = *b"MQ";
+const HEADER_LEN: usize = 4;
+
+/// A value stored in the versioned format
+pub trait Record: Sized {
+    /// Tells the kinds of records apart, so a record is never decoded as another one
+    const KIND: u8;
+
+    /// The body of the record in [`FORMAT_VERSION`]
+    fn encode_body(&self) -> Result<Vec<u8>, StoreError>;
+
+    /// Decodes a body written by `version`, upgrading it to the current definition
+    fn decode_body(version: u8, body: &[u8]) -> Result<Self, StoreError>;
+}
+
+pub fn encode<T: Record>(record: &T) -> Result<Vec<u8>, StoreError> {
+    let body = record.encode_body()?;
+    let mut bytes = Vec::with_capacity(HEADER_LEN + body.len());
+    bytes.extend_from_slice(&MAGIC);
+    bytes.push(FORMAT_VERSION);
+    bytes.push(T::KIND);
+    bytes.extend_from_slice(&body);
+    Ok(bytes)
+}
+
+pub fn decode<T: Record>(bytes: &[u8]) -> Result<T, StoreError> {
+    if !is_versioned(bytes) {
+        return Err(StoreError::serialization("missing format header"));
+    }
+    let (version, kind) = (bytes[2], bytes[3]);
+    if kind != T::KIND {
+        return Err(StoreError::serialization(format!(
+            "record kind {kind}, expected {}",
+            T::KIND
+        )));
+    }
+    if version > FORMAT_VERSION {
+        return Err(StoreError::serialization(format!(
+            "format version {version} is newer than {FORMAT_VERSION}"
+        )));
+    }
+    T::decode_body(version, &bytes[HEADER_LEN..])
+}
+
+/// Whether `bytes` start with the format header
+///
+/// Records of stores written before the format, e.g. a bare bincode [`StoredSession`] which
+/// starts with the length of the client id, never have a version byte other than 0 there.
+pub fn is_versioned(bytes: &[u8]) -> bool {
+    bytes.len() >= HEADER_LEN && bytes[..2] == MAGIC && bytes[2] != 0
+}
+
+fn to_bincode<T: Serialize>(value: &T) -> Result<Vec<u8>, StoreError> {
+    bincode::serialize(value).map_err(StoreError::serialization)
+}
+
+fn from_bincode<T: DeserializeOwned>(body: &[u8]) -> Result<T, StoreError> {
+    bincode::deserialize(body).map_err(StoreError::serialization)
+}
+
+fn unknown_version(version: u8) -> StoreError {
+    StoreError::serialization(format!("unknown format version {version}"))
+}
+
+fn qos(value: u8) -> Result<QualityOfService, StoreError> {
+    match value {
+        0 => Ok(QualityOfService::Level0),
+        1 => Ok(QualityOfService::Level1),
+        2 => Ok(QualityOfService::Level2),
+        _ => Err(StoreError::serialization(format!("invalid qos {value}"))),
+    }
+}
+
+/// The records of format version 1
+mod v1 {
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Serialize, Deserialize)]
+    pub struct Will {
+        pub topic_name: String,
+        pub payload: Vec<u8>,
+        pub qos: u8,
+        pub retain: bool,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    pub struct Session {
+        pub client_id: String,
+        pub server_packet_id: u16,
+        pub subscriptions: Vec<(String, u8)>,
+        pub will: Option<Will>,
+        pub expire_at: Option<u64>,
+    }
+
+    /// The MQTT 5 properties kept with a message
+    #[derive(Serialize, Deserialize)]
+    pub struct Properties {
+        pub payload_format_indicator: Option<u8>,
+        pub message_expiry_interval: Option<u32>,
+        pub response_topic: Option<String>,
+        pub correlation_data: Option<Vec<u8>>,
+        pub user_properties: Vec<(String, String)>,
+        pub content_type: Option<String>,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    pub struct PublishMessage {
+        pub topic_name: String,
+        pub payload: Vec<u8>,
+        pub qos: u8,
+        pub retain: bool,
+        pub dup: bool,
+        pub properties: Option<Properties>,
+        pub received_at: u64,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    pub struct PendingPublishMessage {
+        pub message: PublishMessage,
+        pub qos: u8,
+        pub packet_id: u16,
+        pub dup: bool,
+        pub pubrec_at: Option<u64>,
+    }
+}
+
+/// The records of format version 2, a pending message counts how often it was sent
+pub(super) mod v2 {
+    use serde::{Deserialize, Serialize};
+
+    pub use super::v1::{PublishMessage, Will};
+
+    #[derive(Serialize, Deserialize)]
+    pub struct PendingPublishMessage {
+        pub message: PublishMessage,
+        pub qos: u8,
+        pub packet_id: u16,
+        pub dup: bool,
+        pub send_attempts: u32,
+        pub pubrec_at: Option<u64>,
+    }
+
+    /// Added in version 2
+    #[derive(Serialize, Deserialize)]
+    pub struct Presence {
+        pub client_id: String,
+        pub connected_at: Option<u64>,
+        pub disconnected_at: Option<u64>,
+        pub ip_address: Option<String>,
+    }
+}
+
+/// The records of format version 3, a subscription keeps its MQTT 5 options
+pub(super) mod v3 {
+    use serde::{Deserialize, Serialize};
+
+    pub use super::v2::{PendingPublishMessage, Presence, PublishMessage, Will};
+
+    #[derive(Serialize, Deserialize)]
+    pub struct SubscribeOptions {
+        pub no_local: bool,
+        pub retain_as_published: bool,
+        pub retain_handling: u8,
+        pub identifier: Option<u32>,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    pub struct Subscription {
+        pub topic_filter: String,
+        pub qos: u8,
+        pub v5: Option<SubscribeOptions>,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    pub struct Session {
+        pub client_id: String,
+        pub server_packet_id: u16,
+        pub subscriptions: Vec<Subscription>,
+        pub will: Option<Will>,
+        pub expire_at: Option<u64>,
+    }
+}
+
+/// Decodes a [`StoredSession`] saved as bare bincode before the versioned format, laid out as
+/// the session of version 1
+pub fn decode_unversioned_session(bytes: &[u8]) -> Result<StoredSession, StoreError> {
+    from_bincode::<v1::Session>(bytes).map(upgrade_session)
+}
+
+// sessions before version 3 only kept the QoS of their subscriptions
+fn upgrade_session(session: v1::Session) -> StoredSession {
+    StoredSession {
+        client_id: session.client_id,
+        server_packet_id: session.server_packet_id,
+        subscriptions: session
+            .subscriptions
+            .into_iter()
+            .map(|(topic_filter, qos)| StoredSubscription {
+                topic_filter,
+                qos,
+                v5: None,
+            })
+            .collect(),
+        will: session.will.map(StoredWill::from),
+        expire_at: session.expire_at,
+    }
+}
+
+impl From<v1::Will> for StoredWill {
+    fn from(will: v1::Will) -> Self {
+        StoredWill {
+            topic_name: will.topic_name,
+            payload: will.payload,
+            qos: will.qos,
+            retain: will.retain,
+        }
+    }
+}
+
+impl Record for StoredSession {
+    const KIND: u8 = 1;
+
+    fn encode_body(&self) -> Result<Vec<u8>, StoreError> {
+        to_bincode(&v3::Session {
+            client_id: self.client_id.clone(),
+            server_packet_id: self.server_packet_id,
+            subscriptions: self
+                .subscriptions
+                .iter()
+                .map(|subscription| v3::Subscription {
+                    topic_filter: subscription.topic_filter.clone(),
+                    qos: subscription.qos,
+                    v5: subscription
+                        .v5
+                        .as_ref()
+                        .map(|options| v3::SubscribeOptions {
+                            no_local: options.no_local,
+                            retain_as_published: options.retain_as_published,
+                            retain_handling: options.retain_handling,
+                            identifier: options.identifier,
+                        }),
+                })
+                .collect(),
+            will: self.will.as_ref().map(|will| v3::Will {
+                topic_name: will.topic_name.clone(),
+                payload: will.payload.clone(),
+                qos: will.qos,
+                retain: will.retain,
+            }),
+            expire_at: self.expire_at,
+        })
+    }
+
+    fn decode_body(version: u8, body: &[u8]) -> Result<Self, StoreError> {
+        let session: v3::Session = match version {
+            // unchanged in version 2
+            1 | 2 => return from_bincode::<v1::Session>(body).map(upgrade_session),
+            3 => from_bincode(body)?,
+            _ => return Err(unknown_version(version)),
+        };
+        Ok(StoredSession {
+            client_id: session.client_id,
+            server_packet_id: session.server_packet_id,
+            subscriptions: session
+                .subscriptions
+                .into_iter()
+                .map(|subscription| StoredSubscription {
+                    topic_filter: subscription.topic_filter,
+                    qos: subscription.qos,
+                    v5: subscription.v5.map(|options| StoredSubscribeOptions {
+                        no_local: options.no_local,
+                        retain_as_published: options.retain_as_published,
+                        retain_handling: options.retain_handling,
+                        identifier: options.identifier,
+                    }),
+                })
+                .collect(),
+            will: session.will.map(StoredWill::from),
+            expire_at: session.expire_at,
+        })
+    }
+}
+
+impl From<&PublishMessage> for v1::PublishMessage {
+    fn from(message: &PublishMessage) -> Self {
+        #[cfg(feature = "v5")]
+        let (properties, received_at) = (
+            message.properties.as_ref().map(|p| v1::Properties {
+                payload_format_indicator: p.payload_format_indicator(),
+                message_expiry_interval: p.message_expiry_interval(),
+                response_topic: p.response_topic().clone(),
+                correlation_data: p.correlation_data().clone().map(|data| data.0),
+                user_properties: p.user_properties().to_vec(),
+                content_type: p.content_type().clone(),
+            }),
+            message.received_at,
+        );
+        // without MQTT 5 there is no expiry interval the receive time would count for
+        #[cfg(not(feature = "v5"))]
+        let (properties, received_at) = (None, 0);
+        v1::PublishMessage {
+            topic_name: message.topic_name.to_string(),
+            payload: message.payload.clone(),
+            qos: message.qos as u8,
+            retain: message.retain,
+            dup: message.dup,
+            properties,
+            received_at,
+        }
+    }
+}
+
+impl TryFrom<v1::PublishMessage> for PublishMessage {
+    type Error = StoreError;
+
+    fn try_from(message: v1::PublishMessage) -> Result<Self, StoreError> {
+        #[cfg(feature = "v5")]
+        let properties = message.properties.map(|p| {
+            let mut properties = PublishProperties::default();
+            properties.set_payload_format_indicator(p.payload_format_indicator);
+            properties.set_message_expiry_interval(p.message_expiry_interval);
+            properties.set_response_topic(p.response_topic);
+            properties.set_correlation_data(p.correlation_data);
+            for (key, value) in p.user_properties {
+                properties.add_user_property(key, value);
+            }
+            properties.set_content_type(p.content_type);
+            properties
+        });
+        Ok(PublishMessage {
+            topic_name: TopicName::new(message.topic_name).map_err(StoreError::serialization)?,
+            payload: message.payload,
+            qos: qos(message.qos)?,
+            retain: message.retain,
+            dup: message.dup,
+            #[cfg(feature = "v5")]
+            properties,
+            #[cfg(feature = "v5")]
+            received_at: message.received_at,
+        })
+    }
+}
+
+impl Record for PublishMessage {
+    const KIND: u8 = 2;
+
+    fn encode_body(&self) -> Result<Vec<u8>, StoreError> {
+        to_bincode(&v3::PublishMessage::from(self))
+    }
+
+    fn decode_body(version: u8, body: &[u8]) -> Result<Self, StoreError> {
+        // unchanged in versions 2 and 3
+        if !matches!(version, 1..=3) {
+            return Err(unknown_version(version));
+        }
+        from_bincode::<v3::PublishMessage>(body)?.try_into()
+    }
+}
+
+impl From<&PendingPublishMessage> for v2::PendingPublishMessage {
+    fn from(pending: &PendingPublishMessage) -> Self {
+        let (qos, packet_id) = pending.qos.split();
+        v2::PendingPublishMessage {
+            message: (&pending.message).into(),
+            qos: qos as u8,
+            packet_id: packet_id.unwrap_or_default(),
+            dup: pending.dup,
+            send_attempts: pending.send_attempts,
+            pubrec_at: pending.pubrec_at,
+        }
+    }
+}
+
+impl TryFrom<v2::PendingPublishMessage> for PendingPublishMessage {
+    type Error = StoreError;
+
+    fn try_from(pending: v2::PendingPublishMessage) -> Result<Self, StoreError> {
+        Ok(PendingPublishMessage {
+            message: pending.message.try_into()?,
+            qos: QoSWithPacketIdentifier::new(qos(pending.qos)?, pending.packet_id),
+            dup: pending.dup,
+            send_attempts: pending.send_attempts,
+            pubrec_at: pending.pubrec_at,
+        })
+    }
+}
+
+impl Record for PendingPublishMessage {
+    const KIND: u8 = 3;
+
+    fn encode_body(&self) -> Result<Vec<u8>, StoreError> {
+        to_bincode(&v3::PendingPublishMessage::from(self))
+    }
+
+    fn decode_body(version: u8, body: &[u8]) -> Result<Self, StoreError> {
+        let pending = match version {
+            1 => {
+                let pending: v1::PendingPublishMessage = from_bincode(body)?;
+                // version 1 saved a message right before its first send
+                v2::PendingPublishMessage {
+                    send_attempts: 1 + u32::from(pending.dup),
+                    message: pending.message,
+                    qos: pending.qos,
+                    packet_id: pending.packet_id,
+                    dup: pending.dup,
+                    pubrec_at: pending.pubrec_at,
+                }
+            }
+            // unchanged in version 3
+            2 | 3 => from_bincode(body)?,
+            _ => return Err(unknown_version(version)),
+        };
+        pending.try_into()
+    }
+}
+
+impl Record for StoredPresence {
+    const KIND: u8 = 4;
+
+    fn encode_body(&self) -> Result<Vec<u8>, StoreError> {
+        to_bincode(&v3::Presence {
+            client_id: self.client_id.clone(),
+            connected_at: self.connected_at,
+            disconnected_at: self.disconnected_at,
+            ip_address: self.ip_address.clone(),
+        })
+    }
+
+    fn decode_body(version: u8, body: &[u8]) -> Result<Self, StoreError> {
+        // unchanged in version 3
+        if !matches!(version, 2 | 3) {
+            return Err(unknown_version(version));
+        }
+        let presence: v3::Presence = from_bincode(body)?;
+        Ok(StoredPresence {
+            client_id: presence.client_id,
+            connected_at: presence.connected_at,
+            disconnected_at: presence.disconnected_at,
+            ip_address: presence.ip_address,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A [`StoredSession`] written by format version 1, must decode as long as version 1 does
+    const SESSION_V1: [u8; 65] = [
+        77, 81, 1, 1, 1, 0, 0, 0, 0, 0, 0, 0, 99, 7, 0, 1, 0, 0, 0, 0, 0, 0, 0, 3, 0, 0, 0, 0, 0,
+        0, 0, 97, 47, 43, 1, 1, 1, 0, 0, 0, 0, 0, 0, 0, 119, 1, 0, 0, 0, 0, 0, 0, 0, 120, 2, 1, 1,
+        9, 0, 0, 0, 0, 0, 0, 0,
+    ];
+
+    /// A [`PendingPublishMessage`] written by format version 1
+    const PENDING_V1: [u8; 50] = [
+        77, 81, 1, 3, 3, 0, 0, 0, 0, 0, 0, 0, 97, 47, 98, 2, 0, 0, 0, 0, 0, 0, 0, 104, 105, 1, 0,
+        0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2, 5, 0, 1, 1, 3, 0, 0, 0, 0, 0, 0, 0,
+    ];
+
+    fn session() -> StoredSession {
+        StoredSession {
+            client_id: "c".to_owned(),
+            server_packet_id: 7,
+            subscriptions: vec![StoredSubscription {
+                topic_filter: "a/+".to_owned(),
+                qos: 1,
+                v5: None,
+            }],
+            will: Some(StoredWill {
+                topic_name: "w".to_owned(),
+                payload: b"x".to_vec(),
+                qos: 2,
+                retain: true,
+            }),
+            expire_at: Some(9),
+        }
+    }
+
+    fn message() -> PublishMessage {
+        PublishMessage {
+            topic_name: TopicName::new("a/b").unwrap(),
+            payload: b"hi".to_vec(),
+            qos: QualityOfService::Level1,
+            retain: false,
+            dup: false,
+            #[cfg(feature = "v5")]
+            properties: None,
+            #[cfg(feature = "v5")]
+            received_at: 0,
+        }
+    }
+
+    #[test]
+    fn session_round_trip() {
+        let bytes = encode(&session()).unwrap();
+        assert!(is_versioned(&bytes));
+        assert_eq!(decode::<StoredSession>(&bytes).unwrap(), session());
+    }
+
+    #[test]
+    fn v5_subscription_options_round_trip() {
+        let mut session = session();
+        session.subscriptions.push(StoredSubscription {
+            topic_filter: "b/#".to_owned(),
+            qos: 2,
+            v5: Some(StoredSubscribeOptions {
+                no_local: true,
+                retain_as_published: true,
+                retain_handling: 2,
+                identifier: Some(42),
+            }),
+        });
+        let bytes = encode(&session).unwrap();
+        assert_eq!(decode::<StoredSession>(&bytes).unwrap(), session);
+    }
+
+    #[test]
+    fn message_round_trip() {
+        let bytes = encode(&message()).unwrap();
+        let decoded: PublishMessage = decode(&bytes).unwrap();
+        assert_eq!(decoded.topic_name, message().topic_name);
+        assert_eq!(decoded.payload, message().payload);
+        assert_eq!(decoded.qos, QualityOfService::Level1);
+
+        let pending = PendingPublishMessage {
+            message: message(),
+            qos: QoSWithPacketIdentifier::Level2(5),
+            dup: true,
+            send_attempts: 4,
+            pubrec_at: Some(3),
+        };
+        let decoded: PendingPublishMessage = decode(&encode(&pending).unwrap()).unwrap();
+        assert_eq!(decoded.qos, QoSWithPacketIdentifier::Level2(5));
+        assert!(decoded.dup);
+        assert_eq!(decoded.send_attempts, 4);
+        assert_eq!(decoded.pubrec_at, Some(3));
+        assert_eq!(decoded.message.payload, b"hi");
+    }
+
+    #[test]
+    fn presence_round_trip() {
+        let presence = StoredPresence {
+            client_id: "c".to_owned(),
+            connected_at: Some(5),
+            disconnected_at: None,
+            ip_address: Some("10.0.0.7".to_owned()),
+        };
+        let bytes = encode(&presence).unwrap();
+        assert_eq!(decode::<StoredPresence>(&bytes).unwrap(), presence);
+        assert!(decode::<StoredSession>(&bytes).is_err());
+    }
+
+    #[test]
+    fn decodes_version_1() {
+        assert_eq!(decode::<StoredSession>(&SESSION_V1).unwrap(), session());
+
+        let pending: PendingPublishMessage = decode(&PENDING_V1).unwrap();
+        assert_eq!(pending.qos, QoSWithPacketIdentifier::Level2(5));
+        assert_eq!(pending.message.topic_name, message().topic_name);
+        assert_eq!(pending.message.qos, QualityOfService::Level1);
+        assert_eq!(pending.pubrec_at, Some(3));
+        assert_eq!(pending.send_attempts, 1 + u32::from(pending.dup));
+    }
+
+    #[test]
+    fn rejects_other_kinds_and_newer_versions() {
+        assert!(decode::<PublishMessage>(&SESSION_V1).is_err());
+
+        let mut newer = SESSION_V1;
+        newer[2] = FORMAT_VERSION + 1;
+        assert!(decode::<StoredSession>(&newer).is_err());
+    }
+
+    #[test]
+    fn tells_legacy_records_apart() {
+        // a legacy session is laid out as the session of version 1
+        let legacy = SESSION_V1[HEADER_LEN..].to_vec();
+        assert!(!is_versioned(&legacy));
+        assert!(decode::<StoredSession>(&legacy).is_err());
+        assert_eq!(decode_unversioned_session(&legacy).unwrap(), session());
+    }
+}
@@ -1,15 +1,22 @@
 use std::{
     hash::{Hash, Hasher},
-    io,
+    sync::atomic::{AtomicU64, Ordering},
 };
 
-use foldhash::HashMap;
+use dashmap::DashMap;
+use foldhash::{fast::RandomState, HashMap};
 use mqtt_codec_kit::common::QualityOfService;
-use parking_lot::RwLock;
 
 use crate::{
     error,
-    store::message::{get_unix_ts, MessageStore, PendingPublishMessage, PublishMessage},
+    store::{
+        error::StoreError,
+        message::{
+            get_unix_ts, ClientMessages, MessageStore, PendingPublishMessage, PublishMessage,
+            ReceiveOutcome,
+        },
+        Page,
+    },
 };
 
 #[derive(Debug)]
@@ -21,8 +28,9 @@ struct ReceivedMessage {
 #[derive(Debug)]
 struct PendingMessage {
     message: PendingPublishMessage,
-    retrieve_attempts: usize,
     add_at: u64,
+    // insertion order, pending messages are resent in the order they were sent
+    seq: u64,
 }
 
 #[derive(Debug, Eq, PartialEq)]
@@ -43,14 +51,18 @@ impl Hash for MessageKey {
     }
 }
 
+// the messages of each client, sharded by client id so sessions do not contend on one lock
+type PerClient<T> = DashMap<String, T, RandomState>;
+
 #[derive(Default)]
 pub struct MessageMemoryStore {
     max_packets: usize,
     max_attempts: usize,
     max_timeout: usize,
     retrieve_factor: usize,
-    received_message: RwLock<HashMap<String, HashMap<u16, ReceivedMessage>>>,
-    pending_message: RwLock<HashMap<String, HashMap<MessageKey, PendingMessage>>>,
+    received_message: PerClient<HashMap<u16, ReceivedMessage>>,
+    pending_message: PerClient<HashMap<MessageKey, PendingMessage>>,
+    next_seq: AtomicU64,
 }
 
 impl MessageMemoryStore {
@@ -69,8 +81,73 @@ impl MessageMemoryStore {
             retrieve_factor,
             received_message: Default::default(),
             pending_message: Default::default(),
+            next_seq: AtomicU64::new(0),
         }
     }
+
+    fn count(&self, client_id: &str) -> usize {
+        self.received_message.get(client_id).map_or(0, |v| v.len())
+            + self.pending_message.get(client_id).map_or(0, |v| v.len())
+    }
+
+    // Marks at most `limit` pending messages saved after the one numbered `after` as retrieved
+    // again, the page continues after the sequence number of the last one when more are left
+    fn retrieve_pending(
+        &self,
+        client_id: &str,
+        after: Option<u64>,
+        limit: usize,
+    ) -> Option<Page<(u16, PendingPublishMessage)>> {
+        let mut packets = self.pending_message.get_mut(client_id)?;
+        if packets.is_empty() {
+            return None;
+        }
+
+        let mut selected: Vec<_> = packets
+            .iter_mut()
+            .filter(|(_, msg)| {
+                msg.message.send_attempts() as usize <= self.max_attempts
+                    && after.is_none_or(|after| msg.seq > after)
+            })
+            .collect();
+        selected.sort_unstable_by_key(|(_, msg)| msg.seq);
+        let next = if selected.len() > limit {
+            selected.truncate(limit);
+            selected.last().map(|(_, msg)| msg.seq)
+        } else {
+            None
+        };
+        let mut sent_once = Vec::new();
+        let useful_values = selected
+            .into_iter()
+            .map(|(key, msg)| {
+                if key.qos == QualityOfService::Level0 {
+                    sent_once.push(key.packet_id);
+                    return (key.packet_id, msg.message.clone());
+                }
+                msg.message.record_send_attempt();
+                (key.packet_id, msg.message.clone_pooled())
+            })
+            .collect();
+        for packet_id in sent_once {
+            packets.remove(&MessageKey {
+                packet_id,
+                qos: QualityOfService::Level0,
+            });
+        }
+
+        let now_ts = get_unix_ts();
+        let max_timeout = self.max_timeout as u64;
+
+        packets.retain(|_, msg| match msg.message.pubrec_at() {
+            Some(pubrec_at) => now_ts < max_timeout + pubrec_at,
+            None => now_ts < max_timeout + msg.add_at,
+        });
+        Some(Page {
+            items: useful_values,
+            next: next.map(|seq| seq.to_string()),
+        })
+    }
 }
 
 impl MessageStore for MessageMemoryStore {
@@ -79,44 +156,49 @@ impl MessageStore for MessageMemoryStore {
         client_id: &str,
         packet_id: u16,
         message: PublishMessage,
-    ) -> Result<bool, io::Error> {
-        if let Some(messages) = self.received_message.read().get(client_id) {
-            if messages.len() > self.max_packets {
-                error!(
-                    "drop received publish packet {:?}, store is full: {}",
-                    message,
-                    messages.len()
-                );
-                return Ok(true);
-            }
-        }
-
-        let mut received_message_guard = self.received_message.write();
-        let packets = received_message_guard
+    ) -> Result<ReceiveOutcome, StoreError> {
+        let mut packets = self
+            .received_message
             .entry(client_id.to_string())
             .or_default();
 
+        // the client gave up on messages it did not release in time, their ids are free again
+        let max_timeout = self.max_timeout as u64;
+        let now_ts = get_unix_ts();
+        packets.retain(|_, v| now_ts < max_timeout + v.add_at);
+
+        if packets.contains_key(&packet_id) {
+            return Ok(ReceiveOutcome::Duplicate);
+        }
+        if packets.len() > self.max_packets {
+            error!(
+                "drop received publish packet {:?}, store is full: {}",
+                message,
+                packets.len()
+            );
+            return Ok(ReceiveOutcome::Full);
+        }
+
         packets.insert(
             packet_id,
             ReceivedMessage {
                 message,
-                add_at: get_unix_ts(),
+                add_at: now_ts,
             },
         );
-        Ok(false)
+        Ok(ReceiveOutcome::Received)
     }
 
     async fn pubrel(
         &self,
         client_id: &str,
         packet_id: u16,
-    ) -> Result<Option<PublishMessage>, io::Error> {
-        if let Some(packets) = self.received_message.write().get_mut(client_id) {
-            let ret = packets.remove(&packet_id).map(|v| v.message);
+    ) -> Result<Option<PublishMessage>, StoreError> {
+        if let Some(mut packets) = self.received_message.get_mut(client_id) {
             let max_timeout = self.max_timeout as u64;
             let now_ts = get_unix_ts();
             packets.retain(|_, v| now_ts < max_timeout + v.add_at);
-            return Ok(ret);
+            return Ok(packets.remove(&packet_id).map(|v| v.message));
         }
         Ok(None)
     }
@@ -126,30 +208,33 @@ impl MessageStore for MessageMemoryStore {
         client_id: &str,
         packet_id: u16,
         message: PendingPublishMessage,
-    ) -> Result<bool, io::Error> {
-        if let Some(messages) = self.pending_message.read().get(client_id) {
-            if messages.len() > self.max_packets {
-                error!(
-                    "drop pending publish packet {:?}, store is full: {}",
-                    message,
-                    messages.len()
-                );
-                return Ok(true);
-            }
-        }
-
-        let mut pending_message_guard = self.pending_message.write();
-        let packets = pending_message_guard
+    ) -> Result<bool, StoreError> {
+        let mut packets = self
+            .pending_message
             .entry(client_id.to_string())
             .or_default();
+        if packets.len() > self.max_packets {
+            error!(
+                "drop pending publish packet {:?}, store is full: {}",
+                message,
+                packets.len()
+            );
+            return Ok(true);
+        }
+
         let (qos, _) = message.qos().split();
+        let key = MessageKey { packet_id, qos };
+        let seq = match packets.get(&key) {
+            Some(pending) => pending.seq,
+            None => self.next_seq.fetch_add(1, Ordering::Relaxed),
+        };
 
         packets.insert(
-            MessageKey { packet_id, qos },
+            key,
             PendingMessage {
                 message,
-                retrieve_attempts: 1,
                 add_at: get_unix_ts(),
+                seq,
             },
         );
 
@@ -159,29 +244,35 @@ impl MessageStore for MessageMemoryStore {
     async fn try_get_pending_messages(
         &self,
         client_id: &str,
-    ) -> Result<Option<Vec<(u16, PendingPublishMessage)>>, io::Error> {
-        if let Some(packets) = self.pending_message.write().get_mut(client_id) {
+    ) -> Result<Option<Vec<(u16, PendingPublishMessage)>>, StoreError> {
+        if let Some(mut packets) = self.pending_message.get_mut(client_id) {
             if packets.is_empty() {
                 return Ok(None);
             }
 
             let now_ts = get_unix_ts();
             let retrieve_factor = self.retrieve_factor as u64;
-            let useful_values = packets
+            let mut useful_values: Vec<_> = packets
                 .iter_mut()
                 .filter_map(|(key, msg)| {
-                    if msg.retrieve_attempts > self.max_attempts {
+                    let attempts = msg.message.send_attempts();
+                    if attempts as usize > self.max_attempts || key.qos == QualityOfService::Level0
+                    {
                         return None;
                     }
-                    if now_ts > retrieve_factor * msg.retrieve_attempts as u64 + msg.add_at {
-                        msg.retrieve_attempts += 1;
-                        msg.message.set_dup(true);
-                        Some((key.packet_id, msg.message.clone()))
+                    if now_ts > retrieve_factor * attempts as u64 + msg.add_at {
+                        msg.message.record_send_attempt();
+                        Some((msg.seq, key.packet_id, msg.message.clone()))
                     } else {
                         None
                     }
                 })
                 .collect();
+            useful_values.sort_unstable_by_key(|(seq, _, _)| *seq);
+            let useful_values = useful_values
+                .into_iter()
+                .map(|(_, packet_id, msg)| (packet_id, msg))
+                .collect();
 
             let max_timeout = self.max_timeout as u64;
             packets.retain(|_, msg| match msg.message.pubrec_at() {
@@ -196,57 +287,79 @@ impl MessageStore for MessageMemoryStore {
     async fn get_all_pending_messages(
         &self,
         client_id: &str,
-    ) -> Result<Option<Vec<(u16, PendingPublishMessage)>>, io::Error> {
-        if let Some(packets) = self.pending_message.write().get_mut(client_id) {
-            if packets.is_empty() {
-                return Ok(None);
-            }
+    ) -> Result<Option<Vec<(u16, PendingPublishMessage)>>, StoreError> {
+        Ok(self
+            .retrieve_pending(client_id, None, usize::MAX)
+            .map(|page| page.items))
+    }
 
-            let useful_values = packets
-                .iter_mut()
-                .filter_map(|(key, msg)| {
-                    if msg.retrieve_attempts > self.max_attempts {
-                        return None;
-                    }
+    async fn get_pending_messages_page(
+        &self,
+        client_id: &str,
+        cursor: Option<&str>,
+        limit: usize,
+    ) -> Result<Page<(u16, PendingPublishMessage)>, StoreError> {
+        let after = match cursor {
+            Some(cursor) => Some(
+                cursor
+                    .parse()
+                    .map_err(|_| StoreError::NotFound(format!("cursor {cursor}")))?,
+            ),
+            None => None,
+        };
+        Ok(self
+            .retrieve_pending(client_id, after, limit)
+            .unwrap_or_else(|| Page {
+                items: Vec::new(),
+                next: None,
+            }))
+    }
 
-                    msg.retrieve_attempts += 1;
-                    msg.message.set_dup(true);
-                    Some((key.packet_id, msg.message.clone()))
-                })
+    async fn inspect_messages(&self, client_id: &str) -> Result<ClientMessages, StoreError> {
+        let mut messages = ClientMessages::default();
+        if let Some(packets) = self.received_message.get(client_id) {
+            messages.received = packets
+                .iter()
+                .map(|(packet_id, msg)| (*packet_id, msg.message.clone()))
                 .collect();
-
-            let now_ts = get_unix_ts();
-            let max_timeout = self.max_timeout as u64;
-
-            packets.retain(|_, msg| match msg.message.pubrec_at() {
-                Some(pubrec_at) => now_ts < max_timeout + pubrec_at,
-                None => now_ts < max_timeout + msg.add_at,
-            });
-            return Ok(Some(useful_values));
+            messages
+                .received
+                .sort_unstable_by_key(|(packet_id, _)| *packet_id);
         }
-        Ok(None)
+        if let Some(packets) = self.pending_message.get(client_id) {
+            let mut pending: Vec<_> = packets.iter().collect();
+            pending.sort_unstable_by_key(|(_, msg)| msg.seq);
+            messages.pending = pending
+                .into_iter()
+                .map(|(key, msg)| (key.packet_id, msg.message.clone()))
+                .collect();
+        }
+        Ok(messages)
     }
 
-    async fn puback(&self, client_id: &str, packet_id: u16) -> Result<bool, io::Error> {
+    async fn puback(&self, client_id: &str, packet_id: u16) -> Result<bool, StoreError> {
         let key = MessageKey {
             packet_id,
             qos: QualityOfService::Level1,
         };
-        match self.pending_message.write().get_mut(client_id) {
-            Some(packets) => match packets.remove(&key) {
-                Some(_) => Ok(true),
+        match self.pending_message.get_mut(client_id) {
+            Some(mut packets) => match packets.remove(&key) {
+                Some(pending) => {
+                    pending.message.recycle();
+                    Ok(true)
+                }
                 None => Ok(false),
             },
             None => Ok(false),
         }
     }
 
-    async fn pubrec(&self, client_id: &str, packet_id: u16) -> Result<bool, io::Error> {
+    async fn pubrec(&self, client_id: &str, packet_id: u16) -> Result<bool, StoreError> {
         let key = MessageKey {
             packet_id,
             qos: QualityOfService::Level2,
         };
-        if let Some(packets) = self.pending_message.write().get_mut(client_id) {
+        if let Some(mut packets) = self.pending_message.get_mut(client_id) {
             return if let Some(pkt) = packets.get_mut(&key) {
                 pkt.message.renew_pubrec_at();
                 Ok(true)
@@ -258,47 +371,222 @@ impl MessageStore for MessageMemoryStore {
         Ok(false)
     }
 
-    async fn pubcomp(&self, client_id: &str, packet_id: u16) -> Result<bool, io::Error> {
+    async fn pubcomp(&self, client_id: &str, packet_id: u16) -> Result<bool, StoreError> {
         let key = MessageKey {
             packet_id,
             qos: QualityOfService::Level2,
         };
-        match self.pending_message.write().get_mut(client_id) {
-            Some(packets) => match packets.remove(&key) {
-                Some(_) => Ok(true),
+        match self.pending_message.get_mut(client_id) {
+            Some(mut packets) => match packets.remove(&key) {
+                Some(pending) => {
+                    pending.message.recycle();
+                    Ok(true)
+                }
                 None => Ok(false),
             },
             None => Ok(false),
         }
     }
 
-    async fn is_full(&self, client_id: &str) -> Result<bool, io::Error> {
-        let l = match self.received_message.read().get(client_id) {
-            Some(v) => v.len(),
-            None => 0,
-        } + match self.pending_message.read().get(client_id) {
-            Some(v) => v.len(),
-            None => 0,
+    async fn is_packet_id_pending(
+        &self,
+        client_id: &str,
+        packet_id: u16,
+    ) -> Result<bool, StoreError> {
+        let Some(packets) = self.pending_message.get(client_id) else {
+            return Ok(false);
         };
-
-        Ok(l > self.max_packets)
+        Ok([
+            QualityOfService::Level0,
+            QualityOfService::Level1,
+            QualityOfService::Level2,
+        ]
+        .into_iter()
+        .any(|qos| packets.contains_key(&MessageKey { packet_id, qos })))
     }
 
-    async fn message_count(&self, client_id: &str) -> Result<usize, io::Error> {
-        let l = match self.received_message.read().get(client_id) {
-            Some(v) => v.len(),
-            None => 0,
-        } + match self.pending_message.read().get(client_id) {
-            Some(v) => v.len(),
-            None => 0,
-        };
+    async fn is_full(&self, client_id: &str) -> Result<bool, StoreError> {
+        Ok(self.count(client_id) > self.max_packets)
+    }
 
-        Ok(l)
+    async fn message_count(&self, client_id: &str) -> Result<usize, StoreError> {
+        Ok(self.count(client_id))
     }
 
-    async fn clear_all(&self, client_id: &str) -> Result<(), io::Error> {
-        self.pending_message.write().remove(client_id);
-        self.received_message.write().remove(client_id);
+    async fn clear_all(&self, client_id: &str) -> Result<(), StoreError> {
+        self.pending_message.remove(client_id);
+        self.received_message.remove(client_id);
         Ok(())
     }
 }
+
+#[cfg(all(test, feature = "v4"))]
+mod tests {
+    use mqtt_codec_kit::{
+        common::{qos::QoSWithPacketIdentifier, TopicName},
+        v4::packet::PublishPacket,
+    };
+
+    use super::*;
+
+    fn message(payload: &str) -> PublishMessage {
+        let packet = PublishPacket::new(
+            TopicName::new("a/b").unwrap(),
+            QoSWithPacketIdentifier::Level2(1),
+            payload,
+        );
+        (&packet).into()
+    }
+
+    #[tokio::test]
+    async fn resent_publish_is_delivered_once() {
+        let store = MessageMemoryStore::new(16, 30, 3);
+        let outcome = store.save_publish_message("c", 1, message("first")).await;
+        assert_eq!(outcome.unwrap(), ReceiveOutcome::Received);
+        let outcome = store.save_publish_message("c", 1, message("resent")).await;
+        assert_eq!(outcome.unwrap(), ReceiveOutcome::Duplicate);
+
+        let released = store.pubrel("c", 1).await.unwrap().unwrap();
+        assert_eq!(released.payload(), b"first");
+        assert!(store.pubrel("c", 1).await.unwrap().is_none());
+
+        let outcome = store.save_publish_message("c", 1, message("next")).await;
+        assert_eq!(outcome.unwrap(), ReceiveOutcome::Received);
+    }
+
+    #[tokio::test]
+    async fn packet_ids_are_per_client() {
+        let store = MessageMemoryStore::new(16, 30, 3);
+        let outcome = store.save_publish_message("c1", 1, message("a")).await;
+        assert_eq!(outcome.unwrap(), ReceiveOutcome::Received);
+        let outcome = store.save_publish_message("c2", 1, message("b")).await;
+        assert_eq!(outcome.unwrap(), ReceiveOutcome::Received);
+    }
+
+    #[tokio::test]
+    async fn unreleased_publish_times_out() {
+        let store = MessageMemoryStore::new(16, 0, 3);
+        let outcome = store.save_publish_message("c", 1, message("first")).await;
+        assert_eq!(outcome.unwrap(), ReceiveOutcome::Received);
+        let outcome = store.save_publish_message("c", 1, message("second")).await;
+        assert_eq!(outcome.unwrap(), ReceiveOutcome::Received);
+        assert!(store.pubrel("c", 1).await.unwrap().is_none());
+        assert_eq!(store.message_count("c").await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn full_store_drops_publish() {
+        let store = MessageMemoryStore::new(0, 30, 3);
+        let outcome = store.save_publish_message("c", 1, message("first")).await;
+        assert_eq!(outcome.unwrap(), ReceiveOutcome::Received);
+        let outcome = store.save_publish_message("c", 2, message("second")).await;
+        assert_eq!(outcome.unwrap(), ReceiveOutcome::Full);
+        let outcome = store.save_publish_message("c", 1, message("resent")).await;
+        assert_eq!(outcome.unwrap(), ReceiveOutcome::Duplicate);
+    }
+    #[tokio::test]
+    async fn pending_messages_are_paged_in_save_order() {
+        let store = MessageMemoryStore::new(16, 30, 3);
+        for packet_id in [5, 1, 3] {
+            let mut pending = PendingPublishMessage::new(
+                QoSWithPacketIdentifier::Level1(packet_id),
+                message(&packet_id.to_string()),
+            );
+            pending.record_send_attempt();
+            let full = store.save_pending_publish_message("c", packet_id, pending);
+            assert!(!full.await.unwrap());
+        }
+
+        let page = store.get_pending_messages_page("c", None, 2).await.unwrap();
+        let ids: Vec<_> = page.items.iter().map(|(id, _)| *id).collect();
+        assert_eq!(ids, vec![5, 1]);
+        assert!(page.items.iter().all(|(_, pending)| pending.dup()));
+
+        let cursor = page.next.unwrap();
+        let page = store
+            .get_pending_messages_page("c", Some(&cursor), 2)
+            .await
+            .unwrap();
+        let ids: Vec<_> = page.items.iter().map(|(id, _)| *id).collect();
+        assert_eq!(ids, vec![3]);
+        assert!(page.next.is_none());
+
+        let page = store.get_pending_messages_page("x", None, 2).await.unwrap();
+        assert!(page.items.is_empty() && page.next.is_none());
+    }
+
+    #[tokio::test]
+    async fn inspecting_counts_no_send_attempt() {
+        let store = MessageMemoryStore::new(16, 30, 3);
+        let outcome = store
+            .save_publish_message("c", 7, message("received"))
+            .await;
+        assert_eq!(outcome.unwrap(), ReceiveOutcome::Received);
+        for packet_id in [2, 1] {
+            let qos = QoSWithPacketIdentifier::Level1(packet_id);
+            let pending = PendingPublishMessage::new(qos, message("pending"));
+            let full = store.save_pending_publish_message("c", packet_id, pending);
+            assert!(!full.await.unwrap());
+        }
+
+        for _ in 0..2 {
+            let messages = store.inspect_messages("c").await.unwrap();
+            let received: Vec<_> = messages.received.iter().map(|(id, _)| *id).collect();
+            assert_eq!(received, [7]);
+            let pending: Vec<_> = messages
+                .pending
+                .iter()
+                .map(|(id, pending)| (*id, pending.send_attempts()))
+                .collect();
+            assert_eq!(pending, [(2, 0), (1, 0)]);
+        }
+        assert!(store
+            .inspect_messages("x")
+            .await
+            .unwrap()
+            .pending
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn only_retransmissions_are_dup() {
+        let store = MessageMemoryStore::new(16, 30, 3);
+        let qos = QoSWithPacketIdentifier::Level1(1);
+        let mut sent = PendingPublishMessage::new(qos, message("sent"));
+        sent.record_send_attempt();
+        assert!(!sent.dup());
+        let full = store.save_pending_publish_message("c", 1, sent);
+        assert!(!full.await.unwrap());
+        // kept while offline, never sent
+        let qos = QoSWithPacketIdentifier::Level1(2);
+        let offline = PendingPublishMessage::new(qos, message("offline"));
+        let full = store.save_pending_publish_message("c", 2, offline);
+        assert!(!full.await.unwrap());
+
+        let pending = store.get_all_pending_messages("c").await.unwrap().unwrap();
+        let sent: Vec<_> = pending
+            .iter()
+            .map(|(_, pending)| (pending.send_attempts(), pending.dup()))
+            .collect();
+        assert_eq!(sent, [(2, true), (1, false)]);
+    }
+
+    #[tokio::test]
+    async fn acknowledged_messages_are_reused() {
+        let store = MessageMemoryStore::new(16, 30, 3);
+        let qos = QoSWithPacketIdentifier::Level1(1);
+        let mut sent = PendingPublishMessage::new(qos, message("a long payload"));
+        sent.record_send_attempt();
+        let payload = sent.message().payload().as_ptr();
+        let full = store.save_pending_publish_message("c", 1, sent);
+        assert!(!full.await.unwrap());
+        assert!(store.puback("c", 1).await.unwrap());
+
+        // the current thread runtime recycles and takes on the same thread
+        let pooled = PendingPublishMessage::new_pooled(qos, &message("short"));
+        assert_eq!(pooled.message().payload(), b"short");
+        assert_eq!(pooled.message().payload().as_ptr(), payload);
+        assert_eq!(pooled.send_attempts(), 0);
+        assert!(!pooled.dup());
+    }
+}
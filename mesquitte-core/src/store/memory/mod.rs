@@ -1,14 +1,18 @@
 use std::sync::Arc;
 
 use message::MessageMemoryStore;
-use mqtt_codec_kit::common::{QualityOfService, TopicFilter, TopicName};
+use mqtt_codec_kit::common::{TopicFilter, TopicName};
 use retain::RetainMessageMemoryStore;
 use topic::TopicMemoryStore;
 
 use super::{
-    message::{MessageStore, PendingPublishMessage, PublishMessage},
+    error::StoreError,
+    message::{
+        ClientMessages, MessageStore, PendingPublishMessage, PublishMessage, ReceiveOutcome,
+    },
     retain::{RetainContent, RetainMessageStore},
-    topic::{TopicContent, TopicStore},
+    topic::{SubscribeOutcome, SubscriptionOptions, TopicContent, TopicStore},
+    Page,
 };
 
 pub mod message;
@@ -37,122 +41,239 @@ impl MemoryStore {
 }
 
 impl MessageStore for MemoryStore {
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "trace", skip(self, message), err)
+    )]
     async fn save_publish_message(
         &self,
         client_id: &str,
         packet_id: u16,
         message: PublishMessage,
-    ) -> Result<bool, std::io::Error> {
+    ) -> Result<ReceiveOutcome, StoreError> {
         self.message_store
             .save_publish_message(client_id, packet_id, message)
             .await
     }
 
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "trace", skip(self, message), err)
+    )]
     async fn save_pending_publish_message(
         &self,
         client_id: &str,
         packet_id: u16,
         message: PendingPublishMessage,
-    ) -> Result<bool, std::io::Error> {
+    ) -> Result<bool, StoreError> {
         self.message_store
             .save_pending_publish_message(client_id, packet_id, message)
             .await
     }
 
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "trace", skip(self), err)
+    )]
     async fn try_get_pending_messages(
         &self,
         client_id: &str,
-    ) -> Result<Option<Vec<(u16, PendingPublishMessage)>>, std::io::Error> {
+    ) -> Result<Option<Vec<(u16, PendingPublishMessage)>>, StoreError> {
         self.message_store.try_get_pending_messages(client_id).await
     }
 
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "trace", skip(self), err)
+    )]
     async fn get_all_pending_messages(
         &self,
         client_id: &str,
-    ) -> Result<Option<Vec<(u16, PendingPublishMessage)>>, std::io::Error> {
+    ) -> Result<Option<Vec<(u16, PendingPublishMessage)>>, StoreError> {
         self.message_store.get_all_pending_messages(client_id).await
     }
 
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "trace", skip(self), err)
+    )]
+    async fn get_pending_messages_page(
+        &self,
+        client_id: &str,
+        cursor: Option<&str>,
+        limit: usize,
+    ) -> Result<Page<(u16, PendingPublishMessage)>, StoreError> {
+        self.message_store
+            .get_pending_messages_page(client_id, cursor, limit)
+            .await
+    }
+
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "trace", skip(self), err)
+    )]
+    async fn inspect_messages(&self, client_id: &str) -> Result<ClientMessages, StoreError> {
+        self.message_store.inspect_messages(client_id).await
+    }
+
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "trace", skip(self), err)
+    )]
     async fn pubrel(
         &self,
         client_id: &str,
         packet_id: u16,
-    ) -> Result<Option<PublishMessage>, std::io::Error> {
+    ) -> Result<Option<PublishMessage>, StoreError> {
         self.message_store.pubrel(client_id, packet_id).await
     }
 
-    async fn puback(&self, client_id: &str, packet_id: u16) -> Result<bool, std::io::Error> {
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "trace", skip(self), err)
+    )]
+    async fn puback(&self, client_id: &str, packet_id: u16) -> Result<bool, StoreError> {
         self.message_store.puback(client_id, packet_id).await
     }
 
-    async fn pubrec(&self, client_id: &str, packet_id: u16) -> Result<bool, std::io::Error> {
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "trace", skip(self), err)
+    )]
+    async fn pubrec(&self, client_id: &str, packet_id: u16) -> Result<bool, StoreError> {
         self.message_store.pubrec(client_id, packet_id).await
     }
 
-    async fn pubcomp(&self, client_id: &str, packet_id: u16) -> Result<bool, std::io::Error> {
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "trace", skip(self), err)
+    )]
+    async fn pubcomp(&self, client_id: &str, packet_id: u16) -> Result<bool, StoreError> {
         self.message_store.pubcomp(client_id, packet_id).await
     }
 
-    async fn is_full(&self, client_id: &str) -> Result<bool, std::io::Error> {
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "trace", skip(self), err)
+    )]
+    async fn is_packet_id_pending(
+        &self,
+        client_id: &str,
+        packet_id: u16,
+    ) -> Result<bool, StoreError> {
+        self.message_store
+            .is_packet_id_pending(client_id, packet_id)
+            .await
+    }
+
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "trace", skip(self), err)
+    )]
+    async fn is_full(&self, client_id: &str) -> Result<bool, StoreError> {
         self.message_store.is_full(client_id).await
     }
 
-    async fn message_count(&self, client_id: &str) -> Result<usize, std::io::Error> {
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "trace", skip(self), err)
+    )]
+    async fn message_count(&self, client_id: &str) -> Result<usize, StoreError> {
         self.message_store.message_count(client_id).await
     }
 
-    async fn clear_all(&self, client_id: &str) -> Result<(), std::io::Error> {
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "trace", skip(self), err)
+    )]
+    async fn clear_all(&self, client_id: &str) -> Result<(), StoreError> {
         self.message_store.clear_all(client_id).await
     }
 }
 
 impl RetainMessageStore for MemoryStore {
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "trace", skip(self), err)
+    )]
     async fn search(
         &self,
         topic_filter: &TopicFilter,
-    ) -> Result<Vec<Arc<RetainContent>>, std::io::Error> {
+    ) -> Result<Vec<Arc<RetainContent>>, StoreError> {
         self.retain_message_store.search(topic_filter).await
     }
 
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "trace", skip(self), err)
+    )]
+    async fn search_page(
+        &self,
+        topic_filter: &TopicFilter,
+        cursor: Option<&str>,
+        limit: usize,
+    ) -> Result<Page<Arc<RetainContent>>, StoreError> {
+        self.retain_message_store
+            .search_page(topic_filter, cursor, limit)
+            .await
+    }
+
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "trace", skip(self, content), err)
+    )]
     async fn insert(
         &self,
         content: RetainContent,
-    ) -> Result<Option<Arc<RetainContent>>, std::io::Error> {
+    ) -> Result<Option<Arc<RetainContent>>, StoreError> {
         self.retain_message_store.insert(content).await
     }
 
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "trace", skip(self), err)
+    )]
     async fn remove(
         &self,
         topic_name: &TopicName,
-    ) -> Result<Option<Arc<RetainContent>>, std::io::Error> {
+    ) -> Result<Option<Arc<RetainContent>>, StoreError> {
         self.retain_message_store.remove(topic_name).await
     }
 }
 
 impl TopicStore for MemoryStore {
-    async fn match_topic(
-        &self,
-        topic_name: &TopicName,
-    ) -> Result<Vec<TopicContent>, std::io::Error> {
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "trace", skip(self), err)
+    )]
+    async fn match_topic(&self, topic_name: &TopicName) -> Result<Vec<TopicContent>, StoreError> {
         self.topic_store.match_topic(topic_name).await
     }
 
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "trace", skip(self), err)
+    )]
     async fn subscribe(
         &self,
         client_id: &str,
         topic_filter: &TopicFilter,
-        qos: QualityOfService,
-    ) -> Result<(), std::io::Error> {
+        options: SubscriptionOptions,
+    ) -> Result<SubscribeOutcome, StoreError> {
         self.topic_store
-            .subscribe(client_id, topic_filter, qos)
+            .subscribe(client_id, topic_filter, options)
             .await
     }
 
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "trace", skip(self), err)
+    )]
     async fn unsubscribe(
         &self,
         client_id: &str,
         topic_filter: &TopicFilter,
-    ) -> Result<bool, std::io::Error> {
+    ) -> Result<bool, StoreError> {
         self.topic_store.unsubscribe(client_id, topic_filter).await
     }
 }
@@ -1,4 +1,4 @@
-use std::{io, mem, sync::Arc};
+use std::{mem, sync::Arc};
 
 use foldhash::HashMap;
 use mqtt_codec_kit::common::{
@@ -6,7 +6,11 @@ use mqtt_codec_kit::common::{
 };
 use parking_lot::RwLock;
 
-use crate::store::retain::{RetainContent, RetainMessageStore};
+use crate::store::{
+    error::StoreError,
+    retain::{RetainContent, RetainMessageStore},
+    Page,
+};
 
 fn split_topic(topic: &str) -> (&str, Option<&str>) {
     if let Some((head, rest)) = topic.split_once(LEVEL_SEP) {
@@ -132,7 +136,7 @@ impl RetainMessageStore for RetainMessageMemoryStore {
     async fn search(
         &self,
         topic_filter: &mqtt_codec_kit::common::TopicFilter,
-    ) -> Result<Vec<Arc<RetainContent>>, io::Error> {
+    ) -> Result<Vec<Arc<RetainContent>>, StoreError> {
         // [MQTT-4.7.2-1] The Server MUST NOT match Topic Filters starting with a
         // wildcard character (# or +) with Topic Names beginning with a $ character
         let wildcard_first = topic_filter.starts_with([MATCH_ONE_CHAR, MATCH_ALL_CHAR]);
@@ -145,10 +149,35 @@ impl RetainMessageStore for RetainMessageMemoryStore {
         Ok(retains)
     }
 
+    async fn search_page(
+        &self,
+        topic_filter: &mqtt_codec_kit::common::TopicFilter,
+        cursor: Option<&str>,
+        limit: usize,
+    ) -> Result<Page<Arc<RetainContent>>, StoreError> {
+        let mut retains = self.search(topic_filter).await?;
+        if let Some(cursor) = cursor {
+            retains.retain(|content| &content.topic_name()[..] > cursor);
+        }
+        retains.sort_unstable_by(|a, b| a.topic_name().cmp(b.topic_name()));
+        let next = if retains.len() > limit {
+            retains.truncate(limit);
+            retains
+                .last()
+                .map(|content| content.topic_name().to_string())
+        } else {
+            None
+        };
+        Ok(Page {
+            items: retains,
+            next,
+        })
+    }
+
     async fn insert(
         &self,
         content: RetainContent,
-    ) -> Result<Option<Arc<RetainContent>>, io::Error> {
+    ) -> Result<Option<Arc<RetainContent>>, StoreError> {
         let topic_name = content.topic_name().to_owned();
         let (topic_item, rest_items) = split_topic(&topic_name);
 
@@ -158,7 +187,7 @@ impl RetainMessageStore for RetainMessageMemoryStore {
     async fn remove(
         &self,
         topic_name: &TopicName,
-    ) -> Result<Option<Arc<RetainContent>>, io::Error> {
+    ) -> Result<Option<Arc<RetainContent>>, StoreError> {
         let (topic_item, rest_items) = split_topic(topic_name);
         Ok(self.inner.remove(topic_item, rest_items))
     }
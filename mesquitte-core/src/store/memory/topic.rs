@@ -1,13 +1,15 @@
-use std::{io, sync::Arc};
+use std::sync::Arc;
 
 use foldhash::HashMap;
 use mqtt_codec_kit::common::{
-    QualityOfService, TopicFilter, TopicName, LEVEL_SEP, MATCH_ALL_STR, MATCH_DOLLAR_STR,
-    MATCH_ONE_STR,
+    TopicFilter, TopicName, LEVEL_SEP, MATCH_ALL_STR, MATCH_DOLLAR_STR, MATCH_ONE_STR,
 };
 use parking_lot::RwLock;
 
-use crate::store::topic::{TopicContent, TopicStore};
+use crate::store::{
+    error::StoreError,
+    topic::{SubscribeOutcome, SubscriptionOptions, TopicContent, TopicStore},
+};
 
 #[derive(Debug, Default)]
 pub struct TopicMemoryStore {
@@ -15,12 +17,17 @@ pub struct TopicMemoryStore {
 }
 
 impl TopicStore for TopicMemoryStore {
-    async fn match_topic(&self, topic_name: &TopicName) -> io::Result<Vec<TopicContent>> {
+    async fn match_topic(&self, topic_name: &TopicName) -> Result<Vec<TopicContent>, StoreError> {
+        let topic_levels: Vec<&str> = topic_name.split(LEVEL_SEP).collect();
         if topic_name.starts_with(MATCH_DOLLAR_STR) {
-            return Ok(Vec::new());
+            // a filter starting with a wildcard does not match a topic starting with `$`
+            let contents = match self.root.read().children.get(topic_levels[0]) {
+                Some(child) => child.read().match_topic(&topic_levels[1..]),
+                None => Vec::new(),
+            };
+            return Ok(contents);
         }
 
-        let topic_levels: Vec<&str> = topic_name.split(LEVEL_SEP).collect();
         let contents = self.root.read().match_topic(&topic_levels);
         Ok(contents)
     }
@@ -29,8 +36,8 @@ impl TopicStore for TopicMemoryStore {
         &self,
         client_id: &str,
         topic_filter: &TopicFilter,
-        qos: QualityOfService,
-    ) -> io::Result<()> {
+        options: SubscriptionOptions,
+    ) -> Result<SubscribeOutcome, StoreError> {
         let (group, levels) = match topic_filter.shared_info() {
             Some((g, t)) => {
                 let l: Vec<&str> = t.split(LEVEL_SEP).collect();
@@ -62,11 +69,17 @@ impl TopicStore for TopicMemoryStore {
                 .or_default(),
             None => &mut node_write_guard.topic_content.clients,
         };
-        clients.insert(client_id.to_string(), qos);
-        Ok(())
+        Ok(match clients.insert(client_id.to_string(), options) {
+            Some(previous) => SubscribeOutcome::Replaced { previous },
+            None => SubscribeOutcome::New,
+        })
     }
 
-    async fn unsubscribe(&self, client_id: &str, topic_filter: &TopicFilter) -> io::Result<bool> {
+    async fn unsubscribe(
+        &self,
+        client_id: &str,
+        topic_filter: &TopicFilter,
+    ) -> Result<bool, StoreError> {
         let (group, levels) = match topic_filter.shared_info() {
             Some((group, topic)) => (Some(group), topic.split(LEVEL_SEP)),
             None => (None, topic_filter.split(LEVEL_SEP)),
@@ -179,3 +192,92 @@ impl TopicNode {
         contents
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use mqtt_codec_kit::common::QualityOfService;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn dollar_topics_skip_leading_wildcards() {
+        let store = TopicMemoryStore::default();
+        let options = SubscriptionOptions::V4(QualityOfService::Level0);
+        for (client_id, filter) in [("a", "#"), ("b", "+/x"), ("c", "$SYS/#"), ("d", "$SYS/+")] {
+            store
+                .subscribe(client_id, &TopicFilter::new(filter).unwrap(), options)
+                .await
+                .unwrap();
+        }
+
+        let contents = store
+            .match_topic(&TopicName::new("$SYS/x").unwrap())
+            .await
+            .unwrap();
+        let mut client_ids: Vec<_> = contents
+            .iter()
+            .flat_map(|content| content.clients.keys().cloned())
+            .collect();
+        client_ids.sort_unstable();
+        assert_eq!(client_ids, ["c", "d"]);
+    }
+
+    #[tokio::test]
+    async fn subscription_options_are_kept() {
+        let store = TopicMemoryStore::default();
+        let options = SubscriptionOptions::V4(QualityOfService::Level1);
+        store
+            .subscribe("c1", &TopicFilter::new("a/+").unwrap(), options)
+            .await
+            .unwrap();
+        store
+            .subscribe(
+                "c2",
+                &TopicFilter::new("$share/g/a/#").unwrap(),
+                QualityOfService::Level2.into(),
+            )
+            .await
+            .unwrap();
+
+        let contents = store
+            .match_topic(&TopicName::new("a/b").unwrap())
+            .await
+            .unwrap();
+        let clients: Vec<_> = contents.iter().flat_map(|c| c.clients.iter()).collect();
+        assert_eq!(clients, [(&"c1".to_owned(), &options)]);
+        let shared = contents
+            .iter()
+            .find_map(|c| c.shared_clients.get("g"))
+            .unwrap();
+        assert_eq!(shared["c2"].qos(), QualityOfService::Level2);
+    }
+
+    #[tokio::test]
+    async fn resubscribe_replaces_options() {
+        let store = TopicMemoryStore::default();
+        for filter in ["a/+", "$share/g/a/+"] {
+            let filter = TopicFilter::new(filter).unwrap();
+            let first = QualityOfService::Level1.into();
+            let outcome = store.subscribe("c1", &filter, first).await.unwrap();
+            assert_eq!(outcome, SubscribeOutcome::New);
+            let outcome = store
+                .subscribe("c1", &filter, QualityOfService::Level0.into())
+                .await
+                .unwrap();
+            assert_eq!(outcome, SubscribeOutcome::Replaced { previous: first });
+        }
+
+        let contents = store
+            .match_topic(&TopicName::new("a/b").unwrap())
+            .await
+            .unwrap();
+        let content = contents.iter().find(|c| !c.clients.is_empty()).unwrap();
+        assert_eq!(content.clients.len(), 1);
+        assert_eq!(content.clients["c1"].qos(), QualityOfService::Level0);
+        assert_eq!(content.shared_clients["g"].len(), 1);
+        assert_eq!(
+            content.shared_clients["g"]["c1"].qos(),
+            QualityOfService::Level0
+        );
+    }
+}
@@ -1,4 +1,4 @@
-use std::{fmt::Debug, future::Future, io, sync::Arc, time::SystemTime};
+use std::{cell::RefCell, fmt::Debug, future::Future, sync::Arc, time::SystemTime};
 
 use mqtt_codec_kit::common::{qos::QoSWithPacketIdentifier, QualityOfService, TopicName};
 #[cfg(feature = "v4")]
@@ -11,7 +11,7 @@ use mqtt_codec_kit::v5::{
     packet::PublishPacket as V5PublishPacket,
 };
 
-use super::retain::RetainContent;
+use super::{error::StoreError, retain::RetainContent, Page};
 
 pub fn get_unix_ts() -> u64 {
     match SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
@@ -20,30 +20,98 @@ pub fn get_unix_ts() -> u64 {
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Debug)]
 pub struct PublishMessage {
-    topic_name: TopicName,
-    payload: Vec<u8>,
-    qos: QualityOfService,
-    retain: bool,
-    dup: bool,
+    pub(super) topic_name: TopicName,
+    pub(super) payload: Vec<u8>,
+    pub(super) qos: QualityOfService,
+    pub(super) retain: bool,
+    pub(super) dup: bool,
+    #[cfg(feature = "v5")]
+    pub(super) properties: Option<PublishProperties>,
+    // unix timestamp the message expiry interval counts from
     #[cfg(feature = "v5")]
-    properties: Option<PublishProperties>,
+    pub(super) received_at: u64,
+}
+
+impl Clone for PublishMessage {
+    fn clone(&self) -> Self {
+        Self {
+            topic_name: self.topic_name.clone(),
+            payload: self.payload.clone(),
+            qos: self.qos,
+            retain: self.retain,
+            dup: self.dup,
+            #[cfg(feature = "v5")]
+            properties: self.properties.clone(),
+            #[cfg(feature = "v5")]
+            received_at: self.received_at,
+        }
+    }
+
+    /// Reuses the topic and payload buffers of `self` when they are large enough
+    fn clone_from(&mut self, source: &Self) {
+        self.topic_name.clone_from(&source.topic_name);
+        self.payload.clone_from(&source.payload);
+        self.qos = source.qos;
+        self.retain = source.retain;
+        self.dup = source.dup;
+        #[cfg(feature = "v5")]
+        self.properties.clone_from(&source.properties);
+        #[cfg(feature = "v5")]
+        {
+            self.received_at = source.received_at;
+        }
+    }
 }
 
 impl PublishMessage {
+    /// A message the broker publishes itself
+    #[cfg_attr(not(feature = "server"), allow(dead_code))]
+    pub(crate) fn new(
+        topic_name: TopicName,
+        payload: Vec<u8>,
+        qos: QualityOfService,
+        retain: bool,
+    ) -> Self {
+        Self {
+            topic_name,
+            payload,
+            qos,
+            retain,
+            dup: false,
+            #[cfg(feature = "v5")]
+            properties: None,
+            #[cfg(feature = "v5")]
+            received_at: get_unix_ts(),
+        }
+    }
+
     pub fn topic_name(&self) -> &TopicName {
         &self.topic_name
     }
 
+    pub fn set_topic_name(&mut self, topic_name: TopicName) {
+        self.topic_name = topic_name
+    }
+
     pub fn payload(&self) -> &[u8] {
         &self.payload
     }
 
+    /// The topic name and the payload, without copying them
+    pub fn into_parts(self) -> (TopicName, Vec<u8>) {
+        (self.topic_name, self.payload)
+    }
+
     pub fn qos(&self) -> QualityOfService {
         self.qos
     }
 
+    pub fn set_qos(&mut self, qos: QualityOfService) {
+        self.qos = qos
+    }
+
     pub fn dup(&self) -> bool {
         self.dup
     }
@@ -60,6 +128,31 @@ impl PublishMessage {
     pub fn properties(&self) -> Option<&PublishProperties> {
         self.properties.as_ref()
     }
+
+    #[cfg(feature = "v5")]
+    pub fn set_properties(&mut self, properties: Option<PublishProperties>) {
+        self.properties = properties
+    }
+
+    /// Whether the message expiry interval set by the publisher has passed
+    pub fn is_expired(&self) -> bool {
+        #[cfg(feature = "v5")]
+        if let Some(interval) = self
+            .properties
+            .as_ref()
+            .and_then(|properties| properties.message_expiry_interval())
+        {
+            return self.received_at + interval as u64 <= get_unix_ts();
+        }
+        false
+    }
+
+    #[cfg(feature = "v5")]
+    pub fn add_user_property<S: Into<String>>(&mut self, key: S, value: S) {
+        self.properties
+            .get_or_insert_with(PublishProperties::default)
+            .add_user_property(key, value)
+    }
 }
 
 #[cfg(feature = "v4")]
@@ -76,6 +169,8 @@ impl From<&V4PublishPacket> for PublishMessage {
             dup: packet.dup(),
             #[cfg(feature = "v5")]
             properties: None,
+            #[cfg(feature = "v5")]
+            received_at: get_unix_ts(),
         }
     }
 }
@@ -93,6 +188,7 @@ impl From<&V5PublishPacket> for PublishMessage {
             retain: packet.retain(),
             dup: packet.dup(),
             properties: Some(packet.properties().to_owned()),
+            received_at: get_unix_ts(),
         }
     }
 }
@@ -110,6 +206,8 @@ impl From<Arc<RetainContent>> for PublishMessage {
             dup: false,
             #[cfg(feature = "v5")]
             properties: packet.properties().cloned(),
+            #[cfg(feature = "v5")]
+            received_at: get_unix_ts(),
         }
     }
 }
@@ -128,6 +226,8 @@ impl From<V4LastWill> for PublishMessage {
             dup: false,
             #[cfg(feature = "v5")]
             properties: None,
+            #[cfg(feature = "v5")]
+            received_at: get_unix_ts(),
         }
     }
 }
@@ -156,28 +256,90 @@ impl From<V5LastWill> for PublishMessage {
             retain: value.retain(),
             dup: false,
             properties: Some(publish_properties),
+            received_at: get_unix_ts(),
         }
     }
 }
 
+/// Pending messages kept per thread at most by the pool of [`PendingPublishMessage::new_pooled`]
+const PENDING_POOL_SIZE: usize = 256;
+/// Payload capacity above which a recycled message is freed instead of pooled, so a burst of
+/// large messages does not stay allocated
+const PENDING_POOL_MAX_PAYLOAD: usize = 4096;
+
+thread_local! {
+    // acknowledged messages of the thread, their buffers are reused by the next pending ones
+    static PENDING_POOL: RefCell<Vec<PendingPublishMessage>> = const { RefCell::new(Vec::new()) };
+}
+
 #[derive(Clone, Debug)]
 pub struct PendingPublishMessage {
-    message: PublishMessage,
-    qos: QoSWithPacketIdentifier,
-    dup: bool,
-    pubrec_at: Option<u64>,
+    pub(super) message: PublishMessage,
+    pub(super) qos: QoSWithPacketIdentifier,
+    pub(super) dup: bool,
+    pub(super) send_attempts: u32,
+    pub(super) pubrec_at: Option<u64>,
 }
 
 impl PendingPublishMessage {
+    /// A message not sent yet, the DUP flag of the received PUBLISH is not carried over: it
+    /// only marks retransmissions to the subscriber
     pub fn new(qos: QoSWithPacketIdentifier, message: PublishMessage) -> Self {
         Self {
             pubrec_at: None,
             qos,
-            dup: message.dup,
+            dup: false,
+            send_attempts: 0,
             message,
         }
     }
 
+    /// [`PendingPublishMessage::new`] with a copy of `message`, written into the buffers of a
+    /// recycled message if the pool of the thread has one
+    ///
+    /// A message fanned out to many subscribers is saved once per subscriber, copying it into
+    /// pooled buffers saves the two allocations of its topic and payload each time.
+    pub fn new_pooled(qos: QoSWithPacketIdentifier, message: &PublishMessage) -> Self {
+        match PENDING_POOL.with_borrow_mut(Vec::pop) {
+            Some(mut pending) => {
+                pending.message.clone_from(message);
+                pending.qos = qos;
+                pending.dup = false;
+                pending.send_attempts = 0;
+                pending.pubrec_at = None;
+                pending
+            }
+            None => Self::new(qos, message.clone()),
+        }
+    }
+
+    /// A copy of the message made with [`PendingPublishMessage::new_pooled`]
+    pub fn clone_pooled(&self) -> Self {
+        let mut pending = Self::new_pooled(self.qos, &self.message);
+        pending.dup = self.dup;
+        pending.send_attempts = self.send_attempts;
+        pending.pubrec_at = self.pubrec_at;
+        pending
+    }
+
+    /// Returns a message which is not needed anymore to the pool of the thread, e.g. once it is
+    /// acknowledged
+    pub fn recycle(self) {
+        if self.message.payload.capacity() > PENDING_POOL_MAX_PAYLOAD {
+            return;
+        }
+        PENDING_POOL.with_borrow_mut(|pool| {
+            if pool.len() < PENDING_POOL_SIZE {
+                pool.push(self);
+            }
+        });
+    }
+
+    /// The message, without copying it
+    pub fn into_message(self) -> PublishMessage {
+        self.message
+    }
+
     pub fn pubrec_at(&self) -> Option<u64> {
         self.pubrec_at
     }
@@ -198,66 +360,134 @@ impl PendingPublishMessage {
         self.dup = dup;
     }
 
+    /// Times the message was handed out to be sent, the first send included
+    pub fn send_attempts(&self) -> u32 {
+        self.send_attempts
+    }
+
+    /// Counts a send of the message, every send after the first one is a retransmission and
+    /// sets the DUP flag
+    pub fn record_send_attempt(&mut self) {
+        self.send_attempts = self.send_attempts.saturating_add(1);
+        self.dup = self.send_attempts > 1;
+    }
+
     pub fn qos(&self) -> QoSWithPacketIdentifier {
         self.qos
     }
 }
 
+/// Every message a store keeps for a client, see [`MessageStore::inspect_messages`]
+#[derive(Debug, Default)]
+pub struct ClientMessages {
+    /// QoS 2 messages received from the client and waiting for PUBREL
+    pub received: Vec<(u16, PublishMessage)>,
+    /// Messages to the client which are not acknowledged yet, in the order they were saved
+    pub pending: Vec<(u16, PendingPublishMessage)>,
+}
+
+/// What [`MessageStore::save_publish_message`] did with an incoming QoS 2 message
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReceiveOutcome {
+    /// The message is kept until the client releases it with PUBREL
+    Received,
+    /// A message with the same packet identifier is waiting for PUBREL, the new one is a resend
+    /// of it and is dropped
+    Duplicate,
+    /// The store is full, the message is dropped
+    Full,
+}
+
 pub trait MessageStore: Send + Sync {
+    /// Keeps an incoming QoS 2 message until [`MessageStore::pubrel`], the packet identifier
+    /// stays taken until then so a resent PUBLISH is not delivered twice
+    ///
+    /// Messages not released within the store timeout are dropped.
     fn save_publish_message(
         &self,
         client_id: &str,
         packet_id: u16,
         message: PublishMessage,
-    ) -> impl Future<Output = Result<bool, io::Error>> + Send;
+    ) -> impl Future<Output = Result<ReceiveOutcome, StoreError>> + Send;
 
+    /// Releases the packet identifier, returns the message to deliver unless it was released
+    /// already or timed out
     fn pubrel(
         &self,
         client_id: &str,
         packet_id: u16,
-    ) -> impl Future<Output = Result<Option<PublishMessage>, io::Error>> + Send;
+    ) -> impl Future<Output = Result<Option<PublishMessage>, StoreError>> + Send;
 
     fn save_pending_publish_message(
         &self,
         client_id: &str,
         packet_id: u16,
         message: PendingPublishMessage,
-    ) -> impl Future<Output = Result<bool, io::Error>> + Send;
+    ) -> impl Future<Output = Result<bool, StoreError>> + Send;
 
+    /// Messages are returned in the order they were first saved, QoS 0 messages are left out
     fn try_get_pending_messages(
         &self,
         client_id: &str,
-    ) -> impl Future<Output = Result<Option<Vec<(u16, PendingPublishMessage)>>, io::Error>> + Send;
+    ) -> impl Future<Output = Result<Option<Vec<(u16, PendingPublishMessage)>>, StoreError>> + Send;
 
+    /// Messages are returned in the order they were first saved
+    ///
+    /// QoS 0 messages, kept for offline sessions, are never acknowledged: they are returned
+    /// once and removed.
     fn get_all_pending_messages(
         &self,
         client_id: &str,
-    ) -> impl Future<Output = Result<Option<Vec<(u16, PendingPublishMessage)>>, io::Error>> + Send;
+    ) -> impl Future<Output = Result<Option<Vec<(u16, PendingPublishMessage)>>, StoreError>> + Send;
+
+    /// At most `limit` of the messages of [`MessageStore::get_all_pending_messages`], `cursor`
+    /// is `None` for the first page and the `next` of the previous page after that
+    fn get_pending_messages_page(
+        &self,
+        client_id: &str,
+        cursor: Option<&str>,
+        limit: usize,
+    ) -> impl Future<Output = Result<Page<(u16, PendingPublishMessage)>, StoreError>> + Send;
+
+    /// Every message kept for the client, to debug or export its session: unlike the getters
+    /// above it counts no send attempt and removes nothing
+    fn inspect_messages(
+        &self,
+        client_id: &str,
+    ) -> impl Future<Output = Result<ClientMessages, StoreError>> + Send;
 
     fn puback(
         &self,
         client_id: &str,
         packet_id: u16,
-    ) -> impl Future<Output = Result<bool, io::Error>> + Send;
+    ) -> impl Future<Output = Result<bool, StoreError>> + Send;
 
     fn pubrec(
         &self,
         client_id: &str,
         packet_id: u16,
-    ) -> impl Future<Output = Result<bool, io::Error>> + Send;
+    ) -> impl Future<Output = Result<bool, StoreError>> + Send;
 
     fn pubcomp(
         &self,
         client_id: &str,
         packet_id: u16,
-    ) -> impl Future<Output = Result<bool, io::Error>> + Send;
+    ) -> impl Future<Output = Result<bool, StoreError>> + Send;
+
+    /// Whether a message kept for the client, sent and not acknowledged yet or waiting for its
+    /// first send, holds the packet identifier
+    fn is_packet_id_pending(
+        &self,
+        client_id: &str,
+        packet_id: u16,
+    ) -> impl Future<Output = Result<bool, StoreError>> + Send;
 
-    fn is_full(&self, client_id: &str) -> impl Future<Output = Result<bool, io::Error>> + Send;
+    fn is_full(&self, client_id: &str) -> impl Future<Output = Result<bool, StoreError>> + Send;
 
     fn message_count(
         &self,
         client_id: &str,
-    ) -> impl Future<Output = Result<usize, io::Error>> + Send;
+    ) -> impl Future<Output = Result<usize, StoreError>> + Send;
 
-    fn clear_all(&self, client_id: &str) -> impl Future<Output = Result<(), io::Error>> + Send;
+    fn clear_all(&self, client_id: &str) -> impl Future<Output = Result<(), StoreError>> + Send;
 }
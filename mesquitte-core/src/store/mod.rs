@@ -4,11 +4,32 @@ use message::MessageStore;
 use retain::RetainMessageStore;
 use topic::TopicStore;
 
+pub mod dynamic;
+pub mod error;
+#[cfg(feature = "session-export")]
+pub mod export;
+#[cfg(all(feature = "serde", feature = "bincode"))]
+pub mod format;
 pub mod memory;
 pub mod message;
+// waits on the runtime, the rest of the store does not
+#[cfg(feature = "server")]
+pub mod queue;
 pub mod retain;
+#[cfg(feature = "rocksdb-storage")]
+pub mod rocksdb;
+pub mod session;
 pub mod topic;
 
+/// One page of a store scan
+///
+/// Scans are resumed by passing `next` to the following call, `None` means the scan is done.
+#[derive(Debug)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    pub next: Option<String>,
+}
+
 pub struct Storage<S>(S);
 
 impl<S> Storage<S>
@@ -20,6 +41,16 @@ where
     }
 }
 
+/// The memory stores with the default limits, shared by the tests
+#[cfg(test)]
+pub(crate) fn memory_storage() -> Storage<memory::MemoryStore> {
+    Storage::new(memory::MemoryStore::new(
+        memory::message::MessageMemoryStore::new(1024, 30, 3),
+        memory::retain::RetainMessageMemoryStore::default(),
+        memory::topic::TopicMemoryStore::default(),
+    ))
+}
+
 impl<S> AsRef<S> for Storage<S> {
     fn as_ref(&self) -> &S {
         self.deref()
@@ -0,0 +1,345 @@
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use mqtt_codec_kit::common::{QualityOfService, TopicFilter};
+use parking_lot::Mutex;
+use tokio::{sync::Notify, time::Instant};
+
+use super::message::PublishMessage;
+
+/// Maximum number of queued messages a session handles before serving its other events
+pub(crate) const DELIVER_BATCH_SIZE: usize = 64;
+
+/// What to do with a publish for a session whose queue is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OverflowPolicy {
+    /// Drop the incoming message
+    #[default]
+    DropNewest,
+    /// Drop the oldest queued message to make room for the incoming one
+    DropOldest,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct QueueConfig {
+    /// Maximum number of queued messages
+    pub capacity: usize,
+    /// Above this many queued messages, QoS 0 messages are dropped to leave room for QoS 1/2
+    pub high_watermark: usize,
+    pub overflow: OverflowPolicy,
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        Self {
+            capacity: 1024,
+            high_watermark: 768,
+            overflow: OverflowPolicy::DropNewest,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct QueuedMessage {
+    pub topic_filter: TopicFilter,
+    pub subscribe_qos: QualityOfService,
+    /// Shared by every subscriber the message was fanned out to
+    pub message: Arc<PublishMessage>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PushOutcome {
+    Queued,
+    /// The incoming message was dropped
+    Dropped,
+    /// The oldest message was dropped to queue the incoming one
+    Evicted,
+}
+
+/// Per-session queue of messages waiting to be delivered
+///
+/// Publishers never wait on the queue, a full queue is handled with its [`OverflowPolicy`] so a
+/// slow subscriber can not stall the fan-out to every other subscriber. The session waits on
+/// [`DeliverQueue::notified`] and drains the queue in batches.
+#[derive(Debug)]
+pub struct DeliverQueue {
+    config: QueueConfig,
+    // with the time each message was queued
+    messages: Mutex<VecDeque<(Instant, QueuedMessage)>>,
+    notify: Notify,
+    dropped: AtomicUsize,
+    // payload bytes of the queued messages, only changed with `messages` locked
+    bytes: AtomicUsize,
+}
+
+impl DeliverQueue {
+    pub fn new(config: QueueConfig) -> Self {
+        Self {
+            config,
+            messages: Mutex::new(VecDeque::new()),
+            notify: Notify::new(),
+            dropped: AtomicUsize::new(0),
+            bytes: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn push(&self, message: QueuedMessage) -> PushOutcome {
+        let (outcome, _) = self.push_locked(&mut self.messages.lock(), message);
+        if outcome != PushOutcome::Queued {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+        self.notify.notify_one();
+        outcome
+    }
+
+    /// Pushes several messages under one lock and wakes the session once, returns the messages
+    /// dropped by the overflow policy
+    pub fn push_batch(&self, batch: Vec<QueuedMessage>) -> Vec<QueuedMessage> {
+        let mut dropped = Vec::new();
+        {
+            let mut messages = self.messages.lock();
+            for message in batch {
+                if let (_, Some(message)) = self.push_locked(&mut messages, message) {
+                    dropped.push(message);
+                }
+            }
+        }
+        if !dropped.is_empty() {
+            self.dropped.fetch_add(dropped.len(), Ordering::Relaxed);
+        }
+        self.notify.notify_one();
+        dropped
+    }
+
+    /// Returns the dropped message along with the outcome
+    fn push_locked(
+        &self,
+        messages: &mut VecDeque<(Instant, QueuedMessage)>,
+        message: QueuedMessage,
+    ) -> (PushOutcome, Option<QueuedMessage>) {
+        if messages.len() >= self.config.capacity {
+            match self.config.overflow {
+                OverflowPolicy::DropNewest => (PushOutcome::Dropped, Some(message)),
+                OverflowPolicy::DropOldest => {
+                    let evicted = self.pop_locked(messages);
+                    self.push_back_locked(messages, message);
+                    (PushOutcome::Evicted, evicted)
+                }
+            }
+        } else if messages.len() >= self.config.high_watermark
+            && message.subscribe_qos.min(message.message.qos()) == QualityOfService::Level0
+        {
+            (PushOutcome::Dropped, Some(message))
+        } else {
+            self.push_back_locked(messages, message);
+            (PushOutcome::Queued, None)
+        }
+    }
+
+    fn push_back_locked(
+        &self,
+        messages: &mut VecDeque<(Instant, QueuedMessage)>,
+        message: QueuedMessage,
+    ) {
+        self.bytes
+            .fetch_add(message.message.payload().len(), Ordering::Relaxed);
+        messages.push_back((Instant::now(), message));
+    }
+
+    fn pop_locked(
+        &self,
+        messages: &mut VecDeque<(Instant, QueuedMessage)>,
+    ) -> Option<QueuedMessage> {
+        let (_, message) = messages.pop_front()?;
+        self.bytes
+            .fetch_sub(message.message.payload().len(), Ordering::Relaxed);
+        Some(message)
+    }
+
+    pub fn pop(&self) -> Option<QueuedMessage> {
+        self.pop_locked(&mut self.messages.lock())
+    }
+
+    /// Wakes the session waiting on [`DeliverQueue::notified`]
+    pub fn wake(&self) {
+        self.notify.notify_one();
+    }
+
+    /// Removes up to `max` messages from the front of the queue
+    pub fn pop_batch(&self, max: usize) -> Vec<QueuedMessage> {
+        let mut messages = self.messages.lock();
+        let len = max.min(messages.len());
+        let batch = (0..len)
+            .filter_map(|_| self.pop_locked(&mut messages))
+            .collect();
+        if !messages.is_empty() {
+            // let the session come back for the rest after serving its other events
+            self.notify.notify_one();
+        }
+        batch
+    }
+
+    /// Waits until messages were pushed since the last call
+    pub async fn notified(&self) {
+        self.notify.notified().await
+    }
+
+    pub fn len(&self) -> usize {
+        self.messages.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.messages.lock().is_empty()
+    }
+
+    pub fn clear(&self) {
+        let mut messages = self.messages.lock();
+        messages.clear();
+        self.bytes.store(0, Ordering::Relaxed);
+    }
+
+    /// Payload bytes of the queued messages
+    pub fn bytes(&self) -> usize {
+        self.bytes.load(Ordering::Relaxed)
+    }
+
+    /// How long the oldest queued message has been waiting
+    pub fn oldest_age(&self) -> Option<Duration> {
+        let messages = self.messages.lock();
+        messages.front().map(|(queued_at, _)| queued_at.elapsed())
+    }
+
+    /// Number of messages dropped by the overflow policy
+    pub fn dropped(&self) -> usize {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mqtt_codec_kit::common::TopicName;
+
+    use super::*;
+
+    fn queued(payload: &str, qos: QualityOfService) -> QueuedMessage {
+        let message = PublishMessage::new(
+            TopicName::new("a/b").unwrap(),
+            payload.as_bytes().to_vec(),
+            qos,
+            false,
+        );
+        QueuedMessage {
+            topic_filter: TopicFilter::new("a/#").unwrap(),
+            subscribe_qos: QualityOfService::Level2,
+            message: Arc::new(message),
+        }
+    }
+
+    fn payloads(queue: &DeliverQueue) -> Vec<String> {
+        queue
+            .pop_batch(usize::MAX)
+            .iter()
+            .map(|queued| String::from_utf8(queued.message.payload().to_vec()).unwrap())
+            .collect()
+    }
+
+    fn queue(capacity: usize, high_watermark: usize, overflow: OverflowPolicy) -> DeliverQueue {
+        DeliverQueue::new(QueueConfig {
+            capacity,
+            high_watermark,
+            overflow,
+        })
+    }
+
+    #[test]
+    fn full_queue_drops_newest() {
+        let queue = queue(2, 2, OverflowPolicy::DropNewest);
+        for (payload, outcome) in [
+            ("0", PushOutcome::Queued),
+            ("1", PushOutcome::Queued),
+            ("2", PushOutcome::Dropped),
+        ] {
+            assert_eq!(
+                queue.push(queued(payload, QualityOfService::Level1)),
+                outcome
+            );
+        }
+        assert_eq!(queue.dropped(), 1);
+        assert_eq!(queue.bytes(), 2);
+        assert_eq!(payloads(&queue), ["0", "1"]);
+    }
+
+    #[test]
+    fn full_queue_drops_oldest() {
+        let queue = queue(2, 2, OverflowPolicy::DropOldest);
+        for (payload, outcome) in [
+            ("0", PushOutcome::Queued),
+            ("1", PushOutcome::Queued),
+            ("22", PushOutcome::Evicted),
+        ] {
+            assert_eq!(
+                queue.push(queued(payload, QualityOfService::Level1)),
+                outcome
+            );
+        }
+        assert_eq!(queue.dropped(), 1);
+        assert_eq!(queue.bytes(), 3);
+        assert_eq!(payloads(&queue), ["1", "22"]);
+
+        let dropped = queue.push_batch(
+            ["3", "4", "5"]
+                .map(|payload| queued(payload, QualityOfService::Level1))
+                .into(),
+        );
+        assert_eq!(dropped.len(), 1);
+        assert_eq!(dropped[0].message.payload(), b"3");
+        assert_eq!(queue.dropped(), 2);
+        assert_eq!(payloads(&queue), ["4", "5"]);
+    }
+
+    #[test]
+    fn qos0_is_dropped_above_high_watermark() {
+        let queue = queue(4, 2, OverflowPolicy::DropNewest);
+        queue.push(queued("0", QualityOfService::Level0));
+        queue.push(queued("1", QualityOfService::Level1));
+        assert_eq!(
+            queue.push(queued("2", QualityOfService::Level0)),
+            PushOutcome::Dropped
+        );
+        assert_eq!(
+            queue.push(queued("3", QualityOfService::Level2)),
+            PushOutcome::Queued
+        );
+
+        // the QoS the message is delivered with counts, not the QoS it was published with
+        let mut downgraded = queued("4", QualityOfService::Level1);
+        downgraded.subscribe_qos = QualityOfService::Level0;
+        assert_eq!(queue.push(downgraded), PushOutcome::Dropped);
+        assert_eq!(queue.dropped(), 2);
+        assert_eq!(payloads(&queue), ["0", "1", "3"]);
+    }
+
+    #[test]
+    fn clear_empties_the_queue() {
+        let queue = queue(4, 4, OverflowPolicy::DropNewest);
+        queue.push(queued("00", QualityOfService::Level1));
+        queue.push(queued("11", QualityOfService::Level1));
+        assert_eq!(queue.len(), 2);
+        assert_eq!(queue.bytes(), 4);
+        assert!(queue.oldest_age().is_some());
+
+        queue.clear();
+        assert!(queue.is_empty());
+        assert_eq!(queue.bytes(), 0);
+        assert_eq!(queue.oldest_age(), None);
+        assert!(queue.pop().is_none());
+        // clearing is not dropping by the overflow policy
+        assert_eq!(queue.dropped(), 0);
+    }
+}
@@ -1,10 +1,10 @@
-use std::{future::Future, io, sync::Arc};
+use std::{future::Future, sync::Arc};
 
 use mqtt_codec_kit::common::{QualityOfService, TopicFilter, TopicName};
 #[cfg(feature = "v5")]
 use mqtt_codec_kit::v5::control::PublishProperties;
 
-use super::message::PublishMessage;
+use super::{error::StoreError, message::PublishMessage, Page};
 
 #[derive(Clone)]
 pub struct RetainContent {
@@ -60,15 +60,24 @@ pub trait RetainMessageStore: Send + Sync {
     fn search(
         &self,
         topic_filter: &TopicFilter,
-    ) -> impl Future<Output = Result<Vec<Arc<RetainContent>>, io::Error>> + Send;
+    ) -> impl Future<Output = Result<Vec<Arc<RetainContent>>, StoreError>> + Send;
+
+    /// At most `limit` of the messages of [`RetainMessageStore::search`] in topic name order,
+    /// `cursor` is `None` for the first page and the `next` of the previous page after that
+    fn search_page(
+        &self,
+        topic_filter: &TopicFilter,
+        cursor: Option<&str>,
+        limit: usize,
+    ) -> impl Future<Output = Result<Page<Arc<RetainContent>>, StoreError>> + Send;
 
     fn insert(
         &self,
         content: RetainContent,
-    ) -> impl Future<Output = Result<Option<Arc<RetainContent>>, io::Error>> + Send;
+    ) -> impl Future<Output = Result<Option<Arc<RetainContent>>, StoreError>> + Send;
 
     fn remove(
         &self,
         topic_name: &TopicName,
-    ) -> impl Future<Output = Result<Option<Arc<RetainContent>>, io::Error>> + Send;
+    ) -> impl Future<Output = Result<Option<Arc<RetainContent>>, StoreError>> + Send;
 }
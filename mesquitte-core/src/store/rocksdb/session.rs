@@ -0,0 +1,144 @@
+use std::path::Path;
+
+use rust_rocksdb::{IteratorMode, Options, DB};
+
+use crate::store::{
+    error::StoreError,
+    format,
+    session::{SessionStore, StoredPresence, StoredSession},
+};
+
+/// Prefix of the keys of the presence records, a client identifier never contains U+0000
+const PRESENCE_PREFIX: &[u8] = b"\0presence/";
+
+/// RocksDB backed [`SessionStore`], keyed by client id
+pub struct SessionRocksDBStore {
+    db: DB,
+}
+
+impl SessionRocksDBStore {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, StoreError> {
+        let mut db_opts = Options::default();
+        db_opts.create_if_missing(true);
+
+        let db = DB::open(&db_opts, path).map_err(StoreError::backend)?;
+        Ok(Self { db })
+    }
+}
+
+/// Sessions saved before the versioned format are bare bincode and still load
+fn decode(value: &[u8]) -> Result<StoredSession, StoreError> {
+    if format::is_versioned(value) {
+        format::decode(value)
+    } else {
+        format::decode_unversioned_session(value)
+    }
+}
+
+impl SessionStore for SessionRocksDBStore {
+    fn save_session(&self, session: &StoredSession) -> Result<(), StoreError> {
+        let value = format::encode(session)?;
+        self.db
+            .put(session.client_id.as_bytes(), value)
+            .map_err(StoreError::backend)
+    }
+
+    fn load_session(&self, client_id: &str) -> Result<Option<StoredSession>, StoreError> {
+        match self
+            .db
+            .get(client_id.as_bytes())
+            .map_err(StoreError::backend)?
+        {
+            Some(value) => decode(&value).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn remove_session(&self, client_id: &str) -> Result<(), StoreError> {
+        self.db
+            .delete(client_id.as_bytes())
+            .map_err(StoreError::backend)
+    }
+
+    fn load_all_sessions(&self) -> Result<Vec<StoredSession>, StoreError> {
+        let mut sessions = Vec::new();
+        for item in self.db.iterator(IteratorMode::Start) {
+            let (key, value) = item.map_err(StoreError::backend)?;
+            if key.starts_with(PRESENCE_PREFIX) {
+                continue;
+            }
+            sessions.push(decode(&value)?);
+        }
+        Ok(sessions)
+    }
+
+    fn save_presence(&self, presence: &StoredPresence) -> Result<(), StoreError> {
+        let value = format::encode(presence)?;
+        self.db
+            .put(presence_key(&presence.client_id), value)
+            .map_err(StoreError::backend)
+    }
+
+    fn load_presence(&self, client_id: &str) -> Result<Option<StoredPresence>, StoreError> {
+        match self
+            .db
+            .get(presence_key(client_id))
+            .map_err(StoreError::backend)?
+        {
+            Some(value) => format::decode(&value).map(Some),
+            None => Ok(None),
+        }
+    }
+}
+
+fn presence_key(client_id: &str) -> Vec<u8> {
+    [PRESENCE_PREFIX, client_id.as_bytes()].concat()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::session::{StoredSubscription, StoredWill};
+
+    #[test]
+    fn sessions_survive_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let session = StoredSession {
+            client_id: "c1".to_owned(),
+            server_packet_id: 7,
+            subscriptions: vec![StoredSubscription {
+                topic_filter: "a/#".to_owned(),
+                qos: 1,
+                v5: None,
+            }],
+            will: Some(StoredWill {
+                topic_name: "will".to_owned(),
+                payload: b"bye".to_vec(),
+                qos: 1,
+                retain: false,
+            }),
+            expire_at: Some(1_700_000_000),
+        };
+        let presence = StoredPresence {
+            client_id: "c1".to_owned(),
+            connected_at: Some(1),
+            disconnected_at: None,
+            ip_address: Some("127.0.0.1".to_owned()),
+        };
+        {
+            let store = SessionRocksDBStore::open(dir.path()).unwrap();
+            store.save_session(&session).unwrap();
+            store.save_presence(&presence).unwrap();
+        }
+
+        let store = SessionRocksDBStore::open(dir.path()).unwrap();
+        assert_eq!(store.load_session("c1").unwrap(), Some(session.clone()));
+        assert_eq!(store.load_presence("c1").unwrap(), Some(presence));
+        // presence records are not sessions
+        assert_eq!(store.load_all_sessions().unwrap(), [session]);
+
+        store.remove_session("c1").unwrap();
+        assert_eq!(store.load_session("c1").unwrap(), None);
+        assert!(store.load_all_sessions().unwrap().is_empty());
+    }
+}
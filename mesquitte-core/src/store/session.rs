@@ -0,0 +1,210 @@
+use mqtt_codec_kit::common::{QualityOfService, TopicFilter};
+#[cfg(feature = "v5")]
+use mqtt_codec_kit::v5::packet::subscribe::{RetainHandling, SubscribeOptions};
+
+use super::{error::StoreError, message::get_unix_ts, topic::SubscriptionOptions};
+
+/// Will message of a persisted session
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct StoredWill {
+    pub topic_name: String,
+    pub payload: Vec<u8>,
+    pub qos: u8,
+    pub retain: bool,
+}
+
+/// Subscription of a persisted session
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct StoredSubscription {
+    pub topic_filter: String,
+    /// Granted QoS
+    pub qos: u8,
+    /// `None` for an MQTT 3.1.1 subscription
+    pub v5: Option<StoredSubscribeOptions>,
+}
+
+/// The subscription options only MQTT 5 has
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct StoredSubscribeOptions {
+    pub no_local: bool,
+    pub retain_as_published: bool,
+    pub retain_handling: u8,
+    /// Subscription Identifier of the SUBSCRIBE
+    pub identifier: Option<u32>,
+}
+
+impl StoredSubscription {
+    pub fn new(topic_filter: &TopicFilter, options: &SubscriptionOptions) -> Self {
+        let v5 = match options {
+            SubscriptionOptions::V4(_) => None,
+            #[cfg(feature = "v5")]
+            SubscriptionOptions::V5 {
+                options,
+                identifier,
+            } => Some(StoredSubscribeOptions {
+                no_local: options.no_local(),
+                retain_as_published: options.retain_as_published(),
+                retain_handling: options.retain_handling().into(),
+                identifier: identifier.and_then(|identifier| u32::try_from(identifier).ok()),
+            }),
+        };
+        Self {
+            topic_filter: topic_filter.to_string(),
+            qos: options.qos() as u8,
+            v5,
+        }
+    }
+
+    /// The options subscribed with, `None` for a QoS or retain handling no version defines
+    ///
+    /// Without MQTT 5 support an MQTT 5 subscription keeps only its QoS.
+    pub fn options(&self) -> Option<SubscriptionOptions> {
+        let qos = match self.qos {
+            0 => QualityOfService::Level0,
+            1 => QualityOfService::Level1,
+            2 => QualityOfService::Level2,
+            _ => return None,
+        };
+        #[cfg(feature = "v5")]
+        if let Some(stored) = &self.v5 {
+            let mut options = SubscribeOptions::default();
+            options.set_qos(qos);
+            options.set_no_local(stored.no_local);
+            options.set_retain_as_published(stored.retain_as_published);
+            options.set_retain_handling(RetainHandling::try_from(stored.retain_handling).ok()?);
+            return Some(SubscriptionOptions::V5 {
+                options,
+                identifier: stored.identifier.map(|identifier| identifier as usize),
+            });
+        }
+        Some(SubscriptionOptions::V4(qos))
+    }
+}
+
+/// State of a non-clean session which has to survive broker restarts
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct StoredSession {
+    pub client_id: String,
+    pub server_packet_id: u16,
+    pub subscriptions: Vec<StoredSubscription>,
+    pub will: Option<StoredWill>,
+    /// Unix timestamp in seconds after which the session is discarded, `None` never expires
+    pub expire_at: Option<u64>,
+}
+
+impl StoredSession {
+    pub fn is_expired(&self) -> bool {
+        self.expire_at.is_some_and(|at| at <= get_unix_ts())
+    }
+
+    /// The subscriptions with valid options, see [`StoredSubscription::options`]
+    pub fn subscriptions(&self) -> impl Iterator<Item = (&str, SubscriptionOptions)> {
+        self.subscriptions.iter().filter_map(|subscription| {
+            Some((subscription.topic_filter.as_str(), subscription.options()?))
+        })
+    }
+}
+
+/// Last connect and disconnect of a client, kept for clean sessions too, see
+/// [`GlobalState::client_status`](crate::server::state::GlobalState::client_status)
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct StoredPresence {
+    pub client_id: String,
+    /// Unix timestamp in milliseconds
+    pub connected_at: Option<u64>,
+    /// Unix timestamp in milliseconds, of a disconnect after the last connect or before it
+    pub disconnected_at: Option<u64>,
+    /// IP address of the last connection
+    pub ip_address: Option<String>,
+}
+
+/// Persistent storage of session state
+///
+/// Unlike the other stores, this trait is synchronous and object safe, so a `GlobalState` can
+/// hold any implementation without another type parameter. Connections call it on the blocking
+/// thread pool, an implementation may block on disk.
+pub trait SessionStore: Send + Sync {
+    fn save_session(&self, session: &StoredSession) -> Result<(), StoreError>;
+
+    fn load_session(&self, client_id: &str) -> Result<Option<StoredSession>, StoreError>;
+
+    fn remove_session(&self, client_id: &str) -> Result<(), StoreError>;
+
+    fn load_all_sessions(&self) -> Result<Vec<StoredSession>, StoreError>;
+
+    /// Saves the presence of a client, a store which keeps none ignores it
+    fn save_presence(&self, presence: &StoredPresence) -> Result<(), StoreError> {
+        let _ = presence;
+        Ok(())
+    }
+
+    fn load_presence(&self, client_id: &str) -> Result<Option<StoredPresence>, StoreError> {
+        let _ = client_id;
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stored_subscriptions_and_expiry() {
+        let subscription = |topic_filter: &str, qos| StoredSubscription {
+            topic_filter: topic_filter.to_owned(),
+            qos,
+            v5: None,
+        };
+        let mut session = StoredSession {
+            client_id: "c1".to_owned(),
+            server_packet_id: 7,
+            subscriptions: vec![
+                subscription("a/#", 1),
+                subscription("b", 2),
+                subscription("c", 3),
+            ],
+            will: None,
+            expire_at: None,
+        };
+        // a QoS no version defines is skipped
+        let subscriptions: Vec<_> = session.subscriptions().collect();
+        assert_eq!(
+            subscriptions,
+            [
+                ("a/#", SubscriptionOptions::V4(QualityOfService::Level1)),
+                ("b", SubscriptionOptions::V4(QualityOfService::Level2))
+            ]
+        );
+
+        assert!(!session.is_expired());
+        session.expire_at = Some(get_unix_ts() + 60);
+        assert!(!session.is_expired());
+        session.expire_at = Some(get_unix_ts());
+        assert!(session.is_expired());
+    }
+
+    #[cfg(feature = "v5")]
+    #[test]
+    fn v5_subscription_options_are_kept() {
+        let mut subscribe_options = SubscribeOptions::default();
+        subscribe_options.set_qos(QualityOfService::Level1);
+        subscribe_options.set_no_local(true);
+        subscribe_options.set_retain_as_published(true);
+        subscribe_options.set_retain_handling(RetainHandling::DoNotSend);
+        let options = SubscriptionOptions::V5 {
+            options: subscribe_options,
+            identifier: Some(7),
+        };
+        let filter = TopicFilter::new("a/#").unwrap();
+        let stored = StoredSubscription::new(&filter, &options);
+        assert_eq!(stored.options(), Some(options));
+
+        let v4 = SubscriptionOptions::V4(QualityOfService::Level2);
+        assert_eq!(StoredSubscription::new(&filter, &v4).options(), Some(v4));
+    }
+}
@@ -1,13 +1,82 @@
-use std::{future::Future, io};
+use std::future::Future;
 
 use foldhash::{HashMap, HashMapExt};
 use mqtt_codec_kit::common::{QualityOfService, TopicFilter, TopicName};
+#[cfg(feature = "v5")]
+use mqtt_codec_kit::v5::packet::subscribe::SubscribeOptions;
+
+use super::error::StoreError;
+
+/// Options a client subscribed to a topic filter with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SubscriptionOptions {
+    /// MQTT 3.1.1 only has the maximum QoS
+    V4(QualityOfService),
+    #[cfg(feature = "v5")]
+    V5 {
+        options: SubscribeOptions,
+        /// Subscription Identifier of the SUBSCRIBE
+        identifier: Option<usize>,
+    },
+}
+
+impl SubscriptionOptions {
+    /// Maximum QoS of the messages sent for the subscription
+    pub fn qos(&self) -> QualityOfService {
+        match self {
+            SubscriptionOptions::V4(qos) => *qos,
+            #[cfg(feature = "v5")]
+            SubscriptionOptions::V5 { options, .. } => options.qos(),
+        }
+    }
+
+    /// Whether messages the client publishes itself are not sent back to it
+    pub fn no_local(&self) -> bool {
+        match self {
+            SubscriptionOptions::V4(_) => false,
+            #[cfg(feature = "v5")]
+            SubscriptionOptions::V5 { options, .. } => options.no_local(),
+        }
+    }
+
+    /// Whether forwarded messages keep their RETAIN flag
+    pub fn retain_as_published(&self) -> bool {
+        match self {
+            SubscriptionOptions::V4(_) => false,
+            #[cfg(feature = "v5")]
+            SubscriptionOptions::V5 { options, .. } => options.retain_as_published(),
+        }
+    }
+
+    pub fn identifier(&self) -> Option<usize> {
+        match self {
+            SubscriptionOptions::V4(_) => None,
+            #[cfg(feature = "v5")]
+            SubscriptionOptions::V5 { identifier, .. } => *identifier,
+        }
+    }
+}
+
+impl From<QualityOfService> for SubscriptionOptions {
+    fn from(qos: QualityOfService) -> Self {
+        SubscriptionOptions::V4(qos)
+    }
+}
+
+/// What [`TopicStore::subscribe`] did with a subscription
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SubscribeOutcome {
+    /// The client was not subscribed with the topic filter
+    New,
+    /// The client was subscribed with the topic filter already, its options are replaced
+    Replaced { previous: SubscriptionOptions },
+}
 
 #[derive(Debug, Clone)]
 pub struct TopicContent {
     pub topic_filter: Option<String>,
-    pub clients: HashMap<String, QualityOfService>,
-    pub shared_clients: HashMap<String, HashMap<String, QualityOfService>>,
+    pub clients: HashMap<String, SubscriptionOptions>,
+    pub shared_clients: HashMap<String, HashMap<String, SubscriptionOptions>>,
 }
 
 impl Default for TopicContent {
@@ -24,18 +93,20 @@ pub trait TopicStore: Send + Sync {
     fn match_topic(
         &self,
         topic_name: &TopicName,
-    ) -> impl Future<Output = io::Result<Vec<TopicContent>>> + Send;
+    ) -> impl Future<Output = Result<Vec<TopicContent>, StoreError>> + Send;
 
+    /// Subscribes the client, a subscription with the same topic filter is replaced with the
+    /// new options
     fn subscribe(
         &self,
         client_id: &str,
         topic_filter: &TopicFilter,
-        qos: QualityOfService,
-    ) -> impl Future<Output = io::Result<()>> + Send;
+        options: SubscriptionOptions,
+    ) -> impl Future<Output = Result<SubscribeOutcome, StoreError>> + Send;
 
     fn unsubscribe(
         &self,
         client_id: &str,
         topic_filter: &TopicFilter,
-    ) -> impl Future<Output = io::Result<bool>> + Send;
+    ) -> impl Future<Output = Result<bool, StoreError>> + Send;
 }
@@ -0,0 +1,200 @@
+use std::{
+    collections::BTreeMap,
+    net::SocketAddr,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use maplit::btreeset;
+use mesquitte_core::cluster::{
+    client::{ClusterClient, ClusterHandle},
+    new_raft,
+    store::Request,
+    typ::Raft,
+    NodeId,
+};
+use tempfile::TempDir;
+use turmoil::{Builder, Sim};
+
+const RPC_PORT: u16 = 21001;
+const API_PORT: u16 = 31001;
+
+type Rafts = Arc<Mutex<BTreeMap<NodeId, Raft>>>;
+
+fn sim() -> Sim<'static> {
+    Builder::new()
+        .simulation_duration(Duration::from_secs(300))
+        .build()
+}
+
+fn rpc_addr(id: NodeId) -> SocketAddr {
+    SocketAddr::new(turmoil::lookup(format!("node{id}")), RPC_PORT)
+}
+
+fn start_node(sim: &mut Sim, dir: &Path, id: NodeId, rafts: &Rafts) {
+    let dir = dir.join(id.to_string());
+    let rafts = rafts.clone();
+    sim.host(format!("node{id}"), move || {
+        let dir = dir.clone();
+        let rafts = rafts.clone();
+        async move {
+            let rpc_addr = rpc_addr(id);
+            let api_addr = SocketAddr::new(rpc_addr.ip(), API_PORT);
+            let (raft, app) = new_raft(id, rpc_addr, api_addr, &dir).await;
+            rafts.lock().unwrap().insert(id, raft);
+            app.run().await;
+            Ok(())
+        }
+    });
+}
+
+async fn client(id: NodeId) -> ClusterClient {
+    ClusterClient::new(id, rpc_addr(id).to_string())
+        .await
+        .unwrap()
+}
+
+/// Initializes node 1 and adds `learners`, voters if `voters` is set
+async fn form_cluster(learners: &[NodeId], voters: bool) -> ClusterClient {
+    let mut leader = client(1).await;
+    leader.init().await.unwrap();
+    for &id in learners {
+        let addr = rpc_addr(id);
+        let api_addr = SocketAddr::new(addr.ip(), API_PORT);
+        leader
+            .add_learner((id, addr.to_string(), api_addr.to_string()))
+            .await
+            .unwrap();
+    }
+    if voters {
+        let mut members = btreeset! {1};
+        members.extend(learners);
+        leader.change_membership(&members).await.unwrap();
+    }
+    leader
+}
+
+/// Waits until node `id` sees a leader among `candidates`
+async fn wait_for_leader(id: NodeId, candidates: &[NodeId]) -> NodeId {
+    let client = client(id).await;
+    loop {
+        let metrics = client.metrics().await.unwrap();
+        if let Some(leader) = metrics.current_leader {
+            if candidates.contains(&leader) {
+                return leader;
+            }
+        }
+        tokio::time::sleep(Duration::from_millis(500)).await;
+    }
+}
+
+/// Waits until `key` has `value` on node `id`
+async fn wait_for_value(id: NodeId, key: &str, value: &str) {
+    let client = client(id).await;
+    loop {
+        if client.read(&key.to_string()).await.unwrap().as_deref() == Some(value) {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(200)).await;
+    }
+}
+
+#[test]
+fn leader_failover() -> turmoil::Result {
+    let dir = TempDir::new()?;
+    let rafts = Rafts::default();
+    let mut sim = sim();
+    for id in 1..=3 {
+        start_node(&mut sim, dir.path(), id, &rafts);
+    }
+    sim.client("admin", async {
+        form_cluster(&[2, 3], true).await;
+        let handle = ClusterHandle::new(vec![rpc_addr(2)]);
+        handle.write(Request::set("foo", "bar")).await?;
+        wait_for_value(3, "foo", "bar").await;
+        Ok(())
+    });
+    sim.run()?;
+
+    sim.crash("node1");
+    sim.client("failover", async {
+        let leader = wait_for_leader(2, &[2, 3]).await;
+        assert_eq!(wait_for_leader(3, &[2, 3]).await, leader);
+        let handle = ClusterHandle::new(vec![rpc_addr(2), rpc_addr(3)]);
+        handle.write(Request::set("foo", "baz")).await?;
+        wait_for_value(2, "foo", "baz").await;
+        wait_for_value(3, "foo", "baz").await;
+        Ok(())
+    });
+    sim.run()
+}
+
+#[test]
+fn partition_healing() -> turmoil::Result {
+    let dir = TempDir::new()?;
+    let rafts = Rafts::default();
+    let mut sim = sim();
+    for id in 1..=3 {
+        start_node(&mut sim, dir.path(), id, &rafts);
+    }
+    sim.client("admin", async {
+        form_cluster(&[2, 3], true).await;
+        let handle = ClusterHandle::new(vec![rpc_addr(1)]);
+        handle.write(Request::set("foo", "before")).await?;
+
+        turmoil::partition("node1", "node2");
+        turmoil::partition("node1", "node3");
+        wait_for_leader(2, &[2, 3]).await;
+        let majority = ClusterHandle::new(vec![rpc_addr(2), rpc_addr(3)]);
+        majority.write(Request::set("foo", "during")).await?;
+        // the old leader is cut off from the write
+        let isolated = client(1).await;
+        assert_eq!(
+            isolated.read(&"foo".to_string()).await?.as_deref(),
+            Some("before")
+        );
+
+        turmoil::repair("node1", "node2");
+        turmoil::repair("node1", "node3");
+        wait_for_value(1, "foo", "during").await;
+        let leader = wait_for_leader(1, &[2, 3]).await;
+        assert_eq!(wait_for_leader(2, &[1, 2, 3]).await, leader);
+        Ok(())
+    });
+    sim.run()
+}
+
+#[test]
+fn learner_installs_snapshot() -> turmoil::Result {
+    let dir = TempDir::new()?;
+    let rafts = Rafts::default();
+    let mut sim = sim();
+    for id in 1..=2 {
+        start_node(&mut sim, dir.path(), id, &rafts);
+    }
+    let leader_raft = rafts.clone();
+    sim.client("admin", async move {
+        let mut leader = form_cluster(&[], false).await;
+        for i in 0..100 {
+            leader.write(&Request::set(format!("key{i}"), i)).await?;
+        }
+        let raft = leader_raft.lock().unwrap()[&1].clone();
+        raft.trigger().snapshot().await?;
+        // every log in the snapshot is purged, the learner can only catch up from it
+        while raft.metrics().borrow().purged.is_none() {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+
+        let addr = rpc_addr(2);
+        let api_addr = SocketAddr::new(addr.ip(), API_PORT);
+        leader
+            .add_learner((2, addr.to_string(), api_addr.to_string()))
+            .await?;
+        wait_for_value(2, "key99", "99").await;
+        let learner = leader_raft.lock().unwrap()[&2].clone();
+        assert!(learner.metrics().borrow().snapshot.is_some());
+        Ok(())
+    });
+    sim.run()
+}
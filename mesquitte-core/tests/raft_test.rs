@@ -1,10 +1,12 @@
 use std::{collections::BTreeMap, env, thread, time::Duration};
 
-use client::ClusterClient;
+use backon::ExponentialBuilder;
+use client::{ClusterClient, ClusterHandle};
 use log::{debug, info};
 use maplit::{btreemap, btreeset};
 use mesquitte_core::cluster::*;
 use store::Request;
+use tempfile::TempDir;
 use tokio::runtime::Runtime;
 
 #[tokio::test(flavor = "multi_thread")]
@@ -52,7 +54,9 @@ async fn raft_test() {
 
     info!("=== init single node cluster");
 
-    let mut leader = ClusterClient::new(1, "127.0.0.1:21001".to_string()).await;
+    let mut leader = ClusterClient::new(1, "127.0.0.1:21001".to_string())
+        .await
+        .unwrap();
     debug!("{:?}", leader.init().await);
 
     info!("=== add-learner 2");
@@ -127,12 +131,16 @@ async fn raft_test() {
     assert_eq!("bar", x);
 
     info!("=== read `foo` on node 2");
-    let mut client2 = ClusterClient::new(2, "127.0.0.1:21002".to_string()).await;
+    let mut client2 = ClusterClient::new(2, "127.0.0.1:21002".to_string())
+        .await
+        .unwrap();
     let x = client2.read(&("foo".to_string())).await.unwrap().unwrap();
     assert_eq!("bar", x);
 
     info!("=== read `foo` on node 3");
-    let client3 = ClusterClient::new(3, "127.0.0.1:21003".to_string()).await;
+    let client3 = ClusterClient::new(3, "127.0.0.1:21003".to_string())
+        .await
+        .unwrap();
     let x = client3.read(&("foo".to_string())).await.unwrap().unwrap();
     assert_eq!("bar", x);
 
@@ -160,4 +168,65 @@ async fn raft_test() {
     info!("=== read `foo` on node 3");
     let x = client3.read(&("foo".to_string())).await.unwrap().unwrap();
     assert_eq!("wow", x);
+
+    info!("=== write `foo` through a handle seeded with node 3");
+    let handle = ClusterHandle::new(vec!["127.0.0.1:21003".parse().unwrap()]);
+    handle.write(Request::set("foo", "again")).await.unwrap();
+
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    info!("=== read `foo` on node 1");
+    let x = leader.read(&("foo".to_string())).await.unwrap().unwrap();
+    assert_eq!("again", x);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn cluster_handle_test() {
+    let dir = TempDir::new().unwrap();
+    let (_, app) = new_raft(
+        1,
+        "127.0.0.1:21011".parse().unwrap(),
+        "127.0.0.1:31011".parse().unwrap(),
+        dir.path(),
+    )
+    .await;
+    let _h = thread::spawn(move || {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(app.run());
+    });
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    let backoff = ExponentialBuilder::default()
+        .with_min_delay(Duration::from_millis(10))
+        .with_max_times(2);
+
+    info!("=== no leader before the cluster is initialized");
+    let handle = ClusterHandle::new(vec!["127.0.0.1:21011".parse().unwrap()]).with_backoff(backoff);
+    assert!(matches!(
+        handle.write(Request::set("foo", "bar")).await,
+        Err(error::Error::NoLeader)
+    ));
+
+    let client = ClusterClient::new(1, "127.0.0.1:21011".to_string())
+        .await
+        .unwrap();
+    client.init().await.unwrap();
+
+    info!("=== unreachable seeds are skipped");
+    let handle = ClusterHandle::new(vec![
+        "127.0.0.1:21019".parse().unwrap(),
+        "127.0.0.1:21011".parse().unwrap(),
+    ]);
+    handle.write(Request::set("foo", "bar")).await.unwrap();
+    // the leader is kept for the following writes
+    handle.write(Request::set("foo", "baz")).await.unwrap();
+    let x = client.read(&("foo".to_string())).await.unwrap();
+    assert_eq!(x.as_deref(), Some("baz"));
+
+    info!("=== no leader without a reachable seed");
+    let handle = ClusterHandle::new(vec!["127.0.0.1:21019".parse().unwrap()]).with_backoff(backoff);
+    assert!(matches!(
+        handle.write(Request::set("foo", "bar")).await,
+        Err(error::Error::NoLeader)
+    ));
 }
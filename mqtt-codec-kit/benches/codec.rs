@@ -0,0 +1,118 @@
+//! Encode and decode throughput of the packets a broker handles most
+//!
+//! ```text
+//! cargo bench -p mqtt-codec-kit --bench codec
+//! ```
+
+use bytes::BytesMut;
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput};
+use mqtt_codec_kit::{
+    common::{qos::QoSWithPacketIdentifier, QualityOfService, TopicFilter, TopicName},
+    v4, v5,
+};
+use tokio_util::codec::{Decoder as _, Encoder as _};
+
+const PAYLOAD_SIZES: [usize; 3] = [16, 1024, 64 * 1024];
+
+fn topic_name() -> TopicName {
+    TopicName::new("sensors/building-1/floor-2/temperature").unwrap()
+}
+
+fn v4_publish(size: usize) -> v4::packet::VariablePacket {
+    v4::packet::PublishPacket::new(
+        topic_name(),
+        QoSWithPacketIdentifier::Level1(1),
+        vec![0u8; size],
+    )
+    .into()
+}
+
+fn v5_publish(size: usize) -> v5::packet::VariablePacket {
+    v5::packet::PublishPacket::new(
+        topic_name(),
+        QoSWithPacketIdentifier::Level1(1),
+        vec![0u8; size],
+    )
+    .into()
+}
+
+fn encoded<T, E: tokio_util::codec::Encoder<T>>(mut encoder: E, packet: T) -> BytesMut
+where
+    E::Error: std::fmt::Debug,
+{
+    let mut buf = BytesMut::new();
+    encoder.encode(packet, &mut buf).unwrap();
+    buf
+}
+
+fn publish(c: &mut Criterion) {
+    let mut group = c.benchmark_group("publish");
+    for size in PAYLOAD_SIZES {
+        group.throughput(Throughput::Bytes(size as u64));
+
+        let packet = v4_publish(size);
+        group.bench_with_input(BenchmarkId::new("v4/encode", size), &packet, |b, packet| {
+            let mut encoder = v4::packet::MqttEncoder::new();
+            let mut buf = BytesMut::with_capacity(size + 64);
+            b.iter(|| {
+                buf.clear();
+                encoder.encode(packet.clone(), &mut buf).unwrap();
+            })
+        });
+        let bytes = encoded(v4::packet::MqttEncoder::new(), packet);
+        group.bench_with_input(BenchmarkId::new("v4/decode", size), &bytes, |b, bytes| {
+            let mut decoder = v4::packet::MqttDecoder::new();
+            b.iter_batched_ref(
+                || bytes.clone(),
+                |buf| decoder.decode(buf).unwrap().unwrap(),
+                BatchSize::SmallInput,
+            )
+        });
+
+        let packet = v5_publish(size);
+        group.bench_with_input(BenchmarkId::new("v5/encode", size), &packet, |b, packet| {
+            let mut encoder = v5::packet::MqttEncoder::new();
+            let mut buf = BytesMut::with_capacity(size + 64);
+            b.iter(|| {
+                buf.clear();
+                encoder.encode(packet.clone(), &mut buf).unwrap();
+            })
+        });
+        let bytes = encoded(v5::packet::MqttEncoder::new(), packet);
+        group.bench_with_input(BenchmarkId::new("v5/decode", size), &bytes, |b, bytes| {
+            let mut decoder = v5::packet::MqttDecoder::new();
+            b.iter_batched_ref(
+                || bytes.clone(),
+                |buf| decoder.decode(buf).unwrap().unwrap(),
+                BatchSize::SmallInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+fn subscribe(c: &mut Criterion) {
+    let filters: Vec<_> = (0..16)
+        .map(|i| TopicFilter::new(format!("sensors/+/floor-{i}/#")).unwrap())
+        .collect();
+    let packet: v4::packet::VariablePacket = v4::packet::SubscribePacket::new(
+        1,
+        filters
+            .iter()
+            .map(|filter| (filter.clone(), QualityOfService::Level1))
+            .collect(),
+    )
+    .into();
+    let bytes = encoded(v4::packet::MqttEncoder::new(), packet);
+    c.bench_function("subscribe/v4/decode", |b| {
+        let mut decoder = v4::packet::MqttDecoder::new();
+        b.iter_batched_ref(
+            || bytes.clone(),
+            |buf| decoder.decode(buf).unwrap().unwrap(),
+            BatchSize::SmallInput,
+        )
+    });
+}
+
+criterion_group!(benches, publish, subscribe);
+criterion_main!(benches);
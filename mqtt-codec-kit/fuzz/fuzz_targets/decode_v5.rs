@@ -0,0 +1,10 @@
+#![no_main]
+
+use std::io::Cursor;
+
+use libfuzzer_sys::fuzz_target;
+use mqtt_codec_kit::{common::Decodable, v5::packet::VariablePacket};
+
+fuzz_target!(|data: &[u8]| {
+    let _ = VariablePacket::decode(&mut Cursor::new(data));
+});
@@ -0,0 +1,17 @@
+#![no_main]
+
+use std::io::Cursor;
+
+use libfuzzer_sys::fuzz_target;
+use mqtt_codec_kit::{
+    common::{Decodable, Encodable},
+    v4::packet::VariablePacket,
+};
+
+fuzz_target!(|packet: VariablePacket| {
+    let mut buf = Vec::new();
+    packet.encode(&mut buf).unwrap();
+
+    let decoded = VariablePacket::decode(&mut Cursor::new(&buf[..])).unwrap();
+    assert_eq!(packet, decoded);
+});
@@ -0,0 +1,116 @@
+//! `Arbitrary` implementations for fuzzing
+//!
+//! Generated values always satisfy the codec's invariants, so an encode→decode
+//! round trip is expected to reproduce them exactly.
+
+use arbitrary::{Arbitrary, Result, Unstructured};
+
+use crate::common::{
+    qos::QoSWithPacketIdentifier, TopicFilter, TopicName, MATCH_ALL_STR, MATCH_ONE_STR,
+};
+
+/// Upper bound of list sizes, keeps generated packets small
+const MAX_ITEMS: usize = 8;
+
+/// UTF-8 string that fits into a two byte length prefix
+pub(crate) fn arbitrary_string(u: &mut Unstructured<'_>) -> Result<String> {
+    let mut s = String::arbitrary(u)?;
+    if s.len() > u16::MAX as usize {
+        let mut end = u16::MAX as usize;
+        while !s.is_char_boundary(end) {
+            end -= 1;
+        }
+        s.truncate(end);
+    }
+    Ok(s)
+}
+
+pub(crate) fn arbitrary_option_string(u: &mut Unstructured<'_>) -> Result<Option<String>> {
+    if bool::arbitrary(u)? {
+        arbitrary_string(u).map(Some)
+    } else {
+        Ok(None)
+    }
+}
+
+pub(crate) fn arbitrary_bytes(u: &mut Unstructured<'_>) -> Result<Vec<u8>> {
+    let len = u.int_in_range(0..=u16::MAX as usize)?.min(u.len());
+    Ok(u.bytes(len)?.to_vec())
+}
+
+#[cfg(feature = "v5")]
+pub(crate) fn arbitrary_option_bytes(u: &mut Unstructured<'_>) -> Result<Option<Vec<u8>>> {
+    if bool::arbitrary(u)? {
+        arbitrary_bytes(u).map(Some)
+    } else {
+        Ok(None)
+    }
+}
+
+pub(crate) fn arbitrary_len(u: &mut Unstructured<'_>, min: usize) -> Result<usize> {
+    u.int_in_range(min..=MAX_ITEMS)
+}
+
+#[cfg(feature = "v5")]
+pub(crate) fn arbitrary_user_properties(u: &mut Unstructured<'_>) -> Result<Vec<(String, String)>> {
+    let len = arbitrary_len(u, 0)?;
+    (0..len)
+        .map(|_| Ok((arbitrary_string(u)?, arbitrary_string(u)?)))
+        .collect()
+}
+
+/// A topic level without wildcards, separators or null characters
+fn arbitrary_level(u: &mut Unstructured<'_>) -> Result<String> {
+    let level = arbitrary_string(u)?;
+    Ok(level
+        .chars()
+        .filter(|ch| !matches!(ch, '/' | '#' | '+' | '\0'))
+        .take(32)
+        .collect())
+}
+
+impl<'a> Arbitrary<'a> for TopicName {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        let len = arbitrary_len(u, 1)?;
+        let levels = (0..len)
+            .map(|_| arbitrary_level(u))
+            .collect::<Result<Vec<_>>>()?;
+        let mut topic_name = levels.join("/");
+        if topic_name.is_empty() {
+            topic_name.push('a');
+        }
+        Ok(TopicName::new(topic_name).expect("generated topic name should be valid"))
+    }
+}
+
+impl<'a> Arbitrary<'a> for TopicFilter {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        let len = arbitrary_len(u, 1)?;
+        let mut levels = Vec::with_capacity(len + 1);
+        for _ in 0..len {
+            if bool::arbitrary(u)? {
+                levels.push(MATCH_ONE_STR.to_owned());
+            } else {
+                levels.push(arbitrary_level(u)?);
+            }
+        }
+        if bool::arbitrary(u)? {
+            levels.push(MATCH_ALL_STR.to_owned());
+        }
+        let mut topic_filter = levels.join("/");
+        if topic_filter.is_empty() {
+            topic_filter.push('a');
+        }
+        Ok(TopicFilter::new(topic_filter).expect("generated topic filter should be valid"))
+    }
+}
+
+impl<'a> Arbitrary<'a> for QoSWithPacketIdentifier {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        Ok(match u.int_in_range(0..=2)? {
+            0 => QoSWithPacketIdentifier::Level0,
+            1 => QoSWithPacketIdentifier::Level1(u16::arbitrary(u)?),
+            _ => QoSWithPacketIdentifier::Level2(u16::arbitrary(u)?),
+        })
+    }
+}
@@ -1,13 +1,9 @@
-use std::{
-    convert::Infallible,
-    error::Error,
-    fmt::Display,
-    io::{self, Read, Write},
-    marker::Sized,
-    slice,
-};
+use alloc::{string::String, vec::Vec};
+use core::{convert::Infallible, error::Error, fmt::Display, marker::Sized, slice};
 
-use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use byteorder::BigEndian;
+
+use crate::common::io::{self, Read, ReadBytesExt, Write, WriteBytesExt};
 
 pub trait Encodable {
     /// Encodes to writer
@@ -43,6 +39,46 @@ impl Encodable for &str {
     }
 }
 
+/// Reasons a string can not be sent as an MQTT UTF-8 Encoded String
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum Utf8StringError {
+    #[error("string of {0} bytes exceeds 65535 bytes")]
+    TooLong(usize),
+    #[error("string contains the null character")]
+    NullCharacter,
+    #[error("string contains the control character {0:?}")]
+    ControlCharacter(char),
+    #[error("string contains the non-character {0:?}")]
+    NonCharacter(char),
+}
+
+/// Checks the length limit and the U+0000 restriction of UTF-8 Encoded Strings
+pub fn check_utf8_string(s: &str) -> Result<(), Utf8StringError> {
+    if s.len() > u16::MAX as usize {
+        return Err(Utf8StringError::TooLong(s.len()));
+    }
+    if s.contains('\0') {
+        return Err(Utf8StringError::NullCharacter);
+    }
+    Ok(())
+}
+
+/// Like [`check_utf8_string`], but also rejects the control characters and Unicode
+/// non-characters which the specification says a string should not include
+pub fn check_utf8_string_strict(s: &str) -> Result<(), Utf8StringError> {
+    check_utf8_string(s)?;
+    for ch in s.chars() {
+        if ch.is_control() {
+            return Err(Utf8StringError::ControlCharacter(ch));
+        }
+        let code = ch as u32;
+        if (0xFDD0..=0xFDEF).contains(&code) || code & 0xFFFE == 0xFFFE {
+            return Err(Utf8StringError::NonCharacter(ch));
+        }
+    }
+    Ok(())
+}
+
 impl Encodable for &[u8] {
     fn encode<W: Write>(&self, writer: &mut W) -> Result<(), io::Error> {
         writer.write_all(self)
@@ -142,6 +178,7 @@ impl Decodable for () {
 
 /// Bytes that encoded with length
 #[derive(Debug, Eq, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct VarBytes(pub Vec<u8>);
 
 impl Encodable for VarBytes {
@@ -171,8 +208,8 @@ impl Decodable for VarBytes {
 }
 
 impl Display for VarBytes {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match std::str::from_utf8(&self.0) {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match core::str::from_utf8(&self.0) {
             Ok(s) if s.chars().all(|c| c.is_ascii_graphic() || c == ' ') => {
                 write!(f, "{}", s)
             }
@@ -192,6 +229,7 @@ impl Display for VarBytes {
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct VarInt(pub u32);
 
 impl Encodable for VarInt {
@@ -251,7 +289,7 @@ impl Decodable for VarInt {
 mod test {
     use super::*;
 
-    use std::io::Cursor;
+    use crate::common::io::Cursor;
 
     #[test]
     fn varbyte_encode() {
@@ -270,4 +308,30 @@ mod test {
 
         assert_eq!(decoded, bytes);
     }
+
+    #[test]
+    fn utf8_string_strict() {
+        check_utf8_string_strict("sport/tennis/player1").unwrap();
+        check_utf8_string_strict("温度/传感器").unwrap();
+
+        assert_eq!(
+            check_utf8_string_strict("a\0b"),
+            Err(Utf8StringError::NullCharacter)
+        );
+        assert_eq!(
+            check_utf8_string_strict("a\u{1b}b"),
+            Err(Utf8StringError::ControlCharacter('\u{1b}'))
+        );
+        assert_eq!(
+            check_utf8_string_strict("a\u{9f}b"),
+            Err(Utf8StringError::ControlCharacter('\u{9f}'))
+        );
+        assert_eq!(
+            check_utf8_string_strict("a\u{fffe}"),
+            Err(Utf8StringError::NonCharacter('\u{fffe}'))
+        );
+        // U+0000 is also rejected by the non-strict check
+        assert_eq!(check_utf8_string("\0"), Err(Utf8StringError::NullCharacter));
+        check_utf8_string("a\u{1b}b").unwrap();
+    }
 }
@@ -0,0 +1,286 @@
+//! I/O traits used by the synchronous codec
+//!
+//! With the `std` feature these are re-exports of `std::io` and `byteorder`. Without it, a
+//! minimal alloc-only replacement is provided, covering exactly what `Encodable` and
+//! `Decodable` need, so packets can be encoded into a `Vec<u8>` and decoded from a `&[u8]`
+//! or `Cursor` on embedded targets.
+
+#[cfg(feature = "std")]
+pub use byteorder::{ReadBytesExt, WriteBytesExt};
+#[cfg(feature = "std")]
+pub use std::io::{Cursor, Error, ErrorKind, Read, Result, Take, Write};
+
+#[cfg(not(feature = "std"))]
+pub use self::no_std::*;
+
+#[cfg(not(feature = "std"))]
+mod no_std {
+    use alloc::{boxed::Box, vec::Vec};
+    use core::{cmp, fmt};
+
+    use byteorder::ByteOrder;
+
+    pub type Result<T> = core::result::Result<T, Error>;
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    #[non_exhaustive]
+    pub enum ErrorKind {
+        InvalidData,
+        UnexpectedEof,
+        WriteZero,
+        Other,
+    }
+
+    impl ErrorKind {
+        fn as_str(&self) -> &'static str {
+            match *self {
+                ErrorKind::InvalidData => "invalid data",
+                ErrorKind::UnexpectedEof => "unexpected end of file",
+                ErrorKind::WriteZero => "write zero",
+                ErrorKind::Other => "other error",
+            }
+        }
+    }
+
+    pub struct Error {
+        kind: ErrorKind,
+        error: Option<Box<dyn core::error::Error + Send + Sync>>,
+    }
+
+    impl Error {
+        pub fn new<E>(kind: ErrorKind, error: E) -> Self
+        where
+            E: Into<Box<dyn core::error::Error + Send + Sync>>,
+        {
+            Self {
+                kind,
+                error: Some(error.into()),
+            }
+        }
+
+        pub fn kind(&self) -> ErrorKind {
+            self.kind
+        }
+    }
+
+    impl From<ErrorKind> for Error {
+        fn from(kind: ErrorKind) -> Self {
+            Self { kind, error: None }
+        }
+    }
+
+    impl fmt::Debug for Error {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.debug_struct("Error")
+                .field("kind", &self.kind)
+                .field("error", &self.error)
+                .finish()
+        }
+    }
+
+    impl fmt::Display for Error {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match &self.error {
+                Some(error) => fmt::Display::fmt(error, f),
+                None => f.write_str(self.kind.as_str()),
+            }
+        }
+    }
+
+    impl core::error::Error for Error {
+        fn source(&self) -> Option<&(dyn core::error::Error + 'static)> {
+            match &self.error {
+                Some(error) => error.source(),
+                None => None,
+            }
+        }
+    }
+
+    pub trait Read {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize>;
+
+        fn read_exact(&mut self, mut buf: &mut [u8]) -> Result<()> {
+            while !buf.is_empty() {
+                match self.read(buf)? {
+                    0 => return Err(ErrorKind::UnexpectedEof.into()),
+                    n => buf = &mut buf[n..],
+                }
+            }
+            Ok(())
+        }
+
+        fn read_to_end(&mut self, buf: &mut Vec<u8>) -> Result<usize> {
+            let start = buf.len();
+            let mut chunk = [0u8; 64];
+            loop {
+                match self.read(&mut chunk)? {
+                    0 => return Ok(buf.len() - start),
+                    n => buf.extend_from_slice(&chunk[..n]),
+                }
+            }
+        }
+
+        fn take(self, limit: u64) -> Take<Self>
+        where
+            Self: Sized,
+        {
+            Take { inner: self, limit }
+        }
+    }
+
+    impl<R: Read + ?Sized> Read for &mut R {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+            (**self).read(buf)
+        }
+    }
+
+    impl Read for &[u8] {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+            let n = cmp::min(buf.len(), self.len());
+            let (head, tail) = self.split_at(n);
+            buf[..n].copy_from_slice(head);
+            *self = tail;
+            Ok(n)
+        }
+    }
+
+    pub trait Write {
+        fn write(&mut self, buf: &[u8]) -> Result<usize>;
+
+        fn flush(&mut self) -> Result<()> {
+            Ok(())
+        }
+
+        fn write_all(&mut self, mut buf: &[u8]) -> Result<()> {
+            while !buf.is_empty() {
+                match self.write(buf)? {
+                    0 => return Err(ErrorKind::WriteZero.into()),
+                    n => buf = &buf[n..],
+                }
+            }
+            Ok(())
+        }
+    }
+
+    impl<W: Write + ?Sized> Write for &mut W {
+        fn write(&mut self, buf: &[u8]) -> Result<usize> {
+            (**self).write(buf)
+        }
+    }
+
+    impl Write for Vec<u8> {
+        fn write(&mut self, buf: &[u8]) -> Result<usize> {
+            self.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+    }
+
+    /// Reader adapter which limits the bytes read from an underlying reader
+    pub struct Take<R> {
+        inner: R,
+        limit: u64,
+    }
+
+    impl<R> Take<R> {
+        pub fn limit(&self) -> u64 {
+            self.limit
+        }
+
+        pub fn into_inner(self) -> R {
+            self.inner
+        }
+    }
+
+    impl<R: Read> Read for Take<R> {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+            if self.limit == 0 {
+                return Ok(0);
+            }
+            let max = cmp::min(buf.len() as u64, self.limit) as usize;
+            let n = self.inner.read(&mut buf[..max])?;
+            self.limit -= n as u64;
+            Ok(n)
+        }
+    }
+
+    /// In-memory reader over a byte buffer
+    #[derive(Debug, Default, Clone)]
+    pub struct Cursor<T> {
+        inner: T,
+        pos: u64,
+    }
+
+    impl<T> Cursor<T> {
+        pub const fn new(inner: T) -> Self {
+            Self { inner, pos: 0 }
+        }
+
+        pub fn into_inner(self) -> T {
+            self.inner
+        }
+
+        pub const fn get_ref(&self) -> &T {
+            &self.inner
+        }
+
+        pub const fn position(&self) -> u64 {
+            self.pos
+        }
+
+        pub fn set_position(&mut self, pos: u64) {
+            self.pos = pos;
+        }
+    }
+
+    impl<T: AsRef<[u8]>> Read for Cursor<T> {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+            let data = self.inner.as_ref();
+            let start = cmp::min(self.pos, data.len() as u64) as usize;
+            let n = (&data[start..]).read(buf)?;
+            self.pos += n as u64;
+            Ok(n)
+        }
+    }
+
+    pub trait ReadBytesExt: Read {
+        fn read_u8(&mut self) -> Result<u8> {
+            let mut buf = [0; 1];
+            self.read_exact(&mut buf)?;
+            Ok(buf[0])
+        }
+
+        fn read_u16<T: ByteOrder>(&mut self) -> Result<u16> {
+            let mut buf = [0; 2];
+            self.read_exact(&mut buf)?;
+            Ok(T::read_u16(&buf))
+        }
+
+        fn read_u32<T: ByteOrder>(&mut self) -> Result<u32> {
+            let mut buf = [0; 4];
+            self.read_exact(&mut buf)?;
+            Ok(T::read_u32(&buf))
+        }
+    }
+
+    impl<R: Read + ?Sized> ReadBytesExt for R {}
+
+    pub trait WriteBytesExt: Write {
+        fn write_u8(&mut self, n: u8) -> Result<()> {
+            self.write_all(&[n])
+        }
+
+        fn write_u16<T: ByteOrder>(&mut self, n: u16) -> Result<()> {
+            let mut buf = [0; 2];
+            T::write_u16(&mut buf, n);
+            self.write_all(&buf)
+        }
+
+        fn write_u32<T: ByteOrder>(&mut self, n: u32) -> Result<()> {
+            let mut buf = [0; 4];
+            T::write_u32(&mut buf, n);
+            self.write_all(&buf)
+        }
+    }
+
+    impl<W: Write + ?Sized> WriteBytesExt for W {}
+}
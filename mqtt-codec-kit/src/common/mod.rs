@@ -6,7 +6,10 @@ pub use self::{
     variable_header::*,
 };
 
+#[cfg(feature = "arbitrary")]
+pub(crate) mod arbitrary;
 pub mod encodable;
+pub mod io;
 pub mod packet;
 pub mod qos;
 pub mod topic_filter;
@@ -26,6 +29,9 @@ pub const MATCH_ALL_STR: &str = "#";
 /// The &str version of `MATCH_DOLLAR_STR`
 pub const MATCH_DOLLAR_STR: &str = "$";
 
+/// Largest value the Remaining Length of a fixed header can carry
+pub const MAX_REMAINING_LENGTH: u32 = 0x0FFF_FFFF;
+
 /// System topic prefix
 pub const SYS_PREFIX: &str = "$SYS/";
 /// Shared topic prefix
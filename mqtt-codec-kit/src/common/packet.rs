@@ -1,9 +1,7 @@
-use std::{
-    error::Error,
-    io::{self, Read, Write},
-};
+use core::error::Error;
 
 use super::Encodable;
+use crate::common::io::{self, Read, Write};
 
 /// A trait representing a packet that can be encoded, when passed as `FooPacket` or as
 /// `&FooPacket`. Different from [`Encodable`] in that it prevents you from accidentally passing
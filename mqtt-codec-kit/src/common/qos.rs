@@ -1,9 +1,11 @@
 //! QoS (Quality of Services)
 
-use std::fmt::Display;
+use core::fmt::Display;
 
 #[repr(u8)]
 #[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 pub enum QualityOfService {
     Level0 = 0,
     Level1 = 1,
@@ -21,13 +23,14 @@ impl From<QoSWithPacketIdentifier> for QualityOfService {
 }
 
 impl Display for QualityOfService {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         write!(f, "{}", *self as u8)
     }
 }
 
 /// QoS with identifier pairs
 #[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum QoSWithPacketIdentifier {
     Level0,
     Level1(u16),
@@ -55,7 +58,7 @@ impl QoSWithPacketIdentifier {
 #[cfg(test)]
 mod test {
     use super::*;
-    use std::cmp::min;
+    use core::cmp::min;
 
     #[test]
     fn min_qos() {
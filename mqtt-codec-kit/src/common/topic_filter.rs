@@ -1,12 +1,11 @@
 //! Topic filter
 
-use std::{
-    fmt::Display,
-    io::{self, Read, Write},
-    ops::Deref,
-};
+use alloc::{borrow::ToOwned, string::String};
+use core::{fmt::Display, ops::Deref};
 
+use crate::common::io::{self, Read, Write};
 use crate::common::{
+    encodable::{check_utf8_string_strict, Utf8StringError},
     TopicNameRef, {Decodable, Encodable},
 };
 
@@ -28,7 +27,7 @@ fn topic_filter_shared_info(topic: &str) -> Option<(&str, &str)> {
 
 #[inline]
 fn is_invalid_topic_filter(topic: &str) -> bool {
-    if topic.is_empty() || topic.len() > 65535 {
+    if topic.is_empty() || topic.len() > 65535 || topic.contains('\0') {
         return true;
     }
 
@@ -75,6 +74,8 @@ fn is_invalid_topic_filter(topic: &str) -> bool {
 /// assert!(matcher.is_match(TopicNameRef::new("sport/abc/player1").unwrap()));
 /// ```
 #[derive(Debug, Eq, PartialEq, Clone, Hash, Ord, PartialOrd)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(try_from = "String", into = "String"))]
 pub struct TopicFilter(String);
 
 impl TopicFilter {
@@ -127,6 +128,14 @@ impl From<TopicFilter> for String {
     }
 }
 
+impl TryFrom<String> for TopicFilter {
+    type Error = TopicFilterError;
+
+    fn try_from(topic: String) -> Result<Self, Self::Error> {
+        TopicFilter::new(topic)
+    }
+}
+
 impl Encodable for TopicFilter {
     fn encode<W: Write>(&self, writer: &mut W) -> Result<(), io::Error> {
         (&self.0[..]).encode(writer)
@@ -156,7 +165,7 @@ impl Deref for TopicFilter {
 }
 
 impl Display for TopicFilter {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         write!(f, "{}", self.0)
     }
 }
@@ -193,6 +202,22 @@ impl TopicFilterRef {
     pub fn get_matcher(&self) -> TopicFilterMatcher<'_> {
         TopicFilterMatcher::new(&self.0)
     }
+
+    /// Stricter validation for topic filters received by a server
+    ///
+    /// Also rejects control characters and Unicode non-characters, which are allowed by
+    /// [`TopicFilter::new`] but should not appear in a topic filter.
+    pub fn validate_strict(&self) -> Result<(), Utf8StringError> {
+        check_utf8_string_strict(&self.0)
+    }
+
+    /// Whether the filter has a valid syntax, as checked by [`TopicFilter::new`]
+    ///
+    /// The filters of a decoded SUBSCRIBE packet are not checked, a server refuses the invalid
+    /// ones in its acknowledgement.
+    pub fn is_valid(&self) -> bool {
+        !is_invalid_topic_filter(&self.0)
+    }
 }
 
 impl Deref for TopicFilterRef {
@@ -287,6 +312,21 @@ impl<'a> TopicFilterMatcher<'a> {
 mod test {
     use super::*;
 
+    #[test]
+    fn topic_filter_invalid_chars() {
+        assert!(TopicFilter::new("a/\0/#").is_err());
+
+        let topic = TopicFilter::new("a/\u{7f}/#").unwrap();
+        assert_eq!(
+            topic.validate_strict(),
+            Err(Utf8StringError::ControlCharacter('\u{7f}'))
+        );
+        TopicFilter::new("a/+/#")
+            .unwrap()
+            .validate_strict()
+            .unwrap();
+    }
+
     #[test]
     fn topic_filter_validate() {
         let topic = "#".to_owned();
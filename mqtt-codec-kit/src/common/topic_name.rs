@@ -1,29 +1,50 @@
 //! Topic name
 
-use std::{
-    borrow::{Borrow, BorrowMut},
+use alloc::{
+    borrow::{Borrow, BorrowMut, ToOwned},
+    string::String,
+};
+use core::{
     fmt::Display,
-    io::{self, Read, Write},
     ops::{Deref, DerefMut},
     str::FromStr,
 };
 
-use crate::common::{Decodable, Encodable};
+use crate::common::io::{self, Read, Write};
+use crate::common::{
+    encodable::{check_utf8_string_strict, Utf8StringError},
+    Decodable, Encodable,
+};
 
 #[inline]
 fn is_invalid_topic_name(topic_name: &str) -> bool {
     topic_name.is_empty()
         || topic_name.len() > 65535
-        || topic_name.chars().any(|ch| ch == '#' || ch == '+')
+        || topic_name
+            .chars()
+            .any(|ch| ch == '#' || ch == '+' || ch == '\0')
 }
 
 /// Topic name
 ///
 /// [MQTT v3.1.1](https://docs.oasis-open.org/mqtt/mqtt/v3.1.1/os/mqtt-v3.1.1-os.html#_Toc398718106)
 /// [MQTT v5.0](https://docs.oasis-open.org/mqtt/mqtt/v5.0/os/mqtt-v5.0-os.html#_Toc3901241)
-#[derive(Debug, Eq, PartialEq, Clone, Hash, Ord, PartialOrd)]
+#[derive(Debug, Eq, PartialEq, Hash, Ord, PartialOrd)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(try_from = "String", into = "String"))]
 pub struct TopicName(String);
 
+impl Clone for TopicName {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+
+    /// Reuses the buffer of `self` when it is large enough
+    fn clone_from(&mut self, source: &Self) {
+        self.0.clone_from(&source.0);
+    }
+}
+
 impl TopicName {
     /// Creates a new topic name from string
     /// Return error if the string is not a valid topic name
@@ -55,6 +76,14 @@ impl From<TopicName> for String {
     }
 }
 
+impl TryFrom<String> for TopicName {
+    type Error = TopicNameError;
+
+    fn try_from(topic_name: String) -> Result<Self, Self::Error> {
+        TopicName::new(topic_name)
+    }
+}
+
 impl FromStr for TopicName {
     type Err = TopicNameError;
 
@@ -110,7 +139,7 @@ impl Decodable for TopicName {
 }
 
 impl Display for TopicName {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         write!(f, "{}", self.0)
     }
 }
@@ -189,6 +218,14 @@ impl TopicNameRef {
     pub fn is_server_specific(&self) -> bool {
         self.0.starts_with('$')
     }
+
+    /// Stricter validation for topic names received by a server
+    ///
+    /// Also rejects control characters and Unicode non-characters, which are allowed by
+    /// [`TopicName::new`] but should not appear in a topic name.
+    pub fn validate_strict(&self) -> Result<(), Utf8StringError> {
+        check_utf8_string_strict(&self.0)
+    }
 }
 
 impl Deref for TopicNameRef {
@@ -219,6 +256,7 @@ impl Encodable for TopicNameRef {
 
 /// Topic name wrapper
 #[derive(Debug, Eq, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct TopicNameHeader(TopicName);
 
 impl TopicNameHeader {
@@ -273,6 +311,19 @@ mod test {
         TopicName::new("/").unwrap();
     }
 
+    #[test]
+    fn topic_name_invalid_chars() {
+        assert!(TopicName::new("a/\0/b").is_err());
+        assert!(TopicName::new("a".repeat(65536)).is_err());
+
+        let topic_name = TopicName::new("a/\u{7}/b").unwrap();
+        assert_eq!(
+            topic_name.validate_strict(),
+            Err(Utf8StringError::ControlCharacter('\u{7}'))
+        );
+        TopicName::new("a/b").unwrap().validate_strict().unwrap();
+    }
+
     #[test]
     fn topic_name_basic() {
         TopicName::new("/finance").unwrap();
@@ -1,14 +1,11 @@
-use std::{
-    fmt::Display,
-    io::{self, Read, Write},
-};
-
-use byteorder::{ReadBytesExt, WriteBytesExt};
+use core::fmt::Display;
 
+use crate::common::io::{self, Read, ReadBytesExt, Write, WriteBytesExt};
 use crate::common::{Decodable, Encodable};
 
 /// Flags in `CONNACK` packet
 #[derive(Debug, Eq, PartialEq, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct ConnackFlags {
     pub session_present: bool,
 }
@@ -49,7 +46,7 @@ impl Decodable for ConnackFlags {
 }
 
 impl Display for ConnackFlags {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         write!(f, "{{session_present: {}}}", self.session_present)
     }
 }
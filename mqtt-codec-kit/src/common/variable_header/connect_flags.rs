@@ -1,14 +1,11 @@
-use std::{
-    fmt::Display,
-    io::{self, Read, Write},
-};
-
-use byteorder::{ReadBytesExt, WriteBytesExt};
+use core::fmt::Display;
 
+use crate::common::io::{self, Read, ReadBytesExt, Write, WriteBytesExt};
 use crate::common::{Decodable, Encodable};
 
 /// Flags for `CONNECT` packet
 #[derive(Debug, Eq, PartialEq, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct ConnectFlags {
     pub username: bool,
     pub password: bool,
@@ -74,7 +71,7 @@ impl Decodable for ConnectFlags {
 }
 
 impl Display for ConnectFlags {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         write!(
             f,
             "{{username: {}, password: {}, will_retain: {}, will_qos: {}, will_flag: {}, clean_session: {}, reserved: {}}}",
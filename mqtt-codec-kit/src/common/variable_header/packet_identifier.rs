@@ -1,14 +1,13 @@
-use std::{
-    fmt::Display,
-    io::{self, Read, Write},
-};
+use core::fmt::Display;
 
-use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use byteorder::BigEndian;
 
+use crate::common::io::{self, Read, ReadBytesExt, Write, WriteBytesExt};
 use crate::common::{Decodable, Encodable};
 
 /// Packet identifier
 #[derive(Debug, Eq, PartialEq, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct PacketIdentifier(pub u16);
 
 impl Encodable for PacketIdentifier {
@@ -31,7 +30,7 @@ impl Decodable for PacketIdentifier {
 }
 
 impl Display for PacketIdentifier {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         write!(f, "{}", self.0)
     }
 }
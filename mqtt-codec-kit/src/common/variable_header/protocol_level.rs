@@ -1,12 +1,8 @@
 //! Protocol level header
 
-use std::{
-    fmt::Display,
-    io::{self, Read, Write},
-};
-
-use byteorder::{ReadBytesExt, WriteBytesExt};
+use core::fmt::Display;
 
+use crate::common::io::{self, Read, ReadBytesExt, Write, WriteBytesExt};
 use crate::common::{Decodable, Encodable};
 
 pub const SPEC_3_1_0: u8 = 0x03;
@@ -15,6 +11,7 @@ pub const SPEC_5_0: u8 = 0x05;
 
 /// Protocol level in MQTT (`0x04` in v3.1.1)
 #[derive(Debug, Eq, PartialEq, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[repr(u8)]
 pub enum ProtocolLevel {
     Version310 = SPEC_3_1_0,
@@ -55,7 +52,7 @@ impl Decodable for ProtocolLevel {
 }
 
 impl Display for ProtocolLevel {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         write!(f, "{}", *self as u8)
     }
 }
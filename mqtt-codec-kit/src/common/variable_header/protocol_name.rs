@@ -1,8 +1,7 @@
-use std::{
-    fmt::Display,
-    io::{self, Read, Write},
-};
+use alloc::string::String;
+use core::fmt::Display;
 
+use crate::common::io::{self, Read, Write};
 use crate::common::{Decodable, Encodable};
 
 /// Protocol name in variable header
@@ -21,6 +20,7 @@ use crate::common::{Decodable, Encodable};
 /// +--------------------------+--------------------------+
 /// ```
 #[derive(Debug, Eq, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct ProtocolName(pub String);
 
 impl Encodable for ProtocolName {
@@ -43,7 +43,7 @@ impl Decodable for ProtocolName {
 }
 
 impl Display for ProtocolName {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         write!(f, "{}", self.0)
     }
 }
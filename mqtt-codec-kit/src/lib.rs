@@ -1,5 +1,11 @@
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
+
+extern crate alloc;
+
 pub mod common;
 #[cfg(any(feature = "v4", feature = "parse"))]
 pub mod v4;
 #[cfg(any(feature = "v5", feature = "parse"))]
 pub mod v5;
+#[cfg(feature = "wasm")]
+pub mod wasm;
@@ -1,14 +1,11 @@
 //! Fixed header in MQTT
 
-use std::{
-    fmt::Display,
-    io::{self, Read, Write},
-};
+use core::fmt::Display;
 
-use byteorder::{ReadBytesExt, WriteBytesExt};
 #[cfg(all(feature = "v4", feature = "parse"))]
 use tokio::io::{AsyncRead, AsyncReadExt};
 
+use crate::common::io::{self, Read, ReadBytesExt, Write, WriteBytesExt};
 use crate::common::{Decodable, Encodable};
 
 use super::{packet_type::PacketTypeError, PacketType};
@@ -26,6 +23,7 @@ use super::{packet_type::PacketTypeError, PacketType};
 /// +-----------------------------------------------------+
 /// ```
 #[derive(Debug, Clone, Copy, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct FixedHeader {
     /// Packet Type
     pub packet_type: PacketType,
@@ -152,7 +150,7 @@ impl Decodable for FixedHeader {
 }
 
 impl Display for FixedHeader {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         write!(
             f,
             "{{packet_type: {}, remaining_length: {}}}",
@@ -175,7 +173,7 @@ pub enum FixedHeaderError {
 
 #[cfg(test)]
 mod test {
-    use std::io::Cursor;
+    use crate::common::io::Cursor;
 
     use crate::v4::control::packet_type::{ControlType, PacketType};
 
@@ -1,10 +1,6 @@
-use std::{
-    fmt::Display,
-    io::{self, Read, Write},
-};
-
-use byteorder::{ReadBytesExt, WriteBytesExt};
+use core::fmt::Display;
 
+use crate::common::io::{self, Read, ReadBytesExt, Write, WriteBytesExt};
 use crate::{
     common::{Decodable, Encodable},
     v4::control::variable_header::VariableHeaderError,
@@ -19,6 +15,7 @@ pub const NOT_AUTHORIZED: u8 = 0x05;
 
 /// Return code for `CONNACK` packet
 #[derive(Debug, Eq, PartialEq, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum ConnectReturnCode {
     ConnectionAccepted,
     UnacceptableProtocolVersion,
@@ -84,7 +81,7 @@ impl Decodable for ConnectReturnCode {
 }
 
 impl Display for ConnectReturnCode {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         let code: u8 = self.into();
         write!(f, "{}", code)
     }
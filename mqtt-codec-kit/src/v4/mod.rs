@@ -5,7 +5,7 @@
 //! ## Usage
 //!
 //! ```rust
-//! use std::io::Cursor;
+//! use mqtt_codec_kit::common::io::Cursor;
 //!
 //! use mqtt_codec_kit::common::{qos::QoSWithPacketIdentifier, Decodable, Encodable, TopicName};
 //! use mqtt_codec_kit::v4::packet::{PublishPacket, VariablePacket};
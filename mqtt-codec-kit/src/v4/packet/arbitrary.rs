@@ -0,0 +1,207 @@
+//! `Arbitrary` implementations for fuzzing
+
+use arbitrary::{Arbitrary, Result, Unstructured};
+
+use crate::{
+    common::{
+        arbitrary::{arbitrary_bytes, arbitrary_len, arbitrary_option_string, arbitrary_string},
+        qos::QoSWithPacketIdentifier,
+        QualityOfService, TopicFilter, TopicName,
+    },
+    v4::{
+        control::ConnectReturnCode,
+        packet::{
+            connect::LastWill, suback::SubscribeReturnCode, ConnackPacket, ConnectPacket,
+            DisconnectPacket, PingreqPacket, PingrespPacket, PubackPacket, PubcompPacket,
+            PublishPacket, PubrecPacket, PubrelPacket, SubackPacket, SubscribePacket,
+            UnsubackPacket, UnsubscribePacket, VariablePacket,
+        },
+    },
+};
+
+impl<'a> Arbitrary<'a> for ConnectReturnCode {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        Ok(u8::arbitrary(u)?.into())
+    }
+}
+
+impl<'a> Arbitrary<'a> for ConnectPacket {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        let mut packet = ConnectPacket::new(arbitrary_string(u)?);
+        packet.set_keep_alive(u16::arbitrary(u)?);
+        packet.set_clean_session(bool::arbitrary(u)?);
+        packet.set_username(arbitrary_option_string(u)?);
+        packet.set_password(arbitrary_option_string(u)?);
+        if bool::arbitrary(u)? {
+            let topic = TopicName::arbitrary(u)?;
+            let will = LastWill::new(topic, arbitrary_bytes(u)?)
+                .expect("generated topic name should be valid");
+            packet.set_will(Some(will));
+            packet.set_will_qos(QualityOfService::arbitrary(u)? as u8);
+            packet.set_will_retain(bool::arbitrary(u)?);
+        }
+        Ok(packet)
+    }
+}
+
+impl<'a> Arbitrary<'a> for ConnackPacket {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        Ok(ConnackPacket::new(
+            bool::arbitrary(u)?,
+            ConnectReturnCode::arbitrary(u)?,
+        ))
+    }
+}
+
+impl<'a> Arbitrary<'a> for PublishPacket {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        let mut packet = PublishPacket::new(
+            TopicName::arbitrary(u)?,
+            QoSWithPacketIdentifier::arbitrary(u)?,
+            arbitrary_bytes(u)?,
+        );
+        packet.set_dup(bool::arbitrary(u)?);
+        packet.set_retain(bool::arbitrary(u)?);
+        Ok(packet)
+    }
+}
+
+macro_rules! arbitrary_packet_id_packet {
+    ($($name:ident),+) => {
+        $(
+            impl<'a> Arbitrary<'a> for $name {
+                fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+                    Ok($name::new(u16::arbitrary(u)?))
+                }
+            }
+        )+
+    };
+}
+
+arbitrary_packet_id_packet!(
+    PubackPacket,
+    PubrecPacket,
+    PubrelPacket,
+    PubcompPacket,
+    UnsubackPacket
+);
+
+macro_rules! arbitrary_empty_packet {
+    ($($name:ident),+) => {
+        $(
+            impl<'a> Arbitrary<'a> for $name {
+                fn arbitrary(_u: &mut Unstructured<'a>) -> Result<Self> {
+                    Ok($name::new())
+                }
+            }
+        )+
+    };
+}
+
+arbitrary_empty_packet!(PingreqPacket, PingrespPacket, DisconnectPacket);
+
+impl<'a> Arbitrary<'a> for SubscribePacket {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        let len = arbitrary_len(u, 1)?;
+        let subscribes = (0..len)
+            .map(|_| Ok((TopicFilter::arbitrary(u)?, QualityOfService::arbitrary(u)?)))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(SubscribePacket::new(u16::arbitrary(u)?, subscribes))
+    }
+}
+
+impl<'a> Arbitrary<'a> for SubackPacket {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        let len = arbitrary_len(u, 1)?;
+        let return_codes = (0..len)
+            .map(|_| SubscribeReturnCode::arbitrary(u))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(SubackPacket::new(u16::arbitrary(u)?, return_codes))
+    }
+}
+
+impl<'a> Arbitrary<'a> for UnsubscribePacket {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        let len = arbitrary_len(u, 1)?;
+        let topic_filters = (0..len)
+            .map(|_| TopicFilter::arbitrary(u))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(UnsubscribePacket::new(u16::arbitrary(u)?, topic_filters))
+    }
+}
+
+impl<'a> Arbitrary<'a> for VariablePacket {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        Ok(match u.int_in_range(0..=13)? {
+            0 => ConnectPacket::arbitrary(u)?.into(),
+            1 => ConnackPacket::arbitrary(u)?.into(),
+            2 => PublishPacket::arbitrary(u)?.into(),
+            3 => PubackPacket::arbitrary(u)?.into(),
+            4 => PubrecPacket::arbitrary(u)?.into(),
+            5 => PubrelPacket::arbitrary(u)?.into(),
+            6 => PubcompPacket::arbitrary(u)?.into(),
+            7 => PingreqPacket::arbitrary(u)?.into(),
+            8 => PingrespPacket::arbitrary(u)?.into(),
+            9 => SubscribePacket::arbitrary(u)?.into(),
+            10 => SubackPacket::arbitrary(u)?.into(),
+            11 => UnsubscribePacket::arbitrary(u)?.into(),
+            12 => UnsubackPacket::arbitrary(u)?.into(),
+            _ => DisconnectPacket::arbitrary(u)?.into(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::common::io::Cursor;
+
+    use crate::common::{Decodable, Encodable};
+
+    /// Deterministic pseudo random input, so failures are reproducible
+    fn input(seed: u64, len: usize) -> Vec<u8> {
+        let mut state = seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_arbitrary_packet_round_trip() {
+        for seed in 0..2048 {
+            let data = input(seed, 512);
+            let mut u = Unstructured::new(&data);
+            let packet = VariablePacket::arbitrary(&mut u).unwrap();
+
+            let mut buf = Vec::new();
+            packet.encode(&mut buf).unwrap();
+
+            let decoded = VariablePacket::decode(&mut Cursor::new(&buf[..])).unwrap();
+            assert_eq!(packet, decoded, "seed {seed}");
+        }
+    }
+
+    #[test]
+    fn test_decode_mutated_packet() {
+        for seed in 0..2048 {
+            let data = input(seed, 512);
+            let mut u = Unstructured::new(&data);
+            let packet = VariablePacket::arbitrary(&mut u).unwrap();
+
+            let mut buf = Vec::new();
+            packet.encode(&mut buf).unwrap();
+
+            for pair in u.take_rest().chunks_exact(2).take(4) {
+                let idx = pair[0] as usize % buf.len();
+                buf[idx] ^= pair[1];
+            }
+            let _ = VariablePacket::decode(&mut Cursor::new(&buf[..]));
+        }
+    }
+}
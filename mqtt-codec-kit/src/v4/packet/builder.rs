@@ -0,0 +1,396 @@
+//! Validating packet builders
+//!
+//! Unlike the setters on the packets themselves, the builders check the constraints of the
+//! specification when calling `build()`, so a successfully built packet can always be sent.
+
+use alloc::{string::String, vec::Vec};
+
+use crate::{
+    common::{
+        encodable::{check_utf8_string, Utf8StringError},
+        packet::EncodablePacket,
+        qos::QoSWithPacketIdentifier,
+        QualityOfService, TopicName, TopicNameError, MAX_REMAINING_LENGTH,
+    },
+    v4::packet::{connect::LastWill, ConnectPacket, PublishPacket},
+};
+
+/// Errors returned by the packet builders
+#[derive(Debug, thiserror::Error)]
+pub enum PacketBuilderError {
+    #[error("topic name is required")]
+    MissingTopicName,
+    #[error(transparent)]
+    InvalidTopicName(#[from] TopicNameError),
+    #[error("QoS {0:?} requires a packet identifier")]
+    MissingPacketIdentifier(QualityOfService),
+    #[error("QoS 0 packets must not carry a packet identifier")]
+    UnexpectedPacketIdentifier,
+    #[error("packet identifier must be non-zero")]
+    ZeroPacketIdentifier,
+    #[error("DUP flag must not be set for QoS 0 packets")]
+    DupWithQoS0,
+    #[error("invalid {0}: {1}")]
+    InvalidString(&'static str, #[source] Utf8StringError),
+    #[error("{0} requires {1}")]
+    MissingDependency(&'static str, &'static str),
+    #[error("empty client identifier requires clean session")]
+    EmptyClientIdentifier,
+    #[error("packet too large, remaining length {0} exceeds {MAX_REMAINING_LENGTH}")]
+    PacketTooLarge(u64),
+}
+
+fn check_string(field: &'static str, s: &str) -> Result<(), PacketBuilderError> {
+    check_utf8_string(s).map_err(|err| PacketBuilderError::InvalidString(field, err))
+}
+
+/// Builder for [`PublishPacket`]
+///
+/// ```rust
+/// use mqtt_codec_kit::common::QualityOfService;
+/// use mqtt_codec_kit::v4::packet::PublishPacket;
+///
+/// let packet = PublishPacket::builder()
+///     .topic_name("sensors/temperature")
+///     .qos(QualityOfService::Level1)
+///     .packet_identifier(10)
+///     .payload("21.5")
+///     .build()
+///     .unwrap();
+/// assert_eq!(packet.payload(), b"21.5");
+/// ```
+#[derive(Debug, Clone)]
+pub struct PublishPacketBuilder {
+    topic_name: Option<String>,
+    qos: QualityOfService,
+    packet_identifier: Option<u16>,
+    dup: bool,
+    retain: bool,
+    payload: Vec<u8>,
+}
+
+impl Default for PublishPacketBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PublishPacketBuilder {
+    pub fn new() -> Self {
+        Self {
+            topic_name: None,
+            qos: QualityOfService::Level0,
+            packet_identifier: None,
+            dup: false,
+            retain: false,
+            payload: Vec::new(),
+        }
+    }
+
+    pub fn topic_name<S: Into<String>>(mut self, topic_name: S) -> Self {
+        self.topic_name = Some(topic_name.into());
+        self
+    }
+
+    pub fn qos(mut self, qos: QualityOfService) -> Self {
+        self.qos = qos;
+        self
+    }
+
+    pub fn packet_identifier(mut self, pkid: u16) -> Self {
+        self.packet_identifier = Some(pkid);
+        self
+    }
+
+    pub fn dup(mut self, dup: bool) -> Self {
+        self.dup = dup;
+        self
+    }
+
+    pub fn retain(mut self, retain: bool) -> Self {
+        self.retain = retain;
+        self
+    }
+
+    pub fn payload<P: Into<Vec<u8>>>(mut self, payload: P) -> Self {
+        self.payload = payload.into();
+        self
+    }
+
+    pub fn build(self) -> Result<PublishPacket, PacketBuilderError> {
+        let topic_name = TopicName::new(
+            self.topic_name
+                .ok_or(PacketBuilderError::MissingTopicName)?,
+        )?;
+
+        let qos = match (self.qos, self.packet_identifier) {
+            (QualityOfService::Level0, None) => QoSWithPacketIdentifier::Level0,
+            (QualityOfService::Level0, Some(_)) => {
+                return Err(PacketBuilderError::UnexpectedPacketIdentifier)
+            }
+            (qos, None) => return Err(PacketBuilderError::MissingPacketIdentifier(qos)),
+            (_, Some(0)) => return Err(PacketBuilderError::ZeroPacketIdentifier),
+            (qos, Some(pkid)) => QoSWithPacketIdentifier::new(qos, pkid),
+        };
+        if self.dup && qos == QoSWithPacketIdentifier::Level0 {
+            return Err(PacketBuilderError::DupWithQoS0);
+        }
+        if self.payload.len() as u64 > MAX_REMAINING_LENGTH as u64 {
+            return Err(PacketBuilderError::PacketTooLarge(self.payload.len() as u64));
+        }
+
+        let mut packet = PublishPacket::new(topic_name, qos, self.payload);
+        packet.set_dup(self.dup);
+        packet.set_retain(self.retain);
+
+        let length = packet.encoded_packet_length();
+        if length > MAX_REMAINING_LENGTH {
+            return Err(PacketBuilderError::PacketTooLarge(length.into()));
+        }
+
+        Ok(packet)
+    }
+}
+
+impl PublishPacket {
+    /// Creates a [`PublishPacketBuilder`]
+    pub fn builder() -> PublishPacketBuilder {
+        PublishPacketBuilder::new()
+    }
+}
+
+/// Builder for [`ConnectPacket`]
+///
+/// ```rust
+/// use mqtt_codec_kit::common::QualityOfService;
+/// use mqtt_codec_kit::v4::packet::ConnectPacket;
+///
+/// let packet = ConnectPacket::builder("client-1")
+///     .keep_alive(30)
+///     .username("user")
+///     .password("secret")
+///     .will("clients/client-1/status", "offline")
+///     .will_qos(QualityOfService::Level1)
+///     .build()
+///     .unwrap();
+/// assert_eq!(packet.will_qos(), QualityOfService::Level1);
+/// ```
+#[derive(Debug, Clone)]
+pub struct ConnectPacketBuilder {
+    client_identifier: String,
+    keep_alive: u16,
+    clean_session: bool,
+    username: Option<String>,
+    password: Option<String>,
+    will: Option<(String, Vec<u8>)>,
+    will_qos: QualityOfService,
+    will_retain: bool,
+}
+
+impl ConnectPacketBuilder {
+    pub fn new<C: Into<String>>(client_identifier: C) -> Self {
+        Self {
+            client_identifier: client_identifier.into(),
+            keep_alive: 0,
+            clean_session: false,
+            username: None,
+            password: None,
+            will: None,
+            will_qos: QualityOfService::Level0,
+            will_retain: false,
+        }
+    }
+
+    pub fn keep_alive(mut self, keep_alive: u16) -> Self {
+        self.keep_alive = keep_alive;
+        self
+    }
+
+    pub fn clean_session(mut self, clean_session: bool) -> Self {
+        self.clean_session = clean_session;
+        self
+    }
+
+    pub fn username<S: Into<String>>(mut self, username: S) -> Self {
+        self.username = Some(username.into());
+        self
+    }
+
+    pub fn password<S: Into<String>>(mut self, password: S) -> Self {
+        self.password = Some(password.into());
+        self
+    }
+
+    pub fn will<S: Into<String>, P: Into<Vec<u8>>>(mut self, topic: S, payload: P) -> Self {
+        self.will = Some((topic.into(), payload.into()));
+        self
+    }
+
+    pub fn will_qos(mut self, qos: QualityOfService) -> Self {
+        self.will_qos = qos;
+        self
+    }
+
+    pub fn will_retain(mut self, retain: bool) -> Self {
+        self.will_retain = retain;
+        self
+    }
+
+    pub fn build(self) -> Result<ConnectPacket, PacketBuilderError> {
+        check_string("client identifier", &self.client_identifier)?;
+        if self.client_identifier.is_empty() && !self.clean_session {
+            return Err(PacketBuilderError::EmptyClientIdentifier);
+        }
+        if let Some(username) = &self.username {
+            check_string("username", username)?;
+        } else if self.password.is_some() {
+            return Err(PacketBuilderError::MissingDependency(
+                "password", "username",
+            ));
+        }
+        if let Some(password) = &self.password {
+            check_string("password", password)?;
+        }
+
+        let will = match self.will {
+            Some((topic, payload)) => {
+                if payload.len() > u16::MAX as usize {
+                    return Err(PacketBuilderError::PacketTooLarge(payload.len() as u64));
+                }
+                Some(
+                    LastWill::new(TopicName::new(topic)?, payload)
+                        .expect("topic name is already validated"),
+                )
+            }
+            None if self.will_qos != QualityOfService::Level0 => {
+                return Err(PacketBuilderError::MissingDependency("will QoS", "will"))
+            }
+            None if self.will_retain => {
+                return Err(PacketBuilderError::MissingDependency("will retain", "will"))
+            }
+            None => None,
+        };
+
+        let mut packet = ConnectPacket::new(self.client_identifier);
+        packet.set_keep_alive(self.keep_alive);
+        packet.set_clean_session(self.clean_session);
+        packet.set_username(self.username);
+        packet.set_password(self.password);
+        packet.set_will(will);
+        packet.set_will_qos(self.will_qos as u8);
+        packet.set_will_retain(self.will_retain);
+
+        Ok(packet)
+    }
+}
+
+impl ConnectPacket {
+    /// Creates a [`ConnectPacketBuilder`]
+    pub fn builder<C: Into<String>>(client_identifier: C) -> ConnectPacketBuilder {
+        ConnectPacketBuilder::new(client_identifier)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::common::{io::Cursor, Decodable, Encodable};
+
+    #[test]
+    fn test_publish_builder() {
+        let packet = PublishPacket::builder()
+            .topic_name("a/b")
+            .qos(QualityOfService::Level1)
+            .packet_identifier(10)
+            .dup(true)
+            .retain(true)
+            .payload("hello")
+            .build()
+            .unwrap();
+
+        assert_eq!(packet.qos(), QoSWithPacketIdentifier::Level1(10));
+        assert!(packet.dup());
+        assert!(packet.retain());
+
+        let mut buf = Vec::new();
+        packet.encode(&mut buf).unwrap();
+        let decoded = PublishPacket::decode(&mut Cursor::new(&buf[..])).unwrap();
+        assert_eq!(packet, decoded);
+    }
+
+    #[test]
+    fn test_publish_builder_rejects_invalid() {
+        let builder = PublishPacket::builder().topic_name("a/b");
+
+        assert!(matches!(
+            PublishPacket::builder().build(),
+            Err(PacketBuilderError::MissingTopicName)
+        ));
+        assert!(matches!(
+            PublishPacket::builder().topic_name("").build(),
+            Err(PacketBuilderError::InvalidTopicName(_))
+        ));
+        assert!(matches!(
+            builder.clone().qos(QualityOfService::Level2).build(),
+            Err(PacketBuilderError::MissingPacketIdentifier(
+                QualityOfService::Level2
+            ))
+        ));
+        assert!(matches!(
+            builder.clone().packet_identifier(1).build(),
+            Err(PacketBuilderError::UnexpectedPacketIdentifier)
+        ));
+        assert!(matches!(
+            builder.dup(true).build(),
+            Err(PacketBuilderError::DupWithQoS0)
+        ));
+    }
+
+    #[test]
+    fn test_connect_builder() {
+        let packet = ConnectPacket::builder("client")
+            .keep_alive(30)
+            .clean_session(true)
+            .username("user")
+            .password("pass")
+            .will("a/b", "bye")
+            .will_qos(QualityOfService::Level2)
+            .will_retain(true)
+            .build()
+            .unwrap();
+
+        assert_eq!(packet.will_qos(), QualityOfService::Level2);
+        assert!(packet.will_retain());
+
+        let mut buf = Vec::new();
+        packet.encode(&mut buf).unwrap();
+        let decoded = ConnectPacket::decode(&mut Cursor::new(&buf[..])).unwrap();
+        assert_eq!(packet, decoded);
+    }
+
+    #[test]
+    fn test_connect_builder_rejects_invalid() {
+        assert!(matches!(
+            ConnectPacket::builder("").build(),
+            Err(PacketBuilderError::EmptyClientIdentifier)
+        ));
+        assert!(matches!(
+            ConnectPacket::builder("client").password("pass").build(),
+            Err(PacketBuilderError::MissingDependency(
+                "password", "username"
+            ))
+        ));
+        assert!(matches!(
+            ConnectPacket::builder("client").will_retain(true).build(),
+            Err(PacketBuilderError::MissingDependency("will retain", "will"))
+        ));
+        assert!(matches!(
+            ConnectPacket::builder("client").username("a\0").build(),
+            Err(PacketBuilderError::InvalidString(
+                "username",
+                Utf8StringError::NullCharacter
+            ))
+        ));
+    }
+}
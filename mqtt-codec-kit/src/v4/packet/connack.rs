@@ -1,7 +1,8 @@
 //! CONNACK
 
-use std::{fmt::Display, io::Read};
+use core::fmt::Display;
 
+use crate::common::io::Read;
 use crate::{
     common::{packet::DecodablePacket, ConnackFlags, ConnectAckFlagsError, Decodable},
     v4::{
@@ -15,6 +16,11 @@ use crate::{
 
 /// `CONNACK` packet
 #[derive(Debug, Eq, PartialEq, Clone)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(remote = "Self")
+)]
 pub struct ConnackPacket {
     fixed_header: FixedHeader,
     flags: ConnackFlags,
@@ -23,6 +29,8 @@ pub struct ConnackPacket {
 
 encodable_packet!(ConnackPacket(flags, return_code));
 
+serde_packet!(ConnackPacket => ConnectAcknowledgement);
+
 impl ConnackPacket {
     pub fn new(session_present: bool, return_code: ConnectReturnCode) -> Self {
         Self {
@@ -45,7 +53,7 @@ impl ConnackPacket {
 }
 
 impl DecodablePacket for ConnackPacket {
-    type DecodePacketError = std::convert::Infallible;
+    type DecodePacketError = core::convert::Infallible;
     type F = FixedHeader;
     type Error = PacketError<Self>;
 
@@ -65,7 +73,7 @@ impl DecodablePacket for ConnackPacket {
 }
 
 impl Display for ConnackPacket {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         write!(
             f,
             "{{fixed_header: {}, flags: {}, return_code: {}}}",
@@ -76,7 +84,7 @@ impl Display for ConnackPacket {
 
 #[cfg(test)]
 mod test {
-    use std::io::Cursor;
+    use crate::common::io::Cursor;
 
     use crate::{
         common::encodable::{Decodable, Encodable},
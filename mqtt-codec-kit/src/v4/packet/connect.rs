@@ -1,10 +1,9 @@
 //! CONNECT
 
-use std::{
-    fmt::Display,
-    io::{self, Read, Write},
-};
+use alloc::{string::String, vec::Vec};
+use core::fmt::Display;
 
+use crate::common::io::{self, Read, Write};
 use crate::{
     common::{
         encodable::VarBytes,
@@ -22,6 +21,11 @@ use crate::{
 
 /// `CONNECT` packet
 #[derive(Debug, Eq, PartialEq, Clone)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(remote = "Self")
+)]
 pub struct ConnectPacket {
     fixed_header: FixedHeader,
     protocol_name: ProtocolName,
@@ -39,6 +43,8 @@ encodable_packet!(ConnectPacket(
     payload
 ));
 
+serde_packet!(ConnectPacket => Connect);
+
 impl ConnectPacket {
     pub fn new<C>(client_identifier: C) -> Self
     where
@@ -216,7 +222,7 @@ impl DecodablePacket for ConnectPacket {
 }
 
 impl Display for ConnectPacket {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         write!(
             f,
             "{{fixed_header: {}, protocol_name: {}, protocol_level: {}, flags: {}, keepalive: {}, payload: {}}}",
@@ -227,6 +233,7 @@ impl Display for ConnectPacket {
 
 /// Payloads for connect packet
 #[derive(Debug, Eq, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 struct ConnectPacketPayload {
     client_identifier: String,
     last_will: Option<LastWill>,
@@ -351,7 +358,7 @@ impl Decodable for ConnectPacketPayload {
 }
 
 impl Display for ConnectPacketPayload {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         write!(f, "{{client_identifier: {}", self.client_identifier)?;
         match &self.last_will {
             Some(last_will) => write!(f, ", last_will: {}", last_will),
@@ -380,6 +387,7 @@ pub enum ConnectPacketError {
 
 // LastWill
 #[derive(Debug, Eq, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct LastWill {
     topic: TopicName,
     message: VarBytes,
@@ -423,12 +431,13 @@ impl Encodable for LastWill {
     }
 
     fn encoded_length(&self) -> u32 {
-        2 + self.topic.encoded_length() + 2 + self.message.encoded_length()
+        // the topic and message carry their two length bytes already
+        self.topic.encoded_length() + self.message.encoded_length()
     }
 }
 
 impl Display for LastWill {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         write!(
             f,
             "{{topic: {}, message: {}, qos: {}, retain: {}}}",
@@ -441,7 +450,7 @@ impl Display for LastWill {
 mod test {
     use super::*;
 
-    use std::io::Cursor;
+    use crate::common::io::Cursor;
 
     use crate::common::{Decodable, Encodable};
 
@@ -493,7 +502,7 @@ mod test {
 
         assert_eq!(
             packet.to_string(),
-            "{fixed_header: {packet_type: CONNECT, remaining_length: 45}, protocol_name: MQTT, protocol_level: 4, flags: {username: true, password: false, will_retain: false, will_qos: 1, will_flag: true, clean_session: false, reserved: false}, keepalive: 0, payload: {client_identifier: test, last_will: {topic: test/topic, message: hello, qos: 1, retain: false}, username: test, password: None}}"
+            "{fixed_header: {packet_type: CONNECT, remaining_length: 41}, protocol_name: MQTT, protocol_level: 4, flags: {username: true, password: false, will_retain: false, will_qos: 1, will_flag: true, clean_session: false, reserved: false}, keepalive: 0, payload: {client_identifier: test, last_will: {topic: test/topic, message: hello, qos: 1, retain: false}, username: test, password: None}}"
         );
     }
 
@@ -509,7 +518,7 @@ mod test {
 
         assert_eq!(
             packet.to_string(),
-            "{fixed_header: {packet_type: CONNECT, remaining_length: 43}, protocol_name: MQTT, protocol_level: 4, flags: {username: true, password: false, will_retain: false, will_qos: 1, will_flag: true, clean_session: false, reserved: false}, keepalive: 0, payload: {client_identifier: test, last_will: {topic: test/topic, message: [1, 2, 3], qos: 1, retain: false}, username: test, password: None}}"
+            "{fixed_header: {packet_type: CONNECT, remaining_length: 39}, protocol_name: MQTT, protocol_level: 4, flags: {username: true, password: false, will_retain: false, will_qos: 1, will_flag: true, clean_session: false, reserved: false}, keepalive: 0, payload: {client_identifier: test, last_will: {topic: test/topic, message: [1, 2, 3], qos: 1, retain: false}, username: test, password: None}}"
         );
     }
 }
@@ -1,7 +1,8 @@
 //! DISCONNECT
 
-use std::{fmt::Display, io::Read};
+use core::fmt::Display;
 
+use crate::common::io::Read;
 use crate::{
     common::packet::DecodablePacket,
     v4::{
@@ -12,12 +13,19 @@ use crate::{
 
 /// `DISCONNECT` packet
 #[derive(Debug, Eq, PartialEq, Clone)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(remote = "Self")
+)]
 pub struct DisconnectPacket {
     fixed_header: FixedHeader,
 }
 
 encodable_packet!(DisconnectPacket());
 
+serde_packet!(DisconnectPacket => Disconnect);
+
 impl DisconnectPacket {
     pub fn new() -> Self {
         Self {
@@ -33,7 +41,7 @@ impl Default for DisconnectPacket {
 }
 
 impl DecodablePacket for DisconnectPacket {
-    type DecodePacketError = std::convert::Infallible;
+    type DecodePacketError = core::convert::Infallible;
     type F = FixedHeader;
     type Error = PacketError<Self>;
 
@@ -43,14 +51,14 @@ impl DecodablePacket for DisconnectPacket {
 }
 
 impl Display for DisconnectPacket {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         write!(f, "{{fixed_header: {}}}", self.fixed_header)
     }
 }
 
 #[cfg(test)]
 mod test {
-    use std::io::Cursor;
+    use crate::common::io::Cursor;
 
     use crate::common::{Decodable, Encodable};
 
@@ -1,18 +1,17 @@
 //! Specific packets
 
-use std::{
-    fmt::{self, Debug},
-    io::{self, Read, Write},
-};
+use alloc::vec::Vec;
+use core::fmt::{self, Debug};
 
 #[cfg(all(feature = "v4", feature = "parse"))]
 use tokio::io::{AsyncRead, AsyncReadExt};
 
+use crate::common::io::{self, Read, Write};
 use crate::{
     common::{
         packet::{DecodablePacket, EncodablePacket},
         topic_name::{TopicNameDecodeError, TopicNameError},
-        Decodable, Encodable,
+        Decodable,
     },
     v4::control::{
         variable_header::VariableHeaderError, ControlType, FixedHeader, FixedHeaderError,
@@ -28,7 +27,7 @@ macro_rules! encodable_packet {
             }
 
             #[allow(unused_variables)]
-            fn encode_packet<W: ::std::io::Write>(&self, writer: &mut W) -> ::std::io::Result<()> {
+            fn encode_packet<W: $crate::common::io::Write>(&self, writer: &mut W) -> $crate::common::io::Result<()> {
                 $($crate::common::Encodable::encode(&self.$field, writer)?;)*
                 Ok(())
             }
@@ -49,7 +48,38 @@ macro_rules! encodable_packet {
     };
 }
 
+/// Implements serde for a packet whose struct derives it with `serde(remote = "Self")`,
+/// rejecting a mismatched control type and recomputing `remaining_length` on deserialize
+macro_rules! serde_packet {
+    ($typ:ident => $ctrl:ident $(, $check:path)?) => {
+        #[cfg(feature = "serde")]
+        impl serde::Serialize for $typ {
+            fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                $typ::serialize(self, serializer)
+            }
+        }
+
+        #[cfg(feature = "serde")]
+        impl<'de> serde::Deserialize<'de> for $typ {
+            fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                let mut packet = $typ::deserialize(deserializer)?;
+                let packet_type = packet.fixed_header.packet_type;
+                if packet_type.control_type() != $crate::v4::control::ControlType::$ctrl {
+                    return Err(serde::de::Error::custom(format_args!(
+                        "unexpected packet type {}",
+                        packet_type
+                    )));
+                }
+                $($check(&packet).map_err(serde::de::Error::custom)?;)?
+                packet.fix_header_remaining_len();
+                Ok(packet)
+            }
+        }
+    };
+}
+
 pub use self::{
+    builder::{ConnectPacketBuilder, PacketBuilderError, PublishPacketBuilder},
     connack::ConnackPacket,
     connect::ConnectPacket,
     disconnect::DisconnectPacket,
@@ -66,6 +96,9 @@ pub use self::{
     unsubscribe::UnsubscribePacket,
 };
 
+#[cfg(feature = "arbitrary")]
+mod arbitrary;
+pub mod builder;
 pub mod connack;
 pub mod connect;
 pub mod disconnect;
@@ -87,7 +120,7 @@ macro_rules! impl_decodable {
             type Error = PacketError<Self>;
             type Cond = Option<FixedHeader>;
 
-            fn decode_with<R: std::io::Read>(
+            fn decode_with<R: $crate::common::io::Read>(
                 reader: &mut R,
                 fixed_header: Self::Cond,
             ) -> Result<Self, Self::Error> {
@@ -171,6 +204,7 @@ macro_rules! impl_variable_packet {
     ($($name:ident & $errname:ident => $hdr:ident,)+) => {
         /// Variable packet
         #[derive(Debug, Eq, PartialEq, Clone)]
+        #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
         pub enum VariablePacket {
             $(
                 $name($name),
@@ -229,6 +263,16 @@ macro_rules! impl_variable_packet {
         //     }
         // }
 
+        impl fmt::Display for VariablePacket {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                match *self {
+                    $(
+                        VariablePacket::$name(ref pk) => fmt::Display::fmt(pk, f),
+                    )+
+                }
+            }
+        }
+
         impl EncodablePacket for VariablePacket {
             type Output = FixedHeader;
 
@@ -291,6 +335,8 @@ macro_rules! impl_variable_packet {
             FixedHeaderError(#[from] FixedHeaderError),
             #[error("reserved packet type ({0}), [u8, ..{n}]", n = .1.len())]
             ReservedPacket(u8, Vec<u8>),
+            #[error("packet too large, remaining length {0} exceeds limit {1}")]
+            PacketTooLarge(u32, u32),
             #[error(transparent)]
             IoError(#[from] io::Error),
             $(
@@ -339,12 +385,16 @@ mod codec {
 
     use super::*;
     use crate::{
-        common::packet::EncodablePacket,
+        common::{packet::EncodablePacket, Encodable},
         v4::control::packet_type::{PacketType, PacketTypeError},
     };
 
+    /// Decodes every packet already buffered, there is no cap on the packets per read: an
+    /// `Ok(None)` with whole packets left would make `FramedRead` wait for bytes the client
+    /// may never send, so the caller bounds how many packets it handles before yielding
     pub struct MqttDecoder {
         state: DecodeState,
+        max_remaining_length: Option<u32>,
     }
 
     enum DecodeState {
@@ -362,8 +412,20 @@ mod codec {
         pub const fn new() -> Self {
             MqttDecoder {
                 state: DecodeState::Start,
+                max_remaining_length: None,
             }
         }
+
+        /// Reject packets whose remaining length exceeds `max` with
+        /// [`VariablePacketError::PacketTooLarge`], before any of the payload is buffered.
+        pub const fn with_max_remaining_length(mut self, max: u32) -> Self {
+            self.max_remaining_length = Some(max);
+            self
+        }
+
+        pub const fn max_remaining_length(&self) -> Option<u32> {
+            self.max_remaining_length
+        }
     }
 
     impl Default for MqttDecoder {
@@ -424,6 +486,11 @@ mod codec {
                 match &mut self.state {
                     DecodeState::Start => match decode_header(&src[..]) {
                         Some(Ok((typ, length, header_size))) => {
+                            if let Some(max) = self.max_remaining_length {
+                                if length > max {
+                                    return Err(VariablePacketError::PacketTooLarge(length, max));
+                                }
+                            }
                             src.advance(header_size);
                             self.state = DecodeState::Packet { length, typ };
                             continue;
@@ -446,7 +513,10 @@ mod codec {
                                     packet_type: typ,
                                     remaining_length: length,
                                 };
-                                return decode_with_header(&mut src.reader(), header).map(Some);
+                                // the packet is taken off the buffer before it is decoded, a
+                                // malformed one leaves the next packet at the start of it
+                                let packet = src.split_to(length as usize);
+                                return decode_with_header(&mut packet.reader(), header).map(Some);
                             }
                             DecodePacketType::Reserved(code) => {
                                 let data = src[..length as usize].to_vec();
@@ -494,6 +564,12 @@ mod codec {
                 encode: MqttEncoder::new(),
             }
         }
+
+        /// See [`MqttDecoder::with_max_remaining_length`]
+        pub const fn with_max_remaining_length(mut self, max: u32) -> Self {
+            self.decode = self.decode.with_max_remaining_length(max);
+            self
+        }
     }
 
     impl Default for MqttCodec {
@@ -527,7 +603,7 @@ pub use codec::{MqttCodec, MqttDecoder, MqttEncoder};
 mod test {
     use super::*;
 
-    use std::io::Cursor;
+    use crate::common::io::Cursor;
 
     use crate::common::{Decodable, Encodable};
 
@@ -549,6 +625,16 @@ mod test {
         assert_eq!(var_packet, decoded_packet);
     }
 
+    #[test]
+    fn test_display_variable_packet() {
+        let packet = VariablePacket::new(PingreqPacket::new());
+
+        assert_eq!(
+            packet.to_string(),
+            "{fixed_header: {packet_type: PINGREQ, remaining_length: 0}}"
+        );
+    }
+
     #[cfg(all(feature = "v4", feature = "parse"))]
     #[tokio::test]
     async fn test_variable_packet_async_parse() {
@@ -606,4 +692,124 @@ mod test {
         assert_eq!(decoded_conn, conn_packet.into());
         assert_eq!(decoded_sub, sub_packet.into());
     }
+
+    #[cfg(feature = "tokio-codec")]
+    #[test]
+    fn test_decoder_max_remaining_length() {
+        use bytes::BytesMut;
+        use tokio_util::codec::Decoder;
+
+        let packet = VariablePacket::new(ConnectPacket::new("1234".to_owned()));
+        let mut buf = Vec::new();
+        packet.encode(&mut buf).unwrap();
+        let remaining_length = packet.fixed_header().remaining_length;
+
+        let mut decoder = MqttDecoder::new().with_max_remaining_length(remaining_length - 1);
+        let mut src = BytesMut::from(&buf[..]);
+        assert!(matches!(
+            decoder.decode(&mut src),
+            Err(VariablePacketError::PacketTooLarge(len, max)) if len == remaining_length && max == remaining_length - 1
+        ));
+
+        let mut decoder = MqttDecoder::new().with_max_remaining_length(remaining_length);
+        let mut src = BytesMut::from(&buf[..]);
+        assert_eq!(decoder.decode(&mut src).unwrap(), Some(packet));
+    }
+
+    #[cfg(feature = "tokio-codec")]
+    #[test]
+    fn test_decoder_skips_malformed_packet() {
+        use bytes::BytesMut;
+        use tokio_util::codec::Decoder;
+
+        // a SUBSCRIBE with QoS 3 followed by a PINGREQ
+        let mut buf = vec![0x82, 6, 0, 1, 0, 1, b'a', 3];
+        PingreqPacket::new().encode(&mut buf).unwrap();
+
+        let mut decoder = MqttDecoder::new();
+        let mut src = BytesMut::from(&buf[..]);
+        assert!(matches!(
+            decoder.decode(&mut src),
+            Err(VariablePacketError::SubscribePacketError(_))
+        ));
+        assert_eq!(
+            decoder.decode(&mut src).unwrap(),
+            Some(PingreqPacket::new().into())
+        );
+        assert!(src.is_empty());
+    }
+
+    #[cfg(feature = "tokio-codec")]
+    #[test]
+    fn test_decoder_drains_buffered_packets() {
+        use bytes::BytesMut;
+        use tokio_util::codec::Decoder;
+
+        // the caller bounds how many packets it handles per read, the decoder returns every
+        // packet already buffered
+        let packet = VariablePacket::new(PingreqPacket::new());
+        let mut buf = Vec::new();
+        for _ in 0..100 {
+            packet.encode(&mut buf).unwrap();
+        }
+
+        let mut decoder = MqttDecoder::new();
+        let mut src = BytesMut::from(&buf[..]);
+        for _ in 0..100 {
+            assert_eq!(decoder.decode(&mut src).unwrap(), Some(packet.clone()));
+        }
+        assert_eq!(decoder.decode(&mut src).unwrap(), None);
+        assert!(src.is_empty());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_variable_packet_serde() {
+        use crate::common::{qos::QoSWithPacketIdentifier, TopicName};
+
+        let packet = VariablePacket::new(PublishPacket::new(
+            TopicName::new("a/b").unwrap(),
+            QoSWithPacketIdentifier::Level1(10),
+            b"hello".to_vec(),
+        ));
+
+        let json = serde_json::to_string(&packet).unwrap();
+        let decoded: VariablePacket = serde_json::from_str(&json).unwrap();
+        assert_eq!(packet, decoded);
+
+        let invalid = json.replace("a/b", "a/#");
+        assert!(serde_json::from_str::<VariablePacket>(&invalid).is_err());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_packet_serde_recomputes_fixed_header() {
+        use crate::common::{qos::QoSWithPacketIdentifier, TopicName};
+
+        let packet = PublishPacket::new(
+            TopicName::new("a/b").unwrap(),
+            QoSWithPacketIdentifier::Level1(10),
+            b"hello".to_vec(),
+        );
+        let mut buf = Vec::new();
+        packet.encode(&mut buf).unwrap();
+
+        let mut value = serde_json::to_value(&packet).unwrap();
+        value["fixed_header"]["remaining_length"] = 9999.into();
+        let decoded: PublishPacket = serde_json::from_value(value.clone()).unwrap();
+        assert_eq!(packet, decoded);
+
+        let mut decoded_buf = Vec::new();
+        decoded.encode(&mut decoded_buf).unwrap();
+        assert_eq!(buf, decoded_buf);
+
+        // QoS 0 flags with a packet identifier
+        let mut mismatched_qos = value.clone();
+        mismatched_qos["fixed_header"]["packet_type"] = 0x30.into();
+        assert!(serde_json::from_value::<PublishPacket>(mismatched_qos).is_err());
+
+        // PUBACK type byte in a PUBLISH body
+        value["fixed_header"]["packet_type"] = 0x40.into();
+        assert!(serde_json::from_value::<PublishPacket>(value).is_err());
+    }
 }
@@ -1,7 +1,8 @@
 //! PUBACK
 
-use std::{fmt::Display, io::Read};
+use core::fmt::Display;
 
+use crate::common::io::Read;
 use crate::{
     common::{packet::DecodablePacket, Decodable, PacketIdentifier},
     v4::{
@@ -12,6 +13,11 @@ use crate::{
 
 /// `PUBACK` packet
 #[derive(Debug, Eq, PartialEq, Clone)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(remote = "Self")
+)]
 pub struct PubackPacket {
     fixed_header: FixedHeader,
     packet_identifier: PacketIdentifier,
@@ -19,6 +25,8 @@ pub struct PubackPacket {
 
 encodable_packet!(PubackPacket(packet_identifier));
 
+serde_packet!(PubackPacket => PublishAcknowledgement);
+
 impl PubackPacket {
     pub fn new(pkid: u16) -> Self {
         Self {
@@ -40,7 +48,7 @@ impl PubackPacket {
 }
 
 impl DecodablePacket for PubackPacket {
-    type DecodePacketError = std::convert::Infallible;
+    type DecodePacketError = core::convert::Infallible;
     type F = FixedHeader;
     type Error = PacketError<Self>;
 
@@ -54,7 +62,7 @@ impl DecodablePacket for PubackPacket {
 }
 
 impl Display for PubackPacket {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         write!(
             f,
             "{{fixed_header: {}, packet_identifier: {}}}",
@@ -65,7 +73,7 @@ impl Display for PubackPacket {
 
 #[cfg(test)]
 mod test {
-    use std::io::Cursor;
+    use crate::common::io::Cursor;
 
     use crate::common::encodable::Encodable;
 
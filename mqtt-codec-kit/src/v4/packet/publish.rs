@@ -1,10 +1,9 @@
 //! PUBLISH
 
-use std::{
-    fmt::Display,
-    io::{self, Read, Write},
-};
+use alloc::vec::Vec;
+use core::fmt::Display;
 
+use crate::common::io::{self, Read, Write};
 use crate::{
     common::{
         packet::{DecodablePacket, EncodablePacket},
@@ -19,6 +18,11 @@ use crate::{
 
 /// `PUBLISH` packet
 #[derive(Debug, Eq, PartialEq, Clone)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(remote = "Self")
+)]
 pub struct PublishPacket {
     fixed_header: FixedHeader,
     topic_name: TopicName,
@@ -28,6 +32,8 @@ pub struct PublishPacket {
 
 encodable_packet!(PublishPacket(topic_name, packet_identifier, payload));
 
+serde_packet!(PublishPacket => Publish, PublishPacket::check_qos);
+
 impl PublishPacket {
     pub fn new<P: Into<Vec<u8>>>(
         topic_name: TopicName,
@@ -52,7 +58,7 @@ impl PublishPacket {
     }
 
     pub fn dup(&self) -> bool {
-        self.fixed_header.packet_type.flags() & 0x80 != 0
+        self.fixed_header.packet_type.flags() & 0x08 != 0
     }
 
     pub fn set_qos(&mut self, qos: QoSWithPacketIdentifier) {
@@ -78,6 +84,17 @@ impl PublishPacket {
         }
     }
 
+    /// The QoS flags and the packet identifier must agree, as `qos()` relies on it
+    #[cfg(feature = "serde")]
+    fn check_qos(&self) -> Result<(), &'static str> {
+        let qos_val = (self.fixed_header.packet_type.flags() & 0b0110) >> 1;
+        if (qos_val == 0) == self.packet_identifier.is_none() {
+            Ok(())
+        } else {
+            Err("QoS flags do not match the packet identifier")
+        }
+    }
+
     pub fn set_retain(&mut self, ret: bool) {
         self.fixed_header
             .packet_type
@@ -108,7 +125,7 @@ impl PublishPacket {
 }
 
 impl DecodablePacket for PublishPacket {
-    type DecodePacketError = std::convert::Infallible;
+    type DecodePacketError = core::convert::Infallible;
     type F = FixedHeader;
     type Error = PacketError<Self>;
 
@@ -141,7 +158,7 @@ impl DecodablePacket for PublishPacket {
 }
 
 impl Display for PublishPacket {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         write!(
             f,
             "{{fixed_header: {}, topic_name: {}",
@@ -152,20 +169,12 @@ impl Display for PublishPacket {
             None => write!(f, ", packet_identifier: None")?,
         };
 
-        match std::str::from_utf8(&self.payload) {
+        match core::str::from_utf8(&self.payload) {
             Ok(s) if s.chars().all(|c| c.is_ascii_graphic() || c == ' ') => {
                 write!(f, ", payload: {}", s)?;
             }
             _ => {
-                write!(f, ", payload: [")?;
-                let mut iter = self.payload.iter();
-                if let Some(first) = iter.next() {
-                    write!(f, "{}", first)?;
-                    for byte in iter {
-                        write!(f, ", {}", byte)?;
-                    }
-                }
-                write!(f, "]")?;
+                write!(f, ", payload: [{} bytes]", self.payload.len())?;
             }
         };
         write!(f, "}}")
@@ -229,7 +238,7 @@ impl EncodablePacket for PublishPacketRef<'_> {
 mod test {
     use super::*;
 
-    use std::io::Cursor;
+    use crate::common::io::Cursor;
 
     use crate::common::topic_name::TopicName;
     use crate::common::{Decodable, Encodable};
@@ -309,7 +318,7 @@ mod test {
 
         assert_eq!(
             packet.to_string(),
-            "{fixed_header: {packet_type: PUBLISH, remaining_length: 11}, topic_name: a/b, packet_identifier: 10, payload: [1, 2, 3, 4]}"
+            "{fixed_header: {packet_type: PUBLISH, remaining_length: 11}, topic_name: a/b, packet_identifier: 10, payload: [4 bytes]}"
         );
     }
 }
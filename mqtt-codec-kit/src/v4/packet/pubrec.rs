@@ -1,7 +1,8 @@
 //! PUBREC
 
-use std::{fmt::Display, io::Read};
+use core::fmt::Display;
 
+use crate::common::io::Read;
 use crate::{
     common::{packet::DecodablePacket, Decodable, PacketIdentifier},
     v4::{
@@ -12,6 +13,11 @@ use crate::{
 
 /// `PUBREC` packet
 #[derive(Debug, Eq, PartialEq, Clone)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(remote = "Self")
+)]
 pub struct PubrecPacket {
     fixed_header: FixedHeader,
     packet_identifier: PacketIdentifier,
@@ -19,6 +25,8 @@ pub struct PubrecPacket {
 
 encodable_packet!(PubrecPacket(packet_identifier));
 
+serde_packet!(PubrecPacket => PublishReceived);
+
 impl PubrecPacket {
     pub fn new(pkid: u16) -> Self {
         Self {
@@ -40,7 +48,7 @@ impl PubrecPacket {
 }
 
 impl DecodablePacket for PubrecPacket {
-    type DecodePacketError = std::convert::Infallible;
+    type DecodePacketError = core::convert::Infallible;
     type F = FixedHeader;
     type Error = PacketError<Self>;
 
@@ -54,7 +62,7 @@ impl DecodablePacket for PubrecPacket {
 }
 
 impl Display for PubrecPacket {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         write!(
             f,
             "{{fixed_header: {}, packet_identifier: {}}}",
@@ -65,7 +73,7 @@ impl Display for PubrecPacket {
 
 #[cfg(test)]
 mod test {
-    use std::io::Cursor;
+    use crate::common::io::Cursor;
 
     use crate::common::encodable::Encodable;
 
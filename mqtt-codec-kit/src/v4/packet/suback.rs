@@ -1,13 +1,9 @@
 //! SUBACK
 
-use std::{
-    cmp::Ordering,
-    fmt::Display,
-    io::{self, Read, Write},
-};
-
-use byteorder::{ReadBytesExt, WriteBytesExt};
+use alloc::vec::Vec;
+use core::{cmp::Ordering, fmt::Display};
 
+use crate::common::io::{self, Read, ReadBytesExt, Write, WriteBytesExt};
 use crate::{
     common::{packet::DecodablePacket, Decodable, Encodable, PacketIdentifier, QualityOfService},
     v4::{
@@ -19,6 +15,8 @@ use crate::{
 /// Subscribe code
 #[repr(u8)]
 #[derive(Debug, Eq, PartialEq, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 pub enum SubscribeReturnCode {
     MaximumQoSLevel0 = 0x00,
     MaximumQoSLevel1 = 0x01,
@@ -54,13 +52,18 @@ impl From<QualityOfService> for SubscribeReturnCode {
 }
 
 impl Display for SubscribeReturnCode {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         write!(f, "{}", *self as u8)
     }
 }
 
 /// `SUBACK` packet
 #[derive(Debug, Eq, PartialEq, Clone)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(remote = "Self")
+)]
 pub struct SubackPacket {
     fixed_header: FixedHeader,
     packet_identifier: PacketIdentifier,
@@ -69,6 +72,8 @@ pub struct SubackPacket {
 
 encodable_packet!(SubackPacket(packet_identifier, payload));
 
+serde_packet!(SubackPacket => SubscribeAcknowledgement);
+
 impl SubackPacket {
     pub fn new(pkid: u16, return_codes: Vec<SubscribeReturnCode>) -> Self {
         let mut pkt = Self {
@@ -117,7 +122,7 @@ impl DecodablePacket for SubackPacket {
 }
 
 impl Display for SubackPacket {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         write!(
             f,
             "{{fixed_header: {}, packet_identifier: {}, payload: {}}}",
@@ -127,6 +132,7 @@ impl Display for SubackPacket {
 }
 
 #[derive(Debug, Eq, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 struct SubackPacketPayload {
     return_codes: Vec<SubscribeReturnCode>,
 }
@@ -177,7 +183,7 @@ impl Decodable for SubackPacketPayload {
 }
 
 impl Display for SubackPacketPayload {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         write!(f, "{{return_codes: [")?;
         let mut iter = self.return_codes.iter();
         if let Some(first) = iter.next() {
@@ -200,7 +206,7 @@ pub enum SubackPacketError {
 
 #[cfg(test)]
 mod test {
-    use std::io::Cursor;
+    use crate::common::io::Cursor;
 
     use crate::common::encodable::Encodable;
 
@@ -1,13 +1,12 @@
 //! SUBSCRIBE
 
-use std::{
-    fmt::Display,
-    io::{self, Read, Write},
-    string::FromUtf8Error,
+use alloc::{
+    string::{FromUtf8Error, String},
+    vec::Vec,
 };
+use core::fmt::Display;
 
-use byteorder::{ReadBytesExt, WriteBytesExt};
-
+use crate::common::io::{self, Read, ReadBytesExt, Write, WriteBytesExt};
 use crate::{
     common::{
         packet::DecodablePacket,
@@ -22,6 +21,11 @@ use crate::{
 
 /// `SUBSCRIBE` packet
 #[derive(Debug, Eq, PartialEq, Clone)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(remote = "Self")
+)]
 pub struct SubscribePacket {
     fixed_header: FixedHeader,
     packet_identifier: PacketIdentifier,
@@ -30,6 +34,8 @@ pub struct SubscribePacket {
 
 encodable_packet!(SubscribePacket(packet_identifier, payload));
 
+serde_packet!(SubscribePacket => Subscribe);
+
 impl SubscribePacket {
     pub fn new(pkid: u16, subscribes: Vec<(TopicFilter, QualityOfService)>) -> Self {
         let mut pkt = Self {
@@ -75,7 +81,7 @@ impl DecodablePacket for SubscribePacket {
 }
 
 impl Display for SubscribePacket {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         write!(
             f,
             "{{fixed_header: {}, packet_identifier: {}, payload: {}}}",
@@ -86,6 +92,7 @@ impl Display for SubscribePacket {
 
 /// Payload of subscribe packet
 #[derive(Debug, Eq, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 struct SubscribePacketPayload {
     subscribes: Vec<(TopicFilter, QualityOfService)>,
 }
@@ -121,7 +128,8 @@ impl Decodable for SubscribePacketPayload {
         let mut subs = Vec::new();
 
         while payload_len > 0 {
-            let filter = TopicFilter::decode(reader)?;
+            // an invalid filter only fails its own subscription, see `TopicFilterRef::is_valid`
+            let filter = unsafe { TopicFilter::new_unchecked(String::decode(reader)?) };
             let qos = match reader.read_u8()? {
                 0 => QualityOfService::Level0,
                 1 => QualityOfService::Level1,
@@ -138,7 +146,7 @@ impl Decodable for SubscribePacketPayload {
 }
 
 impl Display for SubscribePacketPayload {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         write!(f, "{{subscribes: [")?;
         let mut iter = self.subscribes.iter();
         if let Some(first) = iter.next() {
@@ -174,7 +182,7 @@ impl From<TopicFilterDecodeError> for SubscribePacketError {
 
 #[cfg(test)]
 mod test {
-    use std::io::Cursor;
+    use crate::common::io::Cursor;
 
     use crate::common::encodable::Encodable;
 
@@ -224,6 +232,21 @@ mod test {
         assert_eq!(expected, packet);
     }
 
+    #[test]
+    fn test_subscribe_packet_decode_invalid_filter() {
+        let encoded_data = b"\x82\x0e\x00\x01\x00\x05a/#/b\x00\x00\x01a\x01";
+
+        let mut buf = Cursor::new(&encoded_data[..]);
+        let packet = SubscribePacket::decode(&mut buf).unwrap();
+
+        let valid: Vec<_> = packet
+            .subscribes()
+            .iter()
+            .map(|(filter, _)| filter.is_valid())
+            .collect();
+        assert_eq!(valid, [false, true]);
+    }
+
     #[test]
     fn test_subscribe_packet_basic() {
         let subscribes = vec![
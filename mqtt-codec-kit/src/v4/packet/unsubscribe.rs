@@ -1,11 +1,9 @@
 //! UNSUBSCRIBE
 
-use std::{
-    fmt::Display,
-    io::{self, Read, Write},
-    string::FromUtf8Error,
-};
+use alloc::{string::FromUtf8Error, vec::Vec};
+use core::fmt::Display;
 
+use crate::common::io::{self, Read, Write};
 use crate::{
     common::{
         packet::DecodablePacket,
@@ -20,6 +18,11 @@ use crate::{
 
 /// `UNSUBSCRIBE` packet
 #[derive(Debug, Eq, PartialEq, Clone)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(remote = "Self")
+)]
 pub struct UnsubscribePacket {
     fixed_header: FixedHeader,
     packet_identifier: PacketIdentifier,
@@ -28,6 +31,8 @@ pub struct UnsubscribePacket {
 
 encodable_packet!(UnsubscribePacket(packet_identifier, payload));
 
+serde_packet!(UnsubscribePacket => Unsubscribe);
+
 impl UnsubscribePacket {
     pub fn new(pkid: u16, topics: Vec<TopicFilter>) -> Self {
         let mut pkt = Self {
@@ -73,7 +78,7 @@ impl DecodablePacket for UnsubscribePacket {
 }
 
 impl Display for UnsubscribePacket {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         write!(
             f,
             "{{fixed_header: {}, packet_identifier: {}, payload: {}}}",
@@ -83,6 +88,7 @@ impl Display for UnsubscribePacket {
 }
 
 #[derive(Debug, Eq, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 struct UnsubscribePacketPayload {
     topic_filters: Vec<TopicFilter>,
 }
@@ -127,7 +133,7 @@ impl Decodable for UnsubscribePacketPayload {
 }
 
 impl Display for UnsubscribePacketPayload {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         write!(f, "{{topic_filters: [")?;
         let mut iter = self.topic_filters.iter();
         if let Some(first) = iter.next() {
@@ -159,7 +165,7 @@ impl From<TopicFilterDecodeError> for UnsubscribePacketError {
 
 #[cfg(test)]
 mod test {
-    use std::io::Cursor;
+    use crate::common::io::Cursor;
 
     use crate::common::encodable::Encodable;
 
@@ -1,17 +1,20 @@
 //! Packet types
 
-use std::fmt::Display;
+use core::fmt::Display;
 
 use crate::common::QualityOfService;
 
 /// Packet type
 // INVARIANT: the high 4 bits of the byte must be a valid control type
 #[derive(Debug, Eq, PartialEq, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(try_from = "u8", into = "u8"))]
 pub struct PacketType(u8);
 
 /// Defined control types
 #[repr(u8)]
 #[derive(Debug, Eq, PartialEq, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum ControlType {
     /// Client request to connect to Server
     Connect = value::CONNECT,
@@ -121,7 +124,7 @@ impl PacketType {
     pub fn control_type(self) -> ControlType {
         get_control_type(self.0 >> 4).unwrap_or_else(|| {
             // SAFETY: this is maintained by the invariant for PacketType
-            unsafe { std::hint::unreachable_unchecked() }
+            unsafe { core::hint::unreachable_unchecked() }
         })
     }
 
@@ -180,7 +183,7 @@ fn get_control_type(val: u8) -> Option<ControlType> {
 }
 
 impl Display for PacketType {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         match self.control_type() {
             ControlType::Connect => write!(f, "CONNECT"),
             ControlType::ConnectAcknowledgement => write!(f, "CONNACK"),
@@ -1,21 +1,19 @@
 //! Auth Properties
 
-use std::{
-    fmt::Display,
-    io::{self, Write},
-};
-
-use byteorder::{ReadBytesExt, WriteBytesExt};
+use alloc::{string::String, vec::Vec};
+use core::fmt::Display;
 
+use crate::common::io::{self, ReadBytesExt, Write, WriteBytesExt};
 use crate::{
     common::{
         encodable::{VarBytes, VarInt},
         Decodable, Encodable,
     },
-    v5::property::{PropertyType, PropertyTypeError},
+    v5::property::{set_property, PropertyType, PropertyTypeError},
 };
 
 #[derive(Debug, Clone, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct AuthProperties {
     total_length: VarInt,
     reason_string: Option<String>,
@@ -119,10 +117,7 @@ impl Decodable for AuthProperties {
     type Error = PropertyTypeError;
     type Cond = ();
 
-    fn decode_with<R: std::io::Read>(
-        reader: &mut R,
-        _cond: Self::Cond,
-    ) -> Result<Self, Self::Error> {
+    fn decode_with<R: io::Read>(reader: &mut R, _cond: Self::Cond) -> Result<Self, Self::Error> {
         let mut reason_string = None;
         let mut user_properties = Vec::new();
         let mut authentication_method = None;
@@ -144,7 +139,7 @@ impl Decodable for AuthProperties {
                 PropertyType::ReasonString => {
                     let reason = String::decode(reader)?;
                     cursor += 2 + reason.len() as u32;
-                    reason_string = Some(reason);
+                    set_property(&mut reason_string, PropertyType::ReasonString, reason)?;
                 }
                 PropertyType::UserProperty => {
                     let key = String::decode(reader)?;
@@ -155,12 +150,20 @@ impl Decodable for AuthProperties {
                 PropertyType::AuthenticationMethod => {
                     let method = String::decode(reader)?;
                     cursor += 2 + method.len() as u32;
-                    authentication_method = Some(method);
+                    set_property(
+                        &mut authentication_method,
+                        PropertyType::AuthenticationMethod,
+                        method,
+                    )?;
                 }
                 PropertyType::AuthenticationData => {
                     let data = VarBytes::decode(reader)?;
                     cursor += 2 + data.0.len() as u32;
-                    authentication_data = Some(data);
+                    set_property(
+                        &mut authentication_data,
+                        PropertyType::AuthenticationData,
+                        data,
+                    )?;
                 }
                 _ => return Err(PropertyTypeError::InvalidPropertyType(prop)),
             }
@@ -177,7 +180,7 @@ impl Decodable for AuthProperties {
 }
 
 impl Display for AuthProperties {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         write!(f, "{{")?;
         match &self.reason_string {
             Some(reason_string) => write!(f, "reason_string: {}", reason_string)?,
@@ -1,21 +1,23 @@
 //! Connack Properties
 
-use std::{
-    fmt::Display,
-    io::{self, Write},
-};
+use alloc::{string::String, vec::Vec};
+use core::fmt::Display;
 
-use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use byteorder::BigEndian;
 
+use crate::common::io::{self, ReadBytesExt, Write, WriteBytesExt};
 use crate::{
     common::{
         encodable::{VarBytes, VarInt},
         Decodable, Encodable,
     },
-    v5::property::{PropertyType, PropertyTypeError},
+    v5::property::{
+        check_bool_property, check_non_zero_property, set_property, PropertyType, PropertyTypeError,
+    },
 };
 
 #[derive(Debug, Clone, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct ConnackProperties {
     total_length: VarInt,
     session_expiry_interval: Option<u32>,
@@ -238,6 +240,9 @@ impl ConnackProperties {
         if self.subscription_identifiers_available.is_some() {
             len += 1 + 1;
         }
+        if self.shared_subscription_available.is_some() {
+            len += 1 + 1;
+        }
         if self.server_keep_alive.is_some() {
             len += 1 + 2;
         }
@@ -343,10 +348,7 @@ impl Decodable for ConnackProperties {
     type Error = PropertyTypeError;
     type Cond = ();
 
-    fn decode_with<R: std::io::Read>(
-        reader: &mut R,
-        _cond: Self::Cond,
-    ) -> Result<Self, Self::Error> {
+    fn decode_with<R: io::Read>(reader: &mut R, _cond: Self::Cond) -> Result<Self, Self::Error> {
         let mut session_expiry_interval = None;
         let mut receive_max = None;
         let mut max_qos = None;
@@ -379,38 +381,62 @@ impl Decodable for ConnackProperties {
 
             match prop.try_into()? {
                 PropertyType::SessionExpiryInterval => {
-                    session_expiry_interval = Some(reader.read_u32::<BigEndian>()?);
+                    set_property(
+                        &mut session_expiry_interval,
+                        PropertyType::SessionExpiryInterval,
+                        reader.read_u32::<BigEndian>()?,
+                    )?;
                     cursor += 4;
                 }
                 PropertyType::ReceiveMaximum => {
-                    receive_max = Some(reader.read_u16::<BigEndian>()?);
+                    set_property(
+                        &mut receive_max,
+                        PropertyType::ReceiveMaximum,
+                        reader.read_u16::<BigEndian>()?,
+                    )?;
                     cursor += 2;
                 }
                 PropertyType::MaximumQos => {
-                    max_qos = Some(reader.read_u8()?);
+                    set_property(&mut max_qos, PropertyType::MaximumQos, reader.read_u8()?)?;
                     cursor += 1;
                 }
                 PropertyType::RetainAvailable => {
-                    retain_available = Some(reader.read_u8()?);
+                    set_property(
+                        &mut retain_available,
+                        PropertyType::RetainAvailable,
+                        reader.read_u8()?,
+                    )?;
                     cursor += 1;
                 }
                 PropertyType::AssignedClientIdentifier => {
                     let id = String::decode(reader)?;
                     cursor += 2 + id.len() as u32;
-                    assigned_client_identifier = Some(id);
+                    set_property(
+                        &mut assigned_client_identifier,
+                        PropertyType::AssignedClientIdentifier,
+                        id,
+                    )?;
                 }
                 PropertyType::MaximumPacketSize => {
-                    max_packet_size = Some(reader.read_u32::<BigEndian>()?);
+                    set_property(
+                        &mut max_packet_size,
+                        PropertyType::MaximumPacketSize,
+                        reader.read_u32::<BigEndian>()?,
+                    )?;
                     cursor += 4;
                 }
                 PropertyType::TopicAliasMaximum => {
-                    topic_alias_max = Some(reader.read_u16::<BigEndian>()?);
+                    set_property(
+                        &mut topic_alias_max,
+                        PropertyType::TopicAliasMaximum,
+                        reader.read_u16::<BigEndian>()?,
+                    )?;
                     cursor += 2;
                 }
                 PropertyType::ReasonString => {
                     let reason = String::decode(reader)?;
                     cursor += 2 + reason.len() as u32;
-                    reason_string = Some(reason);
+                    set_property(&mut reason_string, PropertyType::ReasonString, reason)?;
                 }
                 PropertyType::UserProperty => {
                     let key = String::decode(reader)?;
@@ -419,45 +445,94 @@ impl Decodable for ConnackProperties {
                     user_properties.push((key, value));
                 }
                 PropertyType::WildcardSubscriptionAvailable => {
-                    wildcard_subscription_available = Some(reader.read_u8()?);
+                    set_property(
+                        &mut wildcard_subscription_available,
+                        PropertyType::WildcardSubscriptionAvailable,
+                        reader.read_u8()?,
+                    )?;
                     cursor += 1;
                 }
                 PropertyType::SubscriptionIdentifierAvailable => {
-                    subscription_identifiers_available = Some(reader.read_u8()?);
+                    set_property(
+                        &mut subscription_identifiers_available,
+                        PropertyType::SubscriptionIdentifierAvailable,
+                        reader.read_u8()?,
+                    )?;
                     cursor += 1;
                 }
                 PropertyType::SharedSubscriptionAvailable => {
-                    shared_subscription_available = Some(reader.read_u8()?);
+                    set_property(
+                        &mut shared_subscription_available,
+                        PropertyType::SharedSubscriptionAvailable,
+                        reader.read_u8()?,
+                    )?;
                     cursor += 1;
                 }
                 PropertyType::ServerKeepAlive => {
-                    server_keep_alive = Some(reader.read_u16::<BigEndian>()?);
+                    set_property(
+                        &mut server_keep_alive,
+                        PropertyType::ServerKeepAlive,
+                        reader.read_u16::<BigEndian>()?,
+                    )?;
                     cursor += 2;
                 }
                 PropertyType::ResponseInformation => {
                     let info = String::decode(reader)?;
                     cursor += 2 + info.len() as u32;
-                    response_information = Some(info);
+                    set_property(
+                        &mut response_information,
+                        PropertyType::ResponseInformation,
+                        info,
+                    )?;
                 }
                 PropertyType::ServerReference => {
                     let reference = String::decode(reader)?;
                     cursor += 2 + reference.len() as u32;
-                    server_reference = Some(reference);
+                    set_property(
+                        &mut server_reference,
+                        PropertyType::ServerReference,
+                        reference,
+                    )?;
                 }
                 PropertyType::AuthenticationMethod => {
                     let method = String::decode(reader)?;
                     cursor += 2 + method.len() as u32;
-                    authentication_method = Some(method);
+                    set_property(
+                        &mut authentication_method,
+                        PropertyType::AuthenticationMethod,
+                        method,
+                    )?;
                 }
                 PropertyType::AuthenticationData => {
                     let data = VarBytes::decode(reader)?;
                     cursor += 2 + data.0.len() as u32;
-                    authentication_data = Some(data);
+                    set_property(
+                        &mut authentication_data,
+                        PropertyType::AuthenticationData,
+                        data,
+                    )?;
                 }
                 _ => return Err(PropertyTypeError::InvalidPropertyType(prop)),
             }
         }
 
+        check_non_zero_property(PropertyType::ReceiveMaximum, receive_max)?;
+        check_non_zero_property(PropertyType::MaximumPacketSize, max_packet_size)?;
+        check_bool_property(PropertyType::MaximumQos, max_qos)?;
+        check_bool_property(PropertyType::RetainAvailable, retain_available)?;
+        check_bool_property(
+            PropertyType::WildcardSubscriptionAvailable,
+            wildcard_subscription_available,
+        )?;
+        check_bool_property(
+            PropertyType::SubscriptionIdentifierAvailable,
+            subscription_identifiers_available,
+        )?;
+        check_bool_property(
+            PropertyType::SharedSubscriptionAvailable,
+            shared_subscription_available,
+        )?;
+
         Ok(Self {
             total_length,
             session_expiry_interval,
@@ -482,7 +557,7 @@ impl Decodable for ConnackProperties {
 }
 
 impl Display for ConnackProperties {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         write!(f, "{{")?;
         match &self.session_expiry_interval {
             Some(session_expiry_interval) => {
@@ -1,18 +1,18 @@
 //! Disconnect Properties
 
-use std::{
-    fmt::Display,
-    io::{self, Write},
-};
+use alloc::{string::String, vec::Vec};
+use core::fmt::Display;
 
-use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use byteorder::BigEndian;
 
+use crate::common::io::{self, ReadBytesExt, Write, WriteBytesExt};
 use crate::{
     common::{encodable::VarInt, Decodable, Encodable},
-    v5::property::{PropertyType, PropertyTypeError},
+    v5::property::{set_property, PropertyType, PropertyTypeError},
 };
 
 #[derive(Debug, Clone, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct DisconnectProperties {
     total_length: VarInt,
     /// Session Expiry Interval in seconds
@@ -123,10 +123,7 @@ impl Decodable for DisconnectProperties {
     type Error = PropertyTypeError;
     type Cond = ();
 
-    fn decode_with<R: std::io::Read>(
-        reader: &mut R,
-        _cond: Self::Cond,
-    ) -> Result<Self, Self::Error> {
+    fn decode_with<R: io::Read>(reader: &mut R, _cond: Self::Cond) -> Result<Self, Self::Error> {
         let total_length = VarInt::decode(reader)?;
 
         if total_length.0 == 0 {
@@ -147,13 +144,17 @@ impl Decodable for DisconnectProperties {
 
             match prop.try_into()? {
                 PropertyType::SessionExpiryInterval => {
-                    session_expiry_interval = Some(reader.read_u32::<BigEndian>()?);
+                    set_property(
+                        &mut session_expiry_interval,
+                        PropertyType::SessionExpiryInterval,
+                        reader.read_u32::<BigEndian>()?,
+                    )?;
                     cursor += 4;
                 }
                 PropertyType::ReasonString => {
                     let reason = String::decode(reader)?;
                     cursor += 2 + reason.len() as u32;
-                    reason_string = Some(reason);
+                    set_property(&mut reason_string, PropertyType::ReasonString, reason)?;
                 }
                 PropertyType::UserProperty => {
                     let key = String::decode(reader)?;
@@ -164,7 +165,11 @@ impl Decodable for DisconnectProperties {
                 PropertyType::ServerReference => {
                     let reference = String::decode(reader)?;
                     cursor += 2 + reference.len() as u32;
-                    server_reference = Some(reference);
+                    set_property(
+                        &mut server_reference,
+                        PropertyType::ServerReference,
+                        reference,
+                    )?;
                 }
                 _ => return Err(PropertyTypeError::InvalidPropertyType(prop)),
             }
@@ -181,7 +186,7 @@ impl Decodable for DisconnectProperties {
 }
 
 impl Display for DisconnectProperties {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         write!(f, "{{")?;
         match &self.session_expiry_interval {
             Some(session_expiry_interval) => {
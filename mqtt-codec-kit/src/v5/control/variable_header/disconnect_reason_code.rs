@@ -1,12 +1,8 @@
 //! Disconnect Reason Code
 
-use std::{
-    fmt::Display,
-    io::{self, Read, Write},
-};
-
-use byteorder::{ReadBytesExt, WriteBytesExt};
+use core::fmt::Display;
 
+use crate::common::io::{self, Read, ReadBytesExt, Write, WriteBytesExt};
 use crate::{
     common::{Decodable, Encodable},
     v5::{control::VariableHeaderError, reason_code_value::*},
@@ -14,6 +10,8 @@ use crate::{
 
 /// Reason code for `DISCONNECT` packet
 #[derive(Debug, Eq, PartialEq, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 pub enum DisconnectReasonCode {
     /// Close the connection normally. Do not send the Will Message.
     NormalDisconnection,
@@ -189,7 +187,7 @@ impl Decodable for DisconnectReasonCode {
 }
 
 impl Display for DisconnectReasonCode {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         let code: u8 = self.into();
         write!(f, "{}", code)
     }
@@ -1,7 +1,8 @@
 //! Variable header in MQTT
 
-use std::{io, string::FromUtf8Error};
+use alloc::string::FromUtf8Error;
 
+use crate::common::io;
 use crate::{
     common::{
         protocol_level::ProtocolLevelError,
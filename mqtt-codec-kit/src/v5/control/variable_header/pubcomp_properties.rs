@@ -1,18 +1,16 @@
 //! Pubcomp Properties
 
-use std::{
-    fmt::Display,
-    io::{self, Write},
-};
-
-use byteorder::{ReadBytesExt, WriteBytesExt};
+use alloc::{string::String, vec::Vec};
+use core::fmt::Display;
 
+use crate::common::io::{self, ReadBytesExt, Write, WriteBytesExt};
 use crate::{
     common::{encodable::VarInt, Decodable, Encodable},
-    v5::property::{PropertyType, PropertyTypeError},
+    v5::property::{set_property, PropertyType, PropertyTypeError},
 };
 
 #[derive(Debug, Clone, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct PubcompProperties {
     total_length: VarInt,
     reason_string: Option<String>,
@@ -83,10 +81,7 @@ impl Decodable for PubcompProperties {
     type Error = PropertyTypeError;
     type Cond = ();
 
-    fn decode_with<R: std::io::Read>(
-        reader: &mut R,
-        _cond: Self::Cond,
-    ) -> Result<Self, Self::Error> {
+    fn decode_with<R: io::Read>(reader: &mut R, _cond: Self::Cond) -> Result<Self, Self::Error> {
         let mut reason_string = None;
         let mut user_properties = Vec::new();
 
@@ -106,7 +101,7 @@ impl Decodable for PubcompProperties {
                 PropertyType::ReasonString => {
                     let reason = String::decode(reader)?;
                     cursor += 2 + reason.len() as u32;
-                    reason_string = Some(reason);
+                    set_property(&mut reason_string, PropertyType::ReasonString, reason)?;
                 }
                 PropertyType::UserProperty => {
                     let key = String::decode(reader)?;
@@ -128,7 +123,7 @@ impl Decodable for PubcompProperties {
 }
 
 impl Display for PubcompProperties {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         write!(f, "{{")?;
         match &self.reason_string {
             Some(reason_string) => write!(f, "reason_string: {}", reason_string)?,
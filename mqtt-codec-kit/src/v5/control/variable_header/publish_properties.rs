@@ -1,21 +1,23 @@
 //! Publish Properties
 
-use std::{
-    fmt::Display,
-    io::{self, Write},
-};
+use alloc::{string::String, vec::Vec};
+use core::fmt::Display;
 
-use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use byteorder::BigEndian;
 
+use crate::common::io::{self, ReadBytesExt, Write, WriteBytesExt};
 use crate::{
     common::{
         encodable::{VarBytes, VarInt},
         Decodable, Encodable,
     },
-    v5::property::{PropertyType, PropertyTypeError},
+    v5::property::{
+        check_bool_property, check_non_zero_property, set_property, PropertyType, PropertyTypeError,
+    },
 };
 
 #[derive(Debug, Clone, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct PublishProperties {
     total_length: VarInt,
     payload_format_indicator: Option<u8>,
@@ -189,10 +191,7 @@ impl Decodable for PublishProperties {
     type Error = PropertyTypeError;
     type Cond = ();
 
-    fn decode_with<R: std::io::Read>(
-        reader: &mut R,
-        _cond: Self::Cond,
-    ) -> Result<Self, Self::Error> {
+    fn decode_with<R: io::Read>(reader: &mut R, _cond: Self::Cond) -> Result<Self, Self::Error> {
         let total_length = VarInt::decode(reader)?;
 
         if total_length.0 == 0 {
@@ -216,26 +215,38 @@ impl Decodable for PublishProperties {
 
             match prop.try_into()? {
                 PropertyType::PayloadFormatIndicator => {
-                    payload_format_indicator = Some(reader.read_u8()?);
+                    set_property(
+                        &mut payload_format_indicator,
+                        PropertyType::PayloadFormatIndicator,
+                        reader.read_u8()?,
+                    )?;
                     cursor += 1;
                 }
                 PropertyType::MessageExpiryInterval => {
-                    message_expiry_interval = Some(reader.read_u32::<BigEndian>()?);
+                    set_property(
+                        &mut message_expiry_interval,
+                        PropertyType::MessageExpiryInterval,
+                        reader.read_u32::<BigEndian>()?,
+                    )?;
                     cursor += 4;
                 }
                 PropertyType::TopicAlias => {
-                    topic_alias = Some(reader.read_u16::<BigEndian>()?);
+                    set_property(
+                        &mut topic_alias,
+                        PropertyType::TopicAlias,
+                        reader.read_u16::<BigEndian>()?,
+                    )?;
                     cursor += 2;
                 }
                 PropertyType::ResponseTopic => {
                     let topic = String::decode(reader)?;
                     cursor += 2 + topic.len() as u32;
-                    response_topic = Some(topic);
+                    set_property(&mut response_topic, PropertyType::ResponseTopic, topic)?;
                 }
                 PropertyType::CorrelationData => {
                     let data = VarBytes::decode(reader)?;
                     cursor += 2 + data.0.len() as u32;
-                    correlation_data = Some(data);
+                    set_property(&mut correlation_data, PropertyType::CorrelationData, data)?;
                 }
                 PropertyType::UserProperty => {
                     let key = String::decode(reader)?;
@@ -251,12 +262,22 @@ impl Decodable for PublishProperties {
                 PropertyType::ContentType => {
                     let typ = String::decode(reader)?;
                     cursor += 2 + typ.len() as u32;
-                    content_type = Some(typ);
+                    set_property(&mut content_type, PropertyType::ContentType, typ)?;
                 }
                 _ => return Err(PropertyTypeError::InvalidPropertyType(prop)),
             }
         }
 
+        check_bool_property(
+            PropertyType::PayloadFormatIndicator,
+            payload_format_indicator,
+        )?;
+        check_non_zero_property(PropertyType::TopicAlias, topic_alias)?;
+        check_non_zero_property(
+            PropertyType::SubscriptionIdentifier,
+            subscription_identifier,
+        )?;
+
         Ok(Self {
             total_length,
             payload_format_indicator,
@@ -272,7 +293,7 @@ impl Decodable for PublishProperties {
 }
 
 impl Display for PublishProperties {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         write!(f, "{{")?;
         match &self.payload_format_indicator {
             Some(payload_format_indicator) => {
@@ -1,18 +1,16 @@
 //! Subscribe Properties
 
-use std::{
-    fmt::Display,
-    io::{self, Write},
-};
-
-use byteorder::{ReadBytesExt, WriteBytesExt};
+use alloc::{string::String, vec::Vec};
+use core::fmt::Display;
 
+use crate::common::io::{self, ReadBytesExt, Write, WriteBytesExt};
 use crate::{
     common::{encodable::VarInt, Decodable, Encodable},
-    v5::property::{PropertyType, PropertyTypeError},
+    v5::property::{set_property, PropertyType, PropertyTypeError},
 };
 
 #[derive(Debug, Clone, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct SubscribeProperties {
     total_length: VarInt,
     identifier: Option<usize>,
@@ -84,10 +82,7 @@ impl Decodable for SubscribeProperties {
     type Error = PropertyTypeError;
     type Cond = ();
 
-    fn decode_with<R: std::io::Read>(
-        reader: &mut R,
-        _cond: Self::Cond,
-    ) -> Result<Self, Self::Error> {
+    fn decode_with<R: io::Read>(reader: &mut R, _cond: Self::Cond) -> Result<Self, Self::Error> {
         let mut id = None;
         let mut user_properties = Vec::new();
 
@@ -107,7 +102,11 @@ impl Decodable for SubscribeProperties {
                 PropertyType::SubscriptionIdentifier => {
                     let sub_id = VarInt::decode(reader)?;
                     cursor += 1 + sub_id.encoded_length();
-                    id = Some(sub_id.0 as usize);
+                    set_property(
+                        &mut id,
+                        PropertyType::SubscriptionIdentifier,
+                        sub_id.0 as usize,
+                    )?;
                 }
                 PropertyType::UserProperty => {
                     let key = String::decode(reader)?;
@@ -119,6 +118,12 @@ impl Decodable for SubscribeProperties {
             }
         }
 
+        if id == Some(0) {
+            return Err(PropertyTypeError::ProtocolError(
+                PropertyType::SubscriptionIdentifier,
+            ));
+        }
+
         Ok(Self {
             total_length,
             identifier: id,
@@ -128,7 +133,7 @@ impl Decodable for SubscribeProperties {
 }
 
 impl Display for SubscribeProperties {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         write!(f, "{{")?;
         match &self.identifier {
             Some(identifier) => write!(f, "identifier: {}", identifier)?,
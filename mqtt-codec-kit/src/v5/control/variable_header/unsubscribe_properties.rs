@@ -1,18 +1,16 @@
 //! Unsubscribe Properties
 
-use std::{
-    fmt::Display,
-    io::{self, Write},
-};
-
-use byteorder::{ReadBytesExt, WriteBytesExt};
+use alloc::{string::String, vec::Vec};
+use core::fmt::Display;
 
+use crate::common::io::{self, ReadBytesExt, Write, WriteBytesExt};
 use crate::{
     common::{encodable::VarInt, Decodable, Encodable},
     v5::property::{PropertyType, PropertyTypeError},
 };
 
 #[derive(Debug, Clone, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct UnsubscribeProperties {
     total_length: VarInt,
     user_properties: Vec<(String, String)>,
@@ -66,10 +64,7 @@ impl Decodable for UnsubscribeProperties {
     type Error = PropertyTypeError;
     type Cond = ();
 
-    fn decode_with<R: std::io::Read>(
-        reader: &mut R,
-        _cond: Self::Cond,
-    ) -> Result<Self, Self::Error> {
+    fn decode_with<R: io::Read>(reader: &mut R, _cond: Self::Cond) -> Result<Self, Self::Error> {
         let mut user_properties = Vec::new();
 
         let total_length = VarInt::decode(reader)?;
@@ -104,7 +99,7 @@ impl Decodable for UnsubscribeProperties {
 }
 
 impl Display for UnsubscribeProperties {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         write!(f, "{{user_properties: [")?;
         let mut iter = self.user_properties.iter();
         if let Some(first) = iter.next() {
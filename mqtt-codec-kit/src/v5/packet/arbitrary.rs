@@ -0,0 +1,427 @@
+//! `Arbitrary` implementations for fuzzing
+
+use arbitrary::{Arbitrary, Result, Unstructured};
+
+use crate::{
+    common::{
+        arbitrary::{
+            arbitrary_bytes, arbitrary_len, arbitrary_option_bytes, arbitrary_option_string,
+            arbitrary_string, arbitrary_user_properties,
+        },
+        qos::QoSWithPacketIdentifier,
+        QualityOfService, TopicFilter, TopicName,
+    },
+    v5::{
+        control::{
+            AuthProperties, AuthenticateReasonCode, ConnackProperties, ConnectReasonCode,
+            DisconnectProperties, DisconnectReasonCode, PubackProperties, PubackReasonCode,
+            PubcompProperties, PubcompReasonCode, PublishProperties, PubrecProperties,
+            PubrecReasonCode, PubrelProperties, PubrelReasonCode, SubackProperties,
+            SubscribeProperties, UnsubackProperties, UnsubscribeProperties,
+        },
+        packet::{
+            connect::{ConnectProperties, LastWill, LastWillProperties},
+            subscribe::SubscribeOptions,
+            AuthPacket, ConnackPacket, ConnectPacket, DisconnectPacket, PingreqPacket,
+            PingrespPacket, PubackPacket, PubcompPacket, PublishPacket, PubrecPacket, PubrelPacket,
+            SubackPacket, SubscribePacket, UnsubackPacket, UnsubscribePacket, VariablePacket,
+        },
+    },
+};
+
+/// Largest value of a variable byte integer
+const MAX_VAR_INT: u32 = 268_435_455;
+
+fn arbitrary_var_int(u: &mut Unstructured<'_>) -> Result<u32> {
+    u.int_in_range(1..=MAX_VAR_INT)
+}
+
+macro_rules! arbitrary_option {
+    ($u:ident, $gen:expr) => {
+        if bool::arbitrary($u)? {
+            Some($gen)
+        } else {
+            None
+        }
+    };
+}
+
+macro_rules! arbitrary_reason_properties {
+    ($($name:ident),+) => {
+        $(
+            impl<'a> Arbitrary<'a> for $name {
+                fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+                    let mut properties = $name::default();
+                    properties.set_reason_string(arbitrary_option_string(u)?);
+                    for (key, value) in arbitrary_user_properties(u)? {
+                        properties.add_user_property(key, value);
+                    }
+                    Ok(properties)
+                }
+            }
+        )+
+    };
+}
+
+arbitrary_reason_properties!(
+    PubackProperties,
+    PubrecProperties,
+    PubrelProperties,
+    PubcompProperties,
+    SubackProperties,
+    UnsubackProperties
+);
+
+impl<'a> Arbitrary<'a> for UnsubscribeProperties {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        let mut properties = UnsubscribeProperties::default();
+        for (key, value) in arbitrary_user_properties(u)? {
+            properties.add_user_property(key, value);
+        }
+        Ok(properties)
+    }
+}
+
+impl<'a> Arbitrary<'a> for SubscribeProperties {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        let mut properties = SubscribeProperties::default();
+        properties.set_identifier(arbitrary_option!(u, arbitrary_var_int(u)? as usize));
+        for (key, value) in arbitrary_user_properties(u)? {
+            properties.add_user_property(key, value);
+        }
+        Ok(properties)
+    }
+}
+
+impl<'a> Arbitrary<'a> for PublishProperties {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        let mut properties = PublishProperties::default();
+        properties.set_payload_format_indicator(arbitrary_option!(u, u.int_in_range(0..=1)?));
+        properties.set_message_expiry_interval(Option::<u32>::arbitrary(u)?);
+        properties.set_topic_alias(arbitrary_option!(u, u.int_in_range(1..=u16::MAX)?));
+        properties.set_response_topic(arbitrary_option!(u, TopicName::arbitrary(u)?.into()));
+        properties.set_correlation_data(arbitrary_option_bytes(u)?);
+        for (key, value) in arbitrary_user_properties(u)? {
+            properties.add_user_property(key, value);
+        }
+        if bool::arbitrary(u)? {
+            properties.set_subscription_identifier(arbitrary_var_int(u)?);
+        }
+        properties.set_content_type(arbitrary_option_string(u)?);
+        Ok(properties)
+    }
+}
+
+impl<'a> Arbitrary<'a> for ConnectProperties {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        let mut properties = ConnectProperties::default();
+        properties.set_session_expiry_interval(Option::<u32>::arbitrary(u)?);
+        properties.set_receive_maximum(arbitrary_option!(u, u.int_in_range(1..=u16::MAX)?));
+        properties.set_max_packet_size(arbitrary_option!(u, u.int_in_range(1..=u32::MAX)?));
+        properties.set_topic_alias_max(Option::<u16>::arbitrary(u)?);
+        properties.set_request_response_info(arbitrary_option!(u, u.int_in_range(0..=1)?));
+        properties.set_request_problem_info(arbitrary_option!(u, u.int_in_range(0..=1)?));
+        for (key, value) in arbitrary_user_properties(u)? {
+            properties.add_user_property(key, value);
+        }
+        let authentication_method = arbitrary_option_string(u)?;
+        if authentication_method.is_some() {
+            properties.set_authentication_data(arbitrary_option_bytes(u)?);
+        }
+        properties.set_authentication_method(authentication_method);
+        Ok(properties)
+    }
+}
+
+impl<'a> Arbitrary<'a> for LastWillProperties {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        let mut properties = LastWillProperties::default();
+        properties.set_delay_interval(Option::<u32>::arbitrary(u)?);
+        properties.set_payload_format_indicator(arbitrary_option!(u, u.int_in_range(0..=1)?));
+        properties.set_message_expiry_interval(Option::<u32>::arbitrary(u)?);
+        properties.set_content_type(arbitrary_option_string(u)?);
+        properties.set_response_topic(arbitrary_option!(u, TopicName::arbitrary(u)?.into()));
+        properties.set_correlation_data(arbitrary_option_bytes(u)?);
+        for (key, value) in arbitrary_user_properties(u)? {
+            properties.add_user_property(key, value);
+        }
+        Ok(properties)
+    }
+}
+
+impl<'a> Arbitrary<'a> for ConnackProperties {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        let mut properties = ConnackProperties::default();
+        properties.set_session_expiry_interval(Option::<u32>::arbitrary(u)?);
+        properties.set_receive_maximum(arbitrary_option!(u, u.int_in_range(1..=u16::MAX)?));
+        properties.set_max_qos(arbitrary_option!(u, u.int_in_range(0..=1)?));
+        properties.set_retain_available(arbitrary_option!(u, u.int_in_range(0..=1)?));
+        properties.set_max_packet_size(arbitrary_option!(u, u.int_in_range(1..=u32::MAX)?));
+        properties.set_assigned_client_identifier(arbitrary_option_string(u)?);
+        properties.set_topic_alias_max(Option::<u16>::arbitrary(u)?);
+        properties.set_reason_string(arbitrary_option_string(u)?);
+        for (key, value) in arbitrary_user_properties(u)? {
+            properties.add_user_property(key, value);
+        }
+        properties
+            .set_wildcard_subscription_available(arbitrary_option!(u, u.int_in_range(0..=1)?));
+        properties
+            .set_subscription_identifiers_available(arbitrary_option!(u, u.int_in_range(0..=1)?));
+        properties.set_shared_subscription_available(arbitrary_option!(u, u.int_in_range(0..=1)?));
+        properties.set_server_keep_alive(Option::<u16>::arbitrary(u)?);
+        properties.set_response_information(arbitrary_option_string(u)?);
+        properties.set_server_reference(arbitrary_option_string(u)?);
+        properties.set_authentication_method(arbitrary_option_string(u)?);
+        properties.set_authentication_data(arbitrary_option_bytes(u)?);
+        Ok(properties)
+    }
+}
+
+impl<'a> Arbitrary<'a> for DisconnectProperties {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        let mut properties = DisconnectProperties::default();
+        properties.set_session_expiry_interval(Option::<u32>::arbitrary(u)?);
+        properties.set_reason_string(arbitrary_option_string(u)?);
+        for (key, value) in arbitrary_user_properties(u)? {
+            properties.add_user_property(key, value);
+        }
+        properties.set_server_reference(arbitrary_option_string(u)?);
+        Ok(properties)
+    }
+}
+
+impl<'a> Arbitrary<'a> for AuthProperties {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        let mut properties = AuthProperties::default();
+        properties.set_reason_string(arbitrary_option_string(u)?);
+        for (key, value) in arbitrary_user_properties(u)? {
+            properties.add_user_property(key, value);
+        }
+        properties.set_authentication_method(arbitrary_option_string(u)?);
+        properties.set_authentication_data(arbitrary_option_bytes(u)?);
+        Ok(properties)
+    }
+}
+
+impl<'a> Arbitrary<'a> for ConnectPacket {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        let mut packet = ConnectPacket::new(arbitrary_string(u)?);
+        packet.set_keep_alive(u16::arbitrary(u)?);
+        packet.set_clean_session(bool::arbitrary(u)?);
+        packet.set_username(arbitrary_option_string(u)?);
+        packet.set_password(arbitrary_option_string(u)?);
+        packet.set_properties(ConnectProperties::arbitrary(u)?);
+        if bool::arbitrary(u)? {
+            let topic = TopicName::arbitrary(u)?;
+            let mut will = LastWill::new(topic, arbitrary_bytes(u)?)
+                .expect("generated topic name should be valid");
+            will.set_properties(LastWillProperties::arbitrary(u)?);
+            packet.set_will(Some(will));
+            packet.set_will_qos(QualityOfService::arbitrary(u)? as u8);
+            packet.set_will_retain(bool::arbitrary(u)?);
+        }
+        Ok(packet)
+    }
+}
+
+impl<'a> Arbitrary<'a> for ConnackPacket {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        let mut packet = ConnackPacket::new(bool::arbitrary(u)?, ConnectReasonCode::arbitrary(u)?);
+        packet.set_properties(ConnackProperties::arbitrary(u)?);
+        Ok(packet)
+    }
+}
+
+impl<'a> Arbitrary<'a> for PublishPacket {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        let mut packet = PublishPacket::new(
+            TopicName::arbitrary(u)?,
+            QoSWithPacketIdentifier::arbitrary(u)?,
+            arbitrary_bytes(u)?,
+        );
+        packet.set_dup(bool::arbitrary(u)?);
+        packet.set_retain(bool::arbitrary(u)?);
+        packet.set_properties(PublishProperties::arbitrary(u)?);
+        Ok(packet)
+    }
+}
+
+macro_rules! arbitrary_ack_packet {
+    ($($name:ident & $code:ident & $properties:ident,)+) => {
+        $(
+            impl<'a> Arbitrary<'a> for $name {
+                fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+                    let mut packet = $name::new(u16::arbitrary(u)?, $code::arbitrary(u)?);
+                    if bool::arbitrary(u)? {
+                        packet.set_properties($properties::arbitrary(u)?);
+                    }
+                    Ok(packet)
+                }
+            }
+        )+
+    };
+}
+
+arbitrary_ack_packet!(
+    PubackPacket & PubackReasonCode & PubackProperties,
+    PubrecPacket & PubrecReasonCode & PubrecProperties,
+    PubrelPacket & PubrelReasonCode & PubrelProperties,
+    PubcompPacket & PubcompReasonCode & PubcompProperties,
+);
+
+impl<'a> Arbitrary<'a> for PingreqPacket {
+    fn arbitrary(_u: &mut Unstructured<'a>) -> Result<Self> {
+        Ok(PingreqPacket::new())
+    }
+}
+
+impl<'a> Arbitrary<'a> for PingrespPacket {
+    fn arbitrary(_u: &mut Unstructured<'a>) -> Result<Self> {
+        Ok(PingrespPacket::new())
+    }
+}
+
+impl<'a> Arbitrary<'a> for SubscribePacket {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        let len = arbitrary_len(u, 1)?;
+        let subscribes = (0..len)
+            .map(|_| Ok((TopicFilter::arbitrary(u)?, SubscribeOptions::arbitrary(u)?)))
+            .collect::<Result<Vec<_>>>()?;
+        let mut packet = SubscribePacket::new(u16::arbitrary(u)?, subscribes);
+        packet.set_properties(SubscribeProperties::arbitrary(u)?);
+        Ok(packet)
+    }
+}
+
+impl<'a> Arbitrary<'a> for SubackPacket {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        let len = arbitrary_len(u, 1)?;
+        let reason_codes = (0..len)
+            .map(|_| Arbitrary::arbitrary(u))
+            .collect::<Result<Vec<_>>>()?;
+        let mut packet = SubackPacket::new(u16::arbitrary(u)?, reason_codes);
+        packet.set_properties(SubackProperties::arbitrary(u)?);
+        Ok(packet)
+    }
+}
+
+impl<'a> Arbitrary<'a> for UnsubscribePacket {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        let len = arbitrary_len(u, 1)?;
+        let topic_filters = (0..len)
+            .map(|_| TopicFilter::arbitrary(u))
+            .collect::<Result<Vec<_>>>()?;
+        let mut packet = UnsubscribePacket::new(u16::arbitrary(u)?, topic_filters);
+        packet.set_properties(UnsubscribeProperties::arbitrary(u)?);
+        Ok(packet)
+    }
+}
+
+impl<'a> Arbitrary<'a> for UnsubackPacket {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        let len = arbitrary_len(u, 1)?;
+        let reason_codes = (0..len)
+            .map(|_| Arbitrary::arbitrary(u))
+            .collect::<Result<Vec<_>>>()?;
+        let mut packet = UnsubackPacket::new(u16::arbitrary(u)?, reason_codes);
+        packet.set_properties(UnsubackProperties::arbitrary(u)?);
+        Ok(packet)
+    }
+}
+
+impl<'a> Arbitrary<'a> for DisconnectPacket {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        let mut packet = DisconnectPacket::new(DisconnectReasonCode::arbitrary(u)?);
+        if bool::arbitrary(u)? {
+            packet.set_properties(DisconnectProperties::arbitrary(u)?);
+        }
+        Ok(packet)
+    }
+}
+
+impl<'a> Arbitrary<'a> for AuthPacket {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        let mut packet = AuthPacket::new(AuthenticateReasonCode::arbitrary(u)?);
+        if bool::arbitrary(u)? {
+            packet.set_properties(Some(AuthProperties::arbitrary(u)?));
+        }
+        Ok(packet)
+    }
+}
+
+impl<'a> Arbitrary<'a> for VariablePacket {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        Ok(match u.int_in_range(0..=14)? {
+            0 => ConnectPacket::arbitrary(u)?.into(),
+            1 => ConnackPacket::arbitrary(u)?.into(),
+            2 => PublishPacket::arbitrary(u)?.into(),
+            3 => PubackPacket::arbitrary(u)?.into(),
+            4 => PubrecPacket::arbitrary(u)?.into(),
+            5 => PubrelPacket::arbitrary(u)?.into(),
+            6 => PubcompPacket::arbitrary(u)?.into(),
+            7 => PingreqPacket::arbitrary(u)?.into(),
+            8 => PingrespPacket::arbitrary(u)?.into(),
+            9 => SubscribePacket::arbitrary(u)?.into(),
+            10 => SubackPacket::arbitrary(u)?.into(),
+            11 => UnsubscribePacket::arbitrary(u)?.into(),
+            12 => UnsubackPacket::arbitrary(u)?.into(),
+            13 => DisconnectPacket::arbitrary(u)?.into(),
+            _ => AuthPacket::arbitrary(u)?.into(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::common::io::Cursor;
+
+    use crate::common::{Decodable, Encodable};
+
+    /// Deterministic pseudo random input, so failures are reproducible
+    fn input(seed: u64, len: usize) -> Vec<u8> {
+        let mut state = seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_arbitrary_packet_round_trip() {
+        for seed in 0..2048 {
+            let data = input(seed, 512);
+            let mut u = Unstructured::new(&data);
+            let packet = VariablePacket::arbitrary(&mut u).unwrap();
+
+            let mut buf = Vec::new();
+            packet.encode(&mut buf).unwrap();
+
+            let decoded = VariablePacket::decode(&mut Cursor::new(&buf[..])).unwrap();
+            assert_eq!(packet, decoded, "seed {seed}");
+        }
+    }
+
+    #[test]
+    fn test_decode_mutated_packet() {
+        for seed in 0..2048 {
+            let data = input(seed, 512);
+            let mut u = Unstructured::new(&data);
+            let packet = VariablePacket::arbitrary(&mut u).unwrap();
+
+            let mut buf = Vec::new();
+            packet.encode(&mut buf).unwrap();
+
+            for pair in u.take_rest().chunks_exact(2).take(4) {
+                let idx = pair[0] as usize % buf.len();
+                buf[idx] ^= pair[1];
+            }
+            let _ = VariablePacket::decode(&mut Cursor::new(&buf[..]));
+        }
+    }
+}
@@ -1,7 +1,7 @@
-use std::fmt::Display;
+use core::fmt::Display;
 
 use crate::{
-    common::{packet::DecodablePacket, Decodable},
+    common::{io::Read, packet::DecodablePacket, Decodable},
     v5::control::{
         AuthProperties, AuthenticateReasonCode, ControlType, FixedHeader, PacketType,
         VariableHeaderError,
@@ -12,6 +12,11 @@ use super::PacketError;
 
 /// `AUTH` packet
 #[derive(Debug, Eq, PartialEq, Clone)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(remote = "Self")
+)]
 pub struct AuthPacket {
     fixed_header: FixedHeader,
     reason_code: AuthenticateReasonCode,
@@ -20,6 +25,8 @@ pub struct AuthPacket {
 
 encodable_packet!(AuthPacket(reason_code, properties));
 
+serde_packet!(AuthPacket => Auth);
+
 impl AuthPacket {
     pub fn new(reason_code: AuthenticateReasonCode) -> Self {
         if reason_code == AuthenticateReasonCode::Success {
@@ -57,14 +64,11 @@ impl AuthPacket {
 }
 
 impl DecodablePacket for AuthPacket {
-    type DecodePacketError = std::convert::Infallible;
+    type DecodePacketError = core::convert::Infallible;
     type F = FixedHeader;
     type Error = PacketError<Self>;
 
-    fn decode_packet<R: std::io::Read>(
-        reader: &mut R,
-        fixed_header: Self::F,
-    ) -> Result<Self, Self::Error> {
+    fn decode_packet<R: Read>(reader: &mut R, fixed_header: Self::F) -> Result<Self, Self::Error> {
         let auth = if fixed_header.remaining_length == 0 {
             Self {
                 fixed_header,
@@ -86,7 +90,7 @@ impl DecodablePacket for AuthPacket {
 }
 
 impl Display for AuthPacket {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         write!(
             f,
             "{{fixed_header: {}, reason_code: {}",
@@ -101,7 +105,7 @@ impl Display for AuthPacket {
 
 #[cfg(test)]
 mod test {
-    use std::io::Cursor;
+    use crate::common::io::Cursor;
 
     use crate::common::encodable::{Decodable, Encodable};
 
@@ -0,0 +1,660 @@
+//! Validating packet builders
+//!
+//! Unlike the setters on the packets themselves, the builders check the constraints of the
+//! specification when calling `build()`, so a successfully built packet can always be sent.
+
+use alloc::{string::String, vec::Vec};
+
+use crate::{
+    common::{
+        encodable::{check_utf8_string, Utf8StringError},
+        packet::EncodablePacket,
+        qos::QoSWithPacketIdentifier,
+        QualityOfService, TopicName, TopicNameError, MAX_REMAINING_LENGTH,
+    },
+    v5::{
+        control::PublishProperties,
+        packet::{
+            connect::{ConnectProperties, LastWill, LastWillProperties},
+            ConnectPacket, PublishPacket,
+        },
+    },
+};
+
+/// Largest value of a Variable Byte Integer, e.g. a subscription identifier
+const MAX_VARIABLE_BYTE_INTEGER: u32 = 0x0FFF_FFFF;
+
+/// Errors returned by the packet builders
+#[derive(Debug, thiserror::Error)]
+pub enum PacketBuilderError {
+    #[error("topic name is required")]
+    MissingTopicName,
+    #[error(transparent)]
+    InvalidTopicName(#[from] TopicNameError),
+    #[error("QoS {0:?} requires a packet identifier")]
+    MissingPacketIdentifier(QualityOfService),
+    #[error("QoS 0 packets must not carry a packet identifier")]
+    UnexpectedPacketIdentifier,
+    #[error("packet identifier must be non-zero")]
+    ZeroPacketIdentifier,
+    #[error("DUP flag must not be set for QoS 0 packets")]
+    DupWithQoS0,
+    #[error("invalid {0}: {1}")]
+    InvalidString(&'static str, #[source] Utf8StringError),
+    #[error("invalid {0} ({1})")]
+    InvalidProperty(&'static str, u32),
+    #[error("{0} requires {1}")]
+    MissingDependency(&'static str, &'static str),
+    #[error("payload is not valid UTF-8 but payload format indicator is 1")]
+    PayloadNotUtf8,
+    #[error("packet too large, remaining length {0} exceeds {MAX_REMAINING_LENGTH}")]
+    PacketTooLarge(u64),
+}
+
+fn check_string(field: &'static str, s: &str) -> Result<(), PacketBuilderError> {
+    check_utf8_string(s).map_err(|err| PacketBuilderError::InvalidString(field, err))
+}
+
+fn check_user_properties(properties: &[(String, String)]) -> Result<(), PacketBuilderError> {
+    for (key, value) in properties {
+        check_string("user property key", key)?;
+        check_string("user property value", value)?;
+    }
+    Ok(())
+}
+
+fn check_bool_property(field: &'static str, value: Option<u8>) -> Result<(), PacketBuilderError> {
+    match value {
+        Some(v) if v > 1 => Err(PacketBuilderError::InvalidProperty(field, v.into())),
+        _ => Ok(()),
+    }
+}
+
+fn check_payload_format(indicator: Option<u8>, payload: &[u8]) -> Result<(), PacketBuilderError> {
+    check_bool_property("payload format indicator", indicator)?;
+    if indicator == Some(1) && core::str::from_utf8(payload).is_err() {
+        return Err(PacketBuilderError::PayloadNotUtf8);
+    }
+    Ok(())
+}
+
+fn check_response_topic(response_topic: &Option<String>) -> Result<(), PacketBuilderError> {
+    if let Some(topic) = response_topic {
+        TopicName::new(topic.as_str())?;
+    }
+    Ok(())
+}
+
+fn check_packet_length<P: EncodablePacket>(packet: &P) -> Result<(), PacketBuilderError> {
+    // `encoded_packet_length` is a u32 and may already have wrapped for huge payloads, so the
+    // builders check their payload separately as well.
+    let length = packet.encoded_packet_length();
+    if length > MAX_REMAINING_LENGTH {
+        return Err(PacketBuilderError::PacketTooLarge(length.into()));
+    }
+    Ok(())
+}
+
+fn check_payload_length(payload: &[u8]) -> Result<(), PacketBuilderError> {
+    if payload.len() as u64 > MAX_REMAINING_LENGTH as u64 {
+        return Err(PacketBuilderError::PacketTooLarge(payload.len() as u64));
+    }
+    Ok(())
+}
+
+/// Builder for [`PublishPacket`]
+///
+/// ```rust
+/// use mqtt_codec_kit::common::QualityOfService;
+/// use mqtt_codec_kit::v5::packet::PublishPacket;
+///
+/// let packet = PublishPacket::builder()
+///     .topic_name("sensors/temperature")
+///     .qos(QualityOfService::Level1)
+///     .packet_identifier(10)
+///     .payload("21.5")
+///     .message_expiry_interval(60)
+///     .build()
+///     .unwrap();
+/// assert_eq!(packet.properties().message_expiry_interval(), Some(60));
+/// ```
+#[derive(Debug, Clone)]
+pub struct PublishPacketBuilder {
+    topic_name: Option<String>,
+    qos: QualityOfService,
+    packet_identifier: Option<u16>,
+    dup: bool,
+    retain: bool,
+    payload: Vec<u8>,
+    properties: PublishProperties,
+}
+
+impl Default for PublishPacketBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PublishPacketBuilder {
+    pub fn new() -> Self {
+        Self {
+            topic_name: None,
+            qos: QualityOfService::Level0,
+            packet_identifier: None,
+            dup: false,
+            retain: false,
+            payload: Vec::new(),
+            properties: PublishProperties::default(),
+        }
+    }
+
+    pub fn topic_name<S: Into<String>>(mut self, topic_name: S) -> Self {
+        self.topic_name = Some(topic_name.into());
+        self
+    }
+
+    pub fn qos(mut self, qos: QualityOfService) -> Self {
+        self.qos = qos;
+        self
+    }
+
+    pub fn packet_identifier(mut self, pkid: u16) -> Self {
+        self.packet_identifier = Some(pkid);
+        self
+    }
+
+    pub fn dup(mut self, dup: bool) -> Self {
+        self.dup = dup;
+        self
+    }
+
+    pub fn retain(mut self, retain: bool) -> Self {
+        self.retain = retain;
+        self
+    }
+
+    pub fn payload<P: Into<Vec<u8>>>(mut self, payload: P) -> Self {
+        self.payload = payload.into();
+        self
+    }
+
+    /// Replaces all properties set so far
+    pub fn properties(mut self, properties: PublishProperties) -> Self {
+        self.properties = properties;
+        self
+    }
+
+    pub fn payload_format_indicator(mut self, payload_format_indicator: u8) -> Self {
+        self.properties
+            .set_payload_format_indicator(Some(payload_format_indicator));
+        self
+    }
+
+    pub fn message_expiry_interval(mut self, message_expiry_interval: u32) -> Self {
+        self.properties
+            .set_message_expiry_interval(Some(message_expiry_interval));
+        self
+    }
+
+    pub fn topic_alias(mut self, topic_alias: u16) -> Self {
+        self.properties.set_topic_alias(Some(topic_alias));
+        self
+    }
+
+    pub fn response_topic<S: Into<String>>(mut self, response_topic: S) -> Self {
+        self.properties
+            .set_response_topic(Some(response_topic.into()));
+        self
+    }
+
+    pub fn correlation_data<D: Into<Vec<u8>>>(mut self, correlation_data: D) -> Self {
+        self.properties
+            .set_correlation_data(Some(correlation_data.into()));
+        self
+    }
+
+    pub fn user_property<S: Into<String>>(mut self, key: S, value: S) -> Self {
+        self.properties.add_user_property(key, value);
+        self
+    }
+
+    pub fn content_type<S: Into<String>>(mut self, content_type: S) -> Self {
+        self.properties.set_content_type(Some(content_type.into()));
+        self
+    }
+
+    pub fn build(self) -> Result<PublishPacket, PacketBuilderError> {
+        let topic_name = TopicName::new(
+            self.topic_name
+                .ok_or(PacketBuilderError::MissingTopicName)?,
+        )?;
+
+        let qos = match (self.qos, self.packet_identifier) {
+            (QualityOfService::Level0, None) => QoSWithPacketIdentifier::Level0,
+            (QualityOfService::Level0, Some(_)) => {
+                return Err(PacketBuilderError::UnexpectedPacketIdentifier)
+            }
+            (qos, None) => return Err(PacketBuilderError::MissingPacketIdentifier(qos)),
+            (_, Some(0)) => return Err(PacketBuilderError::ZeroPacketIdentifier),
+            (qos, Some(pkid)) => QoSWithPacketIdentifier::new(qos, pkid),
+        };
+        if self.dup && qos == QoSWithPacketIdentifier::Level0 {
+            return Err(PacketBuilderError::DupWithQoS0);
+        }
+
+        let properties = &self.properties;
+        check_payload_format(properties.payload_format_indicator(), &self.payload)?;
+        if properties.topic_alias() == Some(0) {
+            return Err(PacketBuilderError::InvalidProperty("topic alias", 0));
+        }
+        check_response_topic(properties.response_topic())?;
+        if let Some(id) = properties.subscription_identifier() {
+            if id == 0 || id > MAX_VARIABLE_BYTE_INTEGER {
+                return Err(PacketBuilderError::InvalidProperty(
+                    "subscription identifier",
+                    id,
+                ));
+            }
+        }
+        if let Some(content_type) = properties.content_type() {
+            check_string("content type", content_type)?;
+        }
+        check_user_properties(properties.user_properties())?;
+        check_payload_length(&self.payload)?;
+
+        let mut packet = PublishPacket::new(topic_name, qos, self.payload);
+        packet.set_dup(self.dup);
+        packet.set_retain(self.retain);
+        packet.set_properties(self.properties);
+        check_packet_length(&packet)?;
+
+        Ok(packet)
+    }
+}
+
+impl PublishPacket {
+    /// Creates a [`PublishPacketBuilder`]
+    pub fn builder() -> PublishPacketBuilder {
+        PublishPacketBuilder::new()
+    }
+}
+
+/// Builder for [`ConnectPacket`]
+///
+/// ```rust
+/// use mqtt_codec_kit::common::QualityOfService;
+/// use mqtt_codec_kit::v5::packet::ConnectPacket;
+///
+/// let packet = ConnectPacket::builder("client-1")
+///     .keep_alive(30)
+///     .username("user")
+///     .password("secret")
+///     .will("clients/client-1/status", "offline")
+///     .will_qos(QualityOfService::Level1)
+///     .session_expiry_interval(3600)
+///     .build()
+///     .unwrap();
+/// assert_eq!(packet.will_qos(), 1);
+/// ```
+#[derive(Debug, Clone)]
+pub struct ConnectPacketBuilder {
+    client_identifier: String,
+    keep_alive: u16,
+    clean_start: bool,
+    username: Option<String>,
+    password: Option<String>,
+    will: Option<(String, Vec<u8>)>,
+    will_qos: QualityOfService,
+    will_retain: bool,
+    will_properties: LastWillProperties,
+    properties: ConnectProperties,
+}
+
+impl ConnectPacketBuilder {
+    pub fn new<C: Into<String>>(client_identifier: C) -> Self {
+        Self {
+            client_identifier: client_identifier.into(),
+            keep_alive: 0,
+            clean_start: false,
+            username: None,
+            password: None,
+            will: None,
+            will_qos: QualityOfService::Level0,
+            will_retain: false,
+            will_properties: LastWillProperties::default(),
+            properties: ConnectProperties::default(),
+        }
+    }
+
+    pub fn keep_alive(mut self, keep_alive: u16) -> Self {
+        self.keep_alive = keep_alive;
+        self
+    }
+
+    pub fn clean_start(mut self, clean_start: bool) -> Self {
+        self.clean_start = clean_start;
+        self
+    }
+
+    pub fn username<S: Into<String>>(mut self, username: S) -> Self {
+        self.username = Some(username.into());
+        self
+    }
+
+    pub fn password<S: Into<String>>(mut self, password: S) -> Self {
+        self.password = Some(password.into());
+        self
+    }
+
+    pub fn will<S: Into<String>, P: Into<Vec<u8>>>(mut self, topic: S, payload: P) -> Self {
+        self.will = Some((topic.into(), payload.into()));
+        self
+    }
+
+    pub fn will_qos(mut self, qos: QualityOfService) -> Self {
+        self.will_qos = qos;
+        self
+    }
+
+    pub fn will_retain(mut self, retain: bool) -> Self {
+        self.will_retain = retain;
+        self
+    }
+
+    pub fn will_properties(mut self, properties: LastWillProperties) -> Self {
+        self.will_properties = properties;
+        self
+    }
+
+    /// Replaces all properties set so far
+    pub fn properties(mut self, properties: ConnectProperties) -> Self {
+        self.properties = properties;
+        self
+    }
+
+    pub fn session_expiry_interval(mut self, session_expiry_interval: u32) -> Self {
+        self.properties
+            .set_session_expiry_interval(Some(session_expiry_interval));
+        self
+    }
+
+    pub fn receive_maximum(mut self, receive_maximum: u16) -> Self {
+        self.properties.set_receive_maximum(Some(receive_maximum));
+        self
+    }
+
+    pub fn max_packet_size(mut self, max_packet_size: u32) -> Self {
+        self.properties.set_max_packet_size(Some(max_packet_size));
+        self
+    }
+
+    pub fn topic_alias_max(mut self, topic_alias_max: u16) -> Self {
+        self.properties.set_topic_alias_max(Some(topic_alias_max));
+        self
+    }
+
+    pub fn request_response_info(mut self, request: bool) -> Self {
+        self.properties
+            .set_request_response_info(Some(request as u8));
+        self
+    }
+
+    pub fn request_problem_info(mut self, request: bool) -> Self {
+        self.properties
+            .set_request_problem_info(Some(request as u8));
+        self
+    }
+
+    pub fn user_property<S: Into<String>>(mut self, key: S, value: S) -> Self {
+        self.properties.add_user_property(key, value);
+        self
+    }
+
+    pub fn authentication<S: Into<String>>(mut self, method: S, data: Option<Vec<u8>>) -> Self {
+        self.properties
+            .set_authentication_method(Some(method.into()));
+        self.properties.set_authentication_data(data);
+        self
+    }
+
+    pub fn build(self) -> Result<ConnectPacket, PacketBuilderError> {
+        check_string("client identifier", &self.client_identifier)?;
+        if let Some(username) = &self.username {
+            check_string("username", username)?;
+        }
+        if let Some(password) = &self.password {
+            check_string("password", password)?;
+        }
+
+        let properties = &self.properties;
+        if properties.receive_maximum() == Some(0) {
+            return Err(PacketBuilderError::InvalidProperty("receive maximum", 0));
+        }
+        if properties.max_packet_size() == Some(0) {
+            return Err(PacketBuilderError::InvalidProperty(
+                "maximum packet size",
+                0,
+            ));
+        }
+        check_bool_property(
+            "request response information",
+            properties.request_response_info(),
+        )?;
+        check_bool_property(
+            "request problem information",
+            properties.request_problem_info(),
+        )?;
+        if let Some(method) = properties.authentication_method() {
+            check_string("authentication method", method)?;
+        } else if properties.authentication_data().is_some() {
+            return Err(PacketBuilderError::MissingDependency(
+                "authentication data",
+                "authentication method",
+            ));
+        }
+        check_user_properties(properties.user_properties())?;
+
+        let will = match self.will {
+            Some((topic, payload)) => {
+                let will_properties = &self.will_properties;
+                check_payload_format(will_properties.payload_format_indicator(), &payload)?;
+                check_response_topic(will_properties.response_topic())?;
+                if let Some(content_type) = will_properties.content_type() {
+                    check_string("will content type", content_type)?;
+                }
+                check_user_properties(will_properties.user_properties())?;
+                if payload.len() > u16::MAX as usize {
+                    return Err(PacketBuilderError::PacketTooLarge(payload.len() as u64));
+                }
+
+                let mut will = LastWill::new(TopicName::new(topic)?, payload)
+                    .expect("topic name is already validated");
+                will.set_properties(self.will_properties);
+                Some(will)
+            }
+            None if self.will_qos != QualityOfService::Level0 => {
+                return Err(PacketBuilderError::MissingDependency("will QoS", "will"))
+            }
+            None if self.will_retain => {
+                return Err(PacketBuilderError::MissingDependency("will retain", "will"))
+            }
+            None => None,
+        };
+
+        let mut packet = ConnectPacket::new(self.client_identifier);
+        packet.set_keep_alive(self.keep_alive);
+        packet.set_clean_session(self.clean_start);
+        packet.set_username(self.username);
+        packet.set_password(self.password);
+        packet.set_properties(self.properties);
+        packet.set_will(will);
+        packet.set_will_qos(self.will_qos as u8);
+        packet.set_will_retain(self.will_retain);
+        check_packet_length(&packet)?;
+
+        Ok(packet)
+    }
+}
+
+impl ConnectPacket {
+    /// Creates a [`ConnectPacketBuilder`]
+    pub fn builder<C: Into<String>>(client_identifier: C) -> ConnectPacketBuilder {
+        ConnectPacketBuilder::new(client_identifier)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::common::{io::Cursor, Decodable, Encodable};
+
+    #[test]
+    fn test_publish_builder() {
+        let packet = PublishPacket::builder()
+            .topic_name("a/b")
+            .qos(QualityOfService::Level2)
+            .packet_identifier(10)
+            .dup(true)
+            .retain(true)
+            .payload("hello")
+            .payload_format_indicator(1)
+            .content_type("text/plain")
+            .user_property("k", "v")
+            .build()
+            .unwrap();
+
+        assert_eq!(packet.qos(), QoSWithPacketIdentifier::Level2(10));
+        assert!(packet.dup());
+        assert!(packet.retain());
+        assert_eq!(packet.properties().payload_format_indicator(), Some(1));
+
+        let mut buf = Vec::new();
+        packet.encode(&mut buf).unwrap();
+        let decoded = PublishPacket::decode(&mut Cursor::new(&buf[..])).unwrap();
+        assert_eq!(packet, decoded);
+    }
+
+    #[test]
+    fn test_publish_builder_rejects_invalid() {
+        let builder = PublishPacket::builder().topic_name("a/b");
+
+        assert!(matches!(
+            PublishPacket::builder().build(),
+            Err(PacketBuilderError::MissingTopicName)
+        ));
+        assert!(matches!(
+            PublishPacket::builder().topic_name("a/+").build(),
+            Err(PacketBuilderError::InvalidTopicName(_))
+        ));
+        assert!(matches!(
+            builder.clone().qos(QualityOfService::Level1).build(),
+            Err(PacketBuilderError::MissingPacketIdentifier(
+                QualityOfService::Level1
+            ))
+        ));
+        assert!(matches!(
+            builder.clone().packet_identifier(1).build(),
+            Err(PacketBuilderError::UnexpectedPacketIdentifier)
+        ));
+        assert!(matches!(
+            builder
+                .clone()
+                .qos(QualityOfService::Level1)
+                .packet_identifier(0)
+                .build(),
+            Err(PacketBuilderError::ZeroPacketIdentifier)
+        ));
+        assert!(matches!(
+            builder.clone().dup(true).build(),
+            Err(PacketBuilderError::DupWithQoS0)
+        ));
+        assert!(matches!(
+            builder.clone().topic_alias(0).build(),
+            Err(PacketBuilderError::InvalidProperty("topic alias", 0))
+        ));
+        assert!(matches!(
+            builder
+                .clone()
+                .payload(vec![0xff, 0xfe])
+                .payload_format_indicator(1)
+                .build(),
+            Err(PacketBuilderError::PayloadNotUtf8)
+        ));
+        assert!(matches!(
+            builder.clone().response_topic("a/#").build(),
+            Err(PacketBuilderError::InvalidTopicName(_))
+        ));
+        assert!(matches!(
+            builder.user_property("k\0", "v").build(),
+            Err(PacketBuilderError::InvalidString(
+                "user property key",
+                Utf8StringError::NullCharacter
+            ))
+        ));
+    }
+
+    #[test]
+    fn test_connect_builder() {
+        let packet = ConnectPacket::builder("client")
+            .keep_alive(30)
+            .clean_start(true)
+            .username("user")
+            .password("pass")
+            .will("a/b", "bye")
+            .will_qos(QualityOfService::Level1)
+            .will_retain(true)
+            .receive_maximum(10)
+            .authentication("SCRAM-SHA-1", Some(b"data".to_vec()))
+            .build()
+            .unwrap();
+
+        assert_eq!(packet.client_identifier(), "client");
+        assert_eq!(packet.will_qos(), 1);
+        assert!(packet.will_retain());
+        assert_eq!(packet.will().unwrap().qos(), QualityOfService::Level1);
+
+        let mut buf = Vec::new();
+        packet.encode(&mut buf).unwrap();
+        let decoded = ConnectPacket::decode(&mut Cursor::new(&buf[..])).unwrap();
+        assert_eq!(packet, decoded);
+    }
+
+    #[test]
+    fn test_connect_builder_rejects_invalid() {
+        assert!(matches!(
+            ConnectPacket::builder("client")
+                .will_qos(QualityOfService::Level1)
+                .build(),
+            Err(PacketBuilderError::MissingDependency("will QoS", "will"))
+        ));
+        assert!(matches!(
+            ConnectPacket::builder("client").will("a/#", "bye").build(),
+            Err(PacketBuilderError::InvalidTopicName(_))
+        ));
+        assert!(matches!(
+            ConnectPacket::builder("client").receive_maximum(0).build(),
+            Err(PacketBuilderError::InvalidProperty("receive maximum", 0))
+        ));
+
+        let mut properties = ConnectProperties::default();
+        properties.set_authentication_data(Some(b"data".to_vec()));
+        assert!(matches!(
+            ConnectPacket::builder("client")
+                .properties(properties)
+                .build(),
+            Err(PacketBuilderError::MissingDependency(
+                "authentication data",
+                "authentication method"
+            ))
+        ));
+
+        assert!(matches!(
+            ConnectPacket::builder("a".repeat(65536)).build(),
+            Err(PacketBuilderError::InvalidString(
+                "client identifier",
+                Utf8StringError::TooLong(65536)
+            ))
+        ));
+    }
+}
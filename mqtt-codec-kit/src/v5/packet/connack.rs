@@ -1,7 +1,8 @@
 //! CONNACK
 
-use std::{fmt::Display, io::Read};
+use core::fmt::Display;
 
+use crate::common::io::Read;
 use crate::{
     common::{packet::DecodablePacket, ConnackFlags, ConnectAckFlagsError, Decodable},
     v5::{
@@ -15,6 +16,11 @@ use crate::{
 
 /// `CONNACK` packet
 #[derive(Debug, Eq, PartialEq, Clone)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(remote = "Self")
+)]
 pub struct ConnackPacket {
     fixed_header: FixedHeader,
     flags: ConnackFlags,
@@ -24,6 +30,8 @@ pub struct ConnackPacket {
 
 encodable_packet!(ConnackPacket(flags, reason_code, properties));
 
+serde_packet!(ConnackPacket => ConnectAcknowledgement);
+
 impl ConnackPacket {
     pub fn new(session_present: bool, reason_code: ConnectReasonCode) -> Self {
         Self {
@@ -56,7 +64,7 @@ impl ConnackPacket {
 }
 
 impl DecodablePacket for ConnackPacket {
-    type DecodePacketError = std::convert::Infallible;
+    type DecodePacketError = core::convert::Infallible;
     type F = FixedHeader;
     type Error = PacketError<Self>;
 
@@ -79,7 +87,7 @@ impl DecodablePacket for ConnackPacket {
 }
 
 impl Display for ConnackPacket {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         write!(
             f,
             "{{fixed_header: {}, flags: {}, reason_code: {}, properties: {}}}",
@@ -90,7 +98,7 @@ impl Display for ConnackPacket {
 
 #[cfg(test)]
 mod test {
-    use std::io::Cursor;
+    use crate::common::io::Cursor;
 
     use crate::{common::encodable::Encodable, v5::control::variable_header::ConnectReasonCode};
 
@@ -144,6 +152,23 @@ mod test {
         assert_eq!(packet, decoded);
     }
 
+    #[test]
+    fn test_connack_packet_shared_subscription_available() {
+        let mut packet = ConnackPacket::new(false, ConnectReasonCode::Success);
+
+        let mut properties = ConnackProperties::default();
+        properties.set_shared_subscription_available(Some(0));
+
+        packet.set_properties(properties);
+
+        let expected = b"\x20\x05\x00\x00\x02\x2a\x00";
+
+        let mut buf = Vec::new();
+        packet.encode(&mut buf).unwrap();
+
+        assert_eq!(&expected[..], &buf[..]);
+    }
+
     #[test]
     fn test_display_connack_packet() {
         let mut packet = ConnackPacket::new(true, ConnectReasonCode::Success);
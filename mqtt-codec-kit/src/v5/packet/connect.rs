@@ -1,12 +1,11 @@
 //! CONNECT
 
-use std::{
-    fmt::Display,
-    io::{self, Read, Write},
-};
+use alloc::{string::String, vec::Vec};
+use core::fmt::Display;
 
-use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use byteorder::BigEndian;
 
+use crate::common::io::{self, Read, ReadBytesExt, Write, WriteBytesExt};
 use crate::{
     common::{
         encodable::{VarBytes, VarInt},
@@ -18,12 +17,20 @@ use crate::{
     v5::{
         control::{ControlType, FixedHeader, PacketType, VariableHeaderError},
         packet::PacketError,
-        property::{PropertyType, PropertyTypeError},
+        property::{
+            check_bool_property, check_non_zero_property, set_property, PropertyType,
+            PropertyTypeError,
+        },
     },
 };
 
 /// `CONNECT` packet
 #[derive(Debug, Eq, PartialEq, Clone)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(remote = "Self")
+)]
 pub struct ConnectPacket {
     fixed_header: FixedHeader,
     protocol_name: ProtocolName,
@@ -43,6 +50,8 @@ encodable_packet!(ConnectPacket(
     payload
 ));
 
+serde_packet!(ConnectPacket => Connect);
+
 impl ConnectPacket {
     pub fn new<C>(client_identifier: C) -> Self
     where
@@ -229,7 +238,7 @@ impl DecodablePacket for ConnectPacket {
 }
 
 impl Display for ConnectPacket {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         write!(
             f,
             "{{fixed_header: {}, protocol_name: {}, protocol_level: {}, flags: {}, keepalive: {}, properties: {}, payload: {}}}",
@@ -240,6 +249,7 @@ impl Display for ConnectPacket {
 
 /// Properties for connect packet
 #[derive(Debug, Clone, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct ConnectProperties {
     total_length: VarInt,
     /// Expiry interval property after loosing connection
@@ -468,27 +478,51 @@ impl Decodable for ConnectProperties {
             cursor += 1;
             match prop.try_into()? {
                 PropertyType::SessionExpiryInterval => {
-                    session_expiry_interval = Some(reader.read_u32::<BigEndian>()?);
+                    set_property(
+                        &mut session_expiry_interval,
+                        PropertyType::SessionExpiryInterval,
+                        reader.read_u32::<BigEndian>()?,
+                    )?;
                     cursor += 4;
                 }
                 PropertyType::ReceiveMaximum => {
-                    receive_maximum = Some(reader.read_u16::<BigEndian>()?);
+                    set_property(
+                        &mut receive_maximum,
+                        PropertyType::ReceiveMaximum,
+                        reader.read_u16::<BigEndian>()?,
+                    )?;
                     cursor += 2;
                 }
                 PropertyType::MaximumPacketSize => {
-                    max_packet_size = Some(reader.read_u32::<BigEndian>()?);
+                    set_property(
+                        &mut max_packet_size,
+                        PropertyType::MaximumPacketSize,
+                        reader.read_u32::<BigEndian>()?,
+                    )?;
                     cursor += 4;
                 }
                 PropertyType::TopicAliasMaximum => {
-                    topic_alias_max = Some(reader.read_u16::<BigEndian>()?);
+                    set_property(
+                        &mut topic_alias_max,
+                        PropertyType::TopicAliasMaximum,
+                        reader.read_u16::<BigEndian>()?,
+                    )?;
                     cursor += 2;
                 }
                 PropertyType::RequestResponseInformation => {
-                    request_response_info = Some(reader.read_u8()?);
+                    set_property(
+                        &mut request_response_info,
+                        PropertyType::RequestResponseInformation,
+                        reader.read_u8()?,
+                    )?;
                     cursor += 1;
                 }
                 PropertyType::RequestProblemInformation => {
-                    request_problem_info = Some(reader.read_u8()?);
+                    set_property(
+                        &mut request_problem_info,
+                        PropertyType::RequestProblemInformation,
+                        reader.read_u8()?,
+                    )?;
                     cursor += 1;
                 }
                 PropertyType::UserProperty => {
@@ -500,17 +534,41 @@ impl Decodable for ConnectProperties {
                 PropertyType::AuthenticationMethod => {
                     let method = String::decode(reader)?;
                     cursor += 2 + method.len() as u32;
-                    authentication_method = Some(method);
+                    set_property(
+                        &mut authentication_method,
+                        PropertyType::AuthenticationMethod,
+                        method,
+                    )?;
                 }
                 PropertyType::AuthenticationData => {
                     let data = VarBytes::decode(reader)?;
                     cursor += 2 + data.0.len() as u32;
-                    authentication_data = Some(data);
+                    set_property(
+                        &mut authentication_data,
+                        PropertyType::AuthenticationData,
+                        data,
+                    )?;
                 }
                 _ => return Err(PropertyTypeError::InvalidPropertyType(prop)),
             }
         }
 
+        check_non_zero_property(PropertyType::ReceiveMaximum, receive_maximum)?;
+        check_non_zero_property(PropertyType::MaximumPacketSize, max_packet_size)?;
+        check_bool_property(
+            PropertyType::RequestResponseInformation,
+            request_response_info,
+        )?;
+        check_bool_property(
+            PropertyType::RequestProblemInformation,
+            request_problem_info,
+        )?;
+        if authentication_method.is_none() && authentication_data.is_some() {
+            return Err(PropertyTypeError::ProtocolError(
+                PropertyType::AuthenticationData,
+            ));
+        }
+
         Ok(Self {
             total_length,
             session_expiry_interval,
@@ -527,7 +585,7 @@ impl Decodable for ConnectProperties {
 }
 
 impl Display for ConnectProperties {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         write!(f, "{{")?;
         match &self.session_expiry_interval {
             Some(session_expiry_interval) => {
@@ -546,6 +604,7 @@ impl Display for ConnectProperties {
 
 /// Payloads for connect packet
 #[derive(Debug, Eq, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 struct ConnectPayload {
     client_identifier: String,
     last_will: Option<LastWill>,
@@ -669,7 +728,7 @@ impl Decodable for ConnectPayload {
 }
 
 impl Display for ConnectPayload {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         write!(f, "{{client_identifier: {}", self.client_identifier)?;
         match &self.last_will {
             Some(last_will) => write!(f, ", last_will: {}", last_will)?,
@@ -699,6 +758,7 @@ pub enum ConnectPacketError {
 
 // LastWill
 #[derive(Debug, Eq, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct LastWill {
     topic: TopicName,
     message: VarBytes,
@@ -753,16 +813,15 @@ impl Encodable for LastWill {
     }
 
     fn encoded_length(&self) -> u32 {
+        // the topic and message carry their two length bytes already
         self.properties.encoded_length()
-            + 2
             + self.topic.encoded_length()
-            + 2
             + self.message.encoded_length()
     }
 }
 
 impl Display for LastWill {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         write!(
             f,
             "{{topic: {}, message: {}, qos: {}, retain: {}, properties: {}}}",
@@ -772,6 +831,7 @@ impl Display for LastWill {
 }
 
 #[derive(Debug, Eq, PartialEq, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct LastWillProperties {
     total_length: VarInt,
     delay_interval: Option<u32>,
@@ -949,31 +1009,43 @@ impl Decodable for LastWillProperties {
 
             match prop.try_into()? {
                 PropertyType::WillDelayInterval => {
-                    delay_interval = Some(reader.read_u32::<BigEndian>()?);
+                    set_property(
+                        &mut delay_interval,
+                        PropertyType::WillDelayInterval,
+                        reader.read_u32::<BigEndian>()?,
+                    )?;
                     cursor += 4;
                 }
                 PropertyType::PayloadFormatIndicator => {
-                    payload_format_indicator = Some(reader.read_u8()?);
+                    set_property(
+                        &mut payload_format_indicator,
+                        PropertyType::PayloadFormatIndicator,
+                        reader.read_u8()?,
+                    )?;
                     cursor += 1;
                 }
                 PropertyType::MessageExpiryInterval => {
-                    message_expiry_interval = Some(reader.read_u32::<BigEndian>()?);
+                    set_property(
+                        &mut message_expiry_interval,
+                        PropertyType::MessageExpiryInterval,
+                        reader.read_u32::<BigEndian>()?,
+                    )?;
                     cursor += 4;
                 }
                 PropertyType::ContentType => {
                     let typ = String::decode(reader)?;
                     cursor += 2 + typ.len() as u32;
-                    content_type = Some(typ);
+                    set_property(&mut content_type, PropertyType::ContentType, typ)?;
                 }
                 PropertyType::ResponseTopic => {
                     let topic = String::decode(reader)?;
                     cursor += 2 + topic.len() as u32;
-                    response_topic = Some(topic);
+                    set_property(&mut response_topic, PropertyType::ResponseTopic, topic)?;
                 }
                 PropertyType::CorrelationData => {
                     let data = VarBytes::decode(reader)?;
                     cursor += 2 + data.0.len() as u32;
-                    correlation_data = Some(data);
+                    set_property(&mut correlation_data, PropertyType::CorrelationData, data)?;
                 }
                 PropertyType::UserProperty => {
                     let key = String::decode(reader)?;
@@ -985,6 +1057,11 @@ impl Decodable for LastWillProperties {
             }
         }
 
+        check_bool_property(
+            PropertyType::PayloadFormatIndicator,
+            payload_format_indicator,
+        )?;
+
         Ok(Self {
             total_length,
             delay_interval,
@@ -999,7 +1076,7 @@ impl Decodable for LastWillProperties {
 }
 
 impl Display for LastWillProperties {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         write!(f, "{{")?;
         match &self.delay_interval {
             Some(delay_interval) => write!(f, "delay_interval: {}", delay_interval)?,
@@ -1048,7 +1125,7 @@ impl Display for LastWillProperties {
 mod test {
     use super::*;
 
-    use std::io::Cursor;
+    use crate::common::io::Cursor;
 
     use crate::common::encodable::{Decodable, Encodable};
 
@@ -1170,7 +1247,7 @@ mod test {
 
         assert_eq!(
             packet.to_string(),
-            "{fixed_header: {packet_type: CONNECT, remaining_length: 47}, protocol_name: MQTT, protocol_level: 5, flags: {username: true, password: false, will_retain: false, will_qos: 1, will_flag: true, clean_session: false, reserved: false}, keepalive: 0, properties: {session_expiry_interval: None, receive_maximum: None}, payload: {client_identifier: test, last_will: {topic: test/topic, message: hello, qos: 1, retain: false, properties: {delay_interval: None, payload_format_indicator: None, message_expiry_interval: None, content_type: None, response_topic: None, correlation_data: None, user_properties: []}}, username: test, password: None}}"
+            "{fixed_header: {packet_type: CONNECT, remaining_length: 43}, protocol_name: MQTT, protocol_level: 5, flags: {username: true, password: false, will_retain: false, will_qos: 1, will_flag: true, clean_session: false, reserved: false}, keepalive: 0, properties: {session_expiry_interval: None, receive_maximum: None}, payload: {client_identifier: test, last_will: {topic: test/topic, message: hello, qos: 1, retain: false, properties: {delay_interval: None, payload_format_indicator: None, message_expiry_interval: None, content_type: None, response_topic: None, correlation_data: None, user_properties: []}}, username: test, password: None}}"
         );
     }
 
@@ -1186,7 +1263,36 @@ mod test {
 
         assert_eq!(
             packet.to_string(),
-            "{fixed_header: {packet_type: CONNECT, remaining_length: 45}, protocol_name: MQTT, protocol_level: 5, flags: {username: true, password: false, will_retain: false, will_qos: 1, will_flag: true, clean_session: false, reserved: false}, keepalive: 0, properties: {session_expiry_interval: None, receive_maximum: None}, payload: {client_identifier: test, last_will: {topic: test/topic, message: [1, 2, 3], qos: 1, retain: false, properties: {delay_interval: None, payload_format_indicator: None, message_expiry_interval: None, content_type: None, response_topic: None, correlation_data: None, user_properties: []}}, username: test, password: None}}"
+            "{fixed_header: {packet_type: CONNECT, remaining_length: 41}, protocol_name: MQTT, protocol_level: 5, flags: {username: true, password: false, will_retain: false, will_qos: 1, will_flag: true, clean_session: false, reserved: false}, keepalive: 0, properties: {session_expiry_interval: None, receive_maximum: None}, payload: {client_identifier: test, last_will: {topic: test/topic, message: [1, 2, 3], qos: 1, retain: false, properties: {delay_interval: None, payload_format_indicator: None, message_expiry_interval: None, content_type: None, response_topic: None, correlation_data: None, user_properties: []}}, username: test, password: None}}"
         );
     }
+
+    #[test]
+    fn test_connect_packet_decode_duplicate_property() {
+        let encoded_data = b"\x10\x1c\x00\x04MQTT\x05\x00\x00\x3c\x0a\x11\xff\xff\xff\xff\x11\x00\x00\x00\x01\x00\x0512345";
+
+        let mut buf = Cursor::new(&encoded_data[..]);
+        let err = ConnectPacket::decode(&mut buf).unwrap_err();
+        assert!(matches!(
+            err,
+            PacketError::VariableHeaderError(VariableHeaderError::PropertyTypeError(
+                PropertyTypeError::DuplicateProperty(PropertyType::SessionExpiryInterval)
+            ))
+        ));
+    }
+
+    #[test]
+    fn test_connect_packet_decode_invalid_property_value() {
+        // receive maximum of 0
+        let encoded_data = b"\x10\x15\x00\x04MQTT\x05\x00\x00\x3c\x03\x21\x00\x00\x00\x0512345";
+
+        let mut buf = Cursor::new(&encoded_data[..]);
+        let err = ConnectPacket::decode(&mut buf).unwrap_err();
+        assert!(matches!(
+            err,
+            PacketError::VariableHeaderError(VariableHeaderError::PropertyTypeError(
+                PropertyTypeError::ProtocolError(PropertyType::ReceiveMaximum)
+            ))
+        ));
+    }
 }
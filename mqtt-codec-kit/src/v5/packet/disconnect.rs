@@ -1,10 +1,8 @@
 //! DISCONNECT
 
-use std::{
-    fmt::Display,
-    io::{self, Read, Write},
-};
+use core::fmt::Display;
 
+use crate::common::io::{self, Read, Write};
 use crate::{
     common::{
         packet::{DecodablePacket, EncodablePacket},
@@ -21,12 +19,19 @@ use crate::{
 
 /// `DISCONNECT` packet
 #[derive(Debug, Eq, PartialEq, Clone)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(remote = "Self")
+)]
 pub struct DisconnectPacket {
     fixed_header: FixedHeader,
     reason_code: DisconnectReasonCode,
     properties: DisconnectProperties,
 }
 
+serde_packet!(DisconnectPacket => Disconnect);
+
 impl DisconnectPacket {
     pub fn new(reason_code: DisconnectReasonCode) -> Self {
         let mut fixed_header =
@@ -102,7 +107,7 @@ impl EncodablePacket for DisconnectPacket {
 }
 
 impl DecodablePacket for DisconnectPacket {
-    type DecodePacketError = std::convert::Infallible;
+    type DecodePacketError = core::convert::Infallible;
 
     type F = FixedHeader;
     type Error = PacketError<Self>;
@@ -132,7 +137,7 @@ impl DecodablePacket for DisconnectPacket {
 }
 
 impl Display for DisconnectPacket {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         write!(
             f,
             "{{fixed_header: {}, reason_code: {}, properties: {}}}",
@@ -143,7 +148,7 @@ impl Display for DisconnectPacket {
 
 #[cfg(test)]
 mod test {
-    use std::io::Cursor;
+    use crate::common::io::Cursor;
 
     use crate::{common::encodable::Encodable, v5::control::variable_header::DisconnectReasonCode};
 
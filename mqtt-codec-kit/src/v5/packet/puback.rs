@@ -1,10 +1,8 @@
 //! PUBACK
 
-use std::{
-    fmt::Display,
-    io::{self, Read, Write},
-};
+use core::fmt::Display;
 
+use crate::common::io::{self, Read, Write};
 use crate::{
     common::{
         packet::{DecodablePacket, EncodablePacket},
@@ -21,6 +19,11 @@ use crate::{
 
 /// `PUBACK` packet, for QoS 1 delivery
 #[derive(Debug, Eq, PartialEq, Clone)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(remote = "Self")
+)]
 pub struct PubackPacket {
     fixed_header: FixedHeader,
     packet_identifier: PacketIdentifier,
@@ -28,6 +31,8 @@ pub struct PubackPacket {
     properties: PubackProperties,
 }
 
+serde_packet!(PubackPacket => PublishAcknowledgement);
+
 impl PubackPacket {
     pub fn new(pkid: u16, reason_code: PubackReasonCode) -> Self {
         let mut fixed_header = FixedHeader::new(
@@ -89,11 +94,13 @@ impl EncodablePacket for PubackPacket {
 
     fn encode_packet<W: Write>(&self, writer: &mut W) -> io::Result<()> {
         self.packet_identifier.encode(writer)?;
-        if self.reason_code != PubackReasonCode::Success {
-            self.reason_code.encode(writer)?;
-            if !self.properties.clone().is_empty() {
-                self.properties.encode(writer)?
+        if self.properties.is_empty() {
+            if self.reason_code != PubackReasonCode::Success {
+                self.reason_code.encode(writer)?;
             }
+        } else {
+            self.reason_code.encode(writer)?;
+            self.properties.encode(writer)?
         }
         Ok(())
     }
@@ -112,7 +119,7 @@ impl EncodablePacket for PubackPacket {
 }
 
 impl DecodablePacket for PubackPacket {
-    type DecodePacketError = std::convert::Infallible;
+    type DecodePacketError = core::convert::Infallible;
     type F = FixedHeader;
     type Error = PacketError<Self>;
 
@@ -141,7 +148,7 @@ impl DecodablePacket for PubackPacket {
 }
 
 impl Display for PubackPacket {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         write!(
             f,
             "{{fixed_header: {}, packet_identifier: {}, reason_code: {}, properties: {}}}",
@@ -152,7 +159,7 @@ impl Display for PubackPacket {
 
 #[cfg(test)]
 mod test {
-    use std::io::Cursor;
+    use crate::common::io::Cursor;
 
     use crate::common::encodable::Encodable;
 
@@ -227,6 +234,24 @@ mod test {
         assert_eq!(packet, decoded);
     }
 
+    #[test]
+    fn test_puback_packet_success_with_properties() {
+        let mut packet = PubackPacket::new(10001, PubackReasonCode::Success);
+
+        let mut properties = PubackProperties::default();
+        properties.add_user_property("foo", "bar");
+
+        packet.set_properties(properties);
+
+        let mut buf = Vec::new();
+        packet.encode(&mut buf).unwrap();
+
+        let mut decode_buf = Cursor::new(buf);
+        let decoded = PubackPacket::decode(&mut decode_buf).unwrap();
+
+        assert_eq!(packet, decoded);
+    }
+
     #[test]
     fn test_display_puback_packet() {
         let packet = PubackPacket::new(123, PubackReasonCode::PacketIdentifierInUse);
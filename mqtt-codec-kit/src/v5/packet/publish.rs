@@ -1,10 +1,9 @@
 //! PUBLISH
 
-use std::{
-    fmt::Display,
-    io::{self, Read, Write},
-};
+use alloc::{string::String, vec::Vec};
+use core::fmt::Display;
 
+use crate::common::io::{self, Read, Write};
 use crate::{
     common::{
         packet::{DecodablePacket, EncodablePacket},
@@ -19,6 +18,11 @@ use crate::{
 
 /// `PUBLISH` packet
 #[derive(Debug, Eq, PartialEq, Clone)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(remote = "Self")
+)]
 pub struct PublishPacket {
     fixed_header: FixedHeader,
     topic_name: TopicName,
@@ -34,6 +38,8 @@ encodable_packet!(PublishPacket(
     payload
 ));
 
+serde_packet!(PublishPacket => Publish, PublishPacket::check_qos);
+
 impl PublishPacket {
     pub fn new<P: Into<Vec<u8>>>(
         topic_name: TopicName,
@@ -59,7 +65,7 @@ impl PublishPacket {
     }
 
     pub fn dup(&self) -> bool {
-        self.fixed_header.packet_type.flags() & 0x80 != 0
+        self.fixed_header.packet_type.flags() & 0x08 != 0
     }
 
     pub fn set_qos(&mut self, qos: QoSWithPacketIdentifier) {
@@ -85,6 +91,17 @@ impl PublishPacket {
         }
     }
 
+    /// The QoS flags and the packet identifier must agree, as `qos()` relies on it
+    #[cfg(feature = "serde")]
+    fn check_qos(&self) -> Result<(), &'static str> {
+        let qos_val = (self.fixed_header.packet_type.flags() & 0b0110) >> 1;
+        if (qos_val == 0) == self.packet_identifier.is_none() {
+            Ok(())
+        } else {
+            Err("QoS flags do not match the packet identifier")
+        }
+    }
+
     pub fn set_retain(&mut self, ret: bool) {
         self.fixed_header
             .packet_type
@@ -121,10 +138,19 @@ impl PublishPacket {
         self.properties = properties;
         self.fix_header_remaining_len();
     }
+
+    /// Sends the topic as `topic_alias` alone, with an empty topic name, for a receiver which
+    /// got the alias with the topic name earlier on the connection
+    pub fn set_topic_alias_only(&mut self, topic_alias: u16) {
+        self.properties.set_topic_alias(Some(topic_alias));
+        // SAFETY: an empty topic name is valid on the wire together with a topic alias
+        self.topic_name = unsafe { TopicName::new_unchecked(String::new()) };
+        self.fix_header_remaining_len();
+    }
 }
 
 impl DecodablePacket for PublishPacket {
-    type DecodePacketError = std::convert::Infallible;
+    type DecodePacketError = core::convert::Infallible;
     type F = FixedHeader;
     type Error = PacketError<Self>;
 
@@ -163,7 +189,7 @@ impl DecodablePacket for PublishPacket {
 }
 
 impl Display for PublishPacket {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         write!(
             f,
             "{{fixed_header: {}, topic_name: {}",
@@ -176,20 +202,12 @@ impl Display for PublishPacket {
 
         write!(f, ", properties: {}", self.properties)?;
 
-        match std::str::from_utf8(&self.payload) {
+        match core::str::from_utf8(&self.payload) {
             Ok(s) if s.chars().all(|c| c.is_ascii_graphic() || c == ' ') => {
                 write!(f, ", payload: {}", s)?;
             }
             _ => {
-                write!(f, ", payload: [")?;
-                let mut iter = self.payload.iter();
-                if let Some(first) = iter.next() {
-                    write!(f, "{}", first)?;
-                    for byte in iter {
-                        write!(f, ", {}", byte)?;
-                    }
-                }
-                write!(f, "]")?;
+                write!(f, ", payload: [{} bytes]", self.payload.len())?;
             }
         };
         write!(f, "}}")
@@ -253,10 +271,11 @@ impl EncodablePacket for PublishPacketRef<'_> {
 mod test {
     use super::*;
 
-    use std::io::Cursor;
+    use crate::common::io::Cursor;
 
     use crate::common::topic_name::TopicName;
     use crate::common::{Decodable, Encodable};
+    use crate::v5::property::{PropertyType, PropertyTypeError};
 
     #[test]
     fn test_publish_packet_encode_hex() {
@@ -343,7 +362,43 @@ mod test {
 
         assert_eq!(
             packet.to_string(),
-            "{fixed_header: {packet_type: PUBLISH, remaining_length: 12}, topic_name: a/b, packet_identifier: 10, properties: {payload_format_indicator: None, message_expiry_interval: None, topic_alias: None, response_topic: None, correlation_data: None, user_properties: [], subscription_identifier: None, content_type: None}, payload: [1, 2, 3, 4]}"
+            "{fixed_header: {packet_type: PUBLISH, remaining_length: 12}, topic_name: a/b, packet_identifier: 10, properties: {payload_format_indicator: None, message_expiry_interval: None, topic_alias: None, response_topic: None, correlation_data: None, user_properties: [], subscription_identifier: None, content_type: None}, payload: [4 bytes]}"
         );
     }
+
+    #[test]
+    fn test_publish_packet_encode_topic_alias_only() {
+        let mut packet = PublishPacket::new(
+            TopicName::new("a/b").unwrap(),
+            QoSWithPacketIdentifier::Level0,
+            b"hi".to_vec(),
+        );
+        packet.set_topic_alias_only(3);
+        assert!(packet.topic_name().is_empty());
+
+        let mut buf = Vec::new();
+        packet.encode(&mut buf).unwrap();
+        assert_eq!(buf, b"\x30\x08\x00\x00\x03\x23\x00\x03hi");
+    }
+
+    #[test]
+    fn test_publish_packet_decode_strict_properties() {
+        let duplicate = b"\x30\x0a\x00\x03a/b\x04\x01\x00\x01\x01";
+        let err = PublishPacket::decode(&mut Cursor::new(&duplicate[..])).unwrap_err();
+        assert!(matches!(
+            err,
+            PacketError::VariableHeaderError(VariableHeaderError::PropertyTypeError(
+                PropertyTypeError::DuplicateProperty(PropertyType::PayloadFormatIndicator)
+            ))
+        ));
+
+        let zero_topic_alias = b"\x30\x09\x00\x03a/b\x03\x23\x00\x00";
+        let err = PublishPacket::decode(&mut Cursor::new(&zero_topic_alias[..])).unwrap_err();
+        assert!(matches!(
+            err,
+            PacketError::VariableHeaderError(VariableHeaderError::PropertyTypeError(
+                PropertyTypeError::ProtocolError(PropertyType::TopicAlias)
+            ))
+        ));
+    }
 }
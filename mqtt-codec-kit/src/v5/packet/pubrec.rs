@@ -1,10 +1,8 @@
 //! PUBREC
 
-use std::{
-    fmt::Display,
-    io::{self, Read, Write},
-};
+use core::fmt::Display;
 
+use crate::common::io::{self, Read, Write};
 use crate::{
     common::{
         packet::{DecodablePacket, EncodablePacket},
@@ -21,6 +19,11 @@ use crate::{
 
 /// `PUBREC` packet, for QoS 2 delivery part 1
 #[derive(Debug, Eq, PartialEq, Clone)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(remote = "Self")
+)]
 pub struct PubrecPacket {
     fixed_header: FixedHeader,
     packet_identifier: PacketIdentifier,
@@ -28,6 +31,8 @@ pub struct PubrecPacket {
     properties: PubrecProperties,
 }
 
+serde_packet!(PubrecPacket => PublishReceived);
+
 impl PubrecPacket {
     pub fn new(pkid: u16, reason_code: PubrecReasonCode) -> Self {
         let mut fixed_header =
@@ -87,11 +92,13 @@ impl EncodablePacket for PubrecPacket {
 
     fn encode_packet<W: Write>(&self, writer: &mut W) -> io::Result<()> {
         self.packet_identifier.encode(writer)?;
-        if self.reason_code != PubrecReasonCode::Success {
-            self.reason_code.encode(writer)?;
-            if !self.properties.clone().is_empty() {
-                self.properties.encode(writer)?
+        if self.properties.is_empty() {
+            if self.reason_code != PubrecReasonCode::Success {
+                self.reason_code.encode(writer)?;
             }
+        } else {
+            self.reason_code.encode(writer)?;
+            self.properties.encode(writer)?
         }
         Ok(())
     }
@@ -110,7 +117,7 @@ impl EncodablePacket for PubrecPacket {
 }
 
 impl DecodablePacket for PubrecPacket {
-    type DecodePacketError = std::convert::Infallible;
+    type DecodePacketError = core::convert::Infallible;
     type F = FixedHeader;
     type Error = PacketError<Self>;
 
@@ -139,7 +146,7 @@ impl DecodablePacket for PubrecPacket {
 }
 
 impl Display for PubrecPacket {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         write!(
             f,
             "{{fixed_header: {}, packet_identifier: {}, reason_code: {}, properties: {}}}",
@@ -150,7 +157,7 @@ impl Display for PubrecPacket {
 
 #[cfg(test)]
 mod test {
-    use std::io::Cursor;
+    use crate::common::io::Cursor;
 
     use crate::common::encodable::Encodable;
 
@@ -1,12 +1,9 @@
 //! SUBACK
 
-use std::{
-    fmt::Display,
-    io::{self, Read, Write},
-};
-
-use byteorder::{ReadBytesExt, WriteBytesExt};
+use alloc::vec::Vec;
+use core::fmt::Display;
 
+use crate::common::io::{self, Read, ReadBytesExt, Write, WriteBytesExt};
 use crate::{
     common::{packet::DecodablePacket, Decodable, Encodable, PacketIdentifier},
     v5::{
@@ -18,6 +15,11 @@ use crate::{
 
 /// `SUBACK` packet
 #[derive(Debug, Eq, PartialEq, Clone)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(remote = "Self")
+)]
 pub struct SubackPacket {
     fixed_header: FixedHeader,
     packet_identifier: PacketIdentifier,
@@ -27,6 +29,8 @@ pub struct SubackPacket {
 
 encodable_packet!(SubackPacket(packet_identifier, properties, payload));
 
+serde_packet!(SubackPacket => SubscribeAcknowledgement);
+
 impl SubackPacket {
     pub fn new(pkid: u16, reason_codes: Vec<SubscribeReasonCode>) -> Self {
         let mut pkt = Self {
@@ -91,7 +95,7 @@ impl DecodablePacket for SubackPacket {
 }
 
 impl Display for SubackPacket {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         write!(
             f,
             "{{fixed_header: {}, packet_identifier: {}, properties: {}, payload: {}}}",
@@ -101,6 +105,7 @@ impl Display for SubackPacket {
 }
 
 #[derive(Debug, Eq, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 struct SubackPacketPayload {
     reason_codes: Vec<SubscribeReasonCode>,
 }
@@ -141,7 +146,7 @@ impl Decodable for SubackPacketPayload {
 }
 
 impl Display for SubackPacketPayload {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         write!(f, "{{reason_codes: [")?;
         let mut iter = self.reason_codes.iter();
         if let Some(first) = iter.next() {
@@ -156,6 +161,8 @@ impl Display for SubackPacketPayload {
 
 /// Reason code for `SUBACK` packet
 #[derive(Debug, Eq, PartialEq, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 pub enum SubscribeReasonCode {
     GrantedQos0,
     GrantedQos1,
@@ -251,7 +258,7 @@ impl Decodable for SubscribeReasonCode {
 }
 
 impl Display for SubscribeReasonCode {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         let code: u8 = self.into();
         write!(f, "{}", code)
     }
@@ -267,7 +274,7 @@ pub enum SubackPacketError {
 
 #[cfg(test)]
 mod test {
-    use std::io::Cursor;
+    use crate::common::io::Cursor;
 
     use crate::common::encodable::Encodable;
 
@@ -1,13 +1,12 @@
 //! SUBSCRIBE
 
-use std::{
-    fmt::Display,
-    io::{self, Read, Write},
-    string::FromUtf8Error,
+use alloc::{
+    string::{FromUtf8Error, String},
+    vec::Vec,
 };
+use core::fmt::Display;
 
-use byteorder::{ReadBytesExt, WriteBytesExt};
-
+use crate::common::io::{self, Read, ReadBytesExt, Write, WriteBytesExt};
 use crate::{
     common::{
         packet::DecodablePacket,
@@ -22,6 +21,11 @@ use crate::{
 
 /// `SUBSCRIBE` packet
 #[derive(Debug, Eq, PartialEq, Clone)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(remote = "Self")
+)]
 pub struct SubscribePacket {
     fixed_header: FixedHeader,
     packet_identifier: PacketIdentifier,
@@ -31,6 +35,8 @@ pub struct SubscribePacket {
 
 encodable_packet!(SubscribePacket(packet_identifier, properties, payload));
 
+serde_packet!(SubscribePacket => Subscribe);
+
 impl SubscribePacket {
     pub fn new(pkid: u16, subscribes: Vec<(TopicFilter, SubscribeOptions)>) -> Self {
         let mut pkt = Self {
@@ -92,7 +98,7 @@ impl DecodablePacket for SubscribePacket {
 }
 
 impl Display for SubscribePacket {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         write!(
             f,
             "{{fixed_header: {}, packet_identifier: {}, properties: {}, payload: {}}}",
@@ -103,6 +109,7 @@ impl Display for SubscribePacket {
 
 /// Payload of subscribe packet
 #[derive(Debug, Eq, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 struct SubscribePacketPayload {
     subscribes: Vec<(TopicFilter, SubscribeOptions)>,
 }
@@ -138,7 +145,8 @@ impl Decodable for SubscribePacketPayload {
         let mut subs = Vec::new();
 
         while payload_len > 0 {
-            let filter = TopicFilter::decode(reader)?;
+            // an invalid filter only fails its own subscription, see `TopicFilterRef::is_valid`
+            let filter = unsafe { TopicFilter::new_unchecked(String::decode(reader)?) };
             let option = SubscribeOptions::decode(reader)?;
 
             payload_len -= filter.encoded_length() + option.encoded_length();
@@ -150,7 +158,7 @@ impl Decodable for SubscribePacketPayload {
 }
 
 impl Display for SubscribePacketPayload {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         write!(f, "{{subscribes: [")?;
         let mut iter = self.subscribes.iter();
         if let Some(first) = iter.next() {
@@ -165,6 +173,8 @@ impl Display for SubscribePacketPayload {
 
 /// SubscribePayload options of subscribe packet
 #[derive(Debug, Eq, PartialEq, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 pub struct SubscribeOptions {
     qos: QualityOfService,
     no_local: bool,
@@ -270,7 +280,7 @@ impl Decodable for SubscribeOptions {
 }
 
 impl Display for SubscribeOptions {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         write!(
             f,
             "{{qos: {}, no_local: {}, retain_as_published: {}, retain_handling: {}}}",
@@ -280,6 +290,8 @@ impl Display for SubscribeOptions {
 }
 
 #[derive(Debug, Eq, PartialEq, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 pub enum RetainHandling {
     SendAtSubscribe,
     SendAtSubscribeIfNotExist,
@@ -316,7 +328,7 @@ impl TryFrom<u8> for RetainHandling {
 }
 
 impl Display for RetainHandling {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         let value: u8 = self.into();
         write!(f, "{}", value)
     }
@@ -347,7 +359,7 @@ impl From<TopicFilterDecodeError> for SubscribePacketError {
 
 #[cfg(test)]
 mod test {
-    use std::io::Cursor;
+    use crate::common::io::Cursor;
 
     use crate::common::encodable::Encodable;
 
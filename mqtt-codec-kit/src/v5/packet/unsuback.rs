@@ -1,12 +1,9 @@
 //! UNSUBACK
 
-use std::{
-    fmt::Display,
-    io::{self, Read, Write},
-};
-
-use byteorder::{ReadBytesExt, WriteBytesExt};
+use alloc::vec::Vec;
+use core::fmt::Display;
 
+use crate::common::io::{self, Read, ReadBytesExt, Write, WriteBytesExt};
 use crate::{
     common::{packet::DecodablePacket, Decodable, Encodable, PacketIdentifier},
     v5::{
@@ -18,6 +15,11 @@ use crate::{
 
 /// `UNSUBACK` packet
 #[derive(Debug, Eq, PartialEq, Clone)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(remote = "Self")
+)]
 pub struct UnsubackPacket {
     fixed_header: FixedHeader,
     packet_identifier: PacketIdentifier,
@@ -27,6 +29,8 @@ pub struct UnsubackPacket {
 
 encodable_packet!(UnsubackPacket(packet_identifier, properties, payload));
 
+serde_packet!(UnsubackPacket => UnsubscribeAcknowledgement);
+
 impl UnsubackPacket {
     pub fn new(pkid: u16, reason_codes: Vec<UnsubscribeReasonCode>) -> Self {
         let mut pkt = Self {
@@ -99,7 +103,7 @@ impl DecodablePacket for UnsubackPacket {
 }
 
 impl Display for UnsubackPacket {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         write!(
             f,
             "{{fixed_header: {}, packet_identifier: {}, properties: {}, payload: {}}}",
@@ -109,6 +113,7 @@ impl Display for UnsubackPacket {
 }
 
 #[derive(Debug, Eq, PartialEq, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 struct UnsubackPacketPayload {
     reason_codes: Vec<UnsubscribeReasonCode>,
 }
@@ -122,7 +127,7 @@ impl UnsubackPacketPayload {
 impl Encodable for UnsubackPacketPayload {
     fn encode<W: Write>(&self, writer: &mut W) -> Result<(), io::Error> {
         for code in self.reason_codes.iter() {
-            writer.write_u8(*code as u8)?;
+            code.encode(writer)?;
         }
 
         Ok(())
@@ -149,7 +154,7 @@ impl Decodable for UnsubackPacketPayload {
 }
 
 impl Display for UnsubackPacketPayload {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         write!(f, "{{reason_codes: [")?;
         let mut iter = self.reason_codes.iter();
         if let Some(first) = iter.next() {
@@ -164,6 +169,8 @@ impl Display for UnsubackPacketPayload {
 
 /// Reason code for `UNSUBACK` packet
 #[derive(Debug, Eq, PartialEq, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 pub enum UnsubscribeReasonCode {
     Success,
     NoSubscriptionExisted,
@@ -238,7 +245,7 @@ impl Decodable for UnsubscribeReasonCode {
 }
 
 impl Display for UnsubscribeReasonCode {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         let code: u8 = self.into();
         write!(f, "{}", code)
     }
@@ -254,7 +261,7 @@ pub enum UnsubackPacketError {
 
 #[cfg(test)]
 mod test {
-    use std::io::Cursor;
+    use crate::common::io::Cursor;
 
     use crate::common::encodable::Encodable;
 
@@ -318,6 +325,24 @@ mod test {
         assert_eq!(packet, decoded);
     }
 
+    #[test]
+    fn test_unsuback_packet_encode_reason_codes() {
+        let packet = UnsubackPacket::new(
+            10001,
+            vec![
+                UnsubscribeReasonCode::NoSubscriptionExisted,
+                UnsubscribeReasonCode::NotAuthorized,
+            ],
+        );
+
+        let expected = b"\xb0\x05\x27\x11\x00\x11\x87";
+
+        let mut buf = Vec::new();
+        packet.encode(&mut buf).unwrap();
+
+        assert_eq!(&expected[..], &buf[..]);
+    }
+
     #[test]
     fn test_display_unsuback_packet() {
         let packet = UnsubackPacket::new(123, vec![UnsubscribeReasonCode::NoSubscriptionExisted]);
@@ -1,11 +1,9 @@
 //! UNSUBSCRIBE
 
-use std::{
-    fmt::Display,
-    io::{self, Read, Write},
-    string::FromUtf8Error,
-};
+use alloc::{string::FromUtf8Error, vec::Vec};
+use core::fmt::Display;
 
+use crate::common::io::{self, Read, Write};
 use crate::{
     common::{
         packet::DecodablePacket,
@@ -22,6 +20,11 @@ use crate::{
 
 /// `UNSUBSCRIBE` packet
 #[derive(Debug, Eq, PartialEq, Clone)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(remote = "Self")
+)]
 pub struct UnsubscribePacket {
     fixed_header: FixedHeader,
     packet_identifier: PacketIdentifier,
@@ -31,6 +34,8 @@ pub struct UnsubscribePacket {
 
 encodable_packet!(UnsubscribePacket(packet_identifier, properties, payload));
 
+serde_packet!(UnsubscribePacket => Unsubscribe);
+
 impl UnsubscribePacket {
     pub fn new(pkid: u16, subscribes: Vec<TopicFilter>) -> Self {
         let mut pkt = Self {
@@ -92,7 +97,7 @@ impl DecodablePacket for UnsubscribePacket {
 }
 
 impl Display for UnsubscribePacket {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         write!(
             f,
             "{{fixed_header: {}, packet_identifier: {}, properties: {}, payload: {}}}",
@@ -102,6 +107,7 @@ impl Display for UnsubscribePacket {
 }
 
 #[derive(Debug, Eq, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 struct UnsubscribePacketPayload {
     topic_filters: Vec<TopicFilter>,
 }
@@ -146,7 +152,7 @@ impl Decodable for UnsubscribePacketPayload {
 }
 
 impl Display for UnsubscribePacketPayload {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         write!(f, "{{topic_filters: [")?;
         let mut iter = self.topic_filters.iter();
         if let Some(first) = iter.next() {
@@ -178,7 +184,7 @@ impl From<TopicFilterDecodeError> for UnsubscribePacketError {
 
 #[cfg(test)]
 mod test {
-    use std::io::Cursor;
+    use crate::common::io::Cursor;
 
     use crate::common::encodable::Encodable;
 
@@ -1,10 +1,11 @@
 //! Properties
 //! <https://docs.oasis-open.org/mqtt/mqtt/v5.0/os/mqtt-v5.0-os.html#_Toc3901027>
 
-use std::io;
+use crate::{common::io, v5::control::DisconnectReasonCode};
 
 #[repr(u8)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum PropertyType {
     PayloadFormatIndicator = 1,
     MessageExpiryInterval = 2,
@@ -79,4 +80,57 @@ pub enum PropertyTypeError {
     IoError(#[from] io::Error),
     #[error("invalid property type ({0})")]
     InvalidPropertyType(u8),
+    #[error("property {0:?} included more than once")]
+    DuplicateProperty(PropertyType),
+    #[error("protocol error, invalid value of property {0:?}")]
+    ProtocolError(PropertyType),
+}
+
+impl PropertyTypeError {
+    /// Reason code of the DISCONNECT sent to a client whose packet failed to decode
+    pub fn reason_code(&self) -> DisconnectReasonCode {
+        match self {
+            PropertyTypeError::IoError(_) | PropertyTypeError::InvalidPropertyType(_) => {
+                DisconnectReasonCode::MalformedPacket
+            }
+            PropertyTypeError::DuplicateProperty(_) | PropertyTypeError::ProtocolError(_) => {
+                DisconnectReasonCode::ProtocolError
+            }
+        }
+    }
+}
+
+/// Stores a decoded property value, it is a Protocol Error to include a property more than once
+pub(crate) fn set_property<T>(
+    slot: &mut Option<T>,
+    property: PropertyType,
+    value: T,
+) -> Result<(), PropertyTypeError> {
+    if slot.is_some() {
+        return Err(PropertyTypeError::DuplicateProperty(property));
+    }
+    *slot = Some(value);
+    Ok(())
+}
+
+/// Checks a property whose only valid values are 0 and 1
+pub(crate) fn check_bool_property(
+    property: PropertyType,
+    value: Option<u8>,
+) -> Result<(), PropertyTypeError> {
+    match value {
+        Some(0 | 1) | None => Ok(()),
+        Some(_) => Err(PropertyTypeError::ProtocolError(property)),
+    }
+}
+
+/// Checks a numeric property for which a value of 0 is a Protocol Error
+pub(crate) fn check_non_zero_property<T: Into<u32>>(
+    property: PropertyType,
+    value: Option<T>,
+) -> Result<(), PropertyTypeError> {
+    match value.map(Into::into) {
+        Some(0) => Err(PropertyTypeError::ProtocolError(property)),
+        _ => Ok(()),
+    }
 }
@@ -0,0 +1,187 @@
+//! [wasm-bindgen](https://rustwasm.github.io/docs/wasm-bindgen/) bindings of the codec, e.g. for
+//! a dashboard in the browser
+//!
+//! Packets cross into JavaScript as JSON in the shape of the `serde` feature, the one a broker
+//! exports them in:
+//!
+//! ```js
+//! import { encodeV5, DecoderV5 } from "mqtt-codec-kit";
+//!
+//! const connect = encodeV5(JSON.stringify({ ConnectPacket: { ... } }));
+//! socket.send(connect);
+//!
+//! const decoder = new DecoderV5();
+//! socket.onmessage = ({ data }) => {
+//!     for (const packet of JSON.parse(decoder.push(new Uint8Array(data)))) {
+//!         console.log(packet);
+//!     }
+//! };
+//! ```
+//!
+//! The `remaining_length` of a fixed header passed to `encode` is ignored, it is computed from
+//! the packet. A decoder buffers the bytes of an incomplete packet until the next `push`. A
+//! malformed packet fails the `push`, or the next one if packets before it were decoded so they
+//! are returned first, and the decoder drops everything it buffered.
+
+use alloc::{
+    string::{String, ToString},
+    vec::Vec,
+};
+
+use wasm_bindgen::prelude::*;
+
+macro_rules! bindings {
+    ($version:ident, $encode:ident, $encode_name:literal, $decoder:ident) => {
+        /// Encodes the JSON of a packet
+        #[wasm_bindgen(js_name = $encode_name)]
+        pub fn $encode(json: &str) -> Result<Vec<u8>, String> {
+            use crate::{
+                common::{packet::EncodablePacket, Encodable},
+                $version::{control::FixedHeader, packet::VariablePacket},
+            };
+
+            let packet: VariablePacket = serde_json::from_str(json).map_err(|e| e.to_string())?;
+            let fixed_header = FixedHeader::new(
+                packet.fixed_header().packet_type,
+                packet.encoded_packet_length(),
+            );
+            let mut buf = Vec::with_capacity(
+                (fixed_header.encoded_length() + packet.encoded_packet_length()) as usize,
+            );
+            fixed_header
+                .encode(&mut buf)
+                .and_then(|_| packet.encode_packet(&mut buf))
+                .map_err(|e| e.to_string())?;
+            Ok(buf)
+        }
+
+        /// Decodes the packets of a byte stream
+        #[wasm_bindgen]
+        #[derive(Debug, Default)]
+        pub struct $decoder {
+            buf: Vec<u8>,
+        }
+
+        #[wasm_bindgen]
+        impl $decoder {
+            #[wasm_bindgen(constructor)]
+            pub fn new() -> Self {
+                Self::default()
+            }
+
+            /// Appends `bytes` to the stream, the JSON array of the packets completed by them
+            pub fn push(&mut self, bytes: &[u8]) -> Result<String, String> {
+                use crate::{
+                    common::{io::ErrorKind, Decodable},
+                    $version::{
+                        control::{fixed_header::FixedHeaderError, FixedHeader},
+                        packet::VariablePacket,
+                    },
+                };
+
+                self.buf.extend_from_slice(bytes);
+                let mut packets = Vec::new();
+                let mut start = 0;
+                let error = loop {
+                    let buf = &self.buf[start..];
+                    let mut rest = buf;
+                    let len = match FixedHeader::decode(&mut rest) {
+                        Ok(header) => buf.len() - rest.len() + header.remaining_length as usize,
+                        Err(FixedHeaderError::IoError(err))
+                            if err.kind() == ErrorKind::UnexpectedEof =>
+                        {
+                            break None
+                        }
+                        Err(err) => break Some(err.to_string()),
+                    };
+                    if len > buf.len() {
+                        break None;
+                    }
+                    match VariablePacket::decode(&mut &buf[..len]) {
+                        Ok(packet) => packets.push(packet),
+                        Err(err) => break Some(err.to_string()),
+                    }
+                    start += len;
+                };
+                match error {
+                    Some(err) if packets.is_empty() => {
+                        self.buf.clear();
+                        Err(err)
+                    }
+                    // the malformed packet stays buffered and fails the next push
+                    _ => {
+                        self.buf.drain(..start);
+                        serde_json::to_string(&packets).map_err(|e| e.to_string())
+                    }
+                }
+            }
+        }
+    };
+}
+
+#[cfg(feature = "v4")]
+bindings!(v4, encode_v4, "encodeV4", DecoderV4);
+#[cfg(feature = "v5")]
+bindings!(v5, encode_v5, "encodeV5", DecoderV5);
+
+#[cfg(all(test, feature = "v4"))]
+mod tests {
+    use super::*;
+    use crate::{
+        common::{qos::QoSWithPacketIdentifier, Encodable, TopicName},
+        v4::packet::{PingreqPacket, PublishPacket, VariablePacket},
+    };
+
+    #[test]
+    fn round_trip_across_pushes() {
+        let publish = VariablePacket::new(PublishPacket::new(
+            TopicName::new("a/b").unwrap(),
+            QoSWithPacketIdentifier::Level1(10),
+            b"hello".to_vec(),
+        ));
+        let pingreq = VariablePacket::new(PingreqPacket::new());
+        // a wrong remaining length from JavaScript is corrected
+        let json = serde_json::to_string(&publish)
+            .unwrap()
+            .replace(r#""remaining_length":12"#, r#""remaining_length":1"#);
+        assert!(json.contains(r#""remaining_length":1}"#));
+        let mut bytes = encode_v4(&json).unwrap();
+        let mut expected = Vec::new();
+        publish.encode(&mut expected).unwrap();
+        assert_eq!(bytes, expected);
+        pingreq.encode(&mut bytes).unwrap();
+
+        let mut decoder = DecoderV4::new();
+        assert_eq!(decoder.push(&bytes[..4]).unwrap(), "[]");
+        let json = decoder.push(&bytes[4..]).unwrap();
+        let packets: Vec<VariablePacket> = serde_json::from_str(&json).unwrap();
+        assert_eq!(packets, [publish, pingreq]);
+        assert!(decoder.buf.is_empty());
+
+        assert!(decoder.push(&[0x30, 0xFF, 0xFF, 0xFF, 0xFF, 0x7F]).is_err());
+        assert!(decoder.buf.is_empty());
+        assert!(encode_v4("{}").is_err());
+    }
+
+    #[test]
+    fn packets_before_a_malformed_one_are_returned() {
+        let pingreq = VariablePacket::new(PingreqPacket::new());
+        let mut bytes = Vec::new();
+        pingreq.encode(&mut bytes).unwrap();
+        pingreq.encode(&mut bytes).unwrap();
+        // a PUBLISH with the reserved QoS 3
+        bytes.extend_from_slice(&[0x36, 0x00]);
+
+        let mut decoder = DecoderV4::new();
+        let json = decoder.push(&bytes).unwrap();
+        let packets: Vec<VariablePacket> = serde_json::from_str(&json).unwrap();
+        assert_eq!(packets, [pingreq.clone(), pingreq.clone()]);
+        assert!(decoder.push(&[]).is_err());
+        assert!(decoder.buf.is_empty());
+
+        // the stream starts over
+        let json = decoder.push(&bytes[..2]).unwrap();
+        let packets: Vec<VariablePacket> = serde_json::from_str(&json).unwrap();
+        assert_eq!(packets, [pingreq]);
+    }
+}